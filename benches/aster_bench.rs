@@ -2,6 +2,8 @@
 extern crate criterion;
 
 use libaster::protocol::redis::resp::MessageMut;
+use libaster::proxy::standalone::fnv::fnv1a64;
+use libaster::proxy::standalone::hash::HashMethod;
 
 use bytes::BytesMut;
 use criterion::Criterion;
@@ -35,5 +37,28 @@ fn bench_resp(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_resp);
+fn bench_hash(c: &mut Criterion) {
+    let key = "user:1024:profile".as_bytes();
+    // the fn pointer used before the hash method is configurable, as the baseline
+    c.bench_function("hash fnv1a_64 fn pointer", move |b| {
+        let hasher: fn(&[u8]) -> u64 = fnv1a64;
+        b.iter(|| hasher(criterion::black_box(key)))
+    });
+
+    let methods = [
+        ("fnv1a_64", HashMethod::Fnv1a64),
+        ("murmur3_32", HashMethod::Murmur3x32),
+        ("murmur3_128", HashMethod::Murmur3x128),
+        ("xxhash64", HashMethod::XxHash64),
+        ("crc32a", HashMethod::Crc32a),
+        ("crc16", HashMethod::Crc16),
+    ];
+    for (name, method) in methods.iter().cloned() {
+        c.bench_function(&format!("hash {}", name), move |b| {
+            b.iter(|| method.hash(criterion::black_box(key)))
+        });
+    }
+}
+
+criterion_group!(benches, bench_resp, bench_hash);
 criterion_main!(benches);
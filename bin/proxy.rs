@@ -1,5 +1,8 @@
 extern crate libaster;
 
 fn main() {
-    libaster::run().unwrap();
+    if let Err(err) = libaster::run() {
+        eprintln!("aster exit due {}", err);
+        std::process::exit(libaster::exit_code(&err));
+    }
 }
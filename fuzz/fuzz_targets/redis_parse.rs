@@ -0,0 +1,10 @@
+#![no_main]
+use libaster::protocol::check_parse;
+use libaster::protocol::redis::MessageMut;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    check_parse(data, |src| {
+        MessageMut::parse(src).map(|x| x.map(|msg| msg.data.len()))
+    });
+});
@@ -7,6 +7,15 @@ echo "aster start tests......"
 
 docker run -e "IP=0.0.0.0" -d -p 7000-7007:7000-7007 grokzen/redis-cluster:5.0.7 && cargo test --verbose --all
 
+# the minimal builds must build alone
+for features in redis memcache redis,metrics memcache,metrics redis,memcache,metrics redis,admin; do
+    cargo build --verbose --lib --bins --no-default-features --features "$features"
+done
+
+# replies proxied by aster must be the same as redis
+sudo apt install redis-server -y
+cargo test --verbose --test conformance -- --ignored
+
 sudo apt install python3 -y
 sudo pip install pytest mock python-toml
 
@@ -0,0 +1,402 @@
+//! admin api served by the same http server of metrics
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::com::AsError;
+use crate::proxy::capture::{self, CaptureOption};
+use crate::proxy::clients::{self, DrainOption};
+use crate::proxy::cluster::slotstat::{self, TopOption};
+use crate::proxy::doctor;
+use crate::proxy::fault::{self, DownFault, ErrorFault, LatencyFault};
+use crate::proxy::maintenance;
+use crate::proxy::readonly;
+use crate::proxy::ringdiff::{self, RingDiffOption};
+use crate::proxy::slo;
+use crate::proxy::standalone::drain::{self, NodeState};
+use crate::proxy::standalone::failover;
+use crate::proxy::standalone::reload;
+use crate::proxy::standalone::transition;
+use crate::proxy::startup;
+use crate::proxy::warmup::{self, WarmupOption};
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/admin/failback/{cluster}", web::post().to(failback))
+        .route(
+            "/admin/readonly/{cluster}/{mode}",
+            web::post().to(read_only),
+        )
+        .route(
+            "/admin/maintenance/{cluster}/{mode}",
+            web::post().to(maintenance),
+        )
+        .route("/admin/backend/{cluster}/{node}", web::get().to(backend))
+        .route(
+            "/admin/backend/{cluster}/{node}/{state}",
+            web::post().to(set_backend),
+        )
+        .route(
+            "/admin/capture/{cluster}/start",
+            web::post().to(start_capture),
+        )
+        .route(
+            "/admin/capture/{cluster}/stop",
+            web::post().to(stop_capture),
+        )
+        .route(
+            "/admin/fault/{cluster}/latency",
+            web::post().to(fault_latency),
+        )
+        .route("/admin/fault/{cluster}/error", web::post().to(fault_error))
+        .route("/admin/fault/{cluster}/down", web::post().to(fault_down))
+        .route("/admin/fault/{cluster}/clear", web::post().to(fault_clear))
+        .route("/admin/health", web::get().to(health))
+        .route("/admin/clusters", web::get().to(clusters))
+        .route(
+            "/admin/clusters/{cluster}/retry",
+            web::post().to(retry_cluster),
+        )
+        .route("/admin/warmup/{cluster}", web::get().to(warmup_progress))
+        .route(
+            "/admin/warmup/{cluster}/start",
+            web::post().to(start_warmup),
+        )
+        .route("/admin/warmup/{cluster}/stop", web::post().to(stop_warmup))
+        .route("/admin/slots/{cluster}", web::get().to(hot_slots))
+        .route("/admin/slots/{cluster}/reset", web::post().to(reset_slots))
+        .route(
+            "/admin/drain/{cluster}/clients",
+            web::get().to(drain_progress),
+        )
+        .route(
+            "/admin/drain/{cluster}/clients",
+            web::post().to(drain_clients),
+        )
+        .route("/admin/slo/{cluster}", web::get().to(slo_status))
+        .route("/admin/weights/{cluster}", web::get().to(weights))
+        .route("/admin/doctor/{cluster}", web::get().to(diagnose))
+        .route("/admin/checktopo/{cluster}", web::get().to(check_topology))
+        .route("/admin/ring/{cluster}/diff", web::post().to(ring_diff));
+}
+
+fn failback(cluster: web::Path<String>) -> impl Responder {
+    info!(
+        "admin request failback from standby for cluster {}",
+        cluster
+    );
+    failover::request_failback(&cluster);
+    HttpResponse::Ok().body(format!("failback requested for cluster {}\n", cluster))
+}
+
+fn read_only(path: web::Path<(String, String)>) -> impl Responder {
+    let (cluster, mode) = path.into_inner();
+    let enable = match mode.as_str() {
+        "on" => true,
+        "off" => false,
+        _ => return HttpResponse::BadRequest().body("mode must be on or off\n"),
+    };
+    if !readonly::set_read_only(&cluster, enable) {
+        return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster));
+    }
+    info!(
+        "admin set read-only mode of cluster {} to {}",
+        cluster, mode
+    );
+    HttpResponse::Ok().body(format!("cluster {} read-only mode is {}\n", cluster, mode))
+}
+
+fn maintenance(path: web::Path<(String, String)>) -> impl Responder {
+    let (cluster, mode) = path.into_inner();
+    let enable = match mode.as_str() {
+        "on" => true,
+        "off" => false,
+        _ => return HttpResponse::BadRequest().body("mode must be on or off\n"),
+    };
+    if !maintenance::set_maintenance(&cluster, enable) {
+        return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster));
+    }
+    warn!(
+        "admin set maintenance mode of cluster {} to {}",
+        cluster, mode
+    );
+    HttpResponse::Ok().body(format!(
+        "cluster {} maintenance mode is {}\n",
+        cluster, mode
+    ))
+}
+
+fn backend(path: web::Path<(String, String)>) -> impl Responder {
+    let (cluster, node) = path.into_inner();
+    match drain::get_state(&cluster, &node) {
+        Some(state) => HttpResponse::Ok().body(format!("{}\n", state.as_str())),
+        None => HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+    }
+}
+
+fn set_backend(path: web::Path<(String, String, String)>) -> impl Responder {
+    let (cluster, node, state) = path.into_inner();
+    let state = match NodeState::parse(&state) {
+        Some(state) => state,
+        None => {
+            return HttpResponse::BadRequest().body("state must be active, draining or disabled\n")
+        }
+    };
+    if !drain::set_state(&cluster, &node, state) {
+        return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster));
+    }
+    info!(
+        "admin set backend {} of cluster {} to {}",
+        node,
+        cluster,
+        state.as_str()
+    );
+    HttpResponse::Ok().body(format!(
+        "backend {} of cluster {} is {}\n",
+        node,
+        cluster,
+        state.as_str()
+    ))
+}
+
+fn start_capture(cluster: web::Path<String>, opt: web::Query<CaptureOption>) -> impl Responder {
+    let opt = opt.into_inner();
+    let path = opt.path.clone();
+    match capture::start(&cluster, opt) {
+        Ok(true) => {
+            info!("admin start capture of cluster {} into {}", cluster, path);
+            HttpResponse::Ok().body(format!("capture cluster {} into {}\n", cluster, path))
+        }
+        Ok(false) => HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+        Err(err) => HttpResponse::BadRequest().body(format!("{}\n", err)),
+    }
+}
+
+fn stop_capture(cluster: web::Path<String>) -> impl Responder {
+    if !capture::stop(&cluster) {
+        return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster));
+    }
+    info!("admin stop capture of cluster {}", cluster);
+    HttpResponse::Ok().body(format!("capture of cluster {} is stopped\n", cluster))
+}
+
+fn fault_reply(cluster: &str, fault: &str, rslt: Result<bool, AsError>) -> HttpResponse {
+    match rslt {
+        Ok(true) => {
+            warn!("admin inject {} fault into cluster {}", fault, cluster);
+            HttpResponse::Ok().body(format!(
+                "{} fault injected into cluster {}\n",
+                fault, cluster
+            ))
+        }
+        Ok(false) => HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+        Err(err) => HttpResponse::BadRequest().body(format!("{}\n", err)),
+    }
+}
+
+fn fault_latency(cluster: web::Path<String>, opt: web::Query<LatencyFault>) -> impl Responder {
+    fault_reply(
+        &cluster,
+        "latency",
+        fault::set_latency(&cluster, opt.into_inner()),
+    )
+}
+
+fn fault_error(cluster: web::Path<String>, opt: web::Query<ErrorFault>) -> impl Responder {
+    fault_reply(
+        &cluster,
+        "error",
+        fault::set_error(&cluster, opt.into_inner()),
+    )
+}
+
+fn fault_down(cluster: web::Path<String>, opt: web::Query<DownFault>) -> impl Responder {
+    fault_reply(
+        &cluster,
+        "down",
+        fault::set_down(&cluster, opt.into_inner()),
+    )
+}
+
+fn fault_clear(cluster: web::Path<String>) -> impl Responder {
+    if !fault::clear(&cluster) {
+        return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster));
+    }
+    warn!("admin clear all faults of cluster {}", cluster);
+    HttpResponse::Ok().body(format!("faults of cluster {} are cleared\n", cluster))
+}
+
+fn health() -> impl Responder {
+    let failed = startup::failed();
+    if failed.is_empty() {
+        return HttpResponse::Ok().body("ready\n");
+    }
+    HttpResponse::ServiceUnavailable().body(format!("failed clusters: {}\n", failed.join(",")))
+}
+
+fn clusters() -> impl Responder {
+    let body: String = startup::list()
+        .into_iter()
+        .map(|(name, status)| format!("{} {}\n", name, status))
+        .collect();
+    HttpResponse::Ok().body(body)
+}
+
+fn retry_cluster(cluster: web::Path<String>) -> impl Responder {
+    match startup::retry(&cluster) {
+        // the workers of cluster keep running without the handle
+        Ok(_handle) => {
+            info!("admin retry to start cluster {} succeed", cluster);
+            HttpResponse::Ok().body(format!("cluster {} is running\n", cluster))
+        }
+        Err(err) => HttpResponse::BadRequest().body(format!("{}\n", err)),
+    }
+}
+
+fn start_warmup(cluster: web::Path<String>, opt: web::Query<WarmupOption>) -> impl Responder {
+    let cc = match reload::cluster(&cluster) {
+        Some(cc) => cc,
+        None => return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+    };
+    match warmup::start(&cc, opt.into_inner()) {
+        Ok(()) => {
+            info!("admin start warm-up of cluster {}", cluster);
+            HttpResponse::Ok().body(format!("warm-up of cluster {} is started\n", cluster))
+        }
+        Err(err) => HttpResponse::BadRequest().body(format!("{}\n", err)),
+    }
+}
+
+fn stop_warmup(cluster: web::Path<String>) -> impl Responder {
+    if !warmup::stop(&cluster) {
+        return HttpResponse::NotFound()
+            .body(format!("no warm-up of cluster {} running\n", cluster));
+    }
+    info!("admin abort warm-up of cluster {}", cluster);
+    HttpResponse::Ok().body(format!("warm-up of cluster {} is aborted\n", cluster))
+}
+
+fn warmup_progress(cluster: web::Path<String>) -> impl Responder {
+    match warmup::progress(&cluster) {
+        Some(progress) => HttpResponse::Ok().body(format!("{}\n", progress)),
+        None => HttpResponse::NotFound().body(format!("no warm-up of cluster {}\n", cluster)),
+    }
+}
+
+fn hot_slots(cluster: web::Path<String>, opt: web::Query<TopOption>) -> impl Responder {
+    let stats = match slotstat::get(&cluster) {
+        Some(stats) => stats,
+        None => {
+            return HttpResponse::NotFound()
+                .body(format!("cluster {} not found in cluster mode\n", cluster))
+        }
+    };
+    let body: String = stats
+        .top(opt.top())
+        .into_iter()
+        .map(|x| format!("{} {} {}\n", x.slot, x.requests, x.errors))
+        .collect();
+    HttpResponse::Ok().body(body)
+}
+
+fn reset_slots(cluster: web::Path<String>) -> impl Responder {
+    let stats = match slotstat::get(&cluster) {
+        Some(stats) => stats,
+        None => {
+            return HttpResponse::NotFound()
+                .body(format!("cluster {} not found in cluster mode\n", cluster))
+        }
+    };
+    match stats.reset() {
+        Ok(()) => {
+            warn!("admin reset slot stats of cluster {}", cluster);
+            HttpResponse::Ok().body(format!("slot stats of cluster {} are reset\n", cluster))
+        }
+        Err(err) => HttpResponse::InternalServerError().body(format!("{}\n", err)),
+    }
+}
+
+fn drain_clients(cluster: web::Path<String>, opt: web::Query<DrainOption>) -> impl Responder {
+    let clients = match clients::get(&cluster) {
+        Some(clients) => clients,
+        None => return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+    };
+    match clients.drain(&cluster, opt.into_inner()) {
+        Ok(()) => {
+            warn!("admin start draining clients of cluster {}", cluster);
+            HttpResponse::Ok().body(format!("clients of cluster {} are draining\n", cluster))
+        }
+        Err(err) => HttpResponse::BadRequest().body(format!("{}\n", err)),
+    }
+}
+
+fn drain_progress(cluster: web::Path<String>) -> impl Responder {
+    match clients::get(&cluster) {
+        Some(clients) => HttpResponse::Ok().body(format!("{}\n", clients.progress())),
+        None => HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+    }
+}
+
+fn slo_status(cluster: web::Path<String>) -> impl Responder {
+    let slo = match slo::get(&cluster) {
+        Some(slo) => slo,
+        None => return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+    };
+    let body: String = slo
+        .status()
+        .into_iter()
+        .map(|x| format!("{}\n", x))
+        .collect();
+    HttpResponse::Ok().body(body)
+}
+
+fn weights(cluster: web::Path<String>) -> impl Responder {
+    let weights = match transition::weights(&cluster) {
+        Some(weights) => weights,
+        None => {
+            return HttpResponse::NotFound()
+                .body(format!("cluster {} not found in proxy mode\n", cluster))
+        }
+    };
+    let body: String = weights
+        .into_iter()
+        .map(|(node, effective, target)| format!("{} {} {}\n", node, effective, target))
+        .collect();
+    HttpResponse::Ok().body(body)
+}
+
+fn diagnose(cluster: web::Path<String>) -> impl Responder {
+    let cc = match reload::cluster(&cluster) {
+        Some(cc) => cc,
+        None => return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+    };
+    let report = doctor::diagnose(&cc);
+    if report.is_failed() {
+        return HttpResponse::ServiceUnavailable().body(report.to_string());
+    }
+    HttpResponse::Ok().body(report.to_string())
+}
+
+fn check_topology(cluster: web::Path<String>) -> impl Responder {
+    let cc = match reload::cluster(&cluster) {
+        Some(cc) => cc,
+        None => return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+    };
+    let report = doctor::check_topology(&cc);
+    if report.is_failed() {
+        return HttpResponse::ServiceUnavailable().body(report.to_string());
+    }
+    HttpResponse::Ok().body(report.to_string())
+}
+
+fn ring_diff(
+    cluster: web::Path<String>,
+    opt: web::Query<RingDiffOption>,
+    body: String,
+) -> impl Responder {
+    let cc = match reload::cluster(&cluster) {
+        Some(cc) => cc,
+        None => return HttpResponse::NotFound().body(format!("cluster {} not found\n", cluster)),
+    };
+    match ringdiff::diff_by_option(&cc, &opt, &body) {
+        Ok(diff) => HttpResponse::Ok().body(diff.to_string()),
+        Err(err) => HttpResponse::BadRequest().body(format!("{}\n", err)),
+    }
+}
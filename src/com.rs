@@ -1,7 +1,10 @@
+use futures::Future;
 use net2::TcpBuilder;
 use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio::prelude::FutureExt;
+use tokio::timer::timeout;
 
 pub use failure::Error;
 
@@ -10,10 +13,39 @@ use std::env;
 use std::fs;
 use std::num;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 pub mod meta;
 
+use crate::metrics::{backend_connect_observe, HANDSHAKE_TCP};
+use crate::protocol::redis::ttl::TtlPolicies;
+use crate::protocol::redis::ReplyLimits;
+use crate::proxy::accesslog;
+use crate::proxy::acl;
+use crate::proxy::info;
+use crate::proxy::slo;
+use crate::proxy::standalone::adaptive;
+use crate::proxy::standalone::deadline::CommandTimeouts;
+use crate::proxy::standalone::dialect::BackendProtocols;
+use crate::proxy::standalone::hash::HashMethod;
+use crate::proxy::standalone::pin::Pins;
+use crate::proxy::standalone::respcache::Rules;
+use crate::proxy::standalone::subfail::SubFailures;
+use crate::proxy::standalone::tombstone::Tombstones;
+
 pub const ENV_ASTER_DEFAULT_THREADS: &str = "ASTER_DEFAULT_THREAD";
+pub const DEFAULT_MULTI_KEY_BATCH: usize = 1024;
+pub const DEFAULT_DEDUP_WINDOW: u64 = 5;
+pub const DEFAULT_BACKEND_QUEUE_LIMIT: usize = 1024 * 8;
+pub const DEFAULT_STALE_CONN_LIMIT: u8 = 3;
+pub const DEFAULT_PROTOCOL_ERROR_LIMIT: u8 = 3;
+pub const DEFAULT_RELOAD_DRAIN_TIMEOUT: u64 = 30_000;
+pub const DEFAULT_RESPONSE_CACHE_TTL: u64 = 100;
+pub const DEFAULT_RESPONSE_CACHE_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_WARMUP_RATE: u64 = 1000;
+pub const DEFAULT_BLOCKING_TIMEOUT_MAX: u64 = 1;
+pub const DEFAULT_WARMUP_TTL: u32 = 3600;
+pub const DEFAULT_LISTENER: &str = "default";
 
 #[derive(Debug, Fail)]
 pub enum AsError {
@@ -25,18 +57,51 @@ pub enum AsError {
     #[fail(display = "invalid message")]
     BadMessage,
 
+    #[fail(display = "ERR Protocol error: {}", _0)]
+    ProtocolError(String),
+
     #[fail(display = "message is ok but request bad or not allowed")]
     BadReqeust,
 
     #[fail(display = "request not supported")]
     RequestNotSupport,
 
+    #[fail(display = "READONLY proxy is in read-only mode")]
+    ReadOnly,
+
+    #[fail(display = "ERR proxy in maintenance")]
+    Maintenance,
+
+    #[fail(display = "ERR server shutting down")]
+    ShuttingDown,
+
+    #[fail(display = "ERR dangerous command {} is denied by proxy", _0)]
+    Dangerous(String),
+
+    #[fail(display = "NOAUTH Authentication required.")]
+    NoAuth,
+
+    #[fail(display = "WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+
+    #[fail(
+        display = "NOPERM User {} has no permissions to run the '{}' command",
+        _0, _1
+    )]
+    NoPermCommand(String, String),
+
+    #[fail(display = "NOPERM No permissions to access a key")]
+    NoPermKey,
+
     #[fail(display = "inline request don't support multi keys")]
     RequestInlineWithMultiKeys,
 
     #[fail(display = "message reply is bad")]
     BadReply,
 
+    #[fail(display = "reply of command is missing")]
+    ReplyMissing,
+
     #[fail(display = "proxy fail")]
     ProxyFail,
 
@@ -55,6 +120,9 @@ pub enum AsError {
     #[fail(display = "CLUSTER SLOTS must contains slot info")]
     WrongClusterSlotsReplySlot,
 
+    #[fail(display = "CLUSTER NODES replied with bad line {}", _0)]
+    WrongClusterNodesReply(String),
+
     #[fail(display = "cluster fail to proxy command")]
     ClusterFailDispatch,
 
@@ -67,12 +135,112 @@ pub enum AsError {
     #[fail(display = "fail to redirect command")]
     RedirectFailError,
 
+    #[fail(display = "backend {} replied out of order, connection closed", _0)]
+    ReplyMismatch(String),
+
+    #[fail(display = "ERR reply exceeds max_reply_size of {} bytes", _0)]
+    ReplyTooLarge(usize),
+
+    #[fail(
+        display = "ERR command not replied in {} millis of command_timeouts",
+        _0
+    )]
+    CommandTimeout(u64),
+
+    #[fail(display = "ERR deadline given by ASTER DEADLINE expired")]
+    DeadlineExpired,
+
+    #[fail(
+        display = "ERR queue timeout, not dispatched in {} millis of queue_timeout",
+        _0
+    )]
+    QueueTimeout(u64),
+
+    #[fail(display = "NOPROTO sorry, this protocol version is not supported")]
+    NoProto,
+
+    #[fail(display = "ERR too many arguments of command, max_args is {}", _0)]
+    TooManyArgs(usize),
+
+    #[fail(
+        display = "ERR too many subs of fan-out commands in flight, max_outstanding_subs is {}",
+        _0
+    )]
+    TooManySubs(usize),
+
+    // the wording of redis, memcache replies SERVER_ERROR object too large for cache
+    #[fail(display = "ERR Protocol error: invalid bulk length")]
+    ValueTooLarge(usize),
+
+    #[fail(display = "ERR Protocol error of backend reply: {}", _0)]
+    ReplyProtocolError(String),
+
     #[fail(display = "fail to init cluster {} due to all seed nodes is die", _0)]
     ClusterAllSeedsDie(String),
 
     #[fail(display = "fail to load config toml error {}", _0)]
     ConfigError(toml::de::Error), // de error
 
+    #[fail(display = "fail to capture traffic due to {}", _0)]
+    BadCapture(String),
+
+    #[fail(display = "fail to inject fault due to {}", _0)]
+    BadFault(String),
+
+    #[fail(display = "fail to warm up due to {}", _0)]
+    BadWarmup(String),
+
+    #[fail(display = "fail to load state due to {}", _0)]
+    BadState(String),
+
+    #[fail(display = "fail to drain clients due to {}", _0)]
+    BadDrain(String),
+
+    #[fail(display = "ERR injected")]
+    Injected,
+
+    #[fail(display = "ERR injected backend {} down", _0)]
+    InjectedDown(String),
+
+    #[fail(display = "fail to spawn cluster {}", _0)]
+    SpawnFail(String),
+
+    #[fail(display = "clusters {} never started", _0)]
+    PartialStart(String),
+
+    #[fail(display = "doctor found failed checks of clusters {}", _0)]
+    DoctorFail(String),
+
+    #[fail(display = "config {} needs feature {} which is compiled out", _0, _1)]
+    CompiledOut(String, &'static str),
+
+    #[fail(display = "client output buffer limit exceeded by {} bytes", _0)]
+    OutputBufferLimit(usize),
+
+    #[fail(display = "ERR {}", _0)]
+    Rejected(String),
+
+    #[fail(display = "ERR backend {} is overloaded", _0)]
+    BackendOverloaded(String),
+
+    #[fail(display = "ERR backend {} is ejected", _0)]
+    BackendEjected(String),
+
+    #[fail(
+        display = "CROSSSLOT SORT pattern {} may reference keys on other nodes",
+        _0
+    )]
+    SortCrossKey(String),
+
+    #[fail(display = "CROSSSLOT Keys in request don't hash to the same slot")]
+    CrossSlot,
+
+    #[fail(display = "ERR {}", _0)]
+    BadProxyCommand(String),
+
+    #[fail(display = "ERR {}", _0)]
+    BadClientCommand(String),
+
     #[fail(display = "fail to load system info")]
     SystemError,
 
@@ -87,15 +255,43 @@ impl PartialEq for AsError {
             (Self::BadMessage, Self::BadMessage) => true,
             (Self::BadReqeust, Self::BadReqeust) => true,
             (Self::RequestNotSupport, Self::RequestNotSupport) => true,
+            (Self::ReadOnly, Self::ReadOnly) => true,
+            (Self::Maintenance, Self::Maintenance) => true,
+            (Self::ShuttingDown, Self::ShuttingDown) => true,
+            (Self::Dangerous(inner), Self::Dangerous(other_inner)) => inner == other_inner,
+            (Self::NoAuth, Self::NoAuth) => true,
+            (Self::WrongPass, Self::WrongPass) => true,
+            (Self::NoPermCommand(user, name), Self::NoPermCommand(other_user, other_name)) => {
+                user == other_user && name == other_name
+            }
+            (Self::NoPermKey, Self::NoPermKey) => true,
             (Self::RequestInlineWithMultiKeys, Self::RequestInlineWithMultiKeys) => true,
             (Self::BadReply, Self::BadReply) => true,
+            (Self::ReplyMissing, Self::ReplyMissing) => true,
             (Self::ProxyFail, Self::ProxyFail) => true,
             (Self::RequestReachMaxCycle, Self::RequestReachMaxCycle) => true,
             (Self::ParseIntError(inner), Self::ParseIntError(other_inner)) => inner == other_inner,
             (Self::WrongClusterSlotsReplyType, Self::WrongClusterSlotsReplyType) => true,
             (Self::WrongClusterSlotsReplySlot, Self::WrongClusterSlotsReplySlot) => true,
+            (Self::WrongClusterNodesReply(inner), Self::WrongClusterNodesReply(other_inner)) => {
+                inner == other_inner
+            }
             (Self::ClusterFailDispatch, Self::ClusterFailDispatch) => true,
             (Self::RedirectFailError, Self::RedirectFailError) => true,
+            (Self::ReplyMismatch(inner), Self::ReplyMismatch(other_inner)) => inner == other_inner,
+            (Self::ReplyTooLarge(inner), Self::ReplyTooLarge(other_inner)) => inner == other_inner,
+            (Self::CommandTimeout(inner), Self::CommandTimeout(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::DeadlineExpired, Self::DeadlineExpired) => true,
+            (Self::QueueTimeout(inner), Self::QueueTimeout(other_inner)) => inner == other_inner,
+            (Self::NoProto, Self::NoProto) => true,
+            (Self::TooManyArgs(inner), Self::TooManyArgs(other_inner)) => inner == other_inner,
+            (Self::TooManySubs(inner), Self::TooManySubs(other_inner)) => inner == other_inner,
+            (Self::ValueTooLarge(inner), Self::ValueTooLarge(other_inner)) => inner == other_inner,
+            (Self::ReplyProtocolError(inner), Self::ReplyProtocolError(other_inner)) => {
+                inner == other_inner
+            }
             (Self::BackendClosedError(inner), Self::BackendClosedError(other_inner)) => {
                 inner == other_inner
             }
@@ -110,6 +306,38 @@ impl PartialEq for AsError {
                 inner.kind() == other_inner.kind()
             }
             (Self::ConfigError(_), Self::ConfigError(_)) => true,
+            (Self::BadCapture(inner), Self::BadCapture(other_inner)) => inner == other_inner,
+            (Self::BadFault(inner), Self::BadFault(other_inner)) => inner == other_inner,
+            (Self::BadWarmup(inner), Self::BadWarmup(other_inner)) => inner == other_inner,
+            (Self::BadState(inner), Self::BadState(other_inner)) => inner == other_inner,
+            (Self::BadDrain(inner), Self::BadDrain(other_inner)) => inner == other_inner,
+            (Self::Injected, Self::Injected) => true,
+            (Self::InjectedDown(inner), Self::InjectedDown(other_inner)) => inner == other_inner,
+            (Self::SpawnFail(inner), Self::SpawnFail(other_inner)) => inner == other_inner,
+            (Self::PartialStart(inner), Self::PartialStart(other_inner)) => inner == other_inner,
+            (Self::DoctorFail(inner), Self::DoctorFail(other_inner)) => inner == other_inner,
+            (Self::CompiledOut(field, feature), Self::CompiledOut(other_field, other_feature)) => {
+                field == other_field && feature == other_feature
+            }
+            (Self::OutputBufferLimit(inner), Self::OutputBufferLimit(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::Rejected(inner), Self::Rejected(other_inner)) => inner == other_inner,
+            (Self::BackendOverloaded(inner), Self::BackendOverloaded(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::BackendEjected(inner), Self::BackendEjected(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::SortCrossKey(inner), Self::SortCrossKey(other_inner)) => inner == other_inner,
+            (Self::CrossSlot, Self::CrossSlot) => true,
+            (Self::BadProxyCommand(inner), Self::BadProxyCommand(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::BadClientCommand(inner), Self::BadClientCommand(other_inner)) => {
+                inner == other_inner
+            }
+            (Self::ProtocolError(inner), Self::ProtocolError(other_inner)) => inner == other_inner,
             (Self::SystemError, Self::SystemError) => true,
             (Self::ConnClosed(addr1), Self::ConnClosed(addr2)) => addr1 == addr2,
             _ => false,
@@ -117,6 +345,59 @@ impl PartialEq for AsError {
     }
 }
 
+impl AsError {
+    /// error class for metrics, timeout/backend_closed/backend_error are caused by backend,
+    /// injected is caused by fault injection, deadline is given by client, queue is the wait
+    /// before dispatched, and the others are caused by proxy itself or bad request.
+    pub fn class(&self) -> &'static str {
+        use std::io::ErrorKind;
+
+        match self {
+            AsError::IoError(err)
+                if err.kind() == ErrorKind::TimedOut || err.kind() == ErrorKind::WouldBlock =>
+            {
+                "timeout"
+            }
+            AsError::CommandTimeout(_) => "timeout",
+            AsError::QueueTimeout(_) => "queue",
+            AsError::DeadlineExpired => "deadline",
+            AsError::IoError(_) | AsError::BackendClosedError(_) | AsError::ConnClosed(_) => {
+                "backend_closed"
+            }
+            AsError::BadReply
+            | AsError::ReplyMismatch(_)
+            | AsError::ReplyTooLarge(_)
+            | AsError::ReplyProtocolError(_)
+            | AsError::WrongClusterSlotsReplyType
+            | AsError::WrongClusterSlotsReplySlot
+            | AsError::WrongClusterNodesReply(_)
+            | AsError::ClusterAllSeedsDie(_) => "backend_error",
+            AsError::RequestNotSupport
+            | AsError::RequestInlineWithMultiKeys
+            | AsError::SortCrossKey(_)
+            | AsError::CrossSlot => "not_support",
+            AsError::ClusterFailDispatch
+            | AsError::RedirectFailError
+            | AsError::RequestReachMaxCycle => "redirect",
+            AsError::Injected | AsError::InjectedDown(_) => "injected",
+            AsError::Rejected(_)
+            | AsError::TooManyArgs(_)
+            | AsError::TooManySubs(_)
+            | AsError::ValueTooLarge(_)
+            | AsError::BackendOverloaded(_)
+            | AsError::BackendEjected(_)
+            | AsError::Maintenance
+            | AsError::ShuttingDown
+            | AsError::Dangerous(_)
+            | AsError::NoAuth
+            | AsError::WrongPass
+            | AsError::NoPermCommand(_, _)
+            | AsError::NoPermKey => "rejected",
+            _ => "proxy",
+        }
+    }
+}
+
 impl From<tokio::io::Error> for AsError {
     fn from(oe: tokio::io::Error) -> AsError {
         AsError::IoError(oe)
@@ -141,10 +422,31 @@ impl From<num::ParseIntError> for AsError {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct Config {
     #[serde(default)]
     pub clusters: Vec<ClusterConfig>,
+    // the pipelines the metrics are pushed to besides /metrics, never changed by hot reload
+    #[serde(default)]
+    pub exporters: Vec<ExporterConfig>,
+}
+
+/// the metrics exporter, see metrics::exporter.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExporterConfig {
+    pub kind: ExporterKind,
+    // host:port of statsd, or url of otlp over http e.g.: http://127.0.0.1:4318/v1/metrics
+    pub endpoint: String,
+    // millis between pushes, 10s by default
+    pub interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ExporterKind {
+    #[serde(rename = "statsd")]
+    Statsd,
+    #[serde(rename = "otlp")]
+    Otlp,
 }
 
 impl Config {
@@ -176,6 +478,436 @@ impl Config {
     }
 
     pub fn valid(&self) -> Result<(), AsError> {
+        self.valid_exporters()?;
+        for cluster in &self.clusters {
+            if !cluster.cache_type.is_compiled() {
+                return Err(AsError::CompiledOut(
+                    format!("{}.cache_type", cluster.name),
+                    cluster.cache_type.feature(),
+                ));
+            }
+            let is_redis = match cluster.cache_type {
+                CacheType::Redis => true,
+                _ => false,
+            };
+            let is_memcache = match cluster.cache_type {
+                CacheType::Memcache | CacheType::MemcacheBinary => true,
+                _ => false,
+            };
+            if cluster.key_prefix.is_some() && !is_redis {
+                return Err(AsError::BadConfig(format!(
+                    "{}.key_prefix only support cache_type redis",
+                    cluster.name
+                )));
+            }
+            if !cluster.ttl_policies.is_empty() {
+                if !is_redis {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.ttl_policies only support cache_type redis",
+                        cluster.name
+                    )));
+                }
+                TtlPolicies::new(&cluster.ttl_policies)?;
+            }
+            if !cluster.info_sections.is_empty() {
+                if is_memcache {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.info_sections only support cache_type redis and redis_cluster",
+                        cluster.name
+                    )));
+                }
+                info::check_sections(&cluster.info_sections)?;
+            }
+            if !cluster.compat.is_empty() && is_memcache {
+                return Err(AsError::BadConfig(format!(
+                    "{}.compat only support cache_type redis and redis_cluster",
+                    cluster.name
+                )));
+            }
+            if cluster.lenient_newline.is_some() && !is_memcache {
+                return Err(AsError::BadConfig(format!(
+                    "{}.lenient_newline only support cache_type memcache",
+                    cluster.name
+                )));
+            }
+            if cluster.admin_node.is_some() && !is_redis {
+                return Err(AsError::BadConfig(format!(
+                    "{}.admin_node only support cache_type redis",
+                    cluster.name
+                )));
+            }
+            if cluster.function_broadcast.is_some() && !is_redis {
+                return Err(AsError::BadConfig(format!(
+                    "{}.function_broadcast only support cache_type redis",
+                    cluster.name
+                )));
+            }
+            if cluster.proxy_admin.unwrap_or(false) && !is_redis {
+                return Err(AsError::BadConfig(format!(
+                    "{}.proxy_admin only support cache_type redis",
+                    cluster.name
+                )));
+            }
+            if !cluster.users.is_empty() && is_memcache {
+                return Err(AsError::BadConfig(format!(
+                    "{}.users only support redis",
+                    cluster.name
+                )));
+            }
+            acl::validate(&cluster.users).map_err(|reason| {
+                AsError::BadConfig(format!("{}.users {}", cluster.name, reason))
+            })?;
+            slo::validate(&cluster.slo)
+                .map_err(|reason| AsError::BadConfig(format!("{}.slo {}", cluster.name, reason)))?;
+            if cluster.max_reply_size.is_some() || !cluster.max_reply_size_overrides.is_empty() {
+                if !is_redis {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.max_reply_size only support cache_type redis",
+                        cluster.name
+                    )));
+                }
+                ReplyLimits::from_config(cluster)?;
+            }
+            match cluster.cache_type {
+                CacheType::Redis | CacheType::RedisCluster => {}
+                _ if cluster.backend_flavor.is_some() => {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.backend_flavor only support cache_type redis and redis_cluster",
+                        cluster.name
+                    )));
+                }
+                _ if cluster.blocking_commands.is_some() => {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.blocking_commands only support cache_type redis and redis_cluster",
+                        cluster.name
+                    )));
+                }
+                _ => {}
+            }
+            if cluster.warmup_keys.is_some() || cluster.warmup_donor.is_some() {
+                match cluster.cache_type {
+                    CacheType::Memcache => {}
+                    _ => {
+                        return Err(AsError::BadConfig(format!(
+                            "{}.warmup_keys only support cache_type memcache",
+                            cluster.name
+                        )));
+                    }
+                }
+                if cluster.warmup_rate == Some(0) {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.warmup_rate must be greater than 0",
+                        cluster.name
+                    )));
+                }
+            }
+            if cluster.blocking_commands == Some(BlockingCommands::Bounded) {
+                if cluster.blocking_timeout_max == Some(0) {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.blocking_timeout_max must be greater than 0",
+                        cluster.name
+                    )));
+                }
+                // the connection blocked longer than read_timeout is taken as stale
+                let max = cluster.blocking_timeout_max() * 1000;
+                if cluster.read_timeout.map(|x| x <= max).unwrap_or(false) {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.blocking_timeout_max must be less than read_timeout",
+                        cluster.name
+                    )));
+                }
+                // the keys in the replies are never stripped from the namespace
+                if cluster.key_prefix.is_some() {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.blocking_commands bounded doesn't support key_prefix",
+                        cluster.name
+                    )));
+                }
+            }
+            if cluster.tcp_keepalive_interval == Some(0) || cluster.tcp_keepalive_count == Some(0) {
+                return Err(AsError::BadConfig(format!(
+                    "{}.tcp_keepalive_interval and tcp_keepalive_count must be greater than 0",
+                    cluster.name
+                )));
+            }
+            if cluster.accept_batch == Some(0) {
+                return Err(AsError::BadConfig(format!(
+                    "{}.accept_batch must be greater than 0",
+                    cluster.name
+                )));
+            }
+            if let Some(pacing) = cluster.accept_pacing {
+                if pacing <= 0.0 || pacing >= 1.0 {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.accept_pacing must be between 0 and 1",
+                        cluster.name
+                    )));
+                }
+            }
+            if cluster.retry_budget.unwrap_or(0) > 100 {
+                return Err(AsError::BadConfig(format!(
+                    "{}.retry_budget must be a percent not greater than 100",
+                    cluster.name
+                )));
+            }
+            if cluster.backend_queue_limit == Some(0) {
+                return Err(AsError::BadConfig(format!(
+                    "{}.backend_queue_limit must be greater than 0",
+                    cluster.name
+                )));
+            }
+            let is_proxy = match cluster.cache_type {
+                CacheType::RedisCluster => false,
+                _ => true,
+            };
+            if let Some(hash_tag) = cluster.hash_tag.as_ref() {
+                if hash_tag.len() != 2 {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.hash_tag must be two characters of open and close, e.g.: \"{{}}\"",
+                        cluster.name
+                    )));
+                }
+                if !is_proxy && hash_tag != "{}" {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.hash_tag must be \"{{}}\" in redis_cluster mode",
+                        cluster.name
+                    )));
+                }
+            }
+            if !cluster.listeners.is_empty() && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.listeners only support proxy mode",
+                    cluster.name
+                )));
+            }
+            let mut names = BTreeSet::new();
+            for listener in &cluster.listeners {
+                let bad = |reason: &str| {
+                    AsError::BadConfig(format!(
+                        "{}.listeners {}: {}",
+                        cluster.name, listener.name, reason
+                    ))
+                };
+                if listener.name.is_empty() || listener.name == DEFAULT_LISTENER {
+                    return Err(bad("name must be given and not be default"));
+                }
+                if !names.insert(listener.name.as_str()) {
+                    return Err(bad("name is duplicated"));
+                }
+                let addr = listener
+                    .listen_addr
+                    .parse::<SocketAddr>()
+                    .map_err(|_| bad("listen_addr is bad"))?;
+                if addr.port() != 0 && listener.listen_addr == cluster.listen_addr {
+                    return Err(bad("listen_addr is the same as the cluster"));
+                }
+                if listener.protocol.is_some() && is_redis {
+                    return Err(bad("protocol only support cache_type memcache"));
+                }
+            }
+            if cluster.replica_strategy.is_some() && is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.replica_strategy only support cluster mode",
+                    cluster.name
+                )));
+            }
+            if cluster.slot_stats_file.is_some() && is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.slot_stats_file only support cluster mode",
+                    cluster.name
+                )));
+            }
+            if cluster.replica_read_consistency.is_some() && is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.replica_read_consistency only support cluster mode",
+                    cluster.name
+                )));
+            }
+            if cluster.backend_idle_timeout.unwrap_or(0) > 0 && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.backend_idle_timeout only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.backend_max_lifetime.unwrap_or(0) > 0 && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.backend_max_lifetime only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.adaptive_timeout.unwrap_or(false) && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.adaptive_timeout only support proxy mode",
+                    cluster.name
+                )));
+            }
+            adaptive::validate(cluster)
+                .map_err(|reason| AsError::BadConfig(format!("{}.{}", cluster.name, reason)))?;
+            if cluster.reload_drain_timeout.is_some() && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.reload_drain_timeout only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.weight_transition.unwrap_or(0) > 0 && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.weight_transition only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.self_probe_interval.unwrap_or(0) > 0 && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.self_probe_interval only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if !cluster.dedup_writes.is_empty() && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.dedup_writes only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.retry_on_stale.is_some() && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.retry_on_stale only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.keyless_policy.is_some() && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.keyless_policy only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.max_memory.is_some() && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.max_memory only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if !cluster.pin_keys.is_empty() {
+                if !is_proxy {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.pin_keys only support proxy mode",
+                        cluster.name
+                    )));
+                }
+                Pins::new(&cluster.pin_keys, &cluster.servers)?;
+            }
+            if !cluster.tombstone_commands.is_empty() {
+                if !is_proxy {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.tombstone_commands only support proxy mode",
+                        cluster.name
+                    )));
+                }
+                Tombstones::new(&cluster.tombstone_commands)?;
+            }
+            if !cluster.command_timeouts.is_empty() {
+                if !is_proxy {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.command_timeouts only support proxy mode",
+                        cluster.name
+                    )));
+                }
+                CommandTimeouts::new(&cluster.command_timeouts)?;
+            }
+            if !cluster.sub_failures.is_empty() {
+                if !is_proxy {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.sub_failures only support proxy mode",
+                        cluster.name
+                    )));
+                }
+                SubFailures::new(&cluster.sub_failures)?;
+            }
+            if !cluster.backend_protocols.is_empty() {
+                if !is_redis {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.backend_protocols only support redis proxy mode",
+                        cluster.name
+                    )));
+                }
+                BackendProtocols::new(&cluster.backend_protocols)?;
+            }
+            if cluster.queue_timeout.unwrap_or(0) > 0 && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.queue_timeout only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.integrity_sample.unwrap_or(0) > 0 && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.integrity_sample only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if !cluster.response_cache.is_empty() {
+                if !is_proxy {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.response_cache only support proxy mode",
+                        cluster.name
+                    )));
+                }
+                Rules::new(&cluster.response_cache)?;
+                if cluster.response_cache_ttl == Some(0) || cluster.response_cache_size == Some(0) {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.response_cache_ttl and response_cache_size must be greater than 0",
+                        cluster.name
+                    )));
+                }
+            }
+            if cluster.hash.is_some() && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.hash only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.upstream_link.unwrap_or(false) && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.upstream_link only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if cluster.link_compression_level.unwrap_or(0) > 9 {
+                return Err(AsError::BadConfig(format!(
+                    "{}.link_compression_level must be in [0, 9]",
+                    cluster.name
+                )));
+            }
+            if cluster.access_log.is_some() && !is_proxy {
+                return Err(AsError::BadConfig(format!(
+                    "{}.access_log only support proxy mode",
+                    cluster.name
+                )));
+            }
+            if let Some(field) = cluster
+                .access_log_fields
+                .iter()
+                .find(|x| !accesslog::FIELDS.contains(&x.as_str()))
+            {
+                return Err(AsError::BadConfig(format!(
+                    "{}.access_log_fields unknown field {}",
+                    cluster.name, field
+                )));
+            }
+            if cluster.access_log_compression == Some(LogCompression::Zstd)
+                && !cfg!(feature = "zstd-log")
+            {
+                return Err(AsError::CompiledOut(
+                    format!("{}.access_log_compression", cluster.name),
+                    "zstd-log",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// the exporters push the metrics of prometheus, which is compiled out without metrics.
+    pub fn valid_exporters(&self) -> Result<(), AsError> {
+        if !self.exporters.is_empty() && !cfg!(feature = "metrics") {
+            return Err(AsError::CompiledOut("exporters".to_string(), "metrics"));
+        }
         Ok(())
     }
 
@@ -198,7 +930,7 @@ impl Config {
     }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
 pub enum CacheType {
     #[serde(rename = "redis")]
     Redis,
@@ -216,29 +948,574 @@ impl Default for CacheType {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Default)]
+impl CacheType {
+    /// the cargo feature serving the cache type, which may be compiled out of a minimal build.
+    pub fn feature(self) -> &'static str {
+        match self {
+            CacheType::Redis | CacheType::RedisCluster => "redis",
+            CacheType::Memcache | CacheType::MemcacheBinary => "memcache",
+        }
+    }
+
+    pub fn is_compiled(self) -> bool {
+        match self {
+            CacheType::Redis | CacheType::RedisCluster => cfg!(feature = "redis"),
+            CacheType::Memcache | CacheType::MemcacheBinary => cfg!(feature = "memcache"),
+        }
+    }
+}
+
+/// protocol of the memcache clients accepted by a listener.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum FrontProtocol {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "binary")]
+    Binary,
+}
+
+/// one more listener of the cluster, whose clients share the same backends, hash ring, health
+/// checks and connections with the others, e.g.: for the apps speaking another protocol.
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub listen_addr: String,
+    // memcache only, decided by the first message of each connection if absent
+    pub protocol: Option<FrontProtocol>,
+    // reject write commands of the clients of this listener
+    pub read_only: Option<bool>,
+}
+
+/// the latency objective of one command evaluated by the proxy, see proxy::slo.
+#[derive(Clone, Debug, Deserialize, Serialize, Default, PartialEq)]
+pub struct SloConfig {
+    // the command name, e.g.: GET
+    pub command: String,
+    // replies slower than latency millis (or errors) are bad
+    pub latency: u64,
+    // the percent of good replies, e.g.: 99.0
+    pub objective: f64,
+}
+
+/// one user of the proxy, see proxy::acl.
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
+pub struct UserConfig {
+    pub name: String,
+    // sha1 hex digest of the password
+    pub password: String,
+    // categories of commands allowed: read, write, admin and dangerous
+    #[serde(default)]
+    pub commands: Vec<String>,
+    // glob patterns of the keys allowed, no key is allowed if empty
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+/// selection among the replicas of one slot when read from slave.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReplicaStrategy {
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    #[serde(rename = "weighted_random")]
+    WeightedRandom,
+    #[serde(rename = "least_outstanding")]
+    LeastOutstanding,
+    #[serde(rename = "latency_weighted")]
+    LatencyWeighted,
+}
+
+impl Default for ReplicaStrategy {
+    fn default() -> ReplicaStrategy {
+        ReplicaStrategy::RoundRobin
+    }
+}
+
+/// the shim of replies for the legacy clients of redis, see protocol::redis::legacy.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum ClientCompat {
+    #[serde(rename = "inline_errors")]
+    InlineErrors,
+    #[serde(rename = "no_nested_arrays")]
+    NoNestedArrays,
+    #[serde(rename = "resp2_only")]
+    Resp2Only,
+}
+
+/// the consistency of the reads from replicas when read from slave.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReplicaReadConsistency {
+    #[serde(rename = "eventual")]
+    Eventual,
+    // read your writes within a connection
+    #[serde(rename = "session")]
+    Session,
+}
+
+impl Default for ReplicaReadConsistency {
+    fn default() -> ReplicaReadConsistency {
+        ReplicaReadConsistency::Eventual
+    }
+}
+
+/// the redis compatible store behind aster, see proxy::compat for the differences.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum BackendFlavor {
+    #[serde(rename = "redis")]
+    Redis,
+    #[serde(rename = "dragonfly")]
+    Dragonfly,
+    #[serde(rename = "keydb")]
+    KeyDB,
+    #[serde(rename = "garnet")]
+    Garnet,
+}
+
+impl Default for BackendFlavor {
+    fn default() -> BackendFlavor {
+        BackendFlavor::Redis
+    }
+}
+
+/// the compression of the links between aster, see proxy::link.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum LinkCompression {
+    #[serde(rename = "deflate")]
+    Deflate,
+}
+
+impl Default for LinkCompression {
+    fn default() -> LinkCompression {
+        LinkCompression::Deflate
+    }
+}
+
+/// the compression of access log, see proxy::accesslog.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum LogCompression {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+impl Default for LogCompression {
+    fn default() -> LogCompression {
+        LogCompression::None
+    }
+}
+
+/// what to do with the requests to a backend whose queue is full.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum BackendOverload {
+    // wait for the backend, without blocking the requests to the others
+    #[serde(rename = "queue")]
+    Queue,
+    // fail at once with the error of overloaded backend
+    #[serde(rename = "fail")]
+    Fail,
+}
+
+impl Default for BackendOverload {
+    fn default() -> BackendOverload {
+        BackendOverload::Queue
+    }
+}
+
+/// how the commands without key (e.g.: version of memcache) are routed in proxy mode.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum KeylessPolicy {
+    // answered by proxy itself where it's implemented, the others are spread as random
+    #[serde(rename = "local")]
+    Local,
+    // spread in turn across the healthy backends
+    #[serde(rename = "random")]
+    Random,
+    // always routed to the backend owning the hash of empty key
+    #[serde(rename = "pinned")]
+    Pinned,
+}
+
+impl Default for KeylessPolicy {
+    fn default() -> KeylessPolicy {
+        KeylessPolicy::Random
+    }
+}
+
+/// policy of the blocking commands (e.g.: BLPOP), which hold the backend connection shared by
+/// all the clients until replied.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum BlockingCommands {
+    #[serde(rename = "deny")]
+    Deny,
+    #[serde(rename = "warn")]
+    Warn,
+    // the timeout of them is capped by blocking_timeout_max
+    #[serde(rename = "bounded")]
+    Bounded,
+}
+
+impl Default for BlockingCommands {
+    fn default() -> BlockingCommands {
+        BlockingCommands::Deny
+    }
+}
+
+impl BlockingCommands {
+    pub fn check(self, cluster: &str, name: &str) -> Result<(), AsError> {
+        match self {
+            BlockingCommands::Deny => Err(AsError::RequestNotSupport),
+            BlockingCommands::Warn => {
+                warn!(
+                    "blocking command {} of cluster {} may stall the other clients",
+                    name, cluster
+                );
+                Ok(())
+            }
+            BlockingCommands::Bounded => Ok(()),
+        }
+    }
+}
+
+/// policy of SORT with BY/GET patterns which may reference the keys on other nodes.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum SortPatterns {
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "error")]
+    Error,
+}
+
+impl Default for SortPatterns {
+    fn default() -> SortPatterns {
+        SortPatterns::Warn
+    }
+}
+
+impl SortPatterns {
+    pub fn check(self, cluster: &str, pattern: &[u8]) -> Result<(), AsError> {
+        let pattern = String::from_utf8_lossy(pattern).to_string();
+        match self {
+            SortPatterns::Warn => {
+                warn!(
+                    "SORT pattern {} of cluster {} may reference keys on other nodes",
+                    pattern, cluster
+                );
+                Ok(())
+            }
+            SortPatterns::Error => Err(AsError::SortCrossKey(pattern)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Default)]
 pub struct ClusterConfig {
     pub name: String,
     pub listen_addr: String,
     pub hash_tag: Option<String>,
+    // hash function of keys in proxy mode, fnv1a_64 by default
+    pub hash: Option<HashMethod>,
 
     pub thread: Option<usize>,
     pub cache_type: CacheType,
+    // the redis compatible store of backends, redis by default
+    pub backend_flavor: Option<BackendFlavor>,
 
     pub read_timeout: Option<u64>,
     pub write_timeout: Option<u64>,
 
+    // TCP keepalive of the backend sockets, which finds out the dead peers (e.g.: dropped
+    // silently by NAT) even if no command is flowing. The idle time in seconds before the first
+    // probe, 0 or absent means disabled. The interval in seconds between probes and the count of
+    // probes unanswered before the connection is dropped are of OS if absent, linux only.
+    pub tcp_keepalive_idle: Option<u64>,
+    pub tcp_keepalive_interval: Option<u64>,
+    pub tcp_keepalive_count: Option<u32>,
+
+    // the connections accepted by one poll of listener, the rest are accepted after the other
+    // tasks of the worker, 64 by default
+    pub accept_batch: Option<usize>,
+    // accepting is paused while the worker is busier than the ratio (0, 1), absent means never
+    pub accept_pacing: Option<f64>,
+
     #[serde(default)]
     pub servers: Vec<String>,
 
     // cluster special
     pub fetch_interval: Option<u64>,
     pub read_from_slave: Option<bool>,
+    // selection among replicas of read_from_slave, round_robin by default
+    pub replica_strategy: Option<ReplicaStrategy>,
+    // weights of replicas by address for weighted_random and latency_weighted, 1 if absent
+    #[serde(default)]
+    pub replica_weights: BTreeMap<String, usize>,
+    // session routes the reads of the keys written by the same connection within
+    // replica_read_session_window millis to master, eventual by default
+    pub replica_read_consistency: Option<ReplicaReadConsistency>,
+    pub replica_read_session_window: Option<u64>,
+    // the recent writes remembered by each connection, the oldest are forgotten beyond it
+    pub replica_read_session_keys: Option<usize>,
 
     // proxy special
     pub ping_fail_limit: Option<u8>,
     pub ping_interval: Option<u64>,
     pub ping_succ_interval: Option<u64>,
+    // ping replied slower than the millis is a soft failure counted toward ejection, 0 or
+    // absent means disabled
+    pub ping_slow_threshold: Option<u64>,
+    // the connection is cycled once its requests are found waiting longer than read_timeout
+    // by the limit checks in a row, 3 by default and 0 means disabled
+    pub stale_conn_limit: Option<u8>,
+    // the timeout of the stall checks above adapts to the replies of each backend, which is
+    // mean + adaptive_timeout_k * stddev of their latency (4 by default) clamped into
+    // [adaptive_timeout_floor, adaptive_timeout_ceiling] millis, 100 and read_timeout by default
+    pub adaptive_timeout: Option<bool>,
+    pub adaptive_timeout_k: Option<f64>,
+    pub adaptive_timeout_floor: Option<u64>,
+    pub adaptive_timeout_ceiling: Option<u64>,
+    // time limits of the commands by name, e.g.: "MGET 3000", the command not replied in the
+    // millis since sent is failed and its reply is discarded, proxy mode only
+    #[serde(default)]
+    pub command_timeouts: Vec<String>,
+    // policies of the fan-out commands when any sub is failed by proxy, e.g.: "MGET best_effort",
+    // each is fail_fast, best_effort or retry, proxy mode only
+    #[serde(default)]
+    pub sub_failures: Vec<String>,
+    // protocols of the redis backends speaking other than RESP2, e.g.: "redis-2 resp3", the
+    // node is named by alias or address, proxy mode only
+    #[serde(default)]
+    pub backend_protocols: Vec<String>,
+    // the backend is ejected once its connections are closed by the violations of reply
+    // framing the limit times, 3 by default and 0 means disabled
+    pub protocol_error_limit: Option<u8>,
+    // the backend connection without requests for the millis is closed and reconnected on
+    // demand, 0 or absent means never closed
+    pub backend_idle_timeout: Option<u64>,
+    // the connections of each worker never closed by backend_idle_timeout, 0 by default
+    pub backend_min_idle: Option<usize>,
+    // the backend connection older than the millis is replaced and drained even if healthy,
+    // 0 or absent means never recycled
+    pub backend_max_lifetime: Option<u64>,
+    // the random millis added to the lifetime of each connection, a tenth of it by default
+    pub backend_lifetime_jitter: Option<u64>,
+    // millis to wait for the commands in flight to the backends removed by reload, the rest
+    // are failed with BackendClosed after it, 30000 by default
+    pub reload_drain_timeout: Option<u64>,
+
+    // standby backends, routing switch to them when primary is majority ejected
+    #[serde(default)]
+    pub standby: Vec<String>,
+    pub standby_fail_ratio: Option<f64>,
+    pub standby_fail_grace: Option<u64>,
+    // failback automatically after primary healthy for given millis, 0 or absent means
+    // failback only by admin api
+    pub standby_recover_after: Option<u64>,
+
+    // namespace prefixed to every key sent to backend, redis only
+    pub key_prefix: Option<String>,
+
+    // the max TTL in seconds of the writes of keys matched, and the default TTL given to (or
+    // reject) the ones without TTL, each is "${pattern} ${max} [${default}|reject]", redis only
+    #[serde(default)]
+    pub ttl_policies: Vec<String>,
+
+    // reject write commands without touching backend, togglable by admin api
+    pub read_only: Option<bool>,
+
+    // admin commands (e.g.: FAILOVER, REPLICAOF) are denied unless routed to this node, redis only
+    pub admin_node: Option<String>,
+    // FUNCTION commands are broadcast to every backend rather than denied, redis only
+    pub function_broadcast: Option<bool>,
+    // dangerous commands allowed to pass, e.g.: "SHUTDOWN", "CLUSTER RESET", "flush_all"
+    #[serde(default)]
+    pub allow_dangerous: Vec<String>,
+
+    // the users which the clients must AUTH as, and the commands and keys allowed to each of
+    // them, redis only
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+
+    // backends are added or removed at runtime by PROXY ADDNODE/DELNODE, redis only
+    pub proxy_admin: Option<bool>,
+    // the servers changed by PROXY commands are written back to the config file
+    pub proxy_admin_persist: Option<bool>,
+    // the sections of INFO answered by proxy: proxy, clients, stats and backends, the first
+    // three by default. redis and redis_cluster only
+    #[serde(default)]
+    pub info_sections: Vec<String>,
+
+    // max concurrent subs of one multi-key command, 0 means no limit
+    pub multi_key_batch: Option<usize>,
+
+    // max requests queued to be sent of each backend connection, 8192 by default
+    pub backend_queue_limit: Option<usize>,
+    // queue (default) or fail for the requests to the backend beyond the queue limit,
+    // e.g.: the subs of multi-key commands on a saturated shard
+    pub backend_overload: Option<BackendOverload>,
+    // millis the command may wait in proxy before dispatched (e.g.: queued by backend_overload),
+    // beyond which it's failed without sent, 0 or absent means no limit, proxy mode only
+    pub queue_timeout: Option<u64>,
+    // local, random (default) or pinned for the commands without key, proxy mode only
+    pub keyless_policy: Option<KeylessPolicy>,
+
+    // warm-up period in millis of newly added or recovered backends, 0 or absent means disabled
+    pub slow_start: Option<u64>,
+
+    // write commands deduplicated with the identical one in flight, proxy mode only
+    #[serde(default)]
+    pub dedup_writes: Vec<String>,
+    // window in millis since the first write which the duplicates can join
+    pub dedup_window: Option<u64>,
+
+    // commands in flight on an idle connection found reset or closed by backend before any
+    // reply are retried once on a new connection, proxy mode only
+    pub retry_on_stale: Option<bool>,
+    // retries of all kinds may not exceed the percent of the replies succeeded recently, beyond
+    // which the error is replied as is. 0 or absent means unlimited
+    pub retry_budget: Option<u64>,
+
+    // approximate bytes of all the connections, beyond which new connections are refused and
+    // the largest clients stop being read, and beyond the hard ceiling the most expensive client
+    // is closed. 0 or absent means no limit, proxy mode only
+    pub max_memory: Option<usize>,
+    pub max_memory_hard: Option<usize>,
+
+    // keys matched the glob pattern are always routed to the node, e.g.: "counter:* redis-1",
+    // the first matched wins, proxy mode only
+    #[serde(default)]
+    pub pin_keys: Vec<String>,
+
+    // reads of the keys of ejected nodes replied as if the keys are absent, e.g.: "GET",
+    // "HGETALL empty", "EXISTS zero", while the others are failed. The ejected nodes keep their
+    // hash range instead of moving it to the successors. Only for the caches where a miss is
+    // acceptable, proxy mode only
+    #[serde(default)]
+    pub tombstone_commands: Vec<String>,
+
+    // 1 in integrity_sample of the single key reads is read again on another connection of the
+    // same backend and the checksums are compared, a diagnostic of the silent corruption. 0 or
+    // absent means disabled, proxy mode only
+    pub integrity_sample: Option<u64>,
+
+    // latency objectives of commands whose burn rates are evaluated by the proxy in rolling
+    // windows, replaced by reload in proxy mode
+    #[serde(default)]
+    pub slo: Vec<SloConfig>,
+
+    // replies of reads matched "${command} ${pattern}" are cached for ttl millis by each
+    // worker, and dropped once the key is written through the proxy. proxy mode only
+    #[serde(default)]
+    pub response_cache: Vec<String>,
+    pub response_cache_ttl: Option<u64>,
+    // max bytes of keys and replies cached by each worker thread
+    pub response_cache_size: Option<usize>,
+
+    // warn (default) or error for SORT with BY/GET patterns which may reference other nodes
+    pub sort_patterns: Option<SortPatterns>,
+    // deny (default) or warn for the blocking commands, e.g.: BLPOP
+    pub blocking_commands: Option<BlockingCommands>,
+    // max seconds the blocking commands may block in bounded policy, 1 by default
+    pub blocking_timeout_max: Option<u64>,
+
+    // max bytes of replies pending to a slow client, the connection is closed at once
+    // beyond the hard limit, or beyond the soft limit for soft seconds. 0 or absent means no limit
+    pub output_buffer_hard_limit: Option<usize>,
+    pub output_buffer_soft_limit: Option<usize>,
+    pub output_buffer_soft_seconds: Option<u64>,
+    // the requests of a slow client are no more read once the bytes of replies pending to it
+    // exceed it, until it catches up. 0 or absent means never paced
+    pub reply_pacing: Option<usize>,
+
+    // max bytes of each reply read from backend, the command is failed and the connection is
+    // closed once its reply grows beyond. 0 or absent means no limit, redis only
+    pub max_reply_size: Option<usize>,
+    // limits of the given commands instead of max_reply_size, e.g.: "LRANGE 1073741824"
+    #[serde(default)]
+    pub max_reply_size_overrides: Vec<String>,
+
+    // max count of arguments of each command from client including the command name, the
+    // command beyond is rejected before fanned out. 0 or absent means no limit
+    pub max_args: Option<usize>,
+    // the client address of the command rejected by max_args is logged
+    pub max_args_log: Option<bool>,
+    // max count of the subs of fan-out commands (e.g.: MGET, DEL) in flight across all the
+    // worker threads, the fan-out command beyond is rejected before its subs are made. 0 or
+    // absent means no limit
+    pub max_outstanding_subs: Option<usize>,
+
+    // max size of each value written (each bulk argument of redis, the data block of memcache),
+    // the write beyond is rejected before sent to backends. 0 or absent means no limit
+    pub max_value_size: Option<usize>,
+    // the limits of backends are fetched at startup (proto-max-bulk-len by CONFIG GET of redis,
+    // item_size_max by stats settings of memcache), the minimum of them and max_value_size is
+    // enforced, the backends denying the query are limited by max_value_size only
+    pub probe_value_limit: Option<bool>,
+
+    // text requests ended by bare "\n" instead of "\r\n" are accepted and sent to backend
+    // ended by "\r\n", memcache only
+    pub lenient_newline: Option<bool>,
+
+    // shims of the replies for the legacy clients of redis, each one is toggled by itself:
+    // inline_errors, no_nested_arrays and resp2_only
+    #[serde(default)]
+    pub compat: Vec<ClientCompat>,
+
+    // listeners beyond listen_addr, proxy mode only
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+
+    // key-level access log of JSON lines written into the file, proxy mode only
+    pub access_log: Option<String>,
+    // fields of each line, all fields if empty
+    #[serde(default)]
+    pub access_log_fields: Vec<String>,
+    // replace every key by its digest
+    pub access_log_hash_keys: Option<bool>,
+    // the file is rotated once exceeds the size in bytes, and only max files are kept
+    pub access_log_max_size: Option<u64>,
+    pub access_log_max_files: Option<usize>,
+    // the lines are compressed by gzip or zstd as a stream, none by default
+    pub access_log_compression: Option<LogCompression>,
+
+    // manifest of keys warmed up on startup by GET from the donor (e.g.: the old fleet) and ADD
+    // into this cluster, memcache only
+    pub warmup_keys: Option<String>,
+    pub warmup_donor: Option<String>,
+    // max keys warmed up per second, 1000 by default
+    pub warmup_rate: Option<u64>,
+    // exptime in seconds of the warmed values, 3600 by default
+    pub warmup_ttl: Option<u32>,
+
+    // duration in millis of the transition of the weights changed by hot reload, the ring is
+    // rebuilt in steps of 5 seconds from the former weights to the new ones. 0 or absent means
+    // the new weights take effect at once, proxy mode only
+    pub weight_transition: Option<u64>,
+
+    // the backends are aster too (e.g.: the central proxy across datacenters), and the links to
+    // them are compressed if they answer the handshake, otherwise plain. proxy mode only
+    pub upstream_link: Option<bool>,
+    // compression of the links offered by upstream_link and accepted from other aster,
+    // deflate by default
+    pub link_compression: Option<LinkCompression>,
+    // level of the compression from 0 to 9, 6 by default
+    pub link_compression_level: Option<u32>,
+    // the bytes written at once fewer than it are framed uncompressed, 512 by default
+    pub link_min_frame: Option<usize>,
+
+    // interval in millis of the synthetic PING (version for memcache) sent through listen_addr
+    // by the proxy itself, whose round trip is observed by aster_self_probe_timer. 0 or absent
+    // means disabled, proxy mode only
+    pub self_probe_interval: Option<u64>,
+
+    // the requests and errors counted by each slot are saved into the file periodically and on
+    // graceful shutdown, and loaded on startup if it's saved by the cluster of the same name.
+    // cluster mode only
+    pub slot_stats_file: Option<String>,
+    // interval in seconds of saving, 60 by default
+    pub slot_stats_save_interval: Option<u64>,
+    // the file is ignored on startup, and the counters start from zero
+    pub slot_stats_reset: Option<bool>,
 
     // dead codes
 
@@ -251,6 +1528,129 @@ pub struct ClusterConfig {
     pub node_connections: Option<usize>,
 }
 
+impl ClusterConfig {
+    pub fn multi_key_batch(&self) -> usize {
+        self.multi_key_batch.unwrap_or(DEFAULT_MULTI_KEY_BATCH)
+    }
+
+    pub fn backend_queue_limit(&self) -> usize {
+        self.backend_queue_limit
+            .unwrap_or(DEFAULT_BACKEND_QUEUE_LIMIT)
+    }
+
+    /// the keepalive of backend sockets, None if disabled.
+    pub fn tcp_keepalive(&self) -> Option<Keepalive> {
+        match self.tcp_keepalive_idle {
+            Some(idle) if idle > 0 => Some(Keepalive {
+                idle: Duration::from_secs(idle),
+                interval: self.tcp_keepalive_interval.map(Duration::from_secs),
+                count: self.tcp_keepalive_count,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn stale_conn_limit(&self) -> u8 {
+        self.stale_conn_limit.unwrap_or(DEFAULT_STALE_CONN_LIMIT)
+    }
+
+    pub fn protocol_error_limit(&self) -> u8 {
+        self.protocol_error_limit
+            .unwrap_or(DEFAULT_PROTOCOL_ERROR_LIMIT)
+    }
+
+    pub fn backend_lifetime_jitter(&self) -> u64 {
+        self.backend_lifetime_jitter
+            .unwrap_or(self.backend_max_lifetime.unwrap_or(0) / 10)
+    }
+
+    pub fn reload_drain_timeout(&self) -> u64 {
+        self.reload_drain_timeout
+            .unwrap_or(DEFAULT_RELOAD_DRAIN_TIMEOUT)
+    }
+
+    pub fn dedup_window(&self) -> u64 {
+        self.dedup_window.unwrap_or(DEFAULT_DEDUP_WINDOW)
+    }
+
+    pub fn response_cache_ttl(&self) -> u64 {
+        self.response_cache_ttl
+            .unwrap_or(DEFAULT_RESPONSE_CACHE_TTL)
+    }
+
+    pub fn response_cache_size(&self) -> usize {
+        self.response_cache_size
+            .unwrap_or(DEFAULT_RESPONSE_CACHE_SIZE)
+    }
+
+    pub fn blocking_timeout_max(&self) -> u64 {
+        self.blocking_timeout_max
+            .unwrap_or(DEFAULT_BLOCKING_TIMEOUT_MAX)
+    }
+
+    /// the listener of listen_addr named default, followed by the ones of listeners.
+    pub fn all_listeners(&self) -> Vec<ListenerConfig> {
+        let default = ListenerConfig {
+            name: DEFAULT_LISTENER.to_string(),
+            listen_addr: self.listen_addr.clone(),
+            ..Default::default()
+        };
+        let mut listeners = vec![default];
+        listeners.extend(self.listeners.iter().cloned());
+        listeners
+    }
+
+    /// the dangerous command (e.g.: "CLUSTER RESET") is given by allow_dangerous, which is
+    /// matched ignoring case.
+    pub fn allows_dangerous(&self, name: &str) -> bool {
+        self.allow_dangerous.iter().any(|x| {
+            let allowed: Vec<_> = x.split_whitespace().collect();
+            allowed.join(" ").eq_ignore_ascii_case(name)
+        })
+    }
+
+    pub fn warmup_rate(&self) -> u64 {
+        self.warmup_rate.unwrap_or(DEFAULT_WARMUP_RATE)
+    }
+
+    pub fn warmup_ttl(&self) -> u32 {
+        self.warmup_ttl.unwrap_or(DEFAULT_WARMUP_TTL)
+    }
+
+    /// the config in effect, whose fields absent are given by the defaults of their getters.
+    pub fn effective(&self) -> ClusterConfig {
+        let mut cc = self.clone();
+        cc.multi_key_batch = Some(self.multi_key_batch());
+        cc.backend_queue_limit = Some(self.backend_queue_limit());
+        cc.stale_conn_limit = Some(self.stale_conn_limit());
+        cc.protocol_error_limit = Some(self.protocol_error_limit());
+        if self.backend_max_lifetime.is_some() {
+            cc.backend_lifetime_jitter = Some(self.backend_lifetime_jitter());
+        }
+        cc.reload_drain_timeout = Some(self.reload_drain_timeout());
+        cc.dedup_window = Some(self.dedup_window());
+        cc.response_cache_ttl = Some(self.response_cache_ttl());
+        cc.response_cache_size = Some(self.response_cache_size());
+        cc.blocking_timeout_max = Some(self.blocking_timeout_max());
+        cc.warmup_rate = Some(self.warmup_rate());
+        cc.warmup_ttl = Some(self.warmup_ttl());
+        cc
+    }
+
+    /// open and close bytes of hash tag. redis cluster always use "{}" as the slots of redis,
+    /// and the proxy mode hash the whole key by default.
+    pub fn hash_tag(&self) -> Vec<u8> {
+        match self.cache_type {
+            CacheType::RedisCluster => b"{}".to_vec(),
+            _ => self
+                .hash_tag
+                .as_ref()
+                .map(|x| x.as_bytes().to_vec())
+                .unwrap_or_else(|| vec![]),
+        }
+    }
+}
+
 #[cfg(windows)]
 pub(crate) fn create_reuse_port_listener(addr: &SocketAddr) -> Result<TcpListener, std::io::Error> {
     let builder = TcpBuilder::new_v4()?;
@@ -263,6 +1663,30 @@ pub(crate) fn create_reuse_port_listener(addr: &SocketAddr) -> Result<TcpListene
     TcpListener::from_std(std_listener, &hd)
 }
 
+/// bind the addr without listening, which reserves the port (e.g.: port 0 is resolved)
+/// until all the worker threads are listening on it.
+#[cfg(windows)]
+pub(crate) fn reserve_reuse_port(
+    addr: &SocketAddr,
+) -> Result<(TcpBuilder, SocketAddr), std::io::Error> {
+    let builder = TcpBuilder::new_v4()?;
+    builder.reuse_address(true)?.bind(addr)?;
+    let local_addr = builder.local_addr()?;
+    Ok((builder, local_addr))
+}
+
+#[cfg(not(windows))]
+pub(crate) fn reserve_reuse_port(
+    addr: &SocketAddr,
+) -> Result<(TcpBuilder, SocketAddr), std::io::Error> {
+    use net2::unix::UnixTcpBuilderExt;
+
+    let builder = TcpBuilder::new_v4()?;
+    builder.reuse_address(true)?.reuse_port(true)?.bind(addr)?;
+    let local_addr = builder.local_addr()?;
+    Ok((builder, local_addr))
+}
+
 #[cfg(not(windows))]
 pub(crate) fn create_reuse_port_listener(addr: &SocketAddr) -> Result<TcpListener, std::io::Error> {
     use net2::unix::UnixTcpBuilderExt;
@@ -279,6 +1703,85 @@ pub(crate) fn create_reuse_port_listener(addr: &SocketAddr) -> Result<TcpListene
     TcpListener::from_std(std_listener, &hd)
 }
 
+/// connect to the backend node of cluster, the time of establishment is observed by
+/// aster_backend_connect_timer once connected.
+pub(crate) fn connect_backend(
+    cluster: &str,
+    node: &str,
+    addr: &SocketAddr,
+    timeout: Duration,
+) -> impl Future<Item = TcpStream, Error = timeout::Error<std::io::Error>> {
+    let cluster = cluster.to_string();
+    let node = node.to_string();
+    let start = Instant::now();
+    TcpStream::connect(addr)
+        .timeout(timeout)
+        .inspect(move |_| backend_connect_observe(&cluster, &node, HANDSHAKE_TCP, start.elapsed()))
+}
+
+/// TCP keepalive of the backend sockets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Option<Duration>,
+    pub count: Option<u32>,
+}
+
+/// enable TCP keepalive of the socket if given, the interval and count are left to OS except
+/// linux.
+pub fn set_keepalive(sock: &TcpStream, keepalive: Option<Keepalive>) -> Result<(), AsError> {
+    let keepalive = match keepalive {
+        Some(keepalive) => keepalive,
+        None => return Ok(()),
+    };
+    sock.set_keepalive(Some(keepalive.idle))?;
+    set_keepalive_probes(sock, &keepalive)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_keepalive_probes(_sock: &TcpStream, _keepalive: &Keepalive) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_keepalive_probes(sock: &TcpStream, keepalive: &Keepalive) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = sock.as_raw_fd();
+    if let Some(interval) = keepalive.interval {
+        let secs = interval.as_secs() as libc::c_int;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs)?;
+    }
+    if let Some(count) = keepalive.count {
+        let count = count as libc::c_int;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt(
+    fd: std::os::unix::io::RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 #[cfg(not(linux))]
 #[inline]
 pub fn set_read_write_timeout(
@@ -310,3 +1813,185 @@ pub fn set_read_write_timeout(
     let stream = TcpStream::from_std(nsock, &hd)?;
     return Ok(stream);
 }
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_set_keepalive() {
+    use std::os::unix::io::AsRawFd;
+
+    let getsockopt = |sock: &TcpStream, name: libc::c_int| {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                sock.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        value
+    };
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let connect = || {
+        let sock = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        TcpStream::from_std(sock, &tokio::reactor::Handle::default()).unwrap()
+    };
+
+    let cc = ClusterConfig {
+        tcp_keepalive_idle: Some(60),
+        tcp_keepalive_interval: Some(10),
+        tcp_keepalive_count: Some(3),
+        ..Default::default()
+    };
+    let sock = connect();
+    set_keepalive(&sock, cc.tcp_keepalive()).unwrap();
+    assert_eq!(sock.keepalive().unwrap(), Some(Duration::from_secs(60)));
+    assert_eq!(getsockopt(&sock, libc::TCP_KEEPIDLE), 60);
+    assert_eq!(getsockopt(&sock, libc::TCP_KEEPINTVL), 10);
+    assert_eq!(getsockopt(&sock, libc::TCP_KEEPCNT), 3);
+
+    // disabled by default
+    assert_eq!(ClusterConfig::default().tcp_keepalive(), None);
+    let sock = connect();
+    set_keepalive(&sock, None).unwrap();
+    assert_eq!(sock.keepalive().unwrap(), None);
+}
+
+#[test]
+fn test_sort_patterns() {
+    assert_eq!(SortPatterns::default().check("test", b"w_*"), Ok(()));
+    assert_eq!(SortPatterns::Warn.check("test", b"w_*"), Ok(()));
+    assert_eq!(
+        SortPatterns::Error.check("test", b"w_*"),
+        Err(AsError::SortCrossKey("w_*".to_string()))
+    );
+}
+
+#[test]
+fn test_allows_dangerous() {
+    let cc = ClusterConfig {
+        allow_dangerous: vec!["shutdown".to_string(), "CLUSTER  reset".to_string()],
+        ..Default::default()
+    };
+    assert!(cc.allows_dangerous("SHUTDOWN"));
+    assert!(cc.allows_dangerous("CLUSTER RESET"));
+    assert!(!cc.allows_dangerous("CLUSTER"));
+    assert!(!cc.allows_dangerous("CLUSTER FORGET"));
+    assert!(!ClusterConfig::default().allows_dangerous("SHUTDOWN"));
+}
+
+#[test]
+fn test_cluster_hash_tag() {
+    let cc = ClusterConfig {
+        cache_type: CacheType::RedisCluster,
+        ..Default::default()
+    };
+    assert_eq!(cc.hash_tag(), b"{}".to_vec());
+    let cc = ClusterConfig {
+        cache_type: CacheType::Redis,
+        ..Default::default()
+    };
+    assert!(cc.hash_tag().is_empty());
+    let cc = ClusterConfig {
+        cache_type: CacheType::Memcache,
+        hash_tag: Some("[]".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(cc.hash_tag(), b"[]".to_vec());
+
+    let valid = |cache_type: CacheType, hash_tag: &str| {
+        let cc = ClusterConfig {
+            cache_type,
+            hash_tag: Some(hash_tag.to_string()),
+            ..Default::default()
+        };
+        Config {
+            clusters: vec![cc],
+            ..Default::default()
+        }
+        .valid()
+        .is_ok()
+    };
+    assert!(valid(CacheType::Redis, "::"));
+    assert!(!valid(CacheType::Redis, "{"));
+    assert!(!valid(CacheType::Redis, "{}}"));
+    assert!(valid(CacheType::RedisCluster, "{}"));
+    assert!(!valid(CacheType::RedisCluster, "[]"));
+}
+
+#[test]
+fn test_upstream_link_config() {
+    let valid = |cache_type: CacheType, level: u32| {
+        let cc = ClusterConfig {
+            cache_type,
+            upstream_link: Some(true),
+            link_compression_level: Some(level),
+            ..Default::default()
+        };
+        Config {
+            clusters: vec![cc],
+            ..Default::default()
+        }
+        .valid()
+        .is_ok()
+    };
+    assert!(valid(CacheType::Redis, 9));
+    assert!(valid(CacheType::Memcache, 0));
+    assert!(!valid(CacheType::Redis, 10));
+    assert!(!valid(CacheType::RedisCluster, 6));
+}
+
+#[test]
+fn test_compiled_out_config() {
+    let cc = ClusterConfig {
+        name: "test-mc".to_string(),
+        cache_type: CacheType::Memcache,
+        ..Default::default()
+    };
+    let config = Config {
+        clusters: vec![cc],
+        ..Default::default()
+    };
+    if cfg!(feature = "memcache") {
+        assert!(config.valid().is_ok());
+    } else {
+        assert_eq!(
+            config.valid().unwrap_err(),
+            AsError::CompiledOut("test-mc.cache_type".to_string(), "memcache")
+        );
+    }
+
+    let config = Config {
+        exporters: vec![ExporterConfig {
+            kind: ExporterKind::Statsd,
+            endpoint: "127.0.0.1:8125".to_string(),
+            interval: None,
+        }],
+        ..Default::default()
+    };
+    assert_eq!(config.valid().is_ok(), cfg!(feature = "metrics"));
+}
+
+#[test]
+fn test_connect_backend_observed() {
+    use crate::metrics::backend_connect_count;
+    use tokio::runtime::current_thread::Runtime;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let node = addr.to_string();
+    let count = || backend_connect_count("test-connect", &node, HANDSHAKE_TCP);
+    let connect = || connect_backend("test-connect", &node, &addr, Duration::from_secs(1));
+    let mut rt = Runtime::new().unwrap();
+    assert_eq!(count(), 0);
+    assert!(rt.block_on(connect()).is_ok());
+    assert_eq!(count(), 1);
+
+    // never observed if fail to connect
+    drop(listener);
+    assert!(rt.block_on(connect()).is_err());
+    assert_eq!(count(), 1);
+}
@@ -0,0 +1,575 @@
+//! embedding api to run the proxy in process (e.g.: in tests), a cluster is spawned with the
+//! same options as the config file, and the binary is a thin wrapper of it.
+use std::net::SocketAddr;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::com::{reserve_reuse_port, AsError, CacheType, ClusterConfig, Config};
+use crate::metrics::{cluster_stats, listener_stats, ClusterStats, ListenerStats};
+use crate::protocol::redis::Cmd;
+#[cfg(feature = "redis")]
+use crate::proxy::cluster;
+use crate::proxy::hook::{self, Hook};
+use crate::proxy::info;
+use crate::proxy::standalone::{self, reload};
+use crate::proxy::valuelimit;
+use crate::proxy::worker::{Control, DEFAULT_DRAIN_TIMEOUT};
+
+const READY_TIMEOUT: u64 = 30_000;
+
+pub struct ClusterBuilder {
+    cc: ClusterConfig,
+    ip: Option<String>,
+    drain_timeout: u64,
+    hooks: Vec<Arc<dyn Hook<Cmd>>>,
+}
+
+impl ClusterBuilder {
+    /// redis proxy of one worker thread listening on a random port of localhost by default.
+    pub fn new(name: &str) -> ClusterBuilder {
+        let cc = ClusterConfig {
+            name: name.to_string(),
+            listen_addr: "127.0.0.1:0".to_string(),
+            cache_type: CacheType::Redis,
+            thread: Some(1),
+            ..Default::default()
+        };
+        ClusterBuilder::from_config(cc)
+    }
+
+    pub fn from_config(cc: ClusterConfig) -> ClusterBuilder {
+        ClusterBuilder {
+            cc,
+            ip: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            hooks: Vec::new(),
+        }
+    }
+
+    pub fn listen_addr(mut self, addr: &str) -> Self {
+        self.cc.listen_addr = addr.to_string();
+        self
+    }
+
+    pub fn cache_type(mut self, cache_type: CacheType) -> Self {
+        self.cc.cache_type = cache_type;
+        self
+    }
+
+    /// the same format of servers in config file, e.g.: "127.0.0.1:7001:10 redis-1".
+    pub fn servers<I, S>(mut self, servers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.cc.servers = servers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn hash_tag(mut self, hash_tag: &str) -> Self {
+        self.cc.hash_tag = Some(hash_tag.to_string());
+        self
+    }
+
+    pub fn thread(mut self, thread: usize) -> Self {
+        self.cc.thread = Some(thread);
+        self
+    }
+
+    pub fn read_timeout(mut self, millis: u64) -> Self {
+        self.cc.read_timeout = Some(millis);
+        self
+    }
+
+    pub fn write_timeout(mut self, millis: u64) -> Self {
+        self.cc.write_timeout = Some(millis);
+        self
+    }
+
+    /// set the other options of config file.
+    pub fn config<F: FnOnce(&mut ClusterConfig)>(mut self, f: F) -> Self {
+        f(&mut self.cc);
+        self
+    }
+
+    /// the ip announced in the replies of CLUSTER NODES and CLUSTER SLOTS.
+    pub fn ip(mut self, ip: Option<String>) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    /// max millis to wait for the in-flight requests when shutdown.
+    pub fn drain_timeout(mut self, millis: u64) -> Self {
+        self.drain_timeout = millis;
+        self
+    }
+
+    /// add the hook of requests and responses of redis, which is called in the order added.
+    pub fn hook<H: Hook<Cmd> + 'static>(mut self, hook: H) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// spawn the worker threads and return after all of them are listening.
+    pub fn spawn(self) -> Result<ClusterHandle, AsError> {
+        let ClusterBuilder {
+            mut cc,
+            ip,
+            drain_timeout,
+            hooks,
+        } = self;
+        if cc.name.is_empty() || cc.servers.is_empty() {
+            return Err(AsError::BadConfig(format!(
+                "{}.name and servers must be given",
+                cc.name
+            )));
+        }
+        let config = Config {
+            clusters: vec![cc.clone()],
+            ..Default::default()
+        };
+        config.valid()?;
+        info::mark_started();
+        if !hooks.is_empty() {
+            match cc.cache_type {
+                CacheType::Redis | CacheType::RedisCluster => {}
+                _ => {
+                    return Err(AsError::BadConfig(format!(
+                        "{}.hook only support redis",
+                        cc.name
+                    )))
+                }
+            }
+        }
+        match cc.cache_type {
+            CacheType::RedisCluster => {}
+            _ => standalone::check_servers(&cc.servers)?,
+        }
+        let addr = cc
+            .listen_addr
+            .parse::<SocketAddr>()
+            .map_err(|_| AsError::BadConfig(format!("{}.listen_addr", cc.name)))?;
+        let (reserved, local_addr) = reserve_reuse_port(&addr)?;
+        cc.listen_addr = local_addr.to_string();
+        // the random ports of listeners are resolved as well
+        let mut reserves = vec![reserved];
+        let mut listeners = Vec::with_capacity(cc.listeners.len());
+        for listener in cc.listeners.iter_mut() {
+            let addr = listener.listen_addr.parse::<SocketAddr>().map_err(|_| {
+                AsError::BadConfig(format!("{}.listeners {}", cc.name, listener.name))
+            })?;
+            let (reserved, local_addr) = reserve_reuse_port(&addr)?;
+            listener.listen_addr = local_addr.to_string();
+            listeners.push((listener.name.clone(), local_addr));
+            reserves.push(reserved);
+        }
+        reload::register(&cc)?;
+        valuelimit::learn(&cc);
+        hook::register(&cc.name, hooks);
+
+        let (ready, ready_rx) = channel();
+        let control = Arc::new(Control::new(ready, Duration::from_millis(drain_timeout)));
+        let threads = match cc.cache_type {
+            #[cfg(feature = "redis")]
+            CacheType::RedisCluster => cluster::run(cc.clone(), ip, control.clone()),
+            _ => standalone::run(cc.clone(), ip, control.clone()),
+        };
+        for _ in 0..threads.len() {
+            if ready_rx
+                .recv_timeout(Duration::from_millis(READY_TIMEOUT))
+                .is_err()
+            {
+                control.close();
+                return Err(AsError::SpawnFail(cc.name.clone()));
+            }
+        }
+        drop(reserves);
+
+        Ok(ClusterHandle {
+            name: cc.name.clone(),
+            cache_type: cc.cache_type,
+            local_addr,
+            listeners,
+            control,
+            threads,
+        })
+    }
+}
+
+pub struct ClusterHandle {
+    name: String,
+    cache_type: CacheType,
+    local_addr: SocketAddr,
+    // name and address of the listeners beyond local_addr
+    listeners: Vec<(String, SocketAddr)>,
+    control: Arc<Control>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ClusterHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// the address of the listener given by listeners.
+    pub fn listener_addr(&self, name: &str) -> Option<SocketAddr> {
+        self.listeners.iter().find(|x| x.0 == name).map(|x| x.1)
+    }
+
+    /// stats of all the listeners sharing the backends.
+    pub fn stats(&self) -> ClusterStats {
+        cluster_stats(&self.name)
+    }
+
+    /// stats of one listener, the one of local_addr is named default.
+    pub fn listener_stats(&self, name: &str) -> ListenerStats {
+        listener_stats(&self.name, name)
+    }
+
+    /// add the backend of the same format as servers, which is applied by all the workers
+    /// in seconds the same as hot reload.
+    pub fn add_backend(&self, server: &str) -> Result<(), AsError> {
+        self.check_proxy_mode()?;
+        if server.trim().is_empty() {
+            return Err(AsError::BadConfig(format!("{}.servers", self.name)));
+        }
+        let server = server.to_string();
+        standalone::check_servers(&[server.clone()])?;
+        reload::update(&self.name, move |cc| {
+            cc.servers.push(server);
+            Ok(())
+        })
+    }
+
+    /// remove the backend named by alias or address.
+    pub fn remove_backend(&self, node: &str) -> Result<(), AsError> {
+        self.check_proxy_mode()?;
+        reload::update(&self.name, |cc| {
+            let count = cc.servers.len();
+            cc.servers.retain(|x| !standalone::is_server_of(x, node));
+            if cc.servers.len() == count {
+                return Err(AsError::BadConfig(format!(
+                    "{}.servers absents {}",
+                    cc.name, node
+                )));
+            }
+            if cc.servers.is_empty() {
+                return Err(AsError::BadConfig(format!(
+                    "{}.servers can't be empty",
+                    cc.name
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    fn check_proxy_mode(&self) -> Result<(), AsError> {
+        if let CacheType::RedisCluster = self.cache_type {
+            return Err(AsError::BadConfig(format!(
+                "{}.servers of redis_cluster is discovered",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// stop accepting and wait for the in-flight requests to be replied (at most the drain
+    /// timeout), then all the worker threads exit.
+    pub fn shutdown(self) {
+        info!("shutdown cluster {}", self.name);
+        self.control.close();
+        self.join();
+    }
+
+    /// block until all the worker threads exit.
+    pub fn join(self) {
+        for th in self.threads {
+            if th.join().is_err() {
+                error!("worker thread of cluster {} panicked", self.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::com::{BlockingCommands, ListenerConfig};
+    use crate::metrics::self_probe_count;
+    use crate::proxy::maintenance;
+    use crate::testsupport::{Action, MockBackend, Script};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_embed_spawn_and_shutdown() {
+        let backend = MockBackend::redis(Script::new());
+        let handle = ClusterBuilder::new("test-embed")
+            .servers(vec![backend.server("redis-1")])
+            .config(|cc| cc.ping_fail_limit = Some(0))
+            .spawn()
+            .unwrap();
+        assert_ne!(handle.local_addr().port(), 0);
+
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n")
+            .unwrap();
+        let mut reply = [0u8; 5];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+        let stats = handle.stats();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.accepted, 1);
+
+        assert!(handle.add_backend("").is_err());
+        assert!(handle.remove_backend("redis-2").is_err());
+        assert!(handle.remove_backend("redis-1").is_err());
+        handle
+            .add_backend(&backend.server("redis-2"))
+            .unwrap();
+        handle.remove_backend("redis-1").unwrap();
+
+        // the idle connection is closed by shutdown
+        handle.shutdown();
+        assert_eq!(client.read(&mut reply).unwrap_or(0), 0);
+    }
+
+    #[test]
+    fn test_embed_maintenance_mode() {
+        // mock redis replies the key "slow" after a while
+        let backend = MockBackend::redis(Script::new().on_key(
+            "SET",
+            "slow",
+            vec![Action::Delay(500), Action::Respond],
+        ));
+        let handle = ClusterBuilder::new("test-embed-maintenance")
+            .servers(vec![backend.server("redis-1")])
+            .config(|cc| cc.ping_fail_limit = Some(0))
+            .spawn()
+            .unwrap();
+        let set = |key: &str| {
+            format!(
+                "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n$1\r\n1\r\n",
+                key.len(),
+                key
+            )
+        };
+
+        let mut slow = TcpStream::connect(handle.local_addr()).unwrap();
+        slow.write_all(set("slow").as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(maintenance::set_maintenance("test-embed-maintenance", true));
+
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client.write_all(set("a").as_bytes()).unwrap();
+        let mut reply = [0u8; 27];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[..], &b"-ERR proxy in maintenance\r\n"[..]);
+        // the command in flight is finished in maintenance
+        let mut ok = [0u8; 5];
+        slow.read_exact(&mut ok).unwrap();
+        assert_eq!(&ok, b"+OK\r\n");
+
+        assert!(maintenance::set_maintenance(
+            "test-embed-maintenance",
+            false
+        ));
+        client.write_all(set("a").as_bytes()).unwrap();
+        client.read_exact(&mut ok).unwrap();
+        assert_eq!(&ok, b"+OK\r\n");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_embed_dangerous_denied() {
+        // mock redis replies "+OK" even for SHUTDOWN
+        let backend = MockBackend::redis(Script::new());
+        let handle = ClusterBuilder::new("test-embed-dangerous")
+            .servers(vec![backend.server("redis-1")])
+            .config(|cc| {
+                cc.ping_fail_limit = Some(0);
+                cc.admin_node = Some(backend.addr().to_string());
+            })
+            .spawn()
+            .unwrap();
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client.write_all(b"*1\r\n$8\r\nSHUTDOWN\r\n").unwrap();
+        let expect = b"-ERR dangerous command SHUTDOWN is denied by proxy\r\n";
+        let mut reply = vec![0u8; expect.len()];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[..], &expect[..]);
+        handle.shutdown();
+
+        // allowed for the cluster proxies admin traffic
+        let handle = ClusterBuilder::new("test-embed-dangerous-allowed")
+            .servers(vec![backend.server("redis-1")])
+            .config(|cc| {
+                cc.ping_fail_limit = Some(0);
+                cc.admin_node = Some(backend.addr().to_string());
+                cc.allow_dangerous = vec!["shutdown".to_string()];
+            })
+            .spawn()
+            .unwrap();
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client.write_all(b"*1\r\n$8\r\nSHUTDOWN\r\n").unwrap();
+        let mut reply = [0u8; 5];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_embed_listeners_share_backends() {
+        let backend = MockBackend::redis(Script::new());
+        let handle = ClusterBuilder::new("test-embed-listeners")
+            .servers(vec![backend.server("redis-1")])
+            .config(|cc| {
+                cc.ping_fail_limit = Some(0);
+                cc.listeners = vec![ListenerConfig {
+                    name: "reader".to_string(),
+                    listen_addr: "127.0.0.1:0".to_string(),
+                    read_only: Some(true),
+                    ..Default::default()
+                }];
+            })
+            .spawn()
+            .unwrap();
+        let reader = handle.listener_addr("reader").unwrap();
+        assert_ne!(reader, handle.local_addr());
+        assert!(handle.listener_addr("absent").is_none());
+
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client.write_all(set).unwrap();
+        let mut reply = [0u8; 5];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+
+        // the settings of front are of the listener
+        let mut client = TcpStream::connect(reader).unwrap();
+        client.write_all(set).unwrap();
+        let expect = format!("-{}\r\n", AsError::ReadOnly);
+        let mut reply = vec![0u8; expect.len()];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply[..], expect.as_bytes());
+
+        for name in &["default", "reader"] {
+            let stats = handle.listener_stats(name);
+            assert_eq!(stats.connections, 1);
+            assert_eq!(stats.accepted, 1);
+            assert_eq!(stats.requests, 1);
+        }
+        assert_eq!(handle.stats().connections, 2);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_embed_proxy_monitor() {
+        let backend = MockBackend::redis(Script::new());
+        let handle = ClusterBuilder::new("test-embed-monitor")
+            .servers(vec![backend.server("redis-1")])
+            .config(|cc| {
+                cc.ping_fail_limit = Some(0);
+                cc.proxy_admin = Some(true);
+            })
+            .spawn()
+            .unwrap();
+        let mut monitor = TcpStream::connect(handle.local_addr()).unwrap();
+        monitor
+            .write_all(b"*2\r\n$5\r\nPROXY\r\n$7\r\nMONITOR\r\n")
+            .unwrap();
+        let mut reply = [0u8; 5];
+        monitor.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$6\r\nuser:1\r\n$6\r\nsecret\r\n")
+            .unwrap();
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+
+        // the event line of SET without its value
+        let mut event = Vec::new();
+        while !event.ends_with(b"\r\n") {
+            let mut byte = [0u8; 1];
+            monitor.read_exact(&mut byte).unwrap();
+            event.push(byte[0]);
+        }
+        let event = String::from_utf8_lossy(&event).to_string();
+        assert!(event.starts_with('+'), "{}", event);
+        assert!(event.ends_with("] SET user:1\r\n"), "{}", event);
+        assert!(!event.contains("secret"), "{}", event);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_embed_self_probe() {
+        let backend = MockBackend::redis(Script::new());
+        let handle = ClusterBuilder::new("test-embed-self-probe")
+            .servers(vec![backend.server("redis-1")])
+            .config(|cc| {
+                cc.ping_fail_limit = Some(0);
+                cc.self_probe_interval = Some(20);
+            })
+            .spawn()
+            .unwrap();
+        let start = Instant::now();
+        while self_probe_count("test-embed-self-probe") < 3 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(20));
+        }
+        // the probe is never counted as client
+        let stats = handle.listener_stats("default");
+        assert_eq!(stats.connections, 0);
+        assert_eq!(stats.accepted, 0);
+        assert_eq!(stats.requests, 0);
+        assert_eq!(handle.stats().connections, 0);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_embed_bounded_blocking_timeout() {
+        // mock redis blocks for the timeout bounded and replies nil
+        let backend = MockBackend::redis(Script::new().on(
+            "BLPOP",
+            vec![Action::Delay(1_000), Action::Reply(b"*-1\r\n".to_vec())],
+        ));
+        let handle = ClusterBuilder::new("test-embed-blocking")
+            .servers(vec![backend.server("redis-1")])
+            .config(|cc| {
+                cc.ping_fail_limit = Some(0);
+                cc.blocking_commands = Some(BlockingCommands::Bounded);
+                cc.blocking_timeout_max = Some(1);
+            })
+            .spawn()
+            .unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        let start = Instant::now();
+        client
+            .write_all(b"*3\r\n$5\r\nBLPOP\r\n$4\r\nlist\r\n$1\r\n0\r\n")
+            .unwrap();
+        let mut reply = [0u8; 5];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"*-1\r\n");
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+        // the timeout 0 (forever) is bounded by blocking_timeout_max
+        assert_eq!(
+            backend.requests_of("BLPOP"),
+            vec![vec!["BLPOP".to_string(), "list".to_string(), "1".to_string()]]
+        );
+        handle.shutdown();
+    }
+}
@@ -10,88 +10,165 @@ extern crate failure;
 #[macro_use]
 extern crate clap;
 
+#[cfg(feature = "metrics")]
 #[macro_use]
 extern crate prometheus;
 
+#[cfg(not(any(feature = "redis", feature = "memcache")))]
+compile_error!("at least one of the features redis and memcache must be enabled");
+
 pub mod metrics;
 
 use clap::App;
+use std::fs;
+use std::io::{self, BufRead};
 
 pub const ASTER_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+#[cfg(feature = "admin")]
+pub(crate) mod admin;
 pub mod com;
+pub mod embed;
 pub mod protocol;
 pub mod proxy;
+pub mod routing;
+#[cfg(any(test, feature = "testsupport"))]
+pub mod testsupport;
 pub(crate) mod utils;
 
 use failure::Error;
 
+pub use embed::{ClusterBuilder, ClusterHandle};
+pub use proxy::hook::{Action, Hook, MaxTtl};
+
 pub fn run() -> Result<(), Error> {
     env_logger::init();
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).version(ASTER_VERSION).get_matches();
+    if let Some(replay) = matches.subcommand_matches("replay") {
+        let file = replay.value_of("file").unwrap();
+        let target = replay.value_of("target").unwrap();
+        let speed = value_t!(replay, "speed", f64).unwrap_or(1.0);
+        info!(
+            "[aster-{}] replay {} against {}",
+            ASTER_VERSION, file, target
+        );
+        let report = proxy::capture::replay::run(file, target, speed)?;
+        println!("{}", report);
+        return Ok(());
+    }
+    if let Some(keys) = matches.subcommand_matches("keys") {
+        let config = keys.value_of("config").unwrap();
+        let cluster = keys.value_of("cluster").unwrap();
+        let routing = routing::Routing::load(config, cluster)?;
+        for key in keys.values_of("keys").unwrap() {
+            match routing.locate(key.as_bytes()) {
+                Some(placement) => println!("{} {}", key, placement),
+                None => println!("{} -", key),
+            }
+        }
+        return Ok(());
+    }
+    if let Some(sort) = matches.subcommand_matches("trace-sort") {
+        let cluster = sort.value_of("cluster").unwrap();
+        let reqs = if sort.is_present("req") {
+            values_t!(sort, "req", u64).unwrap_or_else(|err| err.exit())
+        } else {
+            Vec::new()
+        };
+        let mut lines = Vec::new();
+        match sort.values_of("files") {
+            Some(files) => {
+                for file in files {
+                    lines.extend(fs::read_to_string(file)?.lines().map(String::from));
+                }
+            }
+            None => {
+                let stdin = io::stdin();
+                for line in stdin.lock().lines() {
+                    lines.push(line?);
+                }
+            }
+        }
+        for line in proxy::standalone::tick::sort(lines, cluster, &reqs) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
     let config = matches.value_of("config").unwrap_or("default.toml");
     let watch_file = config.to_string();
     let ip = matches.value_of("ip").map(|x| x.to_string());
     let enable_reload = matches.is_present("reload");
+    let strict = matches.is_present("strict");
     info!("[aster-{}] loading config from {}", ASTER_VERSION, config);
     let cfg = com::Config::load(&config)?;
     debug!("use config : {:?}", cfg);
+    if strict {
+        cfg.valid()?;
+    }
+    cfg.valid_exporters()?;
+    if matches.is_present("doctor") {
+        proxy::doctor::run(&cfg)?;
+        return Ok(());
+    }
     assert!(
         !cfg.clusters.is_empty(),
         "clusters is absent of config file"
     );
     crate::proxy::standalone::reload::init(&watch_file, cfg.clone(), enable_reload)?;
+    proxy::startup::init(ip);
 
-    let mut ths = Vec::new();
+    // each cluster is started alone, the failed one never stops the others unless strict
+    let mut handles = Vec::new();
     for cluster in cfg.clusters.into_iter() {
-        if cluster.servers.is_empty() {
-            warn!(
-                "fail to running cluster {} in addr {} due filed `servers` is empty",
-                cluster.name, cluster.listen_addr
-            );
-            continue;
-        }
-
-        if cluster.name.is_empty() {
-            warn!(
-                "fail to running cluster {} in addr {} due filed `name` is empty",
-                cluster.name, cluster.listen_addr
-            );
-            continue;
-        }
-
         info!(
             "starting aster cluster {} in addr {}",
             cluster.name, cluster.listen_addr
         );
-
-        match cluster.cache_type {
-            com::CacheType::RedisCluster => {
-                let jhs = proxy::cluster::run(cluster, ip.clone());
-                ths.extend(jhs);
-            }
-            _ => {
-                let jhs = proxy::standalone::run(cluster, ip.clone());
-                ths.extend(jhs);
-            }
+        let name = cluster.name.clone();
+        match proxy::startup::start(cluster) {
+            Ok(handle) => handles.push(handle),
+            Err(err) if strict => return Err(err.into()),
+            Err(err) => error!("fail to start cluster {} due {}", name, err),
         }
     }
+    if handles.is_empty() {
+        return Err(com::AsError::SpawnFail("all clusters".to_string()).into());
+    }
 
+    #[cfg(feature = "metrics")]
     {
         let port_str = matches.value_of("metrics").unwrap_or("2110");
         let port = port_str.parse::<usize>().unwrap_or(2110);
         spawn_metrics(port);
+        for exporter in &cfg.exporters {
+            metrics::exporter::spawn(exporter)?;
+        }
     }
 
-    for th in ths {
-        th.join().unwrap();
+    for handle in handles {
+        handle.join();
+    }
+    let failed = proxy::startup::failed();
+    if !failed.is_empty() {
+        return Err(com::AsError::PartialStart(failed.join(",")).into());
     }
     Ok(())
 }
 
+/// exit code of the error returned by run, 2 if some clusters never started while the others
+/// were serving, and 1 for the others (e.g.: any cluster failed in strict mode).
+pub fn exit_code(err: &Error) -> i32 {
+    match err.downcast_ref::<com::AsError>() {
+        Some(com::AsError::PartialStart(_)) => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(feature = "metrics")]
 use std::thread;
 
+#[cfg(feature = "metrics")]
 fn spawn_metrics(port: usize) -> Vec<thread::JoinHandle<()>> {
     vec![
         thread::Builder::new()
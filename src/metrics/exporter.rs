@@ -0,0 +1,529 @@
+//! push the metrics of prometheus registry to the other pipelines by the exporters of config,
+//! each exporter runs in its own thread and pushes every interval millis:
+//!
+//! ```toml
+//! [[exporters]]
+//! kind = "statsd"
+//! endpoint = "127.0.0.1:8125"
+//! interval = 10000
+//!
+//! [[exporters]]
+//! kind = "otlp"
+//! endpoint = "http://127.0.0.1:4318/v1/metrics"
+//! ```
+//!
+//! The metrics are gathered the same as served by /metrics, so any metric registered is pushed
+//! without knowing the exporters. StatsD lines are sent over UDP: the label values are joined to
+//! the name by '.', counters are sent by the delta since the last push (skipped if unchanged),
+//! gauges by the value and histograms by the deltas of `_count` and `_sum`. OTLP is posted as
+//! JSON over plain HTTP with the cumulative values.
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::com::{AsError, ExporterConfig, ExporterKind};
+use crate::ASTER_VERSION as VERSION;
+
+const DEFAULT_INTERVAL: u64 = 10_000;
+const IO_TIMEOUT: u64 = 5_000;
+// the payload of each datagram, which is never fragmented by the common MTU
+const MAX_DATAGRAM: usize = 1432;
+
+/// push the gathered metric families to somewhere.
+pub trait Exporter: Send {
+    fn export(&mut self, families: &[MetricFamily]) -> Result<(), AsError>;
+}
+
+/// build the exporter of config, the endpoint is checked here.
+pub fn build(cfg: &ExporterConfig) -> Result<Box<dyn Exporter>, AsError> {
+    match cfg.kind {
+        ExporterKind::Statsd => Ok(Box::new(StatsdExporter::new(&cfg.endpoint)?)),
+        ExporterKind::Otlp => Ok(Box::new(OtlpExporter::new(&cfg.endpoint)?)),
+    }
+}
+
+/// spawn the thread pushing by the exporter of config every interval.
+pub fn spawn(cfg: &ExporterConfig) -> Result<thread::JoinHandle<()>, AsError> {
+    let mut exporter = build(cfg)?;
+    let interval = Duration::from_millis(cfg.interval.unwrap_or(DEFAULT_INTERVAL));
+    let endpoint = cfg.endpoint.clone();
+    let handle = thread::Builder::new()
+        .name(format!("aster-exporter-{:?}", cfg.kind).to_lowercase())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(err) = exporter.export(&prometheus::gather()) {
+                warn!("fail to export metrics to {} due to {}", endpoint, err);
+            }
+        })?;
+    Ok(handle)
+}
+
+fn label_values(metric: &Metric) -> Vec<&str> {
+    metric.get_label().iter().map(|x| x.get_value()).collect()
+}
+
+/// StatsD lines over UDP.
+pub struct StatsdExporter {
+    sock: UdpSocket,
+    // the counters of the last push by the line name
+    last: HashMap<String, f64>,
+}
+
+impl StatsdExporter {
+    pub fn new(endpoint: &str) -> Result<StatsdExporter, AsError> {
+        let addr = endpoint
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut x| x.next())
+            .ok_or_else(|| AsError::BadConfig(format!("exporters.endpoint {}", endpoint)))?;
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let sock = UdpSocket::bind(local)?;
+        sock.connect(addr)?;
+        Ok(StatsdExporter {
+            sock,
+            last: HashMap::new(),
+        })
+    }
+
+    // the delta of counter since the last push
+    fn delta(&mut self, name: String, value: f64) -> Option<(String, f64)> {
+        let last = self.last.insert(name.clone(), value).unwrap_or(0.0);
+        // the counter is never decreased but by restart
+        let delta = if value < last { value } else { value - last };
+        if delta == 0.0 {
+            return None;
+        }
+        Some((name, delta))
+    }
+
+    /// the lines of families, the counters of the last push are updated.
+    pub fn lines(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for family in families {
+            for metric in family.get_metric() {
+                let name = statsd_name(family.get_name(), &label_values(metric));
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let value = metric.get_counter().get_value();
+                        if let Some((name, delta)) = self.delta(name, value) {
+                            lines.push(format!("{}:{}|c", name, delta));
+                        }
+                    }
+                    MetricType::GAUGE => {
+                        lines.push(format!("{}:{}|g", name, metric.get_gauge().get_value()));
+                    }
+                    MetricType::HISTOGRAM => {
+                        let hist = metric.get_histogram();
+                        let count = hist.get_sample_count() as f64;
+                        if let Some((name, delta)) = self.delta(format!("{}_count", name), count) {
+                            lines.push(format!("{}:{}|c", name, delta));
+                        }
+                        let sum = hist.get_sample_sum();
+                        if let Some((name, delta)) = self.delta(format!("{}_sum", name), sum) {
+                            lines.push(format!("{}:{}|c", name, delta));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// the name joined by the label values, whose reserved chars of StatsD are replaced by '_'.
+fn statsd_name(name: &str, values: &[&str]) -> String {
+    let mut line = name.to_string();
+    for value in values {
+        line.push('.');
+        line.extend(value.chars().map(|x| match x {
+            '.' | ':' | '|' | '@' | '#' | ' ' => '_',
+            x => x,
+        }));
+    }
+    line
+}
+
+impl Exporter for StatsdExporter {
+    fn export(&mut self, families: &[MetricFamily]) -> Result<(), AsError> {
+        let mut datagram = String::new();
+        for line in self.lines(families) {
+            if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+                self.sock.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.sock.send(datagram.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// OTLP metrics posted as JSON over HTTP.
+pub struct OtlpExporter {
+    // host:port
+    authority: String,
+    path: String,
+}
+
+impl OtlpExporter {
+    pub fn new(endpoint: &str) -> Result<OtlpExporter, AsError> {
+        let bad = || AsError::BadConfig(format!("exporters.endpoint {}", endpoint));
+        let rest = endpoint.strip_prefix("http://").ok_or_else(bad)?;
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/v1/metrics"),
+        };
+        if authority.is_empty() {
+            return Err(bad());
+        }
+        Ok(OtlpExporter {
+            authority: authority.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    fn post(&self, body: &[u8]) -> Result<(), AsError> {
+        let timeout = Duration::from_millis(IO_TIMEOUT);
+        let addr =
+            self.authority.to_socket_addrs()?.next().ok_or_else(|| {
+                AsError::BadConfig(format!("exporters.endpoint {}", self.authority))
+            })?;
+        let mut sock = TcpStream::connect_timeout(&addr, timeout)?;
+        sock.set_read_timeout(Some(timeout))?;
+        sock.set_write_timeout(Some(timeout))?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.authority,
+            body.len()
+        );
+        sock.write_all(head.as_bytes())?;
+        sock.write_all(body)?;
+        let mut buf = [0u8; 64];
+        let size = sock.read(&mut buf)?;
+        // HTTP/1.1 2xx
+        if buf[..size].get(9) == Some(&b'2') {
+            return Ok(());
+        }
+        let reply = String::from_utf8_lossy(&buf[..size]);
+        warn!(
+            "otlp endpoint {} replied {}",
+            self.authority,
+            reply.lines().next().unwrap_or("")
+        );
+        Err(AsError::BadReply)
+    }
+}
+
+impl Exporter for OtlpExporter {
+    fn export(&mut self, families: &[MetricFamily]) -> Result<(), AsError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let body = serde_json::to_vec(&otlp_request(families, now))
+            .map_err(|err| AsError::BadConfig(err.to_string()))?;
+        self.post(&body)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportRequest {
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceMetrics {
+    resource: Resource,
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Serialize)]
+struct Resource {
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Serialize)]
+struct ScopeMetrics {
+    scope: Scope,
+    metrics: Vec<OtlpMetric>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct KeyValue {
+    key: String,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    string_value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpMetric {
+    name: String,
+    description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sum: Option<Sum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gauge: Option<Gauge>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    histogram: Option<Histogram>,
+}
+
+// cumulative
+const TEMPORALITY: u8 = 2;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Sum {
+    data_points: Vec<NumberDataPoint>,
+    aggregation_temporality: u8,
+    is_monotonic: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Gauge {
+    data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Histogram {
+    data_points: Vec<HistogramDataPoint>,
+    aggregation_temporality: u8,
+}
+
+// the 64 bits integers are strings in JSON of OTLP
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NumberDataPoint {
+    attributes: Vec<KeyValue>,
+    time_unix_nano: String,
+    as_double: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistogramDataPoint {
+    attributes: Vec<KeyValue>,
+    time_unix_nano: String,
+    count: String,
+    sum: f64,
+    bucket_counts: Vec<String>,
+    explicit_bounds: Vec<f64>,
+}
+
+fn key_value(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: AnyValue {
+            string_value: value.to_string(),
+        },
+    }
+}
+
+fn attributes(metric: &Metric) -> Vec<KeyValue> {
+    metric
+        .get_label()
+        .iter()
+        .map(|x| key_value(x.get_name(), x.get_value()))
+        .collect()
+}
+
+fn number_point(metric: &Metric, now: &str, value: f64) -> NumberDataPoint {
+    NumberDataPoint {
+        attributes: attributes(metric),
+        time_unix_nano: now.to_string(),
+        as_double: value,
+    }
+}
+
+// the cumulative buckets of prometheus are counted by each bound, and the last is of +Inf
+fn histogram_point(metric: &Metric, now: &str) -> HistogramDataPoint {
+    let hist = metric.get_histogram();
+    let mut bucket_counts = Vec::new();
+    let mut explicit_bounds = Vec::new();
+    let mut last = 0;
+    for bucket in hist.get_bucket() {
+        if bucket.get_upper_bound().is_infinite() {
+            continue;
+        }
+        let count = bucket.get_cumulative_count();
+        bucket_counts.push((count - last).to_string());
+        explicit_bounds.push(bucket.get_upper_bound());
+        last = count;
+    }
+    bucket_counts.push((hist.get_sample_count().saturating_sub(last)).to_string());
+    HistogramDataPoint {
+        attributes: attributes(metric),
+        time_unix_nano: now.to_string(),
+        count: hist.get_sample_count().to_string(),
+        sum: hist.get_sample_sum(),
+        bucket_counts,
+        explicit_bounds,
+    }
+}
+
+fn otlp_request(families: &[MetricFamily], now: u128) -> ExportRequest {
+    let now = now.to_string();
+    let metrics = families
+        .iter()
+        .filter_map(|family| {
+            let mut metric = OtlpMetric {
+                name: family.get_name().to_string(),
+                description: family.get_help().to_string(),
+                sum: None,
+                gauge: None,
+                histogram: None,
+            };
+            let points = family.get_metric();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    metric.sum = Some(Sum {
+                        data_points: points
+                            .iter()
+                            .map(|x| number_point(x, &now, x.get_counter().get_value()))
+                            .collect(),
+                        aggregation_temporality: TEMPORALITY,
+                        is_monotonic: true,
+                    })
+                }
+                MetricType::GAUGE => {
+                    metric.gauge = Some(Gauge {
+                        data_points: points
+                            .iter()
+                            .map(|x| number_point(x, &now, x.get_gauge().get_value()))
+                            .collect(),
+                    })
+                }
+                MetricType::HISTOGRAM => {
+                    metric.histogram = Some(Histogram {
+                        data_points: points.iter().map(|x| histogram_point(x, &now)).collect(),
+                        aggregation_temporality: TEMPORALITY,
+                    })
+                }
+                _ => return None,
+            }
+            Some(metric)
+        })
+        .collect();
+    ExportRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Resource {
+                attributes: vec![key_value("service.name", "aster")],
+            },
+            scope_metrics: vec![ScopeMetrics {
+                scope: Scope {
+                    name: "aster",
+                    version: VERSION,
+                },
+                metrics,
+            }],
+        }],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prometheus::{IntCounterVec, Opts, Registry};
+
+    fn registry() -> (Registry, IntCounterVec) {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(
+            Opts::new("aster_test_requests", "requests of test counter"),
+            &["cluster", "node"],
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        (registry, counter)
+    }
+
+    #[test]
+    fn test_statsd_name() {
+        assert_eq!(
+            statsd_name("aster_backend_ping_latency", &["test", "127.0.0.1:7001"]),
+            "aster_backend_ping_latency.test.127_0_0_1_7001"
+        );
+        assert_eq!(statsd_name("aster_cpu", &[]), "aster_cpu");
+    }
+
+    #[test]
+    fn test_statsd_export() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(IO_TIMEOUT)))
+            .unwrap();
+        let endpoint = receiver.local_addr().unwrap().to_string();
+        let mut exporter = StatsdExporter::new(&endpoint).unwrap();
+
+        let (registry, counter) = registry();
+        counter.with_label_values(&["test", "redis-1"]).inc_by(3);
+        exporter.export(&registry.gather()).unwrap();
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let size = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &b"aster_test_requests.test.redis-1:3|c"[..]);
+
+        // the delta since the last push, the unchanged one is skipped
+        counter.with_label_values(&["test", "redis-1"]).inc_by(2);
+        counter.with_label_values(&["test", "redis-2"]).inc();
+        let lines = exporter.lines(&registry.gather());
+        assert_eq!(
+            lines,
+            vec![
+                "aster_test_requests.test.redis-1:2|c".to_string(),
+                "aster_test_requests.test.redis-2:1|c".to_string(),
+            ]
+        );
+        assert!(exporter.lines(&registry.gather()).is_empty());
+    }
+
+    #[test]
+    fn test_otlp_request() {
+        let (registry, counter) = registry();
+        counter.with_label_values(&["test", "redis-1"]).inc_by(3);
+        let body = serde_json::to_string(&otlp_request(&registry.gather(), 42)).unwrap();
+        assert!(body.contains(r#""name":"aster_test_requests""#));
+        assert!(body.contains(r#""aggregationTemporality":2,"isMonotonic":true"#));
+        assert!(body.contains(r#"{"key":"node","value":{"stringValue":"redis-1"}}"#));
+        assert!(body.contains(r#""timeUnixNano":"42","asDouble":3.0"#));
+    }
+
+    #[test]
+    fn test_otlp_endpoint() {
+        let exporter = OtlpExporter::new("http://127.0.0.1:4318").unwrap();
+        assert_eq!(exporter.authority, "127.0.0.1:4318");
+        assert_eq!(exporter.path, "/v1/metrics");
+        let exporter = OtlpExporter::new("http://collector:4318/otlp/v1/metrics").unwrap();
+        assert_eq!(exporter.path, "/otlp/v1/metrics");
+        assert!(OtlpExporter::new("https://collector:4318").is_err());
+        assert!(OtlpExporter::new("http:///v1/metrics").is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! the metrics without prometheus, the counters read by proxy itself are kept alone and the
+//! others are dropped.
+use super::{ClusterStats, ListenerStats, Tracker};
+use crate::com::AsError;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+lazy_static! {
+    static ref OUTSTANDING_SUBS: Mutex<HashMap<String, IntGauge>> = Mutex::new(HashMap::new());
+}
+
+/// the gauge shared by the clones, the same as the one of prometheus.
+#[derive(Clone, Debug, Default)]
+pub struct IntGauge(Arc<AtomicI64>);
+
+impl IntGauge {
+    pub fn inc(&self) {
+        self.add(1)
+    }
+
+    pub fn dec(&self) {
+        self.sub(1)
+    }
+
+    pub fn add(&self, v: i64) {
+        self.0.fetch_add(v, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, v: i64) {
+        self.0.fetch_sub(v, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed)
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// the counter shared by the clones, the same as the one of prometheus.
+#[derive(Clone, Debug, Default)]
+pub struct IntCounter(Arc<AtomicU64>);
+
+impl IntCounter {
+    pub fn inc(&self) {
+        self.inc_by(1)
+    }
+
+    pub fn inc_by(&self, v: u64) {
+        self.0.fetch_add(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// the histogram dropping all the observed.
+#[derive(Clone, Debug, Default)]
+pub struct Histogram;
+
+impl Histogram {
+    pub fn observe(&self, _v: f64) {}
+}
+
+pub fn front_conn_incr(_cluster: &str) {}
+
+pub fn front_conn_decr(_cluster: &str) {}
+
+pub fn cluster_stats(_cluster: &str) -> ClusterStats {
+    ClusterStats::default()
+}
+
+pub fn listener_stats(_cluster: &str, _listener: &str) -> ListenerStats {
+    ListenerStats::default()
+}
+
+pub fn listener_conn_incr(_cluster: &str, _listener: &str) {}
+
+pub fn listener_conn_decr(_cluster: &str, _listener: &str) {}
+
+pub fn listener_requests(_cluster: &str, _listener: &str) -> IntCounter {
+    IntCounter::default()
+}
+
+pub fn standby_active_set(_cluster: &str, _active: bool) {}
+
+pub fn slow_start_ramp_set(_cluster: &str, _node: &str, _fraction: f64) {}
+
+pub fn ping_latency_set(_cluster: &str, _node: &str, _millis: f64) {}
+
+pub fn backend_timeout_set(_cluster: &str, _node: &str, _millis: f64) {}
+
+pub fn slo_burn_rate_set(_cluster: &str, _command: &str, _window: &str, _rate: f64) {}
+
+pub fn slo_burn_rate_remove(_cluster: &str, _command: &str, _window: &str) {}
+
+pub fn connection_memory_set(_cluster: &str, _kind: &str, _bytes: usize) {}
+
+pub fn memory_closed_incr(_cluster: &str) {}
+
+pub fn capture_dropped_incr(_cluster: &str) {}
+
+#[cfg(test)]
+pub fn capture_dropped_get(_cluster: &str) -> u64 {
+    0
+}
+
+pub fn fault_injected_incr(_cluster: &str, _fault: &str) {}
+
+#[cfg(test)]
+pub fn fault_injected_get(_cluster: &str, _fault: &str) -> u64 {
+    0
+}
+
+pub fn dedup_writes_incr(_cluster: &str) {}
+
+pub fn response_cache_incr(_cluster: &str, _result: &str) {}
+
+pub fn output_limit_closed_incr(_cluster: &str) {}
+
+#[cfg(test)]
+pub fn output_limit_closed_get(_cluster: &str) -> u64 {
+    0
+}
+
+pub fn reply_paced_incr(_cluster: &str) {}
+
+#[cfg(test)]
+pub fn reply_paced_get(_cluster: &str) -> u64 {
+    0
+}
+
+pub fn access_log_dropped_incr(_cluster: &str) {}
+
+pub fn reply_mismatch_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn reply_mismatch_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn reply_too_large_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn reply_too_large_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn protocol_error_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn protocol_error_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn retry_budget_denied_incr(_cluster: &str) {}
+
+pub fn queue_timeout_incr(_cluster: &str) {}
+
+#[cfg(test)]
+pub fn queue_timeout_get(_cluster: &str) -> u64 {
+    0
+}
+
+pub fn role_confusion_incr(_cluster: &str, _node: &str, _role: &str) {}
+
+pub fn session_read_incr(_cluster: &str, _role: &str) {}
+
+#[cfg(test)]
+pub fn session_read_get(_cluster: &str, _role: &str) -> u64 {
+    0
+}
+
+pub fn keyless_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn keyless_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn outstanding_subs(cluster: &str) -> IntGauge {
+    let mut gauges = OUTSTANDING_SUBS.lock().unwrap();
+    gauges.entry(cluster.to_string()).or_default().clone()
+}
+
+pub fn client_deadline_expired_incr(_cluster: &str, _stage: &str) {}
+
+#[cfg(test)]
+pub fn client_deadline_expired_get(_cluster: &str, _stage: &str) -> u64 {
+    0
+}
+
+pub fn tombstone_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn tombstone_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn integrity_check_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn integrity_check_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn integrity_mismatch_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn integrity_mismatch_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn idle_eviction_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn idle_eviction_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn backend_recycled_incr(_cluster: &str, _node: &str) {}
+
+#[cfg(test)]
+pub fn backend_recycled_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn reload_drain_inflight_add(_cluster: &str, _node: &str, _delta: f64) {}
+
+#[cfg(test)]
+pub fn reload_drain_inflight_get(_cluster: &str, _node: &str) -> f64 {
+    0.0
+}
+
+pub fn reload_drain_observe(_cluster: &str, _result: &str, _dur: Duration) {}
+
+#[cfg(test)]
+pub fn reload_drain_get(_cluster: &str, _result: &str) -> u64 {
+    0
+}
+
+pub fn reload_drain_failed_incr(_cluster: &str, _node: &str, _count: usize) {}
+
+#[cfg(test)]
+pub fn reload_drain_failed_get(_cluster: &str, _node: &str) -> u64 {
+    0
+}
+
+pub fn link_handshake_incr(_cluster: &str, _peer: &str, _result: &str) {}
+
+#[cfg(test)]
+pub fn link_handshake_get(_cluster: &str, _peer: &str, _result: &str) -> u64 {
+    0
+}
+
+pub fn link_saved_add(_cluster: &str, _peer: &str, _direction: &str, _size: usize) {}
+
+#[cfg(test)]
+pub fn link_saved_get(_cluster: &str, _peer: &str, _direction: &str) -> u64 {
+    0
+}
+
+pub fn global_error_incr() {}
+
+pub fn reply_missing_incr(_command: &str) {}
+
+#[cfg(test)]
+pub fn reply_missing_get(_command: &str) -> u64 {
+    0
+}
+
+pub fn notify_wakeup_incr() {}
+
+pub fn notify_reregister_incr() {}
+
+pub fn error_type_incr(_command: &str, _err: &AsError) {}
+
+#[cfg(test)]
+pub fn error_type_get(_command: &str, _class: &str) -> u64 {
+    0
+}
+
+pub fn backend_connect_observe(_cluster: &str, _node: &str, _handshake: &str, _dur: Duration) {}
+
+#[cfg(test)]
+pub fn backend_connect_count(_cluster: &str, _node: &str, _handshake: &str) -> u64 {
+    0
+}
+
+pub fn self_probe_observe(_cluster: &str, _dur: Duration) {}
+
+#[cfg(test)]
+pub fn self_probe_count(_cluster: &str) -> u64 {
+    0
+}
+
+pub fn self_probe_error_incr(_cluster: &str) {}
+
+pub fn accept_observe(_cluster: &str, _dur: Duration) {}
+
+#[cfg(test)]
+pub fn accepted_get(_cluster: &str) -> u64 {
+    0
+}
+
+pub fn accept_paced_incr(_cluster: &str) {}
+
+#[cfg(test)]
+pub fn accept_paced_get(_cluster: &str) -> u64 {
+    0
+}
+
+pub fn remote_tracker(_cluster: &str) -> Tracker {
+    Tracker::new(Histogram::default())
+}
+
+pub fn total_tracker(_cluster: &str) -> Tracker {
+    Tracker::new(Histogram::default())
+}
+
+pub fn thread_incr() {}
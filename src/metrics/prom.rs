@@ -0,0 +1,984 @@
+use super::{ClusterStats, ListenerStats, Tracker};
+use crate::com::AsError;
+use crate::proxy::accept;
+use crate::ASTER_VERSION as VERSION;
+
+use std::thread;
+use std::time::Duration;
+
+use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use prometheus::{
+    self, Encoder, Gauge, GaugeVec, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use sysinfo::{ProcessExt, SystemExt};
+
+pub use prometheus::{Histogram, IntCounter, IntGauge};
+
+lazy_static! {
+    static ref ASTER_FRONT_CONNECTIONS: GaugeVec = {
+        let opt = opts!(
+            "aster_front_connection",
+            "each front nodes connections gauge"
+        );
+        register_gauge_vec!(opt, &["cluster"]).unwrap()
+    };
+
+    static ref ASTER_FRONT_INCR: IntCounterVec = {
+        let opt = opts!(
+            "aster_front_connection_incr",
+            "each front nodes connections gauge"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_LISTENER_CONNECTIONS: GaugeVec = {
+        let opt = opts!(
+            "aster_listener_connection",
+            "front connections of each listener of cluster gauge"
+        );
+        register_gauge_vec!(opt, &["cluster", "listener"]).unwrap()
+    };
+    static ref ASTER_LISTENER_INCR: IntCounterVec = {
+        let opt = opts!(
+            "aster_listener_connection_incr",
+            "front connections accepted by each listener of cluster counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "listener"]).unwrap()
+    };
+    static ref ASTER_LISTENER_REQUESTS: IntCounterVec = {
+        let opt = opts!(
+            "aster_listener_requests",
+            "requests received by each listener of cluster counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "listener"]).unwrap()
+    };
+    static ref ASTER_STANDBY_ACTIVE: GaugeVec = {
+        let opt = opts!(
+            "aster_standby_active",
+            "each cluster is serving by standby backends gauge"
+        );
+        register_gauge_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_SLOW_START_RAMP: GaugeVec = {
+        let opt = opts!(
+            "aster_slow_start_ramp",
+            "fraction of keys routed to each warming backend gauge"
+        );
+        register_gauge_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_PING_LATENCY: GaugeVec = {
+        let opt = opts!(
+            "aster_backend_ping_latency",
+            "moving average of ping latency in millis of each backend gauge"
+        );
+        register_gauge_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_BACKEND_TIMEOUT: GaugeVec = {
+        let opt = opts!(
+            "aster_backend_timeout",
+            "effective timeout in millis of each backend adapted to its replies gauge"
+        );
+        register_gauge_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_RELOAD_DRAIN_INFLIGHT: GaugeVec = {
+        let opt = opts!(
+            "aster_reload_drain_inflight",
+            "commands in flight to each backend removed by reload and draining gauge"
+        );
+        register_gauge_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_CONNECTION_MEMORY: GaugeVec = {
+        let opt = opts!(
+            "aster_connection_memory",
+            "approximate bytes of front or back connections gauge"
+        );
+        register_gauge_vec!(opt, &["cluster", "kind"]).unwrap()
+    };
+    static ref ASTER_SLO_BURN_RATE: GaugeVec = {
+        let opt = opts!(
+            "aster_slo_burn_rate",
+            "error budget burn rate of each latency objective of cluster by window gauge"
+        );
+        register_gauge_vec!(opt, &["cluster", "command", "window"]).unwrap()
+    };
+    static ref ASTER_MEMORY_CLOSED: IntCounterVec = {
+        let opt = opts!(
+            "aster_memory_closed",
+            "front connections closed by the hard ceiling of max memory counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_CAPTURE_DROPPED: IntCounterVec = {
+        let opt = opts!(
+            "aster_capture_dropped",
+            "captured records dropped due to overload counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_FAULT_INJECTED: IntCounterVec = {
+        let opt = opts!(
+            "aster_fault_injected",
+            "faults injected by admin api for resilience testing counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "fault"]).unwrap()
+    };
+    static ref ASTER_DEDUP_WRITES: IntCounterVec = {
+        let opt = opts!(
+            "aster_dedup_writes",
+            "duplicate writes replied by the identical one in flight counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_RESPONSE_CACHE: IntCounterVec = {
+        let opt = opts!(
+            "aster_response_cache",
+            "reads looked up in response cache counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "result"]).unwrap()
+    };
+    static ref ASTER_OUTPUT_LIMIT_CLOSED: IntCounterVec = {
+        let opt = opts!(
+            "aster_output_limit_closed",
+            "front connections closed by output buffer limit counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_REPLY_PACED: IntCounterVec = {
+        let opt = opts!(
+            "aster_reply_paced",
+            "front connections paced by reply_pacing counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_ACCESS_LOG_DROPPED: IntCounterVec = {
+        let opt = opts!(
+            "aster_access_log_dropped",
+            "access log lines dropped due to the writer is overloaded counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_REPLY_MISMATCH: IntCounterVec = {
+        let opt = opts!(
+            "aster_backend_reply_mismatch",
+            "backend connections closed by the replies shifted or surplus counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_REPLY_TOO_LARGE: IntCounterVec = {
+        let opt = opts!(
+            "aster_backend_reply_too_large",
+            "backend connections closed by the replies beyond max_reply_size counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_PROTOCOL_ERROR: IntCounterVec = {
+        let opt = opts!(
+            "aster_backend_protocol_error",
+            "backend connections closed by the violations of reply framing counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_RETRY_BUDGET_DENIED: IntCounterVec = {
+        let opt = opts!(
+            "aster_retry_budget_denied",
+            "retries skipped by the exhausted retry_budget counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_QUEUE_TIMEOUT: IntCounterVec = {
+        let opt = opts!(
+            "aster_queue_timeout",
+            "commands failed by waiting longer than queue_timeout before dispatched counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_ROLE_CONFUSION: IntCounterVec = {
+        let opt = opts!(
+            "aster_cluster_role_confusion",
+            "commands refused by READONLY of the node whose role was mistaken counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node", "role"]).unwrap()
+    };
+    static ref ASTER_SESSION_READS: IntCounterVec = {
+        let opt = opts!(
+            "aster_session_reads",
+            "reads of replica_read_consistency session by the role they are routed to counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "role"]).unwrap()
+    };
+    static ref ASTER_KEYLESS_REQUESTS: IntCounterVec = {
+        let opt = opts!(
+            "aster_keyless_requests",
+            "commands without key routed by keyless_policy counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_OUTSTANDING_SUBS: IntGaugeVec = {
+        let opt = opts!(
+            "aster_outstanding_subs",
+            "subs of fan-out commands in flight, limited by max_outstanding_subs"
+        );
+        register_int_gauge_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_CLIENT_DEADLINE_EXPIRED: IntCounterVec = {
+        let opt = opts!(
+            "aster_client_deadline_expired",
+            "commands given up by the expired ASTER DEADLINE of clients counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "stage"]).unwrap()
+    };
+    static ref ASTER_TOMBSTONE_REPLIES: IntCounterVec = {
+        let opt = opts!(
+            "aster_tombstone_replies",
+            "reads to ejected backends replied by tombstone_commands counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_INTEGRITY_CHECKS: IntCounterVec = {
+        let opt = opts!(
+            "aster_integrity_checks",
+            "reads sampled by integrity_sample and compared with their replays counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_INTEGRITY_MISMATCHES: IntCounterVec = {
+        let opt = opts!(
+            "aster_integrity_mismatches",
+            "reads sampled by integrity_sample whose replays replied different values counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_IDLE_EVICTIONS: IntCounterVec = {
+        let opt = opts!(
+            "aster_backend_idle_evictions",
+            "backend connections closed by backend_idle_timeout counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_BACKEND_RECYCLED: IntCounterVec = {
+        let opt = opts!(
+            "aster_backend_recycled",
+            "backend connections recycled by backend_max_lifetime counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_RELOAD_DRAIN_FAILED: IntCounterVec = {
+        let opt = opts!(
+            "aster_reload_drain_failed",
+            "commands in flight failed by reload_drain_timeout of each removed backend counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "node"]).unwrap()
+    };
+    static ref ASTER_LINK_HANDSHAKES: IntCounterVec = {
+        let opt = opts!(
+            "aster_link_handshakes",
+            "links to or from other aster by the result of handshake counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "peer", "result"]).unwrap()
+    };
+    static ref ASTER_LINK_SAVED_BYTES: IntCounterVec = {
+        let opt = opts!(
+            "aster_link_saved_bytes",
+            "bytes saved by the compression of links counter"
+        );
+        register_int_counter_vec!(opt, &["cluster", "peer", "direction"]).unwrap()
+    };
+    static ref ASTER_ACCEPTED: IntCounterVec = {
+        let opt = opts!(
+            "aster_accepted_connections",
+            "connections accepted by the listeners counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_ACCEPT_PACED: IntCounterVec = {
+        let opt = opts!(
+            "aster_accept_paced",
+            "accepting paused by accept_pacing counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_LISTEN_OVERFLOWS: Gauge = {
+        let opt = opts!(
+            "aster_listen_overflows",
+            "connections dropped by the full accept queues of the host, linux only"
+        );
+        register_gauge!(opt).unwrap()
+    };
+    static ref ASTER_VERSION: GaugeVec = {
+        let opt = opts!("aster_version", "aster current running version");
+        register_gauge_vec!(opt, &["version"]).unwrap()
+    };
+    // static ref ASTER_PID: GaugeVec = {
+    //     let opt = opts!("aster_pid", "aster current processs id");
+    //     register_gauge_vec!(opt, &["pid"]).unwrap()
+    // };
+    static ref ASTER_MEMORY: Gauge = {
+        let opt = opts!("aster_memory_usage", "aster current memory usage");
+        register_gauge!(opt).unwrap()
+    };
+    static ref ASTER_CPU: Gauge = {
+        let opt = opts!("aster_cpu_usage", "aster current cpu usage");
+        register_gauge!(opt).unwrap()
+    };
+    static ref ASTER_THREADS: IntCounter = {
+        let opt = opts!("aster_thread_count", "aster thread count counter");
+        register_int_counter!(opt).unwrap()
+    };
+    static ref ASTER_REPLY_MISSING: IntCounterVec = {
+        let opt = opts!(
+            "aster_reply_missing",
+            "commands encoded to client without reply counter"
+        );
+        register_int_counter_vec!(opt, &["command"]).unwrap()
+    };
+    static ref ASTER_ERROR_BY_TYPE: IntCounterVec = {
+        let opt = opts!(
+            "aster_error_by_type",
+            "error counter by command and error class"
+        );
+        register_int_counter_vec!(opt, &["command", "class"]).unwrap()
+    };
+    static ref ASTER_NOTIFY_WAKEUPS: IntCounter = {
+        let opt = opts!(
+            "aster_notify_wakeups",
+            "tasks notified by the commands done counter"
+        );
+        register_int_counter!(opt).unwrap()
+    };
+    static ref ASTER_NOTIFY_REREGISTERS: IntCounter = {
+        let opt = opts!(
+            "aster_notify_reregisters",
+            "tasks registered to be notified by the commands counter"
+        );
+        register_int_counter!(opt).unwrap()
+    };
+    static ref ASTER_GLOBAL_ERROR: IntCounter = {
+        let opt = opts!("aster_global_error", "aster global error counter");
+        register_int_counter!(opt).unwrap()
+    };
+    static ref ASTER_TOTAL_TIMER: HistogramVec = {
+        register_histogram_vec!(
+            "aster_total_timer",
+            "set up each cluster command proxy total timer",
+            &["cluster"],
+            vec![1_000.0, 10_000.0, 40_000.0, 100_000.0, 200_000.0]
+        )
+        .unwrap()
+    };
+    static ref ASTER_BACKEND_CONNECT_TIMER: HistogramVec = {
+        register_histogram_vec!(
+            "aster_backend_connect_timer",
+            "set up each backend node connection establishment timer by handshake",
+            &["cluster", "node", "handshake"],
+            vec![100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0]
+        )
+        .unwrap()
+    };
+    static ref ASTER_SELF_PROBE_TIMER: HistogramVec = {
+        register_histogram_vec!(
+            "aster_self_probe_timer",
+            "set up each cluster self probe round trip timer through the proxy",
+            &["cluster"],
+            vec![1_000.0, 10_000.0, 40_000.0, 100_000.0, 200_000.0]
+        )
+        .unwrap()
+    };
+    static ref ASTER_SELF_PROBE_ERROR: IntCounterVec = {
+        let opt = opts!(
+            "aster_self_probe_error",
+            "self probes failed or timed out of each cluster counter"
+        );
+        register_int_counter_vec!(opt, &["cluster"]).unwrap()
+    };
+    static ref ASTER_ACCEPT_TIMER: HistogramVec = {
+        register_histogram_vec!(
+            "aster_accept_timer",
+            "set up each cluster time spent in accepting one connection timer",
+            &["cluster"],
+            vec![10.0, 100.0, 1_000.0, 10_000.0]
+        )
+        .unwrap()
+    };
+    static ref ASTER_RELOAD_DRAIN_TIMER: HistogramVec = {
+        register_histogram_vec!(
+            "aster_reload_drain_timer",
+            "set up each cluster drain of backends removed by reload timer by result",
+            &["cluster", "result"],
+            vec![10_000.0, 100_000.0, 1_000_000.0, 10_000_000.0, 60_000_000.0]
+        )
+        .unwrap()
+    };
+    static ref ASTER_REMOTE_TIMER: HistogramVec = {
+        register_histogram_vec!(
+            "aster_remote_timer",
+            "set up each cluster command proxy remote timer",
+            &["cluster"],
+            vec![1_000.0, 10_000.0, 100_000.0]
+        )
+        .unwrap()
+    };
+}
+
+pub fn front_conn_incr(cluster: &str) {
+    ASTER_FRONT_INCR.with_label_values(&[cluster]).inc();
+    ASTER_FRONT_CONNECTIONS.with_label_values(&[cluster]).inc()
+}
+
+pub fn front_conn_decr(cluster: &str) {
+    ASTER_FRONT_CONNECTIONS.with_label_values(&[cluster]).dec()
+}
+
+pub fn cluster_stats(cluster: &str) -> ClusterStats {
+    ClusterStats {
+        connections: ASTER_FRONT_CONNECTIONS.with_label_values(&[cluster]).get() as i64,
+        accepted: ASTER_FRONT_INCR.with_label_values(&[cluster]).get(),
+        requests: ASTER_TOTAL_TIMER
+            .with_label_values(&[cluster])
+            .get_sample_count(),
+        remote_requests: ASTER_REMOTE_TIMER
+            .with_label_values(&[cluster])
+            .get_sample_count(),
+    }
+}
+
+pub fn listener_stats(cluster: &str, listener: &str) -> ListenerStats {
+    let labels = &[cluster, listener];
+    ListenerStats {
+        connections: ASTER_LISTENER_CONNECTIONS.with_label_values(labels).get() as i64,
+        accepted: ASTER_LISTENER_INCR.with_label_values(labels).get(),
+        requests: ASTER_LISTENER_REQUESTS.with_label_values(labels).get(),
+    }
+}
+
+pub fn listener_conn_incr(cluster: &str, listener: &str) {
+    let labels = &[cluster, listener];
+    ASTER_LISTENER_INCR.with_label_values(labels).inc();
+    ASTER_LISTENER_CONNECTIONS.with_label_values(labels).inc()
+}
+
+pub fn listener_conn_decr(cluster: &str, listener: &str) {
+    ASTER_LISTENER_CONNECTIONS
+        .with_label_values(&[cluster, listener])
+        .dec()
+}
+
+/// the counter of requests of the listener, which is kept by each front connection.
+pub fn listener_requests(cluster: &str, listener: &str) -> IntCounter {
+    ASTER_LISTENER_REQUESTS.with_label_values(&[cluster, listener])
+}
+
+pub fn standby_active_set(cluster: &str, active: bool) {
+    let value = if active { 1.0 } else { 0.0 };
+    ASTER_STANDBY_ACTIVE
+        .with_label_values(&[cluster])
+        .set(value)
+}
+
+pub fn slow_start_ramp_set(cluster: &str, node: &str, fraction: f64) {
+    ASTER_SLOW_START_RAMP
+        .with_label_values(&[cluster, node])
+        .set(fraction)
+}
+
+pub fn ping_latency_set(cluster: &str, node: &str, millis: f64) {
+    ASTER_PING_LATENCY
+        .with_label_values(&[cluster, node])
+        .set(millis)
+}
+
+pub fn backend_timeout_set(cluster: &str, node: &str, millis: f64) {
+    ASTER_BACKEND_TIMEOUT
+        .with_label_values(&[cluster, node])
+        .set(millis)
+}
+
+pub fn slo_burn_rate_set(cluster: &str, command: &str, window: &str, rate: f64) {
+    ASTER_SLO_BURN_RATE
+        .with_label_values(&[cluster, command, window])
+        .set(rate)
+}
+
+pub fn slo_burn_rate_remove(cluster: &str, command: &str, window: &str) {
+    let _ = ASTER_SLO_BURN_RATE.remove_label_values(&[cluster, command, window]);
+}
+
+pub fn connection_memory_set(cluster: &str, kind: &str, bytes: usize) {
+    ASTER_CONNECTION_MEMORY
+        .with_label_values(&[cluster, kind])
+        .set(bytes as f64)
+}
+
+pub fn memory_closed_incr(cluster: &str) {
+    ASTER_MEMORY_CLOSED.with_label_values(&[cluster]).inc()
+}
+
+pub fn capture_dropped_incr(cluster: &str) {
+    ASTER_CAPTURE_DROPPED.with_label_values(&[cluster]).inc()
+}
+
+#[cfg(test)]
+pub fn capture_dropped_get(cluster: &str) -> u64 {
+    ASTER_CAPTURE_DROPPED.with_label_values(&[cluster]).get()
+}
+
+pub fn fault_injected_incr(cluster: &str, fault: &str) {
+    ASTER_FAULT_INJECTED
+        .with_label_values(&[cluster, fault])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn fault_injected_get(cluster: &str, fault: &str) -> u64 {
+    ASTER_FAULT_INJECTED
+        .with_label_values(&[cluster, fault])
+        .get()
+}
+
+pub fn dedup_writes_incr(cluster: &str) {
+    ASTER_DEDUP_WRITES.with_label_values(&[cluster]).inc()
+}
+
+/// result is hit or miss.
+pub fn response_cache_incr(cluster: &str, result: &str) {
+    ASTER_RESPONSE_CACHE
+        .with_label_values(&[cluster, result])
+        .inc()
+}
+
+pub fn output_limit_closed_incr(cluster: &str) {
+    ASTER_OUTPUT_LIMIT_CLOSED
+        .with_label_values(&[cluster])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn output_limit_closed_get(cluster: &str) -> u64 {
+    ASTER_OUTPUT_LIMIT_CLOSED
+        .with_label_values(&[cluster])
+        .get()
+}
+
+pub fn reply_paced_incr(cluster: &str) {
+    ASTER_REPLY_PACED.with_label_values(&[cluster]).inc()
+}
+
+#[cfg(test)]
+pub fn reply_paced_get(cluster: &str) -> u64 {
+    ASTER_REPLY_PACED.with_label_values(&[cluster]).get()
+}
+
+pub fn access_log_dropped_incr(cluster: &str) {
+    ASTER_ACCESS_LOG_DROPPED.with_label_values(&[cluster]).inc()
+}
+
+pub fn reply_mismatch_incr(cluster: &str, node: &str) {
+    ASTER_REPLY_MISMATCH
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn reply_mismatch_get(cluster: &str, node: &str) -> u64 {
+    ASTER_REPLY_MISMATCH
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn reply_too_large_incr(cluster: &str, node: &str) {
+    ASTER_REPLY_TOO_LARGE
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn reply_too_large_get(cluster: &str, node: &str) -> u64 {
+    ASTER_REPLY_TOO_LARGE
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn protocol_error_incr(cluster: &str, node: &str) {
+    ASTER_PROTOCOL_ERROR
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn protocol_error_get(cluster: &str, node: &str) -> u64 {
+    ASTER_PROTOCOL_ERROR
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn retry_budget_denied_incr(cluster: &str) {
+    ASTER_RETRY_BUDGET_DENIED
+        .with_label_values(&[cluster])
+        .inc()
+}
+
+pub fn queue_timeout_incr(cluster: &str) {
+    ASTER_QUEUE_TIMEOUT.with_label_values(&[cluster]).inc()
+}
+
+#[cfg(test)]
+pub fn queue_timeout_get(cluster: &str) -> u64 {
+    ASTER_QUEUE_TIMEOUT.with_label_values(&[cluster]).get()
+}
+
+/// the role is the one the node was taken as when the command was routed to it.
+pub fn role_confusion_incr(cluster: &str, node: &str, role: &str) {
+    ASTER_ROLE_CONFUSION
+        .with_label_values(&[cluster, node, role])
+        .inc()
+}
+
+/// the role is master for the reads of the keys written recently, otherwise replica.
+pub fn session_read_incr(cluster: &str, role: &str) {
+    ASTER_SESSION_READS
+        .with_label_values(&[cluster, role])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn session_read_get(cluster: &str, role: &str) -> u64 {
+    ASTER_SESSION_READS
+        .with_label_values(&[cluster, role])
+        .get()
+}
+
+/// the node is the backend address, or proxy for the ones replied by proxy itself.
+pub fn keyless_incr(cluster: &str, node: &str) {
+    ASTER_KEYLESS_REQUESTS
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn keyless_get(cluster: &str, node: &str) -> u64 {
+    ASTER_KEYLESS_REQUESTS
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+/// the gauge of the subs in flight of cluster, which is kept by the front codecs, see SubsLimit.
+pub fn outstanding_subs(cluster: &str) -> IntGauge {
+    ASTER_OUTSTANDING_SUBS.with_label_values(&[cluster])
+}
+
+/// stage is dispatch for the command dropped before sent, or backend for the one in flight.
+pub fn client_deadline_expired_incr(cluster: &str, stage: &str) {
+    ASTER_CLIENT_DEADLINE_EXPIRED
+        .with_label_values(&[cluster, stage])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn client_deadline_expired_get(cluster: &str, stage: &str) -> u64 {
+    ASTER_CLIENT_DEADLINE_EXPIRED
+        .with_label_values(&[cluster, stage])
+        .get()
+}
+
+pub fn tombstone_incr(cluster: &str, node: &str) {
+    ASTER_TOMBSTONE_REPLIES
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn tombstone_get(cluster: &str, node: &str) -> u64 {
+    ASTER_TOMBSTONE_REPLIES
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn integrity_check_incr(cluster: &str, node: &str) {
+    ASTER_INTEGRITY_CHECKS
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn integrity_check_get(cluster: &str, node: &str) -> u64 {
+    ASTER_INTEGRITY_CHECKS
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn integrity_mismatch_incr(cluster: &str, node: &str) {
+    ASTER_INTEGRITY_MISMATCHES
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn integrity_mismatch_get(cluster: &str, node: &str) -> u64 {
+    ASTER_INTEGRITY_MISMATCHES
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn idle_eviction_incr(cluster: &str, node: &str) {
+    ASTER_IDLE_EVICTIONS
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn idle_eviction_get(cluster: &str, node: &str) -> u64 {
+    ASTER_IDLE_EVICTIONS
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn backend_recycled_incr(cluster: &str, node: &str) {
+    ASTER_BACKEND_RECYCLED
+        .with_label_values(&[cluster, node])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn backend_recycled_get(cluster: &str, node: &str) -> u64 {
+    ASTER_BACKEND_RECYCLED
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn reload_drain_inflight_add(cluster: &str, node: &str, delta: f64) {
+    ASTER_RELOAD_DRAIN_INFLIGHT
+        .with_label_values(&[cluster, node])
+        .add(delta)
+}
+
+#[cfg(test)]
+pub fn reload_drain_inflight_get(cluster: &str, node: &str) -> f64 {
+    ASTER_RELOAD_DRAIN_INFLIGHT
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+pub fn reload_drain_observe(cluster: &str, result: &str, dur: Duration) {
+    let micro = f64::from(dur.subsec_nanos()) / 1e3;
+    ASTER_RELOAD_DRAIN_TIMER
+        .with_label_values(&[cluster, result])
+        .observe(micro + (dur.as_secs() as f64 * 1_000_000.0));
+}
+
+#[cfg(test)]
+pub fn reload_drain_get(cluster: &str, result: &str) -> u64 {
+    ASTER_RELOAD_DRAIN_TIMER
+        .with_label_values(&[cluster, result])
+        .get_sample_count()
+}
+
+pub fn reload_drain_failed_incr(cluster: &str, node: &str, count: usize) {
+    ASTER_RELOAD_DRAIN_FAILED
+        .with_label_values(&[cluster, node])
+        .inc_by(count as u64)
+}
+
+#[cfg(test)]
+pub fn reload_drain_failed_get(cluster: &str, node: &str) -> u64 {
+    ASTER_RELOAD_DRAIN_FAILED
+        .with_label_values(&[cluster, node])
+        .get()
+}
+
+/// the result is compressed, or plain for the link fallen back.
+pub fn link_handshake_incr(cluster: &str, peer: &str, result: &str) {
+    ASTER_LINK_HANDSHAKES
+        .with_label_values(&[cluster, peer, result])
+        .inc()
+}
+
+#[cfg(test)]
+pub fn link_handshake_get(cluster: &str, peer: &str, result: &str) -> u64 {
+    ASTER_LINK_HANDSHAKES
+        .with_label_values(&[cluster, peer, result])
+        .get()
+}
+
+/// the plain bytes minus the bytes on the wire, of the frames sent or received.
+pub fn link_saved_add(cluster: &str, peer: &str, direction: &str, size: usize) {
+    ASTER_LINK_SAVED_BYTES
+        .with_label_values(&[cluster, peer, direction])
+        .inc_by(size as u64)
+}
+
+#[cfg(test)]
+pub fn link_saved_get(cluster: &str, peer: &str, direction: &str) -> u64 {
+    ASTER_LINK_SAVED_BYTES
+        .with_label_values(&[cluster, peer, direction])
+        .get()
+}
+
+pub fn global_error_incr() {
+    ASTER_GLOBAL_ERROR.inc();
+}
+
+pub fn notify_wakeup_incr() {
+    ASTER_NOTIFY_WAKEUPS.inc();
+}
+
+pub fn notify_reregister_incr() {
+    ASTER_NOTIFY_REREGISTERS.inc();
+}
+
+pub fn reply_missing_incr(command: &str) {
+    ASTER_REPLY_MISSING.with_label_values(&[command]).inc();
+}
+
+#[cfg(test)]
+pub fn reply_missing_get(command: &str) -> u64 {
+    ASTER_REPLY_MISSING.with_label_values(&[command]).get()
+}
+
+pub fn error_type_incr(command: &str, err: &AsError) {
+    ASTER_ERROR_BY_TYPE
+        .with_label_values(&[command, err.class()])
+        .inc();
+}
+
+#[cfg(test)]
+pub fn error_type_get(command: &str, class: &str) -> u64 {
+    ASTER_ERROR_BY_TYPE
+        .with_label_values(&[command, class])
+        .get()
+}
+
+pub fn backend_connect_observe(cluster: &str, node: &str, handshake: &str, dur: Duration) {
+    let micro = f64::from(dur.subsec_nanos()) / 1e3;
+    ASTER_BACKEND_CONNECT_TIMER
+        .with_label_values(&[cluster, node, handshake])
+        .observe(micro + (dur.as_secs() as f64 * 1_000_000.0));
+}
+
+#[cfg(test)]
+pub fn backend_connect_count(cluster: &str, node: &str, handshake: &str) -> u64 {
+    ASTER_BACKEND_CONNECT_TIMER
+        .with_label_values(&[cluster, node, handshake])
+        .get_sample_count()
+}
+
+/// the round trip of self probe, in the same unit (micros) as aster_total_timer.
+pub fn self_probe_observe(cluster: &str, dur: Duration) {
+    let micro = f64::from(dur.subsec_nanos()) / 1e3;
+    ASTER_SELF_PROBE_TIMER
+        .with_label_values(&[cluster])
+        .observe(micro + (dur.as_secs() as f64 * 1_000_000.0));
+}
+
+#[cfg(test)]
+pub fn self_probe_count(cluster: &str) -> u64 {
+    ASTER_SELF_PROBE_TIMER
+        .with_label_values(&[cluster])
+        .get_sample_count()
+}
+
+pub fn self_probe_error_incr(cluster: &str) {
+    ASTER_SELF_PROBE_ERROR.with_label_values(&[cluster]).inc();
+}
+
+/// the connection accepted and the time spent in accepting it, in micros.
+pub fn accept_observe(cluster: &str, dur: Duration) {
+    ASTER_ACCEPTED.with_label_values(&[cluster]).inc();
+    let micro = f64::from(dur.subsec_nanos()) / 1e3;
+    ASTER_ACCEPT_TIMER
+        .with_label_values(&[cluster])
+        .observe(micro + (dur.as_secs() as f64 * 1_000_000.0));
+}
+
+#[cfg(test)]
+pub fn accepted_get(cluster: &str) -> u64 {
+    ASTER_ACCEPTED.with_label_values(&[cluster]).get()
+}
+
+pub fn accept_paced_incr(cluster: &str) {
+    ASTER_ACCEPT_PACED.with_label_values(&[cluster]).inc();
+}
+
+#[cfg(test)]
+pub fn accept_paced_get(cluster: &str) -> u64 {
+    ASTER_ACCEPT_PACED.with_label_values(&[cluster]).get()
+}
+
+pub fn remote_tracker(cluster: &str) -> Tracker {
+    Tracker::new(ASTER_REMOTE_TIMER.with_label_values(&[cluster]))
+}
+
+pub fn total_tracker(cluster: &str) -> Tracker {
+    Tracker::new(ASTER_TOTAL_TIMER.with_label_values(&[cluster]))
+}
+
+fn show_metrics() -> impl Responder {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    let metric_familys = prometheus::gather();
+    encoder.encode(&metric_familys[..], &mut buffer).unwrap();
+    HttpResponse::Ok().body(buffer)
+}
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(show_metrics));
+    #[cfg(feature = "admin")]
+    crate::admin::routes(cfg);
+}
+
+pub fn thread_incr() {
+    ASTER_THREADS.inc();
+}
+
+pub fn measure_system() -> Result<(), AsError> {
+    // register global thread pool with only one thread to reduce thread number
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build_global()
+        .expect("rayon thread register failed");
+
+    thread_incr();
+    let pid = match sysinfo::get_current_pid() {
+        Ok(pid) => pid,
+        Err(err) => {
+            warn!("fail get pid of current aster due {}", err);
+            return Err(AsError::SystemError);
+        }
+    };
+
+    // ASTER_PID
+    //     .with_label_values(&[&format!("{}", pid.as_u32())])
+    //     .set(1.0);
+    let sleep_interval = Duration::from_secs(30); // 30s to sleep;
+    let mut system = sysinfo::System::new();
+    system.refresh_all();
+    loop {
+        // First we update all information of our system struct.
+        if !system.refresh_process(pid) {
+            return Ok(());
+        }
+        if let Some(process) = system.get_process(pid) {
+            let cpu_usage = process.cpu_usage() as f64;
+            let memory_usage = process.memory() as f64;
+            ASTER_MEMORY.set(memory_usage);
+            ASTER_CPU.set(cpu_usage);
+            if let Some(overflows) = accept::listen_overflows() {
+                ASTER_LISTEN_OVERFLOWS.set(overflows as f64);
+            }
+            thread::sleep(sleep_interval);
+        } else {
+            return Ok(());
+        }
+    }
+}
+
+pub fn init(port: usize) -> Result<(), AsError> {
+    ASTER_VERSION.with_label_values(&[VERSION]).set(1.0);
+    thread_incr();
+    let addr = format!("0.0.0.0:{}", port);
+    info!("listen http metrics port in addr {}", port);
+    HttpServer::new(|| App::new().configure(routes))
+        .shutdown_timeout(3)
+        .disable_signals()
+        .workers(1)
+        .bind(&addr)?
+        .run()?;
+    Ok(())
+}
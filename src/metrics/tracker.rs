@@ -1,6 +1,6 @@
-use prometheus::Histogram;
+use super::Histogram;
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub struct Tracker {
     start: Instant,
@@ -20,6 +20,10 @@ impl Tracker {
             hist,
         }
     }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
 }
 
 impl Drop for Tracker {
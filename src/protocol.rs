@@ -1,5 +1,14 @@
 use bitflags::bitflags;
+use bytes::BytesMut;
 
+use std::fmt;
+
+use crate::com::{AsError, ClusterConfig};
+use crate::metrics::{outstanding_subs, IntGauge};
+use crate::proxy::valuelimit;
+use crate::utils::notify::Reserved;
+
+#[cfg(feature = "memcache")]
 pub mod mc;
 pub mod redis;
 
@@ -13,6 +22,221 @@ impl<T> IntoReply<T> for T {
     }
 }
 
+/// the strategy to merge the replies of sub commands into the reply of fan-out command.
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum Merge {
+    /// the replies in the order of requested keys, e.g.: MGET and mc get.
+    ConcatArray,
+    /// the sum of integer replies, e.g.: DEL and EXISTS.
+    SumIntegers,
+    /// OK once all the subs succeed, e.g.: MSET.
+    AllOk,
+    /// the first reply, unless any sub replies an error.
+    FirstError,
+}
+
+/// the max count of arguments of each command decoded from client by max_args, 0 means no limit.
+/// It's checked once the command is framed, so the command beyond is rejected before it's fanned
+/// out to sub commands, while the framing itself is limited by the parser.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArgsLimit {
+    max: usize,
+    // the client logged for the command rejected, None if max_args_log is off
+    client: Option<String>,
+}
+
+impl ArgsLimit {
+    pub fn new(max: usize, client: Option<String>) -> ArgsLimit {
+        ArgsLimit { max, client }
+    }
+
+    pub fn from_config(cc: &ClusterConfig, client: &str) -> ArgsLimit {
+        let client = if cc.max_args_log.unwrap_or(false) {
+            Some(client.to_string())
+        } else {
+            None
+        };
+        ArgsLimit::new(cc.max_args.unwrap_or(0), client)
+    }
+
+    /// TooManyArgs if the count of arguments including the command name exceeds max.
+    pub fn check(&self, count: usize) -> Result<(), AsError> {
+        if self.max == 0 || count <= self.max {
+            return Ok(());
+        }
+        if let Some(client) = self.client.as_ref() {
+            warn!(
+                "client {} send command of {} arguments beyond max_args {}",
+                client, count, self.max
+            );
+        }
+        Err(AsError::TooManyArgs(self.max))
+    }
+}
+
+/// the max size of each value written by client, the minimum of max_value_size and the limits
+/// learned from backends, 0 means no limit. It's checked once the command is framed like
+/// ArgsLimit, so the write beyond is never sent to backends which would reject it anyway.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ValueLimit {
+    max: usize,
+}
+
+impl ValueLimit {
+    pub fn new(max: usize) -> ValueLimit {
+        ValueLimit { max }
+    }
+
+    pub fn from_config(cc: &ClusterConfig) -> ValueLimit {
+        ValueLimit::new(valuelimit::effective(cc))
+    }
+
+    /// ValueTooLarge if the size of the largest value exceeds max.
+    pub fn check(self, size: usize) -> Result<(), AsError> {
+        if self.max == 0 || size <= self.max {
+            return Ok(());
+        }
+        Err(AsError::ValueTooLarge(self.max))
+    }
+}
+
+/// the max count of the subs of fan-out commands in flight of the cluster across all the worker
+/// threads by max_outstanding_subs, 0 means no limit. The subs are reserved by the gauge of
+/// aster_outstanding_subs once the command is framed and before they are made, and released once
+/// the command and all its subs are dropped, so a storm of multi-key commands is rejected rather
+/// than exhausting the memory.
+#[derive(Clone, Default)]
+pub struct SubsLimit {
+    max: usize,
+    // None if the subs are not tracked, e.g.: the codecs of tests
+    gauge: Option<IntGauge>,
+}
+
+impl SubsLimit {
+    pub fn new(cluster: &str, max: usize) -> SubsLimit {
+        SubsLimit {
+            max,
+            gauge: Some(outstanding_subs(cluster)),
+        }
+    }
+
+    pub fn from_config(cc: &ClusterConfig) -> SubsLimit {
+        SubsLimit::new(&cc.name, cc.max_outstanding_subs.unwrap_or(0))
+    }
+
+    /// reserve count subs, TooManySubs if the subs in flight would exceed max.
+    pub fn reserve(&self, count: usize) -> Result<Option<Reserved>, AsError> {
+        let gauge = match self.gauge.as_ref() {
+            Some(gauge) if count > 0 => gauge,
+            _ => return Ok(None),
+        };
+        // added before checked, so the ones of other threads never exceed max together
+        let reserved = Reserved::new(gauge.clone(), count);
+        if self.max > 0 && gauge.get() as usize > self.max {
+            return Err(AsError::TooManySubs(self.max));
+        }
+        Ok(Some(reserved))
+    }
+}
+
+impl fmt::Debug for SubsLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SubsLimit({})", self.max)
+    }
+}
+
+/// merge the replies of sub commands by the strategy, each protocol decides how the error
+/// replies of subs are carried by the merged reply.
+pub trait ReplyMerge: Sized {
+    /// the reply is an error of backend or proxy.
+    fn is_error_reply(&self) -> bool;
+
+    /// merge replies of subs in the order of requested keys into buf, None means the sub is
+    /// not replied which fails the merging. return the size of merged reply.
+    fn merge(merge: Merge, replies: &[Option<&Self>], buf: &mut BytesMut)
+        -> Result<usize, AsError>;
+
+    /// the reply standing for the sub failed by proxy under best_effort of sub_failures, e.g.:
+    /// nil of MGET, None if the merged reply can't be partial (e.g.: MSET).
+    fn absent(merge: Merge) -> Option<Self>;
+}
+
+/// all the replies of subs, or BadReply if any sub is not replied.
+pub(crate) fn all_replied<'a, T>(replies: &[Option<&'a T>]) -> Result<Vec<&'a T>, AsError> {
+    replies
+        .iter()
+        .cloned()
+        .collect::<Option<Vec<_>>>()
+        .ok_or(AsError::BadReply)
+}
+
+/// the first error reply of subs.
+pub(crate) fn first_error<'a, T: ReplyMerge>(replies: &[&'a T]) -> Option<&'a T> {
+    replies.iter().find(|x| x.is_error_reply()).cloned()
+}
+
+/// release the next wave of at most batch sub commands once the former wave is all done,
+/// batch 0 means release all the sub commands at once.
+pub(crate) fn next_wave<T, F>(
+    subs: &[T],
+    released: &mut usize,
+    batch: usize,
+    is_done: F,
+) -> Option<Vec<T>>
+where
+    T: Clone,
+    F: Fn(&T) -> bool,
+{
+    if *released >= subs.len() {
+        return None;
+    }
+    let former = &subs[released.saturating_sub(batch)..*released];
+    if !former.iter().all(is_done) {
+        return None;
+    }
+    let end = if batch == 0 {
+        subs.len()
+    } else {
+        subs.len().min(*released + batch)
+    };
+    let wave = subs[*released..end].to_vec();
+    *released = end;
+    Some(wave)
+}
+
+/// parse all the messages of data one by one, which is the driver of fuzz targets. parse
+/// returns the raw size of the parsed message, it panics if the guarantees of parsing relied
+/// by the codecs are broken:
+///
+/// - the parsed message consumes exactly its raw bytes.
+/// - the incomplete message consumes nothing.
+/// - the bad message consumes at least one byte, so the loop always ends.
+///
+/// return the count of parsed messages.
+pub fn check_parse<F>(data: &[u8], parse: F) -> usize
+where
+    F: Fn(&mut BytesMut) -> Result<Option<usize>, AsError>,
+{
+    let mut src = BytesMut::from(data);
+    let mut count = 0;
+    while !src.is_empty() {
+        let before = src.len();
+        match parse(&mut src) {
+            Ok(Some(size)) => {
+                assert!(size > 0, "empty message is parsed");
+                assert_eq!(before - src.len(), size, "message consumes other bytes");
+                count += 1;
+            }
+            Ok(None) => {
+                assert_eq!(before, src.len(), "incomplete message is consumed");
+                break;
+            }
+            Err(_) => assert!(src.len() < before, "bad message consumes nothing"),
+        }
+    }
+    count
+}
+
 bitflags! {
     pub struct CmdFlags: u8 {
         const DONE     = 0b00_000_001;
@@ -22,9 +246,10 @@ bitflags! {
         // mc only
         const NOREPLY  = 0b00_001_000;
         const QUIET    = 0b00_010_000;
-
-        // retry
-        // const RETRY    = 0b00100000;
+        // retried once on a stale connection
+        const RETRY    = 0b00_100_000;
+        // redis cluster only, read from master for session consistency
+        const MASTER   = 0b01_000_000;
 
         const ERROR    = 0b10_000_000;
     }
@@ -43,4 +268,56 @@ pub enum CmdType {
     Exists, // Read
     Eval,   // Write
     Del,    // Write
+    Admin,  // denied unless routed to admin node
+}
+
+#[cfg(test)]
+pub(crate) mod fuzz {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const MUTATIONS: usize = 2000;
+
+    /// every valid message of the corpus is parsed only if it's complete, and then the corpus
+    /// is mutated randomly (flip, insert, remove and truncate bytes) with a fixed seed.
+    pub(crate) fn check_corpus<F>(corpus: &[&[u8]], parse: F)
+    where
+        F: Fn(&mut BytesMut) -> Result<Option<usize>, AsError>,
+    {
+        for msg in corpus {
+            for end in 0..msg.len() {
+                let mut src = BytesMut::from(&msg[..end]);
+                let rslt = parse(&mut src);
+                assert!(
+                    rslt.as_ref().map(|x| x.is_none()).unwrap_or(false),
+                    "prefix {:?} of {:?} is parsed as {:?}",
+                    &msg[..end],
+                    msg,
+                    rslt
+                );
+                assert_eq!(src.len(), end);
+            }
+            assert_eq!(check_parse(msg, &parse), 1, "fail to parse {:?}", msg);
+        }
+        let all = corpus.concat();
+        assert_eq!(check_parse(&all, &parse), corpus.len());
+
+        let mut rng = StdRng::seed_from_u64(0x6173_7465_72);
+        for _ in 0..MUTATIONS {
+            let mut data = corpus[rng.gen_range(0, corpus.len())].to_vec();
+            for _ in 0..rng.gen_range(1, 4) {
+                let pos = rng.gen_range(0, data.len() + 1);
+                match rng.gen_range(0, 4) {
+                    0 if pos < data.len() => data[pos] ^= 1 << rng.gen_range(0, 8),
+                    1 => data.insert(pos, rng.gen()),
+                    2 if pos < data.len() => {
+                        data.remove(pos);
+                    }
+                    _ => data.truncate(pos),
+                }
+            }
+            check_parse(&data, &parse);
+        }
+    }
 }
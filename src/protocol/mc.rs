@@ -1,21 +1,31 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use futures::task::Task;
 
 use tokio::codec::{Decoder, Encoder};
 
 use crate::metrics::*;
 
-use crate::com::AsError;
-use crate::protocol::{CmdFlags, CmdType, IntoReply};
-use crate::proxy::standalone::Request;
+use crate::com::{AsError, BackendFlavor, ClusterConfig, FrontProtocol};
+use crate::protocol::{next_wave, ArgsLimit, CmdFlags, CmdType, IntoReply, ReplyMerge};
+use crate::protocol::{SubsLimit, ValueLimit};
+use crate::proxy::acl::{AclReply, Category};
+use crate::proxy::budget::Budget;
+use crate::proxy::standalone::hash::HashMethod;
+use crate::proxy::standalone::hint::RouteHint;
+use crate::proxy::standalone::integrity;
+use crate::proxy::standalone::subfail::SubFailure;
+use crate::proxy::standalone::tombstone::Tombstone;
+use crate::proxy::standalone::{join_nodes, Request};
 use crate::utils::notify::Notify;
 use crate::utils::trim_hash_tag;
 
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 pub mod msg;
 pub use self::msg::Message;
+use self::msg::BIN_HEADER_LEN;
 
 const MAX_CYCLE: u8 = 1;
 
@@ -25,18 +35,6 @@ pub struct Cmd {
     notify: Notify,
 }
 
-impl Drop for Cmd {
-    fn drop(&mut self) {
-        let expect = self.notify.expect();
-        let origin = self.notify.fetch_sub(1);
-        // TODO: sub command maybe notify multiple
-        // trace!("cmd drop strong ref {} and expect {}", origin, expect);
-        if origin - 1 == expect {
-            self.notify.notify();
-        }
-    }
-}
-
 impl Request for Cmd {
     type Reply = Message;
     type FrontCodec = FrontCodec;
@@ -44,17 +42,24 @@ impl Request for Cmd {
 
     fn ping_request() -> Self {
         let cmd = Command {
-            ctype: CmdType::Read,
+            ctype: CmdType::Ctrl,
             flags: CmdFlags::empty(),
             cycle: 0,
 
             req: Message::version_request(),
             reply: None,
             subs: None,
+            released: 0,
 
             total_tracker: None,
 
             remote_tracker: None,
+            node: None,
+            timeout: None,
+            time_limit: None,
+            queued: None,
+            trace: 0,
+            sub_failure: None,
         };
         let mut notify = Notify::empty();
         notify.set_expect(1);
@@ -64,14 +69,42 @@ impl Request for Cmd {
         }
     }
 
+    fn status_reply(line: &str) -> Self {
+        let cmd = Self::ping_request();
+        cmd.set_reply(Message::inline_line(line));
+        cmd
+    }
+
+    fn back_codec(_cc: &ClusterConfig, _node: &str) -> BackCodec {
+        BackCodec::default()
+    }
+
+    fn front_codec(
+        cc: &ClusterConfig,
+        protocol: Option<FrontProtocol>,
+        client: &str,
+    ) -> FrontCodec {
+        FrontCodec {
+            binary: protocol.map(|x| x == FrontProtocol::Binary),
+            lenient: cc.lenient_newline.unwrap_or(false),
+            args_limit: ArgsLimit::from_config(cc, client),
+            value_limit: ValueLimit::from_config(cc),
+            subs_limit: SubsLimit::from_config(cc),
+            error: None,
+        }
+    }
+
     fn reregister(&mut self, task: Task) {
         self.notify.set_task(task);
     }
 
-    fn key_hash(&self, hash_tag: &[u8], hasher: fn(&[u8]) -> u64) -> u64 {
+    fn key_hash(&self, hash_tag: &[u8], hasher: HashMethod) -> Option<u64> {
         let cmd = self.cmd.borrow();
         let key = cmd.req.get_key();
-        hasher(trim_hash_tag(key, hash_tag))
+        if key.is_empty() {
+            return None;
+        }
+        Some(hasher.hash(trim_hash_tag(key, hash_tag)))
     }
 
     fn subs(&self) -> Option<Vec<Self>> {
@@ -79,11 +112,7 @@ impl Request for Cmd {
     }
 
     fn is_done(&self) -> bool {
-        if let Some(subs) = self.subs() {
-            subs.iter().all(|x| x.is_done())
-        } else {
-            self.cmd.borrow().is_done()
-        }
+        self.cmd.borrow().is_done()
     }
 
     fn add_cycle(&self) {
@@ -92,23 +121,379 @@ impl Request for Cmd {
     fn can_cycle(&self) -> bool {
         self.cmd.borrow().can_cycle()
     }
+    fn mark_retry(&self) -> bool {
+        self.cmd.borrow_mut().mark_retry()
+    }
+
+    fn accept_reply(&self, reply: &Message) -> bool {
+        self.cmd.borrow().req.accept_reply(reply)
+    }
 
     fn is_error(&self) -> bool {
         self.cmd.borrow().is_error()
     }
 
+    fn reply_size(&self) -> usize {
+        self.cmd.borrow().reply_size()
+    }
+
     fn valid(&self) -> bool {
         true
     }
 
+    // memcached closes the connection without any reply
+    fn reply_quit(&self) -> bool {
+        if !self.cmd.borrow().req.is_quit_request() {
+            return false;
+        }
+        self.set_reply(Message::raw_inline_reply());
+        true
+    }
+
+    fn reply_tombstone(&self, _tombstone: Tombstone) -> bool {
+        if !self.cmd.borrow().req.is_text_get() {
+            return false;
+        }
+        self.set_reply(Message::inline_line("END"));
+        true
+    }
+
+    fn reply_checksum(&self) -> Option<u64> {
+        let cmd = self.cmd.borrow();
+        if cmd.subs.is_some() || !cmd.req.is_single_get() {
+            return None;
+        }
+        cmd.reply.as_ref().map(|x| integrity::checksum(&x.bytes()))
+    }
+
+    fn integrity_replay(&self) -> Option<Self> {
+        let cmd = self.cmd.borrow();
+        if cmd.subs.is_some() || !cmd.req.is_single_get() {
+            return None;
+        }
+        let command = Command {
+            ctype: CmdType::Read,
+            flags: CmdFlags::empty(),
+            cycle: 0,
+
+            req: Message::text_get(cmd.req.get_key()),
+            reply: None,
+            subs: None,
+            released: 0,
+
+            total_tracker: None,
+
+            remote_tracker: None,
+            node: None,
+            timeout: None,
+            time_limit: None,
+            queued: None,
+            trace: 0,
+            sub_failure: None,
+        };
+        let mut notify = Notify::empty();
+        notify.set_expect(1);
+        Some(Cmd {
+            cmd: Rc::new(RefCell::new(command)),
+            notify,
+        })
+    }
+
     fn set_reply<R: IntoReply<Message>>(&self, t: R) {
         let reply = t.into_reply();
-        self.cmd.borrow_mut().set_reply(reply);
+        self.transit(|cmd| cmd.set_reply(reply));
     }
 
     fn set_error(&self, t: &AsError) {
         let reply: Message = t.into_reply();
-        self.cmd.borrow_mut().set_error(reply);
+        let name = self.cmd.borrow().req.cmd_name();
+        error_type_incr(&name, t);
+        self.transit(|cmd| cmd.set_error(reply));
+    }
+
+    fn is_ctrl(&self) -> bool {
+        self.cmd.borrow().ctype.is_ctrl()
+    }
+
+    fn is_mutation(&self) -> bool {
+        self.cmd.borrow().ctype.is_mutation()
+    }
+
+    fn is_read(&self) -> bool {
+        let cmd = self.cmd.borrow();
+        cmd.ctype.is_read() && !cmd.req.is_keyless()
+    }
+
+    fn is_write(&self) -> bool {
+        let cmd = self.cmd.borrow();
+        cmd.ctype.is_write() && !cmd.req.is_keyless()
+    }
+
+    fn is_admin(&self) -> bool {
+        false
+    }
+
+    fn dangerous_name(&self) -> Option<String> {
+        self.cmd.borrow().req.dangerous_name()
+    }
+
+    fn is_blocking(&self) -> bool {
+        false
+    }
+
+    fn is_keyless(&self) -> bool {
+        self.cmd.borrow().req.is_keyless()
+    }
+
+    fn reply_keyless(&self) -> bool {
+        if !self.cmd.borrow().req.is_version_request() {
+            return false;
+        }
+        self.set_reply(Message::inline_line(&format!(
+            "VERSION {}",
+            crate::ASTER_VERSION
+        )));
+        true
+    }
+
+    fn next_wave(&self, batch: usize) -> Option<Vec<Self>> {
+        self.cmd.borrow_mut().next_wave(batch)
+    }
+
+    fn has_wave(&self) -> bool {
+        self.cmd.borrow().has_wave()
+    }
+
+    fn has_key_prefix(&self, prefix: &[u8]) -> bool {
+        let cmd = self.cmd.borrow();
+        let key = cmd.req.get_key();
+        !key.is_empty() && key.starts_with(prefix)
+    }
+
+    fn with_key<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let cmd = self.cmd.borrow();
+        let key = cmd.req.get_key();
+        if key.is_empty() {
+            return None;
+        }
+        Some(f(key))
+    }
+
+    fn sort_pattern(&self, _hash_tag: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn check_two_keys(&self, _hash_tag: &[u8], _hasher: HashMethod) -> Result<(), AsError> {
+        Ok(())
+    }
+
+    fn bound_timeout(
+        &self,
+        _hash_tag: &[u8],
+        _hasher: HashMethod,
+        _max: u64,
+    ) -> Result<Option<String>, AsError> {
+        Ok(None)
+    }
+
+    fn handle_proxy<F>(&self, _f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<Option<Vec<String>>, AsError>,
+    {
+        false
+    }
+
+    fn barrier_timeout(&self) -> Option<Result<u64, AsError>> {
+        None
+    }
+
+    fn route_hint(&self) -> Option<Result<RouteHint, AsError>> {
+        None
+    }
+
+    fn set_pinned(&self, _addr: &str) {
+        unreachable!("memcache never has PROXY ROUTE")
+    }
+
+    fn pinned(&self) -> Option<String> {
+        None
+    }
+
+    fn broadcast(&self, _addrs: &[String]) -> bool {
+        false
+    }
+
+    fn set_synced(&self, _count: usize) {
+        unreachable!("memcache never has PROXY BARRIER")
+    }
+
+    fn handle_client_kill<F>(&self, _f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<usize, AsError>,
+    {
+        false
+    }
+
+    fn handle_info<F>(&self, _f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> String,
+    {
+        false
+    }
+
+    // users are redis only
+    fn acl_category(&self) -> Category {
+        Category::Connection
+    }
+
+    fn acl_keys(&self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    fn handle_acl<F>(&self, _f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<AclReply, AsError>,
+    {
+        false
+    }
+
+    fn req_data(&self) -> Bytes {
+        self.cmd.borrow().req.bytes()
+    }
+
+    fn reply_data(&self, buf: &mut BytesMut) {
+        self.cmd.borrow().reply_data(buf)
+    }
+
+    fn cmd_name(&self) -> String {
+        self.cmd.borrow().req.cmd_name()
+    }
+
+    fn reply(&self) -> Option<Message> {
+        self.cmd.borrow().reply.clone()
+    }
+
+    fn is_unavailable(&self, _flavor: BackendFlavor) -> bool {
+        false
+    }
+
+    fn is_pong(&self) -> bool {
+        let cmd = self.cmd.borrow();
+        cmd.reply
+            .as_ref()
+            .map(|x| x.is_version_reply())
+            .unwrap_or(false)
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        let cmd = self.cmd.borrow();
+        match cmd.subs.as_ref() {
+            Some(subs) => subs.iter().flat_map(|x| x.keys()).collect(),
+            None => {
+                let key = cmd.req.get_key();
+                if key.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![key.to_vec()]
+                }
+            }
+        }
+    }
+
+    fn set_node(&self, node: &str) {
+        self.cmd.borrow_mut().node = Some(node.to_string());
+    }
+
+    fn node(&self) -> Option<String> {
+        let cmd = self.cmd.borrow();
+        match cmd.subs.as_ref() {
+            Some(subs) => join_nodes(subs.iter().filter_map(|x| x.node())),
+            None => cmd.node.clone(),
+        }
+    }
+
+    fn set_timeout(&self, timeout: Duration) {
+        self.cmd.borrow_mut().timeout = Some(timeout);
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        let cmd = self.cmd.borrow();
+        match cmd.subs.as_ref() {
+            Some(subs) => subs.iter().filter_map(|x| x.timeout()).max(),
+            None => cmd.timeout,
+        }
+    }
+
+    fn set_time_limit(&self, limit: Duration) {
+        self.cmd.borrow_mut().time_limit = Some(limit);
+    }
+
+    fn time_limit(&self) -> Option<Duration> {
+        self.cmd.borrow().time_limit
+    }
+
+    fn set_queued(&self, since: Option<Instant>) {
+        self.cmd.borrow_mut().queued = since;
+    }
+
+    fn queued(&self) -> Option<Instant> {
+        self.cmd.borrow().queued
+    }
+
+    fn handle_deadline<F>(&self, _f: F) -> bool
+    where
+        F: FnOnce(Duration),
+    {
+        false
+    }
+
+    fn set_deadline(&self, _deadline: Instant) {
+        unreachable!("memcache never has ASTER DEADLINE")
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn set_trace_id(&self, id: u64) {
+        for sub in self.subs().unwrap_or_default() {
+            sub.set_trace_id(id);
+        }
+        self.cmd.borrow_mut().trace = id;
+    }
+
+    fn trace_id(&self) -> u64 {
+        self.cmd.borrow().trace
+    }
+
+    fn set_sub_failure(&self, policy: SubFailure) {
+        self.cmd.borrow_mut().sub_failure = Some(policy);
+    }
+
+    fn retry_failed_subs(&self, budget: &Budget) -> Vec<Self> {
+        let failed: Vec<_> = {
+            let cmd = self.cmd.borrow();
+            if cmd.sub_failure != Some(SubFailure::Retry) {
+                return Vec::new();
+            }
+            cmd.subs
+                .iter()
+                .flatten()
+                .filter(|x| x.cmd.borrow().is_error())
+                .cloned()
+                .collect()
+        };
+        let retried: Vec<_> = failed
+            .into_iter()
+            .filter(|x| x.mark_retry() && budget.try_retry())
+            .collect();
+        for sub in retried.iter() {
+            sub.transit(|cmd| cmd.unset_failed());
+        }
+        retried
     }
 
     fn mark_total(&self, cluster: &str) {
@@ -120,14 +505,44 @@ impl Request for Cmd {
         let timer = remote_tracker(cluster);
         self.cmd.borrow_mut().remote_tracker.replace(timer);
     }
+
+    fn total_elapsed(&self) -> Option<Duration> {
+        self.cmd
+            .borrow()
+            .total_tracker
+            .as_ref()
+            .map(|x| x.elapsed())
+    }
 }
 
 impl Cmd {
+    // change the reply of command and count its completion, the parent rejected as a whole
+    // wakes the task at once since its subs are never replied.
+    fn transit<F: FnOnce(&mut Command)>(&self, f: F) {
+        let (was, now, is_sub) = {
+            let mut cmd = self.cmd.borrow_mut();
+            let was = cmd.is_replied();
+            f(&mut cmd);
+            (was, cmd.is_replied(), cmd.subs.is_none())
+        };
+        match (was, now) {
+            (false, true) if is_sub => self.notify.done(),
+            (false, true) => self.notify.notify(),
+            (true, false) if is_sub => self.notify.undone(),
+            _ => {}
+        }
+    }
+
     fn from_msg(msg: Message, mut notify: Notify) -> Cmd {
         let flags = CmdFlags::empty();
         let ctype = CmdType::Read;
+        let msg_ctype = if msg.is_write() {
+            CmdType::Write
+        } else {
+            CmdType::Read
+        };
         let sub_msgs = msg.mk_subs();
-        notify.set_expect((1 + sub_msgs.len()) as u16);
+        notify.set_expect(sub_msgs.len().max(1));
 
         let subs: Vec<_> = sub_msgs
             .into_iter()
@@ -139,10 +554,17 @@ impl Cmd {
                     req: sub_msg,
                     reply: None,
                     subs: None,
+                    released: 0,
 
                     total_tracker: None,
 
                     remote_tracker: None,
+                    node: None,
+                    timeout: None,
+                    time_limit: None,
+                    queued: None,
+                    trace: 0,
+                    sub_failure: None,
                 };
                 Cmd {
                     notify: notify.clone(),
@@ -152,16 +574,23 @@ impl Cmd {
             .collect();
         let subs = if subs.is_empty() { None } else { Some(subs) };
         let command = Command {
-            ctype: CmdType::Read,
+            ctype: msg_ctype,
             flags: CmdFlags::empty(),
             cycle: 0,
             req: msg,
             reply: None,
             subs,
+            released: 0,
 
             total_tracker: None,
 
             remote_tracker: None,
+            node: None,
+            timeout: None,
+            time_limit: None,
+            queued: None,
+            trace: 0,
+            sub_failure: None,
         };
         Cmd {
             cmd: Rc::new(RefCell::new(command)),
@@ -186,17 +615,49 @@ pub struct Command {
     reply: Option<Message>,
 
     subs: Option<Vec<Cmd>>,
+    // count of subs released to dispatch in waves
+    released: usize,
 
     total_tracker: Option<Tracker>,
 
     remote_tracker: Option<Tracker>,
+
+    // backend node dispatched to, only set if access log is enabled
+    node: Option<String>,
+    // timeout of backend in effect when dispatched, only set if access log is enabled
+    timeout: Option<Duration>,
+    // time limit by command_timeouts, only set at dispatch
+    time_limit: Option<Duration>,
+    // since queued in front for dispatch, None once it's sent
+    queued: Option<Instant>,
+    // tick of cluster when decoded, 0 if it's not traced
+    trace: u64,
+    // policy of the subs failed by proxy, None keeps the merging by the command
+    sub_failure: Option<SubFailure>,
 }
 
 impl Command {
+    // the parent is done once every sub is replied unless it's rejected as a whole.
     fn is_done(&self) -> bool {
+        match self.subs.as_ref() {
+            Some(_) if self.is_error() && self.is_replied() => true,
+            Some(subs) => subs.iter().all(|x| x.cmd.borrow().is_done()),
+            None => self.is_replied(),
+        }
+    }
+
+    fn is_replied(&self) -> bool {
         self.flags & CmdFlags::DONE == CmdFlags::DONE
     }
 
+    // the approximate bytes of reply sent to client, which is the raw replies of backend.
+    fn reply_size(&self) -> usize {
+        match self.subs.as_ref() {
+            Some(subs) => subs.iter().map(|x| x.cmd.borrow().reply_size()).sum(),
+            None => self.reply.as_ref().map(|x| x.raw_len()).unwrap_or(0),
+        }
+    }
+
     fn is_error(&self) -> bool {
         self.flags & CmdFlags::ERROR == CmdFlags::ERROR
     }
@@ -209,6 +670,14 @@ impl Command {
         self.cycle += 1;
     }
 
+    pub fn mark_retry(&mut self) -> bool {
+        if !self.can_cycle() || self.flags & CmdFlags::RETRY == CmdFlags::RETRY {
+            return false;
+        }
+        self.flags |= CmdFlags::RETRY;
+        true
+    }
+
     pub fn set_reply(&mut self, reply: Message) {
         self.reply = Some(reply);
         self.set_done();
@@ -216,6 +685,42 @@ impl Command {
         let _ = self.remote_tracker.take();
     }
 
+    // the same as encoded by front codec, but the reply is kept
+    fn reply_data(&self, dst: &mut BytesMut) {
+        if self.subs.is_some() && !self.is_error() {
+            let _ = self.merge_subs(dst);
+        } else if let Some(reply) = self.reply.as_ref() {
+            let _ = self.req.save_reply(reply.clone(), dst);
+        }
+    }
+
+    fn merge_subs(&self, dst: &mut BytesMut) -> Result<usize, AsError> {
+        // the parent is never merged until all its subs are replied
+        if !self.is_done() {
+            return Err(AsError::BadReply);
+        }
+        let subs: Vec<_> = self.subs.iter().flatten().map(|x| x.cmd.borrow()).collect();
+        let merge = self.req.merge();
+        // the failed subs are replied as a whole by merging, unless they're absent by best_effort
+        let absent = match self.sub_failure {
+            Some(SubFailure::BestEffort) => Message::absent(merge),
+            _ => None,
+        };
+        let replies: Vec<_> = subs
+            .iter()
+            .map(|x| match absent.as_ref() {
+                Some(absent) if x.is_error() => Some(absent),
+                _ => x.reply.as_ref(),
+            })
+            .collect();
+        Message::merge(merge, &replies, dst)
+    }
+
+    // the sub failed by proxy is reset to be dispatched again
+    fn unset_failed(&mut self) {
+        self.flags &= !(CmdFlags::DONE | CmdFlags::ERROR);
+    }
+
     pub fn set_error(&mut self, reply: Message) {
         self.set_reply(reply);
         self.flags |= CmdFlags::ERROR;
@@ -224,22 +729,111 @@ impl Command {
     fn set_done(&mut self) {
         self.flags |= CmdFlags::DONE;
     }
+
+    fn next_wave(&mut self, batch: usize) -> Option<Vec<Cmd>> {
+        let subs = self.subs.as_ref()?;
+        next_wave(subs, &mut self.released, batch, |x| {
+            x.cmd.borrow().is_done()
+        })
+    }
+
+    fn has_wave(&self) -> bool {
+        self.subs
+            .as_ref()
+            .map(|x| self.released < x.len())
+            .unwrap_or(false)
+    }
 }
 
 #[derive(Default)]
-pub struct FrontCodec {}
+pub struct FrontCodec {
+    // the protocol of connection is decided by its first message as memcached does, so the
+    // binary garbage of text connection never stalls the stream as a binary header
+    binary: Option<bool>,
+    // the text requests ended by bare `\n` are accepted, see lenient_newline
+    lenient: bool,
+    args_limit: ArgsLimit,
+    value_limit: ValueLimit,
+    subs_limit: SubsLimit,
+    // the bad binary header of client, no more requests are decoded once it's replied
+    error: Option<String>,
+}
+
+impl FrontCodec {
+    // the message beyond the limits is consumed, so the following ones are still decoded.
+    fn check_limits(&self, msg: Message) -> Cmd {
+        match self
+            .args_limit
+            .check(msg.args_count())
+            .and_then(|_| self.value_limit.check(msg.value_len()))
+            .and_then(|_| self.subs_limit.reserve(msg.subs_count()))
+        {
+            Ok(reserved) => {
+                let mut notify = Notify::empty();
+                if let Some(reserved) = reserved {
+                    notify.hold(reserved);
+                }
+                Cmd::from_msg(msg, notify)
+            }
+            Err(err) => new_error_cmd(&err, msg.binary_header()),
+        }
+    }
+
+    // the binary stream is never resynchronized after a bad header since the length of its body
+    // is untrusted, so it's replied by the binary error and the connection is closed then.
+    fn decode_binary(&mut self, src: &mut BytesMut) -> Result<Option<Cmd>, AsError> {
+        match Message::parse_binary(src) {
+            Ok(Some(msg)) => Ok(Some(self.check_limits(msg))),
+            Ok(None) => Ok(None),
+            Err(AsError::BadMessage) => {
+                let header = src.split_to(BIN_HEADER_LEN);
+                self.error = Some("invalid binary header".to_string());
+                Ok(Some(new_error_cmd(&AsError::BadMessage, Some(&header))))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// the error found by proxy before sent is replied in the protocol of the request: the binary
+// one by the binary header, and the text one by the wording of memcached.
+fn new_error_cmd(err: &AsError, header: Option<&[u8]>) -> Cmd {
+    let cmd: Cmd = Message::raw_inline_reply().into();
+    match header {
+        Some(header) => {
+            error_type_incr(&cmd.cmd_name(), err);
+            let reply = Message::binary_error_reply(header, err);
+            cmd.transit(|x| x.set_error(reply));
+        }
+        None => cmd.set_error(err),
+    }
+    cmd
+}
 
 impl Decoder for FrontCodec {
     type Item = Cmd;
     type Error = AsError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        match Message::parse(src).map(|x| x.map(Into::into)) {
-            Ok(val) => Ok(val),
-            Err(AsError::BadMessage) => {
-                let cmd: Cmd = Message::raw_inline_reply().into();
-                cmd.set_error(&AsError::BadMessage);
-                Ok(Some(cmd))
-            }
+        if let Some(reason) = self.error.as_ref() {
+            return Err(AsError::ProtocolError(reason.clone()));
+        }
+        if src.is_empty() {
+            return Ok(None);
+        }
+        if *self.binary.get_or_insert_with(|| Message::is_binary(src)) {
+            return self.decode_binary(src);
+        }
+        let rslt = if self.lenient {
+            Message::parse_text_lenient(src)
+        } else {
+            // the bad message is consumed up to the next line, so the following commands are
+            // parsed from the start of line
+            Message::parse_text(src)
+        };
+        match rslt {
+            Ok(Some(msg)) => Ok(Some(self.check_limits(msg))),
+            Ok(None) => Ok(None),
+            Err(AsError::BadMessage) => Ok(Some(new_error_cmd(&AsError::BadMessage, None))),
             Err(err) => Err(err),
         }
     }
@@ -250,19 +844,32 @@ impl Encoder for FrontCodec {
     type Error = AsError;
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let mut cmd = item.cmd.borrow_mut();
-        if let Some(subs) = cmd.subs.as_ref().cloned() {
-            for sub in subs {
-                self.encode(sub, dst)?;
-            }
-            cmd.req.try_save_ends(dst);
+        if cmd.subs.is_some() && !cmd.is_error() {
+            cmd.merge_subs(dst)?;
         } else {
-            let reply = cmd.reply.take().expect("reply must exits");
+            let reply = match cmd.reply.take() {
+                Some(reply) => reply,
+                None => missing_reply(&cmd.req),
+            };
             cmd.req.save_reply(reply, dst)?;
         }
         Ok(())
     }
 }
 
+// the command encoded without reply is a bug of proxy, it's failed alone by the server error
+// rather than panicking the whole proxy with all the clients.
+fn missing_reply(req: &Message) -> Message {
+    let name = req.cmd_name();
+    error!("reply of command {} is missing when it's encoded", name);
+    reply_missing_incr(&name);
+    error_type_incr(&name, &AsError::ReplyMissing);
+    match req.binary_header() {
+        Some(header) => Message::binary_error_reply(header, &AsError::ReplyMissing),
+        None => (&AsError::ReplyMissing).into(),
+    }
+}
+
 #[derive(Default)]
 pub struct BackCodec {}
 
@@ -282,6 +889,546 @@ impl Encoder for BackCodec {
     }
 }
 
+#[test]
+fn test_mc_keyless_hash() {
+    use crate::proxy::standalone::fnv::fnv1a64;
+
+    let mut data = BytesMut::from(&b"version\r\nget mykey\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let version = codec.decode(&mut data).unwrap().unwrap();
+    assert_eq!(version.key_hash(b"", HashMethod::Fnv1a64), None);
+
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    assert_eq!(
+        get.key_hash(b"", HashMethod::Fnv1a64),
+        Some(fnv1a64(b"mykey"))
+    );
+    assert_eq!(
+        get.key_hash(b"", HashMethod::Crc32a),
+        Some(HashMethod::Crc32a.hash(b"mykey"))
+    );
+}
+
+#[test]
+fn test_mc_read_write_class() {
+    let mut data =
+        BytesMut::from(&b"get a\r\ngets a b\r\nset a 0 0 1\r\nx\r\ndelete a\r\nversion\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let mut next = || codec.decode(&mut data).unwrap().unwrap();
+    let get = next();
+    assert!(get.is_read() && !get.is_write());
+    let gets = next();
+    assert!(gets.is_read() && !gets.is_write());
+    let set = next();
+    assert!(set.is_write() && !set.is_read());
+    let delete = next();
+    assert!(delete.is_write() && !delete.is_read());
+    let version = next();
+    assert!(!version.is_read() && !version.is_write());
+}
+
+#[test]
+fn test_mc_keyless_reply() {
+    let mut data = BytesMut::from(&b"version\r\nget mykey\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let version = codec.decode(&mut data).unwrap().unwrap();
+    assert!(version.is_keyless());
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    assert!(!get.is_keyless());
+    assert!(!get.reply_keyless());
+    assert!(!get.is_done());
+
+    assert!(version.reply_keyless());
+    assert!(version.is_done());
+    let reply = version.reply().unwrap();
+    assert!(reply.is_version_reply());
+    assert!(reply
+        .bytes()
+        .ends_with(format!("{}\r\n", crate::ASTER_VERSION).as_bytes()));
+
+    // noop of binary protocol has no key
+    let mut header = vec![0u8; 24];
+    header[0] = 0x80;
+    header[1] = 0x0a;
+    let mut data = BytesMut::from(&header[..]);
+    let noop = codec.decode(&mut data).unwrap().unwrap();
+    assert!(noop.is_keyless());
+    assert!(!noop.reply_keyless());
+}
+
+#[test]
+fn test_mc_front_protocol_of_listener() {
+    // the magic byte of binary protocol is taken as text by the listener of text
+    let cc = ClusterConfig::default();
+    let mut codec = Cmd::front_codec(&cc, Some(FrontProtocol::Text), "127.0.0.1:50001");
+    let mut data = BytesMut::from(&b"\x80get a\r\n"[..]);
+    let cmd = codec.decode(&mut data).unwrap().unwrap();
+    assert!(cmd.is_error());
+    assert!(data.is_empty());
+
+    let mut codec = Cmd::front_codec(&cc, None, "127.0.0.1:50001");
+    let mut data = BytesMut::from(&b"\x80get a\r\n"[..]);
+    assert!(codec.decode(&mut data).unwrap().is_none());
+}
+
+#[test]
+fn test_mc_reply_bad_binary_header() {
+    let cc = ClusterConfig::default();
+    let mut codec = Cmd::front_codec(&cc, Some(FrontProtocol::Binary), "127.0.0.1:50001");
+    // unknown opcode 0xff with opaque 1, 2, 3, 4, followed by the body taken as garbage
+    let mut header = vec![0u8; 24];
+    header[0] = 0x80;
+    header[1] = 0xff;
+    header[12..16].copy_from_slice(&[1, 2, 3, 4]);
+    let mut data = BytesMut::from(&header[..]);
+    data.extend_from_slice(b"get a\r\n");
+    let bad = codec.decode(&mut data).unwrap().unwrap();
+    assert!(bad.is_done());
+    assert!(bad.is_error());
+    // nothing is decoded after the bad header
+    assert_eq!(
+        codec.decode(&mut data).unwrap_err(),
+        AsError::ProtocolError("invalid binary header".to_string())
+    );
+
+    let mut buf = BytesMut::new();
+    codec.encode(bad, &mut buf).unwrap();
+    let mut reply = vec![0u8; 24];
+    reply[0] = 0x81;
+    reply[1] = 0xff;
+    // status of invalid arguments
+    reply[7] = 0x04;
+    reply[12..16].copy_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(&buf[..], &reply[..]);
+
+    // the text listener never takes it as binary, and resyncs to the next line
+    let mut codec = Cmd::front_codec(&cc, Some(FrontProtocol::Text), "127.0.0.1:50001");
+    let mut data = BytesMut::from(&b"set k 0 0 x\r\nget a\r\n"[..]);
+    let bad = codec.decode(&mut data).unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    codec.encode(bad, &mut buf).unwrap();
+    assert_eq!(&buf[..], &b"CLIENT_ERROR bad command line format\r\n"[..]);
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    assert_eq!(get.keys(), vec![b"a".to_vec()]);
+}
+
+#[test]
+fn test_mc_encode_missing_reply() {
+    let mut codec = FrontCodec::default();
+    let mut data = BytesMut::from(&b"set k 0 0 1\r\nx\r\nget a b\r\n"[..]);
+    let set = codec.decode(&mut data).unwrap().unwrap();
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    assert!(set.reply().is_none());
+    let before = reply_missing_get("set");
+
+    // the command without reply is failed alone, and the connection goes on
+    let mut buf = BytesMut::new();
+    codec.encode(set, &mut buf).unwrap();
+    assert_eq!(&buf[..], &b"SERVER_ERROR reply is missing\r\n"[..]);
+    assert_eq!(reply_missing_get("set"), before + 1);
+    get.set_error(&AsError::ProxyFail);
+    let mut buf = BytesMut::new();
+    codec.encode(get, &mut buf).unwrap();
+    assert!(buf.starts_with(b"error "));
+
+    // the binary one is replied by the internal error of its header
+    let mut codec = FrontCodec::default();
+    let mut header = vec![0u8; 24];
+    header[0] = 0x80;
+    header[1] = 0x0a;
+    header[12..16].copy_from_slice(&[1, 2, 3, 4]);
+    let noop = codec
+        .decode(&mut BytesMut::from(&header[..]))
+        .unwrap()
+        .unwrap();
+    let mut buf = BytesMut::new();
+    codec.encode(noop, &mut buf).unwrap();
+    let mut reply = vec![0u8; 24];
+    reply[0] = 0x81;
+    reply[1] = 0x0a;
+    reply[7] = 0x84;
+    reply[12..16].copy_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(&buf[..], &reply[..]);
+}
+
+#[test]
+fn test_mc_max_args_reject() {
+    let mut cc = ClusterConfig::default();
+    cc.max_args = Some(3);
+    let mut codec = Cmd::front_codec(&cc, None, "127.0.0.1:50001");
+    let mut data = BytesMut::from(&b"get a b\r\ngets a b c\r\nset a 0 0 1\r\nb\r\n"[..]);
+
+    // the command name is counted as well
+    let at = codec.decode(&mut data).unwrap().unwrap();
+    assert!(!at.is_done());
+    assert_eq!(at.subs().map(|x| x.len()), Some(2));
+
+    // the command beyond is never fanned out
+    let above = codec.decode(&mut data).unwrap().unwrap();
+    assert!(above.is_done());
+    assert!(above.subs().is_none());
+    let mut buf = BytesMut::new();
+    codec.encode(above, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"CLIENT_ERROR too many arguments of command, max_args is 3\r\n"[..]
+    );
+
+    // only the words of line are counted, the data block is not
+    cc.max_args = Some(5);
+    let mut codec = Cmd::front_codec(&cc, None, "127.0.0.1:50001");
+    let set = codec.decode(&mut data).unwrap().unwrap();
+    assert!(!set.is_done());
+    assert!(data.is_empty());
+}
+
+#[test]
+fn test_mc_max_value_size_reject() {
+    let mut cc = ClusterConfig::default();
+    cc.max_value_size = Some(4);
+    let mut codec = Cmd::front_codec(&cc, None, "127.0.0.1:50001");
+    let mut data =
+        BytesMut::from(&b"set a 0 0 4\r\nbcde\r\nset a 0 0 5\r\nbcdef\r\nget abcdef\r\n"[..]);
+
+    let at = codec.decode(&mut data).unwrap().unwrap();
+    assert!(!at.is_done());
+
+    // the data block beyond is swallowed with the command and never sent to backend
+    let above = codec.decode(&mut data).unwrap().unwrap();
+    assert!(above.is_done());
+    let mut buf = BytesMut::new();
+    codec.encode(above, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"SERVER_ERROR object too large for cache\r\n"[..]
+    );
+
+    // the key is not a value
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    assert!(!get.is_done());
+    assert!(data.is_empty());
+}
+
+#[test]
+fn test_mc_read_only_reject() {
+    let mut data = BytesMut::from(&b"set a 0 0 1\r\nb\r\nget a\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let set = codec.decode(&mut data).unwrap().unwrap();
+    assert!(set.is_mutation());
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    assert!(!get.is_mutation());
+
+    set.set_error(&AsError::ReadOnly);
+    let mut buf = BytesMut::new();
+    codec.encode(set, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"SERVER_ERROR proxy is in read-only mode\r\n"[..]
+    );
+}
+
+#[test]
+fn test_mc_dangerous_reject() {
+    let mut data = BytesMut::from(&b"shutdown\r\nFLUSH_ALL 10\r\nget shutdown\r\nversion\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let shutdown = codec.decode(&mut data).unwrap().unwrap();
+    assert_eq!(shutdown.dangerous_name(), Some("shutdown".to_string()));
+    let flush = codec.decode(&mut data).unwrap().unwrap();
+    assert_eq!(flush.dangerous_name(), Some("flush_all".to_string()));
+    for _ in 0..2 {
+        let cmd = codec.decode(&mut data).unwrap().unwrap();
+        assert_eq!(cmd.dangerous_name(), None);
+    }
+
+    shutdown.set_error(&AsError::Dangerous("shutdown".to_string()));
+    let mut buf = BytesMut::new();
+    codec.encode(shutdown, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"CLIENT_ERROR dangerous command shutdown is denied by proxy\r\n"[..]
+    );
+}
+
+#[test]
+fn test_mc_multi_get_merge() {
+    let mut data = BytesMut::from(&b"get a b c\r\nget a b\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let parse = |data: &[u8]| Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    let subs = get.subs().unwrap();
+    subs[0].set_reply(parse(b"VALUE a 0 1\r\n1\r\nEND\r\n"));
+    subs[1].set_reply(parse(b"END\r\n"));
+    subs[2].set_reply(parse(b"VALUE c 0 1\r\n3\r\nEND\r\n"));
+    let mut buf = BytesMut::new();
+    codec.encode(get, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"VALUE a 0 1\r\n1\r\nVALUE c 0 1\r\n3\r\nEND\r\n"[..]
+    );
+
+    // the values are never mixed with errors
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    let subs = get.subs().unwrap();
+    subs[0].set_reply(parse(b"VALUE a 0 1\r\n1\r\nEND\r\n"));
+    subs[1].set_error(&AsError::ReadOnly);
+    let mut buf = BytesMut::new();
+    codec.encode(get, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"SERVER_ERROR proxy is in read-only mode\r\n"[..]
+    );
+}
+
+#[test]
+fn test_mc_sub_failure_policies() {
+    let budget = Budget::default();
+    let mut codec = FrontCodec::default();
+    let parse = |data: &[u8]| Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+    let err = AsError::BackendClosedError("mock".to_string());
+    // the sub of key b is failed by proxy
+    let mut fan_out = |policy: SubFailure| {
+        let get = codec
+            .decode(&mut BytesMut::from(&b"get a b c\r\n"[..]))
+            .unwrap()
+            .unwrap();
+        get.set_sub_failure(policy);
+        let subs = get.subs().unwrap();
+        subs[0].set_reply(parse(b"VALUE a 0 1\r\n1\r\nEND\r\n"));
+        subs[1].set_error(&err);
+        subs[2].set_reply(parse(b"VALUE c 0 1\r\n3\r\nEND\r\n"));
+        get
+    };
+    let merged = |get: Cmd| {
+        let mut buf = BytesMut::new();
+        FrontCodec::default().encode(get, &mut buf).unwrap();
+        buf.to_vec()
+    };
+    let mut failed = BytesMut::new();
+    let reply: Message = (&err).into_reply();
+    failed.extend_from_slice(&reply.bytes());
+
+    let get = fan_out(SubFailure::FailFast);
+    assert!(get.retry_failed_subs(&budget).is_empty());
+    assert_eq!(merged(get), failed.to_vec());
+
+    // the failed key is a miss
+    assert_eq!(
+        merged(fan_out(SubFailure::BestEffort)),
+        b"VALUE a 0 1\r\n1\r\nVALUE c 0 1\r\n3\r\nEND\r\n".to_vec()
+    );
+
+    let get = fan_out(SubFailure::Retry);
+    let retried = get.retry_failed_subs(&budget);
+    assert_eq!(retried.len(), 1);
+    assert!(!get.is_done());
+    retried[0].set_reply(parse(b"VALUE b 0 1\r\n2\r\nEND\r\n"));
+    assert!(get.is_done());
+    assert_eq!(
+        merged(get),
+        b"VALUE a 0 1\r\n1\r\nVALUE b 0 1\r\n2\r\nVALUE c 0 1\r\n3\r\nEND\r\n".to_vec()
+    );
+
+    // failed again after retried
+    let get = fan_out(SubFailure::Retry);
+    let retried = get.retry_failed_subs(&budget);
+    retried[0].set_error(&err);
+    assert!(get.retry_failed_subs(&budget).is_empty());
+    assert_eq!(merged(get), failed.to_vec());
+}
+
+// the values are proxied by slicing the replies of backends, so the flags, bytes and cas are
+// carried at the full width and the reply is byte-identical to the one of a single backend
+#[test]
+fn test_mc_reply_passthrough_identical() {
+    let max_key = "k".repeat(250);
+    let values = vec![
+        (
+            "a".to_string(),
+            "VALUE a 4294967295 3\r\nabc\r\n".to_string(),
+        ),
+        ("b".to_string(), "VALUE b 65536 0\r\n\r\n".to_string()),
+        // the data looks like the end of reply
+        (
+            "c".to_string(),
+            "VALUE c 70000 5\r\nEND\r\n\r\n".to_string(),
+        ),
+        (
+            max_key.clone(),
+            format!("VALUE {} 131072 2\r\nxy\r\n", max_key),
+        ),
+    ];
+    let cas_values = vec![
+        (
+            "a".to_string(),
+            "VALUE a 16777216 1 18446744073709551615\r\nz\r\n".to_string(),
+        ),
+        (
+            max_key.clone(),
+            format!("VALUE {} 4294967295 0 1\r\n\r\n", max_key),
+        ),
+    ];
+    let single = values[3..].to_vec();
+    let cases = vec![("get", &values), ("gets", &cas_values), ("get", &single)];
+
+    let mut codec = FrontCodec::default();
+    let mut back = BackCodec::default();
+    for (cmd, values) in cases {
+        let keys: Vec<_> = values.iter().map(|x| x.0.as_str()).collect();
+        let mut data = BytesMut::from(format!("{} {}\r\n", cmd, keys.join(" ")).as_bytes());
+        let req = codec.decode(&mut data).unwrap().unwrap();
+        let subs = req.subs().unwrap_or_else(|| vec![req.clone()]);
+        assert_eq!(subs.len(), values.len());
+        for (sub, (key, value)) in subs.iter().zip(values.iter()) {
+            let mut sent = BytesMut::new();
+            back.encode(sub.clone(), &mut sent).unwrap();
+            assert_eq!(&sent[..], format!("{} {}\r\n", cmd, key).as_bytes());
+            let mut reply = BytesMut::from(format!("{}END\r\n", value).as_bytes());
+            sub.set_reply(back.decode(&mut reply).unwrap().unwrap());
+            assert!(reply.is_empty());
+        }
+        let mut buf = BytesMut::new();
+        codec.encode(req, &mut buf).unwrap();
+        let direct: String = values.iter().map(|x| x.1.as_str()).collect();
+        assert_eq!(&buf[..], format!("{}END\r\n", direct).as_bytes());
+    }
+}
+
+#[test]
+fn test_mc_pipeline_with_bad_message() {
+    let mut data = BytesMut::from(&b"delete a\r\nset k 0 0 x\r\ndelete b\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let parse = |data: &[u8]| Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+    let cmds: Vec<_> = (0..3)
+        .map(|_| codec.decode(&mut data).unwrap().unwrap())
+        .collect();
+    assert!(codec.decode(&mut data).unwrap().is_none());
+
+    // the connection is never closed but resynchronized to the next line
+    cmds[0].set_reply(parse(b"DELETED\r\n"));
+    cmds[2].set_reply(parse(b"NOT_FOUND\r\n"));
+    let mut buf = BytesMut::new();
+    for cmd in cmds {
+        codec.encode(cmd, &mut buf).unwrap();
+    }
+    assert_eq!(
+        &buf[..],
+        &b"DELETED\r\nCLIENT_ERROR bad command line format\r\nNOT_FOUND\r\n"[..]
+    );
+}
+
+#[test]
+fn test_mc_accept_reply() {
+    let parse = |data: &[u8]| Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+    let mut data = BytesMut::from(&b"get a\r\ndelete a\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    let delete = codec.decode(&mut data).unwrap().unwrap();
+
+    let value = parse(b"VALUE a 0 1\r\nb\r\nEND\r\n");
+    assert!(get.accept_reply(&value));
+    assert!(get.accept_reply(&parse(b"END\r\n")));
+    assert!(!get.accept_reply(&parse(b"DELETED\r\n")));
+    assert!(delete.accept_reply(&parse(b"NOT_FOUND\r\n")));
+    assert!(!delete.accept_reply(&value));
+    // error is accepted by any request
+    assert!(get.accept_reply(&parse(b"SERVER_ERROR out of memory\r\n")));
+    assert!(delete.accept_reply(&parse(b"SERVER_ERROR out of memory\r\n")));
+}
+
+// getdel is sent as get and delete on the same connection, and replied by the get only
+#[test]
+fn test_mc_getdel() {
+    let parse = |data: &[u8]| Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+    let mut data = BytesMut::from(&b"getdel a\r\ngetdel\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let getdel = codec.decode(&mut data).unwrap().unwrap();
+    assert!(getdel.subs().is_none());
+    assert!(getdel.is_mutation());
+    assert_eq!(getdel.cmd_name(), "getdel");
+
+    let mut req = BytesMut::new();
+    BackCodec::default()
+        .encode(getdel.clone(), &mut req)
+        .unwrap();
+    assert_eq!(&req[..], &b"get a\r\ndelete a noreply\r\n"[..]);
+
+    let value = parse(b"VALUE a 0 1\r\nb\r\nEND\r\n");
+    assert!(getdel.accept_reply(&value));
+    assert!(!getdel.accept_reply(&parse(b"DELETED\r\n")));
+    getdel.set_reply(value);
+    let mut buf = BytesMut::new();
+    codec.encode(getdel, &mut buf).unwrap();
+    assert_eq!(&buf[..], &b"VALUE a 0 1\r\nb\r\nEND\r\n"[..]);
+
+    // the key is required
+    let bad = codec.decode(&mut data).unwrap().unwrap();
+    assert!(bad.is_done());
+    assert!(bad.is_error());
+}
+
+#[test]
+fn test_mc_quit() {
+    let mut data = BytesMut::from(&b"get a\r\nquit\r\n"[..]);
+    let mut codec = FrontCodec::default();
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    assert!(!get.reply_quit());
+    // replied by nothing as memcached
+    let quit = codec.decode(&mut data).unwrap().unwrap();
+    assert!(quit.reply_quit());
+    assert!(quit.is_done());
+    let mut buf = BytesMut::new();
+    codec.encode(quit, &mut buf).unwrap();
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_mc_version_pong() {
+    let ping = Cmd::ping_request();
+    assert!(!ping.is_pong());
+    let replied = |data: &[u8]| {
+        let ping = Cmd::ping_request();
+        ping.set_reply(Message::parse(&mut BytesMut::from(data)).unwrap().unwrap());
+        ping.is_pong()
+    };
+    assert!(replied(b"VERSION 1.6.9\r\n"));
+    assert!(!replied(b"VERSION\r\n"));
+    assert!(!replied(b"STORED\r\n"));
+    assert!(!replied(b"SERVER_ERROR out of memory\r\n"));
+}
+
+#[test]
+fn test_mc_resync_bad_message() {
+    let mut data = BytesMut::from(
+        &b"get a\r\n\r\nset k 0 0 2\r\nabcd\r\nset k 0 0 x\r\n\x80\x01\xff\xfe garbage\r\nget b\r\n"[..],
+    );
+    let mut codec = FrontCodec::default();
+    let mut decode = || codec.decode(&mut data).unwrap().unwrap();
+    assert_eq!(decode().keys(), vec![b"a".to_vec()]);
+    for _ in 0..3 {
+        let bad = decode();
+        assert!(bad.is_done());
+        assert!(bad.is_error());
+    }
+    // binary garbage of text connection is an unknown command, which is replied by backend
+    let garbage = decode();
+    assert!(!garbage.is_done());
+    assert_eq!(garbage.cmd_name(), "unknown");
+    assert_eq!(decode().keys(), vec![b"b".to_vec()]);
+    assert!(data.is_empty());
+
+    // the bad data chunk is swallowed once its line is complete
+    let mut data = BytesMut::from(&b"set k 0 0 2\r\nabc"[..]);
+    assert!(codec.decode(&mut data).unwrap().is_none());
+    data.extend_from_slice(b"d\r\nget c\r\n");
+    let bad = codec.decode(&mut data).unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    codec.encode(bad, &mut buf).unwrap();
+    assert_eq!(&buf[..], &b"CLIENT_ERROR bad command line format\r\n"[..]);
+    let get = codec.decode(&mut data).unwrap().unwrap();
+    assert_eq!(get.keys(), vec![b"c".to_vec()]);
+}
+
 #[test]
 fn test_mc_parse_wrong_case() {
     test_mc_parse_error_in_path("../fuzz/corpus/fuzz_mc_parser/");
@@ -1,17 +1,17 @@
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
 use byteorder::{BigEndian, ReadBytesExt};
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
 use crate::com::AsError;
-use crate::protocol::CmdFlags;
 use crate::protocol::IntoReply;
+use crate::protocol::{all_replied, first_error, CmdFlags, Merge, ReplyMerge};
 use crate::utils::simdfind::find_lf_simd;
 use crate::utils::Range;
 
 use std::cmp::min;
 use std::io::{Cursor, Seek, SeekFrom};
 
-const BIN_HEADER_LEN: usize = 24;
+pub(crate) const BIN_HEADER_LEN: usize = 24;
 
 const BYTE_SPACE: u8 = b' ';
 
@@ -19,17 +19,34 @@ const BYTES_CRLF: &[u8] = b"\r\n";
 const BYTES_SPACE: &[u8] = b" ";
 const BYTES_END: &[u8] = b"END\r\n";
 const BYTES_NOREPLY: &[u8] = b"noreply";
+const BYTES_SERVER_ERROR_READONLY: &[u8] = b"SERVER_ERROR proxy is in read-only mode\r\n";
+const BYTES_SERVER_ERROR_INJECTED: &[u8] = b"SERVER_ERROR injected\r\n";
+// the wording of memcached for the value beyond item_size_max
+const BYTES_SERVER_ERROR_TOO_LARGE: &[u8] = b"SERVER_ERROR object too large for cache\r\n";
+const BYTES_SERVER_ERROR_MAINTENANCE: &[u8] = b"SERVER_ERROR proxy in maintenance\r\n";
+const BYTES_SERVER_ERROR_SHUTTING_DOWN: &[u8] = b"SERVER_ERROR server shutting down\r\n";
+const BYTES_SERVER_ERROR_REPLY_MISSING: &[u8] = b"SERVER_ERROR reply is missing\r\n";
+// the wording of memcached for the malformed command line
+const BYTES_CLIENT_ERROR_BAD_FORMAT: &[u8] = b"CLIENT_ERROR bad command line format\r\n";
+// the error replies of memcached and the proxy itself
+const BYTES_ERRORS: &[&[u8]] = &[b"ERROR", b"CLIENT_ERROR ", b"SERVER_ERROR ", b"error "];
 
 const BIN_STATUS_KEY_NOT_FOUND: u16 = 0x0001u16;
+const BIN_STATUS_VALUE_TOO_LARGE: u16 = 0x0003u16;
+const BIN_STATUS_INVALID_ARGUMENTS: u16 = 0x0004u16;
+const BIN_STATUS_INTERNAL_ERROR: u16 = 0x0084u16;
+
+// the commands may break the whole memcached, denied unless given by allow_dangerous
+const DANGEROUS_CMDS: &[&str] = &["shutdown", "flush_all", "flushq"];
 
 const TEXT_CMDS: &[&str] = &[
     "set", "add", "replace", "append", "prepend", "cas", // storage [0, 5]
-    "gets", "get",    // retrieval [6, 7]
-    "delete", // delete [8, 8]
-    "incr", "decr",  // incr/decr [9, 10]
-    "touch", // touch [11, 11]
-    "gats", "gat", // get and touch [12, 13]
-    "version", "quit", // special command [14, 15]
+    "getdel", "gets", "get",    // retrieval [6, 8], getdel is leftmost first of get
+    "delete", // delete [9, 9]
+    "incr", "decr",  // incr/decr [10, 11]
+    "touch", // touch [12, 12]
+    "gats", "gat", // get and touch [13, 14]
+    "version", "quit", // special command [15, 16]
 ];
 
 const TEXT_PAT_SET: usize = 0;
@@ -39,21 +56,22 @@ const TEXT_PAT_APPEND: usize = 3;
 const TEXT_PAT_PREPEND: usize = 4;
 const TEXT_PAT_CAS: usize = 5;
 
-const TEXT_PAT_GET: usize = 7;
-const TEXT_PAT_GETS: usize = 6;
+const TEXT_PAT_GETDEL: usize = 6;
+const TEXT_PAT_GET: usize = 8;
+const TEXT_PAT_GETS: usize = 7;
 
-const TEXT_PAT_DELETE: usize = 8;
+const TEXT_PAT_DELETE: usize = 9;
 
-const TEXT_PAT_INCR: usize = 9;
-const TEXT_PAT_DECR: usize = 10;
+const TEXT_PAT_INCR: usize = 10;
+const TEXT_PAT_DECR: usize = 11;
 
-const TEXT_PAT_TOUCH: usize = 11;
+const TEXT_PAT_TOUCH: usize = 12;
 
-const TEXT_PAT_GAT: usize = 13;
-const TEXT_PAT_GATS: usize = 12;
+const TEXT_PAT_GAT: usize = 14;
+const TEXT_PAT_GATS: usize = 13;
 
-const TEXT_PAT_VERSION: usize = 14;
-const TEXT_PAT_QUIT: usize = 15;
+const TEXT_PAT_VERSION: usize = 15;
+const TEXT_PAT_QUIT: usize = 16;
 
 const TEXT_RESPS: &[&str] = &[
     "VALUE", // response value sets
@@ -79,6 +97,9 @@ lazy_static! {
 const MSG_TEXT_MAX_CMD_SIZE: usize = 7; // prepend
 const MSG_TEXT_MAX_RESP_TYPE_SIZE: usize = 5; // VALUE
 
+/// max length of data block or binary body, the same as the max item size of memcached.
+pub const MAX_BODY_SIZE: usize = 1024 * 1024 * 1024;
+
 const MSG_BIN_REQ: u8 = 0x80;
 const MSG_BIN_RESP: u8 = 0x81;
 
@@ -94,6 +115,8 @@ pub enum TextCmd {
     // retrieval commands
     Get(Vec<Range>),
     Gets(Vec<Range>),
+    // get and delete of one key synthesized by proxy, which memcached lacks, see save_req
+    GetDel(Range),
     // Deleteion
     Delete(Range),
     // Incr/Decr
@@ -116,7 +139,7 @@ impl TextCmd {
 
         match self {
             Set(rng) | Add(rng) | Replace(rng) | Append(rng) | Prepend(rng) | Cas(rng)
-            | Delete(rng) | Incr(rng) | Decr(rng) | Touch(rng) => *rng,
+            | Delete(rng) | Incr(rng) | Decr(rng) | Touch(rng) | GetDel(rng) => *rng,
             Get(rngs) | Gets(rngs) | Gats(_, rngs) | Gat(_, rngs) => {
                 if rngs.is_empty() {
                     return Range::new(0, 0);
@@ -130,6 +153,30 @@ impl TextCmd {
         }
     }
 
+    fn is_write(&self) -> bool {
+        use TextCmd::*;
+        match self {
+            Set(_) | Add(_) | Replace(_) | Append(_) | Prepend(_) | Cas(_) | Delete(_)
+            | Incr(_) | Decr(_) | Touch(_) | GetDel(_) => true,
+            _ => false,
+        }
+    }
+
+    fn is_keyless(&self) -> bool {
+        match self {
+            TextCmd::Version | TextCmd::Quit => true,
+            _ => false,
+        }
+    }
+
+    fn is_retrieval(&self) -> bool {
+        use TextCmd::*;
+        match self {
+            Get(_) | Gets(_) | Gat(_, _) | Gats(_, _) | GetDel(_) => true,
+            _ => false,
+        }
+    }
+
     fn cmd_slice(&self) -> &[u8] {
         use TextCmd::*;
         match &self {
@@ -142,6 +189,7 @@ impl TextCmd {
             // retrieval commands
             Get(_) => &b"get"[..],
             Gets(_) => &b"gets"[..],
+            GetDel(_) => &b"getdel"[..],
             // Deleteion
             Delete(_) => &b"delete"[..],
             // Incr/Decr
@@ -170,7 +218,8 @@ impl TextCmd {
             | TextCmd::Delete(ref mut rg)
             | TextCmd::Incr(ref mut rg)
             | TextCmd::Decr(ref mut rg)
-            | TextCmd::Touch(ref mut rg) => {
+            | TextCmd::Touch(ref mut rg)
+            | TextCmd::GetDel(ref mut rg) => {
                 rg.set_begin(begin);
                 rg.set_end(end);
             }
@@ -250,6 +299,26 @@ pub enum BinMsgType {
 }
 
 impl BinMsgType {
+    fn is_write(self) -> bool {
+        use BinMsgType::*;
+        match self {
+            Set | Add | Replace | Delete | Incr | Decr | Append | Prepend | SetQ | AddQ
+            | ReplaceQ | DeleteQ | IncrementQ | DecrementQ | FlushQ | AppendQ | PrependQ
+            | Touch | RSet | RSetQ | RAppend | RAppendQ | RPrepend | RPrependQ | RDelete
+            | RDeleteQ | RIncr | RIncrQ | RDecr | RDecrQ => true,
+            _ => false,
+        }
+    }
+
+    // the opcodes sent without key by the protocol, e.g.: noop
+    fn is_keyless(self) -> bool {
+        use BinMsgType::*;
+        match self {
+            Quit | QuitQ | Noop | Version | Stat | FlushQ | Verbosity => true,
+            _ => false,
+        }
+    }
+
     pub(crate) fn is_quiet(self) -> bool {
         use BinMsgType::*;
         match &self {
@@ -386,26 +455,57 @@ pub struct Message {
 }
 
 impl Message {
+    /// parse one text or binary message (request or reply) from the front of data.
+    ///
+    /// - `Ok(Some(msg))`: exactly the bytes of msg are consumed.
+    /// - `Ok(None)`: the message is incomplete and nothing is consumed, call it again with
+    ///   more data appended.
+    /// - `Err(AsError::BadMessage)`: the message is malformed (e.g.: bad length, missing CRLF
+    ///   or body longer than MAX_BODY_SIZE), and at least the bad line or binary header is
+    ///   consumed, so the parsing loop always ends. The bad text message is consumed up to
+    ///   the end of line, as well as the line of its bad data chunk.
+    ///
+    /// It never panics on any input.
     pub fn parse(data: &mut BytesMut) -> Result<Option<Message>, AsError> {
-        if data.is_empty() {
-            return Ok(None);
+        if !Self::is_binary(data) {
+            return Self::parse_text(data);
         }
-        // detect binary
-        if data[0] == MSG_BIN_REQ || data[0] == MSG_BIN_RESP {
-            match Self::parse_binary(data) {
-                Ok(msg) => return Ok(msg),
-                Err(AsError::BadMessage) => {
-                    data.advance(BIN_HEADER_LEN);
-                    return Err(AsError::BadMessage);
-                }
-                Err(err) => return Err(err),
+        match Self::parse_binary(data) {
+            Err(AsError::BadMessage) => {
+                data.advance(BIN_HEADER_LEN);
+                Err(AsError::BadMessage)
             }
+            rslt => rslt,
         }
+    }
 
-        let line_size = if let Some(pos) = find_lf_simd(&data) {
-            pos + 1
-        } else {
-            return Ok(None);
+    /// the data starts with the magic byte of binary protocol.
+    pub fn is_binary(data: &[u8]) -> bool {
+        data.first()
+            .map(|x| *x == MSG_BIN_REQ || *x == MSG_BIN_RESP)
+            .unwrap_or(false)
+    }
+
+    /// parse one text message the same as parse, but the magic byte of binary protocol is
+    /// taken as text, which is for the connection known to speak text protocol.
+    pub fn parse_text(data: &mut BytesMut) -> Result<Option<Message>, AsError> {
+        Self::parse_text_inner(data, false)
+    }
+
+    /// parse one text request the same as parse_text, but the line or data block ended by bare
+    /// `\n` is taken as ended by `\r\n`, which is rewritten so before sent to backend.
+    pub fn parse_text_lenient(data: &mut BytesMut) -> Result<Option<Message>, AsError> {
+        Self::parse_text_inner(data, true)
+    }
+
+    fn parse_text_inner(data: &mut BytesMut, lenient: bool) -> Result<Option<Message>, AsError> {
+        let line_size = match find_lf_simd(&data) {
+            Some(pos) if lenient && (pos == 0 || data[pos - 1] != b'\r') => {
+                insert_cr(data, pos);
+                pos + 2
+            }
+            Some(pos) => pos + 1,
+            None => return Ok(None),
         };
 
         if line_size <= BYTES_CRLF.len() {
@@ -415,13 +515,21 @@ impl Message {
         }
 
         if let Some(mat) = TEXT_CMD_FINDER.find(&data[..min(line_size, MSG_TEXT_MAX_CMD_SIZE)]) {
-            return Self::parse_text_req(data, line_size, mat.pattern());
+            if is_leading_word(&data[..line_size], mat.start(), mat.end()) {
+                return Self::parse_text_req(data, line_size, mat.pattern(), lenient);
+            }
         }
 
         if let Some(mat) =
             TEXT_RESP_FINDER.find(&data[..min(line_size, MSG_TEXT_MAX_RESP_TYPE_SIZE)])
         {
-            return Self::parse_text_value(data, line_size, mat.pattern() != TEXT_RESP_PAT_VALUE);
+            if is_leading_word(&data[..line_size], mat.start(), mat.end()) {
+                return Self::parse_text_value(
+                    data,
+                    line_size,
+                    mat.pattern() != TEXT_RESP_PAT_VALUE,
+                );
+            }
         }
 
         Self::parse_text_inline(data, line_size)
@@ -431,31 +539,32 @@ impl Message {
         data: &mut BytesMut,
         line: usize,
         pat: usize,
+        lenient: bool,
     ) -> Result<Option<Message>, AsError> {
         match pat {
             TEXT_PAT_SET => {
                 let cmd = TextCmd::Set(Range::default());
-                Self::parse_text_storage(data, cmd, line, pat)
+                Self::parse_text_storage(data, cmd, line, pat, lenient)
             }
             TEXT_PAT_ADD => {
                 let cmd = TextCmd::Add(Range::default());
-                Self::parse_text_storage(data, cmd, line, pat)
+                Self::parse_text_storage(data, cmd, line, pat, lenient)
             }
             TEXT_PAT_REPLACE => {
                 let cmd = TextCmd::Replace(Range::default());
-                Self::parse_text_storage(data, cmd, line, pat)
+                Self::parse_text_storage(data, cmd, line, pat, lenient)
             }
             TEXT_PAT_APPEND => {
                 let cmd = TextCmd::Append(Range::default());
-                Self::parse_text_storage(data, cmd, line, pat)
+                Self::parse_text_storage(data, cmd, line, pat, lenient)
             }
             TEXT_PAT_PREPEND => {
                 let cmd = TextCmd::Prepend(Range::default());
-                Self::parse_text_storage(data, cmd, line, pat)
+                Self::parse_text_storage(data, cmd, line, pat, lenient)
             }
             TEXT_PAT_CAS => {
                 let cmd = TextCmd::Cas(Range::default());
-                Self::parse_text_storage(data, cmd, line, pat)
+                Self::parse_text_storage(data, cmd, line, pat, lenient)
             }
             TEXT_PAT_GET => {
                 let cmd = TextCmd::Get(Vec::new());
@@ -465,6 +574,14 @@ impl Message {
                 let cmd = TextCmd::Gets(Vec::new());
                 Self::parse_text_retrieval(data, cmd, line, pat)
             }
+            TEXT_PAT_GETDEL => {
+                let cmd = TextCmd::GetDel(Range::default());
+                match Self::parse_text_one_line(data, cmd, line, pat)? {
+                    // the line is consumed already
+                    Some(msg) if msg.get_key().is_empty() => Err(AsError::BadMessage),
+                    rslt => Ok(rslt),
+                }
+            }
             TEXT_PAT_DELETE => {
                 let cmd = TextCmd::Delete(Range::default());
                 Self::parse_text_one_line(data, cmd, line, pat)
@@ -522,6 +639,10 @@ impl Message {
             ranges.push(Range::new(cursor, cursor + key.len()));
             cursor += key.len() + 1;
         }
+        if ranges.is_empty() {
+            data.advance(line);
+            return Err(AsError::BadMessage);
+        }
         cmd.set_multi_key_range(&mut ranges);
         Ok(Some(Message {
             data: data.split_to(line).freeze(),
@@ -586,6 +707,7 @@ impl Message {
         mut cmd: TextCmd,
         line: usize,
         pat: usize,
+        lenient: bool,
     ) -> Result<Option<Message>, AsError> {
         let key_begin = TEXT_CMDS[pat].len() + 1;
         let mut iter = (&data[..line - BYTES_CRLF.len()]).split(|x| *x == BYTE_SPACE);
@@ -604,14 +726,13 @@ impl Message {
             iter.next();
             iter.next();
 
-            let bs = match iter.next() {
-                Some(bs) => bs,
-                None => {
+            match iter.next().and_then(|bs| btoi::btoi::<usize>(bs).ok()) {
+                Some(len) if len <= MAX_BODY_SIZE => len,
+                _ => {
                     data.advance(line);
                     return Err(AsError::BadMessage);
                 }
-            };
-            btoi::btoi::<usize>(bs)?
+            }
         };
         let mut flags = CmdFlags::empty();
         if let Some(last) = iter.last() {
@@ -619,10 +740,24 @@ impl Message {
                 flags |= CmdFlags::NOREPLY;
             }
         }
-        let total_size = line.wrapping_add(len).wrapping_add(BYTES_CRLF.len());
+        if lenient && data.get(line + len) == Some(&b'\n') {
+            insert_cr(data, line + len);
+        }
+        let total_size = line + len + BYTES_CRLF.len();
         if data.len() < total_size {
             return Ok(None);
         }
+        if &data[total_size - BYTES_CRLF.len()..total_size] != BYTES_CRLF {
+            // bad data chunk, which is swallowed with the command line, otherwise it would be
+            // taken as the next command
+            return match find_lf_simd(&data[line..]) {
+                Some(pos) => {
+                    data.advance(line + pos + 1);
+                    Err(AsError::BadMessage)
+                }
+                None => Ok(None),
+            };
+        }
 
         Ok(Some(Message {
             data: data.split_to(total_size).freeze(),
@@ -657,8 +792,8 @@ impl Message {
             .nth(3)
         {
             match btoi::btoi::<usize>(len_data) {
-                Ok(len) => len,
-                Err(_err) => {
+                Ok(len) if len <= MAX_BODY_SIZE => len,
+                _ => {
                     data.advance(line);
                     return Err(AsError::BadMessage);
                 }
@@ -702,6 +837,9 @@ impl Message {
         let body_len = cursor
             .read_u32::<BigEndian>()
             .map_err(|_| AsError::BadMessage)? as usize;
+        if body_len > MAX_BODY_SIZE || extra_len + key_len > body_len {
+            return Err(AsError::BadMessage);
+        }
 
         let tlen = BIN_HEADER_LEN + body_len;
         if data.len() < tlen {
@@ -727,6 +865,23 @@ impl Message {
     }
 }
 
+// insert `\r` before the bare `\n` at pos, the data after it is copied once.
+fn insert_cr(data: &mut BytesMut, pos: usize) {
+    let rest = data.split_off(pos);
+    data.reserve(rest.len() + 1);
+    data.extend_from_slice(b"\r");
+    data.extend_from_slice(&rest);
+}
+
+// the command or reply type is the first word of the line.
+fn is_leading_word(line: &[u8], begin: usize, end: usize) -> bool {
+    begin == 0
+        && line
+            .get(end)
+            .map(|x| *x == BYTE_SPACE || *x == b'\r' || *x == b'\n')
+            .unwrap_or(false)
+}
+
 #[cfg(test)]
 mod test {
     use self::super::*;
@@ -806,6 +961,11 @@ mod test {
                 flags: CmdFlags::empty(),
                 mtype: MsgType::TextReq(TextCmd::Gets(vec![Range::new(5, 10), Range::new(11, 18)])),
             },
+            Message {
+                data: Bytes::from("getdel mykey\r\n".as_bytes()),
+                flags: CmdFlags::empty(),
+                mtype: MsgType::TextReq(TextCmd::GetDel(Range::new(7, 12))),
+            },
             Message {
                 data: Bytes::from("incr mykey 10\r\n".as_bytes()),
                 flags: CmdFlags::empty(),
@@ -918,6 +1078,162 @@ mod test {
         let msg_rslt = Message::parse_binary(&mut data);
         assert!(msg_rslt.is_err());
     }
+
+    fn parse_size(src: &mut BytesMut) -> Result<Option<usize>, AsError> {
+        Message::parse(src).map(|x| x.map(|msg| msg.raw_len()))
+    }
+
+    fn bin_header(magic: u8, key_len: u16, extra_len: u8, body_len: u32) -> Vec<u8> {
+        let mut data = vec![magic, 0x0c];
+        data.extend_from_slice(&key_len.to_be_bytes());
+        data.extend_from_slice(&[extra_len, 0x00, 0x00, 0x00]);
+        data.extend_from_slice(&body_len.to_be_bytes());
+        data.extend_from_slice(&[0u8; 12]);
+        data
+    }
+
+    #[test]
+    fn test_parse_corpus() {
+        let bin_get = [bin_header(MSG_BIN_REQ, 3, 0, 3), b"ABC".to_vec()].concat();
+        let bin_value = [
+            bin_header(MSG_BIN_RESP, 3, 4, 12),
+            vec![0u8; 4],
+            b"ABC".to_vec(),
+            b"DE\x00fg".to_vec(),
+        ]
+        .concat();
+        let corpus: &[&[u8]] = &[
+            b"set mykey 0 0 2\r\nab\r\n",
+            b"cas mykey 0 0 3 47 noreply\r\na\x00b\r\n",
+            b"get mykey yourkey\r\n",
+            b"gets mykey\r\n",
+            b"gat 10 mykey\r\n",
+            b"delete mykey noreply\r\n",
+            b"incr mykey 10\r\n",
+            b"version\r\n",
+            b"VALUE mykey 0 2\r\nab\r\nEND\r\n",
+            b"END\r\n",
+            b"STORED\r\n",
+            &bin_get,
+            &bin_value,
+        ];
+        crate::protocol::fuzz::check_corpus(corpus, parse_size);
+    }
+
+    #[test]
+    fn test_parse_pathological() {
+        let bad_key = [bin_header(MSG_BIN_REQ, 8, 0, 3), b"ABC".to_vec()].concat();
+        let huge_body = bin_header(MSG_BIN_REQ, 3, 0, u32::max_value());
+        let bad: &[&[u8]] = &[
+            // huge declared length
+            b"set k 0 0 18446744073709551615\r\n",
+            b"set k 0 0 1073741825\r\n",
+            b"VALUE k 0 99999999999999999999\r\n",
+            &huge_body,
+            // key beyond the body
+            &bad_key,
+            // missing CRLF of data chunk
+            b"set k 0 0 2\r\nabXY\r\n",
+            b"set k 0 0 x\r\nab\r\n",
+            b"gat 10\r\n",
+            b"get\r\n",
+            b"\r\n",
+        ];
+        for data in bad {
+            let mut src = BytesMut::from(&data[..]);
+            assert_eq!(Message::parse(&mut src), Err(AsError::BadMessage));
+            assert!(src.len() < data.len());
+        }
+
+        // the command must be the first word
+        for data in &[&b"a gets\r\n"[..], b"getx k\r\n", b"xEND\r\n"] {
+            let mut src = BytesMut::from(&data[..]);
+            let msg = Message::parse(&mut src).unwrap().unwrap();
+            assert_eq!(msg.mtype, MsgType::TextInline);
+            assert!(src.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_parse_bare_lf() {
+        // strict: the line is cut before the last byte, so the key is never taken as given
+        let mut src = BytesMut::from(&b"get a\n"[..]);
+        let msg = Message::parse_text(&mut src).unwrap().unwrap();
+        assert_ne!(msg.get_key(), b"a");
+        let mut src = BytesMut::from(&b"set b 0 0 1\nc\n"[..]);
+        assert_eq!(Message::parse_text(&mut src), Err(AsError::BadMessage));
+
+        // lenient: the lines and data block are ended by `\r\n` as sent to backend
+        let mut src = BytesMut::from(&b"get a\nset b 0 0 1\nc"[..]);
+        let msg = Message::parse_text_lenient(&mut src).unwrap().unwrap();
+        assert_eq!(msg.get_key(), b"a");
+        assert_eq!(&msg.data[..], b"get a\r\n");
+        // waiting for the end of data block
+        assert_eq!(Message::parse_text_lenient(&mut src), Ok(None));
+        src.extend_from_slice(b"\n");
+        let msg = Message::parse_text_lenient(&mut src).unwrap().unwrap();
+        assert_eq!(msg.get_key(), b"b");
+        assert_eq!(&msg.data[..], b"set b 0 0 1\r\nc\r\n");
+        assert!(src.is_empty());
+
+        // the lines ended by `\r\n` are kept
+        let mut src = BytesMut::from(&b"set b 0 0 1\r\nc\r\n"[..]);
+        let msg = Message::parse_text_lenient(&mut src).unwrap().unwrap();
+        assert_eq!(&msg.data[..], b"set b 0 0 1\r\nc\r\n");
+    }
+
+    #[test]
+    fn test_reply_merge() {
+        let parse = |data: &[u8]| Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+        let merge = |merge: Merge, replies: &[&Message]| {
+            let replies: Vec<_> = replies.iter().map(|x| Some(*x)).collect();
+            let mut buf = BytesMut::new();
+            let size = Message::merge(merge, &replies, &mut buf).unwrap();
+            assert_eq!(size, buf.len());
+            buf
+        };
+        let value = parse(b"VALUE a 0 2\r\nab\r\nEND\r\n");
+        let miss = parse(b"END\r\n");
+        let stored = parse(b"STORED\r\n");
+        let count = parse(b"3\r\n");
+        let server_error = parse(b"SERVER_ERROR out of memory\r\n");
+        let proxy_error: Message = AsError::BackendClosedError("a".to_string()).into_reply();
+        assert!(server_error.is_error_reply());
+        assert!(proxy_error.is_error_reply());
+        assert!(!value.is_error_reply());
+
+        assert_eq!(
+            &merge(Merge::ConcatArray, &[&value, &miss, &value])[..],
+            &b"VALUE a 0 2\r\nab\r\nVALUE a 0 2\r\nab\r\nEND\r\n"[..]
+        );
+        assert_eq!(&merge(Merge::ConcatArray, &[])[..], BYTES_END);
+        assert_eq!(&merge(Merge::SumIntegers, &[&count, &count])[..], b"6\r\n");
+        assert_eq!(&merge(Merge::AllOk, &[&stored, &stored])[..], b"STORED\r\n");
+        assert_eq!(&merge(Merge::FirstError, &[&count, &stored])[..], b"3\r\n");
+
+        // the first error takes over the whole reply by any strategy
+        for strategy in &[
+            Merge::ConcatArray,
+            Merge::SumIntegers,
+            Merge::AllOk,
+            Merge::FirstError,
+        ] {
+            assert_eq!(
+                &merge(*strategy, &[&value, &server_error, &proxy_error])[..],
+                &server_error.data[..]
+            );
+            assert_eq!(
+                &merge(*strategy, &[&count, &proxy_error, &stored])[..],
+                &proxy_error.data[..]
+            );
+        }
+
+        let mut buf = BytesMut::new();
+        assert_eq!(
+            Message::merge(Merge::ConcatArray, &[Some(&value), None], &mut buf),
+            Err(AsError::BadReply)
+        );
+    }
 }
 
 impl Message {
@@ -968,6 +1284,17 @@ impl Message {
         subs
     }
 
+    /// the count of subs made by mk_subs, without making them.
+    pub(crate) fn subs_count(&self) -> usize {
+        match &self.mtype {
+            MsgType::TextReq(TextCmd::Get(ranges))
+            | MsgType::TextReq(TextCmd::Gets(ranges))
+            | MsgType::TextReq(TextCmd::Gat(_, ranges))
+            | MsgType::TextReq(TextCmd::Gats(_, ranges)) => ranges.len(),
+            _ => 0,
+        }
+    }
+
     pub(crate) fn version_request() -> Message {
         Message {
             data: Bytes::from(&b"version\r\n"[..]),
@@ -976,6 +1303,14 @@ impl Message {
         }
     }
 
+    /// the reply of version_request, e.g.: VERSION 1.6.9
+    pub(crate) fn is_version_reply(&self) -> bool {
+        let data = self.data.as_ref();
+        data.starts_with(b"VERSION ")
+            && data.ends_with(b"\r\n")
+            && !data[..data.len() - 2].contains(&b'\n')
+    }
+
     pub(crate) fn raw_inline_reply() -> Message {
         Message {
             data: Bytes::new(),
@@ -984,19 +1319,180 @@ impl Message {
         }
     }
 
+    /// the binary reply of the error found by proxy itself, its opcode and opaque are echoed
+    /// from the request header, so the client matches it to the request.
+    pub(crate) fn binary_error_reply(header: &[u8], err: &AsError) -> Message {
+        let status = match err {
+            AsError::ValueTooLarge(_) => BIN_STATUS_VALUE_TOO_LARGE,
+            AsError::ReplyMissing => BIN_STATUS_INTERNAL_ERROR,
+            _ => BIN_STATUS_INVALID_ARGUMENTS,
+        };
+        let mut data = BytesMut::with_capacity(BIN_HEADER_LEN);
+        data.put_u8(MSG_BIN_RESP);
+        data.put_u8(header.get(1).cloned().unwrap_or(0));
+        // key length, extras length and data type
+        data.put_slice(&[0u8; 4]);
+        data.put_u16_be(status);
+        // body length
+        data.put_u32_be(0);
+        data.put_slice(header.get(12..16).unwrap_or(&[0u8; 4]));
+        // cas
+        data.put_u64_be(0);
+        Message {
+            data: data.freeze(),
+            mtype: MsgType::TextInline,
+            flags: CmdFlags::empty(),
+        }
+    }
+
+    /// the header of binary message, None for text.
+    pub(crate) fn binary_header(&self) -> Option<&[u8]> {
+        match self.mtype {
+            MsgType::Binary { .. } => self.data.get(..BIN_HEADER_LEN),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn inline_line(line: &str) -> Message {
+        Message {
+            data: Bytes::from(format!("{}\r\n", line)),
+            mtype: MsgType::TextInline,
+            flags: CmdFlags::empty(),
+        }
+    }
+
+    /// request has no key by the command (e.g.: version), other than the key given empty.
+    pub(crate) fn is_keyless(&self) -> bool {
+        match &self.mtype {
+            MsgType::TextReq(cmd) => cmd.is_keyless(),
+            MsgType::Binary { bmtype, .. } => bmtype.is_keyless(),
+            _ => false,
+        }
+    }
+
+    /// the count of words in the line of text request including the command name, e.g.: the
+    /// keys of get plus one. The binary request is counted as one.
+    pub(crate) fn args_count(&self) -> usize {
+        match &self.mtype {
+            MsgType::TextReq(_) => {
+                let line = self.data.split(|x| *x == b'\n').next().unwrap_or(&[]);
+                line.split(|x| x.is_ascii_whitespace())
+                    .filter(|x| !x.is_empty())
+                    .count()
+            }
+            _ => 1,
+        }
+    }
+
+    /// the size of the data block of storage request (the value of binary request), 0 for
+    /// the others.
+    pub(crate) fn value_len(&self) -> usize {
+        match &self.mtype {
+            MsgType::TextReq(TextCmd::Set(_))
+            | MsgType::TextReq(TextCmd::Add(_))
+            | MsgType::TextReq(TextCmd::Replace(_))
+            | MsgType::TextReq(TextCmd::Append(_))
+            | MsgType::TextReq(TextCmd::Prepend(_))
+            | MsgType::TextReq(TextCmd::Cas(_)) => {
+                let line = self.data.iter().position(|x| *x == b'\n').unwrap_or(0) + 1;
+                self.data.len().saturating_sub(line + BYTES_CRLF.len())
+            }
+            MsgType::Binary { key, .. } => self.data.len().saturating_sub(key.end()),
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn is_version_request(&self) -> bool {
+        match &self.mtype {
+            MsgType::TextReq(TextCmd::Version) => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_quit_request(&self) -> bool {
+        match &self.mtype {
+            MsgType::TextReq(TextCmd::Quit) => true,
+            _ => false,
+        }
+    }
+
+    /// request may change the data of backend
+    pub(crate) fn is_write(&self) -> bool {
+        match &self.mtype {
+            MsgType::TextReq(cmd) => cmd.is_write(),
+            MsgType::Binary { bmtype, .. } => bmtype.is_write(),
+            _ => false,
+        }
+    }
+
+    /// the reply is compatible with the request, that's values (or END) for retrieval only and
+    /// binary reply of the same opcode, while error is compatible with any request.
+    pub(crate) fn accept_reply(&self, reply: &Message) -> bool {
+        if reply.is_error_reply() {
+            return true;
+        }
+        match (&self.mtype, &reply.mtype) {
+            (MsgType::TextReq(cmd), MsgType::TextRespValue) => cmd.is_retrieval(),
+            (MsgType::TextReq(cmd), MsgType::TextInline) => !cmd.is_retrieval(),
+            (
+                MsgType::Binary { bmtype, .. },
+                MsgType::Binary {
+                    btype: BinType::Resp,
+                    bmtype: reply_type,
+                    ..
+                },
+            ) => bmtype == reply_type,
+            (MsgType::TextReq(_), _) | (MsgType::Binary { .. }, _) => false,
+            _ => true,
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> Bytes {
+        self.data.clone()
+    }
+
+    /// name of the request command, used as the label of metrics
+    pub(crate) fn cmd_name(&self) -> String {
+        match &self.mtype {
+            MsgType::TextReq(cmd) => String::from_utf8_lossy(cmd.cmd_slice()).trim().to_string(),
+            MsgType::Binary { bmtype, .. } => format!("{:?}", bmtype).to_lowercase(),
+            _ => "unknown".to_string(),
+        }
+    }
+
+    /// the name of dangerous command (e.g.: shutdown) sent as is, see DANGEROUS_CMDS.
+    pub(crate) fn dangerous_name(&self) -> Option<String> {
+        let name = match &self.mtype {
+            MsgType::TextInline => {
+                let word = self.data.split(|x| x.is_ascii_whitespace()).next()?;
+                String::from_utf8_lossy(word).to_lowercase()
+            }
+            MsgType::Binary { bmtype, .. } => format!("{:?}", bmtype).to_lowercase(),
+            _ => return None,
+        };
+        if DANGEROUS_CMDS.contains(&name.as_str()) {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
     pub(crate) fn is_noreply(&self) -> bool {
         self.flags & CmdFlags::NOREPLY == CmdFlags::NOREPLY
     }
 
-    pub fn try_save_ends(&self, target: &mut BytesMut) {
+    pub fn raw_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// the strategy to merge the replies of subs, see mk_subs for the fan-out commands.
+    pub fn merge(&self) -> Merge {
         match &self.mtype {
             MsgType::TextReq(TextCmd::Get(_))
             | MsgType::TextReq(TextCmd::Gets(_))
             | MsgType::TextReq(TextCmd::Gat(_, _))
-            | MsgType::TextReq(TextCmd::Gats(_, _)) => {
-                target.extend_from_slice(BYTES_END);
-            }
-            _ => {}
+            | MsgType::TextReq(TextCmd::Gats(_, _)) => Merge::ConcatArray,
+            _ => Merge::FirstError,
         }
     }
 
@@ -1012,6 +1508,38 @@ impl Message {
         &self.data[key.begin()..key.end()]
     }
 
+    /// the text get of one key, e.g.: each sub of get.
+    pub(crate) fn is_single_get(&self) -> bool {
+        match &self.mtype {
+            MsgType::TextReq(TextCmd::Get(ranges)) => ranges.len() == 1,
+            _ => false,
+        }
+    }
+
+    /// the text get of the key, e.g.: the replay of integrity check.
+    pub(crate) fn text_get(key: &[u8]) -> Message {
+        let mut data = BytesMut::with_capacity(key.len() + 6);
+        data.extend_from_slice(b"get ");
+        data.extend_from_slice(key);
+        data.extend_from_slice(BYTES_CRLF);
+        Message {
+            data: data.freeze(),
+            mtype: MsgType::TextReq(TextCmd::Get(vec![Range::new(4, 4 + key.len())])),
+            flags: CmdFlags::empty(),
+        }
+    }
+
+    /// the text get, gets, gat or gats, whose miss is replied by END.
+    pub(crate) fn is_text_get(&self) -> bool {
+        matches!(
+            self.mtype,
+            MsgType::TextReq(TextCmd::Get(_))
+                | MsgType::TextReq(TextCmd::Gets(_))
+                | MsgType::TextReq(TextCmd::Gat(_, _))
+                | MsgType::TextReq(TextCmd::Gats(_, _))
+        )
+    }
+
     pub fn save_reply(&self, reply: Message, target: &mut BytesMut) -> Result<(), AsError> {
         if self.is_noreply() {
             return Ok(());
@@ -1077,6 +1605,17 @@ impl Message {
                     target.extend_from_slice(BYTES_CRLF);
                     Ok(())
                 }
+                // the delete follows the get on the same connection, so nothing sets the key
+                // between them, and it's never replied so the get is the only reply expected
+                TextCmd::GetDel(ref rng) => {
+                    let key = &self.data[rng.begin()..rng.end()];
+                    target.extend_from_slice(b"get ");
+                    target.extend_from_slice(key);
+                    target.extend_from_slice(b"\r\ndelete ");
+                    target.extend_from_slice(key);
+                    target.extend_from_slice(b" noreply\r\n");
+                    Ok(())
+                }
                 _ => {
                     target.extend_from_slice(self.data.as_ref());
                     Ok(())
@@ -1097,6 +1636,61 @@ impl Message {
     }
 }
 
+impl ReplyMerge for Message {
+    fn is_error_reply(&self) -> bool {
+        let data = self.data.as_ref();
+        BYTES_ERRORS.iter().any(|x| data.starts_with(x))
+    }
+
+    fn merge(
+        merge: Merge,
+        replies: &[Option<&Message>],
+        buf: &mut BytesMut,
+    ) -> Result<usize, AsError> {
+        let replies = all_replied(replies)?;
+        let begin = buf.len();
+        // text protocol can't carry the error of one key among the values, so the first
+        // error is replied as a whole
+        if let Some(err) = first_error(&replies) {
+            buf.extend_from_slice(err.data.as_ref());
+            return Ok(buf.len() - begin);
+        }
+        match merge {
+            Merge::ConcatArray => {
+                // the values are sliced as replied, the flags, bytes and cas are never
+                // re-formatted
+                for reply in replies {
+                    let data = reply.data.as_ref();
+                    buf.extend_from_slice(data.strip_suffix(BYTES_END).unwrap_or(data));
+                }
+                buf.extend_from_slice(BYTES_END);
+            }
+            Merge::SumIntegers => {
+                let total: usize = replies
+                    .iter()
+                    .map(|x| x.data.as_ref())
+                    .map(|x| x.strip_suffix(BYTES_CRLF).unwrap_or(x))
+                    .map(|x| btoi::btoi::<usize>(x).unwrap_or(0))
+                    .sum();
+                buf.extend_from_slice(format!("{}\r\n", total).as_bytes());
+            }
+            Merge::AllOk | Merge::FirstError => {
+                if let Some(reply) = replies.first() {
+                    buf.extend_from_slice(reply.data.as_ref());
+                }
+            }
+        }
+        Ok(buf.len() - begin)
+    }
+
+    fn absent(merge: Merge) -> Option<Message> {
+        match merge {
+            Merge::ConcatArray => Some(Message::inline_line("END")),
+            Merge::SumIntegers | Merge::AllOk | Merge::FirstError => None,
+        }
+    }
+}
+
 impl From<AsError> for Message {
     fn from(oe: AsError) -> Message {
         (&oe).into()
@@ -1105,8 +1699,33 @@ impl From<AsError> for Message {
 
 impl<'a> Into<Message> for &'a AsError {
     fn into(self) -> Message {
+        let data = match self {
+            AsError::ReadOnly => BYTES_SERVER_ERROR_READONLY.to_vec(),
+            AsError::Maintenance => BYTES_SERVER_ERROR_MAINTENANCE.to_vec(),
+            AsError::ShuttingDown => BYTES_SERVER_ERROR_SHUTTING_DOWN.to_vec(),
+            AsError::ReplyMissing => BYTES_SERVER_ERROR_REPLY_MISSING.to_vec(),
+            AsError::BadMessage => BYTES_CLIENT_ERROR_BAD_FORMAT.to_vec(),
+            AsError::Dangerous(name) => format!(
+                "CLIENT_ERROR dangerous command {} is denied by proxy\r\n",
+                name
+            )
+            .into_bytes(),
+            AsError::Injected | AsError::InjectedDown(_) => BYTES_SERVER_ERROR_INJECTED.to_vec(),
+            AsError::ValueTooLarge(_) => BYTES_SERVER_ERROR_TOO_LARGE.to_vec(),
+            AsError::TooManyArgs(max) => format!(
+                "CLIENT_ERROR too many arguments of command, max_args is {}\r\n",
+                max
+            )
+            .into_bytes(),
+            AsError::TooManySubs(max) => format!(
+                "SERVER_ERROR too many keys in flight, max_outstanding_subs is {}\r\n",
+                max
+            )
+            .into_bytes(),
+            _ => format!("error {}\r\n", self).into_bytes(),
+        };
         Message {
-            data: Bytes::from(format!("error {}\r\n", self).as_bytes()),
+            data: Bytes::from(data),
             mtype: MsgType::TextInline,
             flags: CmdFlags::empty(),
         }
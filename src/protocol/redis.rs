@@ -4,22 +4,42 @@ use tokio::codec::{Decoder, Encoder};
 
 use crate::metrics::*;
 
-use crate::com::{meta, AsError};
+use crate::com::{meta, AsError, BackendFlavor, ClusterConfig, FrontProtocol};
+use crate::protocol::redis::cmd::{CommandFlags, CMD_DANGEROUS_SUBS, CMD_TYPE};
+use crate::protocol::redis::legacy::Shims;
+use crate::protocol::redis::ttl::TtlPolicies;
 use crate::protocol::IntoReply;
-use crate::protocol::{CmdFlags, CmdType};
-use crate::proxy::standalone::Request;
+use crate::protocol::{all_replied, first_error, Merge, ReplyMerge};
+use crate::protocol::{next_wave, ArgsLimit, CmdFlags, CmdType, SubsLimit, ValueLimit};
+use crate::proxy::acl::{AclReply, Category};
+use crate::proxy::budget::Budget;
+use crate::proxy::standalone::barrier;
+use crate::proxy::standalone::deadline;
+use crate::proxy::standalone::dialect::BackendProtocols;
+use crate::proxy::standalone::hash::HashMethod;
+use crate::proxy::standalone::hint::{self, RouteHint};
+use crate::proxy::standalone::integrity;
+use crate::proxy::standalone::subfail::SubFailure;
+use crate::proxy::standalone::tombstone::Tombstone;
+use crate::proxy::standalone::{join_nodes, Request};
 use crate::utils::notify::Notify;
 use crate::utils::{myitoa, trim_hash_tag, upper};
 
 use std::cell::{Ref, RefCell, RefMut};
 use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
 
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 pub const SLOTS_COUNT: usize = 16384;
 
 pub mod cmd;
+pub mod diag;
+pub mod help;
+pub mod legacy;
+pub mod prefix;
 pub mod resp;
+pub mod ttl;
 
 pub use resp::{Message, MessageIter, MessageMut, RespType};
 pub use resp::{RESP_ERROR, RESP_INT, RESP_STRING};
@@ -28,6 +48,18 @@ const BYTES_CMD_CLUSTER: &[u8] = b"CLUSTER";
 const BYTES_CMD_QUIT: &[u8] = b"QUIT";
 const BYTES_SLOTS: &[u8] = b"SLOTS";
 const BYTES_NODES: &[u8] = b"NODES";
+const BYTES_CMD_SORT: &[u8] = b"SORT";
+const BYTES_CMD_SORT_RO: &[u8] = b"SORT_RO";
+const BYTES_CMD_PROXY: &[u8] = b"PROXY";
+const BYTES_CMD_ASTER: &[u8] = b"ASTER";
+const BYTES_CMD_CLIENT: &[u8] = b"CLIENT";
+const BYTES_KILL: &[u8] = b"KILL";
+const BYTES_KEYSLOT: &[u8] = b"KEYSLOT";
+const BYTES_CMD_HELLO: &[u8] = b"HELLO";
+const BYTES_CMD_AUTH: &[u8] = b"AUTH";
+const BYTES_CMD_ACL: &[u8] = b"ACL";
+const BYTES_CMD_FUNCTION: &[u8] = b"FUNCTION";
+const BYTES_HELLO_3: &[u8] = b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n";
 
 #[derive(Clone, Debug)]
 pub struct Cmd {
@@ -35,18 +67,6 @@ pub struct Cmd {
     notify: Notify,
 }
 
-impl Drop for Cmd {
-    fn drop(&mut self) {
-        let expect = self.notify.expect();
-        let origin = self.notify.fetch_sub(1);
-        // TODO: sub command maybe notify multiple
-        // trace!("cmd drop strong ref {} and expect {}", origin, expect);
-        if origin - 1 == expect {
-            self.notify.notify();
-        }
-    }
-}
-
 impl Request for Cmd {
     type Reply = Message;
     type FrontCodec = RedisHandleCodec;
@@ -55,8 +75,7 @@ impl Request for Cmd {
     fn ping_request() -> Self {
         let msg = Message::new_ping_request();
         let flags = CmdFlags::empty();
-        let mut notify = Notify::empty();
-        notify.set_expect(1);
+        let notify = Notify::empty();
         let ctype = CmdType::get_cmd_type(&msg);
 
         let cmd = Command {
@@ -66,20 +85,59 @@ impl Request for Cmd {
             req: msg,
             reply: None,
             subs: None,
+            released: 0,
 
             total_tracker: None,
 
             remote_tracker: None,
+            node: None,
+            slot: None,
+            pinned: None,
+            timeout: None,
+            time_limit: None,
+            queued: None,
+            deadline: None,
+            trace: 0,
+            sub_failure: None,
         };
         cmd.into_cmd(notify)
     }
 
+    fn status_reply(line: &str) -> Self {
+        let cmd = Self::ping_request();
+        cmd.set_reply(line);
+        cmd
+    }
+
+    fn back_codec(cc: &ClusterConfig, node: &str) -> RedisNodeCodec {
+        // the limits and protocols are checked by Config::valid already
+        let limits = ReplyLimits::from_config(cc).unwrap_or_default();
+        let protocols = BackendProtocols::new(&cc.backend_protocols).unwrap_or_default();
+        RedisNodeCodec::with_prefix(cc.key_prefix.as_ref().map(|x| x.as_str()))
+            .reply_limits(limits)
+            .protocol(protocols.get(cc, node))
+            .ttl_policies(TtlPolicies::from_config(cc))
+    }
+
+    fn front_codec(
+        cc: &ClusterConfig,
+        _protocol: Option<FrontProtocol>,
+        client: &str,
+    ) -> RedisHandleCodec {
+        RedisHandleCodec::default()
+            .args_limit(ArgsLimit::from_config(cc, client))
+            .value_limit(ValueLimit::from_config(cc))
+            .subs_limit(SubsLimit::from_config(cc))
+            .shims(Shims::from_config(cc))
+            .ttl_policies(TtlPolicies::from_config(cc))
+    }
+
     fn reregister(&mut self, task: Task) {
         self.notify.set_task(task);
     }
 
-    fn key_hash(&self, hash_tag: &[u8], hasher: fn(&[u8]) -> u64) -> u64 {
-        self.cmd.borrow().key_hash(hash_tag, hasher)
+    fn key_hash(&self, hash_tag: &[u8], hasher: HashMethod) -> Option<u64> {
+        self.cmd.borrow().key_hash(hash_tag, |x| hasher.hash(x))
     }
 
     fn subs(&self) -> Option<Vec<Self>> {
@@ -87,43 +145,469 @@ impl Request for Cmd {
     }
 
     fn is_done(&self) -> bool {
-        if let Some(subs) = self.subs() {
-            subs.into_iter().all(|x| x.is_done())
-        } else {
-            self.cmd.borrow().is_done()
-        }
+        self.cmd.borrow().is_done()
     }
 
     fn is_error(&self) -> bool {
         self.cmd.borrow().is_error()
     }
 
+    fn reply_size(&self) -> usize {
+        self.cmd.borrow().reply_size()
+    }
+
     fn add_cycle(&self) {
         self.borrow_mut().add_cycle()
     }
     fn can_cycle(&self) -> bool {
         self.borrow().can_cycle()
     }
+    fn mark_retry(&self) -> bool {
+        self.borrow_mut().mark_retry()
+    }
+
+    fn accept_reply(&self, reply: &Message) -> bool {
+        CmdType::accept_reply(&self.cmd.borrow().req, reply)
+    }
 
     fn valid(&self) -> bool {
         self.check_valid()
     }
 
+    fn reply_quit(&self) -> bool {
+        if !self.cmd.borrow().is_quit() {
+            return false;
+        }
+        self.set_reply("OK");
+        true
+    }
+
+    fn reply_tombstone(&self, tombstone: Tombstone) -> bool {
+        self.set_reply(tombstone);
+        true
+    }
+
+    fn reply_checksum(&self) -> Option<u64> {
+        let cmd = self.cmd.borrow();
+        if !cmd.is_single_get() {
+            return None;
+        }
+        cmd.reply
+            .as_ref()
+            .map(|x| integrity::checksum(x.raw_data()))
+    }
+
+    fn integrity_replay(&self) -> Option<Self> {
+        let cmd = self.cmd.borrow();
+        if !cmd.is_single_get() {
+            return None;
+        }
+        let msg = Message::from_args(vec![BYTES_CMD_GET, cmd.req.nth(1)?]);
+        let ctype = CmdType::get_cmd_type(&msg);
+        let command = Command {
+            flags: CmdFlags::empty(),
+            ctype,
+            cycle: DEFAULT_CYCLE,
+            req: msg,
+            reply: None,
+            subs: None,
+            released: 0,
+
+            total_tracker: None,
+
+            remote_tracker: None,
+            node: None,
+            slot: None,
+            pinned: None,
+            timeout: None,
+            time_limit: None,
+            queued: None,
+            deadline: None,
+            trace: 0,
+            sub_failure: None,
+        };
+        Some(command.into_cmd(Notify::empty()))
+    }
+
     fn set_reply<R: IntoReply<Message>>(&self, t: R) {
         let reply = t.into_reply();
-        let mut cmd = self.cmd.borrow_mut();
-        cmd.set_reply(reply);
+        self.transit(|cmd| cmd.set_reply(reply));
     }
 
     fn set_error(&self, t: &AsError) {
-        let reply: Message = t.into_reply();
-        let mut cmd = self.cmd.borrow_mut();
-        cmd.set_reply(reply);
-        cmd.set_error();
+        self.transit(|cmd| cmd.set_error_by(t));
 
         global_error_incr();
     }
 
+    fn is_ctrl(&self) -> bool {
+        self.cmd.borrow().ctype.is_ctrl()
+    }
+
+    fn is_mutation(&self) -> bool {
+        self.cmd.borrow().is_mutation()
+    }
+
+    fn is_read(&self) -> bool {
+        self.cmd.borrow().is_read()
+    }
+
+    fn is_write(&self) -> bool {
+        self.cmd.borrow().is_write()
+    }
+
+    fn is_admin(&self) -> bool {
+        self.cmd.borrow().is_admin()
+    }
+
+    fn dangerous_name(&self) -> Option<String> {
+        self.cmd.borrow().dangerous_name()
+    }
+
+    fn is_blocking(&self) -> bool {
+        self.cmd.borrow().is_blocking()
+    }
+
+    fn is_keyless(&self) -> bool {
+        self.cmd.borrow().is_keyless_by_table()
+    }
+
+    fn reply_keyless(&self) -> bool {
+        // PING and the like are replied by proxy ahead
+        false
+    }
+
+    fn next_wave(&self, batch: usize) -> Option<Vec<Self>> {
+        self.cmd.borrow_mut().next_wave(batch)
+    }
+
+    fn has_wave(&self) -> bool {
+        self.cmd.borrow().has_wave()
+    }
+
+    fn has_key_prefix(&self, prefix: &[u8]) -> bool {
+        self.cmd.borrow().has_key_prefix(prefix)
+    }
+
+    fn with_key<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let cmd = self.cmd.borrow();
+        cmd.req.nth(cmd.key_pos()).map(f)
+    }
+
+    fn sort_pattern(&self, hash_tag: &[u8]) -> Option<Vec<u8>> {
+        self.cmd.borrow().sort_pattern(hash_tag).map(|x| x.to_vec())
+    }
+
+    fn check_two_keys(&self, hash_tag: &[u8], hasher: HashMethod) -> Result<(), AsError> {
+        self.cmd
+            .borrow()
+            .check_two_keys(hash_tag, |x| hasher.hash(x))
+    }
+
+    fn bound_timeout(
+        &self,
+        hash_tag: &[u8],
+        hasher: HashMethod,
+        max: u64,
+    ) -> Result<Option<String>, AsError> {
+        let timeout = self
+            .cmd
+            .borrow_mut()
+            .bound_timeout(hash_tag, |x| hasher.hash(x), max)?;
+        Ok(timeout.map(|x| String::from_utf8_lossy(&x).to_string()))
+    }
+
+    fn handle_proxy<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<Option<Vec<String>>, AsError>,
+    {
+        let args = match self.cmd.borrow().proxy_args() {
+            Some(args) => args,
+            None => return false,
+        };
+        match f(&args) {
+            Ok(None) => self.set_reply("OK"),
+            Ok(Some(items)) => self.set_reply(Message::from_args(&items)),
+            Err(err) => self.set_error(&err),
+        }
+        true
+    }
+
+    fn barrier_timeout(&self) -> Option<Result<u64, AsError>> {
+        let args = self.cmd.borrow().proxy_args()?;
+        barrier::barrier_timeout(&args)
+    }
+
+    fn route_hint(&self) -> Option<Result<RouteHint, AsError>> {
+        let args = self.cmd.borrow().proxy_args()?;
+        hint::route_hint(&args)
+    }
+
+    fn set_pinned(&self, addr: &str) {
+        for sub in self.subs().unwrap_or_default() {
+            sub.set_pinned(addr);
+        }
+        self.cmd.borrow_mut().pinned = Some(addr.to_string());
+    }
+
+    fn pinned(&self) -> Option<String> {
+        self.cmd.borrow().pinned.clone()
+    }
+
+    fn broadcast(&self, addrs: &[String]) -> bool {
+        let subs: Vec<_> = {
+            let cmd = self.cmd.borrow();
+            if addrs.is_empty() || cmd.subs.is_some() || !cmd.is_function() {
+                return false;
+            }
+            addrs
+                .iter()
+                .map(|addr| {
+                    let sub = Command {
+                        flags: cmd.flags,
+                        ctype: cmd.ctype,
+                        cycle: DEFAULT_CYCLE,
+                        req: cmd.req.clone(),
+                        reply: None,
+                        subs: None,
+                        released: 0,
+
+                        total_tracker: None,
+
+                        remote_tracker: None,
+                        node: None,
+                        slot: None,
+                        pinned: Some(addr.clone()),
+                        timeout: None,
+                        time_limit: None,
+                        queued: None,
+                        deadline: None,
+                        trace: 0,
+                        sub_failure: None,
+                    };
+                    sub.into_cmd(self.notify.clone())
+                })
+                .collect()
+        };
+        let mut notify = self.notify.clone();
+        notify.set_expect(subs.len());
+        self.cmd.borrow_mut().subs = Some(subs);
+        true
+    }
+
+    fn set_synced(&self, count: usize) {
+        self.set_reply(count)
+    }
+
+    fn handle_client_kill<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<usize, AsError>,
+    {
+        let args = match self.cmd.borrow().client_kill_args() {
+            Some(args) => args,
+            None => return false,
+        };
+        match f(&args) {
+            Ok(count) => self.set_reply(count),
+            Err(err) => self.set_error(&err),
+        }
+        true
+    }
+
+    fn handle_info<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> String,
+    {
+        let args = match self.cmd.borrow().info_args() {
+            Some(args) => args,
+            None => return false,
+        };
+        self.set_reply(Message::bulk(f(&args).as_bytes()));
+        true
+    }
+
+    fn acl_category(&self) -> Category {
+        self.cmd.borrow().acl_category()
+    }
+
+    fn acl_keys(&self) -> Vec<Vec<u8>> {
+        self.cmd
+            .borrow()
+            .acl_keys()
+            .into_iter()
+            .map(|x| x.to_vec())
+            .collect()
+    }
+
+    fn handle_acl<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<AclReply, AsError>,
+    {
+        let args = match self.cmd.borrow().acl_args() {
+            Some(args) => args,
+            None => return false,
+        };
+        match f(&args) {
+            Ok(reply) => self.set_reply(reply),
+            Err(err) => self.set_error(&err),
+        }
+        true
+    }
+
+    fn req_data(&self) -> Bytes {
+        self.cmd.borrow().req_data()
+    }
+
+    fn reply_data(&self, buf: &mut BytesMut) {
+        let _ = self.cmd.borrow().reply_cmd(buf);
+    }
+
+    fn cmd_name(&self) -> String {
+        let cmd = self.cmd.borrow();
+        let name = cmd.req.nth(COMMAND_POS).unwrap_or_default();
+        String::from_utf8_lossy(name).to_string()
+    }
+
+    fn reply(&self) -> Option<Message> {
+        self.cmd.borrow().reply.clone()
+    }
+
+    fn is_pong(&self) -> bool {
+        let cmd = self.cmd.borrow();
+        cmd.reply
+            .as_ref()
+            .map(|x| x.raw_data() == BYTES_PONG)
+            .unwrap_or(false)
+    }
+
+    fn is_unavailable(&self, flavor: BackendFlavor) -> bool {
+        let cmd = self.cmd.borrow();
+        cmd.reply
+            .as_ref()
+            .map(|x| flavor.is_unavailable(x))
+            .unwrap_or(false)
+    }
+
+    fn keys(&self) -> Vec<Vec<u8>> {
+        self.cmd
+            .borrow()
+            .keys()
+            .into_iter()
+            .map(|x| x.to_vec())
+            .collect()
+    }
+
+    fn set_node(&self, node: &str) {
+        self.cmd.borrow_mut().node = Some(node.to_string());
+    }
+
+    fn node(&self) -> Option<String> {
+        let cmd = self.cmd.borrow();
+        match cmd.subs.as_ref() {
+            Some(subs) => join_nodes(subs.iter().filter_map(|x| x.node())),
+            None => cmd.node.clone(),
+        }
+    }
+
+    fn set_timeout(&self, timeout: Duration) {
+        self.cmd.borrow_mut().timeout = Some(timeout);
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        let cmd = self.cmd.borrow();
+        match cmd.subs.as_ref() {
+            Some(subs) => subs.iter().filter_map(|x| x.timeout()).max(),
+            None => cmd.timeout,
+        }
+    }
+
+    fn set_time_limit(&self, limit: Duration) {
+        self.cmd.borrow_mut().time_limit = Some(limit);
+    }
+
+    fn time_limit(&self) -> Option<Duration> {
+        self.cmd.borrow().time_limit
+    }
+
+    fn set_queued(&self, since: Option<Instant>) {
+        self.cmd.borrow_mut().queued = since;
+    }
+
+    fn queued(&self) -> Option<Instant> {
+        self.cmd.borrow().queued
+    }
+
+    fn handle_deadline<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(Duration),
+    {
+        let budget = match self.cmd.borrow().client_deadline() {
+            Some(budget) => budget,
+            None => return false,
+        };
+        match budget {
+            Ok(budget) => {
+                f(budget);
+                self.set_reply("OK");
+            }
+            Err(err) => self.set_error(&err),
+        }
+        true
+    }
+
+    fn set_deadline(&self, deadline: Instant) {
+        for sub in self.subs().unwrap_or_default() {
+            sub.set_deadline(deadline);
+        }
+        self.cmd.borrow_mut().deadline = Some(deadline);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.cmd.borrow().deadline
+    }
+
+    fn set_trace_id(&self, id: u64) {
+        for sub in self.subs().unwrap_or_default() {
+            sub.set_trace_id(id);
+        }
+        self.cmd.borrow_mut().trace = id;
+    }
+
+    fn trace_id(&self) -> u64 {
+        self.cmd.borrow().trace
+    }
+
+    fn set_sub_failure(&self, policy: SubFailure) {
+        self.cmd.borrow_mut().sub_failure = Some(policy);
+    }
+
+    fn retry_failed_subs(&self, budget: &Budget) -> Vec<Self> {
+        let failed: Vec<_> = {
+            let cmd = self.cmd.borrow();
+            if cmd.sub_failure != Some(SubFailure::Retry) {
+                return Vec::new();
+            }
+            cmd.subs
+                .iter()
+                .flatten()
+                .filter(|x| x.borrow().is_rejected())
+                .cloned()
+                .collect()
+        };
+        let retried: Vec<_> = failed
+            .into_iter()
+            .filter(|x| x.mark_retry() && budget.try_retry())
+            .collect();
+        for sub in retried.iter() {
+            sub.unset_error();
+            sub.unset_done();
+        }
+        retried
+    }
+
     fn mark_total(&self, cluster: &str) {
         let timer = total_tracker(cluster);
         self.cmd.borrow_mut().total_tracker.replace(timer);
@@ -133,6 +617,14 @@ impl Request for Cmd {
         let timer = remote_tracker(cluster);
         self.cmd.borrow_mut().remote_tracker.replace(timer);
     }
+
+    fn total_elapsed(&self) -> Option<Duration> {
+        self.cmd
+            .borrow()
+            .total_tracker
+            .as_ref()
+            .map(|x| x.elapsed())
+    }
 }
 
 impl Cmd {
@@ -141,6 +633,10 @@ impl Cmd {
         self.cmd.borrow_mut().total_tracker.replace(timer);
     }
 
+    pub fn set_slot(&self, slot: usize) {
+        self.cmd.borrow_mut().slot = Some(slot);
+    }
+
     pub fn cluster_mark_remote(&self, cluster: &str) {
         let timer = remote_tracker(cluster);
         if self.cmd.borrow().remote_tracker.is_none() {
@@ -148,10 +644,6 @@ impl Cmd {
         }
     }
 
-    pub fn incr_notify(&self, count: u16) {
-        self.notify.fetch_add(count);
-    }
-
     pub fn borrow(&self) -> Ref<Command> {
         self.cmd.borrow()
     }
@@ -165,44 +657,82 @@ impl Cmd {
     }
 
     pub fn unset_done(&self) {
-        self.cmd.borrow_mut().unset_done();
+        self.transit(|cmd| cmd.unset_done());
     }
 
     pub fn set_reply<T: IntoReply<Message>>(&self, reply: T) {
-        self.borrow_mut().set_reply(reply);
+        self.transit(|cmd| cmd.set_reply(reply));
+    }
+
+    pub fn set_error(&self, err: &AsError) {
+        self.transit(|cmd| cmd.set_error_by(err));
     }
 
-    pub fn set_error<T: IntoReply<Message>>(&self, reply: T) {
-        self.borrow_mut().set_reply(reply);
-        self.borrow_mut().set_error();
+    // change the reply of command and count its completion by the transition of done, the
+    // parent rejected as a whole wakes the task at once since its subs are never replied.
+    fn transit<F: FnOnce(&mut Command)>(&self, f: F) {
+        let (was, now, is_sub) = {
+            let mut cmd = self.cmd.borrow_mut();
+            let was = cmd.is_replied();
+            f(&mut cmd);
+            (was, cmd.is_replied(), cmd.subs.is_none())
+        };
+        match (was, now) {
+            (false, true) if is_sub => self.notify.done(),
+            (false, true) => self.notify.notify(),
+            (true, false) if is_sub => self.notify.undone(),
+            _ => {}
+        }
     }
 
     pub fn reregister(&mut self, task: Task) {
         self.notify.set_task(task);
     }
 
-    pub fn check_valid(&self) -> bool {
-        if self.borrow().ctype.is_not_support() {
-            self.borrow_mut().set_reply(AsError::RequestNotSupport);
-            return false;
+    /// the request sent to backend, e.g.: ["MGET", "a"] of each sub of MGET a b.
+    pub fn req(&self) -> Ref<Message> {
+        Ref::map(self.cmd.borrow(), |x| &x.req)
+    }
+
+    /// rewrite the request before it's dispatched (e.g.: by hooks), the type of command
+    /// follows the rewritten command name.
+    pub fn rewrite<F: FnOnce(&mut Message)>(&mut self, f: F) {
+        let mut cmd = self.cmd.borrow_mut();
+        f(&mut cmd.req);
+        let name = cmd.req.nth(COMMAND_POS).map(|x| x.to_ascii_uppercase());
+        if let Some(name) = name {
+            if cmd.req.nth(COMMAND_POS) != Some(&name[..]) {
+                cmd.req.set_nth(COMMAND_POS, &name);
+            }
         }
+        cmd.ctype = CmdType::get_cmd_type(&cmd.req);
+    }
+
+    pub fn check_valid(&self) -> bool {
         if self.borrow().is_done() {
             return true;
         }
+        if self.borrow().ctype.is_not_support() {
+            self.set_reply(AsError::RequestNotSupport);
+            return false;
+        }
 
         if self.borrow().ctype.is_ctrl() {
-            let is_quit = self
-                .borrow()
-                .req
-                .nth(0)
-                .map(|x| x == BYTES_CMD_QUIT)
-                .unwrap_or(false);
-            if is_quit {
-                self.borrow_mut()
-                    .set_reply(Message::inline_raw(Bytes::new()));
+            if self.borrow().is_quit() {
+                self.set_reply("OK");
                 return false;
             }
 
+            // PROXY and ASTER commands, CLIENT KILL, INFO, AUTH and ACL are handled by the front
+            if self.borrow().is_proxy()
+                || self.borrow().is_aster()
+                || self.borrow().is_client_kill()
+                || self.borrow().is_info()
+                || self.borrow().is_acl()
+            {
+                return true;
+            }
+
             // check if is cluster
             let is_cluster = self
                 .borrow()
@@ -220,7 +750,7 @@ impl Cmd {
                             MessageMut::parse(&mut data).map(|x| x.map(|y| y.into()))
                         {
                             let msg: Message = msg;
-                            self.borrow_mut().set_reply(msg);
+                            self.set_reply(msg);
                             return false;
                         };
                     } else if sub_cmd == BYTES_NODES {
@@ -229,13 +759,13 @@ impl Cmd {
                             MessageMut::parse(&mut data).map(|x| x.map(|y| y.into()))
                         {
                             let msg: Message = msg;
-                            self.borrow_mut().set_reply(msg);
+                            self.set_reply(msg);
                             return false;
                         };
                     }
                 }
             }
-            self.borrow_mut().set_reply(AsError::RequestNotSupport);
+            self.set_reply(AsError::RequestNotSupport);
             return false;
         }
         // and other conditions
@@ -254,19 +784,47 @@ pub struct Command {
     pub reply: Option<Message>,
 
     subs: Option<Vec<Cmd>>,
+    // count of subs released to dispatch in waves
+    released: usize,
 
     total_tracker: Option<Tracker>,
 
     remote_tracker: Option<Tracker>,
+
+    // backend node dispatched to, only set if access log is enabled
+    node: Option<String>,
+    // slot dispatched to, only set in cluster mode
+    slot: Option<usize>,
+    // backend address pinned by PROXY ROUTE, which bypasses the hashing
+    pinned: Option<String>,
+    // timeout of backend in effect when dispatched, only set if access log is enabled
+    timeout: Option<Duration>,
+    // time limit by command_timeouts, only set at dispatch
+    time_limit: Option<Duration>,
+    // since queued in front for dispatch, None once it's sent
+    queued: Option<Instant>,
+    // deadline given by ASTER DEADLINE right before the command
+    deadline: Option<Instant>,
+    // tick of cluster when decoded, 0 if it's not traced
+    trace: u64,
+    // policy of the subs failed by proxy, None keeps the merging by the command table
+    sub_failure: Option<SubFailure>,
 }
 
 const BYTES_JUSTOK: &[u8] = b"+OK\r\n";
 const BYTES_NULL_ARRAY: &[u8] = b"*-1\r\n";
-const BYTES_ZERO_INT: &[u8] = b":0\r\n";
 const BYTES_CMD_PING: &[u8] = b"PING";
 const BYTES_CMD_COMMAND: &[u8] = b"COMMAND";
-const BYTES_REPLY_NULL_ARRAY: &[u8] = b"*-1\n";
+const BYTES_CMD_GETKEYS: &[u8] = b"GETKEYS";
+const BYTES_CMD_INFO: &[u8] = b"INFO";
+const BYTES_CMD_COUNT: &[u8] = b"COUNT";
+const BYTES_NULL_BULK: &[u8] = b"$-1\r\n";
+const BYTES_EMPTY_ARRAY: &[u8] = b"*0\r\n";
+const BYTES_ZERO: &[u8] = b":0\r\n";
+const STR_ERR_GETKEYS_INVALID: &str = "ERR Invalid arguments specified for command";
+const STR_ERR_GETKEYS_NO_KEY: &str = "ERR The command has no key arguments";
 const STR_REPLY_PONG: &str = "PONG";
+const BYTES_PONG: &[u8] = b"+PONG\r\n";
 
 const BYTES_CRLF: &[u8] = b"\r\n";
 
@@ -278,7 +836,14 @@ const MAX_CYCLE: u8 = 1;
 
 // for front end
 impl Command {
-    pub fn into_cmd(self, notify: Notify) -> Cmd {
+    pub fn into_cmd(self, mut notify: Notify) -> Cmd {
+        // the completions still expected, the subs share the notify of their parent
+        let pending = match self.subs.as_ref() {
+            Some(subs) => subs.iter().filter(|x| !x.borrow().is_replied()).count(),
+            None if self.is_replied() => 0,
+            None => 1,
+        };
+        notify.set_expect(pending);
         Cmd {
             cmd: Rc::new(RefCell::new(self)),
             notify,
@@ -290,59 +855,73 @@ impl Command {
         Ok(msg.map(Into::into))
     }
 
+    /// save the reply as replied by backend.
     pub fn reply_cmd(&self, buf: &mut BytesMut) -> Result<usize, AsError> {
-        if self.ctype.is_mset() {
-            buf.extend_from_slice(BYTES_JUSTOK);
-            Ok(BYTES_JUSTOK.len())
-        } else if self.ctype.is_mget() {
-            if let Some(subs) = self.subs.as_ref() {
-                buf.extend_from_slice(BYTES_ARRAY);
-
-                let begin = buf.len();
-                let len = subs.len();
-                myitoa(len, buf);
-                buf.extend_from_slice(BYTES_CRLF);
+        self.reply_cmd_as(RespVersion::Resp3, buf)
+    }
 
-                for sub in subs {
-                    sub.borrow().reply_raw(buf)?;
-                }
-                Ok(buf.len() - begin)
-            } else {
-                // debug!("subs is empty");
-                buf.extend_from_slice(BYTES_NULL_ARRAY);
-                Ok(BYTES_NULL_ARRAY.len())
-            }
-        } else if self.ctype.is_del() || self.ctype.is_exists() {
-            if let Some(subs) = self.subs.as_ref() {
-                let begin = buf.len();
-                buf.extend_from_slice(BYTES_INTEGER);
-                let mut total = 0usize;
-                for sub in subs {
-                    if let Some(Some(data)) = sub.borrow().reply.as_ref().map(|x| x.nth(0)) {
-                        let ecount = btoi::btoi::<usize>(data).unwrap_or(0);
-                        total += ecount;
-                    }
-                }
-                myitoa(total, buf);
-                buf.extend_from_slice(BYTES_CRLF);
-                Ok(buf.len() - begin)
-            } else {
-                buf.extend_from_slice(BYTES_ZERO_INT);
-                Ok(BYTES_ZERO_INT.len())
+    /// save the reply framed by the protocol version of client, the types of RESP3 are
+    /// downgraded for RESP2.
+    pub fn reply_cmd_as(&self, version: RespVersion, buf: &mut BytesMut) -> Result<usize, AsError> {
+        // the parent is never merged until all its subs are replied
+        if !self.is_done() {
+            return Err(AsError::BadReply);
+        }
+        if self.subs.is_some() && self.is_rejected() {
+            // multi key command rejected by proxy as a whole
+            return self.reply_raw(version, buf);
+        }
+        if let Some(subs) = self.subs.as_ref() {
+            let subs: Vec<_> = subs.iter().map(|x| x.borrow()).collect();
+            let merge = CmdType::get_merge(&self.req);
+            // the subs failed by proxy under sub_failures
+            let absent = match (self.sub_failure, subs.iter().find(|x| x.is_rejected())) {
+                (Some(SubFailure::BestEffort), Some(failed)) => match Message::absent(merge) {
+                    Some(absent) => Some(absent),
+                    None => return failed.reply_raw(version, buf),
+                },
+                (Some(_), Some(failed)) => return failed.reply_raw(version, buf),
+                _ => None,
+            };
+            let replies: Vec<_> = subs
+                .iter()
+                .map(|x| match absent.as_ref() {
+                    Some(absent) if x.is_rejected() => Some(absent),
+                    _ => x.reply.as_ref(),
+                })
+                .collect();
+            if version == RespVersion::Resp2 && replies.iter().flatten().any(|x| x.is_resp3()) {
+                let replies: Vec<_> = replies.iter().map(|x| x.map(Message::to_resp2)).collect();
+                let replies: Vec<_> = replies.iter().map(Option::as_ref).collect();
+                return Message::merge(merge, &replies, buf);
             }
+            Message::merge(merge, &replies, buf)
         } else {
-            self.reply_raw(buf)
+            self.reply_raw(version, buf)
+        }
+    }
+
+    fn reply_raw(&self, version: RespVersion, buf: &mut BytesMut) -> Result<usize, AsError> {
+        let reply = self.reply.as_ref().ok_or(AsError::BadReply)?;
+        match version {
+            RespVersion::Resp2 => Ok(reply.save_resp2(buf)),
+            RespVersion::Resp3 => Ok(reply.save(buf)),
         }
     }
 
-    fn reply_raw(&self, buf: &mut BytesMut) -> Result<usize, AsError> {
-        self.reply.as_ref().map(|x| x.save(buf)).ok_or_else(||AsError::BadReply)
+    /// the protocol version switched to by the HELLO replied without error.
+    fn hello_version(&self) -> Option<RespVersion> {
+        if self.req.nth(COMMAND_POS) != Some(BYTES_CMD_HELLO) || self.is_error() {
+            return None;
+        }
+        self.req.nth(1).and_then(RespVersion::from_arg)
     }
 }
 
-const BYTES_ASK: &[u8] = b"*1\r\n$3\r\nASK\r\n";
+const BYTES_ASKING: &[u8] = b"*1\r\n$6\r\nASKING\r\n";
 
 const BYTES_GET: &[u8] = b"$3\r\nGET\r\n";
+const BYTES_CMD_GET: &[u8] = b"GET";
 const BYTES_LEN2_HEAD: &[u8] = b"*2\r\n";
 const BYTES_LEN3_HEAD: &[u8] = b"*3\r\n";
 
@@ -351,7 +930,7 @@ impl Command {
     /// save redis Command into given BytesMut
     pub fn send_req(&self, buf: &mut BytesMut) -> Result<(), AsError> {
         if self.is_ask() {
-            buf.extend_from_slice(BYTES_ASK);
+            buf.extend_from_slice(BYTES_ASKING);
         }
 
         if self.ctype.is_exists() || self.ctype.is_del() {
@@ -384,46 +963,323 @@ impl Command {
         self.req.save(buf);
         Ok(())
     }
+
+    /// save redis Command into given BytesMut with keys limited into the namespace,
+    /// return true if the reply must be stripped from the namespace.
+    pub fn send_req_with_prefix(&self, buf: &mut BytesMut, prefix: &[u8]) -> bool {
+        if self.ctype.is_ctrl() || self.ctype.is_admin() {
+            self.req.save(buf);
+            return false;
+        }
+
+        let mut args: Vec<&[u8]> = Vec::new();
+        let mut pos = 0;
+        if self.ctype.is_mget() {
+            args.push(BYTES_CMD_GET);
+            pos = 1;
+        }
+        while let Some(arg) = self.req.nth(pos) {
+            args.push(arg);
+            pos += 1;
+        }
+        prefix::save_with_prefix(&args, prefix, buf)
+    }
 }
 
 impl Command {
-    pub fn key_hash<T>(&self, hash_tag: &[u8], method: T) -> u64
+    pub fn key_hash<T>(&self, hash_tag: &[u8], method: T) -> Option<u64>
     where
         T: Fn(&[u8]) -> u64,
     {
         let pos = self.key_pos();
-
-        if let Some(key_data) = self.req.nth(pos) {
-            method(trim_hash_tag(key_data, hash_tag)) as u64
-        } else {
-            // TODO: set bad request error
-            unreachable!()
-        }
+        self.req
+            .nth(pos)
+            .map(|key_data| method(trim_hash_tag(key_data, hash_tag)))
     }
 
-    #[inline(always)]
-    fn key_pos(&self) -> usize {
-        if self.ctype.is_eval() {
-            return KEY_EVAL_POS;
-        }
-        KEY_RAW_POS
+    pub fn has_key_prefix(&self, prefix: &[u8]) -> bool {
+        self.req
+            .nth(self.key_pos())
+            .map(|key| key.starts_with(prefix))
+            .unwrap_or(false)
     }
 
-    pub fn subs(&self) -> Option<Vec<Cmd>> {
-        self.subs.as_ref().cloned()
-    }
+    /// the BY/GET pattern of SORT which may reference the keys on other nodes. The pattern is
+    /// local if it's not a pattern at all (e.g.: BY nosort and GET #), or it has the same hash
+    /// tag as the key to sort.
+    pub fn sort_pattern(&self, hash_tag: &[u8]) -> Option<&[u8]> {
+        let name = self.req.nth(COMMAND_POS)?;
+        if name != BYTES_CMD_SORT && name != BYTES_CMD_SORT_RO {
+            return None;
+        }
+        let key = self.req.nth(KEY_RAW_POS)?;
+        let mut pos = KEY_RAW_POS + 1;
+        while let Some(arg) = self.req.nth(pos) {
+            if arg.eq_ignore_ascii_case(b"LIMIT") {
+                pos += 3;
+            } else if arg.eq_ignore_ascii_case(b"STORE") {
+                pos += 2;
+            } else if arg.eq_ignore_ascii_case(b"BY") || arg.eq_ignore_ascii_case(b"GET") {
+                match self.req.nth(pos + 1) {
+                    Some(pattern) if !is_local_pattern(key, pattern, hash_tag) => {
+                        return Some(pattern)
+                    }
+                    _ => pos += 2,
+                }
+            } else {
+                pos += 1;
+            }
+        }
+        None
+    }
+
+    pub fn is_proxy(&self) -> bool {
+        self.req.nth(COMMAND_POS) == Some(BYTES_CMD_PROXY)
+    }
+
+    /// FUNCTION LOAD and the like, which is broadcast to every backend by function_broadcast.
+    pub fn is_function(&self) -> bool {
+        self.req.nth(COMMAND_POS) == Some(BYTES_CMD_FUNCTION)
+    }
+
+    /// the arguments after PROXY, e.g.: ["ADDNODE", "127.0.0.1:7003", "10"].
+    pub fn proxy_args(&self) -> Option<Vec<String>> {
+        if !self.is_proxy() {
+            return None;
+        }
+        let args = self
+            .req
+            .iter()
+            .skip(COMMAND_POS + 1)
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .collect();
+        Some(args)
+    }
+
+    pub fn is_aster(&self) -> bool {
+        self.req.nth(COMMAND_POS) == Some(BYTES_CMD_ASTER)
+    }
+
+    /// the budget of ASTER DEADLINE, None if it's not an ASTER command.
+    pub fn client_deadline(&self) -> Option<Result<Duration, AsError>> {
+        if !self.is_aster() {
+            return None;
+        }
+        let args: Vec<_> = self.req.iter().skip(COMMAND_POS + 1).collect();
+        Some(deadline::client_deadline(&args))
+    }
+
+    /// INFO is answered by proxy, see proxy::info.
+    pub fn is_info(&self) -> bool {
+        self.req.nth(COMMAND_POS) == Some(BYTES_CMD_INFO)
+    }
+
+    /// the sections after INFO, e.g.: ["clients"].
+    pub fn info_args(&self) -> Option<Vec<String>> {
+        if !self.is_info() {
+            return None;
+        }
+        let args = self
+            .req
+            .iter()
+            .skip(COMMAND_POS + 1)
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .collect();
+        Some(args)
+    }
+
+    pub fn is_client_kill(&self) -> bool {
+        let sub_cmd = match self.req.nth(COMMAND_POS + 1) {
+            Some(sub_cmd) if self.req.nth(COMMAND_POS) == Some(BYTES_CMD_CLIENT) => sub_cmd,
+            _ => return false,
+        };
+        sub_cmd.eq_ignore_ascii_case(BYTES_KILL)
+    }
+
+    /// the filters after CLIENT KILL, e.g.: ["ID", "42"].
+    pub fn client_kill_args(&self) -> Option<Vec<String>> {
+        if !self.is_client_kill() {
+            return None;
+        }
+        let args = self
+            .req
+            .iter()
+            .skip(COMMAND_POS + 2)
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .collect();
+        Some(args)
+    }
+
+    /// the key of CLUSTER KEYSLOT, which is answered by the front of cluster mode by the hash tag
+    /// of routing, error if the arguments are wrong.
+    pub fn keyslot_key(&self) -> Option<Result<Vec<u8>, AsError>> {
+        let sub_cmd = match self.req.nth(COMMAND_POS + 1) {
+            Some(sub_cmd) if self.req.nth(COMMAND_POS) == Some(BYTES_CMD_CLUSTER) => sub_cmd,
+            _ => return None,
+        };
+        if !sub_cmd.eq_ignore_ascii_case(BYTES_KEYSLOT) {
+            return None;
+        }
+        match (self.req.nth(COMMAND_POS + 2), self.req.nth(COMMAND_POS + 3)) {
+            (Some(key), None) => Some(Ok(key.to_vec())),
+            _ => Some(Err(AsError::BadClientCommand(
+                "wrong number of arguments for 'cluster|keyslot' command".to_string(),
+            ))),
+        }
+    }
+
+    /// QUIT, replied by proxy which closes the connection after it.
+    pub fn is_quit(&self) -> bool {
+        self.req.nth(COMMAND_POS) == Some(BYTES_CMD_QUIT)
+    }
+
+    pub fn is_acl(&self) -> bool {
+        let name = self.req.nth(COMMAND_POS);
+        name == Some(BYTES_CMD_AUTH) || name == Some(BYTES_CMD_ACL)
+    }
+
+    /// the arguments of AUTH or ACL with the command name, e.g.: ["AUTH", "app", "secret"].
+    pub fn acl_args(&self) -> Option<Vec<String>> {
+        if !self.is_acl() {
+            return None;
+        }
+        let args = self
+            .req
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .collect();
+        Some(args)
+    }
+
+    /// the category of command checked against the users of proxy.
+    pub fn acl_category(&self) -> Category {
+        let name = match self.req.nth(COMMAND_POS) {
+            Some(name) => name,
+            None => return Category::Connection,
+        };
+        if name == BYTES_CMD_AUTH || name == BYTES_CMD_QUIT {
+            Category::Auth
+        } else if self.dangerous_name().is_some() {
+            Category::Dangerous
+        } else if self.ctype.is_admin() || self.is_proxy() || self.is_client_kill() {
+            Category::Admin
+        } else if self.is_mutation() {
+            Category::Write
+        } else if self.is_read() {
+            Category::Read
+        } else {
+            Category::Connection
+        }
+    }
+
+    /// every key checked against the key patterns of users, which are the keys of routing, the
+    /// destination of two-key command and all the keys of blocking one, e.g.: BLPOP a b 0.
+    pub fn acl_keys(&self) -> Vec<&[u8]> {
+        let mut keys = self.keys();
+        let flags = self
+            .req
+            .nth(COMMAND_POS)
+            .map(CommandFlags::of)
+            .unwrap_or_else(CommandFlags::empty);
+        if keys.is_empty() {
+            return keys;
+        }
+        if flags.contains(CommandFlags::TWO_KEYS) {
+            keys.extend(self.req.nth(KEY_RAW_POS + 1));
+        } else if flags.contains(CommandFlags::BLOCKING) {
+            // the last argument is the timeout
+            let count = self.req.args_len();
+            keys = (KEY_RAW_POS..count.saturating_sub(1))
+                .filter_map(|i| self.req.nth(i))
+                .collect();
+        }
+        keys
+    }
+
+    /// the keys which the command is routed by, each key of multi-key command is routed by
+    /// its own sub command and EVAL is routed by the first of its keys.
+    pub fn keys(&self) -> Vec<&[u8]> {
+        let ctype = self.ctype;
+        if ctype.is_ctrl() || ctype.is_not_support() || ctype.is_admin() || self.is_keyless() {
+            return Vec::new();
+        }
+        let args = (KEY_RAW_POS..).map_while(|i| self.req.nth(i));
+        if ctype.is_mget() || ctype.is_del() || ctype.is_exists() {
+            args.collect()
+        } else if ctype.is_mset() {
+            args.step_by(2).collect()
+        } else if ctype.is_eval() {
+            let count = self
+                .req
+                .nth(KEY_EVAL_POS - 1)
+                .and_then(|x| btoi::btoi::<usize>(x).ok())
+                .unwrap_or(0);
+            (KEY_EVAL_POS..KEY_EVAL_POS + count)
+                .map_while(|i| self.req.nth(i))
+                .collect()
+        } else {
+            self.req.nth(self.key_pos()).into_iter().collect()
+        }
+    }
+
+    fn is_keyless(&self) -> bool {
+        self.req.nth(self.key_pos()).is_none()
+    }
+
+    #[inline(always)]
+    fn key_pos(&self) -> usize {
+        if self.ctype.is_eval() {
+            return KEY_EVAL_POS;
+        }
+        KEY_RAW_POS
+    }
+
+    pub fn subs(&self) -> Option<Vec<Cmd>> {
+        self.subs.as_ref().cloned()
+    }
+
+    /// the next wave of subs to dispatch, which bounds the concurrent subs of large command.
+    pub fn next_wave(&mut self, batch: usize) -> Option<Vec<Cmd>> {
+        let subs = self.subs.as_ref()?;
+        let wave = next_wave(subs, &mut self.released, batch, |x| x.borrow().is_done())?;
+        // the subs replied by proxy ahead (e.g.: by hooks) are never dispatched
+        Some(wave.into_iter().filter(|x| !x.borrow().is_done()).collect())
+    }
+
+    pub fn has_wave(&self) -> bool {
+        self.subs
+            .as_ref()
+            .map(|x| self.released < x.len())
+            .unwrap_or(false)
+    }
 
+    /// the command is done once it's replied, the parent is done once every sub is replied
+    /// unless it's rejected by proxy as a whole.
     pub fn is_done(&self) -> bool {
-        if self.subs.is_some() {
-            return self
-                .subs
-                .as_ref()
-                .map(|x| x.iter().all(|y| y.borrow().is_done()))
-                .unwrap_or(false);
+        match self.subs.as_ref() {
+            Some(_) if self.is_rejected() => true,
+            Some(subs) => subs.iter().all(|x| x.borrow().is_done()),
+            None => self.is_replied(),
         }
+    }
+
+    fn is_replied(&self) -> bool {
         self.flags & CmdFlags::DONE == CmdFlags::DONE
     }
 
+    // the parent replied by the error of proxy, whose subs are never replied.
+    fn is_rejected(&self) -> bool {
+        self.is_replied() && self.flags & CmdFlags::ERROR == CmdFlags::ERROR
+    }
+
+    /// the approximate bytes of reply sent to client, which is the raw replies of backend.
+    pub fn reply_size(&self) -> usize {
+        match self.subs.as_ref() {
+            Some(subs) => subs.iter().map(|x| x.borrow().reply_size()).sum(),
+            None => self.reply.as_ref().map(|x| x.data.len()).unwrap_or(0),
+        }
+    }
+
     fn set_reply<T: IntoReply<Message>>(&mut self, reply: T) {
         self.reply = Some(reply.into_reply());
         self.set_done();
@@ -447,6 +1303,25 @@ impl Command {
         self.flags |= CmdFlags::ERROR;
     }
 
+    fn set_error_by(&mut self, err: &AsError) {
+        let reply: Message = err.into_reply();
+        self.set_reply(reply);
+        self.set_error();
+
+        // only known command is labeled to bound the cardinality of metrics
+        let name = self
+            .req
+            .nth(COMMAND_POS)
+            .filter(|x| CMD_TYPE.contains_key(*x))
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .unwrap_or("UNKNOWN");
+        error_type_incr(name, err);
+    }
+
+    pub fn req_data(&self) -> Bytes {
+        self.req.data.clone()
+    }
+
     pub fn cycle(&self) -> u8 {
         self.cycle
     }
@@ -459,6 +1334,14 @@ impl Command {
         self.cycle += 1;
     }
 
+    pub fn mark_retry(&mut self) -> bool {
+        if !self.can_cycle() || self.flags & CmdFlags::RETRY == CmdFlags::RETRY {
+            return false;
+        }
+        self.flags |= CmdFlags::RETRY;
+        true
+    }
+
     pub fn is_ask(&self) -> bool {
         self.flags & CmdFlags::ASK == CmdFlags::ASK
     }
@@ -483,6 +1366,14 @@ impl Command {
         self.flags &= !CmdFlags::MOVED;
     }
 
+    pub fn is_read_master(&self) -> bool {
+        self.flags & CmdFlags::MASTER == CmdFlags::MASTER
+    }
+
+    pub fn set_read_master(&mut self) {
+        self.flags |= CmdFlags::MASTER;
+    }
+
     pub fn is_error(&self) -> bool {
         if self.subs.is_some() {
             return self
@@ -494,13 +1385,178 @@ impl Command {
         self.flags & CmdFlags::ERROR == CmdFlags::ERROR
     }
 
+    pub fn is_mutation(&self) -> bool {
+        self.ctype.is_mutation() && !self.is_readonly_script()
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.ctype.is_write() || self.ctype.is_mset() || self.ctype.is_del()
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.ctype.is_admin()
+    }
+
     pub fn is_read(&self) -> bool {
-        self.ctype.is_read()
+        self.ctype.is_read() || self.is_readonly_script()
+    }
+
+    // the script declared read only, e.g.: FCALL_RO, which may be served by replica.
+    fn is_readonly_script(&self) -> bool {
+        self.ctype.is_eval()
+            && self
+                .req
+                .nth(COMMAND_POS)
+                .map(|name| CommandFlags::of(name).contains(CommandFlags::READONLY))
+                .unwrap_or(false)
+    }
+
+    // GET or each sub of MGET, whose reply is the bulk of one key.
+    fn is_single_get(&self) -> bool {
+        if self.subs.is_some() {
+            return false;
+        }
+        if self.ctype.is_mget() {
+            return true;
+        }
+        let is_get = self
+            .req
+            .nth(0)
+            .map(|x| x.eq_ignore_ascii_case(BYTES_CMD_GET))
+            .unwrap_or(false);
+        is_get && self.req.nth(1).is_some() && self.req.nth(2).is_none()
+    }
+
+    /// the slot of redis cluster the command is dispatched to.
+    pub fn slot(&self) -> Option<usize> {
+        self.slot
+    }
+
+    /// the name of dangerous command (e.g.: SHUTDOWN) or subcommand (e.g.: CLUSTER RESET), which
+    /// is denied unless given by allow_dangerous.
+    pub fn dangerous_name(&self) -> Option<String> {
+        let name = self.req.nth(COMMAND_POS)?;
+        if CommandFlags::of(name).contains(CommandFlags::DANGEROUS) {
+            if diag::is_harmless(&self.req) {
+                return None;
+            }
+            return Some(String::from_utf8_lossy(name).to_string());
+        }
+        let subs = CMD_DANGEROUS_SUBS.get(name)?;
+        let sub_cmd = self.req.nth(COMMAND_POS + 1)?;
+        if !subs.iter().any(|x| sub_cmd.eq_ignore_ascii_case(x)) {
+            return None;
+        }
+        Some(format!(
+            "{} {}",
+            String::from_utf8_lossy(name),
+            String::from_utf8_lossy(sub_cmd).to_uppercase()
+        ))
+    }
+
+    /// the command is sent without key by the command table, e.g.: PING.
+    pub fn is_keyless_by_table(&self) -> bool {
+        self.req
+            .nth(COMMAND_POS)
+            .map(|name| CommandFlags::of(name).contains(CommandFlags::CTRL))
+            .unwrap_or(false)
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        self.req
+            .nth(COMMAND_POS)
+            .map(|name| CommandFlags::of(name).contains(CommandFlags::BLOCKING))
+            .unwrap_or(false)
+    }
+
+    fn is_two_keys(&self) -> bool {
+        self.req
+            .nth(COMMAND_POS)
+            .map(|name| CommandFlags::of(name).contains(CommandFlags::TWO_KEYS))
+            .unwrap_or(false)
+    }
+
+    /// the source and destination keys of the two-key command (e.g.: SMOVE src dst member,
+    /// LMOVE src dst LEFT RIGHT) must hash the same, since it's routed by the source key only.
+    /// The arguments after them (e.g.: the directions of LMOVE) are never taken as keys. So are
+    /// the keys declared by numkeys of script (e.g.: EVAL and FCALL), routed by the first one.
+    pub fn check_two_keys<F>(&self, hash_tag: &[u8], method: F) -> Result<(), AsError>
+    where
+        F: Fn(&[u8]) -> u64,
+    {
+        if self.ctype.is_eval() {
+            let hashes: HashSet<_> = self
+                .keys()
+                .into_iter()
+                .map(|key| method(trim_hash_tag(key, hash_tag)))
+                .collect();
+            if hashes.len() > 1 {
+                return Err(AsError::CrossSlot);
+            }
+            return Ok(());
+        }
+        if !self.is_two_keys() {
+            return Ok(());
+        }
+        let (src, dst) = match (self.req.nth(KEY_RAW_POS), self.req.nth(KEY_RAW_POS + 1)) {
+            (Some(src), Some(dst)) => (src, dst),
+            // the malformed one is replied by backend
+            _ => return Ok(()),
+        };
+        if method(trim_hash_tag(src, hash_tag)) != method(trim_hash_tag(dst, hash_tag)) {
+            return Err(AsError::CrossSlot);
+        }
+        Ok(())
+    }
+
+    /// cap the timeout (always the last argument) of the blocking command by max seconds, where
+    /// 0 (block forever) is capped too. It's routed by the first key, so all the keys must be
+    /// hashed the same. Return the timeout given by client if it's capped.
+    pub fn bound_timeout<F>(
+        &mut self,
+        hash_tag: &[u8],
+        method: F,
+        max: u64,
+    ) -> Result<Option<Vec<u8>>, AsError>
+    where
+        F: Fn(&[u8]) -> u64,
+    {
+        let count = self.req.args_len();
+        if !self.is_blocking() || count < 3 {
+            // the malformed one is replied by backend
+            return Ok(None);
+        }
+        let last = count - 1;
+        let positions: Vec<usize> = if self.is_two_keys() {
+            vec![KEY_RAW_POS, KEY_RAW_POS + 1]
+        } else {
+            (KEY_RAW_POS..last).collect()
+        };
+        let hashes: HashSet<_> = positions
+            .into_iter()
+            .filter_map(|i| self.req.nth(i))
+            .map(|key| method(trim_hash_tag(key, hash_tag)))
+            .collect();
+        if hashes.len() > 1 {
+            return Err(AsError::CrossSlot);
+        }
+
+        let timeout = self.req.nth(last).unwrap_or_default().to_vec();
+        let capped = std::str::from_utf8(&timeout)
+            .ok()
+            .and_then(|x| x.parse::<f64>().ok())
+            .map(|x| x == 0.0 || x > max as f64)
+            .unwrap_or(false);
+        if !capped {
+            return Ok(None);
+        }
+        self.req.set_nth(last, max.to_string().as_bytes());
+        Ok(Some(timeout))
     }
 }
 
 impl Command {
-    fn mk_mset(flags: CmdFlags, ctype: CmdType, mut notify: Notify, msg: Message) -> Cmd {
+    fn mk_mset(flags: CmdFlags, ctype: CmdType, notify: Notify, msg: Message) -> Cmd {
         let Message { rtype, data } = msg.clone();
         if let RespType::Array(head, array) = rtype {
             let array_len = array.len();
@@ -511,7 +1567,6 @@ impl Command {
             }
 
             let cmd_count = array_len / 2;
-            notify.set_expect((cmd_count + 1) as u16);
             let mut subs = Vec::with_capacity(cmd_count / 2);
 
             for chunk in (&array[1..]).chunks(2) {
@@ -529,10 +1584,20 @@ impl Command {
                     req: sub,
                     reply: None,
                     subs: None,
+                    released: 0,
 
                     total_tracker: None,
 
                     remote_tracker: None,
+                    node: None,
+                    slot: None,
+                    pinned: None,
+                    timeout: None,
+                    time_limit: None,
+                    queued: None,
+                    deadline: None,
+                    trace: 0,
+                    sub_failure: None,
                 };
 
                 subs.push(subcmd.into_cmd(notify.clone()));
@@ -542,12 +1607,22 @@ impl Command {
                 ctype,
                 cycle: DEFAULT_CYCLE,
                 subs: Some(subs),
+                released: 0,
                 req: msg,
                 reply: None,
 
                 total_tracker: None,
 
                 remote_tracker: None,
+                node: None,
+                slot: None,
+                pinned: None,
+                timeout: None,
+                time_limit: None,
+                queued: None,
+                deadline: None,
+                trace: 0,
+                sub_failure: None,
             };
             command.into_cmd(notify)
         } else {
@@ -558,10 +1633,20 @@ impl Command {
                 req: msg,
                 reply: None,
                 subs: None,
+                released: 0,
 
                 total_tracker: None,
 
                 remote_tracker: None,
+                node: None,
+                slot: None,
+                pinned: None,
+                timeout: None,
+                time_limit: None,
+                queued: None,
+                deadline: None,
+                trace: 0,
+                sub_failure: None,
             };
             let cmd = cmd.into_cmd(notify);
             cmd.set_reply(&AsError::RequestInlineWithMultiKeys);
@@ -569,7 +1654,7 @@ impl Command {
         }
     }
 
-    fn mk_subs(flags: CmdFlags, ctype: CmdType, mut notify: Notify, msg: Message) -> Cmd {
+    fn mk_subs(flags: CmdFlags, ctype: CmdType, notify: Notify, msg: Message) -> Cmd {
         let Message { rtype, data } = msg.clone();
         if let RespType::Array(head, array) = rtype {
             let array_len = array.len();
@@ -578,7 +1663,6 @@ impl Command {
             //     unimplemented!();
             // }
 
-            notify.set_expect((array_len - 1 + 1) as u16);
             let mut subs = Vec::with_capacity(array_len - 1);
             for key in &array[1..] {
                 let sub = Message {
@@ -593,10 +1677,20 @@ impl Command {
                     req: sub,
                     reply: None,
                     subs: None,
+                    released: 0,
 
                     total_tracker: None,
 
                     remote_tracker: None,
+                    node: None,
+                    slot: None,
+                    pinned: None,
+                    timeout: None,
+                    time_limit: None,
+                    queued: None,
+                    deadline: None,
+                    trace: 0,
+                    sub_failure: None,
                 };
 
                 subs.push(subcmd.into_cmd(notify.clone()));
@@ -609,10 +1703,20 @@ impl Command {
                 req: msg,
                 reply: None,
                 subs: Some(subs),
+                released: 0,
 
                 total_tracker: None,
 
                 remote_tracker: None,
+                node: None,
+                slot: None,
+                pinned: None,
+                timeout: None,
+                time_limit: None,
+                queued: None,
+                deadline: None,
+                trace: 0,
+                sub_failure: None,
             };
             cmd.into_cmd(notify)
         } else {
@@ -623,10 +1727,20 @@ impl Command {
                 req: msg,
                 reply: None,
                 subs: None,
+                released: 0,
 
                 total_tracker: None,
 
                 remote_tracker: None,
+                node: None,
+                slot: None,
+                pinned: None,
+                timeout: None,
+                time_limit: None,
+                queued: None,
+                deadline: None,
+                trace: 0,
+                sub_failure: None,
             };
             let cmd = cmd.into_cmd(notify);
             cmd.set_reply(&AsError::RequestInlineWithMultiKeys);
@@ -635,6 +1749,15 @@ impl Command {
     }
 }
 
+fn is_local_pattern(key: &[u8], pattern: &[u8], hash_tag: &[u8]) -> bool {
+    if !pattern.contains(&b'*') {
+        return true;
+    }
+    // the keys formed by the pattern are hashed by its tag only if '*' is out of the tag
+    let tag = trim_hash_tag(pattern, hash_tag);
+    tag.len() < pattern.len() && !tag.contains(&b'*') && tag == trim_hash_tag(key, hash_tag)
+}
+
 const COMMAND_POS: usize = 0;
 const KEY_EVAL_POS: usize = 3;
 const KEY_RAW_POS: usize = 1;
@@ -642,9 +1765,15 @@ const KEY_RAW_POS: usize = 1;
 const MAX_KEY_COUNT: usize = 10000;
 
 impl From<MessageMut> for Cmd {
-    fn from(mut msg_mut: MessageMut) -> Cmd {
+    fn from(msg_mut: MessageMut) -> Cmd {
+        Cmd::from_limited(msg_mut, &SubsLimit::default())
+    }
+}
+
+impl Cmd {
+    /// the fan-out command beyond the limit is replied by the error before its subs are made.
+    pub fn from_limited(mut msg_mut: MessageMut, limit: &SubsLimit) -> Cmd {
         let mut notify = Notify::empty();
-        notify.set_expect(1);
         // upper the given command
         if let Some(data) = msg_mut.nth_mut(COMMAND_POS) {
             upper(data);
@@ -659,20 +1788,43 @@ impl From<MessageMut> for Cmd {
                 req: msg,
                 reply: None,
                 subs: None,
+                released: 0,
 
                 total_tracker: None,
 
                 remote_tracker: None,
+                node: None,
+                slot: None,
+                pinned: None,
+                timeout: None,
+                time_limit: None,
+                queued: None,
+                deadline: None,
+                trace: 0,
+                sub_failure: None,
             };
             let cmd: Cmd = command.into_cmd(notify);
             cmd.set_reply(AsError::RequestNotSupport);
             return cmd;
         }
 
+        let args_count = msg_mut.args_count();
         let msg = msg_mut.into();
         let ctype = CmdType::get_cmd_type(&msg);
         let flags = CmdFlags::empty();
 
+        if ctype.is_exists() || ctype.is_del() || ctype.is_mget() || ctype.is_mset() {
+            let count = if ctype.is_mset() {
+                args_count.saturating_sub(1) / 2
+            } else {
+                args_count.saturating_sub(1)
+            };
+            match limit.reserve(count) {
+                Ok(Some(reserved)) => notify.hold(reserved),
+                Ok(None) => {}
+                Err(err) => return new_error_cmd(&err),
+            }
+        }
         if ctype.is_exists() || ctype.is_del() || ctype.is_mget() {
             return Command::mk_subs(flags, ctype, notify, msg);
         } else if ctype.is_mset() {
@@ -686,18 +1838,56 @@ impl From<MessageMut> for Cmd {
             req: msg.clone(),
             reply: None,
             subs: None,
+            released: 0,
 
             total_tracker: None,
 
             remote_tracker: None,
+            node: None,
+            slot: None,
+            pinned: None,
+            timeout: None,
+            time_limit: None,
+            queued: None,
+            deadline: None,
+            trace: 0,
+            sub_failure: None,
         };
-        if ctype.is_ctrl() {
+        if !ctype.is_ctrl() && !ctype.is_not_support() && !ctype.is_admin() && cmd.is_keyless() {
+            // key command without key must never be dispatched to backend
+            cmd.set_reply(AsError::BadReqeust);
+            cmd.set_error();
+        }
+        if let Some(reply) = help::reply(&msg).or_else(|| diag::reply(&msg)) {
+            cmd.set_reply(reply);
+            cmd.unset_error();
+        } else if ctype.is_ctrl() {
             if let Some(data) = msg.nth(COMMAND_POS) {
                 if data == BYTES_CMD_PING {
                     cmd.set_reply(STR_REPLY_PONG);
                     cmd.unset_error();
+                } else if data == BYTES_CMD_HELLO {
+                    match build_hello_reply(&msg) {
+                        Ok(reply) => {
+                            cmd.set_reply(reply);
+                            cmd.unset_error();
+                        }
+                        Err(err) => {
+                            cmd.set_reply(err);
+                            cmd.set_error();
+                        }
+                    }
                 } else if data == BYTES_CMD_COMMAND {
-                    cmd.set_reply(BYTES_REPLY_NULL_ARRAY);
+                    let sub = msg.nth(1).map(|x| x.to_ascii_uppercase());
+                    if sub.as_ref().map(|x| &x[..]) == Some(BYTES_CMD_GETKEYS) {
+                        cmd.set_reply(build_getkeys_reply(&msg));
+                    } else if sub.as_ref().map(|x| &x[..]) == Some(BYTES_CMD_INFO) {
+                        cmd.set_reply(build_command_info_reply(&msg));
+                    } else if sub.as_ref().map(|x| &x[..]) == Some(BYTES_CMD_COUNT) {
+                        cmd.set_reply(supported_commands().len());
+                    } else {
+                        cmd.set_reply(BYTES_NULL_ARRAY);
+                    }
                     cmd.unset_error();
                 } else {
                     // unsupport commands
@@ -708,14 +1898,111 @@ impl From<MessageMut> for Cmd {
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-pub struct RedisHandleCodec {}
+/// the protocol version of client connection, switched by `HELLO 2` and `HELLO 3`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RespVersion {
+    Resp2,
+    Resp3,
+}
+
+impl Default for RespVersion {
+    fn default() -> RespVersion {
+        RespVersion::Resp2
+    }
+}
+
+impl RespVersion {
+    fn from_arg(arg: &[u8]) -> Option<RespVersion> {
+        match arg {
+            b"2" => Some(RespVersion::Resp2),
+            b"3" => Some(RespVersion::Resp3),
+            _ => None,
+        }
+    }
+
+    fn number(self) -> usize {
+        match self {
+            RespVersion::Resp2 => 2,
+            RespVersion::Resp3 => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RedisHandleCodec {
+    // the protocol error of client, no more requests are decoded once it's replied
+    error: Option<String>,
+    args_limit: ArgsLimit,
+    value_limit: ValueLimit,
+    subs_limit: SubsLimit,
+    // the protocol version of client, the replies are framed by it
+    proto: RespVersion,
+    // the shims of replies for the legacy clients, by compat
+    shims: Shims,
+    // the writes without TTL are rejected by ttl_policies
+    ttls: TtlPolicies,
+}
+
+impl RedisHandleCodec {
+    pub fn args_limit(mut self, args_limit: ArgsLimit) -> Self {
+        self.args_limit = args_limit;
+        self
+    }
+
+    pub fn value_limit(mut self, value_limit: ValueLimit) -> Self {
+        self.value_limit = value_limit;
+        self
+    }
+
+    pub fn subs_limit(mut self, subs_limit: SubsLimit) -> Self {
+        self.subs_limit = subs_limit;
+        self
+    }
+
+    pub fn shims(mut self, shims: Shims) -> Self {
+        self.shims = shims;
+        self
+    }
+
+    pub fn ttl_policies(mut self, ttls: TtlPolicies) -> Self {
+        self.ttls = ttls;
+        self
+    }
+}
 
 impl Decoder for RedisHandleCodec {
     type Item = Cmd;
     type Error = AsError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Command::parse_cmd(src)
+        if let Some(reason) = self.error.as_ref() {
+            return Err(AsError::ProtocolError(reason.clone()));
+        }
+        match MessageMut::parse_request(src) {
+            Ok(Some(msg)) => match self
+                .args_limit
+                .check(msg.args_count())
+                .and_then(|_| self.value_limit.check(msg.max_arg_size()))
+            {
+                Ok(()) => {
+                    let cmd = Cmd::from_limited(msg, &self.subs_limit);
+                    let checked = self.ttls.check(&cmd.borrow().req);
+                    match checked {
+                        Ok(()) => Ok(Some(cmd)),
+                        Err(err) => Ok(Some(new_error_cmd(&err))),
+                    }
+                }
+                // the command is consumed, so the following ones are still decoded
+                Err(err) => Ok(Some(new_error_cmd(&err))),
+            },
+            Ok(None) => Ok(None),
+            Err(AsError::ProtocolError(reason)) => {
+                // the requests decoded before are still replied ahead of it
+                let cmd = new_error_cmd(&AsError::ProtocolError(reason.clone()));
+                self.error = Some(reason);
+                Ok(Some(cmd))
+            }
+            Err(err) => Err(err),
+        }
     }
 }
 
@@ -723,27 +2010,196 @@ impl Encoder for RedisHandleCodec {
     type Item = Cmd;
     type Error = AsError;
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let _ = item.borrow().reply_cmd(dst)?;
+        let cmd = item.borrow();
+        // the replies are encoded in order, so the ones ahead of HELLO keep the former version
+        if let Some(version) = cmd.hello_version() {
+            self.proto = version;
+        }
+        if self.shims.is_empty() {
+            let _ = cmd.reply_cmd_as(self.proto, dst)?;
+            return Ok(());
+        }
+        let mut buf = BytesMut::new();
+        let _ = cmd.reply_cmd_as(self.shims.version(self.proto), &mut buf)?;
+        self.shims.rewrite(buf, dst);
         Ok(())
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-pub struct RedisNodeCodec {}
+/// max bytes of each reply read from backend by max_reply_size, which is overridden for the
+/// commands given by max_reply_size_overrides, e.g.: "LRANGE 1073741824". 0 means no limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplyLimits {
+    default: usize,
+    // upper case command and its limit
+    overrides: HashMap<Vec<u8>, usize>,
+}
+
+impl ReplyLimits {
+    pub fn new(default: usize, overrides: &[String]) -> Result<ReplyLimits, AsError> {
+        let mut parsed = HashMap::new();
+        for line in overrides {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let limit = match fields.as_slice() {
+                [_, limit] => limit.parse::<usize>().ok(),
+                _ => None,
+            };
+            let limit = limit.ok_or_else(|| {
+                AsError::BadConfig(format!(
+                    "max_reply_size_overrides: {} must be \"${{command}} ${{bytes}}\"",
+                    line
+                ))
+            })?;
+            parsed.insert(fields[0].to_uppercase().into_bytes(), limit);
+        }
+        Ok(ReplyLimits {
+            default,
+            overrides: parsed,
+        })
+    }
+
+    pub fn from_config(cc: &ClusterConfig) -> Result<ReplyLimits, AsError> {
+        ReplyLimits::new(cc.max_reply_size.unwrap_or(0), &cc.max_reply_size_overrides)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default == 0 && self.overrides.is_empty()
+    }
+
+    fn limit(&self, name: &[u8]) -> usize {
+        if self.overrides.is_empty() {
+            return self.default;
+        }
+        *self
+            .overrides
+            .get(&name.to_ascii_uppercase())
+            .unwrap_or(&self.default)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RedisNodeCodec {
+    prefix: Option<Bytes>,
+    // whether the replies of the sent requests must be stripped from prefix
+    strips: VecDeque<bool>,
+    limits: ReplyLimits,
+    // the reply limit of each request sent, only if any limit is set
+    pending: VecDeque<usize>,
+    // the backend speaks RESP3 once HELLO 3 is replied, see dialect
+    protocol: RespVersion,
+    handshake: Handshake,
+    // the TTL of writes capped or given by ttl_policies
+    ttls: TtlPolicies,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Handshake {
+    Idle,
+    // HELLO 3 is sent ahead of the first command, and its reply is never passed on
+    Sent,
+    Done,
+}
+
+impl Default for Handshake {
+    fn default() -> Handshake {
+        Handshake::Idle
+    }
+}
+
+impl RedisNodeCodec {
+    pub fn with_prefix(prefix: Option<&str>) -> RedisNodeCodec {
+        RedisNodeCodec {
+            prefix: prefix
+                .filter(|x| !x.is_empty())
+                .map(|x| Bytes::from(x.as_bytes())),
+            ..Default::default()
+        }
+    }
+
+    pub fn reply_limits(mut self, limits: ReplyLimits) -> RedisNodeCodec {
+        self.limits = limits;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: RespVersion) -> RedisNodeCodec {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn ttl_policies(mut self, ttls: TtlPolicies) -> RedisNodeCodec {
+        self.ttls = ttls;
+        self
+    }
+
+    // the reply of HELLO 3, the backend which can't speak RESP3 is taken as violating the
+    // framing, and its connection is closed.
+    fn decode_hello(&mut self, src: &mut BytesMut) -> Result<bool, AsError> {
+        let hello = match MessageMut::parse_reply(src)? {
+            Some(hello) => hello,
+            None => return Ok(false),
+        };
+        if let RespType::Error(_) = hello.rtype {
+            return Err(AsError::ReplyProtocolError(
+                "HELLO 3 of resp3 backend is refused".to_string(),
+            ));
+        }
+        self.handshake = Handshake::Done;
+        Ok(true)
+    }
+}
 
 impl Decoder for RedisNodeCodec {
     type Item = Message;
     type Error = AsError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let reply = MessageMut::parse(src)?;
-        Ok(reply.map(Into::into))
+        if self.handshake == Handshake::Sent && !self.decode_hello(src)? {
+            return Ok(None);
+        }
+        let limit = self.pending.front().cloned().unwrap_or(0);
+        let reply = match MessageMut::parse_reply(src)? {
+            Some(reply) => reply,
+            // checked as the reply is read, the rest of it can't be skipped without reading
+            None if limit > 0 && src.len() > limit => return Err(AsError::ReplyTooLarge(limit)),
+            None => return Ok(None),
+        };
+        self.pending.pop_front();
+        if limit > 0 && reply.data.len() > limit {
+            return Err(AsError::ReplyTooLarge(limit));
+        }
+        let reply: Message = reply.into();
+        match self.prefix.as_ref() {
+            Some(prefix) if self.strips.pop_front().unwrap_or(false) => {
+                Ok(Some(prefix::strip_scan_reply(reply, prefix)))
+            }
+            _ => Ok(Some(reply)),
+        }
     }
 }
 
 impl Encoder for RedisNodeCodec {
     type Item = Cmd;
     type Error = AsError;
-    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+    fn encode(&mut self, mut item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if !self.ttls.is_empty() {
+            let enforced = self.ttls.enforce(&item.borrow().req, SystemTime::now());
+            if let Some(req) = enforced {
+                item.rewrite(|x| *x = req);
+            }
+        }
+        if self.protocol == RespVersion::Resp3 && self.handshake == Handshake::Idle {
+            dst.extend_from_slice(BYTES_HELLO_3);
+            self.handshake = Handshake::Sent;
+        }
+        if !self.limits.is_empty() {
+            let cmd = item.borrow();
+            let name = cmd.req.nth(COMMAND_POS).unwrap_or_default();
+            self.pending.push_back(self.limits.limit(name));
+        }
+        if let Some(prefix) = self.prefix.as_ref() {
+            let strip = item.borrow().send_req_with_prefix(dst, prefix);
+            self.strips.push_back(strip);
+            return Ok(());
+        }
         item.borrow().send_req(dst)
     }
 }
@@ -751,8 +2207,7 @@ impl Encoder for RedisNodeCodec {
 pub fn new_read_only_cmd() -> Cmd {
     let msg = Message::new_read_only();
     let flags = CmdFlags::empty();
-    let mut notify = Notify::empty();
-    notify.set_expect(1);
+    let notify = Notify::empty();
     let ctype = CmdType::get_cmd_type(&msg);
 
     let cmd = Command {
@@ -762,20 +2217,65 @@ pub fn new_read_only_cmd() -> Cmd {
         req: msg,
         reply: None,
         subs: None,
+        released: 0,
 
         total_tracker: None,
 
         remote_tracker: None,
+        node: None,
+        slot: None,
+        pinned: None,
+        timeout: None,
+        time_limit: None,
+        queued: None,
+        deadline: None,
+        trace: 0,
+        sub_failure: None,
     };
     cmd.into_cmd(notify)
 }
 
-pub fn new_cluster_slots_cmd() -> Cmd {
-    let msg = Message::new_cluster_slots();
-    let flags = CmdFlags::empty();
-    let mut notify = Notify::empty();
-    notify.set_expect(1);
-    let ctype = CmdType::get_cmd_type(&msg);
+/// the command replied by the error without any request, e.g.: the malformed one.
+pub fn new_error_cmd(err: &AsError) -> Cmd {
+    let notify = Notify::empty();
+    let mut cmd = Command {
+        flags: CmdFlags::empty(),
+        ctype: CmdType::NotSupport,
+        cycle: DEFAULT_CYCLE,
+        req: Message::inline_raw(Bytes::new()),
+        reply: None,
+        subs: None,
+        released: 0,
+
+        total_tracker: None,
+
+        remote_tracker: None,
+        node: None,
+        slot: None,
+        pinned: None,
+        timeout: None,
+        time_limit: None,
+        queued: None,
+        deadline: None,
+        trace: 0,
+        sub_failure: None,
+    };
+    cmd.set_error_by(err);
+    cmd.into_cmd(notify)
+}
+
+pub fn new_cluster_slots_cmd() -> Cmd {
+    new_topology_cmd(Message::new_cluster_slots())
+}
+
+pub fn new_cluster_nodes_cmd() -> Cmd {
+    new_topology_cmd(Message::from_args(&["CLUSTER", "NODES"]))
+}
+
+fn new_topology_cmd(msg: Message) -> Cmd {
+    let flags = CmdFlags::empty();
+    let notify = Notify::empty();
+    let ctype = CmdType::get_cmd_type(&msg);
 
     let cmd = Command {
         flags,
@@ -784,10 +2284,20 @@ pub fn new_cluster_slots_cmd() -> Cmd {
         req: msg,
         reply: None,
         subs: None,
+        released: 0,
 
         total_tracker: None,
 
         remote_tracker: None,
+        node: None,
+        slot: None,
+        pinned: None,
+        timeout: None,
+        time_limit: None,
+        queued: None,
+        deadline: None,
+        trace: 0,
+        sub_failure: None,
     };
     cmd.into_cmd(notify)
 }
@@ -915,6 +2425,220 @@ impl IntoReply<Message> for usize {
     }
 }
 
+impl IntoReply<Message> for AclReply {
+    fn into_reply(self) -> Message {
+        match self {
+            AclReply::Ok => "OK".into_reply(),
+            AclReply::User(name) => {
+                let mut buf = BytesMut::new();
+                prefix::save_bulk(&[name.as_bytes()], &mut buf);
+                MessageMut::parse(&mut buf)
+                    .ok()
+                    .and_then(|x| x)
+                    .map(Into::into)
+                    .unwrap_or_else(|| AsError::BadReply.into_reply())
+            }
+            AclReply::Rules(rules) => Message::from_args(&rules),
+        }
+    }
+}
+
+impl IntoReply<Message> for Tombstone {
+    fn into_reply(self) -> Message {
+        let data: &[u8] = match self {
+            Tombstone::Nil => BYTES_NULL_BULK,
+            Tombstone::Empty => BYTES_EMPTY_ARRAY,
+            Tombstone::Zero => BYTES_ZERO,
+        };
+        MessageMut::parse(&mut BytesMut::from(data))
+            .ok()
+            .and_then(|x| x)
+            .map(Into::into)
+            .unwrap_or_else(|| AsError::BadReply.into_reply())
+    }
+}
+
+impl ReplyMerge for Message {
+    fn is_error_reply(&self) -> bool {
+        matches!(self.rtype, RespType::Error(_))
+    }
+
+    fn merge(
+        merge: Merge,
+        replies: &[Option<&Message>],
+        buf: &mut BytesMut,
+    ) -> Result<usize, AsError> {
+        let replies = all_replied(replies)?;
+        // the error of one key is carried as the element of array, otherwise the first error
+        // is replied as a whole
+        if merge != Merge::ConcatArray {
+            if let Some(err) = first_error(&replies) {
+                return Ok(err.save(buf));
+            }
+        }
+        let begin = buf.len();
+        match merge {
+            Merge::ConcatArray if replies.is_empty() => buf.extend_from_slice(BYTES_NULL_ARRAY),
+            Merge::ConcatArray => {
+                buf.extend_from_slice(BYTES_ARRAY);
+                myitoa(replies.len(), buf);
+                buf.extend_from_slice(BYTES_CRLF);
+                for reply in replies {
+                    reply.save(buf);
+                }
+            }
+            Merge::SumIntegers => {
+                let total: usize = replies
+                    .iter()
+                    .filter_map(|x| x.nth(0))
+                    .map(|x| btoi::btoi::<usize>(x).unwrap_or(0))
+                    .sum();
+                buf.extend_from_slice(BYTES_INTEGER);
+                myitoa(total, buf);
+                buf.extend_from_slice(BYTES_CRLF);
+            }
+            Merge::AllOk => buf.extend_from_slice(BYTES_JUSTOK),
+            Merge::FirstError => match replies.first() {
+                Some(reply) => {
+                    reply.save(buf);
+                }
+                None => buf.extend_from_slice(BYTES_JUSTOK),
+            },
+        }
+        Ok(buf.len() - begin)
+    }
+
+    fn absent(merge: Merge) -> Option<Message> {
+        match merge {
+            Merge::ConcatArray => Some(Tombstone::Nil.into_reply()),
+            Merge::SumIntegers => Some(Tombstone::Zero.into_reply()),
+            Merge::AllOk | Merge::FirstError => None,
+        }
+    }
+}
+
+// COMMAND GETKEYS is answered locally by the same key extraction of routing
+fn build_getkeys_reply(msg: &Message) -> Message {
+    let args: Vec<&[u8]> = (2..).map_while(|i| msg.nth(i)).collect();
+    if args.is_empty() {
+        return Message::plain(STR_ERR_GETKEYS_INVALID, RESP_ERROR);
+    }
+    let mut req = BytesMut::new();
+    prefix::save_array_head(args.len(), &mut req);
+    for arg in args {
+        prefix::save_bulk(&[arg], &mut req);
+    }
+    let inner: Cmd = match MessageMut::parse(&mut req) {
+        Ok(Some(msg)) => msg.into(),
+        _ => return Message::plain(STR_ERR_GETKEYS_INVALID, RESP_ERROR),
+    };
+    let inner = inner.borrow();
+    if inner.is_error() {
+        return Message::plain(STR_ERR_GETKEYS_INVALID, RESP_ERROR);
+    }
+    let keys = inner.keys();
+    if keys.is_empty() {
+        return Message::plain(STR_ERR_GETKEYS_NO_KEY, RESP_ERROR);
+    }
+
+    let mut buf = BytesMut::new();
+    prefix::save_array_head(keys.len(), &mut buf);
+    for key in keys {
+        prefix::save_bulk(&[key], &mut buf);
+    }
+    MessageMut::parse(&mut buf)
+        .ok()
+        .and_then(|x| x)
+        .map(Into::into)
+        .unwrap_or_else(|| Message::plain(STR_ERR_GETKEYS_INVALID, RESP_ERROR))
+}
+
+// names of the commands proxied or answered by proxy, in order.
+fn supported_commands() -> Vec<Vec<u8>> {
+    let mut names: Vec<_> = CMD_TYPE
+        .iter()
+        .filter(|(_, ctype)| !ctype.is_not_support())
+        .map(|(name, _)| name.to_vec())
+        .collect();
+    names.sort();
+    names
+}
+
+// COMMAND INFO is answered locally by the classification table, so the key spec is the same
+// as routing, and the commands not supported by proxy are replied with nil. All the supported
+// commands are replied if no name is given.
+fn build_command_info_reply(msg: &Message) -> Message {
+    let mut names: Vec<Vec<u8>> = (2..)
+        .map_while(|i| msg.nth(i))
+        .map(|x| x.to_ascii_uppercase())
+        .collect();
+    if names.is_empty() {
+        names = supported_commands();
+    }
+
+    let mut buf = BytesMut::new();
+    prefix::save_array_head(names.len(), &mut buf);
+    for name in names {
+        let ctype = match CMD_TYPE.get(&name[..]) {
+            Some(ctype) if !ctype.is_not_support() => *ctype,
+            _ => {
+                buf.extend_from_slice(BYTES_NULL_BULK);
+                continue;
+            }
+        };
+        let (first, last, step) = ctype.key_spec();
+        let flags = CommandFlags::of(&name).names();
+        prefix::save_array_head(6, &mut buf);
+        prefix::save_bulk(&[&name.to_ascii_lowercase()], &mut buf);
+        buf.extend_from_slice(format!(":{}\r\n", ctype.arity()).as_bytes());
+        prefix::save_array_head(flags.len(), &mut buf);
+        for flag in flags {
+            buf.extend_from_slice(format!("+{}\r\n", flag).as_bytes());
+        }
+        for pos in &[first, last, step] {
+            buf.extend_from_slice(format!(":{}\r\n", pos).as_bytes());
+        }
+    }
+    MessageMut::parse(&mut buf)
+        .ok()
+        .and_then(|x| x)
+        .map(Into::into)
+        .unwrap_or_else(|| Message::plain(STR_ERR_GETKEYS_INVALID, RESP_ERROR))
+}
+
+// the reply of HELLO is always a map of RESP3, which is downgraded by the encoder for RESP2
+fn build_hello_reply(msg: &Message) -> Result<Message, AsError> {
+    let version = match msg.nth(1) {
+        Some(arg) => RespVersion::from_arg(arg).ok_or(AsError::NoProto)?,
+        None => RespVersion::Resp2,
+    };
+    if msg.nth(2).is_some() {
+        return Err(AsError::BadClientCommand(
+            "HELLO with AUTH or SETNAME".to_string(),
+        ));
+    }
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(b"%6\r\n");
+    let fields: &[&[u8]] = &[
+        b"server",
+        b"aster",
+        b"version",
+        crate::ASTER_VERSION.as_bytes(),
+    ];
+    for field in fields {
+        prefix::save_bulk(&[*field], &mut buf);
+    }
+    prefix::save_bulk(&[b"proto"], &mut buf);
+    buf.extend_from_slice(format!(":{}\r\n", version.number()).as_bytes());
+    for field in &[&b"mode"[..], b"standalone", b"role", b"master", b"modules"] {
+        prefix::save_bulk(&[*field], &mut buf);
+    }
+    buf.extend_from_slice(b"*0\r\n");
+    MessageMut::parse(&mut buf)?
+        .map(Into::into)
+        .ok_or(AsError::BadReply)
+}
+
 fn build_cluster_nodes_reply() -> BytesMut {
     let port = meta::get_port();
     let ip = meta::get_ip();
@@ -934,6 +2658,508 @@ fn build_cluster_slots_reply() -> BytesMut {
     data
 }
 
+#[test]
+fn test_redis_keyless_cmd() {
+    use crate::utils::crc::crc16;
+
+    let mut src = BytesMut::from(&b"*1\r\n$3\r\nGET\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..]);
+    let keyless = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(keyless.borrow().is_done());
+    assert!(keyless.borrow().is_error());
+    assert_eq!(keyless.borrow().key_hash(b"", crc16), None);
+
+    let get = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(!get.borrow().is_done());
+    assert_eq!(get.borrow().key_hash(b"", crc16), Some(crc16(b"a")));
+}
+
+#[test]
+fn test_redis_append_binary_value() {
+    use crate::utils::crc::crc16;
+
+    let data = b"*3\r\n$6\r\nappend\r\n$5\r\nmykey\r\n$6\r\na\r\n$1\r\n\r\n";
+    let mut src = BytesMut::from(&data[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert_eq!(src.len(), 0);
+    assert_eq!(cmd.borrow().ctype, CmdType::Write);
+    assert!(!cmd.borrow().is_done());
+    assert_eq!(cmd.borrow().req.nth(1), Some(&b"mykey"[..]));
+    assert_eq!(cmd.borrow().req.nth(2), Some(&b"a\r\n$1\r\n"[..]));
+    assert_eq!(cmd.borrow().key_hash(b"", crc16), Some(crc16(b"mykey")));
+
+    let mut reply = BytesMut::from(&b":11\r\n"[..]);
+    let msg: Message = MessageMut::parse(&mut reply).unwrap().unwrap().into();
+    cmd.set_reply(msg);
+    let mut buf = BytesMut::new();
+    cmd.borrow().reply_cmd(&mut buf).unwrap();
+    assert_eq!(&buf[..], &b":11\r\n"[..]);
+}
+
+#[test]
+fn test_redis_read_only_reject() {
+    let mut src =
+        BytesMut::from(&b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n"[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(cmd.borrow().is_mutation());
+    for sub in cmd.borrow().subs().unwrap() {
+        sub.set_error(&AsError::ReadOnly);
+    }
+    cmd.set_error(&AsError::ReadOnly);
+    assert!(cmd.borrow().is_done());
+
+    let mut buf = BytesMut::new();
+    cmd.borrow().reply_cmd(&mut buf).unwrap();
+    assert_eq!(&buf[..], &b"-READONLY proxy is in read-only mode\r\n"[..]);
+}
+
+#[test]
+fn test_redis_error_by_type() {
+    use crate::metrics::error_type_get;
+    use std::io::{Error, ErrorKind};
+
+    let errors = vec![
+        (
+            AsError::IoError(Error::new(ErrorKind::TimedOut, "timeout")),
+            "timeout",
+        ),
+        (
+            AsError::BackendClosedError("127.0.0.1:7000".to_string()),
+            "backend_closed",
+        ),
+        (AsError::BadReply, "backend_error"),
+        (AsError::RequestNotSupport, "not_support"),
+        (AsError::RedirectFailError, "redirect"),
+        (AsError::DeadlineExpired, "deadline"),
+        (AsError::ProxyFail, "proxy"),
+    ];
+    for (err, class) in errors {
+        assert_eq!(err.class(), class);
+        let before = error_type_get("PTTL", class);
+        let mut src = BytesMut::from(&b"*2\r\n$4\r\npttl\r\n$1\r\na\r\n"[..]);
+        let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+        cmd.set_error(&err);
+        assert!(cmd.borrow().is_error());
+        assert_eq!(error_type_get("PTTL", class), before + 1);
+    }
+}
+
+#[test]
+fn test_redis_accept_reply() {
+    let parse_reply = |data: &[u8]| Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+    let parse_cmd = |data: &[u8]| {
+        Command::parse_cmd(&mut BytesMut::from(data))
+            .unwrap()
+            .unwrap()
+    };
+    let get = parse_cmd(b"*2\r\n$3\r\nget\r\n$1\r\na\r\n");
+    let set = parse_cmd(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nb\r\n");
+    let spop = parse_cmd(b"*2\r\n$4\r\nSPOP\r\n$1\r\na\r\n");
+
+    let ok = parse_reply(b"+OK\r\n");
+    let bulk = parse_reply(b"$1\r\nb\r\n");
+    let nil = parse_reply(b"$-1\r\n");
+    let array = parse_reply(b"*1\r\n$1\r\nb\r\n");
+    let error = parse_reply(b"-ERR wrong type\r\n");
+    assert!(get.accept_reply(&bulk));
+    assert!(get.accept_reply(&nil));
+    assert!(!get.accept_reply(&ok));
+    assert!(set.accept_reply(&ok));
+    assert!(set.accept_reply(&bulk));
+    assert!(!set.accept_reply(&array));
+    assert!(!set.accept_reply(&parse_reply(b":1\r\n")));
+    // error is always accepted, and undeclared command is never checked
+    assert!(get.accept_reply(&error));
+    assert!(spop.accept_reply(&array));
+    assert!(spop.accept_reply(&bulk));
+}
+
+#[test]
+fn test_redis_bit_cmds() {
+    use crate::utils::crc::crc16;
+
+    let cmds = vec![
+        ("SETBIT", CmdType::Write, &b":0\r\n"[..]),
+        ("GETBIT", CmdType::Read, &b":1\r\n"[..]),
+        ("BITCOUNT", CmdType::Read, &b":1\r\n"[..]),
+        ("BITPOS", CmdType::Read, &b":-1\r\n"[..]),
+        ("BITFIELD", CmdType::Write, &b"*1\r\n:0\r\n"[..]),
+        ("BITFIELD_RO", CmdType::Read, &b"*1\r\n:0\r\n"[..]),
+        ("GETRANGE", CmdType::Read, &b"$0\r\n\r\n"[..]),
+        ("SETRANGE", CmdType::Write, &b":5\r\n"[..]),
+        ("STRLEN", CmdType::Read, &b":5\r\n"[..]),
+    ];
+    for (name, ctype, reply) in cmds {
+        let req = Message::from_args(vec![name, "flags", "0"]);
+        let mut buf = BytesMut::new();
+        req.save(&mut buf);
+        let cmd = Command::parse_cmd(&mut buf).unwrap().unwrap();
+        assert_eq!(cmd.borrow().ctype, ctype, "{}", name);
+        assert_eq!(cmd.borrow().key_hash(b"", crc16), Some(crc16(b"flags")));
+        let reply = Message::parse(&mut BytesMut::from(reply)).unwrap().unwrap();
+        assert!(cmd.accept_reply(&reply), "{}", name);
+        let shifted = Message::parse(&mut BytesMut::from(&b"+OK\r\n"[..]))
+            .unwrap()
+            .unwrap();
+        assert!(!cmd.accept_reply(&shifted), "{}", name);
+    }
+}
+
+#[test]
+fn test_redis_ping_pong() {
+    let replied = |data: &[u8]| {
+        let ping = Cmd::ping_request();
+        assert!(!ping.is_pong());
+        let reply = Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+        ping.set_reply(reply);
+        ping.is_pong()
+    };
+    assert!(replied(b"+PONG\r\n"));
+    assert!(!replied(b"+OK\r\n"));
+    assert!(!replied(b"$4\r\nPONG\r\n"));
+    assert!(!replied(b"-LOADING loading the dataset\r\n"));
+}
+
+#[test]
+fn test_redis_range_cmds() {
+    use crate::utils::crc::crc16;
+
+    let slot_of = |cmd: &Cmd| cmd.borrow().key_hash(b"{}", crc16).unwrap() as usize % SLOTS_COUNT;
+    let mut src = BytesMut::from(
+        &b"*4\r\n$8\r\nGETRANGE\r\n$17\r\n{123456789}:range\r\n$1\r\n0\r\n$2\r\n-1\r\n"[..],
+    );
+    let getrange = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert_eq!(src.len(), 0);
+    assert!(!getrange.borrow().is_done());
+    assert!(getrange.borrow().is_read());
+    assert_eq!(getrange.borrow().keys(), vec![&b"{123456789}:range"[..]]);
+    // the slot of 123456789 by the spec of redis cluster
+    assert_eq!(slot_of(&getrange), 12739);
+
+    // the value looks like the RESP of another argument and has a NUL byte
+    let value = &b"\x00\r\n$2\r\n10\r\n"[..];
+    let setrange = Message::from_args(vec![&b"SETRANGE"[..], b"{123456789}:range", b"10", value]);
+    let mut src = BytesMut::new();
+    setrange.save(&mut src);
+    let setrange = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert_eq!(src.len(), 0);
+    assert!(!setrange.borrow().is_done());
+    assert!(setrange.borrow().is_mutation());
+    assert_eq!(setrange.borrow().keys(), vec![&b"{123456789}:range"[..]]);
+    assert_eq!(setrange.borrow().req.nth(2), Some(&b"10"[..]));
+    assert_eq!(setrange.borrow().req.nth(3), Some(value));
+    assert_eq!(slot_of(&setrange), 12739);
+
+    // the key is never taken from the offsets or the value
+    let mut src = BytesMut::from(&b"*1\r\n$8\r\nSETRANGE\r\n"[..]);
+    let keyless = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(keyless.borrow().is_error());
+    assert!(keyless.borrow().keys().is_empty());
+}
+
+#[test]
+fn test_redis_admin_cmd_gated() {
+    let mut src = BytesMut::from(
+        &b"*1\r\n$8\r\nFAILOVER\r\n*3\r\n$9\r\nreplicaof\r\n$2\r\nNO\r\n$3\r\nONE\r\n"[..],
+    );
+    let failover = Command::parse_cmd(&mut src).unwrap().unwrap();
+    let replicaof = Command::parse_cmd(&mut src).unwrap().unwrap();
+    for cmd in &[&failover, &replicaof] {
+        assert!(cmd.borrow().is_admin());
+        assert!(!cmd.borrow().is_done());
+        assert!(!cmd.borrow().is_mutation());
+    }
+
+    // admin command is forwarded as is even if key_prefix is set
+    let mut codec = RedisNodeCodec::with_prefix(Some("t1:"));
+    let mut dst = BytesMut::new();
+    codec.encode(replicaof, &mut dst).unwrap();
+    assert_eq!(
+        &dst[..],
+        &b"*3\r\n$9\r\nreplicaof\r\n$2\r\nNO\r\n$3\r\nONE\r\n"[..]
+    );
+
+    // denied by the front without touching backend
+    failover.set_error(&AsError::RequestNotSupport);
+    assert!(failover.borrow().is_done());
+}
+
+#[test]
+fn test_redis_fcall_keys() {
+    use crate::utils::crc::crc16;
+
+    let parse = |args: &[&[u8]]| {
+        let mut src = BytesMut::new();
+        prefix::save_array_head(args.len(), &mut src);
+        for arg in args {
+            prefix::save_bulk(&[*arg], &mut src);
+        }
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    };
+    let slot = |x: &[u8]| u64::from(crc16(x)) % 16384;
+
+    let fcall = parse(&[
+        b"FCALL",
+        b"myfunc",
+        b"2",
+        b"{user1}.a",
+        b"{user1}.b",
+        b"arg",
+    ]);
+    let fcall = fcall.borrow();
+    assert_eq!(fcall.ctype, CmdType::Eval);
+    assert_eq!(fcall.keys(), vec![&b"{user1}.a"[..], &b"{user1}.b"[..]]);
+    assert_eq!(
+        fcall.key_hash(b"{}", slot),
+        Some(slot(trim_hash_tag(b"{user1}.a", b"{}")))
+    );
+    assert!(fcall.is_mutation() && !fcall.is_read());
+    assert_eq!(fcall.check_two_keys(b"{}", slot), Ok(()));
+
+    let fcall_ro = parse(&[b"fcall_ro", b"myfunc", b"1", b"a"]);
+    let fcall_ro = fcall_ro.borrow();
+    assert_eq!(fcall_ro.keys(), vec![&b"a"[..]]);
+    assert!(fcall_ro.is_read() && !fcall_ro.is_mutation());
+
+    // the keys declared must hash the same as EVAL
+    for args in &[
+        &[&b"FCALL"[..], b"myfunc", b"2", b"a", b"b"][..],
+        &[
+            &b"FCALL_RO"[..],
+            b"myfunc",
+            b"2",
+            b"{user1}.a",
+            b"{user2}.b",
+        ][..],
+        &[&b"EVAL"[..], b"return 1", b"2", b"a", b"b"][..],
+    ] {
+        let cmd = parse(args);
+        assert_eq!(
+            cmd.borrow().check_two_keys(b"{}", slot),
+            Err(AsError::CrossSlot),
+            "{:?}",
+            args
+        );
+    }
+    // the arguments after the keys are never checked
+    let eval = parse(&[b"EVAL", b"return 1", b"1", b"a", b"b"]);
+    assert_eq!(eval.borrow().check_two_keys(b"{}", slot), Ok(()));
+}
+
+#[test]
+fn test_redis_function_broadcast() {
+    let mut src = BytesMut::from(
+        &b"*3\r\n$8\r\nFUNCTION\r\n$4\r\nLOAD\r\n$5\r\nlua..\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..],
+    );
+    let load = Command::parse_cmd(&mut src).unwrap().unwrap();
+    let get = Command::parse_cmd(&mut src).unwrap().unwrap();
+    // gated as admin, denied unless admin_node or function_broadcast
+    assert!(load.borrow().is_admin());
+    assert!(load.borrow().is_function());
+    assert!(!get.broadcast(&["127.0.0.1:7001".to_string()]));
+    assert!(!load.broadcast(&[]));
+
+    let addrs = vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7002".to_string()];
+    assert!(load.broadcast(&addrs));
+    let subs = load.subs().unwrap();
+    let pinned: Vec<_> = subs.iter().filter_map(|x| x.pinned()).collect();
+    assert_eq!(pinned, addrs);
+    assert!(subs.iter().all(|x| x.req().nth(1) == Some(&b"LOAD"[..])));
+    // broadcast once only
+    assert!(!load.broadcast(&addrs));
+
+    let bulk = |data: &[u8]| -> Message {
+        MessageMut::parse(&mut BytesMut::from(data))
+            .unwrap()
+            .unwrap()
+            .into()
+    };
+    subs[0].set_reply(bulk(b"$5\r\nmylib\r\n"));
+    assert!(!load.borrow().is_done());
+    subs[1].set_reply(bulk(b"$5\r\nmylib\r\n"));
+    assert!(load.borrow().is_done());
+    let mut buf = BytesMut::new();
+    load.borrow().reply_cmd(&mut buf).unwrap();
+    assert_eq!(&buf[..], &b"$5\r\nmylib\r\n"[..]);
+
+    // the first error is replied, e.g.: the library exists on one backend
+    let mut src = BytesMut::from(&b"*2\r\n$8\r\nFUNCTION\r\n$5\r\nFLUSH\r\n"[..]);
+    let flush = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(flush.broadcast(&addrs));
+    let subs = flush.subs().unwrap();
+    subs[0].set_reply(bulk(b"+OK\r\n"));
+    subs[1].set_reply(bulk(b"-ERR busy\r\n"));
+    let mut buf = BytesMut::new();
+    flush.borrow().reply_cmd(&mut buf).unwrap();
+    assert_eq!(&buf[..], &b"-ERR busy\r\n"[..]);
+}
+
+#[test]
+fn test_redis_reply_limits() {
+    assert!(ReplyLimits::new(0, &[]).unwrap().is_empty());
+    assert!(ReplyLimits::new(0, &["LRANGE".to_string()]).is_err());
+    assert!(ReplyLimits::new(0, &["LRANGE x".to_string()]).is_err());
+
+    let limits = ReplyLimits::new(16, &["lrange 64".to_string()]).unwrap();
+    let mut codec = RedisNodeCodec::default().reply_limits(limits);
+    let mut dst = BytesMut::new();
+    for req in &[
+        &b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..],
+        &b"*4\r\n$6\r\nLRANGE\r\n$1\r\nl\r\n$1\r\n0\r\n$2\r\n-1\r\n"[..],
+        &b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n"[..],
+    ] {
+        let cmd = Command::parse_cmd(&mut BytesMut::from(*req))
+            .unwrap()
+            .unwrap();
+        codec.encode(cmd, &mut dst).unwrap();
+    }
+
+    let mut src = BytesMut::from(&b"$3\r\nabc\r\n"[..]);
+    assert!(codec.decode(&mut src).unwrap().is_some());
+    // raised for LRANGE
+    let mut src = BytesMut::from(&b"*2\r\n$10\r\n0123456789\r\n$10\r\n0123456789\r\n"[..]);
+    assert!(codec.decode(&mut src).unwrap().is_some());
+    // failed as soon as the reply read grows beyond the limit, before it's complete
+    let mut src = BytesMut::from(&b"$64\r\n0123456789abcdef"[..]);
+    assert!(matches!(
+        codec.decode(&mut src),
+        Err(AsError::ReplyTooLarge(16))
+    ));
+}
+
+#[test]
+fn test_redis_dangerous_cmd() {
+    let dangerous = |req: &[u8]| {
+        let mut src = BytesMut::from(req);
+        let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+        cmd.dangerous_name()
+    };
+    let cases: &[(&[u8], Option<&str>)] = &[
+        (b"*1\r\n$8\r\nshutdown\r\n", Some("SHUTDOWN")),
+        (b"*2\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n", Some("DEBUG")),
+        (
+            b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n1\r\n",
+            Some("DEBUG"),
+        ),
+        // answered by proxy itself
+        (b"*3\r\n$5\r\nDEBUG\r\n$5\r\nsleep\r\n$1\r\n0\r\n", None),
+        (b"*2\r\n$6\r\nMODULE\r\n$4\r\nLIST\r\n", Some("MODULE")),
+        (b"*1\r\n$8\r\nFAILOVER\r\n", Some("FAILOVER")),
+        (
+            b"*3\r\n$7\r\nSLAVEOF\r\n$2\r\nNO\r\n$3\r\nONE\r\n",
+            Some("SLAVEOF"),
+        ),
+        (
+            b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nreset\r\n",
+            Some("CLUSTER RESET"),
+        ),
+        (
+            b"*3\r\n$7\r\nCLUSTER\r\n$6\r\nFORGET\r\n$1\r\na\r\n",
+            Some("CLUSTER FORGET"),
+        ),
+        (
+            b"*3\r\n$3\r\nACL\r\n$7\r\nSETUSER\r\n$1\r\na\r\n",
+            Some("ACL SETUSER"),
+        ),
+        (
+            b"*4\r\n$6\r\nconfig\r\n$3\r\nset\r\n$1\r\na\r\n$1\r\nb\r\n",
+            Some("CONFIG SET"),
+        ),
+        (b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nNODES\r\n", None),
+        (b"*2\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n", None),
+        (b"*1\r\n$7\r\nWAITAOF\r\n", None),
+        (b"*2\r\n$3\r\nGET\r\n$8\r\nSHUTDOWN\r\n", None),
+    ];
+    for (req, expect) in cases {
+        assert_eq!(dangerous(req).as_deref(), *expect);
+    }
+
+    let mut src = BytesMut::from(&b"*1\r\n$8\r\nSHUTDOWN\r\n"[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+    cmd.set_error(&AsError::Dangerous("SHUTDOWN".to_string()));
+    let mut buf = BytesMut::new();
+    cmd.borrow().reply_cmd(&mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"-ERR dangerous command SHUTDOWN is denied by proxy\r\n"[..]
+    );
+}
+
+#[test]
+fn test_redis_mget_waves() {
+    let count = 10_000;
+    let batch = 512;
+    let mut data = format!("*{}\r\n$4\r\nMGET\r\n", count + 1).into_bytes();
+    for i in 0..count {
+        let key = format!("k{}", i);
+        data.extend_from_slice(format!("${}\r\n{}\r\n", key.len(), key).as_bytes());
+    }
+    let mut src = BytesMut::from(&data[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+
+    let mut released = 0;
+    while cmd.borrow().has_wave() {
+        let wave = cmd.borrow_mut().next_wave(batch).unwrap();
+        assert!(wave.len() <= batch);
+        // the next wave is never released before the former is done
+        assert!(cmd.borrow_mut().next_wave(batch).is_none());
+        assert!(!cmd.borrow().is_done());
+
+        // replied in reversed order inside the wave
+        for sub in wave.iter().rev() {
+            let key = sub.borrow().req.nth(1).unwrap().to_vec();
+            let mut reply = format!("${}\r\n", key.len()).into_bytes();
+            reply.extend_from_slice(&key);
+            reply.extend_from_slice(b"\r\n");
+            let mut reply = BytesMut::from(&reply[..]);
+            let msg: Message = MessageMut::parse(&mut reply).unwrap().unwrap().into();
+            sub.set_reply(msg);
+        }
+        released += wave.len();
+    }
+    assert_eq!(released, count);
+    assert!(cmd.borrow_mut().next_wave(batch).is_none());
+    assert!(cmd.borrow().is_done());
+
+    let mut buf = BytesMut::new();
+    cmd.borrow().reply_cmd(&mut buf).unwrap();
+    let mut expect = format!("*{}\r\n", count).into_bytes();
+    for i in 0..count {
+        let key = format!("k{}", i);
+        expect.extend_from_slice(format!("${}\r\n{}\r\n", key.len(), key).as_bytes());
+    }
+    assert_eq!(&buf[..], &expect[..]);
+    // raw replies of subs without the array head
+    let head = format!("*{}\r\n", count).len();
+    assert_eq!(cmd.borrow().reply_size(), expect.len() - head);
+}
+
+#[test]
+fn test_redis_node_codec_prefix() {
+    let mut codec = RedisNodeCodec::with_prefix(Some("t1:"));
+    let mut src =
+        BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n*2\r\n$4\r\nSCAN\r\n$1\r\n0\r\n"[..]);
+    let get = Command::parse_cmd(&mut src).unwrap().unwrap();
+    let scan = Command::parse_cmd(&mut src).unwrap().unwrap();
+
+    let mut dst = BytesMut::new();
+    codec.encode(get, &mut dst).unwrap();
+    assert_eq!(&dst[..], &b"*2\r\n$3\r\nGET\r\n$4\r\nt1:a\r\n"[..]);
+    dst.clear();
+    codec.encode(scan, &mut dst).unwrap();
+    assert_eq!(
+        &dst[..],
+        &b"*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nMATCH\r\n$4\r\nt1:*\r\n"[..]
+    );
+
+    let mut replies = BytesMut::from(&b"$4\r\nt1:b\r\n*2\r\n$1\r\n0\r\n*1\r\n$4\r\nt1:a\r\n"[..]);
+    let mut buf = BytesMut::new();
+    codec.decode(&mut replies).unwrap().unwrap().save(&mut buf);
+    assert_eq!(&buf[..], &b"$4\r\nt1:b\r\n"[..]);
+    buf.clear();
+    codec.decode(&mut replies).unwrap().unwrap().save(&mut buf);
+    assert_eq!(&buf[..], &b"*2\r\n$1\r\n0\r\n*1\r\n$1\r\na\r\n"[..]);
+}
+
 #[test]
 fn test_redis_parse_wrong_case() {
     use std::fs::{self, File};
@@ -964,3 +3190,1321 @@ fn test_redis_parse_wrong_case() {
         }
     }
 }
+
+#[test]
+fn test_redis_command_getkeys() {
+    use crate::utils::crc::crc16;
+
+    fn parse(args: &[&[u8]]) -> Cmd {
+        let mut src = BytesMut::new();
+        prefix::save_array_head(args.len(), &mut src);
+        for arg in args {
+            prefix::save_bulk(&[*arg], &mut src);
+        }
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    }
+
+    fn getkeys(args: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut req: Vec<&[u8]> = vec![b"command", b"getkeys"];
+        req.extend_from_slice(args);
+        let cmd = parse(&req);
+        assert!(cmd.borrow().is_done());
+        let reply = cmd.borrow().reply.clone().unwrap();
+        (0..)
+            .map_while(|i| reply.nth(i))
+            .map(|x| x.to_vec())
+            .collect()
+    }
+
+    // single key command is routed by its key
+    let args: &[&[u8]] = &[b"set", b"{user}:1", b"v"];
+    let keys = getkeys(args);
+    assert_eq!(keys, vec![b"{user}:1".to_vec()]);
+    assert_eq!(
+        parse(args).borrow().key_hash(b"{}", crc16),
+        Some(crc16(trim_hash_tag(&keys[0], b"{}")))
+    );
+
+    // eval is routed by the first key
+    let args: &[&[u8]] = &[b"EVAL", b"return 1", b"2", b"a", b"b", b"arg"];
+    let keys = getkeys(args);
+    assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(
+        parse(args).borrow().key_hash(b"", crc16),
+        Some(crc16(&keys[0]))
+    );
+
+    // each key of multi-key command is routed by its own sub command
+    for args in &[
+        &[&b"MGET"[..], b"a", b"b", b"c"][..],
+        &[&b"MSET"[..], b"a", b"1", b"b", b"2"][..],
+        &[&b"DEL"[..], b"a", b"b"][..],
+    ] {
+        let keys = getkeys(args);
+        let subs = parse(args).borrow().subs().unwrap();
+        assert_eq!(keys.len(), subs.len());
+        for (key, sub) in keys.iter().zip(subs.iter()) {
+            assert_eq!(sub.borrow().key_hash(b"", crc16), Some(crc16(key)));
+        }
+    }
+
+    // keyless and unknown commands are rejected
+    for args in &[&[&b"PING"[..]][..], &[&b"GET"[..]][..], &[][..]] {
+        let mut req: Vec<&[u8]> = vec![b"COMMAND", b"GETKEYS"];
+        req.extend_from_slice(args);
+        let cmd = parse(&req);
+        let reply = cmd.borrow().reply.clone().unwrap();
+        assert!(reply.raw_data().starts_with(b"-ERR"));
+    }
+}
+
+#[test]
+fn test_redis_command_flags() {
+    use crate::protocol::redis::cmd::CMD_FLAGS;
+
+    let flags = |name: &[u8]| CommandFlags::of(name);
+    let ctype = |name: &[u8]| CmdType::from(CommandFlags::of(name));
+
+    assert_eq!(flags(b"GET"), CommandFlags::READONLY);
+    assert_eq!(flags(b"SET"), CommandFlags::WRITE);
+    assert_eq!(
+        flags(b"FAILOVER"),
+        CommandFlags::ADMIN | CommandFlags::DANGEROUS
+    );
+    assert_eq!(flags(b"PING"), CommandFlags::CTRL);
+    assert!(flags(b"BLPOP").contains(CommandFlags::WRITE | CommandFlags::BLOCKING));
+    assert!(flags(b"WAIT").contains(CommandFlags::BLOCKING | CommandFlags::UNSUPPORTED));
+    assert!(flags(b"SUBSCRIBE").contains(CommandFlags::PUBSUB | CommandFlags::UNSUPPORTED));
+    assert!(flags(b"KEYS").contains(CommandFlags::READONLY | CommandFlags::UNSUPPORTED));
+    assert!(flags(b"NOSUCH").is_empty());
+
+    // CmdType is derived from the flags
+    assert_eq!(ctype(b"GET"), CmdType::Read);
+    assert_eq!(ctype(b"SET"), CmdType::Write);
+    assert_eq!(ctype(b"MGET"), CmdType::MGet);
+    assert_eq!(ctype(b"MSET"), CmdType::MSet);
+    assert_eq!(ctype(b"DEL"), CmdType::Del);
+    assert_eq!(ctype(b"UNLINK"), CmdType::Del);
+    assert_eq!(ctype(b"EXISTS"), CmdType::Exists);
+    assert_eq!(ctype(b"EVAL"), CmdType::Eval);
+    assert_eq!(ctype(b"EVALSHA"), CmdType::NotSupport);
+    assert_eq!(ctype(b"PING"), CmdType::Ctrl);
+    assert_eq!(ctype(b"FAILOVER"), CmdType::Admin);
+    assert_eq!(ctype(b"BLPOP"), CmdType::Write);
+    assert_eq!(ctype(b"WAIT"), CmdType::NotSupport);
+    assert_eq!(ctype(b"NOSUCH"), CmdType::NotSupport);
+    for (name, flags) in CMD_FLAGS.iter() {
+        assert_eq!(CMD_TYPE.get(name), Some(&CmdType::from(*flags)));
+    }
+
+    let mut src = BytesMut::from(&b"*3\r\n$5\r\nBLPOP\r\n$1\r\na\r\n$1\r\n0\r\n"[..]);
+    let blpop = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(blpop.borrow().is_blocking());
+    assert!(blpop.borrow().is_mutation());
+    assert!(!blpop.borrow().is_done());
+    let mut src = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..]);
+    let get = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(!get.borrow().is_blocking());
+    assert!(get.borrow().is_read());
+}
+
+#[test]
+fn test_redis_command_info() {
+    use crate::utils::crc::crc16;
+
+    fn parse(args: &[&[u8]]) -> Cmd {
+        let mut src = BytesMut::new();
+        prefix::save_array_head(args.len(), &mut src);
+        for arg in args {
+            prefix::save_bulk(&[*arg], &mut src);
+        }
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    }
+
+    let info = |name: &[u8]| {
+        let cmd = parse(&[b"command", b"info", name]);
+        assert!(cmd.borrow().is_done());
+        let reply = cmd.borrow().reply.clone().unwrap();
+        reply.raw_data().to_vec()
+    };
+    // the last three lines of the entry are first, last and step of keys
+    let key_spec = |name: &[u8]| -> Vec<i64> {
+        let raw = info(name);
+        let lines: Vec<_> = raw
+            .split(|x| *x == b'\n')
+            .filter(|x| !x.is_empty())
+            .collect();
+        lines[lines.len() - 3..]
+            .iter()
+            .map(|x| btoi::btoi(&x[1..x.len() - 1]).unwrap())
+            .collect()
+    };
+
+    assert_eq!(
+        info(b"get"),
+        b"*1\r\n*6\r\n$3\r\nget\r\n:-2\r\n*1\r\n+readonly\r\n:1\r\n:1\r\n:1\r\n".to_vec()
+    );
+    assert_eq!(
+        info(b"EVAL"),
+        b"*1\r\n*6\r\n$4\r\neval\r\n:-3\r\n*2\r\n+write\r\n+movablekeys\r\n:0\r\n:0\r\n:0\r\n"
+            .to_vec()
+    );
+    assert_eq!(
+        info(b"ping"),
+        b"*1\r\n*6\r\n$4\r\nping\r\n:-1\r\n*1\r\n+fast\r\n:0\r\n:0\r\n:0\r\n".to_vec()
+    );
+    // the commands rejected by proxy are nil
+    assert_eq!(info(b"KEYS"), b"*1\r\n$-1\r\n".to_vec());
+    assert_eq!(info(b"NOSUCH"), b"*1\r\n$-1\r\n".to_vec());
+
+    // all the supported ones without names, the same as COMMAND COUNT
+    let all = parse(&[b"COMMAND", b"INFO"]);
+    let count = parse(&[b"COMMAND", b"COUNT"]);
+    let count = count.borrow().reply.clone().unwrap();
+    let expected = format!(
+        "*{}\r\n",
+        btoi::btoi::<usize>(count.data().unwrap()).unwrap()
+    );
+    let all = all.borrow().reply.clone().unwrap();
+    assert!(all.raw_data().starts_with(expected.as_bytes()));
+
+    // the keys given by the key spec are the same as routing
+    for args in &[
+        &[&b"GET"[..], b"a"][..],
+        &[&b"SET"[..], b"a", b"1", b"EX", b"10"][..],
+        &[&b"MGET"[..], b"a", b"b", b"c"][..],
+        &[&b"MSET"[..], b"a", b"1", b"b", b"2"][..],
+        &[&b"DEL"[..], b"a", b"b"][..],
+    ] {
+        let spec = key_spec(args[0]);
+        let last = if spec[1] < 0 {
+            args.len() as i64 + spec[1]
+        } else {
+            spec[1]
+        };
+        let hashes: Vec<_> = (spec[0]..=last)
+            .step_by(spec[2] as usize)
+            .map(|i| Some(crc16(args[i as usize])))
+            .collect();
+        let cmd = parse(args);
+        let routed: Vec<_> = match cmd.borrow().subs() {
+            Some(subs) => subs
+                .iter()
+                .map(|x| x.borrow().key_hash(b"", crc16))
+                .collect(),
+            None => vec![cmd.borrow().key_hash(b"", crc16)],
+        };
+        assert_eq!(hashes, routed);
+    }
+}
+
+#[test]
+fn test_redis_sort_pattern() {
+    fn parse(args: &[&str]) -> Cmd {
+        let mut src = BytesMut::new();
+        Message::from_args(args).save(&mut src);
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    }
+    let pattern = |args: &[&str], hash_tag: &[u8]| {
+        parse(args)
+            .borrow()
+            .sort_pattern(hash_tag)
+            .map(|x| String::from_utf8_lossy(x).to_string())
+    };
+
+    // routed by the key to sort
+    let cmd = parse(&["SORT_RO", "list", "BY", "w_*"]);
+    assert!(!cmd.borrow().is_mutation());
+    assert!(parse(&["SORT", "list"]).borrow().is_mutation());
+    assert_eq!(
+        cmd.borrow().key_hash(b"", |x| x.len() as u64),
+        Some("list".len() as u64)
+    );
+
+    assert_eq!(pattern(&["SORT", "list"], b""), None);
+    assert_eq!(
+        pattern(&["SORT", "list", "LIMIT", "0", "10", "ALPHA"], b""),
+        None
+    );
+    assert_eq!(
+        pattern(&["SORT", "list", "by", "w_*"], b""),
+        Some("w_*".to_string())
+    );
+    assert_eq!(
+        pattern(&["SORT_RO", "list", "GET", "#", "GET", "o_*->name"], b""),
+        Some("o_*->name".to_string())
+    );
+    // not patterns at all
+    assert_eq!(
+        pattern(&["SORT", "list", "BY", "nosort", "GET", "#"], b""),
+        None
+    );
+    // options are never taken as BY/GET
+    assert_eq!(pattern(&["SORT", "list", "STORE", "by", "DESC"], b""), None);
+    assert_eq!(pattern(&["SORT", "by", "LIMIT", "0", "1"], b""), None);
+
+    // the same hash tag as the key
+    let tag = b"{}";
+    assert_eq!(pattern(&["SORT", "{u1}:list", "BY", "{u1}:w_*"], tag), None);
+    assert_eq!(pattern(&["SORT", "u1", "GET", "{u1}:o_*"], tag), None);
+    assert_eq!(
+        pattern(&["SORT", "{u1}:list", "BY", "{u2}:w_*"], tag),
+        Some("{u2}:w_*".to_string())
+    );
+    assert_eq!(
+        pattern(&["SORT", "{u1}:list", "BY", "{u*}:w"], tag),
+        Some("{u*}:w".to_string())
+    );
+    assert_eq!(
+        pattern(&["SORT", "{u1}:list", "BY", "{u1}:w_*"], b""),
+        Some("{u1}:w_*".to_string())
+    );
+}
+
+#[test]
+fn test_redis_proxy_args() {
+    let parse = |args: &[&str]| {
+        let mut src = BytesMut::new();
+        Message::from_args(args).save(&mut src);
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    };
+    let cmd = parse(&["proxy", "addnode", "127.0.0.1:7003", "10"]);
+    assert!(cmd.check_valid());
+    assert!(!cmd.borrow().is_done());
+    assert_eq!(
+        cmd.borrow().proxy_args(),
+        Some(vec![
+            "addnode".to_string(),
+            "127.0.0.1:7003".to_string(),
+            "10".to_string()
+        ])
+    );
+
+    let get = parse(&["GET", "a"]);
+    assert!(!get.borrow().is_proxy());
+    assert!(!get.handle_proxy(|_| Ok(None)));
+
+    let delnode = parse(&["PROXY", "DELNODE", "redis-1"]);
+    assert!(delnode.handle_proxy(|args| {
+        assert_eq!(args, &["DELNODE".to_string(), "redis-1".to_string()][..]);
+        Ok(None)
+    }));
+    assert_eq!(delnode.reply(), Some(Message::plain("OK", RESP_STRING)));
+
+    let shard = parse(&["PROXY", "SHARD", "a"]);
+    assert!(shard.handle_proxy(|_| Ok(Some(vec!["hash".to_string(), "1".to_string()]))));
+    assert_eq!(shard.reply(), Some(Message::from_args(&["hash", "1"])));
+}
+
+#[test]
+fn test_redis_client_kill_args() {
+    let parse = |args: &[&str]| {
+        let mut src = BytesMut::new();
+        Message::from_args(args).save(&mut src);
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    };
+    let kill = parse(&["client", "kill", "id", "42"]);
+    assert!(kill.check_valid());
+    assert!(!kill.borrow().is_done());
+    assert_eq!(
+        kill.borrow().client_kill_args(),
+        Some(vec!["id".to_string(), "42".to_string()])
+    );
+    assert!(kill.handle_client_kill(|args| {
+        assert_eq!(args, &["id".to_string(), "42".to_string()][..]);
+        Ok(1)
+    }));
+    assert_eq!(kill.reply(), Some(Message::plain("1", RESP_INT)));
+
+    // other CLIENT sub commands are still not supported
+    let list = parse(&["CLIENT", "LIST"]);
+    assert!(!list.borrow().is_client_kill());
+    assert!(!list.check_valid());
+    assert!(!list.handle_client_kill(|_| Ok(0)));
+}
+
+#[test]
+fn test_redis_reply_merge() {
+    let parse = |data: &[u8]| Message::parse(&mut BytesMut::from(data)).unwrap().unwrap();
+    let merge = |merge: Merge, replies: &[&Message]| {
+        let replies: Vec<_> = replies.iter().map(|x| Some(*x)).collect();
+        let mut buf = BytesMut::new();
+        let size = Message::merge(merge, &replies, &mut buf).unwrap();
+        assert_eq!(size, buf.len());
+        buf
+    };
+    let ok = parse(b"+OK\r\n");
+    let one = parse(b":1\r\n");
+    let bulk = parse(b"$1\r\na\r\n");
+    let wrong_type = parse(b"-WRONGTYPE Operation against a key\r\n");
+    let proxy_error: Message = AsError::BackendClosedError("a".to_string()).into_reply();
+    assert!(wrong_type.is_error_reply());
+    assert!(proxy_error.is_error_reply());
+    assert!(!ok.is_error_reply());
+
+    // the errors are kept as elements in the order of keys
+    assert_eq!(
+        &merge(Merge::ConcatArray, &[&bulk, &wrong_type, &one])[..],
+        &b"*3\r\n$1\r\na\r\n-WRONGTYPE Operation against a key\r\n:1\r\n"[..]
+    );
+    assert_eq!(&merge(Merge::ConcatArray, &[])[..], BYTES_NULL_ARRAY);
+
+    assert_eq!(
+        &merge(Merge::SumIntegers, &[&one, &one, &one])[..],
+        b":3\r\n"
+    );
+    assert_eq!(&merge(Merge::SumIntegers, &[])[..], b":0\r\n");
+    assert_eq!(
+        &merge(Merge::SumIntegers, &[&one, &wrong_type, &proxy_error])[..],
+        &b"-WRONGTYPE Operation against a key\r\n"[..]
+    );
+
+    assert_eq!(&merge(Merge::AllOk, &[&ok, &ok])[..], BYTES_JUSTOK);
+    assert_eq!(
+        &merge(Merge::AllOk, &[&ok, &proxy_error, &wrong_type])[..],
+        &proxy_error.data[..]
+    );
+
+    assert_eq!(
+        &merge(Merge::FirstError, &[&bulk, &one])[..],
+        b"$1\r\na\r\n"
+    );
+    assert_eq!(
+        &merge(Merge::FirstError, &[&bulk, &wrong_type])[..],
+        &b"-WRONGTYPE Operation against a key\r\n"[..]
+    );
+
+    let mut buf = BytesMut::new();
+    assert_eq!(
+        Message::merge(Merge::SumIntegers, &[Some(&one), None], &mut buf),
+        Err(AsError::BadReply)
+    );
+}
+
+#[test]
+fn test_redis_merge_by_table() {
+    let mut src = BytesMut::from(
+        &b"*3\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n"[..],
+    );
+    let del = Command::parse_cmd(&mut src).unwrap().unwrap();
+    let mget = Command::parse_cmd(&mut src).unwrap().unwrap();
+    let mset = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert_eq!(CmdType::get_merge(&del.borrow().req), Merge::SumIntegers);
+    assert_eq!(CmdType::get_merge(&mget.borrow().req), Merge::ConcatArray);
+    assert_eq!(CmdType::get_merge(&mset.borrow().req), Merge::AllOk);
+
+    for sub in del.borrow().subs().unwrap() {
+        sub.set_reply(1usize);
+    }
+    let mget_subs = mget.borrow().subs().unwrap();
+    let bulk = Message::parse(&mut BytesMut::from(&b"$1\r\n1\r\n"[..])).unwrap();
+    mget_subs[0].set_reply(bulk.unwrap());
+    mget_subs[1].set_error(&AsError::ReadOnly);
+    let mset_subs = mset.borrow().subs().unwrap();
+    mset_subs[0].set_reply("OK");
+    mset_subs[1].set_error(&AsError::ReadOnly);
+
+    let reply = |cmd: &Cmd| {
+        let mut buf = BytesMut::new();
+        cmd.borrow().reply_cmd(&mut buf).unwrap();
+        buf
+    };
+    assert_eq!(&reply(&del)[..], b":2\r\n");
+    assert_eq!(
+        &reply(&mget)[..],
+        &b"*2\r\n$1\r\n1\r\n-READONLY proxy is in read-only mode\r\n"[..]
+    );
+    assert_eq!(
+        &reply(&mset)[..],
+        &b"-READONLY proxy is in read-only mode\r\n"[..]
+    );
+}
+
+#[test]
+fn test_redis_codec_protocol_error() {
+    let mut src =
+        BytesMut::from(&b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI\r\n*1\r\n$4\r\nPING\r\n"[..]);
+    let mut codec = RedisHandleCodec::default();
+    let ping = codec.decode(&mut src).unwrap().unwrap();
+    let bad = codec.decode(&mut src).unwrap().unwrap();
+    assert!(bad.check_valid());
+    assert!(bad.borrow().is_done());
+    // nothing is decoded after the protocol error
+    assert_eq!(
+        codec.decode(&mut src).unwrap_err(),
+        AsError::ProtocolError("invalid bulk length".to_string())
+    );
+    assert!(src.is_empty());
+
+    let mut buf = BytesMut::new();
+    codec.encode(ping, &mut buf).unwrap();
+    codec.encode(bad, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"+PONG\r\n-ERR Protocol error: invalid bulk length\r\n"[..]
+    );
+}
+
+#[test]
+fn test_redis_codec_max_args() {
+    let mut src = BytesMut::new();
+    src.extend_from_slice(b"*4\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n");
+    src.extend_from_slice(b"*5\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n");
+    src.extend_from_slice(b"DEL a b c d\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+    let limit = ArgsLimit::new(4, Some("127.0.0.1:50001".to_string()));
+    let mut codec = RedisHandleCodec::default().args_limit(limit);
+
+    // the command name is counted as well
+    let at = codec.decode(&mut src).unwrap().unwrap();
+    assert!(!at.borrow().is_done());
+    assert_eq!(at.subs().map(|x| x.len()), Some(3));
+
+    // the command beyond is never fanned out, inline as well
+    for _ in 0..2 {
+        let above = codec.decode(&mut src).unwrap().unwrap();
+        assert!(above.borrow().is_done());
+        assert!(above.subs().is_none());
+        let mut buf = BytesMut::new();
+        codec.encode(above, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            &b"-ERR too many arguments of command, max_args is 4\r\n"[..]
+        );
+    }
+
+    // the following commands are decoded as usual
+    let get = codec.decode(&mut src).unwrap().unwrap();
+    assert!(!get.borrow().is_done());
+    assert!(src.is_empty());
+
+    // no limit by default
+    let mut codec = RedisHandleCodec::default();
+    let mut src = BytesMut::from(&b"*3\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n"[..]);
+    let del = codec.decode(&mut src).unwrap().unwrap();
+    assert_eq!(del.subs().map(|x| x.len()), Some(2));
+}
+
+#[test]
+fn test_redis_codec_max_outstanding_subs() {
+    use crate::metrics::outstanding_subs;
+
+    let gauge = outstanding_subs("test-subs-limit");
+    let mut codec = RedisHandleCodec::default().subs_limit(SubsLimit::new("test-subs-limit", 4));
+    let mut decode = |data: &[u8]| codec.decode(&mut BytesMut::from(data)).unwrap().unwrap();
+
+    let mget = decode(b"*4\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n");
+    assert_eq!(mget.subs().map(|x| x.len()), Some(3));
+    let sub = mget.subs().unwrap()[0].clone();
+    assert_eq!(gauge.get(), 3);
+
+    // saturated, the fan-out command beyond is never fanned out
+    let mset = decode(b"*5\r\n$4\r\nMSET\r\n$1\r\nd\r\n$1\r\n1\r\n$1\r\ne\r\n$1\r\n2\r\n");
+    assert!(mset.borrow().is_done());
+    assert!(mset.subs().is_none());
+    let mut buf = BytesMut::new();
+    RedisHandleCodec::default().encode(mset, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"-ERR too many subs of fan-out commands in flight, max_outstanding_subs is 4\r\n"[..]
+    );
+    assert_eq!(gauge.get(), 3);
+
+    // the ones within and the single key ones are served as usual
+    let del = decode(b"*2\r\n$3\r\nDEL\r\n$1\r\nf\r\n");
+    assert_eq!(del.subs().map(|x| x.len()), Some(1));
+    let get = decode(b"*2\r\n$3\r\nGET\r\n$1\r\ng\r\n");
+    assert!(!get.borrow().is_done());
+    assert_eq!(gauge.get(), 4);
+
+    // released once the command and all its subs are dropped
+    drop(mget);
+    assert_eq!(gauge.get(), 4);
+    drop(sub);
+    assert_eq!(gauge.get(), 1);
+    let mget = decode(b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n");
+    assert_eq!(mget.subs().map(|x| x.len()), Some(2));
+    drop((mget, del));
+    assert_eq!(gauge.get(), 0);
+}
+
+#[test]
+fn test_redis_codec_max_value_size() {
+    let mut src = BytesMut::new();
+    src.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$4\r\nbcde\r\n");
+    src.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$5\r\nbcdef\r\n");
+    src.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+    let mut codec = RedisHandleCodec::default().value_limit(ValueLimit::new(4));
+
+    let at = codec.decode(&mut src).unwrap().unwrap();
+    assert!(!at.borrow().is_done());
+
+    // rejected by the wording of redis before sent to backend
+    let above = codec.decode(&mut src).unwrap().unwrap();
+    assert!(above.borrow().is_done());
+    let mut buf = BytesMut::new();
+    codec.encode(above, &mut buf).unwrap();
+    assert_eq!(
+        &buf[..],
+        &b"-ERR Protocol error: invalid bulk length\r\n"[..]
+    );
+
+    // the following commands are decoded as usual
+    let get = codec.decode(&mut src).unwrap().unwrap();
+    assert!(!get.borrow().is_done());
+    assert!(src.is_empty());
+}
+
+#[test]
+fn test_redis_codec_ttl_policies() {
+    let lines: Vec<_> = ["session:* 3600 600", "cache:* 3600 reject"]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+    let ttls = TtlPolicies::new(&lines).unwrap();
+    let mut codec = RedisNodeCodec::default().ttl_policies(ttls.clone());
+    let sent = |codec: &mut RedisNodeCodec, req: &[u8]| {
+        let cmd = Command::parse_cmd(&mut BytesMut::from(req))
+            .unwrap()
+            .unwrap();
+        let mut buf = BytesMut::new();
+        codec.encode(cmd, &mut buf).unwrap();
+        buf
+    };
+
+    // the TTL beyond max is capped
+    assert_eq!(
+        &sent(
+            &mut codec,
+            b"*5\r\n$3\r\nSET\r\n$9\r\nsession:1\r\n$1\r\nv\r\n$2\r\nEX\r\n$5\r\n99999\r\n"
+        )[..],
+        &b"*5\r\n$3\r\nSET\r\n$9\r\nsession:1\r\n$1\r\nv\r\n$2\r\nEX\r\n$4\r\n3600\r\n"[..]
+    );
+    // the default TTL is given to the write without TTL
+    assert_eq!(
+        &sent(
+            &mut codec,
+            b"*3\r\n$3\r\nSET\r\n$9\r\nsession:2\r\n$1\r\nv\r\n"
+        )[..],
+        &b"*5\r\n$3\r\nSET\r\n$9\r\nsession:2\r\n$1\r\nv\r\n$2\r\nEX\r\n$3\r\n600\r\n"[..]
+    );
+    // the others are sent as is
+    let get = &b"*2\r\n$3\r\nGET\r\n$9\r\nsession:1\r\n"[..];
+    assert_eq!(&sent(&mut codec, get)[..], get);
+
+    // and the write without TTL of reject is failed by proxy
+    let mut front = RedisHandleCodec::default().ttl_policies(ttls);
+    let mut src = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$7\r\ncache:1\r\n$1\r\nv\r\n"[..]);
+    src.extend_from_slice(
+        b"*5\r\n$3\r\nSET\r\n$7\r\ncache:1\r\n$1\r\nv\r\n$2\r\nEX\r\n$2\r\n60\r\n",
+    );
+    let rejected = front.decode(&mut src).unwrap().unwrap();
+    assert!(rejected.borrow().is_done());
+    assert!(rejected.borrow().is_error());
+    let with_ttl = front.decode(&mut src).unwrap().unwrap();
+    assert!(!with_ttl.borrow().is_done());
+}
+
+#[test]
+fn test_redis_codec_resp_version() {
+    fn reply_of(codec: &mut RedisHandleCodec, req: &[u8], reply: Option<&[u8]>) -> BytesMut {
+        let mut src = BytesMut::from(req);
+        let cmd = codec.decode(&mut src).unwrap().unwrap();
+        if let Some(reply) = reply {
+            let reply = Message::parse(&mut BytesMut::from(reply)).unwrap().unwrap();
+            cmd.set_reply(reply);
+        }
+        let mut buf = BytesMut::new();
+        codec.encode(cmd, &mut buf).unwrap();
+        buf
+    }
+    let hgetall = &b"*2\r\n$7\r\nHGETALL\r\n$1\r\nh\r\n"[..];
+    let map = &b"%2\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n_\r\n"[..];
+
+    // the same reply of backend is downgraded before HELLO 3
+    let mut codec = RedisHandleCodec::default();
+    assert_eq!(
+        &reply_of(&mut codec, hgetall, Some(map))[..],
+        &b"*4\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$-1\r\n"[..]
+    );
+    let hello = reply_of(&mut codec, b"*2\r\n$5\r\nhello\r\n$1\r\n3\r\n", None);
+    assert!(hello.starts_with(b"%6\r\n$6\r\nserver\r\n$5\r\naster\r\n"));
+    assert!(hello.ends_with(b"$5\r\nproto\r\n:3\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n$4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n"));
+    assert_eq!(&reply_of(&mut codec, hgetall, Some(map))[..], map);
+
+    // back to RESP2 by HELLO 2, the reply of which is already a flat array
+    let hello = reply_of(&mut codec, b"*2\r\n$5\r\nHELLO\r\n$1\r\n2\r\n", None);
+    assert!(hello.starts_with(b"*12\r\n$6\r\nserver\r\n"));
+    assert_eq!(
+        &reply_of(&mut codec, hgetall, Some(map))[..],
+        &b"*4\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$-1\r\n"[..]
+    );
+
+    // the version is kept by the unsupported protocol
+    let hello = reply_of(&mut codec, b"*2\r\n$5\r\nHELLO\r\n$1\r\n4\r\n", None);
+    assert_eq!(
+        &hello[..],
+        &b"-NOPROTO sorry, this protocol version is not supported\r\n"[..]
+    );
+    assert_eq!(codec.proto, RespVersion::Resp2);
+}
+
+#[test]
+fn test_redis_codec_legacy_shims() {
+    use crate::com::ClientCompat;
+
+    fn reply_of(codec: &mut RedisHandleCodec, req: &[u8], reply: Option<&[u8]>) -> BytesMut {
+        let mut src = BytesMut::from(req);
+        let cmd = codec.decode(&mut src).unwrap().unwrap();
+        if let Some(reply) = reply {
+            let reply = Message::parse(&mut BytesMut::from(reply)).unwrap().unwrap();
+            cmd.set_reply(reply);
+        }
+        let mut buf = BytesMut::new();
+        codec.encode(cmd, &mut buf).unwrap();
+        buf
+    }
+    let hgetall = &b"*2\r\n$7\r\nHGETALL\r\n$1\r\nh\r\n"[..];
+    let map = &b"%1\r\n$1\r\na\r\n%1\r\n$1\r\nb\r\n,1.5\r\n"[..];
+    let hello = &b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n"[..];
+
+    // the types of RESP3 are translated even after HELLO 3
+    let mut codec = RedisHandleCodec::default().shims(Shims::new(&[ClientCompat::Resp2Only]));
+    let _ = reply_of(&mut codec, hello, None);
+    assert_eq!(codec.proto, RespVersion::Resp3);
+    assert_eq!(
+        &reply_of(&mut codec, hgetall, Some(map))[..],
+        &b"*2\r\n$1\r\na\r\n*2\r\n$1\r\nb\r\n$3\r\n1.5\r\n"[..]
+    );
+
+    // flattened after translated
+    let mut codec = RedisHandleCodec::default().shims(Shims::new(&[
+        ClientCompat::Resp2Only,
+        ClientCompat::NoNestedArrays,
+    ]));
+    let _ = reply_of(&mut codec, hello, None);
+    assert_eq!(
+        &reply_of(&mut codec, hgetall, Some(map))[..],
+        &b"*3\r\n$1\r\na\r\n$1\r\nb\r\n$3\r\n1.5\r\n"[..]
+    );
+
+    // the map of RESP3 is kept without resp2_only
+    let mut codec = RedisHandleCodec::default().shims(Shims::new(&[ClientCompat::NoNestedArrays]));
+    let _ = reply_of(&mut codec, hello, None);
+    assert_eq!(&reply_of(&mut codec, hgetall, Some(map))[..], map);
+
+    // the errors in the reply of EXEC are inlined
+    let exec = &b"*1\r\n$4\r\nEXEC\r\n"[..];
+    let replies = &b"*2\r\n+OK\r\n-ERR value is not an integer\r\n"[..];
+    let mut codec = RedisHandleCodec::default().shims(Shims::new(&[ClientCompat::InlineErrors]));
+    assert_eq!(
+        &reply_of(&mut codec, exec, Some(replies))[..],
+        &b"-ERR value is not an integer\r\n"[..]
+    );
+    let mut codec = RedisHandleCodec::default();
+    assert_eq!(&reply_of(&mut codec, exec, Some(replies))[..], replies);
+}
+
+#[test]
+fn test_redis_touch_fan_out() {
+    use crate::utils::crc::crc16;
+
+    let mut src = BytesMut::from(&b"*4\r\n$5\r\ntouch\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(!cmd.borrow().is_mutation());
+    assert_eq!(CmdType::get_merge(&cmd.borrow().req), Merge::SumIntegers);
+
+    let subs = cmd.borrow().subs().unwrap();
+    assert_eq!(subs.len(), 3);
+    let slots: HashSet<_> = subs
+        .iter()
+        .map(|x| x.borrow().key_hash(b"", crc16).unwrap() % 16384)
+        .collect();
+    assert_eq!(slots.len(), 3);
+
+    let mut dst = BytesMut::new();
+    subs[1].borrow().send_req(&mut dst).unwrap();
+    assert_eq!(&dst[..], &b"*2\r\n$5\r\nTOUCH\r\n$1\r\nb\r\n"[..]);
+
+    // the subs complete out of order, and the missing key counts nothing
+    subs[2].set_reply(1usize);
+    assert!(!cmd.borrow().is_done());
+    subs[0].set_reply(1usize);
+    subs[1].set_reply(0usize);
+    assert!(cmd.borrow().is_done());
+
+    let mut buf = BytesMut::new();
+    cmd.borrow().reply_cmd(&mut buf).unwrap();
+    assert_eq!(&buf[..], b":2\r\n");
+}
+
+#[test]
+fn test_redis_unlink_fan_out() {
+    use crate::utils::crc::crc16;
+
+    // UNLINK is classified as a delete the same as DEL, only the name sent to backend differs
+    let reply = |name: &str| {
+        let mut src = BytesMut::new();
+        Message::from_args(&[name, "a", "b", "c"]).save(&mut src);
+        let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+        assert_eq!(cmd.borrow().ctype, CmdType::Del);
+        assert!(cmd.borrow().is_mutation());
+        assert_eq!(CmdType::get_merge(&cmd.borrow().req), Merge::SumIntegers);
+
+        let subs = cmd.borrow().subs().unwrap();
+        let slots: HashSet<_> = subs
+            .iter()
+            .map(|x| x.borrow().key_hash(b"", crc16).unwrap() % 16384)
+            .collect();
+        assert_eq!(slots.len(), 3);
+        let mut dst = BytesMut::new();
+        subs[1].borrow().send_req(&mut dst).unwrap();
+        let expect = format!("*2\r\n${}\r\n{}\r\n$1\r\nb\r\n", name.len(), name);
+        assert_eq!(&dst[..], expect.as_bytes());
+
+        subs[0].set_reply(1usize);
+        subs[1].set_reply(0usize);
+        subs[2].set_reply(1usize);
+        let mut buf = BytesMut::new();
+        cmd.borrow().reply_cmd(&mut buf).unwrap();
+        buf
+    };
+    assert_eq!(&reply("UNLINK")[..], b":2\r\n");
+    assert_eq!(reply("UNLINK"), reply("DEL"));
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct WakeCount(std::sync::atomic::AtomicUsize);
+
+#[cfg(test)]
+impl futures::executor::Notify for WakeCount {
+    fn notify(&self, _id: usize) {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    if n == 0 {
+        return vec![vec![]];
+    }
+    let mut all = Vec::new();
+    for rest in permutations(n - 1) {
+        for pos in 0..=rest.len() {
+            let mut order = rest.clone();
+            order.insert(pos, n - 1);
+            all.push(order);
+        }
+    }
+    all
+}
+
+// the mock backends reply the subs in the given order, the first one fails and is reset to
+// retry ahead, and the last one is redirected to another backend, return the merged reply.
+#[cfg(test)]
+fn complete_in_order(req: &[u8], order: &[usize]) -> Vec<u8> {
+    use futures::{executor, task};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    let mut src = BytesMut::from(req);
+    let mut cmd = RedisHandleCodec::default()
+        .decode(&mut src)
+        .unwrap()
+        .unwrap();
+    let wakes = Arc::new(WakeCount::default());
+    let mut front = executor::spawn(futures::future::empty::<(), ()>());
+    front.poll_fn_notify(&wakes, 0, |_| cmd.reregister(task::current()));
+
+    let reply = |sub: &Cmd| -> Message {
+        let sub = sub.borrow();
+        if sub.ctype.is_mget() {
+            let key = sub.req.nth(1).unwrap();
+            let mut data = BytesMut::new();
+            prefix::save_bulk(&[key], &mut data);
+            MessageMut::parse(&mut data).unwrap().unwrap().into()
+        } else if sub.ctype.is_mset() {
+            "OK".into_reply()
+        } else {
+            1usize.into_reply()
+        }
+    };
+
+    let subs = cmd.borrow().subs().unwrap();
+    let attempt = subs[order[0]].clone();
+    attempt.set_error(&AsError::BackendClosedError("mock".to_string()));
+    attempt.unset_error();
+    attempt.unset_done();
+
+    // clones kept by backends after replied never count
+    let mut inflight = Vec::new();
+    for (n, &i) in order.iter().enumerate() {
+        assert!(!cmd.borrow().is_done());
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        assert!(cmd.borrow().reply_cmd(&mut BytesMut::new()).is_err());
+
+        let mut sub = subs[i].clone();
+        if n + 1 == order.len() {
+            let moved = sub.clone();
+            moved.borrow_mut().set_moved();
+            moved.borrow_mut().add_cycle();
+            sub = moved;
+        }
+        sub.set_reply(reply(&sub));
+        inflight.push(sub);
+    }
+    assert!(cmd.borrow().is_done());
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+
+    let mut buf = BytesMut::new();
+    cmd.borrow().reply_cmd(&mut buf).unwrap();
+    buf.to_vec()
+}
+
+#[test]
+fn test_redis_sub_completion_in_any_order() {
+    let cases: &[(&[u8], &[u8])] = &[
+        (
+            b"*5\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n",
+            b"*4\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n",
+        ),
+        (
+            b"*5\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n",
+            b":4\r\n",
+        ),
+        (
+            b"*5\r\n$6\r\nEXISTS\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n",
+            b":4\r\n",
+        ),
+        (
+            b"*9\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n\
+              $1\r\nc\r\n$1\r\n3\r\n$1\r\nd\r\n$1\r\n4\r\n",
+            b"+OK\r\n",
+        ),
+    ];
+    for (req, expect) in cases {
+        for order in permutations(4) {
+            let merged = complete_in_order(req, &order);
+            assert_eq!(&merged[..], *expect, "completed in order {:?}", order);
+        }
+    }
+}
+
+#[test]
+fn test_redis_fan_out_wakeups_bounded() {
+    use futures::{executor, task};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    let keys: Vec<_> = (0..64).map(|i| format!("key-{}", i)).collect();
+    let mut req = format!("*{}\r\n$4\r\nMGET\r\n", keys.len() + 1);
+    for key in keys.iter() {
+        req.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
+    }
+    let mut cmd = Command::parse_cmd(&mut BytesMut::from(req.as_bytes()))
+        .unwrap()
+        .unwrap();
+    let wakes = Arc::new(WakeCount::default());
+    let mut front = executor::spawn(futures::future::empty::<(), ()>());
+    front.poll_fn_notify(&wakes, 0, |_| cmd.reregister(task::current()));
+
+    // every sub is retried once before replied
+    let subs = cmd.borrow().subs().unwrap();
+    for sub in subs.iter() {
+        sub.set_error(&AsError::BackendClosedError("mock".to_string()));
+        sub.unset_error();
+        sub.unset_done();
+        sub.set_reply(1usize);
+    }
+    assert!(cmd.borrow().is_done());
+    // the task is woken up once by the whole fan-out rather than by each sub
+    assert_eq!(cmd.notify.wakeups(), 1);
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_redis_rejected_multi_key_wakes_once() {
+    let mut src = BytesMut::from(&b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n"[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+    let subs = cmd.borrow().subs().unwrap();
+    subs[0].set_reply(1usize);
+
+    // rejected as a whole, the sub in flight is never waited
+    cmd.set_error(&AsError::ReadOnly);
+    assert!(cmd.borrow().is_done());
+    let mut buf = BytesMut::new();
+    cmd.borrow().reply_cmd(&mut buf).unwrap();
+    assert_eq!(&buf[..], &b"-READONLY proxy is in read-only mode\r\n"[..]);
+}
+
+#[test]
+fn test_redis_two_keys_same_slot() {
+    fn check(args: &[&str]) -> Result<(), AsError> {
+        let mut req = format!("*{}\r\n", args.len());
+        for arg in args {
+            req.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        let mut src = BytesMut::from(req.as_bytes());
+        let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+        let slot = |x: &[u8]| crate::utils::crc::crc16(x) % 16384;
+        let rslt = cmd.borrow().check_two_keys(b"{}", slot);
+        rslt
+    }
+
+    let same: &[&[&str]] = &[
+        &["SMOVE", "{user1}.a", "{user1}.b", "m"],
+        &["RENAMENX", "{user1}.a", "{user1}.b"],
+        &["RPOPLPUSH", "{user1}.a", "{user1}.b"],
+        // the directions are never taken as keys
+        &["LMOVE", "{user1}.a", "{user1}.b", "LEFT", "RIGHT"],
+        &["BLMOVE", "{user1}.a", "{user1}.b", "RIGHT", "LEFT", "0"],
+        &["smove", "a", "a", "m"],
+    ];
+    for args in same {
+        assert_eq!(check(args), Ok(()), "{:?}", args);
+    }
+    let cross: &[&[&str]] = &[
+        &["SMOVE", "{user1}.a", "{user2}.b", "m"],
+        &["RENAMENX", "a", "b"],
+        &["RPOPLPUSH", "a", "b"],
+        &["LMOVE", "a", "b", "LEFT", "RIGHT"],
+        &["BLMOVE", "a", "b", "RIGHT", "LEFT", "0"],
+    ];
+    for args in cross {
+        assert_eq!(check(args), Err(AsError::CrossSlot), "{:?}", args);
+    }
+    // routed by the source key as usual, the others are never checked
+    assert_eq!(check(&["LMOVE", "a"]), Ok(()));
+    assert_eq!(check(&["SET", "a", "b"]), Ok(()));
+    assert_eq!(check(&["MSET", "a", "1", "b", "2"]), Ok(()));
+}
+
+#[test]
+fn test_redis_bound_blocking_timeout() {
+    fn bound(req: &[u8], hash_tag: &[u8]) -> (Result<Option<Vec<u8>>, AsError>, Vec<u8>) {
+        let mut src = BytesMut::from(req);
+        let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+        let rslt = cmd
+            .borrow_mut()
+            .bound_timeout(hash_tag, |x| crate::utils::crc::crc16(x), 1);
+        let timeout = cmd.borrow().req.nth(2).unwrap().to_vec();
+        (rslt, timeout)
+    }
+
+    // block forever is capped
+    let (rslt, timeout) = bound(b"*3\r\n$5\r\nblpop\r\n$4\r\nlist\r\n$1\r\n0\r\n", b"");
+    assert_eq!(rslt, Ok(Some(b"0".to_vec())));
+    assert_eq!(&timeout[..], b"1");
+    // the shorter one is kept
+    let (rslt, timeout) = bound(b"*3\r\n$5\r\nBRPOP\r\n$4\r\nlist\r\n$3\r\n0.5\r\n", b"");
+    assert_eq!(rslt, Ok(None));
+    assert_eq!(&timeout[..], b"0.5");
+    // non blocking command is never touched
+    let (rslt, _) = bound(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n0\r\n", b"");
+    assert_eq!(rslt, Ok(None));
+
+    let cross = b"*4\r\n$5\r\nBLPOP\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\n0\r\n";
+    assert_eq!(bound(cross, b"").0, Err(AsError::CrossSlot));
+    let tagged = b"*4\r\n$5\r\nBLPOP\r\n$4\r\n{a}1\r\n$4\r\n{a}2\r\n$1\r\n9\r\n";
+    assert_eq!(bound(tagged, b"{}").0, Ok(Some(b"9".to_vec())));
+}
+
+#[test]
+fn test_redis_integrity_replay() {
+    let mut src = BytesMut::from(&b"*2\r\n$3\r\nget\r\n$1\r\na\r\n"[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert_eq!(cmd.reply_checksum(), None);
+    let mut reply = BytesMut::from(&b"$1\r\nx\r\n"[..]);
+    cmd.set_reply(Message::parse(&mut reply).unwrap().unwrap());
+    assert_eq!(
+        cmd.reply_checksum(),
+        Some(integrity::checksum(b"$1\r\nx\r\n"))
+    );
+
+    let replay = cmd.integrity_replay().unwrap();
+    assert!(!replay.is_done());
+    let mut dst = BytesMut::new();
+    replay.borrow().send_req(&mut dst).unwrap();
+    assert_eq!(&dst[..], &b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..]);
+
+    // each key of MGET is sampled alone, the writes never
+    let mut src = BytesMut::from(&b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n"[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(cmd.integrity_replay().is_none());
+    let subs = cmd.borrow().subs().unwrap();
+    assert!(subs.iter().all(|x| x.integrity_replay().is_some()));
+    let mut src = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nx\r\n"[..]);
+    let cmd = Command::parse_cmd(&mut src).unwrap().unwrap();
+    assert!(cmd.integrity_replay().is_none());
+}
+
+#[test]
+fn test_redis_keyslot_key() {
+    let parse = |data: &[u8]| {
+        let mut src = BytesMut::from(data);
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    };
+    let keyslot = parse(b"*3\r\n$7\r\ncluster\r\n$7\r\nkeyslot\r\n$11\r\n{user1000}a\r\n");
+    assert_eq!(
+        keyslot.borrow().keyslot_key(),
+        Some(Ok(b"{user1000}a".to_vec()))
+    );
+    let bad = parse(b"*2\r\n$7\r\nCLUSTER\r\n$7\r\nKEYSLOT\r\n");
+    assert!(bad.borrow().keyslot_key().unwrap().is_err());
+    let nodes = parse(b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nNODES\r\n");
+    assert_eq!(nodes.borrow().keyslot_key(), None);
+    let get = parse(b"*2\r\n$3\r\nGET\r\n$7\r\nKEYSLOT\r\n");
+    assert_eq!(get.borrow().keyslot_key(), None);
+}
+
+#[test]
+fn test_redis_read_write_class() {
+    let parse = |data: &[u8]| {
+        let mut src = BytesMut::from(data);
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    };
+    let get = parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+    assert!(get.is_read() && !get.is_write());
+    let mget = parse(b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n");
+    assert!(mget.is_read() && !mget.is_write());
+    let exists = parse(b"*2\r\n$6\r\nEXISTS\r\n$1\r\na\r\n");
+    assert!(exists.is_read() && !exists.is_write());
+
+    let set = parse(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\nx\r\n");
+    assert!(set.is_write() && !set.is_read());
+    let mset = parse(b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\nx\r\n$1\r\nb\r\n$1\r\ny\r\n");
+    assert!(mset.is_write() && !mset.is_read());
+    let del = parse(b"*2\r\n$3\r\nDEL\r\n$1\r\na\r\n");
+    assert!(del.is_write() && !del.is_read());
+
+    // scripts and control commands are neither
+    let eval = parse(b"*4\r\n$4\r\nEVAL\r\n$8\r\nreturn 1\r\n$1\r\n1\r\n$1\r\na\r\n");
+    assert!(!eval.is_read() && !eval.is_write());
+    let ping = parse(b"*1\r\n$4\r\nPING\r\n");
+    assert!(!ping.is_read() && !ping.is_write());
+}
+
+#[test]
+fn test_redis_sub_failure_policies() {
+    let budget = Budget::default();
+    let err = AsError::BackendClosedError("mock".to_string());
+    let mut failed = BytesMut::new();
+    let reply: Message = (&err).into_reply();
+    reply.save(&mut failed);
+    let bulk = |key: &[u8]| -> Message {
+        let mut data = BytesMut::new();
+        prefix::save_bulk(&[key], &mut data);
+        MessageMut::parse(&mut data).unwrap().unwrap().into()
+    };
+    // the sub of key b is failed by proxy, and the others are replied
+    let fan_out = |req: &[u8], policy: Option<SubFailure>| {
+        let cmd = Command::parse_cmd(&mut BytesMut::from(req))
+            .unwrap()
+            .unwrap();
+        if let Some(policy) = policy {
+            cmd.set_sub_failure(policy);
+        }
+        for sub in cmd.subs().unwrap() {
+            let (is_mget, key) = {
+                let sub = sub.borrow();
+                (sub.ctype.is_mget(), sub.req.nth(1).unwrap().to_vec())
+            };
+            if key == b"b" {
+                sub.set_error(&err);
+            } else if is_mget {
+                sub.set_reply(bulk(&key));
+            } else {
+                sub.set_reply(1usize);
+            }
+        }
+        assert!(cmd.is_done());
+        cmd
+    };
+    let merged = |cmd: &Cmd| {
+        let mut buf = BytesMut::new();
+        cmd.borrow().reply_cmd(&mut buf).unwrap();
+        buf.to_vec()
+    };
+    let mget: &[u8] = b"*4\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n";
+    let del: &[u8] = b"*4\r\n$3\r\nDEL\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n";
+    let mset: &[u8] =
+        b"*7\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n$1\r\nc\r\n$1\r\n3\r\n";
+
+    // the error is carried inline by MGET without policy
+    let mut inline = b"*3\r\n$1\r\na\r\n".to_vec();
+    inline.extend_from_slice(&failed);
+    inline.extend_from_slice(b"$1\r\nc\r\n");
+    assert_eq!(merged(&fan_out(mget, None)), inline);
+    assert_eq!(merged(&fan_out(del, None)), failed.to_vec());
+
+    // fail_fast
+    let cmd = fan_out(mget, Some(SubFailure::FailFast));
+    assert!(cmd.retry_failed_subs(&budget).is_empty());
+    assert_eq!(merged(&cmd), failed.to_vec());
+    assert_eq!(
+        merged(&fan_out(del, Some(SubFailure::FailFast))),
+        failed.to_vec()
+    );
+
+    // best_effort
+    assert_eq!(
+        merged(&fan_out(mget, Some(SubFailure::BestEffort))),
+        b"*3\r\n$1\r\na\r\n$-1\r\n$1\r\nc\r\n".to_vec()
+    );
+    assert_eq!(
+        merged(&fan_out(del, Some(SubFailure::BestEffort))),
+        b":2\r\n".to_vec()
+    );
+    // MSET can't be partial
+    assert_eq!(
+        merged(&fan_out(mset, Some(SubFailure::BestEffort))),
+        failed.to_vec()
+    );
+
+    // retry succeeds
+    let cmd = fan_out(mget, Some(SubFailure::Retry));
+    let retried = cmd.retry_failed_subs(&budget);
+    assert_eq!(retried.len(), 1);
+    assert!(!cmd.is_done());
+    retried[0].set_reply(bulk(b"b"));
+    assert!(cmd.is_done());
+    assert_eq!(
+        merged(&cmd),
+        b"*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n".to_vec()
+    );
+
+    // retry fails again, and fails fast
+    let cmd = fan_out(del, Some(SubFailure::Retry));
+    let retried = cmd.retry_failed_subs(&budget);
+    assert_eq!(retried.len(), 1);
+    retried[0].set_error(&err);
+    assert!(cmd.is_done());
+    assert!(cmd.retry_failed_subs(&budget).is_empty());
+    assert_eq!(merged(&cmd), failed.to_vec());
+}
+
+#[test]
+fn test_redis_help_subcommands() {
+    fn parse(args: &[&[u8]]) -> Cmd {
+        let mut src = BytesMut::new();
+        prefix::save_array_head(args.len(), &mut src);
+        for arg in args {
+            prefix::save_bulk(&[*arg], &mut src);
+        }
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    }
+
+    fn help(args: &[&[u8]]) -> Vec<Vec<u8>> {
+        let cmd = parse(args);
+        assert!(cmd.borrow().is_done());
+        assert!(!cmd.borrow().is_error());
+        let reply = cmd.borrow().reply.clone().unwrap();
+        match &reply.rtype {
+            RespType::Array(_, items) => {
+                assert!(items.iter().all(|x| matches!(x, RespType::String(_))))
+            }
+            _ => panic!("help must be replied as array"),
+        }
+        (0..)
+            .map_while(|i| reply.nth(i))
+            .map(|x| x.to_vec())
+            .collect()
+    }
+
+    let lines = help(&[b"OBJECT", b"HELP"]);
+    assert!(!lines.is_empty());
+    assert!(lines[0].starts_with(b"OBJECT"));
+    assert_eq!(help(&[b"object", b"help"]), lines);
+    for family in &[&b"CLIENT"[..], b"COMMAND", b"CLUSTER", b"CONFIG", b"PROXY"] {
+        let lines = help(&[*family, b"HELP"]);
+        assert!(lines[0].starts_with(family));
+    }
+
+    // only the bare HELP subcommand is answered
+    let cmd = parse(&[b"OBJECT", b"HELP", b"extra"]);
+    assert!(help::reply(&cmd.borrow().req).is_none());
+    let cmd = parse(&[b"GET", b"HELP"]);
+    assert!(!cmd.borrow().is_done());
+}
+
+#[test]
+fn test_redis_node_codec_protocols() {
+    let cc = ClusterConfig {
+        servers: vec![
+            "127.0.0.1:7001:10 redis-1".to_string(),
+            "127.0.0.1:7002:10 redis-2".to_string(),
+        ],
+        backend_protocols: vec!["redis-2 resp3".to_string()],
+        ..Default::default()
+    };
+    let get = || {
+        Command::parse_cmd(&mut BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..]))
+            .unwrap()
+            .unwrap()
+    };
+    let mut resp2 = <Cmd as Request>::back_codec(&cc, "127.0.0.1:7001");
+    let mut resp3 = <Cmd as Request>::back_codec(&cc, "127.0.0.1:7002");
+    let (mut dst2, mut dst3) = (BytesMut::new(), BytesMut::new());
+    for _ in 0..2 {
+        resp2.encode(get(), &mut dst2).unwrap();
+        resp3.encode(get(), &mut dst3).unwrap();
+    }
+    let get_a = &b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..];
+    assert_eq!(&dst2[..], [get_a, get_a].concat().as_slice());
+    // HELLO 3 only once ahead of the first command
+    assert_eq!(&dst3[..], [BYTES_HELLO_3, get_a, get_a].concat().as_slice());
+
+    // the reply of HELLO is never passed on, even if it's read in pieces
+    let mut src = BytesMut::from(&b"%1\r\n$6\r\nserver\r\n"[..]);
+    assert!(resp3.decode(&mut src).unwrap().is_none());
+    src.extend_from_slice(b"$5\r\nredis\r\n_\r\n$1\r\nb\r\n");
+    let reply = resp3.decode(&mut src).unwrap().unwrap();
+    assert_eq!(&reply.data[..], b"_\r\n");
+    let reply = resp3.decode(&mut src).unwrap().unwrap();
+    assert_eq!(&reply.data[..], b"$1\r\nb\r\n");
+
+    let mut src = BytesMut::from(&b"$-1\r\n"[..]);
+    let reply = resp2.decode(&mut src).unwrap().unwrap();
+    assert_eq!(&reply.data[..], b"$-1\r\n");
+
+    // the backend can't speak RESP3
+    let mut refused = <Cmd as Request>::back_codec(&cc, "127.0.0.1:7002");
+    refused.encode(get(), &mut BytesMut::new()).unwrap();
+    let mut src = BytesMut::from(&b"-ERR unknown command 'HELLO'\r\n"[..]);
+    assert!(matches!(
+        refused.decode(&mut src),
+        Err(AsError::ReplyProtocolError(_))
+    ));
+}
+
+#[test]
+fn test_redis_diag_commands() {
+    let parse = |req: &[u8]| {
+        Command::parse_cmd(&mut BytesMut::from(req))
+            .unwrap()
+            .unwrap()
+    };
+    for req in &[
+        &b"*1\r\n$6\r\nLOLWUT\r\n"[..],
+        &b"*3\r\n$6\r\nlolwut\r\n$7\r\nVERSION\r\n$1\r\n5\r\n"[..],
+    ] {
+        let lolwut = parse(req);
+        assert!(lolwut.borrow().is_done());
+        assert!(!lolwut.borrow().is_error());
+        let reply = lolwut.borrow().reply.clone().unwrap();
+        assert!(matches!(reply.rtype, RespType::Bulk(_, _)));
+        let banner = reply.nth(0).unwrap_or_default().to_vec();
+        assert!(!banner.is_empty());
+        assert!(String::from_utf8_lossy(&banner).contains(crate::ASTER_VERSION));
+    }
+
+    let sleep = parse(b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n0\r\n");
+    assert!(sleep.borrow().is_done());
+    assert_eq!(&sleep.borrow().reply.as_ref().unwrap().data[..], b"+OK\r\n");
+    for req in &[
+        &b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n1\r\n"[..],
+        &b"*2\r\n$5\r\nDEBUG\r\n$6\r\nOBJECT\r\n"[..],
+    ] {
+        assert!(!parse(req).borrow().is_done());
+    }
+}
@@ -1,168 +1,445 @@
-use crate::protocol::redis::resp::Message;
+use crate::protocol::redis::resp::{Message, RespType};
+use crate::protocol::redis::resp::{RESP_BIG_NUMBER, RESP_BOOLEAN, RESP_DOUBLE, RESP_NULL};
 
+use bitflags::bitflags;
 use hashbrown::HashMap;
 
-use crate::protocol::CmdType;
+use crate::protocol::{CmdType, Merge};
+
+bitflags! {
+    /// the attributes of command in CMD_FLAGS, CmdType is derived from them.
+    pub struct CommandFlags: u16 {
+        const WRITE        = 0b000_000_000_001;
+        const READONLY     = 0b000_000_000_010;
+        // denied unless routed to admin node
+        const ADMIN        = 0b000_000_000_100;
+        // may hold the backend connection shared by all the clients, e.g.: BLPOP
+        const BLOCKING     = 0b000_000_001_000;
+        const PUBSUB       = 0b000_000_010_000;
+        // answered by proxy itself or sent without key, e.g.: PING
+        const CTRL         = 0b000_000_100_000;
+        // split into a sub command by each key
+        const MULTI_KEY    = 0b000_001_000_000;
+        // each key is followed by its value, e.g.: MSET
+        const KEY_VALUE    = 0b000_010_000_000;
+        // each sub is sent as GET, e.g.: MGET
+        const FETCH_VALUES = 0b000_100_000_000;
+        // keys are given by numkeys, e.g.: EVAL
+        const MOVABLE_KEYS = 0b001_000_000_000;
+        // rejected by proxy, e.g.: the commands over the whole keyspace
+        const UNSUPPORTED  = 0b010_000_000_000;
+        // may break the whole backend, denied unless given by allow_dangerous, e.g.: SHUTDOWN
+        const DANGEROUS    = 0b100_000_000_000;
+        // the first two arguments are keys, which must hash the same, e.g.: SMOVE src dst
+        const TWO_KEYS     = 0b1_000_000_000_000;
+    }
+}
+
+impl CommandFlags {
+    /// the flags of the command, empty for the unknown one.
+    pub fn of(name: &[u8]) -> CommandFlags {
+        CMD_FLAGS
+            .get(name)
+            .cloned()
+            .unwrap_or_else(CommandFlags::empty)
+    }
+
+    /// the flags reported by COMMAND INFO, ctrl commands are answered by proxy itself.
+    pub fn names(self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.contains(CommandFlags::WRITE) {
+            names.push("write");
+        }
+        if self.contains(CommandFlags::READONLY) {
+            names.push("readonly");
+        }
+        if self.contains(CommandFlags::CTRL) && !self.contains(CommandFlags::BLOCKING) {
+            names.push("fast");
+        }
+        if self.contains(CommandFlags::ADMIN) {
+            names.push("admin");
+        }
+        if self.contains(CommandFlags::BLOCKING) {
+            names.push("blocking");
+        }
+        if self.contains(CommandFlags::PUBSUB) {
+            names.push("pubsub");
+        }
+        if self.contains(CommandFlags::MOVABLE_KEYS) {
+            names.push("movablekeys");
+        }
+        names
+    }
+}
+
+impl From<CommandFlags> for CmdType {
+    fn from(flags: CommandFlags) -> CmdType {
+        if flags.contains(CommandFlags::UNSUPPORTED) {
+            CmdType::NotSupport
+        } else if flags.contains(CommandFlags::ADMIN) {
+            CmdType::Admin
+        } else if flags.contains(CommandFlags::CTRL) {
+            CmdType::Ctrl
+        } else if flags.contains(CommandFlags::MOVABLE_KEYS) {
+            CmdType::Eval
+        } else if flags.contains(CommandFlags::MULTI_KEY) {
+            if flags.contains(CommandFlags::KEY_VALUE) {
+                CmdType::MSet
+            } else if flags.contains(CommandFlags::FETCH_VALUES) {
+                CmdType::MGet
+            } else if flags.contains(CommandFlags::WRITE) {
+                CmdType::Del
+            } else {
+                CmdType::Exists
+            }
+        } else if flags.contains(CommandFlags::WRITE) {
+            CmdType::Write
+        } else if flags.contains(CommandFlags::READONLY) {
+            CmdType::Read
+        } else {
+            CmdType::NotSupport
+        }
+    }
+}
 
 lazy_static! {
-    pub static ref CMD_TYPE: HashMap<&'static [u8], CmdType> = {
+    /// the fan-out commands split into subs by keys, with the strategy to merge their replies.
+    pub static ref CMD_MERGE: HashMap<&'static [u8], Merge> = {
+        let mut hmap = HashMap::new();
+        hmap.insert(&b"DEL"[..], Merge::SumIntegers);
+        hmap.insert(&b"UNLINK"[..], Merge::SumIntegers);
+        hmap.insert(&b"EXISTS"[..], Merge::SumIntegers);
+        hmap.insert(&b"TOUCH"[..], Merge::SumIntegers);
+        hmap.insert(&b"MGET"[..], Merge::ConcatArray);
+        hmap.insert(&b"MSET"[..], Merge::AllOk);
+        hmap
+    };
+
+    /// the types of reply (by the leading byte of RESP) the command may get from backend,
+    /// which finds out the replies shifted by a misbehaving backend. Error reply is always
+    /// expected, and the command not declared (e.g.: SPOP with or without count) is never
+    /// checked. The subs of fan-out command are sent by its own name, e.g.: MGET a.
+    pub static ref CMD_REPLY: HashMap<&'static [u8], &'static [u8]> = {
+        let mut hmap = HashMap::new();
+        // bulk reply
+        hmap.insert(&b"GET"[..], &b"$"[..]);
+        hmap.insert(&b"GETSET"[..], &b"$"[..]);
+        hmap.insert(&b"GETRANGE"[..], &b"$"[..]);
+        hmap.insert(&b"SUBSTR"[..], &b"$"[..]);
+        hmap.insert(&b"HGET"[..], &b"$"[..]);
+        hmap.insert(&b"LINDEX"[..], &b"$"[..]);
+        hmap.insert(&b"DUMP"[..], &b"$"[..]);
+        hmap.insert(&b"ECHO"[..], &b"$"[..]);
+        hmap.insert(&b"INCRBYFLOAT"[..], &b"$"[..]);
+        hmap.insert(&b"HINCRBYFLOAT"[..], &b"$"[..]);
+        hmap.insert(&b"ZSCORE"[..], &b"$"[..]);
+
+        // integer reply
+        hmap.insert(&b"INCR"[..], &b":"[..]);
+        hmap.insert(&b"DECR"[..], &b":"[..]);
+        hmap.insert(&b"INCRBY"[..], &b":"[..]);
+        hmap.insert(&b"DECRBY"[..], &b":"[..]);
+        hmap.insert(&b"APPEND"[..], &b":"[..]);
+        hmap.insert(&b"STRLEN"[..], &b":"[..]);
+        hmap.insert(&b"SETNX"[..], &b":"[..]);
+        hmap.insert(&b"SETRANGE"[..], &b":"[..]);
+        hmap.insert(&b"GETBIT"[..], &b":"[..]);
+        hmap.insert(&b"SETBIT"[..], &b":"[..]);
+        hmap.insert(&b"BITCOUNT"[..], &b":"[..]);
+        hmap.insert(&b"BITPOS"[..], &b":"[..]);
+        hmap.insert(&b"HSET"[..], &b":"[..]);
+        hmap.insert(&b"HSETNX"[..], &b":"[..]);
+        hmap.insert(&b"HDEL"[..], &b":"[..]);
+        hmap.insert(&b"HLEN"[..], &b":"[..]);
+        hmap.insert(&b"HSTRLEN"[..], &b":"[..]);
+        hmap.insert(&b"HEXISTS"[..], &b":"[..]);
+        hmap.insert(&b"HINCRBY"[..], &b":"[..]);
+        hmap.insert(&b"LLEN"[..], &b":"[..]);
+        hmap.insert(&b"LPUSH"[..], &b":"[..]);
+        hmap.insert(&b"RPUSH"[..], &b":"[..]);
+        hmap.insert(&b"LPUSHX"[..], &b":"[..]);
+        hmap.insert(&b"RPUSHX"[..], &b":"[..]);
+        hmap.insert(&b"SADD"[..], &b":"[..]);
+        hmap.insert(&b"SREM"[..], &b":"[..]);
+        hmap.insert(&b"SCARD"[..], &b":"[..]);
+        hmap.insert(&b"SISMEMBER"[..], &b":"[..]);
+        hmap.insert(&b"ZCARD"[..], &b":"[..]);
+        hmap.insert(&b"ZCOUNT"[..], &b":"[..]);
+        hmap.insert(&b"ZREM"[..], &b":"[..]);
+        hmap.insert(&b"EXPIRE"[..], &b":"[..]);
+        hmap.insert(&b"EXPIREAT"[..], &b":"[..]);
+        hmap.insert(&b"EXPIRETIME"[..], &b":"[..]);
+        hmap.insert(&b"PEXPIRE"[..], &b":"[..]);
+        hmap.insert(&b"PEXPIREAT"[..], &b":"[..]);
+        hmap.insert(&b"PEXPIRETIME"[..], &b":"[..]);
+        hmap.insert(&b"PERSIST"[..], &b":"[..]);
+        hmap.insert(&b"TTL"[..], &b":"[..]);
+        hmap.insert(&b"PTTL"[..], &b":"[..]);
+        hmap.insert(&b"PFADD"[..], &b":"[..]);
+        hmap.insert(&b"PFCOUNT"[..], &b":"[..]);
+        hmap.insert(&b"DEL"[..], &b":"[..]);
+        hmap.insert(&b"UNLINK"[..], &b":"[..]);
+        hmap.insert(&b"EXISTS"[..], &b":"[..]);
+        hmap.insert(&b"TOUCH"[..], &b":"[..]);
+
+        // status reply
+        hmap.insert(&b"SETEX"[..], &b"+"[..]);
+        hmap.insert(&b"PSETEX"[..], &b"+"[..]);
+        hmap.insert(&b"LSET"[..], &b"+"[..]);
+        hmap.insert(&b"LTRIM"[..], &b"+"[..]);
+        hmap.insert(&b"HMSET"[..], &b"+"[..]);
+        hmap.insert(&b"PFMERGE"[..], &b"+"[..]);
+        hmap.insert(&b"MSET"[..], &b"+"[..]);
+
+        // array reply
+        hmap.insert(&b"MGET"[..], &b"*"[..]);
+        hmap.insert(&b"HGETALL"[..], &b"*"[..]);
+        hmap.insert(&b"HKEYS"[..], &b"*"[..]);
+        hmap.insert(&b"HVALS"[..], &b"*"[..]);
+        hmap.insert(&b"HMGET"[..], &b"*"[..]);
+        hmap.insert(&b"LRANGE"[..], &b"*"[..]);
+        hmap.insert(&b"SMEMBERS"[..], &b"*"[..]);
+        hmap.insert(&b"ZRANGE"[..], &b"*"[..]);
+        hmap.insert(&b"ZREVRANGE"[..], &b"*"[..]);
+        hmap.insert(&b"ZRANGEBYSCORE"[..], &b"*"[..]);
+        hmap.insert(&b"ZREVRANGEBYSCORE"[..], &b"*"[..]);
+        hmap.insert(&b"BITFIELD"[..], &b"*"[..]);
+        hmap.insert(&b"BITFIELD_RO"[..], &b"*"[..]);
+
+        // SET with GET option is replied with bulk, and PING with message too
+        hmap.insert(&b"SET"[..], &b"+$"[..]);
+        hmap.insert(&b"PING"[..], &b"+$"[..]);
+        hmap
+    };
+
+    /// the flags of each command, which the policies of proxy are enforced by.
+    pub static ref CMD_FLAGS: HashMap<&'static [u8], CommandFlags> = {
         let mut hmap = HashMap::new();
 
         // special commands
-        hmap.insert(&b"DEL"[..], CmdType::Del);
-        hmap.insert(&b"UNLINK"[..], CmdType::Del);
-        hmap.insert(&b"DUMP"[..], CmdType::Read);
-        hmap.insert(&b"EXISTS"[..], CmdType::Exists);
-        hmap.insert(&b"EXPIRE"[..], CmdType::Write);
-        hmap.insert(&b"EXPIREAT"[..], CmdType::Write);
-        hmap.insert(&b"KEYS"[..], CmdType::NotSupport);
-        hmap.insert(&b"MIGRATE"[..], CmdType::NotSupport);
-        hmap.insert(&b"MOVE"[..], CmdType::NotSupport);
-        hmap.insert(&b"OBJECT"[..], CmdType::NotSupport);
-        hmap.insert(&b"PERSIST"[..], CmdType::Write);
-        hmap.insert(&b"PEXPIRE"[..], CmdType::Write);
-        hmap.insert(&b"PEXPIREAT"[..], CmdType::Write);
-        hmap.insert(&b"PTTL"[..], CmdType::Read);
-        hmap.insert(&b"RANDOMKEY"[..], CmdType::NotSupport);
-        hmap.insert(&b"RENAME"[..], CmdType::NotSupport);
-        hmap.insert(&b"RENAMENX"[..], CmdType::NotSupport);
-        hmap.insert(&b"RESTORE"[..], CmdType::Write);
-        hmap.insert(&b"SCAN"[..], CmdType::NotSupport);
-        hmap.insert(&b"SORT"[..], CmdType::Write);
-        hmap.insert(&b"TTL"[..], CmdType::Read);
-        hmap.insert(&b"TYPE"[..], CmdType::Read);
-        hmap.insert(&b"WAIT"[..], CmdType::NotSupport);
+        hmap.insert(&b"DEL"[..], CommandFlags::WRITE | CommandFlags::MULTI_KEY);
+        hmap.insert(&b"UNLINK"[..], CommandFlags::WRITE | CommandFlags::MULTI_KEY);
+        hmap.insert(&b"DUMP"[..], CommandFlags::READONLY);
+        hmap.insert(&b"EXISTS"[..], CommandFlags::READONLY | CommandFlags::MULTI_KEY);
+        hmap.insert(&b"EXPIRE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"EXPIREAT"[..], CommandFlags::WRITE);
+        hmap.insert(&b"EXPIRETIME"[..], CommandFlags::READONLY);
+        hmap.insert(&b"KEYS"[..], CommandFlags::READONLY | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"MIGRATE"[..], CommandFlags::WRITE | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"MOVE"[..], CommandFlags::WRITE | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"OBJECT"[..], CommandFlags::READONLY | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"PERSIST"[..], CommandFlags::WRITE);
+        hmap.insert(&b"PEXPIRE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"PEXPIREAT"[..], CommandFlags::WRITE);
+        hmap.insert(&b"PEXPIRETIME"[..], CommandFlags::READONLY);
+        hmap.insert(&b"PTTL"[..], CommandFlags::READONLY);
+        hmap.insert(&b"RANDOMKEY"[..], CommandFlags::READONLY | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"RENAME"[..], CommandFlags::WRITE | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"RENAMENX"[..], CommandFlags::WRITE | CommandFlags::TWO_KEYS);
+        hmap.insert(&b"RESTORE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SCAN"[..], CommandFlags::READONLY | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"SORT"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SORT_RO"[..], CommandFlags::READONLY);
+        hmap.insert(&b"TOUCH"[..], CommandFlags::READONLY | CommandFlags::MULTI_KEY);
+        hmap.insert(&b"TTL"[..], CommandFlags::READONLY);
+        hmap.insert(&b"TYPE"[..], CommandFlags::READONLY);
+        hmap.insert(&b"WAIT"[..], CommandFlags::BLOCKING | CommandFlags::UNSUPPORTED);
 
         // string key
-        hmap.insert(&b"APPEND"[..], CmdType::Write);
-        hmap.insert(&b"BITCOUNT"[..], CmdType::Read);
-        hmap.insert(&b"BITOP"[..], CmdType::NotSupport);
-        hmap.insert(&b"BITPOS"[..], CmdType::Read);
-        hmap.insert(&b"DECR"[..], CmdType::Write);
-        hmap.insert(&b"DECRBY"[..], CmdType::Write);
-        hmap.insert(&b"GET"[..], CmdType::Read);
-        hmap.insert(&b"GETBIT"[..], CmdType::Read);
-        hmap.insert(&b"GETRANGE"[..], CmdType::Read);
-        hmap.insert(&b"GETSET"[..], CmdType::Write);
-        hmap.insert(&b"INCR"[..], CmdType::Write);
-        hmap.insert(&b"INCRBY"[..], CmdType::Write);
-        hmap.insert(&b"INCRBYFLOAT"[..], CmdType::Write);
-        hmap.insert(&b"MGET"[..], CmdType::MGet);
-        hmap.insert(&b"MSET"[..], CmdType::MSet);
-        hmap.insert(&b"MSETNX"[..], CmdType::NotSupport);
-        hmap.insert(&b"PSETEX"[..], CmdType::Write);
-        hmap.insert(&b"SET"[..], CmdType::Write);
-        hmap.insert(&b"SETBIT"[..], CmdType::Write);
-        hmap.insert(&b"SETEX"[..], CmdType::Write);
-        hmap.insert(&b"SETNX"[..], CmdType::Write);
-        hmap.insert(&b"SETRANGE"[..], CmdType::Write);
-        hmap.insert(&b"BITFIELD"[..], CmdType::Write);
-        hmap.insert(&b"STRLEN"[..], CmdType::Read);
-        hmap.insert(&b"SUBSTR"[..], CmdType::Read);
+        hmap.insert(&b"APPEND"[..], CommandFlags::WRITE);
+        hmap.insert(&b"BITCOUNT"[..], CommandFlags::READONLY);
+        hmap.insert(&b"BITOP"[..], CommandFlags::WRITE | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"BITPOS"[..], CommandFlags::READONLY);
+        hmap.insert(&b"DECR"[..], CommandFlags::WRITE);
+        hmap.insert(&b"DECRBY"[..], CommandFlags::WRITE);
+        hmap.insert(&b"GET"[..], CommandFlags::READONLY);
+        hmap.insert(&b"GETBIT"[..], CommandFlags::READONLY);
+        hmap.insert(&b"GETRANGE"[..], CommandFlags::READONLY);
+        hmap.insert(&b"GETSET"[..], CommandFlags::WRITE);
+        hmap.insert(&b"INCR"[..], CommandFlags::WRITE);
+        hmap.insert(&b"INCRBY"[..], CommandFlags::WRITE);
+        hmap.insert(&b"INCRBYFLOAT"[..], CommandFlags::WRITE);
+        hmap.insert(
+            &b"MGET"[..],
+            CommandFlags::READONLY | CommandFlags::MULTI_KEY | CommandFlags::FETCH_VALUES,
+        );
+        hmap.insert(
+            &b"MSET"[..],
+            CommandFlags::WRITE | CommandFlags::MULTI_KEY | CommandFlags::KEY_VALUE,
+        );
+        hmap.insert(&b"MSETNX"[..], CommandFlags::WRITE | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"PSETEX"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SET"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SETBIT"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SETEX"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SETNX"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SETRANGE"[..], CommandFlags::WRITE);
+        // BITFIELD is read only without SET and INCRBY, but it's never retried as read
+        hmap.insert(&b"BITFIELD"[..], CommandFlags::WRITE);
+        hmap.insert(&b"BITFIELD_RO"[..], CommandFlags::READONLY);
+        hmap.insert(&b"STRLEN"[..], CommandFlags::READONLY);
+        hmap.insert(&b"SUBSTR"[..], CommandFlags::READONLY);
 
         // hash type
-        hmap.insert(&b"HDEL"[..], CmdType::Write);
-        hmap.insert(&b"HEXISTS"[..], CmdType::Read);
-        hmap.insert(&b"HGET"[..], CmdType::Read);
-        hmap.insert(&b"HGETALL"[..], CmdType::Read);
-        hmap.insert(&b"HINCRBY"[..], CmdType::Write);
-        hmap.insert(&b"HINCRBYFLOAT"[..], CmdType::Write);
-        hmap.insert(&b"HKEYS"[..], CmdType::Read);
-        hmap.insert(&b"HLEN"[..], CmdType::Read);
-        hmap.insert(&b"HMGET"[..], CmdType::Read);
-        hmap.insert(&b"HMSET"[..], CmdType::Write);
-        hmap.insert(&b"HSET"[..], CmdType::Write);
-        hmap.insert(&b"HSETNX"[..], CmdType::Write);
-        hmap.insert(&b"HSTRLEN"[..], CmdType::Read);
-        hmap.insert(&b"HVALS"[..], CmdType::Read);
-        hmap.insert(&b"HSCAN"[..], CmdType::Read);
+        hmap.insert(&b"HDEL"[..], CommandFlags::WRITE);
+        hmap.insert(&b"HEXISTS"[..], CommandFlags::READONLY);
+        hmap.insert(&b"HGET"[..], CommandFlags::READONLY);
+        hmap.insert(&b"HGETALL"[..], CommandFlags::READONLY);
+        hmap.insert(&b"HINCRBY"[..], CommandFlags::WRITE);
+        hmap.insert(&b"HINCRBYFLOAT"[..], CommandFlags::WRITE);
+        hmap.insert(&b"HKEYS"[..], CommandFlags::READONLY);
+        hmap.insert(&b"HLEN"[..], CommandFlags::READONLY);
+        hmap.insert(&b"HMGET"[..], CommandFlags::READONLY);
+        hmap.insert(&b"HMSET"[..], CommandFlags::WRITE);
+        hmap.insert(&b"HSET"[..], CommandFlags::WRITE);
+        hmap.insert(&b"HSETNX"[..], CommandFlags::WRITE);
+        hmap.insert(&b"HSTRLEN"[..], CommandFlags::READONLY);
+        hmap.insert(&b"HVALS"[..], CommandFlags::READONLY);
+        hmap.insert(&b"HSCAN"[..], CommandFlags::READONLY);
 
         // list type
-        hmap.insert(&b"BLPOP"[..], CmdType::NotSupport);
-        hmap.insert(&b"BRPOP"[..], CmdType::NotSupport);
-        hmap.insert(&b"BRPOPLPUSH"[..], CmdType::NotSupport);
-        hmap.insert(&b"LINDEX"[..], CmdType::Read);
-        hmap.insert(&b"LINSERT"[..], CmdType::Write);
-        hmap.insert(&b"LLEN"[..], CmdType::Read);
-        hmap.insert(&b"LPOP"[..], CmdType::Write);
-        hmap.insert(&b"LPUSH"[..], CmdType::Write);
-        hmap.insert(&b"LPUSHX"[..], CmdType::Write);
-        hmap.insert(&b"LRANGE"[..], CmdType::Read);
-        hmap.insert(&b"LREM"[..], CmdType::Write);
-        hmap.insert(&b"LSET"[..], CmdType::Write);
-        hmap.insert(&b"LTRIM"[..], CmdType::Write);
-        hmap.insert(&b"RPOP"[..], CmdType::Write);
-        hmap.insert(&b"RPOPLPUSH"[..], CmdType::Write);
-        hmap.insert(&b"RPUSH"[..], CmdType::Write);
-        hmap.insert(&b"RPUSHX"[..], CmdType::Write);
+        hmap.insert(&b"BLPOP"[..], CommandFlags::WRITE | CommandFlags::BLOCKING);
+        hmap.insert(&b"BRPOP"[..], CommandFlags::WRITE | CommandFlags::BLOCKING);
+        hmap.insert(
+            &b"BRPOPLPUSH"[..],
+            CommandFlags::WRITE | CommandFlags::BLOCKING | CommandFlags::TWO_KEYS,
+        );
+        hmap.insert(
+            &b"BLMOVE"[..],
+            CommandFlags::WRITE | CommandFlags::BLOCKING | CommandFlags::TWO_KEYS,
+        );
+        hmap.insert(&b"LINDEX"[..], CommandFlags::READONLY);
+        hmap.insert(&b"LINSERT"[..], CommandFlags::WRITE);
+        hmap.insert(&b"LLEN"[..], CommandFlags::READONLY);
+        hmap.insert(&b"LMOVE"[..], CommandFlags::WRITE | CommandFlags::TWO_KEYS);
+        hmap.insert(&b"LPOP"[..], CommandFlags::WRITE);
+        hmap.insert(&b"LPUSH"[..], CommandFlags::WRITE);
+        hmap.insert(&b"LPUSHX"[..], CommandFlags::WRITE);
+        hmap.insert(&b"LRANGE"[..], CommandFlags::READONLY);
+        hmap.insert(&b"LREM"[..], CommandFlags::WRITE);
+        hmap.insert(&b"LSET"[..], CommandFlags::WRITE);
+        hmap.insert(&b"LTRIM"[..], CommandFlags::WRITE);
+        hmap.insert(&b"RPOP"[..], CommandFlags::WRITE);
+        hmap.insert(&b"RPOPLPUSH"[..], CommandFlags::WRITE | CommandFlags::TWO_KEYS);
+        hmap.insert(&b"RPUSH"[..], CommandFlags::WRITE);
+        hmap.insert(&b"RPUSHX"[..], CommandFlags::WRITE);
         // set type
-        hmap.insert(&b"SADD"[..], CmdType::Write);
-        hmap.insert(&b"SCARD"[..], CmdType::Read);
-        hmap.insert(&b"SDIFF"[..], CmdType::Read);
-        hmap.insert(&b"SDIFFSTORE"[..], CmdType::Write);
-        hmap.insert(&b"SINTER"[..], CmdType::Read);
-        hmap.insert(&b"SINTERSTORE"[..], CmdType::Write);
-        hmap.insert(&b"SISMEMBER"[..], CmdType::Read);
-        hmap.insert(&b"SMEMBERS"[..], CmdType::Read);
-        hmap.insert(&b"SMOVE"[..], CmdType::Write);
-        hmap.insert(&b"SPOP"[..], CmdType::Write);
-        hmap.insert(&b"SRANDMEMBER"[..], CmdType::Read);
-        hmap.insert(&b"SREM"[..], CmdType::Write);
-        hmap.insert(&b"SUNION"[..], CmdType::Read);
-        hmap.insert(&b"SUNIONSTORE"[..], CmdType::Write);
-        hmap.insert(&b"SSCAN"[..], CmdType::Read);
+        hmap.insert(&b"SADD"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SCARD"[..], CommandFlags::READONLY);
+        hmap.insert(&b"SDIFF"[..], CommandFlags::READONLY);
+        hmap.insert(&b"SDIFFSTORE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SINTER"[..], CommandFlags::READONLY);
+        hmap.insert(&b"SINTERSTORE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SISMEMBER"[..], CommandFlags::READONLY);
+        hmap.insert(&b"SMEMBERS"[..], CommandFlags::READONLY);
+        hmap.insert(&b"SMOVE"[..], CommandFlags::WRITE | CommandFlags::TWO_KEYS);
+        hmap.insert(&b"SPOP"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SRANDMEMBER"[..], CommandFlags::READONLY);
+        hmap.insert(&b"SREM"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SUNION"[..], CommandFlags::READONLY);
+        hmap.insert(&b"SUNIONSTORE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"SSCAN"[..], CommandFlags::READONLY);
         // zset type
-        hmap.insert(&b"ZADD"[..], CmdType::Write);
-        hmap.insert(&b"ZCARD"[..], CmdType::Read);
-        hmap.insert(&b"ZCOUNT"[..], CmdType::Read);
-        hmap.insert(&b"ZINCRBY"[..], CmdType::Write);
-        hmap.insert(&b"ZINTERSTORE"[..], CmdType::Write);
-        hmap.insert(&b"ZLEXCOUNT"[..], CmdType::Read);
-        hmap.insert(&b"ZRANGE"[..], CmdType::Read);
-        hmap.insert(&b"ZRANGEBYLEX"[..], CmdType::Read);
-        hmap.insert(&b"ZRANGEBYSCORE"[..], CmdType::Read);
-        hmap.insert(&b"ZRANK"[..], CmdType::Read);
-        hmap.insert(&b"ZREM"[..], CmdType::Write);
-        hmap.insert(&b"ZREMRANGEBYLEX"[..], CmdType::Write);
-        hmap.insert(&b"ZREMRANGEBYRANK"[..], CmdType::Write);
-        hmap.insert(&b"ZREMRANGEBYSCORE"[..], CmdType::Write);
-        hmap.insert(&b"ZREVRANGE"[..], CmdType::Read);
-        hmap.insert(&b"ZREVRANGEBYLEX"[..], CmdType::Read);
-        hmap.insert(&b"ZREVRANGEBYSCORE"[..], CmdType::Read);
-        hmap.insert(&b"ZREVRANK"[..], CmdType::Read);
-        hmap.insert(&b"ZSCORE"[..], CmdType::Read);
-        hmap.insert(&b"ZUNIONSTORE"[..], CmdType::Write);
-        hmap.insert(&b"ZSCAN"[..], CmdType::Read);
+        hmap.insert(&b"ZADD"[..], CommandFlags::WRITE);
+        hmap.insert(&b"BZPOPMIN"[..], CommandFlags::WRITE | CommandFlags::BLOCKING);
+        hmap.insert(&b"BZPOPMAX"[..], CommandFlags::WRITE | CommandFlags::BLOCKING);
+        hmap.insert(&b"ZCARD"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZCOUNT"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZINCRBY"[..], CommandFlags::WRITE);
+        hmap.insert(&b"ZINTERSTORE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"ZLEXCOUNT"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZRANGE"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZRANGEBYLEX"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZRANGEBYSCORE"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZRANK"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZREM"[..], CommandFlags::WRITE);
+        hmap.insert(&b"ZREMRANGEBYLEX"[..], CommandFlags::WRITE);
+        hmap.insert(&b"ZREMRANGEBYRANK"[..], CommandFlags::WRITE);
+        hmap.insert(&b"ZREMRANGEBYSCORE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"ZREVRANGE"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZREVRANGEBYLEX"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZREVRANGEBYSCORE"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZREVRANK"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZSCORE"[..], CommandFlags::READONLY);
+        hmap.insert(&b"ZUNIONSTORE"[..], CommandFlags::WRITE);
+        hmap.insert(&b"ZSCAN"[..], CommandFlags::READONLY);
         // hyper log type
-        hmap.insert(&b"PFADD"[..], CmdType::Write);
-        hmap.insert(&b"PFCOUNT"[..], CmdType::Read);
-        hmap.insert(&b"PFMERGE"[..], CmdType::Write);
+        hmap.insert(&b"PFADD"[..], CommandFlags::WRITE);
+        hmap.insert(&b"PFCOUNT"[..], CommandFlags::READONLY);
+        hmap.insert(&b"PFMERGE"[..], CommandFlags::WRITE);
         // geo
-        hmap.insert(&b"GEOADD"[..], CmdType::Write);
-        hmap.insert(&b"GEODIST"[..], CmdType::Read);
-        hmap.insert(&b"GEOHASH"[..], CmdType::Read);
-        hmap.insert(&b"GEOPOS"[..], CmdType::Write);
-        hmap.insert(&b"GEORADIUS"[..], CmdType::Write);
-        hmap.insert(&b"GEORADIUSBYMEMBER"[..], CmdType::Write);
+        hmap.insert(&b"GEOADD"[..], CommandFlags::WRITE);
+        hmap.insert(&b"GEODIST"[..], CommandFlags::READONLY);
+        hmap.insert(&b"GEOHASH"[..], CommandFlags::READONLY);
+        hmap.insert(&b"GEOPOS"[..], CommandFlags::WRITE);
+        hmap.insert(&b"GEORADIUS"[..], CommandFlags::WRITE);
+        hmap.insert(&b"GEORADIUSBYMEMBER"[..], CommandFlags::WRITE);
         // eval type
-        hmap.insert(&b"EVAL"[..], CmdType::Eval);
-        hmap.insert(&b"EVALSHA"[..], CmdType::NotSupport);
+        hmap.insert(&b"EVAL"[..], CommandFlags::WRITE | CommandFlags::MOVABLE_KEYS);
+        hmap.insert(
+            &b"EVALSHA"[..],
+            CommandFlags::WRITE | CommandFlags::MOVABLE_KEYS | CommandFlags::UNSUPPORTED,
+        );
+        // keys of functions are declared by numkeys as EVAL
+        hmap.insert(&b"FCALL"[..], CommandFlags::WRITE | CommandFlags::MOVABLE_KEYS);
+        hmap.insert(&b"FCALL_RO"[..], CommandFlags::READONLY | CommandFlags::MOVABLE_KEYS);
         // ctrl type
-        hmap.insert(&b"AUTH"[..], CmdType::NotSupport);
-        hmap.insert(&b"ECHO"[..], CmdType::Ctrl);
-        hmap.insert(&b"PING"[..], CmdType::Ctrl);
-        hmap.insert(&b"INFO"[..], CmdType::Ctrl);
-        hmap.insert(&b"PROXY"[..], CmdType::NotSupport);
-        hmap.insert(&b"SLOWLOG"[..], CmdType::NotSupport);
-        hmap.insert(&b"QUIT"[..], CmdType::Ctrl);
-        hmap.insert(&b"SELECT"[..], CmdType::NotSupport);
-        hmap.insert(&b"TIME"[..], CmdType::NotSupport);
-        hmap.insert(&b"CONFIG"[..], CmdType::NotSupport);
-        hmap.insert(&b"CLUSTER"[..], CmdType::Ctrl);
-        hmap.insert(&b"READONLY"[..], CmdType::Ctrl);
+        hmap.insert(&b"AUTH"[..], CommandFlags::CTRL);
+        hmap.insert(&b"ECHO"[..], CommandFlags::CTRL);
+        hmap.insert(&b"PING"[..], CommandFlags::CTRL);
+        hmap.insert(&b"INFO"[..], CommandFlags::CTRL);
+        hmap.insert(&b"PROXY"[..], CommandFlags::CTRL);
+        hmap.insert(&b"ASTER"[..], CommandFlags::CTRL);
+        hmap.insert(&b"CLIENT"[..], CommandFlags::CTRL);
+        hmap.insert(&b"SLOWLOG"[..], CommandFlags::ADMIN | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"QUIT"[..], CommandFlags::CTRL);
+        hmap.insert(&b"SELECT"[..], CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"TIME"[..], CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"CONFIG"[..], CommandFlags::ADMIN | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"CLUSTER"[..], CommandFlags::CTRL);
+        hmap.insert(&b"COMMAND"[..], CommandFlags::CTRL);
+        hmap.insert(&b"READONLY"[..], CommandFlags::CTRL);
+        hmap.insert(&b"HELLO"[..], CommandFlags::CTRL);
+        hmap.insert(&b"LOLWUT"[..], CommandFlags::CTRL);
+
+        // admin type, denied by default
+        hmap.insert(&b"WAITAOF"[..], CommandFlags::ADMIN);
+        hmap.insert(&b"FAILOVER"[..], CommandFlags::ADMIN | CommandFlags::DANGEROUS);
+        hmap.insert(&b"REPLICAOF"[..], CommandFlags::ADMIN | CommandFlags::DANGEROUS);
+        hmap.insert(&b"SLAVEOF"[..], CommandFlags::ADMIN | CommandFlags::DANGEROUS);
+        hmap.insert(&b"SHUTDOWN"[..], CommandFlags::ADMIN | CommandFlags::DANGEROUS);
+        hmap.insert(&b"DEBUG"[..], CommandFlags::ADMIN | CommandFlags::DANGEROUS);
+        hmap.insert(&b"MODULE"[..], CommandFlags::ADMIN | CommandFlags::DANGEROUS);
+        // broadcast to every backend under function_broadcast
+        hmap.insert(&b"FUNCTION"[..], CommandFlags::ADMIN);
+        // answered by the users of proxy, see proxy::acl
+        hmap.insert(&b"ACL"[..], CommandFlags::CTRL);
 
+        // pubsub type, the connection of subscriber can't be shared
+        hmap.insert(&b"PUBLISH"[..], CommandFlags::PUBSUB | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"PUBSUB"[..], CommandFlags::PUBSUB | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"SUBSCRIBE"[..], CommandFlags::PUBSUB | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"UNSUBSCRIBE"[..], CommandFlags::PUBSUB | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"PSUBSCRIBE"[..], CommandFlags::PUBSUB | CommandFlags::UNSUPPORTED);
+        hmap.insert(&b"PUNSUBSCRIBE"[..], CommandFlags::PUBSUB | CommandFlags::UNSUPPORTED);
+
+        hmap
+    };
+
+    /// the subcommands as dangerous as the commands flagged DANGEROUS, e.g.: CLUSTER RESET.
+    pub static ref CMD_DANGEROUS_SUBS: HashMap<&'static [u8], &'static [&'static [u8]]> = {
+        let mut hmap: HashMap<&'static [u8], &'static [&'static [u8]]> = HashMap::new();
+        hmap.insert(&b"CLUSTER"[..], &[&b"FAILOVER"[..], &b"RESET"[..], &b"FORGET"[..]]);
+        hmap.insert(&b"ACL"[..], &[&b"SETUSER"[..]]);
+        hmap.insert(&b"CONFIG"[..], &[&b"SET"[..]]);
         hmap
     };
+
+    /// the type of each command derived from its flags.
+    pub static ref CMD_TYPE: HashMap<&'static [u8], CmdType> = CMD_FLAGS
+        .iter()
+        .map(|(name, flags)| (*name, CmdType::from(*flags)))
+        .collect();
 }
 
 impl CmdType {
@@ -174,6 +451,11 @@ impl CmdType {
         CmdType::Write == self
     }
 
+    /// commands may change the data of backend
+    pub fn is_mutation(self) -> bool {
+        self.is_write() || self.is_mset() || self.is_del() || self.is_eval()
+    }
+
     pub fn is_mget(self) -> bool {
         CmdType::MGet == self
     }
@@ -202,6 +484,62 @@ impl CmdType {
         CmdType::Ctrl == self
     }
 
+    pub fn is_admin(self) -> bool {
+        CmdType::Admin == self
+    }
+
+    /// the arity reported by COMMAND INFO, which is the least number of args (with the name)
+    /// proxy accepts, negative as redis does for the variable ones.
+    pub fn arity(self) -> i64 {
+        match self {
+            CmdType::MSet | CmdType::Eval => -3,
+            CmdType::Read | CmdType::Write | CmdType::MGet | CmdType::Exists | CmdType::Del => -2,
+            _ => -1,
+        }
+    }
+
+    /// the first, last and step of key positions reported by COMMAND INFO, the same as the
+    /// keys of routing. Keys of EVAL are given by numkeys, which is reported as movablekeys.
+    pub fn key_spec(self) -> (i64, i64, i64) {
+        match self {
+            CmdType::Read | CmdType::Write => (1, 1, 1),
+            CmdType::MGet | CmdType::Exists | CmdType::Del => (1, -1, 1),
+            CmdType::MSet => (1, -1, 2),
+            _ => (0, 0, 0),
+        }
+    }
+
+    /// the merge strategy declared by the fan-out command, which is FirstError if not declared.
+    pub fn get_merge(msg: &Message) -> Merge {
+        msg.nth(0)
+            .and_then(|data| CMD_MERGE.get(data))
+            .cloned()
+            .unwrap_or(Merge::FirstError)
+    }
+
+    /// the reply is compatible with the request, see CMD_REPLY.
+    pub fn accept_reply(req: &Message, reply: &Message) -> bool {
+        let expected = match req.nth(0).and_then(|name| CMD_REPLY.get(name)) {
+            Some(expected) => expected,
+            None => return true,
+        };
+        let leading = match reply.rtype {
+            RespType::Error(_) => return true,
+            // the types of RESP3 are taken as the ones of RESP2 they're downgraded to
+            RespType::String(rg) => match reply.data[rg.begin()] {
+                RESP_NULL => return true,
+                RESP_BOOLEAN => b':',
+                RESP_DOUBLE | RESP_BIG_NUMBER => b'$',
+                _ => b'+',
+            },
+            RespType::Integer(_) => b':',
+            RespType::Bulk(_, _) => b'$',
+            RespType::Array(_, _) => b'*',
+            RespType::Inline(_) => return false,
+        };
+        expected.contains(&leading)
+    }
+
     pub fn get_cmd_type(msg: &Message) -> CmdType {
         if let Some(data) = msg.nth(0) {
             if let Some(ctype) = CMD_TYPE.get(data) {
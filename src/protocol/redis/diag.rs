@@ -0,0 +1,52 @@
+//! the harmless diagnostic commands answered by proxy itself, which are probed by some clients
+//! and tools (e.g.: redis-cli and the benchmarks) rather than failed as not supported:
+//!
+//! - LOLWUT [VERSION <version>]: the banner of proxy as a bulk string.
+//! - DEBUG SLEEP 0: OK at once, it's never sent to backends, and the other DEBUG subcommands
+//!   are still denied as dangerous.
+use bytes::BytesMut;
+
+use crate::protocol::redis::{Message, MessageMut};
+
+const BYTES_LOLWUT: &[u8] = b"LOLWUT";
+const BYTES_DEBUG: &[u8] = b"DEBUG";
+const BYTES_SLEEP: &[u8] = b"SLEEP";
+const BYTES_ZERO: &[u8] = b"0";
+const BYTES_REPLY_OK: &[u8] = b"+OK\r\n";
+
+fn banner() -> String {
+    format!(
+        "aster is a proxy, it can't draw the art of redis.\naster ver. {}\n",
+        crate::ASTER_VERSION
+    )
+}
+
+fn is_debug_sleep_zero(req: &Message) -> bool {
+    req.args_len() == 3
+        && req
+            .nth(0)
+            .map_or(false, |x| x.eq_ignore_ascii_case(BYTES_DEBUG))
+        && req
+            .nth(1)
+            .map_or(false, |x| x.eq_ignore_ascii_case(BYTES_SLEEP))
+        && req.nth(2) == Some(BYTES_ZERO)
+}
+
+/// the dangerous command answered by proxy without touching backend, so it's never denied.
+pub fn is_harmless(req: &Message) -> bool {
+    is_debug_sleep_zero(req)
+}
+
+/// the reply of the diagnostic command, None if it's not answered by proxy.
+pub fn reply(req: &Message) -> Option<Message> {
+    let mut buf = BytesMut::new();
+    if req.nth(0)?.eq_ignore_ascii_case(BYTES_LOLWUT) {
+        let banner = banner();
+        buf.extend_from_slice(format!("${}\r\n{}\r\n", banner.len(), banner).as_bytes());
+    } else if is_debug_sleep_zero(req) {
+        buf.extend_from_slice(BYTES_REPLY_OK);
+    } else {
+        return None;
+    }
+    MessageMut::parse(&mut buf).ok()?.map(Into::into)
+}
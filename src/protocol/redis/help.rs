@@ -0,0 +1,116 @@
+//! the HELP subcommands answered by proxy itself, e.g.: OBJECT HELP. Each tells what the proxy
+//! supports of the command family rather than the backend does, and is replied as the array of
+//! status lines like redis. The families not listed here are handled as before.
+use bytes::BytesMut;
+
+use crate::protocol::redis::{Message, MessageMut};
+
+const BYTES_HELP: &[u8] = b"HELP";
+
+const HELP_OBJECT: &[&str] = &[
+    "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "ENCODING | FREQ | IDLETIME | REFCOUNT <key>",
+    "    Not supported by aster, ask the backend owning the key (see PROXY SHARD).",
+    "HELP",
+    "    Print this help.",
+];
+
+const HELP_CLIENT: &[&str] = &[
+    "CLIENT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "KILL [ID <client-id>] [ADDR <ip:port>] [SKIPME (YES|NO)]",
+    "    Kill the connections of aster matched by all the filters on any worker thread.",
+    "HELP",
+    "    Print this help.",
+    "The other subcommands are not supported by aster.",
+];
+
+const HELP_COMMAND: &[&str] = &[
+    "COMMAND <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "COUNT",
+    "    Return the number of commands supported by aster.",
+    "INFO [<command-name> ...]",
+    "    Return the details of the commands supported by aster, all of them without names.",
+    "GETKEYS <full-command>",
+    "    Return the keys of the command, the same as routed by aster.",
+    "HELP",
+    "    Print this help.",
+];
+
+const HELP_CLUSTER: &[&str] = &[
+    "CLUSTER <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "SLOTS",
+    "    Return all the slots served by aster itself.",
+    "NODES",
+    "    Return aster itself as the nodes of all the slots.",
+    "KEYSLOT <key>",
+    "    Return the hash slot of key, in cluster mode only.",
+    "HELP",
+    "    Print this help.",
+];
+
+const HELP_CONFIG: &[&str] = &[
+    "CONFIG <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "GET | SET | RESETSTAT | REWRITE",
+    "    Not supported by aster, the config of proxy is reloaded from its file by SIGHUP.",
+    "HELP",
+    "    Print this help.",
+];
+
+const HELP_PROXY: &[&str] = &[
+    "PROXY <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+    "SHARD <key>",
+    "    Return how the key is routed.",
+    "NODES",
+    "    Return the backends and their states seen by the worker.",
+    "COMMANDS",
+    "    Return the commands known by proxy with their routing and status.",
+    "SLO STATUS",
+    "    Return the burn rates of latency objectives.",
+    "RING DIFF [SAMPLES <count>] <server> [<server> ...]",
+    "    Return how many keys would move to the servers proposed.",
+    "BARRIER [<timeout>]",
+    "    Wait for the writes of the connection to be synced by their backends.",
+    "ROUTE <node> [ONCE|CONNECTION] | ROUTE RESET",
+    "    Pin the next command or the connection to the node.",
+    "MONITOR",
+    "    Stream the commands received by proxy.",
+    "ADDNODE <addr> <weight> [<alias>] | DELNODE <node>",
+    "    Add or remove a backend at runtime, if proxy_admin is enabled.",
+    "CONFIG DUMP",
+    "    Return the effective config with secrets redacted, if proxy_admin is enabled.",
+    "HELP",
+    "    Print this help.",
+];
+
+const HELPS: &[(&[u8], &[&str])] = &[
+    (b"OBJECT", HELP_OBJECT),
+    (b"CLIENT", HELP_CLIENT),
+    (b"COMMAND", HELP_COMMAND),
+    (b"CLUSTER", HELP_CLUSTER),
+    (b"CONFIG", HELP_CONFIG),
+    (b"PROXY", HELP_PROXY),
+];
+
+/// the help lines of `${family} HELP`, None if the request is not or the family has no help of
+/// proxy.
+pub fn lines(req: &Message) -> Option<&'static [&'static str]> {
+    if req.args_len() != 2 || !req.nth(1)?.eq_ignore_ascii_case(BYTES_HELP) {
+        return None;
+    }
+    let name = req.nth(0)?;
+    HELPS
+        .iter()
+        .find(|(family, _)| name.eq_ignore_ascii_case(family))
+        .map(|(_, lines)| *lines)
+}
+
+/// the reply of `${family} HELP`, None if it's not answered by proxy.
+pub fn reply(req: &Message) -> Option<Message> {
+    let lines = lines(req)?;
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(format!("*{}\r\n", lines.len()).as_bytes());
+    for line in lines {
+        buf.extend_from_slice(format!("+{}\r\n", line).as_bytes());
+    }
+    MessageMut::parse(&mut buf).ok()?.map(Into::into)
+}
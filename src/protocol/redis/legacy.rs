@@ -0,0 +1,211 @@
+//! the shims of replies for the legacy clients of redis, toggled one by one by compat of cluster
+//! and applied by the front codec right before the reply is written:
+//!
+//! - inline_errors: the array reply containing any error (e.g.: EXEC with a failed command) is
+//!   replied by its first error as a single line, the commands before it are still applied.
+//! - no_nested_arrays: the array nested in the array reply is flattened into it, e.g.: SCAN,
+//!   XRANGE or EXEC, the nil array nested is replied as nil bulk.
+//! - resp2_only: the replies are framed by RESP2 even if the client said HELLO 3.
+//!
+//! The replies without array (and all the replies if no shim is toggled) are never reparsed.
+use bytes::BytesMut;
+
+use crate::com::{ClientCompat, ClusterConfig};
+use crate::protocol::redis::resp::RESP_ARRAY;
+use crate::protocol::redis::{Message, RespType, RespVersion};
+
+const BYTES_NIL_ARRAY: &[u8] = b"*-1\r\n";
+const BYTES_NIL_BULK: &[u8] = b"$-1\r\n";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Shims {
+    inline_errors: bool,
+    no_nested_arrays: bool,
+    resp2_only: bool,
+}
+
+impl Shims {
+    pub fn new(compat: &[ClientCompat]) -> Shims {
+        Shims {
+            inline_errors: compat.contains(&ClientCompat::InlineErrors),
+            no_nested_arrays: compat.contains(&ClientCompat::NoNestedArrays),
+            resp2_only: compat.contains(&ClientCompat::Resp2Only),
+        }
+    }
+
+    pub fn from_config(cc: &ClusterConfig) -> Shims {
+        Shims::new(&cc.compat)
+    }
+
+    /// none of the replies is rewritten.
+    pub fn is_empty(&self) -> bool {
+        *self == Shims::default()
+    }
+
+    /// the version which the replies are framed by, given the one said by the client.
+    pub fn version(&self, version: RespVersion) -> RespVersion {
+        if self.resp2_only {
+            RespVersion::Resp2
+        } else {
+            version
+        }
+    }
+
+    /// write the reply saved in src into dst by the shims of arrays.
+    pub fn rewrite(&self, mut src: BytesMut, dst: &mut BytesMut) {
+        if !self.inline_errors && !self.no_nested_arrays {
+            dst.extend_from_slice(&src);
+            return;
+        }
+        let raw = src.clone();
+        let msg = match Message::parse(&mut src) {
+            Ok(Some(msg)) => msg,
+            _ => {
+                dst.extend_from_slice(&raw);
+                return;
+            }
+        };
+        let (head, subs) = match &msg.rtype {
+            RespType::Array(head, subs) => (head, subs),
+            _ => {
+                dst.extend_from_slice(&raw);
+                return;
+            }
+        };
+        if self.inline_errors {
+            if let Some(err) = first_error(subs) {
+                msg.save_by_rtype(err, dst);
+                return;
+            }
+        }
+        // the map, set and push of RESP3 keep their shape
+        let plain = msg.data[head.begin()] == RESP_ARRAY;
+        if self.no_nested_arrays && plain && subs.iter().any(is_array) {
+            let mut leaves = Vec::new();
+            flatten(&msg, subs, &mut leaves);
+            dst.extend_from_slice(format!("*{}\r\n", leaves.len()).as_bytes());
+            for leaf in leaves {
+                match leaf {
+                    Some(leaf) => {
+                        msg.save_by_rtype(leaf, dst);
+                    }
+                    None => dst.extend_from_slice(BYTES_NIL_BULK),
+                }
+            }
+            return;
+        }
+        dst.extend_from_slice(&raw);
+    }
+}
+
+fn is_array(rtype: &RespType) -> bool {
+    match rtype {
+        RespType::Array(..) => true,
+        _ => false,
+    }
+}
+
+fn first_error(subs: &[RespType]) -> Option<&RespType> {
+    for sub in subs {
+        match sub {
+            RespType::Error(_) => return Some(sub),
+            RespType::Array(_, inner) => {
+                if let Some(err) = first_error(inner) {
+                    return Some(err);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// the leaves of subs in order, None for the nil array.
+fn flatten<'a>(msg: &Message, subs: &'a [RespType], leaves: &mut Vec<Option<&'a RespType>>) {
+    for sub in subs {
+        match sub {
+            RespType::Array(head, inner) => {
+                if &msg.data[head.begin()..head.end()] == BYTES_NIL_ARRAY {
+                    leaves.push(None);
+                } else {
+                    flatten(msg, inner, leaves);
+                }
+            }
+            _ => leaves.push(Some(sub)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rewrite(compat: &[ClientCompat], reply: &[u8]) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        Shims::new(compat).rewrite(BytesMut::from(reply), &mut dst);
+        dst.to_vec()
+    }
+
+    const EXEC: &[u8] =
+        b"*3\r\n+OK\r\n-WRONGTYPE Operation against a key\r\n*2\r\n$1\r\na\r\n*-1\r\n";
+
+    #[test]
+    fn test_shim_inline_errors() {
+        let compat = &[ClientCompat::InlineErrors];
+        assert_eq!(
+            rewrite(compat, EXEC),
+            b"-WRONGTYPE Operation against a key\r\n".to_vec()
+        );
+        // nested deeper
+        assert_eq!(
+            rewrite(compat, b"*2\r\n:1\r\n*1\r\n-ERR bad\r\n"),
+            b"-ERR bad\r\n".to_vec()
+        );
+        // the others are kept
+        let plain = b"*2\r\n$1\r\na\r\n*1\r\n$1\r\nb\r\n";
+        assert_eq!(rewrite(compat, plain), plain.to_vec());
+        assert_eq!(rewrite(compat, b"-ERR top\r\n"), b"-ERR top\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_shim_no_nested_arrays() {
+        let compat = &[ClientCompat::NoNestedArrays];
+        // the error is kept as a leaf
+        assert_eq!(
+            rewrite(compat, EXEC),
+            b"*4\r\n+OK\r\n-WRONGTYPE Operation against a key\r\n$1\r\na\r\n$-1\r\n".to_vec()
+        );
+        // SCAN
+        assert_eq!(
+            rewrite(compat, b"*2\r\n$1\r\n0\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n"),
+            b"*3\r\n$1\r\n0\r\n$1\r\na\r\n$1\r\nb\r\n".to_vec()
+        );
+        // the empty one is dropped
+        assert_eq!(
+            rewrite(compat, b"*2\r\n$1\r\n0\r\n*0\r\n"),
+            b"*1\r\n$1\r\n0\r\n".to_vec()
+        );
+        let flat = b"*2\r\n$1\r\na\r\n$-1\r\n";
+        assert_eq!(rewrite(compat, flat), flat.to_vec());
+        assert_eq!(rewrite(compat, b"*-1\r\n"), b"*-1\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_shims_together() {
+        let all = Shims::new(&[
+            ClientCompat::InlineErrors,
+            ClientCompat::NoNestedArrays,
+            ClientCompat::Resp2Only,
+        ]);
+        assert!(!all.is_empty());
+        assert_eq!(all.version(RespVersion::Resp3), RespVersion::Resp2);
+        let mut dst = BytesMut::new();
+        all.rewrite(BytesMut::from(EXEC), &mut dst);
+        assert_eq!(&dst[..], b"-WRONGTYPE Operation against a key\r\n");
+
+        let none = Shims::new(&[]);
+        assert!(none.is_empty());
+        assert_eq!(none.version(RespVersion::Resp3), RespVersion::Resp3);
+        assert_eq!(rewrite(&[], EXEC), EXEC.to_vec());
+    }
+}
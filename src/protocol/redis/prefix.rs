@@ -0,0 +1,279 @@
+use bytes::BytesMut;
+
+use crate::protocol::redis::{Message, MessageMut, RespType};
+use crate::utils::{myitoa, upper};
+
+const BYTES_CRLF: &[u8] = b"\r\n";
+const BYTES_MATCH: &[u8] = b"MATCH";
+const GLOB_SPECIAL: &[u8] = b"*?[]\\";
+
+/// save the request args as resp array with every key prefixed by namespace.
+/// return true if the reply of the request contains keys which must be stripped.
+pub fn save_with_prefix(args: &[&[u8]], prefix: &[u8], buf: &mut BytesMut) -> bool {
+    let mut name = args.get(0).map(|x| x.to_vec()).unwrap_or_default();
+    upper(&mut name);
+
+    if &name[..] == b"SCAN" {
+        save_scan(args, prefix, buf);
+        return true;
+    }
+
+    let keys = key_positions(&name, args);
+    save_array_head(args.len(), buf);
+    for (i, arg) in args.iter().enumerate() {
+        if keys.contains(&i) {
+            save_bulk(&[prefix, *arg], buf);
+        } else {
+            save_bulk(&[*arg], buf);
+        }
+    }
+    false
+}
+
+/// save the request args as resp array for capture, every key is replaced by its digest if
+/// hash_keys and the other args are truncated into max_value bytes.
+pub fn save_with_mask(
+    args: &[&[u8]],
+    hash_keys: bool,
+    max_value: Option<usize>,
+    buf: &mut BytesMut,
+) {
+    let mut name = args.get(0).map(|x| x.to_vec()).unwrap_or_default();
+    upper(&mut name);
+
+    let keys = key_positions(&name, args);
+    save_array_head(args.len(), buf);
+    for (i, arg) in args.iter().enumerate() {
+        if i == 0 {
+            save_bulk(&[*arg], buf);
+        } else if keys.contains(&i) {
+            if hash_keys {
+                save_bulk(&[&hash_key(arg)[..]], buf);
+            } else {
+                save_bulk(&[*arg], buf);
+            }
+        } else {
+            let len = max_value
+                .map(|x| x.min(arg.len()))
+                .unwrap_or_else(|| arg.len());
+            save_bulk(&[&arg[..len]], buf);
+        }
+    }
+}
+
+// the same key is always hashed into the same digest to keep the access pattern
+pub(crate) fn hash_key(key: &[u8]) -> Vec<u8> {
+    let md5::Digest(bs) = md5::compute(key);
+    bs[..8]
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>()
+        .into_bytes()
+}
+
+// only command accept keys at the known positions is prefixed
+fn key_positions(name: &[u8], args: &[&[u8]]) -> Vec<usize> {
+    if args.len() < 2 {
+        return Vec::new();
+    }
+    match name {
+        b"MGET" | b"DEL" | b"UNLINK" | b"EXISTS" | b"TOUCH" => (1..args.len()).collect(),
+        b"MSET" | b"MSETNX" => (1..args.len()).step_by(2).collect(),
+        b"SUNION" | b"SUNIONSTORE" | b"SINTER" | b"SINTERSTORE" | b"SDIFF" | b"SDIFFSTORE"
+        | b"PFCOUNT" | b"PFMERGE" => (1..args.len()).collect(),
+        b"SMOVE" | b"RENAMENX" | b"RPOPLPUSH" | b"BRPOPLPUSH" | b"LMOVE" | b"BLMOVE" => {
+            vec![1, 2]
+        }
+        b"ZUNIONSTORE" | b"ZINTERSTORE" => {
+            let mut keys = vec![1];
+            keys.extend(numkeys_positions(args));
+            keys
+        }
+        b"EVAL" | b"FCALL" | b"FCALL_RO" => numkeys_positions(args),
+        _ => vec![1],
+    }
+}
+
+// keys followed the numkeys argument at position 2, e.g.: EVAL script numkeys key [key ...]
+fn numkeys_positions(args: &[&[u8]]) -> Vec<usize> {
+    let count = args
+        .get(2)
+        .and_then(|x| btoi::btoi::<usize>(x).ok())
+        .unwrap_or(0);
+    (3..args.len().min(3 + count)).collect()
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count], the pattern is always limited into namespace
+fn save_scan(args: &[&[u8]], prefix: &[u8], buf: &mut BytesMut) {
+    let escaped = escape_glob(prefix);
+    let pos = args.iter().position(|x| {
+        let mut arg = x.to_vec();
+        upper(&mut arg);
+        &arg[..] == BYTES_MATCH
+    });
+
+    match pos {
+        Some(pos) if pos + 1 < args.len() => {
+            save_array_head(args.len(), buf);
+            for (i, arg) in args.iter().enumerate() {
+                if i == pos + 1 {
+                    save_bulk(&[&escaped[..], *arg], buf);
+                } else {
+                    save_bulk(&[*arg], buf);
+                }
+            }
+        }
+        _ => {
+            save_array_head(args.len() + 2, buf);
+            for arg in args {
+                save_bulk(&[*arg], buf);
+            }
+            save_bulk(&[BYTES_MATCH], buf);
+            save_bulk(&[&escaped[..], &b"*"[..]], buf);
+        }
+    }
+}
+
+fn escape_glob(prefix: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(prefix.len());
+    for b in prefix {
+        if GLOB_SPECIAL.contains(b) {
+            escaped.push(b'\\');
+        }
+        escaped.push(*b);
+    }
+    escaped
+}
+
+pub(crate) fn save_array_head(len: usize, buf: &mut BytesMut) {
+    buf.extend_from_slice(b"*");
+    myitoa(len, buf);
+    buf.extend_from_slice(BYTES_CRLF);
+}
+
+pub(crate) fn save_bulk(parts: &[&[u8]], buf: &mut BytesMut) {
+    let len = parts.iter().map(|x| x.len()).sum();
+    buf.extend_from_slice(b"$");
+    myitoa(len, buf);
+    buf.extend_from_slice(BYTES_CRLF);
+    for part in parts {
+        buf.extend_from_slice(part);
+    }
+    buf.extend_from_slice(BYTES_CRLF);
+}
+
+/// strip the namespace from keys of SCAN reply: *2 cursor *N [key ...]
+pub fn strip_scan_reply(reply: Message, prefix: &[u8]) -> Message {
+    let mut buf = BytesMut::new();
+    if let RespType::Array(_, items) = &reply.rtype {
+        if let (Some(cursor), Some(RespType::Array(_, keys))) = (items.get(0), items.get(1)) {
+            save_array_head(2, &mut buf);
+            reply.save_by_rtype(cursor, &mut buf);
+            save_array_head(keys.len(), &mut buf);
+            for key in keys {
+                if let RespType::Bulk(_, body) = key {
+                    let data = &reply.data.as_ref()[body.begin()..body.end() - 2];
+                    if data.starts_with(prefix) {
+                        save_bulk(&[&data[prefix.len()..]], &mut buf);
+                        continue;
+                    }
+                }
+                reply.save_by_rtype(key, &mut buf);
+            }
+        }
+    }
+
+    if buf.is_empty() {
+        return reply;
+    }
+    match MessageMut::parse(&mut buf) {
+        Ok(Some(msg)) => msg.into(),
+        _ => reply,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn saved(args: &[&str], prefix: &[u8]) -> (Vec<u8>, bool) {
+        let args: Vec<_> = args.iter().map(|x| x.as_bytes()).collect();
+        let mut buf = BytesMut::new();
+        let strip = save_with_prefix(&args, prefix, &mut buf);
+        (buf.to_vec(), strip)
+    }
+
+    #[test]
+    fn test_prefix_get_set() {
+        let (data, strip) = saved(&["GET", "a"], b"t1:");
+        assert!(!strip);
+        assert_eq!(&data[..], &b"*2\r\n$3\r\nGET\r\n$4\r\nt1:a\r\n"[..]);
+
+        let (data, _) = saved(&["set", "a", "b"], b"t1:");
+        assert_eq!(
+            &data[..],
+            &b"*3\r\n$3\r\nset\r\n$4\r\nt1:a\r\n$1\r\nb\r\n"[..]
+        );
+
+        let (data, _) = saved(&["EVAL", "s", "1", "a", "b"], b"t1:");
+        assert_eq!(
+            &data[..],
+            &b"*5\r\n$4\r\nEVAL\r\n$1\r\ns\r\n$1\r\n1\r\n$4\r\nt1:a\r\n$1\r\nb\r\n"[..]
+        );
+
+        // the directions of LMOVE are never prefixed
+        let (data, _) = saved(&["LMOVE", "a", "b", "LEFT", "RIGHT"], b"t1:");
+        assert_eq!(
+            &data[..],
+            &b"*5\r\n$5\r\nLMOVE\r\n$4\r\nt1:a\r\n$4\r\nt1:b\r\n$4\r\nLEFT\r\n$5\r\nRIGHT\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_mask_keys_and_values() {
+        let args: Vec<&[u8]> = vec![b"MSET", b"a", b"hello", b"b", b"hi"];
+        let mut buf = BytesMut::new();
+        save_with_mask(&args, false, Some(2), &mut buf);
+        assert_eq!(
+            &buf[..],
+            &b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$2\r\nhe\r\n$1\r\nb\r\n$2\r\nhi\r\n"[..]
+        );
+
+        let args: Vec<&[u8]> = vec![b"GET", b"a"];
+        let mut buf = BytesMut::new();
+        save_with_mask(&args, true, None, &mut buf);
+        let hashed = hash_key(b"a");
+        assert_eq!(hashed.len(), 16);
+        assert_ne!(&hashed[..], &b"a"[..]);
+        let mut expect = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$16\r\n"[..]);
+        expect.extend_from_slice(&hashed);
+        expect.extend_from_slice(b"\r\n");
+        assert_eq!(buf, expect);
+    }
+
+    #[test]
+    fn test_prefix_scan() {
+        let (data, strip) = saved(&["SCAN", "0"], b"t*:");
+        assert!(strip);
+        assert_eq!(
+            &data[..],
+            &b"*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nMATCH\r\n$5\r\nt\\*:*\r\n"[..]
+        );
+
+        let (data, _) = saved(&["SCAN", "0", "match", "a*"], b"t1:");
+        assert_eq!(
+            &data[..],
+            &b"*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nmatch\r\n$5\r\nt1:a*\r\n"[..]
+        );
+
+        let mut src = BytesMut::from(&b"*2\r\n$2\r\n17\r\n*2\r\n$4\r\nt1:a\r\n$5\r\nt1:bc\r\n"[..]);
+        let reply: Message = MessageMut::parse(&mut src).unwrap().unwrap().into();
+        let reply = strip_scan_reply(reply, b"t1:");
+        let mut buf = BytesMut::new();
+        reply.save(&mut buf);
+        assert_eq!(
+            &buf[..],
+            &b"*2\r\n$2\r\n17\r\n*2\r\n$1\r\na\r\n$2\r\nbc\r\n"[..]
+        );
+    }
+}
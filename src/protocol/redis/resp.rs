@@ -1,4 +1,5 @@
 use crate::com::*;
+use crate::protocol::redis::prefix::save_bulk;
 use crate::proxy::cluster::Redirect;
 use crate::utils::simdfind;
 use crate::utils::Range;
@@ -12,12 +13,32 @@ pub const RESP_ERROR: u8 = b'-';
 pub const RESP_BULK: u8 = b'$';
 pub const RESP_ARRAY: u8 = b'*';
 
+// the types of RESP3, which are replied to the connections of HELLO 3 only, they're parsed as
+// the types of RESP2 of the same framing: the simple ones as String, verbatim string as Bulk,
+// and map, set and push as Array (the map of n pairs has 2n items), whose first byte of head
+// tells the type apart.
+pub const RESP_MAP: u8 = b'%';
+pub const RESP_SET: u8 = b'~';
+pub const RESP_PUSH: u8 = b'>';
+pub const RESP_DOUBLE: u8 = b',';
+pub const RESP_NULL: u8 = b'_';
+pub const RESP_BOOLEAN: u8 = b'#';
+pub const RESP_BIG_NUMBER: u8 = b'(';
+pub const RESP_VERBATIM: u8 = b'=';
+
 pub const BYTE_CR: u8 = b'\r';
 pub const BYTE_LF: u8 = b'\n';
 
 pub const BYTES_CMD_CLUSTER_SLOTS: &[u8] = b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nSLOTS\r\n";
 pub const BYTES_CMD_CLUSTER_NODES: &[u8] = b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nNODES\r\n";
 
+/// max length of bulk string, the same as proto-max-bulk-len of redis by default.
+pub const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
+/// max depth of nested arrays, which bounds the recursion of parsing.
+pub const MAX_ARRAY_DEPTH: usize = 128;
+
+const BYTES_CRLF: &[u8] = b"\r\n";
+
 // contains Range means body cursor range [begin..end] for non-array type
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RespType {
@@ -61,7 +82,7 @@ impl MessageMut {
         }))
     }
 
-    fn parse_inner(cursor: usize, src: &[u8]) -> Result<Option<MsgPack>, AsError> {
+    fn parse_inner(cursor: usize, src: &[u8], depth: usize) -> Result<Option<MsgPack>, AsError> {
         let pos = if let Some(p) = simdfind::find_lf_simd(&src[cursor..]) {
             p
         } else {
@@ -74,12 +95,15 @@ impl MessageMut {
 
         // detect pos -1 is CR
         if src[cursor + pos - 1] != BYTE_CR {
-            // should detect inline
+            // only the whole message can be inline
+            if cursor != 0 {
+                return Err(AsError::BadMessage);
+            }
             return Self::try_parse_inline(&src[cursor..cursor + pos + 1]);
         }
 
         match src[cursor] {
-            RESP_STRING => {
+            RESP_STRING | RESP_DOUBLE | RESP_NULL | RESP_BOOLEAN | RESP_BIG_NUMBER => {
                 return Ok(Some(MsgPack {
                     rtype: RespType::String(Range::new(cursor, cursor + pos + 1)),
                     size: pos + 1,
@@ -97,7 +121,7 @@ impl MessageMut {
                     size: pos + 1,
                 }));
             }
-            RESP_BULK => {
+            RESP_BULK | RESP_VERBATIM => {
                 let csize = match btoi::btoi::<isize>(&src[cursor + 1..cursor + pos - 1]) {
                     Ok(csize) => csize,
                     Err(_err) => return Err(AsError::BadMessage),
@@ -106,18 +130,21 @@ impl MessageMut {
                 if csize == -1 {
                     return Ok(Some(MsgPack {
                         rtype: RespType::Bulk(
-                            Range::new(cursor, cursor + 5),
-                            Range::new(cursor, cursor + 5),
+                            Range::new(cursor, cursor + pos + 1),
+                            Range::new(cursor, cursor + pos + 1),
                         ),
-                        size: 5,
+                        size: pos + 1,
                     }));
-                } else if csize < 0 {
+                } else if csize < 0 || csize as usize > MAX_BULK_SIZE {
                     return Err(AsError::BadMessage);
                 }
 
                 let total_size = (pos + 1) + (csize as usize) + 2;
 
                 if src.len() >= cursor + total_size {
+                    if &src[cursor + total_size - 2..cursor + total_size] != BYTES_CRLF {
+                        return Err(AsError::BadMessage);
+                    }
                     return Ok(Some(MsgPack {
                         rtype: RespType::Bulk(
                             Range::new(cursor, cursor + pos + 1),
@@ -127,23 +154,26 @@ impl MessageMut {
                     }));
                 }
             }
-            RESP_ARRAY => {
+            RESP_ARRAY | RESP_MAP | RESP_SET | RESP_PUSH => {
                 let csize = match btoi::btoi::<isize>(&src[cursor + 1..cursor + pos - 1]) {
+                    Ok(csize) if src[cursor] == RESP_MAP => csize.saturating_mul(2),
                     Ok(csize) => csize,
                     Err(_err) => return Err(AsError::BadMessage),
                 };
-                if csize == -1 {
+                if csize == -1 && src[cursor] == RESP_ARRAY {
                     return Ok(Some(MsgPack {
-                        rtype: RespType::Array(Range::new(cursor, cursor + 5), vec![]),
-                        size: 5,
+                        rtype: RespType::Array(Range::new(cursor, cursor + pos + 1), vec![]),
+                        size: pos + 1,
                     }));
-                } else if csize < 0 {
+                } else if csize < 0 || depth >= MAX_ARRAY_DEPTH {
                     return Err(AsError::BadMessage);
                 }
                 let mut mycursor = cursor + pos + 1;
                 let mut items = Vec::new();
                 for _ in 0..csize {
-                    if let Some(MsgPack { rtype, size }) = Self::parse_inner(mycursor, &src[..])? {
+                    if let Some(MsgPack { rtype, size }) =
+                        Self::parse_inner(mycursor, &src[..], depth + 1)?
+                    {
                         mycursor += size;
                         items.push(rtype);
                     } else {
@@ -167,8 +197,62 @@ impl MessageMut {
         Ok(None)
     }
 
+    /// parse the request of client, the same as parse except for the malformed request, whose
+    /// error is the protocol error of redis (e.g.: "ERR Protocol error: invalid bulk length").
+    /// The rest of src is dropped with it, since requests can't be resynchronized in RESP.
+    pub fn parse_request(src: &mut BytesMut) -> Result<Option<MessageMut>, AsError> {
+        match Self::parse_inner(0, &src[..], 0) {
+            Ok(Some(MsgPack { size, rtype })) => {
+                let data = src.split_to(size);
+                Ok(Some(MessageMut { data, rtype }))
+            }
+            Ok(None) => Ok(None),
+            Err(_) => {
+                let reason = protocol_error(&src[..]);
+                src.clear();
+                Err(AsError::ProtocolError(reason))
+            }
+        }
+    }
+
+    /// parse the reply of backend strictly, the same as parse except that the framing must be
+    /// exact: the type of reply must be known (inline is never a reply), the integer must be
+    /// valid and simple strings must not contain CR, besides the bulk lengths, element counts
+    /// and CRLF checked by parse. The violation is ReplyProtocolError and the rest of src is
+    /// dropped with it, since any reply after it can't be trusted.
+    pub fn parse_reply(src: &mut BytesMut) -> Result<Option<MessageMut>, AsError> {
+        let rslt = Self::parse_inner(0, &src[..], 0)
+            .map_err(|_| "invalid framing")
+            .and_then(|pack| match pack {
+                Some(pack) => check_reply(&src[..], &pack.rtype).map(|_| Some(pack)),
+                None => Ok(None),
+            });
+        match rslt {
+            Ok(Some(MsgPack { size, rtype })) => {
+                let data = src.split_to(size);
+                Ok(Some(MessageMut { data, rtype }))
+            }
+            Ok(None) => Ok(None),
+            Err(reason) => {
+                src.clear();
+                Err(AsError::ReplyProtocolError(reason.to_string()))
+            }
+        }
+    }
+
+    /// parse one message (e.g.: request, reply or inline command) from the front of src.
+    ///
+    /// - `Ok(Some(msg))`: exactly the bytes of msg are consumed.
+    /// - `Ok(None)`: the message is incomplete and nothing is consumed, call it again with
+    ///   more data appended.
+    /// - `Err(_)`: the message is malformed (e.g.: bad length, missing CRLF, bulk longer than
+    ///   MAX_BULK_SIZE or arrays nested deeper than MAX_ARRAY_DEPTH), and at least the first line
+    ///   is consumed, so the parsing loop always ends. The stream should be closed since it
+    ///   can't be resynchronized.
+    ///
+    /// It never panics on any input.
     pub fn parse(src: &mut BytesMut) -> Result<Option<MessageMut>, AsError> {
-        let rslt = match Self::parse_inner(0, &src[..]) {
+        let rslt = match Self::parse_inner(0, &src[..], 0) {
             Ok(r) => r,
             Err(err) => {
                 // TODO: should change it as wrong bad command error
@@ -188,6 +272,24 @@ impl MessageMut {
 }
 
 impl MessageMut {
+    /// the count of arguments of request including the command name.
+    pub fn args_count(&self) -> usize {
+        match &self.rtype {
+            RespType::Array(_, items) => items.len(),
+            RespType::Inline(fields) => fields.len(),
+            _ => 1,
+        }
+    }
+
+    /// the size of the largest argument of request, e.g.: the value of SET.
+    pub fn max_arg_size(&self) -> usize {
+        (0..self.args_count())
+            .filter_map(|x| self.nth(x))
+            .map(|x| x.len())
+            .max()
+            .unwrap_or(0)
+    }
+
     pub fn nth_mut(&mut self, index: usize) -> Option<&mut [u8]> {
         if let Some(range) = self.get_nth_data_range(index) {
             Some(&mut self.data.as_mut()[range.begin()..range.end()])
@@ -243,9 +345,10 @@ impl MessageMut {
                 if len == 0 {
                     return Some(*rng);
                 }
-                if len > 0 && self.data[len - 1] == BYTE_LF {
+                // trim the line ending of the last field
+                if len > 0 && self.data[end - 1] == BYTE_LF {
                     end -= 1;
-                    if len > 1 && self.data[len - 2] == BYTE_CR {
+                    if len > 1 && self.data[end - 1] == BYTE_CR {
                         end -= 1;
                     }
                 }
@@ -261,6 +364,77 @@ struct MsgPack {
     size: usize,
 }
 
+// the reason of the malformed request at the front of src in the wording of redis, the
+// request must be an array of bulk strings.
+// the violation of reply framing which parse_inner accepts for requests.
+fn check_reply(src: &[u8], rtype: &RespType) -> Result<(), &'static str> {
+    match rtype {
+        RespType::String(range) | RespType::Error(range) => {
+            let line = &src[range.begin() + 1..range.end() - 2];
+            match src[range.begin()] {
+                RESP_NULL if !line.is_empty() => return Err("invalid null"),
+                RESP_BOOLEAN if line != b"t" && line != b"f" => return Err("invalid boolean"),
+                _ if line.contains(&BYTE_CR) => return Err("CR inside simple reply"),
+                _ => {}
+            }
+        }
+        RespType::Integer(range) => {
+            if btoi::btoi::<i64>(&src[range.begin() + 1..range.end() - 2]).is_err() {
+                return Err("invalid integer");
+            }
+        }
+        RespType::Bulk(_, _) => {}
+        RespType::Array(_, items) => {
+            for item in items {
+                check_reply(src, item)?;
+            }
+        }
+        RespType::Inline(_) => return Err("unknown reply type"),
+    }
+    Ok(())
+}
+
+fn protocol_error(src: &[u8]) -> String {
+    let line_at = |cursor: usize| {
+        let pos = simdfind::find_lf_simd(&src[cursor..])?;
+        Some((&src[cursor..cursor + pos], cursor + pos + 1))
+    };
+    let int_of = |line: &[u8]| {
+        line.strip_suffix(&[BYTE_CR])
+            .and_then(|x| btoi::btoi::<isize>(x).ok())
+    };
+
+    let (head, mut cursor) = match line_at(0) {
+        Some(line) if line.0.first() == Some(&RESP_ARRAY) => line,
+        _ => return "invalid inline request".to_string(),
+    };
+    let count = match int_of(&head[1..]) {
+        Some(count) if count >= -1 => count,
+        _ => return "invalid multibulk length".to_string(),
+    };
+    for _ in 0..count {
+        let (line, next) = match line_at(cursor) {
+            Some(line) => line,
+            None => break,
+        };
+        if src[cursor] != RESP_BULK {
+            return format!("expected '$', got '{}'", src[cursor] as char);
+        }
+        let len = match int_of(&line[1..]) {
+            Some(len) if len >= 0 && len as usize <= MAX_BULK_SIZE => len as usize,
+            _ => return "invalid bulk length".to_string(),
+        };
+        cursor = next + len + 2;
+        if cursor > src.len() {
+            break;
+        }
+        if &src[cursor - 2..cursor] != BYTES_CRLF {
+            return "invalid bulk length".to_string();
+        }
+    }
+    "invalid request".to_string()
+}
+
 impl From<MessageMut> for Message {
     fn from(MessageMut { rtype, data }: MessageMut) -> Message {
         Message {
@@ -292,6 +466,11 @@ pub struct Message {
 }
 
 impl Message {
+    /// parse one message from the front of src, see MessageMut::parse for the guarantees.
+    pub fn parse(src: &mut BytesMut) -> Result<Option<Message>, AsError> {
+        MessageMut::parse(src).map(|x| x.map(Into::into))
+    }
+
     pub fn new_cluster_slots() -> Message {
         Message {
             data: Bytes::from(BYTES_CMD_CLUSTER_SLOTS),
@@ -401,6 +580,87 @@ impl Message {
         }
     }
 
+    /// the reply contains any type of RESP3.
+    pub fn is_resp3(&self) -> bool {
+        self.is_resp3_by_rtype(&self.rtype)
+    }
+
+    fn is_resp3_by_rtype(&self, rtype: &RespType) -> bool {
+        match rtype {
+            RespType::String(rg) => self.data[rg.begin()] != RESP_STRING,
+            RespType::Bulk(head, _) => self.data[head.begin()] != RESP_BULK,
+            RespType::Array(head, subs) => {
+                self.data[head.begin()] != RESP_ARRAY
+                    || subs.iter().any(|x| self.is_resp3_by_rtype(x))
+            }
+            _ => false,
+        }
+    }
+
+    /// save the reply framed by RESP2 for the connections never said HELLO 3, the same as
+    /// redis replies: map as the flat array of pairs, set and push as array, null as null
+    /// bulk, boolean as integer 1 or 0, and double, big number and verbatim string as bulk
+    /// string (without the format of verbatim).
+    pub fn save_resp2(&self, buf: &mut BytesMut) -> usize {
+        if !self.is_resp3() {
+            return self.save(buf);
+        }
+        let begin = buf.len();
+        self.save_resp2_by_rtype(&self.rtype, buf);
+        buf.len() - begin
+    }
+
+    fn save_resp2_by_rtype(&self, rtype: &RespType, buf: &mut BytesMut) {
+        match rtype {
+            RespType::String(rg) => {
+                let line = &self.data[rg.begin() + 1..rg.end() - 2];
+                match self.data[rg.begin()] {
+                    RESP_NULL => buf.extend_from_slice(b"$-1\r\n"),
+                    RESP_BOOLEAN if line == b"t" => buf.extend_from_slice(b":1\r\n"),
+                    RESP_BOOLEAN => buf.extend_from_slice(b":0\r\n"),
+                    RESP_DOUBLE | RESP_BIG_NUMBER => save_bulk(&[line], buf),
+                    _ => {
+                        self.save_by_rtype(rtype, buf);
+                    }
+                }
+            }
+            RespType::Bulk(head, body) if self.data[head.begin()] == RESP_VERBATIM => {
+                // the format of 3 bytes and colon, e.g.: txt:
+                let text = &self.data[body.begin()..body.end() - 2];
+                save_bulk(&[text.get(4..).unwrap_or(&[])], buf);
+            }
+            RespType::Array(head, subs) => {
+                let count = match self.data[head.begin()] {
+                    RESP_ARRAY => None,
+                    _ => Some(subs.len()),
+                };
+                match count {
+                    Some(count) => buf.extend_from_slice(format!("*{}\r\n", count).as_bytes()),
+                    None => buf.extend_from_slice(&self.data[head.begin()..head.end()]),
+                }
+                for sub in subs {
+                    self.save_resp2_by_rtype(sub, buf);
+                }
+            }
+            _ => {
+                self.save_by_rtype(rtype, buf);
+            }
+        }
+    }
+
+    /// the reply framed by RESP2, itself if there is no type of RESP3.
+    pub fn to_resp2(&self) -> Message {
+        if !self.is_resp3() {
+            return self.clone();
+        }
+        let mut buf = BytesMut::new();
+        self.save_resp2(&mut buf);
+        Message::parse(&mut buf)
+            .ok()
+            .and_then(|x| x)
+            .expect("reply of RESP2 must be parsed")
+    }
+
     pub fn raw_data(&self) -> &[u8] {
         self.data.as_ref()
     }
@@ -433,9 +693,10 @@ impl Message {
                 if rng.begin() == rng.end() {
                     return Some(*rng);
                 }
-                if len > 0 && self.data[len - 1] == BYTE_LF {
+                // trim the line ending of the last field
+                if len > 0 && self.data[end - 1] == BYTE_LF {
                     end -= 1;
-                    if len > 1 && self.data[len - 2] == BYTE_CR {
+                    if len > 1 && self.data[end - 1] == BYTE_CR {
                         end -= 1;
                     }
                 }
@@ -490,6 +751,102 @@ impl Message {
         }
     }
 
+    /// request of bulk strings, e.g.: ["SET", "a", "1"].
+    pub fn from_args<I, A>(args: I) -> Message
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<[u8]>,
+    {
+        let args: Vec<A> = args.into_iter().collect();
+        let mut data = BytesMut::new();
+        let mut items = Vec::with_capacity(args.len());
+        data.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+        let head = Range::new(0, data.len());
+        for arg in &args {
+            let arg = arg.as_ref();
+            let begin = data.len();
+            data.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            let body = data.len();
+            data.extend_from_slice(arg);
+            data.extend_from_slice(b"\r\n");
+            items.push(RespType::Bulk(
+                Range::new(begin, body),
+                Range::new(body, data.len()),
+            ));
+        }
+        Message {
+            rtype: RespType::Array(head, items),
+            data: data.freeze(),
+        }
+    }
+
+    /// the bulk string of data, e.g.: the reply of INFO.
+    pub fn bulk(data: &[u8]) -> Message {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+        let body = buf.len();
+        buf.extend_from_slice(data);
+        buf.extend_from_slice(b"\r\n");
+        Message {
+            rtype: RespType::Bulk(Range::new(0, body), Range::new(body, buf.len())),
+            data: buf.freeze(),
+        }
+    }
+
+    /// count of the arguments of request.
+    pub fn args_len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// replace the nth argument of request, return false if it's out of range.
+    pub fn set_nth(&mut self, index: usize, arg: &[u8]) -> bool {
+        self.rewrite(|args| match args.get_mut(index) {
+            Some(x) => {
+                *x = arg.to_vec();
+                true
+            }
+            None => false,
+        })
+    }
+
+    /// insert the argument of request at index, return false if it's out of range.
+    pub fn insert_nth(&mut self, index: usize, arg: &[u8]) -> bool {
+        self.rewrite(|args| {
+            if index > args.len() {
+                return false;
+            }
+            args.insert(index, arg.to_vec());
+            true
+        })
+    }
+
+    /// remove the nth argument of request, return false if it's out of range.
+    pub fn remove_nth(&mut self, index: usize) -> bool {
+        self.rewrite(|args| {
+            if index >= args.len() {
+                return false;
+            }
+            args.remove(index);
+            true
+        })
+    }
+
+    pub fn push_arg(&mut self, arg: &[u8]) {
+        let len = self.args_len();
+        self.insert_nth(len, arg);
+    }
+
+    // the arguments are copied and the request is rebuilt as array (even if it was inline),
+    // which is only for the rare rewriting of request.
+    fn rewrite<F: FnOnce(&mut Vec<Vec<u8>>) -> bool>(&mut self, f: F) -> bool {
+        let mut args: Vec<Vec<u8>> = self.iter().map(|x| x.to_vec()).collect();
+        if !f(&mut args) {
+            return false;
+        }
+        *self = Message::from_args(&args);
+        true
+    }
+
     pub fn check_redirect(&self) -> Option<Redirect> {
         match self.rtype {
             RespType::Error(_) => {}
@@ -500,35 +857,51 @@ impl Message {
         }
         None
     }
+
+    /// the error replied by a replica to the write (e.g.: the master demoted by failover), which
+    /// is refused before executed.
+    pub fn is_readonly_error(&self) -> bool {
+        match self.rtype {
+            RespType::Error(_) => {}
+            _ => return false,
+        }
+        let data = self.data().unwrap_or_default();
+        data.starts_with(BYTES_READONLY)
+            && data
+                .get(BYTES_READONLY.len())
+                .map(|x| *x == BYTE_SPACE)
+                .unwrap_or(true)
+    }
 }
 
 const BYTE_SPACE: u8 = b' ';
+const BYTES_READONLY: &[u8] = b"READONLY";
 const PATTERNS: &[&str] = &["ASK", "MOVED"];
 
 lazy_static! {
     static ref FINDER: AhoCorasick = { AhoCorasick::new(PATTERNS) };
 }
 
+// only the error led by the pattern is redirect, the other errors may mention it too (e.g.:
+// ERR unknown command 'ASKING').
 fn parse_redirect(data: &[u8]) -> Option<Redirect> {
-    if let Some(mat) = FINDER.find(data) {
-        let pat = mat.pattern();
-        let end = mat.end();
-        let rdata = &data[end + 1..];
-
-
-        let pos = rdata.iter().position(|&x| x == BYTE_SPACE)?;
-
-        let sdata = &rdata[..pos];
-        let tdata = &rdata[pos + 1..];
-        if let Ok(slot) = btoi::btoi::<usize>(sdata) {
-            let to = String::from_utf8_lossy(tdata);
-            let to = to.to_string();
-            if pat == 0 {
-                return Some(Redirect::Ask { slot, to });
-            } else {
-                // moved
-                return Some(Redirect::Move { slot, to });
-            }
+    let mat = FINDER.find(data)?;
+    if mat.start() != 0 || data.get(mat.end()) != Some(&BYTE_SPACE) {
+        return None;
+    }
+    let rdata = &data[mat.end() + 1..];
+    let pos = rdata.iter().position(|&x| x == BYTE_SPACE)?;
+
+    let sdata = &rdata[..pos];
+    let tdata = &rdata[pos + 1..];
+    if let Ok(slot) = btoi::btoi::<usize>(sdata) {
+        let to = String::from_utf8_lossy(tdata);
+        let to = to.to_string();
+        if mat.pattern() == 0 {
+            return Some(Redirect::Ask { slot, to });
+        } else {
+            // moved
+            return Some(Redirect::Move { slot, to });
         }
     }
     None
@@ -589,6 +962,40 @@ mod test {
         assert!(iter.next() == Some(b"ab\nc".as_ref()));
     }
 
+    #[test]
+    fn test_rewrite_args() {
+        assert!(Message::from_args(&["PING"]) == Message::new_ping_request());
+
+        let data = b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let mut src = BytesMut::from(&data[..]);
+        let mut msg: Message = MessageMut::parse(&mut src).unwrap().unwrap().into();
+        assert!(msg == Message::from_args(&["SET", "a", "1"]));
+        assert!(msg.args_len() == 3);
+
+        assert!(msg.set_nth(2, b"value"));
+        assert!(!msg.set_nth(3, b"value"));
+        msg.push_arg(b"EX");
+        msg.push_arg(b"10");
+        assert!(msg.iter().collect::<Vec<_>>() == vec![&b"SET"[..], b"a", b"value", b"EX", b"10"]);
+        assert!(msg.remove_nth(3));
+        assert!(msg.insert_nth(3, b"PX"));
+        assert!(!msg.insert_nth(6, b"PX"));
+        assert!(!msg.remove_nth(5));
+
+        let mut buf = BytesMut::new();
+        msg.save(&mut buf);
+        assert!(
+            &buf[..]
+                == &b"*5\r\n$3\r\nSET\r\n$1\r\na\r\n$5\r\nvalue\r\n$2\r\nPX\r\n$2\r\n10\r\n"[..]
+        );
+
+        // inline request is rebuilt as array
+        let mut src = BytesMut::from(&b"GET a\r\n"[..]);
+        let mut msg: Message = MessageMut::parse(&mut src).unwrap().unwrap().into();
+        assert!(msg.set_nth(1, b"b"));
+        assert!(msg == Message::from_args(&["GET", "b"]));
+    }
+
     #[test]
     fn test_iter_plain() {
         let data = b"+abcdef\r\n";
@@ -687,4 +1094,170 @@ mod test {
         let mut src = BytesMut::from(data.as_bytes());
         assert!(MessageMut::parse(&mut src).unwrap_err() == AsError::BadMessage);
     }
+
+    fn parse_size(src: &mut BytesMut) -> Result<Option<usize>, AsError> {
+        MessageMut::parse(src).map(|x| x.map(|msg| msg.data.len()))
+    }
+
+    #[test]
+    fn test_parse_corpus() {
+        let corpus: &[&[u8]] = &[
+            b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n",
+            b"*3\r\n$3\r\nSET\r\n$3\r\na\x00b\r\n$0\r\n\r\n",
+            b"*3\r\n$4\r\nMGET\r\n$-1\r\n$1\r\nb\r\n",
+            b"*2\r\n*3\r\n:0\r\n:5460\r\n*2\r\n$9\r\n127.0.0.1\r\n:7000\r\n*-1\r\n",
+            b"*0\r\n",
+            b"+OK\r\n",
+            b"-ERR unknown command\r\n",
+            b":1024\r\n",
+            b"$5\r\nab\r\nc\r\n",
+            b"PING\r\n",
+            b"SET a b\n",
+        ];
+        crate::protocol::fuzz::check_corpus(corpus, parse_size);
+    }
+
+    #[test]
+    fn test_parse_pathological() {
+        let bad: &[&[u8]] = &[
+            // huge declared length
+            b"$9223372036854775807\r\nabc\r\n",
+            b"*1\r\n$536870913\r\n",
+            // missing CRLF
+            b"$3\r\nabcXY",
+            b"*1\r\n$3\r\nabc\n\n",
+            // inline inside array
+            b"*2\r\n$3\r\nGET\r\nfoo\n",
+            b"\n",
+        ];
+        for data in bad {
+            let mut src = BytesMut::from(&data[..]);
+            check!(MessageMut::parse(&mut src).unwrap_err() == AsError::BadMessage);
+            check!(src.len() < data.len());
+        }
+
+        // nested too deep
+        let deep = b"*1\r\n".repeat(MAX_ARRAY_DEPTH + 1);
+        let mut src = BytesMut::from(&deep[..]);
+        check!(MessageMut::parse(&mut src).unwrap_err() == AsError::BadMessage);
+        let deep = [b"*1\r\n".repeat(MAX_ARRAY_DEPTH), b":1\r\n".to_vec()].concat();
+        check!(crate::protocol::check_parse(&deep, parse_size) == 1);
+
+        // null bulk of any length
+        check!(crate::protocol::check_parse(b"$-01\r\n", parse_size) == 1);
+    }
+
+    #[test]
+    fn test_parse_inline_fields() {
+        let mut src = BytesMut::from(&b"SET a bc\r\n"[..]);
+        let msg = Message::parse(&mut src).unwrap().unwrap();
+        let args: Vec<_> = msg.iter().collect();
+        check!(args == vec![&b"SET"[..], &b"a"[..], &b"bc"[..]]);
+    }
+
+    #[test]
+    fn test_parse_request_protocol_error() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"*x\r\n$3\r\nGET\r\n", "invalid multibulk length"),
+            (b"*-10\r\n", "invalid multibulk length"),
+            (
+                b"*3\r\n$3\r\nSET\r\n$1\r\nx\r\nfooz\r\n",
+                "expected '$', got 'f'",
+            ),
+            (b"*1\r\n$-2\r\n", "invalid bulk length"),
+            (b"*1\r\n$3\r\nabcd\r\n", "invalid bulk length"),
+            (b"\n", "invalid inline request"),
+        ];
+        for (data, reason) in cases {
+            let mut src = BytesMut::from(&data[..]);
+            src.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+            let err = MessageMut::parse_request(&mut src).unwrap_err();
+            check!(err == AsError::ProtocolError(reason.to_string()));
+            check!(src.is_empty());
+        }
+
+        let mut src = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n*1\r\n$4"[..]);
+        check!(MessageMut::parse_request(&mut src).unwrap().is_some());
+        check!(MessageMut::parse_request(&mut src).unwrap().is_none());
+        check!(&src[..] == b"*1\r\n$4");
+    }
+
+    #[test]
+    fn test_parse_reply_strict() {
+        let cases: &[(&[u8], &str)] = &[
+            // declared longer or shorter than the bytes before CRLF
+            (b"$5\r\nabc\r\n+OK\r\n", "invalid framing"),
+            (b"$2\r\nabc\r\n", "invalid framing"),
+            (b"*2\r\n$1\r\na\r\n+b\n", "invalid framing"),
+            (b"+OK\n", "unknown reply type"),
+            (b"OK\r\n", "unknown reply type"),
+            (b":12a\r\n", "invalid integer"),
+            (b"*2\r\n:1\r\n:\r\n", "invalid integer"),
+            (b"+O\rK\r\n", "CR inside simple reply"),
+        ];
+        for (data, reason) in cases {
+            let mut src = BytesMut::from(&data[..]);
+            src.extend_from_slice(b"+OK\r\n");
+            let err = MessageMut::parse_reply(&mut src).unwrap_err();
+            check!(err == AsError::ReplyProtocolError(reason.to_string()));
+            check!(src.is_empty());
+        }
+
+        let mut src = BytesMut::from(&b"*2\r\n$3\r\na\r\n\r\n:-1\r\n$5\r\nabc"[..]);
+        let reply = MessageMut::parse_reply(&mut src).unwrap().unwrap();
+        check!(&reply.data[..] == b"*2\r\n$3\r\na\r\n\r\n:-1\r\n");
+        // incomplete is never a violation
+        check!(MessageMut::parse_reply(&mut src).unwrap().is_none());
+        check!(&src[..] == b"$5\r\nabc");
+    }
+
+    #[test]
+    fn test_readonly_error() {
+        let parse = |data: &[u8]| -> Message {
+            let mut src = BytesMut::from(data);
+            MessageMut::parse_reply(&mut src).unwrap().unwrap().into()
+        };
+        check!(
+            parse(b"-READONLY You can't write against a read only replica.\r\n")
+                .is_readonly_error()
+        );
+        check!(parse(b"-READONLY\r\n").is_readonly_error());
+        check!(!parse(b"-READONLYX no\r\n").is_readonly_error());
+        check!(!parse(b"-ERR unknown command 'READONLY'\r\n").is_readonly_error());
+        check!(!parse(b"+READONLY\r\n").is_readonly_error());
+    }
+
+    #[test]
+    fn test_resp3_to_resp2() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"%1\r\n$1\r\na\r\n#t\r\n", b"*2\r\n$1\r\na\r\n:1\r\n"),
+            (b"~2\r\n_\r\n#f\r\n", b"*2\r\n$-1\r\n:0\r\n"),
+            (
+                b">2\r\n,3.14\r\n(12345678901234567890\r\n",
+                b"*2\r\n$4\r\n3.14\r\n$20\r\n12345678901234567890\r\n",
+            ),
+            (b"=7\r\ntxt:abc\r\n", b"$3\r\nabc\r\n"),
+            (b"*2\r\n%0\r\n+OK\r\n", b"*2\r\n*0\r\n+OK\r\n"),
+        ];
+        for (data, resp2) in cases {
+            let mut src = BytesMut::from(&data[..]);
+            let reply: Message = MessageMut::parse_reply(&mut src).unwrap().unwrap().into();
+            check!(src.is_empty());
+            check!(reply.is_resp3());
+            let mut buf = BytesMut::new();
+            check!(reply.save_resp2(&mut buf) == resp2.len());
+            check!(&buf[..] == *resp2);
+            let mut buf = BytesMut::new();
+            reply.save(&mut buf);
+            check!(&buf[..] == *data);
+        }
+
+        let mut src = BytesMut::from(&b"*2\r\n$1\r\na\r\n:1\r\n"[..]);
+        let reply: Message = MessageMut::parse_reply(&mut src).unwrap().unwrap().into();
+        check!(!reply.is_resp3());
+        check!(reply.to_resp2() == reply);
+
+        let mut src = BytesMut::from(&b"#x\r\n"[..]);
+        check!(MessageMut::parse_reply(&mut src).is_err());
+    }
 }
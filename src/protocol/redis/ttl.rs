@@ -0,0 +1,327 @@
+//! the TTL enforced on the writes of the keys matched by ttl_policies, for the hygiene of cache.
+//! Each policy is "${pattern} ${max} [${default}|reject]" in config, e.g.: "session:* 86400 3600",
+//! the pattern is glob-style matched against the whole key and the TTLs are in seconds:
+//!
+//! - the TTL beyond max is capped to max, e.g.: SET k v EX 999999 is sent as SET k v EX 86400,
+//!   and the unix time of EXAT and PXAT is capped to max from now.
+//! - the write without TTL is given the default TTL by EX, or failed by reject, or sent as is if
+//!   neither is given.
+//!
+//! Only SET, SETEX and PSETEX are enforced, and SET with KEEPTTL is sent as is. Policies are
+//! evaluated in order and the first matched wins. The TTL is rewritten by the codec of backend
+//! right before sent, while the write rejected is failed by proxy without touching backend.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::com::{AsError, ClusterConfig};
+use crate::protocol::redis::resp::Message;
+use crate::proxy::standalone::pin::glob_match;
+
+const BYTES_SET: &[u8] = b"SET";
+const BYTES_SETEX: &[u8] = b"SETEX";
+const BYTES_PSETEX: &[u8] = b"PSETEX";
+const BYTES_EX: &[u8] = b"EX";
+const BYTES_PX: &[u8] = b"PX";
+const BYTES_EXAT: &[u8] = b"EXAT";
+const BYTES_PXAT: &[u8] = b"PXAT";
+const BYTES_KEEPTTL: &[u8] = b"KEEPTTL";
+const REJECT: &str = "reject";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Missing {
+    Allow,
+    // the default TTL in seconds
+    Inject(u64),
+    Reject,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Policy {
+    pattern: Vec<u8>,
+    // in seconds
+    max: u64,
+    missing: Missing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unit {
+    Seconds,
+    Millis,
+}
+
+impl Unit {
+    fn millis(self) -> u64 {
+        match self {
+            Unit::Seconds => 1000,
+            Unit::Millis => 1,
+        }
+    }
+}
+
+// the TTL given by the write
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ttl {
+    Absent,
+    Keep,
+    // the position of the TTL argument and its unit, e.g.: EX and PX
+    Relative(usize, Unit),
+    // the position of the unix time argument and its unit, e.g.: EXAT and PXAT
+    Absolute(usize, Unit),
+}
+
+// the key and TTL of the write enforced, None if it's not SET, SETEX or PSETEX.
+fn parse(req: &Message) -> Option<(&[u8], Ttl)> {
+    let name = req.nth(0)?;
+    let key = req.nth(1)?;
+    if name.eq_ignore_ascii_case(BYTES_SETEX) {
+        return Some((key, Ttl::Relative(2, Unit::Seconds)));
+    }
+    if name.eq_ignore_ascii_case(BYTES_PSETEX) {
+        return Some((key, Ttl::Relative(2, Unit::Millis)));
+    }
+    if !name.eq_ignore_ascii_case(BYTES_SET) {
+        return None;
+    }
+    let mut ttl = Ttl::Absent;
+    // the options follow the key and the value
+    let mut pos = 3;
+    while let Some(arg) = req.nth(pos) {
+        let option = [
+            (BYTES_EX, Ttl::Relative(pos + 1, Unit::Seconds)),
+            (BYTES_PX, Ttl::Relative(pos + 1, Unit::Millis)),
+            (BYTES_EXAT, Ttl::Absolute(pos + 1, Unit::Seconds)),
+            (BYTES_PXAT, Ttl::Absolute(pos + 1, Unit::Millis)),
+        ]
+        .iter()
+        .find(|x| arg.eq_ignore_ascii_case(x.0))
+        .map(|x| x.1);
+        if let Some(option) = option {
+            // skip the value of the option
+            ttl = option;
+            pos += 2;
+            continue;
+        }
+        if arg.eq_ignore_ascii_case(BYTES_KEEPTTL) {
+            ttl = Ttl::Keep;
+        }
+        pos += 1;
+    }
+    Some((key, ttl))
+}
+
+fn parse_policy(line: &str) -> Option<Policy> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let (pattern, max, missing) = match fields.as_slice() {
+        [pattern, max] => (pattern, max, Missing::Allow),
+        [pattern, max, missing] if *missing == REJECT => (pattern, max, Missing::Reject),
+        [pattern, max, missing] => (pattern, max, Missing::Inject(missing.parse().ok()?)),
+        _ => return None,
+    };
+    let max = max.parse().ok().filter(|x| *x > 0)?;
+    match missing {
+        Missing::Inject(ttl) if ttl == 0 || ttl > max => None,
+        _ => Some(Policy {
+            pattern: pattern.as_bytes().to_vec(),
+            max,
+            missing,
+        }),
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TtlPolicies {
+    policies: Vec<Policy>,
+}
+
+impl TtlPolicies {
+    pub fn new(lines: &[String]) -> Result<TtlPolicies, AsError> {
+        let mut policies = Vec::with_capacity(lines.len());
+        for line in lines {
+            let policy = parse_policy(line).ok_or_else(|| {
+                AsError::BadConfig(format!(
+                    "ttl_policies: {} must be \"${{pattern}} ${{max}} [${{default}}|reject]\" and 0 < default <= max",
+                    line
+                ))
+            })?;
+            policies.push(policy);
+        }
+        Ok(TtlPolicies { policies })
+    }
+
+    /// the policies are checked by Config::valid already.
+    pub fn from_config(cc: &ClusterConfig) -> TtlPolicies {
+        TtlPolicies::new(&cc.ttl_policies).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    fn matched(&self, req: &Message) -> Option<(&Policy, Ttl)> {
+        if self.is_empty() {
+            return None;
+        }
+        let (key, ttl) = parse(req)?;
+        let policy = self.policies.iter().find(|x| glob_match(&x.pattern, key))?;
+        Some((policy, ttl))
+    }
+
+    /// Rejected if the write without TTL is matched by the policy of reject.
+    pub fn check(&self, req: &Message) -> Result<(), AsError> {
+        match self.matched(req) {
+            Some((policy, Ttl::Absent)) if policy.missing == Missing::Reject => {
+                Err(AsError::Rejected(format!(
+                    "key {} must be written with TTL by ttl_policies",
+                    String::from_utf8_lossy(req.nth(1).unwrap_or_default())
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// the write with its TTL capped or given the default one, None if it's sent as is.
+    pub fn enforce(&self, req: &Message, now: SystemTime) -> Option<Message> {
+        let (policy, ttl) = self.matched(req)?;
+        let max = policy.max * 1000;
+        let mut req = req.clone();
+        match ttl {
+            Ttl::Absent => match policy.missing {
+                Missing::Inject(ttl) => {
+                    req.push_arg(BYTES_EX);
+                    req.push_arg(ttl.to_string().as_bytes());
+                }
+                _ => return None,
+            },
+            Ttl::Keep => return None,
+            // the malformed TTL is left to be refused by backend
+            Ttl::Relative(pos, unit) => {
+                let given = btoi::btou::<u64>(req.nth(pos)?).ok()?;
+                if given.saturating_mul(unit.millis()) <= max {
+                    return None;
+                }
+                req.set_nth(pos, (max / unit.millis()).to_string().as_bytes());
+            }
+            Ttl::Absolute(pos, unit) => {
+                let given = btoi::btou::<u64>(req.nth(pos)?).ok()?;
+                let limit = now.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64 + max;
+                if given.saturating_mul(unit.millis()) <= limit {
+                    return None;
+                }
+                req.set_nth(pos, (limit / unit.millis()).to_string().as_bytes());
+            }
+        }
+        Some(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn req(line: &str) -> Message {
+        let args: Vec<_> = line.split_whitespace().collect();
+        Message::from_args(&args)
+    }
+
+    fn args(msg: &Message) -> String {
+        let args: Vec<_> = msg.iter().map(String::from_utf8_lossy).collect();
+        args.join(" ")
+    }
+
+    #[test]
+    fn test_ttl_policies_parse() {
+        let lines: Vec<_> = ["session:* 3600 600", "cache:* 86400 reject", "* 604800"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let policies = TtlPolicies::new(&lines).unwrap();
+        assert_eq!(policies.policies.len(), 3);
+        assert_eq!(policies.policies[0].missing, Missing::Inject(600));
+        assert_eq!(policies.policies[1].missing, Missing::Reject);
+        assert_eq!(policies.policies[2].missing, Missing::Allow);
+        assert!(TtlPolicies::new(&[]).unwrap().is_empty());
+
+        for bad in &[
+            "session:*",
+            "session:* 0",
+            "session:* 60 600",
+            "session:* 60 0",
+            "a 1 2 3",
+        ] {
+            assert!(TtlPolicies::new(&[bad.to_string()]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_ttl_policies_cap() {
+        let lines = vec!["session:* 3600 600".to_string()];
+        let policies = TtlPolicies::new(&lines).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let enforce = |line: &str| policies.enforce(&req(line), now).map(|x| args(&x));
+
+        assert_eq!(
+            enforce("SET session:1 v EX 99999"),
+            Some("SET session:1 v EX 3600".to_string())
+        );
+        assert_eq!(
+            enforce("set session:1 v nx px 99999999 get"),
+            Some("set session:1 v nx px 3600000 get".to_string())
+        );
+        assert_eq!(
+            enforce("SETEX session:1 99999 v"),
+            Some("SETEX session:1 3600 v".to_string())
+        );
+        assert_eq!(
+            enforce("PSETEX session:1 99999999 v"),
+            Some("PSETEX session:1 3600000 v".to_string())
+        );
+        assert_eq!(
+            enforce("SET session:1 v EXAT 2000000"),
+            Some("SET session:1 v EXAT 1003600".to_string())
+        );
+        // within max, KEEPTTL, not matched or not the write enforced
+        assert_eq!(enforce("SET session:1 v EX 60"), None);
+        assert_eq!(enforce("SET session:1 v PXAT 1000001000"), None);
+        assert_eq!(enforce("SET session:1 v KEEPTTL"), None);
+        assert_eq!(enforce("SET user:1 v EX 99999"), None);
+        assert_eq!(enforce("GET session:1"), None);
+        // the value of SET is never taken as its option
+        assert_eq!(
+            enforce("SET session:1 EX EX 99999"),
+            Some("SET session:1 EX EX 3600".to_string())
+        );
+    }
+
+    #[test]
+    fn test_ttl_policies_missing() {
+        let lines: Vec<_> = ["session:* 3600 600", "cache:* 3600 reject", "* 3600"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let policies = TtlPolicies::new(&lines).unwrap();
+        let now = SystemTime::now();
+        let enforce = |line: &str| policies.enforce(&req(line), now).map(|x| args(&x));
+
+        assert_eq!(
+            enforce("SET session:1 v"),
+            Some("SET session:1 v EX 600".to_string())
+        );
+        assert_eq!(
+            enforce("SET session:1 v NX"),
+            Some("SET session:1 v NX EX 600".to_string())
+        );
+        assert!(policies.check(&req("SET session:1 v")).is_ok());
+
+        assert_eq!(enforce("SET cache:1 v"), None);
+        assert_eq!(
+            policies.check(&req("SET cache:1 v")),
+            Err(AsError::Rejected(
+                "key cache:1 must be written with TTL by ttl_policies".to_string()
+            ))
+        );
+        assert!(policies.check(&req("SET cache:1 v EX 60")).is_ok());
+
+        assert_eq!(enforce("SET other v"), None);
+        assert!(policies.check(&req("SET other v")).is_ok());
+    }
+}
@@ -1,2 +1,27 @@
+pub mod accept;
+pub mod accesslog;
+pub mod acl;
+pub mod budget;
+pub mod capture;
+pub mod clients;
 pub mod cluster;
+pub mod compat;
+pub mod doctor;
+pub mod fault;
+pub mod hook;
+pub mod info;
+pub mod link;
+pub mod maintenance;
+pub mod memory;
+pub mod monitor;
+pub mod outbuf;
+pub mod probe;
+pub mod readonly;
+pub mod ringdiff;
+pub mod shard;
+pub mod slo;
 pub mod standalone;
+pub mod startup;
+pub mod valuelimit;
+pub mod warmup;
+pub mod worker;
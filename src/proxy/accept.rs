@@ -0,0 +1,276 @@
+//! the accept loop of listeners bounded by accept_batch: at most the number of connections are
+//! accepted by one poll, and the rest are accepted after the other tasks of the worker, so the
+//! established connections keep making progress in connection storms. With accept_pacing,
+//! accepting is paused while the worker is busier than the ratio, and the kernel backlog
+//! absorbs the burst.
+//!
+//! How busy the worker is, is measured by the lateness of a ticker, which is late as long as
+//! the worker is running other tasks when it's due.
+use futures::{task, Async, Future, Poll, Stream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Delay;
+
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::com::ClusterConfig;
+use crate::metrics::{accept_observe, accept_paced_incr};
+
+pub const DEFAULT_ACCEPT_BATCH: usize = 64;
+// the ticker of busy ratio, which is also the pause of accepting
+const TICK: u64 = 50;
+const WINDOW: u64 = 1_000;
+
+/// the busy ratio of worker, the lateness of ticker per the elapsed time.
+pub fn busy_ratio(late: Duration, elapsed: Duration) -> f64 {
+    if elapsed == Duration::from_secs(0) {
+        return 0.0;
+    }
+    (late.as_secs_f64() / elapsed.as_secs_f64()).min(1.0)
+}
+
+struct Busy {
+    tick: Delay,
+    deadline: Instant,
+    since: Instant,
+    late: Duration,
+    ratio: f64,
+}
+
+impl Busy {
+    fn new() -> Busy {
+        let now = Instant::now();
+        let deadline = now + Duration::from_millis(TICK);
+        Busy {
+            tick: Delay::new(deadline),
+            deadline,
+            since: now,
+            late: Duration::from_secs(0),
+            ratio: 0.0,
+        }
+    }
+
+    // the ratio of the last window, updated by the ticker
+    fn poll(&mut self) -> f64 {
+        while let Ok(Async::Ready(())) = self.tick.poll() {
+            let now = Instant::now();
+            self.late += now.saturating_duration_since(self.deadline);
+            let elapsed = now.saturating_duration_since(self.since);
+            if elapsed >= Duration::from_millis(WINDOW) {
+                self.ratio = busy_ratio(self.late, elapsed);
+                self.late = Duration::from_secs(0);
+                self.since = now;
+            }
+            self.deadline = now + Duration::from_millis(TICK);
+            self.tick.reset(self.deadline);
+        }
+        self.ratio
+    }
+}
+
+pub struct Accept {
+    cluster: String,
+    listener: TcpListener,
+    batch: usize,
+    // accepted by the current poll
+    accepted: usize,
+    pacing: Option<(f64, Busy)>,
+    paused: Option<Delay>,
+}
+
+impl Accept {
+    pub fn new(cc: &ClusterConfig, listener: TcpListener) -> Accept {
+        Accept {
+            cluster: cc.name.clone(),
+            listener,
+            batch: cc.accept_batch.unwrap_or(DEFAULT_ACCEPT_BATCH).max(1),
+            accepted: 0,
+            pacing: cc.accept_pacing.map(|x| (x, Busy::new())),
+            paused: None,
+        }
+    }
+
+    fn is_paused(&mut self) -> bool {
+        if let Some(paused) = self.paused.as_mut() {
+            if let Ok(Async::NotReady) = paused.poll() {
+                return true;
+            }
+            self.paused = None;
+        }
+        let too_busy = match self.pacing.as_mut() {
+            Some((pacing, busy)) => busy.poll() > *pacing,
+            None => false,
+        };
+        if !too_busy {
+            return false;
+        }
+        accept_paced_incr(&self.cluster);
+        let mut paused = Delay::new(Instant::now() + Duration::from_millis(TICK));
+        // registered to wake up the accepting after the pause
+        if let Ok(Async::Ready(())) = paused.poll() {
+            return false;
+        }
+        self.paused = Some(paused);
+        true
+    }
+}
+
+impl Stream for Accept {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.is_paused() {
+            return Ok(Async::NotReady);
+        }
+        if self.accepted >= self.batch {
+            self.accepted = 0;
+            // the rest are accepted by the next poll after the other tasks
+            task::current().notify();
+            return Ok(Async::NotReady);
+        }
+        let start = Instant::now();
+        match self.listener.poll_accept()? {
+            Async::Ready((sock, _)) => {
+                self.accepted += 1;
+                accept_observe(&self.cluster, start.elapsed());
+                Ok(Async::Ready(Some(sock)))
+            }
+            Async::NotReady => {
+                self.accepted = 0;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// the connections dropped by the full accept queues of all the listeners of host, which is
+/// only observable by the ListenOverflows of linux.
+pub fn listen_overflows() -> Option<u64> {
+    fs::read_to_string("/proc/net/netstat")
+        .ok()
+        .and_then(|x| parse_listen_overflows(&x))
+}
+
+fn parse_listen_overflows(netstat: &str) -> Option<u64> {
+    let mut lines = netstat.lines().filter(|x| x.starts_with("TcpExt:"));
+    let names = lines.next()?;
+    let values = lines.next()?;
+    names
+        .split_whitespace()
+        .zip(values.split_whitespace())
+        .find(|(name, _)| *name == "ListenOverflows")
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{accept_paced_get, accepted_get};
+    use futures::lazy;
+    use std::net::{SocketAddr, TcpStream as StdStream};
+    use tokio::runtime::current_thread;
+
+    // the count of connections accepted by each poll
+    struct Turns {
+        accept: Accept,
+        turns: Vec<usize>,
+        expected: usize,
+    }
+
+    impl Future for Turns {
+        type Item = Vec<usize>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            let mut count = 0;
+            while let Async::Ready(Some(_)) = self.accept.poll()? {
+                count += 1;
+            }
+            if count > 0 {
+                self.turns.push(count);
+            }
+            if self.turns.iter().sum::<usize>() >= self.expected {
+                return Ok(Async::Ready(self.turns.clone()));
+            }
+            Ok(Async::NotReady)
+        }
+    }
+
+    fn listen(cc: &ClusterConfig) -> (Accept, SocketAddr) {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        (Accept::new(cc, listener), addr)
+    }
+
+    #[test]
+    fn test_accept_bounded_batch() {
+        let cc = ClusterConfig {
+            name: "test-accept-batch".to_string(),
+            accept_batch: Some(2),
+            ..Default::default()
+        };
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let (accept, addr) = rt.block_on(lazy(|| Ok::<_, ()>(listen(&cc)))).unwrap();
+        let clients: Vec<_> = (0..5).map(|_| StdStream::connect(addr).unwrap()).collect();
+        let turns = Turns {
+            accept,
+            turns: Vec::new(),
+            expected: clients.len(),
+        };
+        let turns = rt.block_on(turns).unwrap();
+        assert_eq!(turns.iter().sum::<usize>(), 5);
+        assert!(turns.iter().all(|x| *x <= 2), "{:?}", turns);
+        assert_eq!(accepted_get(&cc.name), 5);
+    }
+
+    #[test]
+    fn test_accept_paced_when_busy() {
+        let cc = ClusterConfig {
+            name: "test-accept-pacing".to_string(),
+            accept_pacing: Some(0.5),
+            ..Default::default()
+        };
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let (mut accept, addr) = rt.block_on(lazy(|| Ok::<_, ()>(listen(&cc)))).unwrap();
+        let _client = StdStream::connect(addr).unwrap();
+        accept.pacing.as_mut().unwrap().1.ratio = 0.9;
+        let paused = rt
+            .block_on(lazy(|| Ok::<_, ()>(accept.poll().unwrap().is_not_ready())))
+            .unwrap();
+        assert!(paused);
+        assert_eq!(accept_paced_get(&cc.name), 1);
+        assert_eq!(accepted_get(&cc.name), 0);
+
+        // and accepted once the worker is idle again
+        accept.pacing.as_mut().unwrap().1.ratio = 0.1;
+        let turns = Turns {
+            accept,
+            turns: Vec::new(),
+            expected: 1,
+        };
+        assert_eq!(rt.block_on(turns).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_busy_ratio() {
+        let second = Duration::from_secs(1);
+        assert_eq!(busy_ratio(Duration::from_millis(250), second), 0.25);
+        assert_eq!(busy_ratio(Duration::from_secs(2), second), 1.0);
+        assert_eq!(busy_ratio(second, Duration::from_secs(0)), 0.0);
+    }
+
+    #[test]
+    fn test_parse_listen_overflows() {
+        let netstat = "TcpExt: SyncookiesSent ListenOverflows ListenDrops\n\
+                       TcpExt: 0 17 17\n\
+                       IpExt: InNoRoutes\n\
+                       IpExt: 0\n";
+        assert_eq!(parse_listen_overflows(netstat), Some(17));
+        assert_eq!(
+            parse_listen_overflows("IpExt: InNoRoutes\nIpExt: 0\n"),
+            None
+        );
+    }
+}
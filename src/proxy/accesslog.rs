@@ -0,0 +1,542 @@
+//! key-level access log of each cluster for auditing, one JSON line per request:
+//!
+//! ```text
+//! {"time":1700000000000000,"client":"127.0.0.1:50001","cmd":"GET","keys":["a"],"node":"127.0.0.1:7001","latency":230,"result":"ok","timeout":1000,"tick":1037}
+//! ```
+//!
+//! time is micros since epoch and latency is in micros. timeout is the millis of the backend in
+//! effect when dispatched (the adaptive one or read_timeout), null if there's none. tick is the
+//! logical clock of cluster when the request was decoded (see standalone::tick), which joins the
+//! line to the trace log, null if trace log is disabled. Lines are
+//! sent to a dedicated writer thread of the cluster by a bounded channel, which is never blocked
+//! by the file, and dropped (counted by aster_access_log_dropped) on overload. The file is
+//! rotated by size.
+//!
+//! The lines are compressed by gzip or zstd as a stream if access_log_compression is given. Each
+//! file (and each restart appending to it) is a complete gzip member or zstd frame finished on
+//! rotation, so the rotated files and their concatenation are decompressed by the common tools,
+//! e.g.: `zcat access.log.1`. The flushes of every second keep the lines decompressible as soon
+//! as they're written. The size of rotation is counted by the lines before compression.
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::com::{AsError, ClusterConfig, LogCompression};
+use crate::metrics::access_log_dropped_incr;
+use crate::protocol::redis::prefix::hash_key;
+
+pub const FIELDS: &[&str] = &[
+    "time", "client", "cmd", "keys", "node", "latency", "result", "timeout", "tick",
+];
+pub const DEFAULT_MAX_SIZE: u64 = 256 * 1024 * 1024;
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+const CHANNEL_SIZE: usize = 8192;
+const FLUSH_INTERVAL: u64 = 1_000;
+
+pub struct Entry {
+    pub time: SystemTime,
+    pub client: String,
+    pub cmd: String,
+    pub keys: Vec<Vec<u8>>,
+    pub node: Option<String>,
+    pub latency: Duration,
+    pub error: bool,
+    pub timeout: Option<Duration>,
+    pub tick: u64,
+}
+
+struct Format {
+    fields: Vec<String>,
+    hash_keys: bool,
+}
+
+impl Format {
+    fn new(cc: &ClusterConfig) -> Format {
+        let fields = if cc.access_log_fields.is_empty() {
+            FIELDS.iter().map(|x| x.to_string()).collect()
+        } else {
+            cc.access_log_fields.clone()
+        };
+        Format {
+            fields,
+            hash_keys: cc.access_log_hash_keys.unwrap_or(false),
+        }
+    }
+
+    fn write_line(&self, entry: &Entry, buf: &mut Vec<u8>) {
+        buf.push(b'{');
+        for (i, field) in self.fields.iter().enumerate() {
+            if i > 0 {
+                buf.push(b',');
+            }
+            write_str(field.as_bytes(), buf);
+            buf.push(b':');
+            match field.as_str() {
+                "time" => {
+                    let time = entry
+                        .time
+                        .duration_since(UNIX_EPOCH)
+                        .map(|x| x.as_micros())
+                        .unwrap_or(0);
+                    buf.extend_from_slice(time.to_string().as_bytes());
+                }
+                "client" => write_str(entry.client.as_bytes(), buf),
+                "cmd" => write_str(entry.cmd.as_bytes(), buf),
+                "keys" => {
+                    buf.push(b'[');
+                    for (j, key) in entry.keys.iter().enumerate() {
+                        if j > 0 {
+                            buf.push(b',');
+                        }
+                        if self.hash_keys {
+                            write_str(&hash_key(key), buf);
+                        } else {
+                            write_str(key, buf);
+                        }
+                    }
+                    buf.push(b']');
+                }
+                "node" => match entry.node.as_ref() {
+                    Some(node) => write_str(node.as_bytes(), buf),
+                    None => buf.extend_from_slice(b"null"),
+                },
+                "latency" => {
+                    let latency = entry.latency.as_micros();
+                    buf.extend_from_slice(latency.to_string().as_bytes());
+                }
+                "result" => {
+                    let result: &[u8] = if entry.error { b"error" } else { b"ok" };
+                    write_str(result, buf);
+                }
+                "timeout" => match entry.timeout {
+                    Some(timeout) => {
+                        buf.extend_from_slice(timeout.as_millis().to_string().as_bytes())
+                    }
+                    None => buf.extend_from_slice(b"null"),
+                },
+                "tick" if entry.tick > 0 => {
+                    buf.extend_from_slice(entry.tick.to_string().as_bytes())
+                }
+                _ => buf.extend_from_slice(b"null"),
+            }
+        }
+        buf.extend_from_slice(b"}\n");
+    }
+}
+
+// JSON string, the invalid utf8 bytes (e.g.: binary keys) are replaced
+fn write_str(data: &[u8], buf: &mut Vec<u8>) {
+    buf.push(b'"');
+    for c in String::from_utf8_lossy(data).chars() {
+        match c {
+            '"' => buf.extend_from_slice(b"\\\""),
+            '\\' => buf.extend_from_slice(b"\\\\"),
+            '\n' => buf.extend_from_slice(b"\\n"),
+            '\r' => buf.extend_from_slice(b"\\r"),
+            '\t' => buf.extend_from_slice(b"\\t"),
+            c if (c as u32) < 0x20 => {
+                buf.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes())
+            }
+            c => {
+                let mut tmp = [0u8; 4];
+                buf.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+            }
+        }
+    }
+    buf.push(b'"');
+}
+
+// the stream of the file compressed or not
+enum Sink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    #[cfg(feature = "zstd-log")]
+    Zstd(zstd::Encoder<BufWriter<File>>),
+}
+
+impl Sink {
+    fn new(file: File, compression: LogCompression) -> io::Result<Sink> {
+        let file = BufWriter::new(file);
+        let sink = match compression {
+            LogCompression::None => Sink::Plain(file),
+            LogCompression::Gzip => Sink::Gzip(GzEncoder::new(file, Compression::default())),
+            #[cfg(feature = "zstd-log")]
+            LogCompression::Zstd => Sink::Zstd(zstd::Encoder::new(file, 0)?),
+            #[cfg(not(feature = "zstd-log"))]
+            LogCompression::Zstd => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "zstd is compiled out without feature zstd-log",
+                ))
+            }
+        };
+        Ok(sink)
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Sink::Plain(file) => file,
+            Sink::Gzip(encoder) => encoder,
+            #[cfg(feature = "zstd-log")]
+            Sink::Zstd(encoder) => encoder,
+        }
+    }
+
+    /// write the trailer of the gzip member or zstd frame.
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Sink::Plain(file) => file,
+            Sink::Gzip(encoder) => encoder.finish()?,
+            #[cfg(feature = "zstd-log")]
+            Sink::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+/// the file rotated once it exceeds max size, the rotated files are suffixed by .1 (the
+/// latest) to .max_files, and the older ones are removed.
+struct RotateFile {
+    path: String,
+    max_size: u64,
+    max_files: usize,
+    compression: LogCompression,
+    size: u64,
+    file: Sink,
+}
+
+impl RotateFile {
+    fn open(
+        path: &str,
+        max_size: u64,
+        max_files: usize,
+        compression: LogCompression,
+    ) -> io::Result<RotateFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotateFile {
+            path: path.to_string(),
+            max_size,
+            max_files,
+            compression,
+            size,
+            file: Sink::new(file, compression)?,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.writer().write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.writer().flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let from = format!("{}.{}", self.path, i);
+                if fs::metadata(&from).is_ok() {
+                    fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+                }
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        // the renamed file is still finished by its fd
+        let file = RotateFile::open(&self.path, self.max_size, self.max_files, self.compression)?;
+        mem::replace(self, file).finish()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.writer().flush()
+    }
+
+    fn finish(self) -> io::Result<()> {
+        self.file.finish()
+    }
+}
+
+lazy_static! {
+    // writers of clusters shared by all the worker threads
+    static ref WRITERS: Mutex<HashMap<String, SyncSender<Entry>>> = Mutex::new(HashMap::new());
+}
+
+/// the access log handle of the cluster held by each worker thread.
+pub struct AccessLog {
+    cluster: String,
+    tx: Option<SyncSender<Entry>>,
+}
+
+pub fn handle(cc: &ClusterConfig) -> AccessLog {
+    let tx = cc.access_log.as_ref().and_then(|path| {
+        let mut writers = WRITERS.lock().unwrap();
+        if let Some(tx) = writers.get(&cc.name) {
+            return Some(tx.clone());
+        }
+        match spawn_writer(cc, path) {
+            Ok(tx) => {
+                writers.insert(cc.name.clone(), tx.clone());
+                Some(tx)
+            }
+            Err(err) => {
+                error!("fail to open access log {} due to {}", path, err);
+                None
+            }
+        }
+    });
+    AccessLog {
+        cluster: cc.name.clone(),
+        tx,
+    }
+}
+
+fn spawn_writer(cc: &ClusterConfig, path: &str) -> Result<SyncSender<Entry>, AsError> {
+    let format = Format::new(cc);
+    let mut file = RotateFile::open(
+        path,
+        cc.access_log_max_size.unwrap_or(DEFAULT_MAX_SIZE),
+        cc.access_log_max_files.unwrap_or(DEFAULT_MAX_FILES),
+        cc.access_log_compression.unwrap_or_default(),
+    )?;
+    let (tx, rx) = sync_channel(CHANNEL_SIZE);
+    let name = cc.name.clone();
+    thread::Builder::new()
+        .name(format!("aster-access-{}", cc.name))
+        .spawn(move || {
+            if let Err(err) = write_loop(&format, rx, &mut file) {
+                error!(
+                    "fail to write access log of cluster {} due to {}",
+                    name, err
+                );
+            }
+            let _ = file.finish();
+        })?;
+    Ok(tx)
+}
+
+fn write_loop(format: &Format, rx: Receiver<Entry>, file: &mut RotateFile) -> io::Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        let entry = match rx.recv_timeout(Duration::from_millis(FLUSH_INTERVAL)) {
+            Ok(entry) => entry,
+            Err(RecvTimeoutError::Timeout) => {
+                file.flush()?;
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        buf.clear();
+        format.write_line(&entry, &mut buf);
+        file.write_line(&buf)?;
+    }
+}
+
+impl AccessLog {
+    pub fn is_enabled(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub fn log(&self, entry: Entry) {
+        if let Some(tx) = self.tx.as_ref() {
+            if let Err(TrySendError::Full(_)) = tx.try_send(entry) {
+                access_log_dropped_incr(&self.cluster);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+
+    fn entry(key: &[u8]) -> Entry {
+        Entry {
+            time: UNIX_EPOCH + Duration::from_micros(1_000_001),
+            client: "127.0.0.1:50001".to_string(),
+            cmd: "GET".to_string(),
+            keys: vec![key.to_vec()],
+            node: Some("127.0.0.1:7001".to_string()),
+            latency: Duration::from_micros(230),
+            error: false,
+            timeout: Some(Duration::from_millis(1000)),
+            tick: 7,
+        }
+    }
+
+    #[test]
+    fn test_access_log_fields() {
+        let path = env::temp_dir().join(format!("aster-access-{}.log", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        let cc = ClusterConfig {
+            name: "test-access-log".to_string(),
+            access_log: Some(path.clone()),
+            access_log_fields: vec![
+                "cmd".to_string(),
+                "keys".to_string(),
+                "node".to_string(),
+                "result".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let format = Format::new(&cc);
+        let mut file = RotateFile::open(
+            &path,
+            DEFAULT_MAX_SIZE,
+            DEFAULT_MAX_FILES,
+            LogCompression::None,
+        )
+        .unwrap();
+        let (tx, rx) = sync_channel(CHANNEL_SIZE);
+        tx.send(entry(b"user:\"1\"")).unwrap();
+        drop(tx);
+        write_loop(&format, rx, &mut file).unwrap();
+        file.flush().unwrap();
+
+        let data = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            data,
+            "{\"cmd\":\"GET\",\"keys\":[\"user:\\\"1\\\"\"],\"node\":\"127.0.0.1:7001\",\"result\":\"ok\"}\n"
+        );
+        fs::remove_file(&path).unwrap();
+
+        // all fields by default, and keys are hashed
+        let cc = ClusterConfig {
+            access_log_hash_keys: Some(true),
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        Format::new(&cc).write_line(&entry(b"a"), &mut buf);
+        let line = String::from_utf8(buf).unwrap();
+        assert_eq!(
+            line,
+            format!(
+                "{{\"time\":1000001,\"client\":\"127.0.0.1:50001\",\"cmd\":\"GET\",\"keys\":[\"{}\"],\"node\":\"127.0.0.1:7001\",\"latency\":230,\"result\":\"ok\",\"timeout\":1000,\"tick\":7}}\n",
+                String::from_utf8(hash_key(b"a")).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_access_log_rotate() {
+        let path = env::temp_dir().join(format!("aster-rotate-{}.log", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let clean = || {
+            for suffix in &["", ".1", ".2", ".3"] {
+                let _ = fs::remove_file(format!("{}{}", path, suffix));
+            }
+        };
+        clean();
+
+        let mut file = RotateFile::open(&path, 10, 2, LogCompression::None).unwrap();
+        for line in &["line-1\n", "line-2\n", "line-3\n", "line-4\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line-4\n");
+        assert_eq!(
+            fs::read_to_string(format!("{}.1", path)).unwrap(),
+            "line-3\n"
+        );
+        assert_eq!(
+            fs::read_to_string(format!("{}.2", path)).unwrap(),
+            "line-2\n"
+        );
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+        clean();
+    }
+
+    fn decompress(path: &str, compression: LogCompression) -> String {
+        let file = File::open(path).unwrap();
+        let mut data = String::new();
+        match compression {
+            LogCompression::Gzip => {
+                flate2::read::MultiGzDecoder::new(file)
+                    .read_to_string(&mut data)
+                    .unwrap();
+            }
+            #[cfg(feature = "zstd-log")]
+            LogCompression::Zstd => {
+                zstd::Decoder::new(file)
+                    .unwrap()
+                    .read_to_string(&mut data)
+                    .unwrap();
+            }
+            _ => unreachable!(),
+        }
+        data
+    }
+
+    fn check_compressed(compression: LogCompression) {
+        let path = env::temp_dir().join(format!(
+            "aster-compressed-{:?}-{}.log",
+            compression,
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap().to_string();
+        let clean = || {
+            for suffix in &["", ".1", ".2"] {
+                let _ = fs::remove_file(format!("{}{}", path, suffix));
+            }
+        };
+        clean();
+
+        let cc = ClusterConfig {
+            access_log_fields: vec!["cmd".to_string(), "keys".to_string()],
+            ..Default::default()
+        };
+        let format = Format::new(&cc);
+        let mut file = RotateFile::open(&path, 64, 2, compression).unwrap();
+        let (tx, rx) = sync_channel(CHANNEL_SIZE);
+        for key in &[&b"a"[..], b"b", b"c", b"d"] {
+            tx.send(entry(key)).unwrap();
+        }
+        drop(tx);
+        write_loop(&format, rx, &mut file).unwrap();
+        let line = |key: &str| format!("{{\"cmd\":\"GET\",\"keys\":[\"{}\"]}}\n", key);
+        // the rotated one is finished at once
+        assert_eq!(
+            decompress(&format!("{}.1", path), compression),
+            line("a") + &line("b")
+        );
+        file.finish().unwrap();
+        assert_eq!(decompress(&path, compression), line("c") + &line("d"));
+
+        // appended by restart as another member or frame
+        let mut file = RotateFile::open(&path, DEFAULT_MAX_SIZE, 2, compression).unwrap();
+        file.write_line(line("e").as_bytes()).unwrap();
+        file.finish().unwrap();
+        assert_eq!(
+            decompress(&path, compression),
+            line("c") + &line("d") + &line("e")
+        );
+        clean();
+    }
+
+    #[test]
+    fn test_access_log_gzip() {
+        check_compressed(LogCompression::Gzip);
+    }
+
+    #[cfg(feature = "zstd-log")]
+    #[test]
+    fn test_access_log_zstd() {
+        check_compressed(LogCompression::Zstd);
+    }
+}
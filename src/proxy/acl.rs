@@ -0,0 +1,443 @@
+//! users of the proxy given by users of each cluster, redis only. Each client connection must
+//! AUTH as one of them before anything else, and then each command is checked against the
+//! categories of commands and the key patterns of the user, e.g.:
+//!
+//!     AUTH app secret
+//!     AUTH secret (as the user named default)
+//!
+//! The category of command is taken from the command table: read, write, admin (the admin
+//! commands, PROXY and CLIENT KILL) and dangerous. The keys are glob-style matched, all the keys
+//! of the command are checked including the ones fanned out (e.g.: MGET) and the destination of
+//! two-key commands. ACL WHOAMI and ACL LIST are answered by the proxy itself.
+use crate::com::{AsError, UserConfig};
+use crate::proxy::standalone::pin::glob_match;
+
+const CMD_AUTH: &str = "AUTH";
+const CMD_ACL: &str = "ACL";
+const SUB_CMD_WHOAMI: &str = "WHOAMI";
+const SUB_CMD_LIST: &str = "LIST";
+// the user of AUTH with password only
+const DEFAULT_USER: &str = "default";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    // AUTH and QUIT, allowed before authenticated
+    Auth,
+    // allowed to all the users authenticated, e.g.: PING
+    Connection,
+    Read,
+    Write,
+    Admin,
+    Dangerous,
+}
+
+impl Category {
+    /// the category given in users.commands, the ones always allowed are never given.
+    pub fn from_name(name: &str) -> Option<Category> {
+        match name.to_ascii_lowercase().as_str() {
+            "read" => Some(Category::Read),
+            "write" => Some(Category::Write),
+            "admin" => Some(Category::Admin),
+            "dangerous" => Some(Category::Dangerous),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Auth => "auth",
+            Category::Connection => "connection",
+            Category::Read => "read",
+            Category::Write => "write",
+            Category::Admin => "admin",
+            Category::Dangerous => "dangerous",
+        }
+    }
+}
+
+/// the reply of AUTH or ACL answered by proxy.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AclReply {
+    Ok,
+    // bulk string of ACL WHOAMI
+    User(String),
+    // the rule of each user of ACL LIST
+    Rules(Vec<String>),
+}
+
+/// the hex digest of password given in users.
+pub fn hash_password(password: &str) -> String {
+    sha1::Sha1::from(password).digest().to_string()
+}
+
+/// check the users config, return the reason of the bad one.
+pub fn validate(users: &[UserConfig]) -> Result<(), String> {
+    let mut names = Vec::with_capacity(users.len());
+    for user in users {
+        if user.name.is_empty() || user.name.contains(char::is_whitespace) {
+            return Err(format!("name '{}' is bad", user.name));
+        }
+        if names.contains(&user.name.as_str()) {
+            return Err(format!("{}: name is duplicated", user.name));
+        }
+        names.push(user.name.as_str());
+        let is_hex = user.password.chars().all(|x| x.is_ascii_hexdigit());
+        if user.password.len() != 40 || !is_hex {
+            return Err(format!(
+                "{}: password must be the sha1 hex digest",
+                user.name
+            ));
+        }
+        if let Some(name) = user
+            .commands
+            .iter()
+            .find(|x| Category::from_name(x).is_none())
+        {
+            return Err(format!(
+                "{}: unknown category {}, try read, write, admin, dangerous",
+                user.name, name
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+struct User {
+    name: String,
+    password: String,
+    categories: Vec<Category>,
+    keys: Vec<Vec<u8>>,
+}
+
+impl User {
+    fn allows(&self, category: Category) -> bool {
+        self.categories.contains(&category)
+    }
+
+    fn allows_key(&self, key: &[u8]) -> bool {
+        self.keys.iter().any(|x| glob_match(x, key))
+    }
+
+    // in the form of redis, e.g.: "user app on #... ~app:* +@read +@write".
+    fn rule(&self) -> String {
+        let mut fields = vec![
+            "user".to_string(),
+            self.name.clone(),
+            "on".to_string(),
+            format!("#{}", self.password),
+        ];
+        for key in &self.keys {
+            fields.push(format!("~{}", String::from_utf8_lossy(key)));
+        }
+        if self.categories.is_empty() {
+            fields.push("-@all".to_string());
+        }
+        for category in &self.categories {
+            fields.push(format!("+@{}", category.as_str()));
+        }
+        fields.join(" ")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    users: Vec<User>,
+}
+
+impl Acl {
+    /// the users must be validated.
+    pub fn new(users: &[UserConfig]) -> Acl {
+        let users = users
+            .iter()
+            .map(|x| User {
+                name: x.name.clone(),
+                password: x.password.to_ascii_lowercase(),
+                categories: x
+                    .commands
+                    .iter()
+                    .filter_map(|x| Category::from_name(x))
+                    .collect(),
+                keys: x.keys.iter().map(|x| x.as_bytes().to_vec()).collect(),
+            })
+            .collect();
+        Acl { users }
+    }
+
+    /// the clients must AUTH, false if no users are given.
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    // the user authenticated, which may be removed by reload since.
+    fn user(&self, name: Option<&str>) -> Result<&User, AsError> {
+        name.and_then(|name| self.users.iter().find(|x| x.name == name))
+            .ok_or(AsError::NoAuth)
+    }
+
+    /// check the command by the user authenticated, its category and keys.
+    pub fn check<K: AsRef<[u8]>>(
+        &self,
+        user: Option<&str>,
+        category: Category,
+        name: &str,
+        keys: &[K],
+    ) -> Result<(), AsError> {
+        if !self.is_enabled() || category == Category::Auth {
+            return Ok(());
+        }
+        let user = self.user(user)?;
+        if category == Category::Connection {
+            return Ok(());
+        }
+        if !user.allows(category) {
+            return Err(AsError::NoPermCommand(
+                user.name.clone(),
+                name.to_ascii_lowercase(),
+            ));
+        }
+        if keys.iter().any(|x| !user.allows_key(x.as_ref())) {
+            return Err(AsError::NoPermKey);
+        }
+        Ok(())
+    }
+
+    /// answer AUTH or ACL by the arguments with the command name, user is set once AUTH
+    /// succeeded.
+    pub fn handle(&self, user: &mut Option<String>, args: &[String]) -> Result<AclReply, AsError> {
+        let name = args
+            .get(0)
+            .map(|x| x.to_ascii_uppercase())
+            .unwrap_or_default();
+        if name == CMD_AUTH {
+            let (name, password) = match args.len() {
+                2 => (DEFAULT_USER, &args[1]),
+                3 => (args[1].as_str(), &args[2]),
+                _ => {
+                    return Err(AsError::BadClientCommand(
+                        "wrong number of arguments for 'auth' command".to_string(),
+                    ))
+                }
+            };
+            if !self.is_enabled() {
+                return Err(AsError::BadClientCommand(
+                    "AUTH <password> called without any password configured for the default \
+                     user. Are you sure your configuration is correct?"
+                        .to_string(),
+                ));
+            }
+            let digest = hash_password(password);
+            let found = self
+                .users
+                .iter()
+                .find(|x| x.name == name && x.password == digest)
+                .ok_or(AsError::WrongPass)?;
+            *user = Some(found.name.clone());
+            return Ok(AclReply::Ok);
+        }
+        if name != CMD_ACL {
+            return Err(AsError::RequestNotSupport);
+        }
+
+        let sub_cmd = args
+            .get(1)
+            .map(|x| x.to_ascii_uppercase())
+            .unwrap_or_default();
+        match sub_cmd.as_str() {
+            SUB_CMD_WHOAMI if !self.is_enabled() => Ok(AclReply::User(DEFAULT_USER.to_string())),
+            SUB_CMD_WHOAMI => Ok(AclReply::User(self.user(user.as_deref())?.name.clone())),
+            SUB_CMD_LIST if !self.is_enabled() => Ok(AclReply::Rules(vec![format!(
+                "user {} on nopass ~* +@all",
+                DEFAULT_USER
+            )])),
+            SUB_CMD_LIST => {
+                let current = self.user(user.as_deref())?;
+                if !current.allows(Category::Admin) {
+                    return Err(AsError::NoPermCommand(
+                        current.name.clone(),
+                        "acl|list".to_string(),
+                    ));
+                }
+                Ok(AclReply::Rules(self.users.iter().map(User::rule).collect()))
+            }
+            _ => Err(AsError::BadClientCommand(format!(
+                "unknown subcommand '{}'. Try ACL WHOAMI, ACL LIST.",
+                args.get(1).map(|x| x.as_str()).unwrap_or_default()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn user(name: &str, password: &str, commands: &[&str], keys: &[&str]) -> UserConfig {
+        UserConfig {
+            name: name.to_string(),
+            password: hash_password(password),
+            commands: args(commands),
+            keys: args(keys),
+        }
+    }
+
+    fn acl() -> Acl {
+        let users = vec![
+            user("app", "app-secret", &["read", "write"], &["app:*"]),
+            user("dashboard", "dash-secret", &["read"], &["*"]),
+            user("ops", "ops-secret", &["read", "write", "admin"], &["*"]),
+        ];
+        assert_eq!(validate(&users), Ok(()));
+        Acl::new(&users)
+    }
+
+    #[test]
+    fn test_validate_users() {
+        let mut bad = user("app", "secret", &["read"], &["*"]);
+        bad.password = "secret".to_string();
+        assert!(validate(&[bad]).is_err());
+        let bad = user("app", "secret", &["read", "everything"], &["*"]);
+        assert!(validate(&[bad]).is_err());
+        let twice = user("app", "secret", &["read"], &["*"]);
+        assert!(validate(&[twice.clone(), twice]).is_err());
+        assert!(validate(&[user("", "secret", &[], &[])]).is_err());
+        assert_eq!(validate(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_auth() {
+        let acl = acl();
+        let mut current = None;
+        assert_eq!(
+            acl.handle(&mut current, &args(&["AUTH", "app", "wrong"])),
+            Err(AsError::WrongPass)
+        );
+        assert_eq!(current, None);
+        // the password only is of the default user, which is absent
+        assert_eq!(
+            acl.handle(&mut current, &args(&["AUTH", "app-secret"])),
+            Err(AsError::WrongPass)
+        );
+        assert!(acl.handle(&mut current, &args(&["AUTH"])).is_err());
+        assert_eq!(
+            acl.handle(&mut current, &args(&["ACL", "WHOAMI"])),
+            Err(AsError::NoAuth)
+        );
+        assert_eq!(
+            acl.handle(&mut current, &args(&["auth", "app", "app-secret"])),
+            Ok(AclReply::Ok)
+        );
+        assert_eq!(current, Some("app".to_string()));
+        assert_eq!(
+            acl.handle(&mut current, &args(&["ACL", "whoami"])),
+            Ok(AclReply::User("app".to_string()))
+        );
+
+        // never enabled without users
+        let mut current = None;
+        let acl = Acl::default();
+        assert!(acl
+            .handle(&mut current, &args(&["AUTH", "secret"]))
+            .is_err());
+        assert_eq!(
+            acl.handle(&mut current, &args(&["ACL", "WHOAMI"])),
+            Ok(AclReply::User("default".to_string()))
+        );
+        assert_eq!(
+            acl.check(None, Category::Admin, "FLUSHALL", &[b"a"]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_check_permissions() {
+        let acl = acl();
+        assert_eq!(
+            acl.check(None, Category::Auth, "AUTH", &[] as &[&[u8]]),
+            Ok(())
+        );
+        assert_eq!(
+            acl.check(None, Category::Connection, "PING", &[] as &[&[u8]]),
+            Err(AsError::NoAuth)
+        );
+        assert_eq!(
+            acl.check(Some("removed"), Category::Read, "GET", &[b"a"]),
+            Err(AsError::NoAuth)
+        );
+
+        let app = Some("app");
+        assert_eq!(acl.check(app, Category::Read, "GET", &[b"app:1"]), Ok(()));
+        assert_eq!(
+            acl.check(app, Category::Write, "MSET", &[&b"app:1"[..], b"app:2"]),
+            Ok(())
+        );
+        // every key of fan-out is checked
+        assert_eq!(
+            acl.check(app, Category::Read, "MGET", &[&b"app:1"[..], b"other"]),
+            Err(AsError::NoPermKey)
+        );
+        assert_eq!(
+            acl.check(app, Category::Admin, "PROXY", &[] as &[&[u8]]),
+            Err(AsError::NoPermCommand(
+                "app".to_string(),
+                "proxy".to_string()
+            ))
+        );
+
+        let dashboard = Some("dashboard");
+        assert_eq!(acl.check(dashboard, Category::Read, "GET", &[b"a"]), Ok(()));
+        assert_eq!(
+            acl.check(dashboard, Category::Write, "SET", &[b"a"]),
+            Err(AsError::NoPermCommand(
+                "dashboard".to_string(),
+                "set".to_string()
+            ))
+        );
+        assert_eq!(
+            acl.check(dashboard, Category::Connection, "PING", &[] as &[&[u8]]),
+            Ok(())
+        );
+        // dangerous is never implied by admin
+        assert!(acl
+            .check(
+                Some("ops"),
+                Category::Dangerous,
+                "SHUTDOWN",
+                &[] as &[&[u8]]
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_acl_list() {
+        let acl = acl();
+        let mut current = Some("app".to_string());
+        assert_eq!(
+            acl.handle(&mut current, &args(&["ACL", "LIST"])),
+            Err(AsError::NoPermCommand(
+                "app".to_string(),
+                "acl|list".to_string()
+            ))
+        );
+        let mut current = Some("ops".to_string());
+        let rules = match acl.handle(&mut current, &args(&["ACL", "LIST"])) {
+            Ok(AclReply::Rules(rules)) => rules,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(rules.len(), 3);
+        assert_eq!(
+            rules[0],
+            format!(
+                "user app on #{} ~app:* +@read +@write",
+                hash_password("app-secret")
+            )
+        );
+        assert!(acl
+            .handle(&mut current, &args(&["ACL", "SETUSER", "x"]))
+            .is_err());
+    }
+}
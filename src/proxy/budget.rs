@@ -0,0 +1,139 @@
+//! retry budget of each cluster shared by all the worker threads, so the retries of all kinds
+//! (e.g.: retry_on_stale, the retry of sub_failures, the redirects and the READONLY refusals of
+//! redis cluster) can't amplify the traffic to backends beyond the percent of retry_budget
+//! during an incident.
+//!
+//! It's a token bucket: each reply succeeded deposits the percent of a retry, and each retry
+//! withdraws a whole one, or it's denied and the error is replied as is. The bucket starts empty
+//! and holds at most BURST retries, so only the recent successes are spent, and a backend down
+//! since start is never retried. The percent is replaced by reload, and 0 means unlimited.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::com::ClusterConfig;
+use crate::metrics::retry_budget_denied_incr;
+use crate::proxy::standalone::Request;
+
+// the tokens are counted in the thousandth of a retry
+const RETRY: u64 = 1000;
+const BURST: u64 = 1000;
+
+lazy_static! {
+    static ref BUDGETS: Mutex<HashMap<String, Arc<Budget>>> = Mutex::new(HashMap::new());
+}
+
+/// get the retry budget of the cluster, whose percent is replaced by the one of cc.
+pub fn handle(cc: &ClusterConfig) -> Arc<Budget> {
+    let mut all = BUDGETS.lock().unwrap();
+    let budget = all
+        .entry(cc.name.clone())
+        .or_insert_with(|| Arc::new(Budget::new(&cc.name)))
+        .clone();
+    budget
+        .percent
+        .store(cc.retry_budget.unwrap_or(0), Ordering::Relaxed);
+    budget
+}
+
+#[derive(Debug, Default)]
+pub struct Budget {
+    cluster: String,
+    percent: AtomicU64,
+    tokens: AtomicU64,
+}
+
+impl Budget {
+    fn new(cluster: &str) -> Budget {
+        Budget {
+            cluster: cluster.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.percent.load(Ordering::Relaxed) > 0
+    }
+
+    /// deposit the percent of a retry if the command is replied without error of proxy.
+    pub fn record<T: Request>(&self, cmd: &T) {
+        if !self.is_enabled() || cmd.is_error() {
+            return;
+        }
+        self.deposit();
+    }
+
+    fn deposit(&self) {
+        let percent = self.percent.load(Ordering::Relaxed);
+        let _ = self
+            .tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                Some((tokens + percent * RETRY / 100).min(BURST * RETRY))
+            });
+    }
+
+    /// withdraw a retry, false if the budget is exhausted and the retry must be skipped.
+    pub fn try_retry(&self) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let withdrawn = self
+            .tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                tokens.checked_sub(RETRY)
+            });
+        if withdrawn.is_err() {
+            retry_budget_denied_incr(&self.cluster);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn budget(name: &str, percent: u64) -> Arc<Budget> {
+        handle(&ClusterConfig {
+            name: name.to_string(),
+            retry_budget: Some(percent),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_retry_budget_disabled() {
+        let budget = budget("test-budget-disabled", 0);
+        assert!(!budget.is_enabled());
+        assert!((0..100).all(|_| budget.try_retry()));
+    }
+
+    #[test]
+    fn test_retry_budget_outage_never_amplified() {
+        let budget = budget("test-budget-outage", 10);
+        // down since start, nothing to spend
+        assert!(!budget.try_retry());
+
+        let succeeded = 1000u64;
+        (0..succeeded).for_each(|_| budget.deposit());
+        // every request fails and wants a retry during the outage
+        let failed = 10_000u64;
+        let retried = (0..failed).filter(|_| budget.try_retry()).count() as u64;
+        assert_eq!(retried, succeeded * 10 / 100);
+        let sent = succeeded + failed + retried;
+        assert!(sent * 100 <= (succeeded + failed) * 110);
+
+        // only the recent successes are spent
+        (0..100_000).for_each(|_| budget.deposit());
+        let retried = (0..failed).filter(|_| budget.try_retry()).count() as u64;
+        assert_eq!(retried, BURST);
+
+        // replaced by reload
+        let budget = handle(&ClusterConfig {
+            name: "test-budget-outage".to_string(),
+            ..Default::default()
+        });
+        assert!(budget.try_retry());
+    }
+}
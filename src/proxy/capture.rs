@@ -0,0 +1,541 @@
+//! traffic capture of each cluster, enabled by admin api for a bounded duration.
+//!
+//! Records are sent to a dedicated writer thread by a bounded channel and dropped on overload,
+//! so the capture never blocks the worker threads. The file is consisted with a header and
+//! length-prefixed records:
+//!
+//! ```text
+//! header: b"ASTERCAP" version(u8) protocol(u8)
+//! record: len(u32) kind(u8) time(u64, micros since epoch) client(u64) seq(u64) tick(u64) data
+//! ```
+//!
+//! data is the raw request for request record, and the md5 digest of reply for reply record.
+//! tick is the logical clock of cluster (see standalone::tick) when the request was decoded or
+//! the reply was flushed, 0 if trace log is disabled. The files of version 1 have no tick.
+pub mod replay;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::{Bytes, BytesMut};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::com::{AsError, CacheType, ClusterConfig};
+use crate::metrics::capture_dropped_incr;
+use crate::protocol::redis::prefix::save_with_mask;
+use crate::protocol::redis::MessageMut;
+
+const MAGIC: &[u8] = b"ASTERCAP";
+const VERSION: u8 = 2;
+// the version before tick was recorded
+const VERSION_NO_TICK: u8 = 1;
+const CHANNEL_SIZE: usize = 4096;
+const MAX_DURATION: u64 = 3600;
+const CHECK_INTERVAL: u64 = 1_000;
+
+pub const KIND_REQUEST: u8 = 1;
+pub const KIND_REPLY: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Proto {
+    Redis = 0,
+    Memcache = 1,
+}
+
+impl Proto {
+    fn from_u8(data: u8) -> Option<Proto> {
+        match data {
+            0 => Some(Proto::Redis),
+            1 => Some(Proto::Memcache),
+            _ => None,
+        }
+    }
+}
+
+impl From<CacheType> for Proto {
+    fn from(cache_type: CacheType) -> Proto {
+        match cache_type {
+            CacheType::Redis | CacheType::RedisCluster => Proto::Redis,
+            CacheType::Memcache | CacheType::MemcacheBinary => Proto::Memcache,
+        }
+    }
+}
+
+/// options of capture given by admin api.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CaptureOption {
+    pub path: String,
+    // capture duration in seconds
+    pub duration: u64,
+    // truncate every value of request into the given bytes
+    pub max_value: Option<usize>,
+    // replace every key of request by its digest
+    #[serde(default)]
+    pub hash_keys: bool,
+    // capture the digest of replies as baseline of replay
+    #[serde(default)]
+    pub replies: bool,
+}
+
+impl CaptureOption {
+    fn is_masked(&self) -> bool {
+        self.hash_keys || self.max_value.is_some()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub kind: u8,
+    pub time: u64,
+    pub client: u64,
+    pub seq: u64,
+    pub tick: u64,
+    pub data: Bytes,
+}
+
+impl Record {
+    fn new(kind: u8, client: u64, seq: u64, tick: u64, data: Bytes) -> Record {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_micros() as u64)
+            .unwrap_or(0);
+        Record {
+            kind,
+            time,
+            client,
+            seq,
+            tick,
+            data,
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u32::<BigEndian>(1 + 8 * 4 + self.data.len() as u32)?;
+        w.write_u8(self.kind)?;
+        w.write_u64::<BigEndian>(self.time)?;
+        w.write_u64::<BigEndian>(self.client)?;
+        w.write_u64::<BigEndian>(self.seq)?;
+        w.write_u64::<BigEndian>(self.tick)?;
+        w.write_all(&self.data)
+    }
+
+    /// read the next record of the file of version given by header, return None at the end of
+    /// file.
+    pub fn read_from<R: Read>(r: &mut R, version: u8) -> io::Result<Option<Record>> {
+        let len = match r.read_u32::<BigEndian>() {
+            Ok(len) => len as usize,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let fixed = if version == VERSION_NO_TICK {
+            1 + 8 * 3
+        } else {
+            1 + 8 * 4
+        };
+        if len < fixed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record too short",
+            ));
+        }
+        let kind = r.read_u8()?;
+        let time = r.read_u64::<BigEndian>()?;
+        let client = r.read_u64::<BigEndian>()?;
+        let seq = r.read_u64::<BigEndian>()?;
+        let tick = if version == VERSION_NO_TICK {
+            0
+        } else {
+            r.read_u64::<BigEndian>()?
+        };
+        let mut data = vec![0u8; len - fixed];
+        r.read_exact(&mut data)?;
+        Ok(Some(Record {
+            kind,
+            time,
+            client,
+            seq,
+            tick,
+            data: data.into(),
+        }))
+    }
+}
+
+pub fn write_header<W: Write>(w: &mut W, proto: Proto) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_u8(VERSION)?;
+    w.write_u8(proto as u8)
+}
+
+/// the protocol and version of the capture file, the version is given to read the records.
+pub fn read_header<R: Read>(r: &mut R) -> io::Result<(Proto, u8)> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    let version = r.read_u8()?;
+    if &magic[..] != MAGIC || (version != VERSION && version != VERSION_NO_TICK) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a capture file of aster",
+        ));
+    }
+    let proto = Proto::from_u8(r.read_u8()?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown protocol"))?;
+    Ok((proto, version))
+}
+
+struct Session {
+    generation: usize,
+    tx: SyncSender<Record>,
+    replies: bool,
+}
+
+struct Shared {
+    proto: Proto,
+    // 0 means not capturing, changed by each start and stop
+    generation: AtomicUsize,
+    session: Mutex<Option<Session>>,
+}
+
+lazy_static! {
+    static ref CAPTURES: Mutex<HashMap<String, Arc<Shared>>> = Mutex::new(HashMap::new());
+    static ref GENERATION: AtomicUsize = AtomicUsize::new(1);
+    static ref CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
+}
+
+/// unique id of client connection in capture file.
+pub fn next_client_id() -> u64 {
+    CLIENT_ID.fetch_add(1, Ordering::Relaxed) as u64
+}
+
+/// the capture handle of the cluster held by each worker thread.
+pub struct Capture {
+    cluster: String,
+    shared: Arc<Shared>,
+    local: RefCell<Option<Session>>,
+}
+
+pub fn handle(cc: &ClusterConfig) -> Capture {
+    let mut captures = CAPTURES.lock().unwrap();
+    let shared = captures
+        .entry(cc.name.clone())
+        .or_insert_with(|| {
+            Arc::new(Shared {
+                proto: cc.cache_type.into(),
+                generation: AtomicUsize::new(0),
+                session: Mutex::new(None),
+            })
+        })
+        .clone();
+    Capture {
+        cluster: cc.name.clone(),
+        shared,
+        local: RefCell::new(None),
+    }
+}
+
+impl Capture {
+    /// check the capture is running, which only costs an atomic load if not.
+    pub fn is_active(&self) -> bool {
+        let generation = self.shared.generation.load(Ordering::Relaxed);
+        let mut local = self.local.borrow_mut();
+        if generation == 0 {
+            local.take();
+            return false;
+        }
+        if local.as_ref().map(|x| x.generation) != Some(generation) {
+            *local = self
+                .shared
+                .session
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|x| Session {
+                    generation: x.generation,
+                    tx: x.tx.clone(),
+                    replies: x.replies,
+                });
+        }
+        local.is_some()
+    }
+
+    pub fn is_replying(&self) -> bool {
+        self.is_active() && self.local.borrow().as_ref().map(|x| x.replies) == Some(true)
+    }
+
+    pub fn request(&self, client: u64, seq: u64, tick: u64, data: Bytes) {
+        self.send(Record::new(KIND_REQUEST, client, seq, tick, data));
+    }
+
+    pub fn reply(&self, client: u64, seq: u64, tick: u64, data: Bytes) {
+        self.send(Record::new(KIND_REPLY, client, seq, tick, data));
+    }
+
+    fn send(&self, record: Record) {
+        let mut local = self.local.borrow_mut();
+        let closed = match local.as_ref().map(|x| x.tx.try_send(record)) {
+            Some(Err(TrySendError::Full(_))) => {
+                capture_dropped_incr(&self.cluster);
+                false
+            }
+            Some(Err(TrySendError::Disconnected(_))) => true,
+            _ => false,
+        };
+        if closed {
+            local.take();
+        }
+    }
+}
+
+/// start to capture the traffic of the cluster into file, return false if the cluster is
+/// not running.
+pub fn start(cluster: &str, opt: CaptureOption) -> Result<bool, AsError> {
+    let shared = match CAPTURES.lock().unwrap().get(cluster) {
+        Some(shared) => shared.clone(),
+        None => return Ok(false),
+    };
+    if opt.duration == 0 || opt.duration > MAX_DURATION {
+        return Err(AsError::BadCapture(format!(
+            "duration must be in 1..={} seconds",
+            MAX_DURATION
+        )));
+    }
+    if opt.is_masked() && shared.proto != Proto::Redis {
+        return Err(AsError::BadCapture(
+            "max_value and hash_keys only support redis".to_string(),
+        ));
+    }
+
+    let mut session = shared.session.lock().unwrap();
+    if session.is_some() {
+        return Err(AsError::BadCapture("capture is running".to_string()));
+    }
+    let mut file = BufWriter::new(File::create(&opt.path)?);
+    write_header(&mut file, shared.proto)?;
+
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = sync_channel(CHANNEL_SIZE);
+    session.replace(Session {
+        generation,
+        tx,
+        replies: opt.replies,
+    });
+    shared.generation.store(generation, Ordering::SeqCst);
+
+    let name = cluster.to_string();
+    let writer = shared.clone();
+    thread::Builder::new()
+        .name(format!("aster-capture-{}", cluster))
+        .spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(opt.duration);
+            if let Err(err) = write_loop(&writer, generation, deadline, &opt, rx, &mut file) {
+                error!("fail to capture cluster {} due to {}", name, err);
+            }
+            let _ = file.flush();
+            stop_generation(&writer, generation);
+            info!("capture of cluster {} into {} is finished", name, opt.path);
+        })?;
+    Ok(true)
+}
+
+/// stop the running capture of the cluster, return false if the cluster is not running.
+pub fn stop(cluster: &str) -> bool {
+    match CAPTURES.lock().unwrap().get(cluster) {
+        Some(shared) => {
+            let generation = shared.generation.load(Ordering::SeqCst);
+            stop_generation(shared, generation);
+            true
+        }
+        None => false,
+    }
+}
+
+fn stop_generation(shared: &Shared, generation: usize) {
+    let mut session = shared.session.lock().unwrap();
+    if session.as_ref().map(|x| x.generation) == Some(generation) {
+        session.take();
+        shared.generation.store(0, Ordering::SeqCst);
+    }
+}
+
+fn write_loop<W: Write>(
+    shared: &Shared,
+    generation: usize,
+    deadline: Instant,
+    opt: &CaptureOption,
+    rx: Receiver<Record>,
+    w: &mut W,
+) -> io::Result<()> {
+    loop {
+        let now = Instant::now();
+        if now >= deadline || shared.generation.load(Ordering::SeqCst) != generation {
+            return Ok(());
+        }
+        let timeout = (deadline - now).min(Duration::from_millis(CHECK_INTERVAL));
+        let mut record = match rx.recv_timeout(timeout) {
+            Ok(record) => record,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        if record.kind == KIND_REPLY {
+            let md5::Digest(digest) = md5::compute(&record.data);
+            record.data = Bytes::from(&digest[..]);
+        } else if opt.is_masked() {
+            record.data = mask(&record.data, opt);
+        }
+        record.write_to(w)?;
+    }
+}
+
+// mask the keys and values of redis request, which is kept as it is if it can't be parsed
+fn mask(data: &[u8], opt: &CaptureOption) -> Bytes {
+    let mut src = BytesMut::from(data);
+    let msg = match MessageMut::parse(&mut src) {
+        Ok(Some(msg)) => msg,
+        _ => return Bytes::from(data),
+    };
+    let args: Vec<&[u8]> = (0..).map_while(|i| msg.nth(i)).collect();
+    let mut buf = BytesMut::new();
+    save_with_mask(&args, opt.hash_keys, opt.max_value, &mut buf);
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::capture_dropped_get;
+    use std::fs;
+
+    fn read_all(path: &str) -> (Proto, Vec<Record>) {
+        let mut file = File::open(path).unwrap();
+        let (proto, version) = read_header(&mut file).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = Record::read_from(&mut file, version).unwrap() {
+            records.push(record);
+        }
+        (proto, records)
+    }
+
+    #[test]
+    fn test_capture_record_roundtrip() {
+        let record = Record::new(
+            KIND_REQUEST,
+            7,
+            3,
+            42,
+            Bytes::from(&b"*1\r\n$4\r\nPING\r\n"[..]),
+        );
+        let mut buf = Vec::new();
+        write_header(&mut buf, Proto::Memcache).unwrap();
+        record.write_to(&mut buf).unwrap();
+
+        let mut cursor = io::Cursor::new(buf);
+        assert_eq!(
+            read_header(&mut cursor).unwrap(),
+            (Proto::Memcache, VERSION)
+        );
+        assert_eq!(
+            Record::read_from(&mut cursor, VERSION).unwrap(),
+            Some(record)
+        );
+        assert_eq!(Record::read_from(&mut cursor, VERSION).unwrap(), None);
+
+        // the records of version 1 are read without tick
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&[VERSION_NO_TICK, Proto::Redis as u8]);
+        buf.write_u32::<BigEndian>(1 + 8 * 3 + 2).unwrap();
+        buf.write_u8(KIND_REPLY).unwrap();
+        for value in &[1u64, 7, 3] {
+            buf.write_u64::<BigEndian>(*value).unwrap();
+        }
+        buf.extend_from_slice(b"ok");
+        let mut cursor = io::Cursor::new(buf);
+        assert_eq!(
+            read_header(&mut cursor).unwrap(),
+            (Proto::Redis, VERSION_NO_TICK)
+        );
+        let record = Record::read_from(&mut cursor, VERSION_NO_TICK)
+            .unwrap()
+            .unwrap();
+        assert_eq!((record.client, record.seq, record.tick), (7, 3, 0));
+        assert_eq!(&record.data[..], b"ok");
+    }
+
+    #[test]
+    fn test_capture_drop_on_overload() {
+        let (tx, _rx) = sync_channel(1);
+        let capture = Capture {
+            cluster: "test-capture-drop".to_string(),
+            shared: Arc::new(Shared {
+                proto: Proto::Redis,
+                generation: AtomicUsize::new(0),
+                session: Mutex::new(None),
+            }),
+            local: RefCell::new(Some(Session {
+                generation: 1,
+                tx,
+                replies: false,
+            })),
+        };
+        let req = Bytes::from(&b"*1\r\n$4\r\nPING\r\n"[..]);
+        capture.request(1, 0, 0, req.clone());
+        assert_eq!(capture_dropped_get("test-capture-drop"), 0);
+        capture.request(1, 1, 0, req);
+        assert_eq!(capture_dropped_get("test-capture-drop"), 1);
+    }
+
+    #[test]
+    fn test_capture_masked() {
+        let cc = ClusterConfig {
+            name: "test-capture".to_string(),
+            cache_type: CacheType::Redis,
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join("aster-test-capture.cap");
+        let path = path.to_str().unwrap().to_string();
+        let opt = CaptureOption {
+            path: path.clone(),
+            duration: 1,
+            max_value: Some(2),
+            hash_keys: false,
+            replies: true,
+        };
+        assert!(!start(&cc.name, opt.clone()).unwrap());
+
+        let capture = handle(&cc);
+        assert!(!capture.is_active());
+        assert!(start(&cc.name, opt.clone()).unwrap());
+        assert!(start(&cc.name, opt).is_err());
+        assert!(capture.is_replying());
+
+        let req = Bytes::from(&b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$5\r\nhello\r\n"[..]);
+        capture.request(1, 0, 5, req.clone());
+        capture.request(2, 0, 6, req);
+        capture.reply(1, 0, 9, Bytes::from(&b"+OK\r\n"[..]));
+
+        // finished after the duration
+        while capture.is_active() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let (proto, records) = read_all(&path);
+        assert_eq!(proto, Proto::Redis);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1].client, 2);
+        assert_eq!(records[1].tick, 6);
+        assert_eq!(
+            &records[0].data[..],
+            &b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$2\r\nhe\r\n"[..]
+        );
+        let md5::Digest(digest) = md5::compute(b"+OK\r\n");
+        assert_eq!(records[2].kind, KIND_REPLY);
+        assert_eq!(&records[2].data[..], &digest[..]);
+        assert_eq!(records[2].tick, 9);
+        let _ = fs::remove_file(&path);
+    }
+}
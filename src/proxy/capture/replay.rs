@@ -0,0 +1,275 @@
+//! replay the captured traffic against the target address, each captured client is replayed
+//! by its own connection to keep the order of requests.
+use bytes::BytesMut;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::com::AsError;
+use crate::protocol::redis::MessageMut;
+use crate::proxy::capture::{read_header, Proto, Record, KIND_REPLY, KIND_REQUEST};
+
+const READ_TIMEOUT: u64 = 5_000;
+const READ_BUF_SIZE: usize = 16 * 1024;
+
+type Digest = [u8; 16];
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub sent: usize,
+    pub replied: usize,
+    // replies compared with the captured baseline
+    pub compared: usize,
+    pub mismatched: usize,
+    pub failed: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sent: {}, replied: {}, compared: {}, mismatched: {}, failed: {}",
+            self.sent, self.replied, self.compared, self.mismatched, self.failed
+        )
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    replied: AtomicUsize,
+    compared: AtomicUsize,
+    mismatched: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+struct Conn {
+    stream: TcpStream,
+    expect: Sender<Option<Digest>>,
+    reader: JoinHandle<()>,
+}
+
+/// replay the capture file against target, speed scales the original interval of requests
+/// and 0 means as fast as possible.
+pub fn run(path: &str, target: &str, speed: f64) -> Result<Report, AsError> {
+    let baseline = load_baseline(path)?;
+    let mut input = BufReader::new(File::open(path)?);
+    let (proto, version) = read_header(&mut input)?;
+
+    let stats = Arc::new(Stats::default());
+    let mut conns: HashMap<u64, Conn> = HashMap::new();
+    let mut report = Report::default();
+    let begin = Instant::now();
+    let mut first = None;
+
+    while let Some(record) = Record::read_from(&mut input, version)? {
+        if record.kind != KIND_REQUEST {
+            continue;
+        }
+        let first = *first.get_or_insert(record.time);
+        if speed > 0.0 {
+            let offset = record.time.saturating_sub(first) as f64 / speed;
+            let at = begin + Duration::from_micros(offset as u64);
+            let now = Instant::now();
+            if at > now {
+                thread::sleep(at - now);
+            }
+        }
+
+        if !conns.contains_key(&record.client) {
+            let conn = connect(target, proto, stats.clone())?;
+            conns.insert(record.client, conn);
+        }
+        let conn = conns
+            .get_mut(&record.client)
+            .expect("conn must be inserted");
+        if let Err(err) = conn.stream.write_all(&record.data) {
+            warn!("fail to replay request to {} due to {}", target, err);
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        report.sent += 1;
+        let expect = baseline.get(&(record.client, record.seq)).cloned();
+        let _ = conn.expect.send(expect);
+    }
+
+    for (_, conn) in conns.into_iter() {
+        drop(conn.expect);
+        let _ = conn.reader.join();
+    }
+    report.replied = stats.replied.load(Ordering::Relaxed);
+    report.compared = stats.compared.load(Ordering::Relaxed);
+    report.mismatched = stats.mismatched.load(Ordering::Relaxed);
+    report.failed = stats.failed.load(Ordering::Relaxed);
+    Ok(report)
+}
+
+// digest of the captured replies, which is keyed by client and seq of request
+fn load_baseline(path: &str) -> Result<HashMap<(u64, u64), Digest>, AsError> {
+    let mut input = BufReader::new(File::open(path)?);
+    let (_, version) = read_header(&mut input)?;
+    let mut baseline = HashMap::new();
+    while let Some(record) = Record::read_from(&mut input, version)? {
+        if record.kind == KIND_REPLY && record.data.len() == 16 {
+            let mut digest = [0u8; 16];
+            digest.copy_from_slice(&record.data);
+            baseline.insert((record.client, record.seq), digest);
+        }
+    }
+    Ok(baseline)
+}
+
+fn connect(target: &str, proto: Proto, stats: Arc<Stats>) -> Result<Conn, AsError> {
+    let stream = TcpStream::connect(target)?;
+    stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT)))?;
+    let read_stream = stream.try_clone()?;
+    let (expect, expect_rx) = channel();
+    let reader = thread::Builder::new()
+        .name("aster-replay-reader".to_string())
+        .spawn(move || match proto {
+            Proto::Redis => read_redis(read_stream, expect_rx, &stats),
+            Proto::Memcache => read_memcache(read_stream, expect_rx, &stats),
+        })?;
+    Ok(Conn {
+        stream,
+        expect,
+        reader,
+    })
+}
+
+// one reply for each request, compared with the baseline if it's captured
+fn read_redis(mut stream: TcpStream, expect: Receiver<Option<Digest>>, stats: &Stats) {
+    let mut buf = BytesMut::new();
+    for digest in expect.iter() {
+        let reply = loop {
+            match MessageMut::parse(&mut buf) {
+                Ok(Some(msg)) => break msg,
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("fail to parse replayed reply due to {}", err);
+                    stats.failed.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+            if let Err(err) = fill(&mut stream, &mut buf) {
+                warn!("fail to read replayed reply due to {}", err);
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        stats.replied.fetch_add(1, Ordering::Relaxed);
+        if let Some(digest) = digest {
+            stats.compared.fetch_add(1, Ordering::Relaxed);
+            let md5::Digest(actual) = md5::compute(&reply.data);
+            if actual != digest {
+                stats.mismatched.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// memcache replies are not framed one by one (e.g.: noreply), so they are only drained.
+fn read_memcache(mut stream: TcpStream, expect: Receiver<Option<Digest>>, stats: &Stats) {
+    let mut buf = [0u8; READ_BUF_SIZE];
+    for _ in expect.iter() {}
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut => return,
+            Err(err) => {
+                warn!("fail to read replayed reply due to {}", err);
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+fn fill(stream: &mut TcpStream, buf: &mut BytesMut) -> io::Result<()> {
+    let mut chunk = [0u8; READ_BUF_SIZE];
+    let size = stream.read(&mut chunk)?;
+    if size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed",
+        ));
+    }
+    buf.extend_from_slice(&chunk[..size]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proxy::capture::write_header;
+    use bytes::Bytes;
+    use std::fs;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_replay_mismatch() {
+        let path = std::env::temp_dir().join("aster-test-replay.cap");
+        let path = path.to_str().unwrap().to_string();
+        let req = Bytes::from(&b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..]);
+        let md5::Digest(ok) = md5::compute(b"$1\r\n1\r\n");
+        let md5::Digest(bad) = md5::compute(b"$-1\r\n");
+        let records = vec![
+            (KIND_REQUEST, 1, 0, req.clone()),
+            (KIND_REQUEST, 1, 1, req.clone()),
+            (KIND_REPLY, 1, 0, Bytes::from(&ok[..])),
+            (KIND_REPLY, 1, 1, Bytes::from(&bad[..])),
+            (KIND_REQUEST, 2, 0, req),
+        ];
+        let mut file = File::create(&path).unwrap();
+        write_header(&mut file, Proto::Redis).unwrap();
+        for (kind, client, seq, data) in records {
+            let record = Record {
+                kind,
+                time: 0,
+                client,
+                seq,
+                tick: 0,
+                data,
+            };
+            record.write_to(&mut file).unwrap();
+        }
+        drop(file);
+
+        // target replies "1" for every GET
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = BytesMut::new();
+                    loop {
+                        while let Ok(Some(_)) = MessageMut::parse(&mut buf) {
+                            stream.write_all(b"$1\r\n1\r\n").unwrap();
+                        }
+                        if fill(&mut stream, &mut buf).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        let report = run(&path, &target, 0.0).unwrap();
+        assert_eq!(report.sent, 3);
+        assert_eq!(report.replied, 3);
+        assert_eq!(report.compared, 2);
+        assert_eq!(report.mismatched, 1);
+        assert_eq!(report.failed, 0);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,390 @@
+//! front connections of each cluster shared by all the worker threads, which can be killed by
+//! CLIENT KILL received from any of them, e.g.:
+//!
+//!     CLIENT KILL ID 42
+//!     CLIENT KILL ADDR 127.0.0.1:50001 SKIPME no
+//!
+//! The filters are ANDed and replied by the number of connections killed. The killed connection
+//! is closed at once without replying its requests in flight, and the connection sending CLIENT
+//! KILL is never killed by itself unless SKIPME no.
+//!
+//! The connections are drained for the rollouts by admin api: the listeners stop accepting at
+//! once, and the connections are marked drained by the rate per second, the oldest first. The
+//! drained connection is closed once it's idle, and the next request received is replied by
+//! "ERR server shutting down" before closed, while the requests in flight are replied as usual.
+use futures::task::Task;
+use futures::{Async, Future, Stream};
+use tokio::timer::Interval;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::com::{AsError, ClusterConfig};
+
+const FILTER_ID: &str = "ID";
+const FILTER_ADDR: &str = "ADDR";
+const FILTER_SKIPME: &str = "SKIPME";
+
+pub const DEFAULT_DRAIN_RATE: u64 = 100;
+const DRAIN_TICK: u64 = 100;
+
+lazy_static! {
+    static ref CLIENTS: Mutex<HashMap<String, Arc<Clients>>> = Mutex::new(HashMap::new());
+}
+
+/// get the front connections of the cluster.
+pub fn handle(cc: &ClusterConfig) -> Arc<Clients> {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .entry(cc.name.clone())
+        .or_insert_with(Default::default)
+        .clone()
+}
+
+/// the front connections of the cluster, None if it's never started.
+pub fn get(cluster: &str) -> Option<Arc<Clients>> {
+    CLIENTS.lock().unwrap().get(cluster).cloned()
+}
+
+/// flags of the connection set from any worker thread, the connection is woken up by its task.
+#[derive(Debug, Default)]
+pub struct ClientFlags {
+    // by CLIENT KILL
+    killed: AtomicBool,
+    // by the draining of clients
+    drained: AtomicBool,
+}
+
+impl ClientFlags {
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    pub fn is_drained(&self) -> bool {
+        self.drained.load(Ordering::Relaxed)
+    }
+}
+
+struct Client {
+    addr: String,
+    flags: Arc<ClientFlags>,
+    task: Task,
+}
+
+#[derive(Default)]
+pub struct Clients {
+    conns: Mutex<HashMap<u64, Client>>,
+    draining: AtomicBool,
+}
+
+/// options of the draining given by admin api.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DrainOption {
+    // connections drained per second, 100 by default
+    pub rate: Option<u64>,
+}
+
+/// progress of the draining of cluster.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DrainProgress {
+    pub draining: bool,
+    // connections not closed yet
+    pub remaining: usize,
+    // connections not marked drained yet
+    pub pending: usize,
+}
+
+impl fmt::Display for DrainProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "draining: {}, remaining: {}, pending: {}",
+            self.draining, self.remaining, self.pending
+        )
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Filter {
+    id: Option<u64>,
+    addr: Option<String>,
+    skip_me: bool,
+}
+
+impl Filter {
+    fn parse(args: &[String]) -> Result<Filter, AsError> {
+        let syntax = || AsError::BadClientCommand("syntax error".to_string());
+        if args.is_empty() || args.len() % 2 != 0 {
+            return Err(syntax());
+        }
+        let mut filter = Filter {
+            skip_me: true,
+            ..Default::default()
+        };
+        for pair in args.chunks(2) {
+            let value = &pair[1];
+            match pair[0].to_uppercase().as_str() {
+                FILTER_ID => {
+                    let id = value.parse().map_err(|_| {
+                        AsError::BadClientCommand("client-id should be greater than 0".to_string())
+                    })?;
+                    filter.id = Some(id);
+                }
+                FILTER_ADDR => filter.addr = Some(value.clone()),
+                FILTER_SKIPME => {
+                    filter.skip_me = match value.to_lowercase().as_str() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(syntax()),
+                    }
+                }
+                _ => return Err(syntax()),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, id: u64, client: &Client) -> bool {
+        if self.id.map(|x| x != id).unwrap_or(false) {
+            return false;
+        }
+        match self.addr {
+            Some(ref addr) => *addr == client.addr,
+            None => true,
+        }
+    }
+}
+
+impl Clients {
+    /// the connection is closed once the returned flags are set, and woken up by the task. The
+    /// one accepted after the draining started is drained at once.
+    pub fn register(&self, id: u64, addr: &str, task: Task) -> Arc<ClientFlags> {
+        let flags = Arc::new(ClientFlags::default());
+        flags.drained.store(self.is_draining(), Ordering::Relaxed);
+        let client = Client {
+            addr: addr.to_string(),
+            flags: flags.clone(),
+            task,
+        };
+        self.conns.lock().unwrap().insert(id, client);
+        flags
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.conns.lock().unwrap().remove(&id);
+    }
+
+    /// kill the connections matching the filters of CLIENT KILL sent by the connection of me,
+    /// return the number of connections killed.
+    pub fn kill(&self, me: u64, args: &[String]) -> Result<usize, AsError> {
+        let filter = Filter::parse(args)?;
+        let mut conns = self.conns.lock().unwrap();
+        let ids: Vec<_> = conns
+            .iter()
+            .filter(|(id, x)| !(filter.skip_me && **id == me) && filter.matches(**id, x))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids.iter() {
+            let client = conns.remove(id).expect("client must exist");
+            info!("kill client {} of id {} by CLIENT KILL", client.addr, id);
+            client.flags.killed.store(true, Ordering::SeqCst);
+            client.task.notify();
+        }
+        Ok(ids.len())
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// resolved once draining, the listeners stop accepting then.
+    pub fn draining(self: &Arc<Self>) -> Draining {
+        Draining {
+            clients: self.clone(),
+            interval: Interval::new(
+                Instant::now() + Duration::from_millis(DRAIN_TICK),
+                Duration::from_millis(DRAIN_TICK),
+            ),
+        }
+    }
+
+    /// start draining the connections by the rate per second in a thread.
+    pub fn drain(self: &Arc<Self>, cluster: &str, opt: DrainOption) -> Result<(), AsError> {
+        let rate = opt.rate.unwrap_or(DEFAULT_DRAIN_RATE);
+        if rate == 0 {
+            return Err(AsError::BadDrain("rate must be positive".to_string()));
+        }
+        if self.draining.swap(true, Ordering::SeqCst) {
+            return Err(AsError::BadDrain("clients are draining".to_string()));
+        }
+        let clients = self.clone();
+        let name = cluster.to_string();
+        thread::Builder::new()
+            .name(format!("aster-drain-{}", cluster))
+            .spawn(move || {
+                let mut allowance = 0.0;
+                loop {
+                    thread::sleep(Duration::from_millis(DRAIN_TICK));
+                    allowance += rate as f64 * DRAIN_TICK as f64 / 1000.0;
+                    let count = allowance.floor();
+                    allowance -= count;
+                    clients.drain_oldest(count as usize);
+                    if clients.progress().pending == 0 {
+                        info!("all clients of cluster {} are marked drained", name);
+                        return;
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    // mark the oldest count connections not drained yet.
+    fn drain_oldest(&self, count: usize) -> usize {
+        let conns = self.conns.lock().unwrap();
+        let mut ids: Vec<_> = conns
+            .iter()
+            .filter(|(_, x)| !x.flags.is_drained())
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids.truncate(count);
+        for id in ids.iter() {
+            let client = &conns[id];
+            debug!("drain client {} of id {}", client.addr, id);
+            client.flags.drained.store(true, Ordering::SeqCst);
+            client.task.notify();
+        }
+        ids.len()
+    }
+
+    pub fn progress(&self) -> DrainProgress {
+        let conns = self.conns.lock().unwrap();
+        DrainProgress {
+            draining: self.is_draining(),
+            remaining: conns.len(),
+            pending: conns.values().filter(|x| !x.flags.is_drained()).count(),
+        }
+    }
+}
+
+pub struct Draining {
+    clients: Arc<Clients>,
+    interval: Interval,
+}
+
+impl Future for Draining {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            if self.clients.is_draining() {
+                return Ok(Async::Ready(()));
+            }
+            match self.interval.poll() {
+                Ok(Async::Ready(_)) => {}
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    error!("fail to poll draining interval due {:?}", err);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::future::lazy;
+    use futures::task;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            Filter::parse(&args(&["id", "42", "addr", "127.0.0.1:50001"])).unwrap(),
+            Filter {
+                id: Some(42),
+                addr: Some("127.0.0.1:50001".to_string()),
+                skip_me: true,
+            }
+        );
+        assert!(!Filter::parse(&args(&["SKIPME", "no"])).unwrap().skip_me);
+        assert!(Filter::parse(&args(&[])).is_err());
+        assert!(Filter::parse(&args(&["127.0.0.1:50001"])).is_err());
+        assert!(Filter::parse(&args(&["ID", "x"])).is_err());
+        assert!(Filter::parse(&args(&["TYPE", "normal"])).is_err());
+        assert!(Filter::parse(&args(&["SKIPME", "maybe"])).is_err());
+    }
+
+    #[test]
+    fn test_drain_oldest_first() {
+        let clients = Arc::new(Clients::default());
+        let flags = lazy(|| {
+            let flags: Vec<_> = (0..5)
+                .map(|i| clients.register(i, "127.0.0.1:50001", task::current()))
+                .collect();
+            Ok::<_, ()>(flags)
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(clients.drain_oldest(2), 2);
+        let drained: Vec<_> = flags.iter().map(|x| x.is_drained()).collect();
+        assert_eq!(drained, vec![true, true, false, false, false]);
+        assert!(!flags[0].is_killed());
+        assert_eq!(
+            clients.progress(),
+            DrainProgress {
+                draining: false,
+                remaining: 5,
+                pending: 3,
+            }
+        );
+
+        clients.unregister(0);
+        assert_eq!(clients.drain_oldest(10), 3);
+        assert_eq!(
+            clients.progress().to_string(),
+            "draining: false, remaining: 4, pending: 0"
+        );
+    }
+
+    #[test]
+    fn test_drain_clients() {
+        let clients = Arc::new(Clients::default());
+        let bad = DrainOption { rate: Some(0) };
+        assert!(clients.drain("test-drain", bad).is_err());
+        assert!(!clients.is_draining());
+
+        let flags = lazy(|| Ok::<_, ()>(clients.register(1, "127.0.0.1:50001", task::current())))
+            .wait()
+            .unwrap();
+        clients.drain("test-drain", DrainOption::default()).unwrap();
+        assert!(clients.is_draining());
+        assert!(clients.drain("test-drain", DrainOption::default()).is_err());
+
+        // accepted after the draining started
+        let late = lazy(|| Ok::<_, ()>(clients.register(2, "127.0.0.1:50002", task::current())))
+            .wait()
+            .unwrap();
+        assert!(late.is_drained());
+        for _ in 0..50 {
+            if flags.is_drained() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(DRAIN_TICK));
+        }
+        assert!(flags.is_drained());
+        assert_eq!(clients.progress().pending, 0);
+    }
+}
@@ -3,18 +3,43 @@ pub mod fetcher;
 pub mod front;
 pub mod init;
 pub mod redirect;
+pub mod replica;
+pub mod session;
+pub mod slotstat;
 
+use crate::com::connect_backend;
 use crate::com::create_reuse_port_listener;
 use crate::com::meta::meta_init;
 use crate::com::set_read_write_timeout;
 use crate::com::AsError;
 use crate::com::ClusterConfig;
+use crate::com::{set_keepalive, Keepalive};
+use crate::com::{BackendOverload, BlockingCommands, DEFAULT_BACKEND_QUEUE_LIMIT};
+use crate::protocol::redis::legacy::Shims;
 use crate::protocol::redis::{new_read_only_cmd, RedisHandleCodec, RedisNodeCodec};
 use crate::protocol::redis::{Cmd, ReplicaLayout, SLOTS_COUNT};
+use crate::protocol::{ArgsLimit, SubsLimit, ValueLimit};
+use crate::proxy::accept::Accept;
+use crate::proxy::acl::Acl;
+use crate::proxy::budget::{self, Budget};
+use crate::proxy::capture::{self, Capture};
+use crate::proxy::clients::{self, Clients};
 use crate::proxy::cluster::fetcher::SingleFlightTrigger;
+use crate::proxy::cluster::replica::{Latencies, Outstanding, Strategy};
+use crate::proxy::cluster::slotstat::{self, SlotStats};
+use crate::proxy::fault::{self, Injector};
+use crate::proxy::hook::{self, Hooks};
+use crate::proxy::info;
+use crate::proxy::maintenance;
+use crate::proxy::readonly;
+use crate::proxy::shard::{Position, Role, Shard};
+use crate::proxy::slo::{self, Slo};
+use crate::proxy::standalone::Request;
+use crate::proxy::worker::{Control, Worker};
 use crate::utils::crc::crc16;
+use crate::utils::trim_hash_tag;
 
-use crate::metrics::{front_conn_incr, thread_incr};
+use crate::metrics::{front_conn_incr, role_confusion_incr, thread_incr};
 
 // use failure::Error;
 use futures::future::ok;
@@ -24,8 +49,6 @@ use futures::unsync::mpsc::{channel, Sender};
 use futures::AsyncSink;
 use futures::{Sink, Stream};
 
-use tokio::net::TcpStream;
-use tokio::prelude::FutureExt;
 use tokio::runtime::current_thread;
 use tokio::timer::Interval;
 use tokio_codec::Decoder;
@@ -34,6 +57,8 @@ use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
@@ -43,6 +68,8 @@ const DEFAULT_FETCH_INTERVAL_MS: u64 = 10 * 60 * 1000; // 10 min
 pub enum Redirect {
     Move { slot: usize, to: String },
     Ask { slot: usize, to: String },
+    // refused by READONLY of the node, whose role is mistaken during failover
+    Readonly { slot: usize, from: String },
 }
 
 impl Redirect {
@@ -87,14 +114,34 @@ pub struct Cluster {
     conns: RefCell<Conns>,
     hash_tag: Vec<u8>,
     read_from_slave: bool,
+    replica_strategy: Box<dyn Strategy>,
+    outstanding: Outstanding,
+    latencies: Latencies,
 
     moved: Sender<Redirection>,
     fetch: RefCell<Option<Rc<SingleFlightTrigger>>>,
     latest: RefCell<Instant>,
+    read_only: Arc<AtomicBool>,
+    maintenance: Arc<AtomicBool>,
+    pub(crate) capture: Capture,
+    pub(crate) fault: Injector,
+    pub(crate) hooks: Hooks<Cmd>,
+    pub(crate) clients: Arc<Clients>,
+    // users of the clients
+    pub(crate) acl: Acl,
+    pub(crate) slot_stats: Arc<SlotStats>,
+    pub(crate) slo: Arc<Slo>,
+    // retries of all kinds shared by the workers
+    pub(crate) budget: Arc<Budget>,
+    pub(crate) worker: Rc<Worker>,
 }
 
 impl Cluster {
-    pub(crate) fn run(cc: ClusterConfig, replica: ReplicaLayout) -> Result<(), AsError> {
+    pub(crate) fn run(
+        cc: ClusterConfig,
+        replica: ReplicaLayout,
+        worker: Rc<Worker>,
+    ) -> Result<(), AsError> {
         let addr = cc
             .listen_addr
             .parse::<SocketAddr>()
@@ -102,15 +149,15 @@ impl Cluster {
         let fut = ok::<ClusterConfig, AsError>(cc)
             .and_then(|mut cc| {
                 let read_from_slave = cc.read_from_slave.clone().unwrap_or(false);
-                let hash_tag = cc
-                    .hash_tag
-                    .clone()
-                    .map(|x| x.as_bytes().to_vec())
-                    .unwrap_or_else(|| vec![]);
+                let hash_tag = cc.hash_tag();
                 let mut slots = Slots::default();
                 let (masters, replicas) = replica;
                 slots.try_update_all(masters, replicas);
                 let (moved, moved_rx) = channel(10240);
+                let budget = budget::handle(&cc);
+                let outstanding = Outstanding::default();
+                let latencies = Latencies::default();
+                let replica_strategy = replica::new_strategy(&cc, &outstanding, &latencies);
 
                 let all_masters = slots.get_all_masters();
                 let mut all_lived = HashSet::new();
@@ -124,6 +171,10 @@ impl Cluster {
                         .node(master.clone())
                         .read_timeout(cc.read_timeout.clone())
                         .write_timeout(cc.write_timeout.clone())
+                        .keepalive(cc.tcp_keepalive())
+                        .inflight(outstanding.track(&master))
+                        .latency(latencies.track(&master))
+                        .budget(budget.clone())
                         .connect()?;
                     conns.insert(&master, conn);
                     all_lived.insert(master.clone());
@@ -138,21 +189,48 @@ impl Cluster {
                             .node(slave.clone())
                             .read_timeout(cc.read_timeout.clone())
                             .write_timeout(cc.write_timeout.clone())
+                            .keepalive(cc.tcp_keepalive())
+                            .inflight(outstanding.track(&slave))
+                            .latency(latencies.track(&slave))
+                            .budget(budget.clone())
                             .replica(true)
                             .connect()?;
                         conns.insert(&slave, conn);
                         all_lived.insert(slave.clone());
                     }
                 }
+                let read_only = readonly::handle(&cc);
+                let maintenance = maintenance::handle(&cc.name);
+                let capture = capture::handle(&cc);
+                let fault = fault::handle(&cc);
+                let hooks = hook::handle(&cc);
+                let clients = clients::handle(&cc);
+                let acl = Acl::new(&cc.users);
+                let slot_stats = slotstat::handle(&cc);
+                let slo = slo::handle(&cc);
                 let cluster = Cluster {
                     cc: RefCell::new(cc),
                     hash_tag,
                     read_from_slave,
+                    replica_strategy,
+                    outstanding,
+                    latencies,
                     moved,
                     slots: RefCell::new(slots),
                     conns: RefCell::new(conns),
                     fetch: RefCell::new(None),
                     latest: RefCell::new(Instant::now()),
+                    read_only,
+                    maintenance,
+                    capture,
+                    fault,
+                    hooks,
+                    clients,
+                    acl,
+                    slot_stats,
+                    slo,
+                    budget,
+                    worker,
                 };
                 Ok((cluster, moved_rx))
             })
@@ -187,9 +265,16 @@ impl Cluster {
                 Ok(rc_cluster)
             })
             .and_then(move |cluster| {
+                let worker = cluster.worker.clone();
+                // stop accepting once closing or the clients are draining
+                let closed = worker
+                    .closed()
+                    .select(cluster.clients.draining())
+                    .map(|_| ())
+                    .map_err(|_| ());
                 let listen = create_reuse_port_listener(&addr).expect("bind never fail");
-                let service = listen
-                    .incoming()
+                let accept = Accept::new(&cluster.cc.borrow(), listen);
+                let service = accept
                     .for_each(move |sock| {
                         let cluster = cluster.clone();
                         if let Err(err) = sock.set_nodelay(true) {
@@ -212,7 +297,12 @@ impl Cluster {
                         };
 
                         front_conn_incr(&cluster.cc.borrow().name);
-                        let codec = RedisHandleCodec {};
+                        let limit = ArgsLimit::from_config(&cluster.cc.borrow(), &client_str);
+                        let codec = RedisHandleCodec::default()
+                            .args_limit(limit)
+                            .value_limit(ValueLimit::from_config(&cluster.cc.borrow()))
+                            .subs_limit(SubsLimit::from_config(&cluster.cc.borrow()))
+                            .shims(Shims::from_config(&cluster.cc.borrow()));
                         let (output, input) = codec.framed(sock).split();
                         let fut = front::Front::new(client_str, cluster, input, output);
                         current_thread::spawn(fut);
@@ -221,7 +311,8 @@ impl Cluster {
                     .map_err(|err| {
                         error!("fail to accept incomming sock due {}", err);
                     });
-                current_thread::spawn(service);
+                current_thread::spawn(service.select(closed).map(|_| ()).map_err(|_| ()));
+                worker.listening();
                 Ok(())
             });
         current_thread::spawn(
@@ -232,10 +323,75 @@ impl Cluster {
 }
 
 impl Cluster {
+    pub(crate) fn is_read_only(&self) -> bool {
+        readonly::is_read_only(&self.read_only)
+    }
+
+    pub(crate) fn is_maintenance(&self) -> bool {
+        maintenance::is_maintenance(&self.maintenance)
+    }
+
+    pub(crate) fn check_blocking(&self, cmd: &Cmd) -> Result<(), AsError> {
+        if !cmd.borrow().is_blocking() {
+            return Ok(());
+        }
+        let cc = self.cc.borrow();
+        let policy = cc.blocking_commands.unwrap_or_default();
+        policy.check(&cc.name, &cmd.cmd_name())?;
+        if policy == BlockingCommands::Bounded {
+            let max = cc.blocking_timeout_max();
+            let slot = |x: &[u8]| crc16(x) % SLOTS_COUNT as u64;
+            let timeout = cmd
+                .borrow_mut()
+                .bound_timeout(self.hash_tag.as_ref(), slot, max)?;
+            if let Some(timeout) = timeout {
+                info!(
+                    "timeout {} of {} in cluster {} is capped to {} seconds",
+                    String::from_utf8_lossy(&timeout),
+                    cmd.cmd_name(),
+                    cc.name,
+                    max
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_dangerous(&self, cmd: &Cmd) -> Result<(), AsError> {
+        let name = match cmd.borrow().dangerous_name() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let cc = self.cc.borrow();
+        if cc.allows_dangerous(&name) {
+            return Ok(());
+        }
+        warn!("deny dangerous command {} in cluster {}", name, cc.name);
+        Err(AsError::Dangerous(name))
+    }
+
+    pub(crate) fn check_two_keys(&self, cmd: &Cmd) -> Result<(), AsError> {
+        let slot = |x: &[u8]| crc16(x) % SLOTS_COUNT as u64;
+        cmd.borrow().check_two_keys(self.hash_tag.as_ref(), slot)
+    }
+
+    pub(crate) fn check_sort(&self, cmd: &Cmd) -> Result<(), AsError> {
+        match cmd.borrow().sort_pattern(&self.hash_tag) {
+            Some(pattern) => {
+                let cc = self.cc.borrow();
+                cc.sort_patterns
+                    .unwrap_or_default()
+                    .check(&cc.name, pattern)
+            }
+            None => Ok(()),
+        }
+    }
+
     fn get_addr(&self, slot: usize, is_read: bool) -> String {
         // trace!("get slot={} and is_read={}", slot, is_read);
         if self.read_from_slave && is_read {
-            if let Some(replica) = self.slots.borrow().get_replica(slot) {
+            let strategy = &*self.replica_strategy;
+            if let Some(replica) = self.slots.borrow().get_replica(slot, strategy) {
                 if replica != "" {
                     return replica.to_string();
                 }
@@ -248,10 +404,49 @@ impl Cluster {
             .expect("master addr never be empty")
     }
 
+    /// the slot of key by the hash tag of routing, the reply of CLUSTER KEYSLOT.
+    pub(crate) fn key_slot(&self, key: &[u8]) -> usize {
+        key_slot(&self.hash_tag, key)
+    }
+
+    /// the reply of INFO, whose backends are the masters of the slots fetched (or the seeds
+    /// before fetched).
+    pub(crate) fn info(&self, args: &[String]) -> String {
+        let cc = self.cc.borrow();
+        let backends: Vec<_> = cc.servers.iter().map(|x| format!("addr={}", x)).collect();
+        info::reply(&cc, args, &backends)
+    }
+
+    /// the routing details of key, the node is the one which reads go to, the same as get_addr
+    /// but the turn of replicas is never taken.
+    pub(crate) fn shard(&self, key: &[u8]) -> Shard {
+        let hash_tag = trim_hash_tag(key, &self.hash_tag);
+        let hash = crc16(hash_tag);
+        let slot = key_slot(&self.hash_tag, key);
+        let strategy = if self.read_from_slave {
+            Some(&*self.replica_strategy)
+        } else {
+            None
+        };
+        let slots = self.slots.borrow();
+        let master = slots.get_master(slot).unwrap_or_default().to_string();
+        let (addr, role) = slots.peek_read(slot, strategy);
+        // the slots are unknown until fetched
+        let addr = Some(addr.to_string()).filter(|x| !x.is_empty());
+        Shard {
+            hash,
+            hash_tag: hash_tag.to_vec(),
+            position: Position::Slot { slot, master },
+            node: addr.clone(),
+            addr,
+            role,
+        }
+    }
+
     pub fn trigger_fetch(&self, trigger_by: fetcher::TriggerBy) {
         if let Some(trigger) = self.fetch.borrow().clone() {
             let if_triggered = match trigger_by {
-                fetcher::TriggerBy::Moved => {
+                fetcher::TriggerBy::Moved | fetcher::TriggerBy::Readonly => {
                     trigger.try_trigger();
                     true
                 }
@@ -269,7 +464,7 @@ impl Cluster {
 
     pub fn dispatch_to(&self, addr: &str, cmd: Cmd) -> Result<AsyncSink<Cmd>, AsError> {
         if !cmd.borrow().can_cycle() {
-            cmd.set_error(AsError::ClusterFailDispatch);
+            cmd.set_error(&AsError::ClusterFailDispatch);
             return Ok(AsyncSink::Ready);
         }
         let mut conns = self.conns.borrow_mut();
@@ -291,7 +486,13 @@ impl Cluster {
         }
     }
 
-    fn inner_dispatch_all(&self, cmds: &mut VecDeque<Cmd>) -> Result<usize, AsError> {
+    fn inner_dispatch_all(
+        &self,
+        cmds: &mut VecDeque<Cmd>,
+        held: &mut VecDeque<Cmd>,
+    ) -> Result<usize, AsError> {
+        let overload = self.cc.borrow().backend_overload.unwrap_or_default();
+        let mut blocked = HashSet::new();
         let mut count = 0usize;
         loop {
             if cmds.is_empty() {
@@ -299,29 +500,50 @@ impl Cluster {
             }
             let cmd = cmds.pop_front().expect("cmds pop front never be empty");
             if !cmd.borrow().can_cycle() {
-                cmd.set_error(AsError::ProxyFail);
+                cmd.set_error(&AsError::ProxyFail);
                 continue;
             }
-            let slot = {
-                let hash_tag = self.hash_tag.as_ref();
-                let signed = cmd.borrow().key_hash(hash_tag, crc16) as usize;
-                signed % SLOTS_COUNT
+            let key_hash = cmd.borrow().key_hash(self.hash_tag.as_ref(), crc16);
+            let slot = match key_hash {
+                Some(signed) => signed as usize % SLOTS_COUNT,
+                None => {
+                    cmd.set_error(&AsError::BadReqeust);
+                    continue;
+                }
             };
 
-            let addr = self.get_addr(slot, cmd.borrow().is_read());
+            // the reads of session consistency may be forced to master
+            let is_read = cmd.is_read() && !cmd.borrow().is_read_master();
+            let addr = self.get_addr(slot, is_read);
+            if self.fault.is_down(|node| node == addr) {
+                cmd.set_error(&AsError::InjectedDown(addr));
+                continue;
+            }
+            if blocked.contains(&addr) {
+                held.push_back(cmd);
+                continue;
+            }
             let mut conns = self.conns.borrow_mut();
 
+            cmd.set_slot(slot);
             if let Some(sender) = conns.get_mut(&addr).map(|x| x.sender()) {
                 match sender.start_send(cmd) {
                     Ok(AsyncSink::Ready) => {
                         // trace!("success start command into backend");
+                        self.slot_stats.incr_request(slot);
                         count += 1;
                     }
-                    Ok(AsyncSink::NotReady(cmd)) => {
-                        cmd.borrow_mut().add_cycle();
-                        cmds.push_front(cmd);
-                        return Ok(count);
-                    }
+                    Ok(AsyncSink::NotReady(cmd)) => match overload {
+                        BackendOverload::Queue => {
+                            // woken up by the channel once the backend catches up
+                            cmd.borrow_mut().add_cycle();
+                            held.push_back(cmd);
+                            blocked.insert(addr);
+                        }
+                        BackendOverload::Fail => {
+                            cmd.set_error(&AsError::BackendOverloaded(addr));
+                        }
+                    },
                     Err(se) => {
                         let cmd = se.into_inner();
                         cmd.borrow_mut().add_cycle();
@@ -338,8 +560,15 @@ impl Cluster {
         }
     }
 
+    /// dispatch commands in order, the ones to the backends with queue full are kept in order
+    /// and put back without blocking the commands to other backends.
     pub fn dispatch_all(&self, cmds: &mut VecDeque<Cmd>) -> Result<usize, AsError> {
-        let count = self.inner_dispatch_all(cmds)?;
+        let mut held = VecDeque::new();
+        let rslt = self.inner_dispatch_all(cmds, &mut held);
+        while let Some(cmd) = held.pop_back() {
+            cmds.push_front(cmd);
+        }
+        let count = rslt?;
         if count != 0 {
             self.latest.replace(Instant::now());
         }
@@ -370,6 +599,41 @@ impl Cluster {
         self.slots.borrow_mut().update_slot(slot, addr)
     }
 
+    /// the command of slot is refused by READONLY of the node, which is taken as the wrong role
+    /// during failover: the demoted master is swapped with its replica and the connections of the
+    /// nodes flipped are set up again by their new roles, then the slots are fetched. The rest
+    /// refused by the same node find it a replica already, which are only routed again. Return
+    /// the master which the command is routed to once more.
+    pub(crate) fn on_readonly(&self, slot: usize, from: &str, cmd: &Cmd) -> String {
+        let is_master = self.slots.borrow().get_master(slot) == Some(from);
+        let role = if is_master {
+            Role::Master
+        } else {
+            Role::Replica
+        };
+        warn!(
+            "cluster {} node {} refused {} of slot {} by READONLY as {}",
+            self.cc.borrow().name,
+            from,
+            cmd.cmd_name(),
+            slot,
+            role.as_str()
+        );
+        role_confusion_incr(&self.cc.borrow().name, from, role.as_str());
+
+        if is_master {
+            let flipped = self.slots.borrow_mut().demote(from);
+            let mut conns = self.conns.borrow_mut();
+            for node in &flipped {
+                conns.remove(node);
+            }
+        }
+        self.trigger_fetch(fetcher::TriggerBy::Readonly);
+        // never refused by READONLY again if it's read from master
+        cmd.borrow_mut().set_read_master();
+        self.get_addr(slot, false)
+    }
+
     pub(crate) fn connect(&self, addr: &str, conns: &mut Conns) -> Result<(), AsError> {
         let is_replica = !self.slots.borrow().is_master(addr);
 
@@ -379,6 +643,7 @@ impl Cluster {
             .node(addr.to_string())
             .read_timeout(self.cc.borrow().read_timeout.clone())
             .write_timeout(self.cc.borrow().write_timeout.clone())
+            .keepalive(self.cc.borrow().tcp_keepalive())
             .fetch(
                 self.fetch
                     .borrow()
@@ -386,6 +651,10 @@ impl Cluster {
                     .map(|x| Rc::downgrade(x))
                     .unwrap_or_default(),
             )
+            .inflight(self.outstanding.track(addr))
+            .latency(self.latencies.track(addr))
+            .queue_limit(self.cc.borrow().backend_queue_limit())
+            .budget(self.budget.clone())
             .replica(is_replica)
             .connect()?;
         conns.insert(&addr, sender);
@@ -393,6 +662,10 @@ impl Cluster {
     }
 }
 
+fn key_slot(hash_tag: &[u8], key: &[u8]) -> usize {
+    (crc16(trim_hash_tag(key, hash_tag)) % SLOTS_COUNT as u64) as usize
+}
+
 pub(crate) struct Conns {
     inner: HashMap<String, Conn<Sender<Cmd>>>,
 }
@@ -409,6 +682,11 @@ impl Conns {
         };
         self.inner.insert(s.to_string(), conn);
     }
+
+    // the backend drains the commands in flight and exits once its sender is dropped.
+    fn remove(&mut self, s: &str) {
+        self.inner.remove(s);
+    }
 }
 
 impl Default for Conns {
@@ -472,12 +750,57 @@ impl Slots {
         old != addr
     }
 
+    // the master refused writes by READONLY, which is guessed to be demoted and replaced by the
+    // first replica of its slots until fetched again, return the nodes whose role is flipped.
+    fn demote(&mut self, addr: &str) -> Vec<String> {
+        let mut promoted = HashSet::new();
+        for (master, replica) in self.masters.iter_mut().zip(self.replicas.iter_mut()) {
+            if master != addr || replica.addrs.is_empty() {
+                continue;
+            }
+            let next = std::mem::replace(&mut replica.addrs[0], addr.to_string());
+            promoted.insert(next.clone());
+            *master = next;
+            replica.current.set(0);
+        }
+        if promoted.is_empty() {
+            return vec![];
+        }
+
+        if !self.masters.iter().any(|x| x == addr) {
+            self.all_masters.remove(addr);
+        }
+        self.all_replicas.insert(addr.to_string());
+        for node in &promoted {
+            self.all_masters.insert(node.clone());
+            if !self.replicas.iter().any(|x| x.addrs.contains(node)) {
+                self.all_replicas.remove(node);
+            }
+        }
+        let mut flipped: Vec<_> = promoted.into_iter().collect();
+        flipped.sort();
+        flipped.insert(0, addr.to_string());
+        flipped
+    }
+
     fn get_master(&self, slot: usize) -> Option<&str> {
         self.masters.get(slot).map(|x| x.as_str())
     }
 
-    fn get_replica(&self, slot: usize) -> Option<&str> {
-        self.replicas.get(slot).map(|x| x.get_replica())
+    fn get_replica(&self, slot: usize, strategy: &dyn Strategy) -> Option<&str> {
+        self.replicas.get(slot).map(|x| x.get_replica(strategy))
+    }
+
+    // the node which the next read of slot goes to and its role, replicas are read only if
+    // strategy is given.
+    fn peek_read(&self, slot: usize, strategy: Option<&dyn Strategy>) -> (&str, Role) {
+        if let Some(strategy) = strategy {
+            let replica = self.replicas[slot].peek_replica(strategy);
+            if replica != "" {
+                return (replica, Role::Replica);
+            }
+        }
+        (self.masters[slot].as_str(), Role::Master)
     }
 
     fn get_all_masters(&self) -> HashSet<String> {
@@ -515,16 +838,22 @@ struct Replica {
 }
 
 impl Replica {
-    fn get_replica(&self) -> &str {
+    fn get_replica(&self, strategy: &dyn Strategy) -> &str {
         if self.addrs.is_empty() {
             return "";
         }
 
-        let current = self.current.get();
-        let len = self.addrs.len();
-        let now = self.current.get();
-        self.current.set((now + 1) % len);
-        &self.addrs[current]
+        let round = self.current.get();
+        self.current.set(round.wrapping_add(1));
+        &self.addrs[strategy.select(&self.addrs, round)]
+    }
+
+    // the replica selected by the next get_replica.
+    fn peek_replica(&self, strategy: &dyn Strategy) -> &str {
+        if self.addrs.is_empty() {
+            return "";
+        }
+        &self.addrs[strategy.select(&self.addrs, self.current.get())]
     }
 }
 
@@ -543,8 +872,13 @@ pub(crate) struct ConnBuilder {
     moved: Option<Sender<Redirection>>,
     rt: Option<u64>,
     wt: Option<u64>,
+    keepalive: Option<Keepalive>,
     replica: bool,
     fetch: Weak<SingleFlightTrigger>,
+    inflight: Rc<Cell<usize>>,
+    latency: Rc<Cell<u64>>,
+    queue_limit: usize,
+    budget: Arc<Budget>,
 }
 
 impl ConnBuilder {
@@ -555,8 +889,13 @@ impl ConnBuilder {
             moved: None,
             rt: Some(1000),
             wt: Some(1000),
+            keepalive: None,
             replica: false,
             fetch: Weak::new(),
+            inflight: Rc::default(),
+            latency: Rc::default(),
+            queue_limit: DEFAULT_BACKEND_QUEUE_LIMIT,
+            budget: Arc::default(),
         }
     }
 
@@ -590,6 +929,12 @@ impl ConnBuilder {
         cb
     }
 
+    pub(crate) fn keepalive(self, keepalive: Option<Keepalive>) -> Self {
+        let mut cb = self;
+        cb.keepalive = keepalive;
+        cb
+    }
+
     pub(crate) fn node(self, node: String) -> Self {
         let mut cb = self;
         cb.node = Some(node);
@@ -602,6 +947,30 @@ impl ConnBuilder {
         cb
     }
 
+    pub(crate) fn inflight(self, inflight: Rc<Cell<usize>>) -> Self {
+        let mut cb = self;
+        cb.inflight = inflight;
+        cb
+    }
+
+    pub(crate) fn latency(self, latency: Rc<Cell<u64>>) -> Self {
+        let mut cb = self;
+        cb.latency = latency;
+        cb
+    }
+
+    pub(crate) fn queue_limit(self, queue_limit: usize) -> Self {
+        let mut cb = self;
+        cb.queue_limit = queue_limit;
+        cb
+    }
+
+    pub(crate) fn budget(self, budget: Arc<Budget>) -> Self {
+        let mut cb = self;
+        cb.budget = budget;
+        cb
+    }
+
     pub(crate) fn check_valid(&self) -> bool {
         self.node.is_some() && self.cluster.is_some() && self.moved.is_some()
     }
@@ -617,15 +986,19 @@ impl ConnBuilder {
 
         let node_addr = self.node.expect("addr must be checked first");
         let node_addr_clone = node_addr.clone();
-        let cluster = self
-            .cluster
-            .expect("cluster name must be checked first");
+        let cluster = self.cluster.expect("cluster name must be checked first");
+        let cluster_conn = cluster.clone();
+        let node_conn = node_addr.clone();
         let rt = self.rt;
         let wt = self.wt;
+        let keepalive = self.keepalive;
         let moved = self.moved.expect("must be checked first");
         let fetch = self.fetch.clone();
+        let inflight = self.inflight.clone();
+        let latency = self.latency.clone();
+        let budget = self.budget.clone();
 
-        let (mut tx, rx) = channel(1024 * 8);
+        let (mut tx, rx) = channel(self.queue_limit);
         let amt = lazy(|| -> Result<(), ()> { Ok(()) })
             .and_then(move |_| {
                 let node_clone = node_addr.clone();
@@ -634,10 +1007,9 @@ impl ConnBuilder {
                     .parse()
                     .map_err(|err| error!("fail to parse addr {} due to {:?}", node_clone, err))
             })
-            .and_then(|addr| {
+            .and_then(move |addr| {
                 let report_addr = format!("{:?}", &addr);
-                TcpStream::connect(&addr)
-                    .timeout(Duration::from_millis(100))
+                connect_backend(&cluster_conn, &node_conn, &addr, Duration::from_millis(100))
                     .map_err(move |err| error!("fail to connect to {} {:?}", &report_addr, err))
             })
             .then(move |sock| {
@@ -647,11 +1019,23 @@ impl ConnBuilder {
                     if sock.set_nodelay(true).is_err() {
                         warn!("fail to set set nodelay when connect to backend but ignore");
                     }
+                    if let Err(err) = set_keepalive(&sock, keepalive) {
+                        warn!("fail to set keepalive of backend but ignore due to {}", err);
+                    }
 
-                    let codec = RedisNodeCodec {};
+                    let codec = RedisNodeCodec::default();
                     let (sink, stream) = codec.framed(sock).split();
-                    let backend =
-                        back::Back::new(cluster, node_addr_clone, rx, sink, stream, moved);
+                    let backend = back::Back::new(
+                        cluster,
+                        node_addr_clone,
+                        rx,
+                        sink,
+                        stream,
+                        moved,
+                        inflight,
+                        latency,
+                    )
+                    .budget(budget);
                     current_thread::spawn(backend);
                 } else {
                     error!("fail to conenct to backend {}", node_addr_clone);
@@ -690,13 +1074,14 @@ impl ConnBuilder {
     }
 }
 
-pub fn run(cc: ClusterConfig, ip: Option<String>) -> Vec<JoinHandle<()>> {
+pub fn run(cc: ClusterConfig, ip: Option<String>, control: Arc<Control>) -> Vec<JoinHandle<()>> {
     let worker = cc.thread.unwrap_or(4);
     (0..worker)
         .map(|_index| {
             let builder = thread::Builder::new();
             let cc = cc.clone();
             let ip = ip.clone();
+            let control = control.clone();
             builder
                 .name(cc.name.clone())
                 .spawn(move || {
@@ -704,13 +1089,124 @@ pub fn run(cc: ClusterConfig, ip: Option<String>) -> Vec<JoinHandle<()>> {
 
                     thread_incr();
 
-                    current_thread::block_on_all(
-                        init::Initializer::new(cc)
-                            .map_err(|err| error!("fail to init cluster due to {}", err)),
-                    )
-                    .unwrap();
+                    let name = cc.name.clone();
+                    let worker = Rc::new(Worker::new(control));
+                    let mut rt = current_thread::Runtime::new().expect("fail to create runtime");
+                    let drained = rt.block_on(
+                        init::Initializer::new(cc, worker.clone())
+                            .map_err(|err| error!("fail to init cluster due to {}", err))
+                            .and_then(move |_| worker.drain()),
+                    );
+                    // the slot stats are saved once the fronts are drained
+                    if let Some(stats) = slotstat::get(&name) {
+                        stats.save_or_warn();
+                    }
+                    drained.unwrap();
                 })
                 .expect("fail to spawn worker thread")
         })
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::redis::{Command, Message};
+    use crate::proxy::cluster::replica::RoundRobin;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_slots_peek_read() {
+        let mut slots = Slots::default();
+        let masters = vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7002".to_string()];
+        let replicas = vec![
+            vec!["127.0.0.1:7003".to_string(), "127.0.0.1:7004".to_string()],
+            vec![],
+        ];
+        assert!(slots.try_update_all(masters, replicas));
+
+        // reads go to master unless read from slave
+        assert_eq!(slots.peek_read(0, None), ("127.0.0.1:7001", Role::Master));
+        let strategy = RoundRobin;
+        assert_eq!(
+            slots.peek_read(0, Some(&strategy)),
+            ("127.0.0.1:7003", Role::Replica)
+        );
+        // peeked without taking the turn of replicas
+        assert_eq!(slots.get_replica(0, &strategy), Some("127.0.0.1:7003"));
+        assert_eq!(
+            slots.peek_read(0, Some(&strategy)),
+            ("127.0.0.1:7004", Role::Replica)
+        );
+        // the slot without replicas is read from master
+        assert_eq!(
+            slots.peek_read(1, Some(&strategy)),
+            ("127.0.0.1:7002", Role::Master)
+        );
+    }
+
+    #[test]
+    fn test_slots_demote() {
+        let mut slots = Slots::default();
+        let masters = vec![
+            "127.0.0.1:7001".to_string(),
+            "127.0.0.1:7001".to_string(),
+            "127.0.0.1:7002".to_string(),
+        ];
+        let replicas = vec![
+            vec!["127.0.0.1:7003".to_string(), "127.0.0.1:7004".to_string()],
+            vec!["127.0.0.1:7003".to_string()],
+            vec![],
+        ];
+        assert!(slots.try_update_all(masters, replicas));
+
+        assert_eq!(
+            slots.demote("127.0.0.1:7001"),
+            vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7003".to_string()]
+        );
+        assert_eq!(slots.get_master(0), Some("127.0.0.1:7003"));
+        assert_eq!(slots.get_master(1), Some("127.0.0.1:7003"));
+        assert_eq!(
+            slots.replicas[0].addrs,
+            vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7004".to_string()]
+        );
+        // connected by the roles flipped
+        assert!(!slots.is_master("127.0.0.1:7001"));
+        assert!(slots.is_master("127.0.0.1:7003"));
+        assert!(slots.get_all_replicas().contains("127.0.0.1:7001"));
+        assert!(!slots.get_all_replicas().contains("127.0.0.1:7003"));
+
+        // nothing to promote without replicas
+        assert!(slots.demote("127.0.0.1:7002").is_empty());
+        assert_eq!(slots.get_master(2), Some("127.0.0.1:7002"));
+        assert!(slots.demote("127.0.0.1:7009").is_empty());
+    }
+
+    #[test]
+    fn test_key_slot_as_routing() {
+        // the examples of redis
+        assert_eq!(key_slot(b"{}", b"somekey"), 11058);
+        assert_eq!(key_slot(b"{}", b"foo{hash_tag}"), 2515);
+        assert_eq!(
+            key_slot(b"{}", b"{user1000}.following"),
+            key_slot(b"{}", b"{user1000}.followers")
+        );
+        // the same as the slot of GET routed
+        let keys: &[&[u8]] = &[
+            b"{user1000}.following",
+            b"foo{}{bar}",
+            b"foo{{bar}}zap",
+            b"{}a",
+            b"{a",
+        ];
+        for key in keys {
+            let mut req = BytesMut::new();
+            Message::from_args(vec![&b"GET"[..], *key]).save(&mut req);
+            let cmd = Command::parse_cmd(&mut req).unwrap().unwrap();
+            let routed = cmd.borrow().key_hash(b"{}", crc16).unwrap() as usize % SLOTS_COUNT;
+            assert_eq!(key_slot(b"{}", key), routed);
+        }
+        // and by the hash tag of cluster
+        assert_eq!(key_slot(b"[]", b"[a]{b}"), key_slot(b"{}", b"{a}"));
+    }
+}
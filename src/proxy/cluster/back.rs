@@ -1,10 +1,18 @@
 use crate::com::AsError;
-use crate::protocol::redis::{Cmd, Message};
-use crate::proxy::cluster::Redirection;
+use crate::metrics::{protocol_error_incr, reply_mismatch_incr};
+use crate::protocol::redis::{Cmd, Message, RespType};
+use crate::protocol::CmdType;
+use crate::proxy::budget::Budget;
+use crate::proxy::cluster::replica;
+use crate::proxy::cluster::{Redirect, Redirection};
 
 use futures::unsync::mpsc::SendError;
 use futures::{Async, AsyncSink, Future, Sink, Stream};
+use std::cell::Cell;
 use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
 
 const MAX_PIPELINE: usize = 512;
 
@@ -42,10 +50,22 @@ where
     addr: String,
     state: State,
 
-    ask_readed: bool,
+    // the reply of ASKING in front of the command in front of cmdq is read
+    asking_readed: bool,
     redirect_store: Option<Redirection>,
     store: Option<Cmd>,
     cmdq: VecDeque<Cmd>,
+    // the time each command of cmdq is sent
+    sent: VecDeque<Instant>,
+    // whether each command of cmdq is sent behind ASKING, kept by the time it's sent since the
+    // flag of the command is changed by its reply
+    askings: VecDeque<bool>,
+    // commands awaiting replies, read by the least_outstanding replica strategy
+    inflight: Rc<Cell<usize>>,
+    // smoothed reply latency, read by the latency_weighted replica strategy
+    latency: Rc<Cell<u64>>,
+    // the redirects and the refusals of READONLY are followed within it
+    budget: Arc<Budget>,
 
     inner_err: AsError,
 
@@ -69,6 +89,8 @@ where
         output: O,
         recv: R,
         moved: M,
+        inflight: Rc<Cell<usize>>,
+        latency: Rc<Cell<u64>>,
     ) -> Back<I, O, R, M> {
         let inner_err = AsError::ConnClosed(addr.clone());
         Back {
@@ -81,13 +103,28 @@ where
             inner_err,
 
             state: State::Running,
-            ask_readed: false,
+            asking_readed: false,
             redirect_store: None,
             store: None,
             cmdq: VecDeque::with_capacity(MAX_PIPELINE),
+            sent: VecDeque::with_capacity(MAX_PIPELINE),
+            askings: VecDeque::with_capacity(MAX_PIPELINE),
+            inflight,
+            latency,
+            budget: Arc::default(),
         }
     }
 
+    pub fn budget(mut self, budget: Arc<Budget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    fn update_inflight(&self) {
+        self.inflight
+            .set(self.cmdq.len() + self.store.iter().count());
+    }
+
     fn try_forward(&mut self) -> Result<Async<State>, AsError> {
         let mut count = 0;
         let mut ret_state = State::Running;
@@ -95,6 +132,7 @@ where
         for _ in 0..MAX_PIPELINE {
             if let Some(cmd) = self.store.take() {
                 let rcmd = cmd.clone();
+                let asking = rcmd.borrow().is_ask();
                 match self.output.start_send(cmd) {
                     Ok(AsyncSink::NotReady(cmd)) => {
                         self.store = Some(cmd);
@@ -105,6 +143,8 @@ where
 
                         rcmd.cluster_mark_remote(&self.cluster);
                         self.cmdq.push_back(rcmd);
+                        self.sent.push_back(Instant::now());
+                        self.askings.push_back(asking);
                     }
                     Err(err) => {
                         error!(
@@ -136,11 +176,14 @@ where
             }
         }
 
+        self.update_inflight();
+        // flushed even if nothing new is sent, since the rest of the partial write is only
+        // resumed by it once the socket is writable again
+        self.output.poll_complete()?;
         if count > 0 {
-            self.output.poll_complete()?;
             Ok(Async::Ready(ret_state))
         } else {
-            Ok(Async::NotReady)            
+            Ok(Async::NotReady)
         }
     }
 
@@ -159,22 +202,16 @@ where
                     Err(se) => {
                         let red: Redirection = se.into_inner();
                         error!("fail to redirect cmd {:?}", red.target);
-                        red.cmd.set_error(AsError::RedirectFailError);
+                        red.cmd.set_error(&AsError::RedirectFailError);
                         return Err(AsError::RedirectFailError);
                     }
                 }
             }
 
-            if self.cmdq.is_empty() {
+            // polled even if idle to find out the surplus reply, but never after closed
+            if self.state.is_closed() {
                 break;
             }
-
-            let is_ask = self
-                .cmdq
-                .front()
-                .map(|x| x.borrow().is_ask())
-                .expect("front always have cmd");
-
             let msg = match self.recv.poll() {
                 Ok(Async::Ready(Some(msg))) => {
                     count += 1;
@@ -188,19 +225,43 @@ where
                 }
                 Err(err) => {
                     error!("fail to recv from back {} due {:?}", self.addr, err);
+                    if let AsError::ReplyProtocolError(reason) = &err {
+                        self.on_protocol_error(reason);
+                    }
                     return Err(err);
                 }
             };
 
-            if is_ask {
-                self.ask_readed = !self.ask_readed;
-                if self.ask_readed {
-                    continue;
+            let asking = match self.askings.front() {
+                Some(asking) => *asking,
+                None => return Err(self.on_mismatch()),
+            };
+            if asking && !self.asking_readed {
+                self.asking_readed = true;
+                if let RespType::Error(_) = msg.rtype {
+                    warn!(
+                        "backend {} of cluster {} refused ASKING due to {}",
+                        self.addr,
+                        self.cluster,
+                        String::from_utf8_lossy(msg.data().unwrap_or_default())
+                    );
                 }
+                continue;
+            }
+            match self.cmdq.front() {
+                Some(cmd) if CmdType::accept_reply(&cmd.req(), &msg) => {}
+                _ => return Err(self.on_mismatch()),
             }
 
             let cmd = self.cmdq.pop_front().expect("cmdq never be empty");
-            if let Some(redirect) = msg.check_redirect() {
+            if let Some(sent) = self.sent.pop_front() {
+                replica::observe(&self.latency, sent.elapsed());
+            }
+            self.askings.pop_front();
+            self.asking_readed = false;
+            // the redirect beyond the budget is replied as is
+            let redirect = msg.check_redirect().filter(|_| self.budget.try_retry());
+            if let Some(redirect) = redirect {
                 {
                     let mut inner_cmd = cmd.borrow_mut();
                     inner_cmd.add_cycle();
@@ -216,10 +277,26 @@ where
                     target: redirect,
                     cmd,
                 });
+            } else if let Some(slot) = readonly_retry(&cmd, &msg, &self.budget) {
+                {
+                    let mut inner_cmd = cmd.borrow_mut();
+                    inner_cmd.add_cycle();
+                    inner_cmd.unset_ask();
+                }
+                self.redirect_store = Some(Redirection {
+                    target: Redirect::Readonly {
+                        slot,
+                        from: self.addr.clone(),
+                    },
+                    cmd,
+                });
             } else {
+                // ASKING is sent once for each ASK redirection
+                cmd.borrow_mut().unset_ask();
                 cmd.set_reply(msg);
             }
         }
+        self.update_inflight();
         if count > 0 {
             Ok(Async::Ready(()))
         } else {
@@ -227,6 +304,31 @@ where
         }
     }
 
+    // the reply doesn't match the command in front, or there's no command waiting for it.
+    fn on_mismatch(&mut self) -> AsError {
+        error!(
+            "backend {} of cluster {} replied out of order with {} commands in flight, close it",
+            self.addr,
+            self.cluster,
+            self.cmdq.len()
+        );
+        reply_mismatch_incr(&self.cluster, &self.addr);
+        self.inner_err = AsError::ReplyMismatch(self.addr.clone());
+        AsError::ReplyMismatch(self.addr.clone())
+    }
+
+    // the reply framing is violated, none of the pending commands can trust its reply.
+    fn on_protocol_error(&mut self, reason: &str) {
+        error!(
+            "backend {} of cluster {} violated reply framing with {} commands in flight, close it",
+            self.addr,
+            self.cluster,
+            self.cmdq.len()
+        );
+        protocol_error_incr(&self.cluster, &self.addr);
+        self.inner_err = AsError::ReplyProtocolError(reason.to_string());
+    }
+
     fn on_closed(&mut self) {
         if let Some(cmd) = self.store.take() {
             cmd.set_error(&self.inner_err);
@@ -234,6 +336,10 @@ where
         for cmd in self.cmdq.drain(0..) {
             cmd.set_error(&self.inner_err);
         }
+        self.sent.clear();
+        self.askings.clear();
+        self.asking_readed = false;
+        self.inflight.set(0);
     }
 
     fn has_cmd(&mut self) -> bool {
@@ -318,6 +424,20 @@ where
     }
 }
 
+// the slot of the command refused by READONLY, which is routed once more since it's never
+// executed, even if it's a write. The refused again or beyond the budget is replied as is.
+fn readonly_retry(cmd: &Cmd, msg: &Message, budget: &Budget) -> Option<usize> {
+    if !msg.is_readonly_error() {
+        return None;
+    }
+    let slot = cmd.borrow().slot()?;
+    if cmd.borrow_mut().mark_retry() && budget.try_retry() {
+        Some(slot)
+    } else {
+        None
+    }
+}
+
 pub struct Blackhole<S>
 where
     S: Stream<Item = Cmd>,
@@ -366,3 +486,102 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::redis::{Command, RedisNodeCodec};
+    use bytes::BytesMut;
+    use futures::future;
+    use futures::unsync::mpsc::channel;
+    use std::cell::RefCell;
+    use tokio::codec::Encoder;
+
+    // the connection to backend, which keeps the bytes sent to it
+    struct Wire {
+        codec: RedisNodeCodec,
+        sent: Rc<RefCell<BytesMut>>,
+    }
+
+    impl Sink for Wire {
+        type SinkItem = Cmd;
+        type SinkError = AsError;
+
+        fn start_send(&mut self, item: Cmd) -> Result<AsyncSink<Cmd>, AsError> {
+            self.codec.encode(item, &mut self.sent.borrow_mut())?;
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Result<Async<()>, AsError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn new_cmd(req: &[u8]) -> Cmd {
+        Command::parse_cmd(&mut BytesMut::from(req))
+            .unwrap()
+            .unwrap()
+    }
+
+    fn reply(cmd: &Cmd) -> BytesMut {
+        let mut buf = BytesMut::new();
+        cmd.borrow().reply_cmd(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_asking_before_asked_cmd() {
+        let sent = Rc::new(RefCell::new(BytesMut::new()));
+        let wire = Wire {
+            codec: RedisNodeCodec::default(),
+            sent: sent.clone(),
+        };
+        let (mut input_tx, input_rx) = channel(16);
+        let (mut reply_tx, reply_rx) = channel::<Message>(16);
+        let (moved_tx, _moved_rx) = channel(16);
+        let recv = reply_rx.map_err(|_| AsError::ConnClosed("127.0.0.1:7001".to_string()));
+        let mut back = Back::new(
+            "test-asking".to_string(),
+            "127.0.0.1:7001".to_string(),
+            input_rx,
+            wire,
+            recv,
+            moved_tx,
+            Rc::default(),
+            Rc::default(),
+        );
+
+        // redirected to the importing node by ASK, pipelined with the command behind it
+        let asked = new_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+        asked.borrow_mut().set_ask();
+        let behind = new_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n");
+        future::lazy(|| {
+            input_tx.start_send(asked.clone()).unwrap();
+            input_tx.start_send(behind.clone()).unwrap();
+            back.poll()
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(
+            &sent.borrow()[..],
+            &b"*1\r\n$6\r\nASKING\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\n*2\r\n$3\r\nGET\r\n$1\r\nb\r\n"
+                [..]
+        );
+
+        // the reply of ASKING is never taken as the reply of any command
+        future::lazy(|| {
+            for msg in &[&b"+OK\r\n"[..], &b"$1\r\n1\r\n"[..], &b"$1\r\n2\r\n"[..]] {
+                let msg = Message::parse(&mut BytesMut::from(*msg)).unwrap().unwrap();
+                reply_tx.start_send(msg).unwrap();
+            }
+            back.poll()
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(&reply(&asked)[..], &b"$1\r\n1\r\n"[..]);
+        assert_eq!(&reply(&behind)[..], &b"$1\r\n2\r\n"[..]);
+
+        // and ASKING is sent once for each ASK
+        assert!(!asked.borrow().is_ask());
+    }
+}
@@ -10,7 +10,7 @@ use std::rc::Rc;
 use std::time::{Duration, Instant};
 
 use crate::com::AsError;
-use crate::protocol::redis::{new_cluster_slots_cmd, slots_reply_to_replicas, Cmd};
+use crate::protocol::redis::Cmd;
 use crate::proxy::cluster::Cluster;
 
 #[derive(Debug, Clone)]
@@ -18,6 +18,7 @@ pub enum TriggerBy {
     Interval,
     Moved,
     Error,
+    Readonly,
 }
 
 pub(crate) type TriggerSender = Sender<TriggerBy>;
@@ -150,6 +151,9 @@ where
                             TriggerBy::Moved => {
                                 debug!("fetcher success trigger by moved");
                             }
+                            TriggerBy::Readonly => {
+                                debug!("fetcher success trigger by readonly");
+                            }
                         }
                         self.state = State::Random;
                     }
@@ -174,7 +178,8 @@ where
                         .cloned()
                         .unwrap();
                     info!("start fetch from remote address {}", addr);
-                    let mut cmd = new_cluster_slots_cmd();
+                    let flavor = self.cluster.cc.borrow().backend_flavor.unwrap_or_default();
+                    let mut cmd = flavor.topology_cmd();
                     cmd.reregister(task::current());
                     self.state = State::Sending(addr, cmd);
                 }
@@ -201,7 +206,8 @@ where
                     self.state = State::Done(addr.clone(), cmd.clone());
                 }
                 State::Done(addr, cmd) => {
-                    let layout = match slots_reply_to_replicas(cmd.clone()) {
+                    let flavor = self.cluster.cc.borrow().backend_flavor.unwrap_or_default();
+                    let layout = match flavor.topology_layout(cmd.clone()) {
                         Ok(Some(layout)) => layout,
                         Ok(None) => {
                             warn!("slots not full covered, this may be not allow in aster");
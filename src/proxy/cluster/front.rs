@@ -1,18 +1,32 @@
 use crate::com::AsError;
-use crate::protocol::redis::Cmd;
+use crate::protocol::redis::{Cmd, Message};
+use crate::proxy::capture;
+use crate::proxy::clients::ClientFlags;
 use crate::proxy::cluster::fetcher::TriggerBy;
+use crate::proxy::cluster::session::Session;
 use crate::proxy::cluster::Cluster;
+use crate::proxy::fault::Fault;
+use crate::proxy::outbuf::{OutputLimit, ReplyPacing};
+use crate::proxy::shard;
+use crate::proxy::standalone::Request;
 
+use bytes::{Bytes, BytesMut};
+use futures::future::poll_fn;
 use futures::task;
 use futures::{Async, AsyncSink, Future, Sink, Stream};
 use std::collections::VecDeque;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::runtime::current_thread;
+use tokio::timer::Delay;
 
 const MAX_BATCH_SIZE: usize = 2048;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum State {
     Running,
+    Closing,
     Closed,
 }
 
@@ -24,12 +38,33 @@ where
     cluster: Rc<Cluster>,
 
     client: String,
+    // id and sequence of requests and replies in traffic capture
+    client_id: u64,
+    recv_seq: u64,
+    reply_seq: u64,
+    // replies before the sequence are passed to hooks
+    hooked_seq: u64,
 
     input: I,
     output: O,
 
     sendq: VecDeque<Cmd>,
     waitq: VecDeque<Cmd>,
+    // some commands in waitq have subs waiting for the next wave
+    waving: bool,
+    // recv sequence and command held until the waves ahead are released, or it may overtake
+    // the subs of the same key
+    held: VecDeque<(u64, Cmd)>,
+    // task is registered to be woken up when worker is closing
+    registered: bool,
+    // set by CLIENT KILL or the draining of clients from any worker thread
+    flags: Arc<ClientFlags>,
+    output_limit: OutputLimit,
+    pacing: ReplyPacing,
+    // the recent writes of replica_read_consistency session
+    session: Option<Session>,
+    // authenticated by AUTH if the cluster has users
+    user: Option<String>,
 
     state: State,
 }
@@ -40,19 +75,36 @@ where
     O: Sink<SinkItem = Cmd, SinkError = AsError>,
 {
     pub fn new(client: String, cluster: Rc<Cluster>, input: I, output: O) -> Front<I, O> {
+        let output_limit = OutputLimit::new(&cluster.cc.borrow());
+        let pacing = ReplyPacing::new(&cluster.cc.borrow());
+        let session = Session::new(&cluster.cc.borrow());
         Front {
             cluster,
             client,
+            client_id: capture::next_client_id(),
+            recv_seq: 0,
+            reply_seq: 0,
+            hooked_seq: 0,
             input,
             output,
             sendq: VecDeque::with_capacity(MAX_BATCH_SIZE),
             waitq: VecDeque::with_capacity(MAX_BATCH_SIZE),
+            waving: false,
+            held: VecDeque::new(),
+            registered: false,
+            flags: Arc::default(),
+            output_limit,
+            pacing,
+            session,
+            user: None,
             state: State::Running,
         }
     }
 
     fn try_reply(&mut self) -> Result<Async<usize>, AsError> {
         let mut count = 0usize;
+        // the client is not writable
+        let mut blocked = false;
         loop {
             if self.waitq.is_empty() {
                 break;
@@ -67,14 +119,30 @@ where
 
             if cmd.borrow().is_error() {
                 self.cluster.trigger_fetch(TriggerBy::Error);
+                self.cluster.slot_stats.record_reply(&cmd);
+            }
+            if self.hooked_seq == self.reply_seq {
+                self.cluster.slo.record(&cmd);
+                self.cluster.budget.record(&cmd);
+                self.cluster.hooks.on_response(&cmd);
+                self.hooked_seq += 1;
             }
 
+            let reply = self.capture_reply(&cmd);
             match self.output.start_send(cmd) {
                 Ok(AsyncSink::Ready) => {
+                    if let Some(reply) = reply {
+                        self.cluster
+                            .capture
+                            .reply(self.client_id, self.reply_seq, reply);
+                    }
+                    self.reply_seq += 1;
                     count += 1;
                 }
                 Ok(AsyncSink::NotReady(cmd)) => {
                     self.waitq.push_front(cmd);
+                    blocked = true;
+                    self.check_output_limit()?;
                     break;
                 }
                 Err(err) => {
@@ -88,17 +156,146 @@ where
         if count > 0 {
             self.output.poll_complete()?;
         }
+        if self.waitq.is_empty() {
+            self.output_limit.reset();
+        }
+        if !blocked {
+            self.pacing.reset();
+        }
         Ok(Async::Ready(count))
     }
 
+    fn check_output_limit(&mut self) -> Result<(), AsError> {
+        if !self.output_limit.is_enabled() && !self.pacing.is_enabled() {
+            return Ok(());
+        }
+        let pending = self
+            .waitq
+            .iter()
+            .take_while(|x| x.borrow().is_done())
+            .map(|x| x.borrow().reply_size())
+            .sum();
+        self.pacing.check(pending);
+        self.output_limit.check(pending)
+    }
+
+    fn capture_reply(&self, cmd: &Cmd) -> Option<Bytes> {
+        if !self.cluster.capture.is_replying() {
+            return None;
+        }
+        let mut buf = BytesMut::new();
+        let _ = cmd.borrow().reply_cmd(&mut buf);
+        Some(buf.freeze())
+    }
+
     fn try_send(&mut self) -> Result<usize, AsError> {
+        if self.waving {
+            self.release_waves();
+        }
         Ok(self.cluster.dispatch_all(&mut self.sendq)?)
     }
 
+    fn release_waves(&mut self) {
+        let batch = self.cluster.cc.borrow().multi_key_batch();
+        let mut waving = false;
+        // the held commands are never released here
+        let end = match self.held.front() {
+            Some((seq, _)) => (seq - self.reply_seq) as usize,
+            None => self.waitq.len(),
+        };
+        for cmd in self.waitq.iter().take(end) {
+            let wave = cmd.borrow_mut().next_wave(batch);
+            if let Some(wave) = wave {
+                self.sendq.extend(wave.into_iter());
+            }
+            waving = waving || cmd.borrow().has_wave();
+        }
+        self.waving = waving;
+        while !self.waving {
+            match self.held.pop_front() {
+                Some((_, cmd)) => self.dispatch(&cmd, batch),
+                None => break,
+            }
+        }
+    }
+
+    fn dispatch(&mut self, cmd: &Cmd, batch: usize) {
+        let wave = cmd.borrow_mut().next_wave(batch);
+        if let Some(wave) = wave {
+            self.sendq.extend(wave.into_iter());
+            self.waving = self.waving || cmd.borrow().has_wave();
+        } else {
+            self.sendq.push_back(cmd.clone());
+        }
+    }
+
+    fn inject(&mut self, cmd: &Cmd, fault: Fault, batch: usize) {
+        let delay = match fault {
+            Fault::Error => {
+                for sub in cmd.borrow().subs().unwrap_or_default() {
+                    sub.set_error(&AsError::Injected);
+                }
+                cmd.set_error(&AsError::Injected);
+                return;
+            }
+            Fault::Latency(delay) => delay,
+        };
+        let wave = cmd.borrow_mut().next_wave(batch);
+        let mut cmds: VecDeque<Cmd> = match wave {
+            Some(wave) => wave.into_iter().collect(),
+            None => vec![cmd.clone()].into_iter().collect(),
+        };
+        self.waving = self.waving || cmd.borrow().has_wave();
+
+        let cluster = self.cluster.clone();
+        let dispatch = Delay::new(Instant::now() + delay)
+            .map_err(|err| error!("fail to inject latency due to {:?}", err))
+            .and_then(move |_| {
+                poll_fn(move || match cluster.dispatch_all(&mut cmds) {
+                    Ok(_) if !cmds.is_empty() => Ok(Async::NotReady),
+                    Ok(_) => Ok(Async::Ready(())),
+                    Err(err) => {
+                        error!("fail to dispatch delayed commands due to {:?}", err);
+                        for cmd in cmds.drain(..) {
+                            cmd.set_error(&err);
+                        }
+                        Ok(Async::Ready(()))
+                    }
+                })
+            });
+        current_thread::spawn(dispatch);
+    }
+
+    // AUTH and ACL are answered by the users of cluster, and the others are checked against the
+    // user authenticated.
+    fn check_acl(&mut self, cmd: &Cmd) -> Result<(), AsError> {
+        let acl = &self.cluster.acl;
+        let user = &mut self.user;
+        if cmd.handle_acl(|args| acl.handle(user, args)) || !acl.is_enabled() {
+            return Ok(());
+        }
+        acl.check(
+            self.user.as_deref(),
+            cmd.acl_category(),
+            &cmd.cmd_name(),
+            &cmd.acl_keys(),
+        )
+    }
+
+    fn track_session(&mut self, cmd: &Cmd) {
+        if let Some(session) = self.session.as_mut() {
+            session.track(cmd);
+        }
+    }
+
     fn try_recv(&mut self) -> Result<usize, AsError> {
         let mut count = 0usize;
+        // mode is loaded once to keep the whole pipelined batch consistent
+        let read_only = self.cluster.is_read_only();
+        let maintenance = self.cluster.is_maintenance();
+        let batch = self.cluster.cc.borrow().multi_key_batch();
         loop {
-            if self.waitq.len() == MAX_BATCH_SIZE {
+            if self.waitq.len() == MAX_BATCH_SIZE || self.pacing.is_paced() {
                 return Ok(count);
             }
 
@@ -111,18 +308,104 @@ where
             if let Some(mut cmd) = cmd {
                 count += 1;
                 cmd.reregister(task::current());
+                if self.cluster.capture.is_active() {
+                    let data = cmd.borrow().req_data();
+                    self.cluster
+                        .capture
+                        .request(self.client_id, self.recv_seq, data);
+                }
+                self.recv_seq += 1;
 
                 cmd.cluster_mark_total(&self.cluster.cc.borrow().name);
 
-                if cmd.check_valid() && !cmd.borrow().is_done() {
+                let keyslot = cmd.borrow().keyslot_key();
+                if cmd.reply_quit() {
+                    // replied after the ones before it, and closed once all are flushed
+                    self.state = State::Closing;
+                } else if self.flags.is_drained() {
+                    // the next request after drained is rejected, and closed once all are flushed
+                    cmd.set_error(&AsError::ShuttingDown);
+                    self.state = State::Closing;
+                } else if let Err(err) = self.check_acl(&cmd) {
+                    cmd.set_error(&err);
+                } else if let Err(err) = self.cluster.check_dangerous(&cmd) {
+                    // denied before anything else, even if it's not supported by proxy
+                    cmd.set_error(&err);
+                } else if let Some(key) = keyslot {
+                    // answered by proxy with the same hash tag as routing
+                    match key {
+                        Ok(key) => cmd.set_reply(self.cluster.key_slot(&key)),
+                        Err(err) => cmd.set_error(&err),
+                    }
+                } else if cmd.check_valid() && !cmd.borrow().is_done() {
                     // for done command, never send to backend
-                    if let Some(subs) = cmd.borrow().subs() {
-                        self.sendq.extend(subs.into_iter());
+                    if cmd.borrow().is_proxy() {
+                        let args = cmd.borrow().proxy_args().unwrap_or_default();
+                        match shard::shard_key(&args) {
+                            Some(Ok(key)) => {
+                                let fields = self.cluster.shard(key.as_bytes()).fields();
+                                cmd.set_reply(Message::from_args(&fields));
+                            }
+                            Some(Err(err)) => cmd.set_error(&err),
+                            // backends of redis cluster are discovered
+                            None => cmd.set_error(&AsError::RequestNotSupport),
+                        }
+                    } else if cmd.borrow().is_aster() {
+                        // ASTER DEADLINE is of proxy mode only
+                        cmd.set_error(&AsError::RequestNotSupport);
+                    } else if cmd.borrow().is_client_kill() {
+                        let args = cmd.borrow().client_kill_args().unwrap_or_default();
+                        match self.cluster.clients.kill(self.client_id, &args) {
+                            Ok(count) => cmd.set_reply(count),
+                            Err(err) => cmd.set_error(&err),
+                        }
+                    } else if cmd.borrow().is_info() {
+                        let args = cmd.borrow().info_args().unwrap_or_default();
+                        cmd.set_reply(Message::bulk(self.cluster.info(&args).as_bytes()));
                     } else {
-                        self.sendq.push_back(cmd.clone());
+                        self.cluster.hooks.on_request(&mut cmd);
+                    }
+                    if cmd.borrow().is_done() {
+                        // replied by hooks
+                    } else if maintenance {
+                        for sub in cmd.borrow().subs().unwrap_or_default() {
+                            sub.set_error(&AsError::Maintenance);
+                        }
+                        cmd.set_error(&AsError::Maintenance);
+                    } else if read_only && cmd.borrow().is_mutation() {
+                        for sub in cmd.borrow().subs().unwrap_or_default() {
+                            sub.set_error(&AsError::ReadOnly);
+                        }
+                        cmd.set_error(&AsError::ReadOnly);
+                    } else if cmd.borrow().is_admin() {
+                        // admin commands may break the topology of redis cluster
+                        cmd.set_error(&AsError::RequestNotSupport);
+                    } else if let Err(err) = self.cluster.check_blocking(&cmd) {
+                        cmd.set_error(&err);
+                    } else if let Err(err) = self.cluster.check_two_keys(&cmd) {
+                        cmd.set_error(&err);
+                    } else if let Err(err) = self.cluster.check_sort(&cmd) {
+                        cmd.set_error(&err);
+                    } else if let Some(fault) =
+                        self.cluster.fault.inject(!cmd.borrow().is_mutation(), |x| {
+                            cmd.borrow().has_key_prefix(x)
+                        })
+                    {
+                        self.track_session(&cmd);
+                        self.inject(&cmd, fault, batch);
+                    } else if self.waving {
+                        self.track_session(&cmd);
+                        self.held.push_back((self.recv_seq - 1, cmd.clone()));
+                    } else {
+                        self.track_session(&cmd);
+                        self.dispatch(&cmd, batch);
                     }
                 }
                 self.waitq.push_back(cmd);
+                if self.state == State::Closing {
+                    // nothing is received after QUIT
+                    return Ok(count);
+                }
             } else {
                 self.state = State::Closed;
                 return Ok(0);
@@ -143,7 +426,46 @@ where
         let mut can_reply = true;
         let mut can_send = true;
         let mut can_recv = self.state == State::Running;
+        if !self.registered {
+            self.cluster
+                .worker
+                .register(self.client_id, task::current());
+            self.flags =
+                self.cluster
+                    .clients
+                    .register(self.client_id, &self.client, task::current());
+            self.registered = true;
+        }
         loop {
+            if self.state != State::Closed && self.flags.is_killed() {
+                // closed by CLIENT KILL
+                self.state = State::Closed;
+            }
+            if self.state == State::Running
+                && self.flags.is_drained()
+                && self.waitq.is_empty()
+                && self.sendq.is_empty()
+            {
+                // drained for the rollouts once all the requests in flight are replied
+                self.state = State::Closing;
+                can_recv = false;
+            }
+            if self.state != State::Closed && self.cluster.worker.is_closing() {
+                // no more requests are received, and closed after all are replied
+                self.state = if self.waitq.is_empty() && self.sendq.is_empty() {
+                    State::Closed
+                } else {
+                    State::Closing
+                };
+                can_recv = false;
+            }
+            if self.state == State::Closing && self.waitq.is_empty() && self.sendq.is_empty() {
+                // the pending replies are flushed before closed
+                if let Ok(Async::NotReady) = self.output.close() {
+                    return Ok(Async::NotReady);
+                }
+                self.state = State::Closed;
+            }
             if self.state == State::Closed {
                 // debug!("front drop of {}", self.client);
                 return Ok(Async::Ready(()));
@@ -205,6 +527,14 @@ where
                         can_recv = 0 != size && self.state == State::Running;
                         // trace!("front recv is ready and recv {}", size);
                     }
+                    Err(AsError::ProtocolError(reason)) => {
+                        // replied by the protocol error, and closed after all are replied
+                        warn!("client {} meet protocol error: {}", self.client, reason);
+                        self.state = State::Closing;
+                        can_send = true;
+                        can_reply = true;
+                        can_recv = false;
+                    }
                     Err(err) => {
                         error!("fail to read from client {} due to {}", self.client, err);
                         self.state = State::Closed;
@@ -222,6 +552,8 @@ where
     O: Sink<SinkItem = Cmd, SinkError = AsError>,
 {
     fn drop(&mut self) {
+        self.cluster.worker.unregister(self.client_id);
+        self.cluster.clients.unregister(self.client_id);
         crate::metrics::front_conn_decr(&self.cluster.cc.borrow().name);
     }
 }
@@ -4,8 +4,11 @@ use futures::{Async, AsyncSink, Future, Sink};
 
 use crate::com::AsError;
 use crate::com::ClusterConfig;
-use crate::protocol::redis::{new_cluster_slots_cmd, slots_reply_to_replicas, Cmd};
+use crate::protocol::redis::Cmd;
 use crate::proxy::cluster::{Cluster, ConnBuilder};
+use crate::proxy::worker::Worker;
+
+use std::rc::Rc;
 
 enum State {
     Pending,
@@ -17,14 +20,16 @@ enum State {
 
 pub struct Initializer {
     cc: ClusterConfig,
+    worker: Rc<Worker>,
     current: usize,
     state: State,
 }
 
 impl Initializer {
-    pub fn new(cc: ClusterConfig) -> Initializer {
+    pub fn new(cc: ClusterConfig, worker: Rc<Worker>) -> Initializer {
         Initializer {
             cc,
+            worker,
             current: 0,
             state: State::Pending,
         }
@@ -61,7 +66,7 @@ impl Future for Initializer {
 
                     match conn {
                         Ok(sender) => {
-                            let mut cmd = new_cluster_slots_cmd();
+                            let mut cmd = self.cc.backend_flavor.unwrap_or_default().topology_cmd();
                             cmd.reregister(task::current());
                             self.state = State::Fetching(sender, cmd);
                         }
@@ -99,9 +104,14 @@ impl Future for Initializer {
                     debug!("CLUSTER SLOTS get response as {:?}", cmd);
                     self.state = State::Done(cmd.clone());
                 }
-                State::Done(cmd) => match slots_reply_to_replicas(cmd.clone()) {
+                State::Done(cmd) => match self
+                    .cc
+                    .backend_flavor
+                    .unwrap_or_default()
+                    .topology_layout(cmd.clone())
+                {
                     Ok(Some(replica)) => {
-                        let cluster = Cluster::run(self.cc.clone(), replica);
+                        let cluster = Cluster::run(self.cc.clone(), replica, self.worker.clone());
                         match cluster {
                             Ok(_) => {
                                 info!("succeed to create cluster {}", self.cc.name);
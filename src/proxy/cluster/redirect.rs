@@ -31,24 +31,27 @@ impl Future for RedirectHandler {
         loop {
             if let Some(Redirection { target, cmd }) = self.store.take() {
                 if !cmd.borrow().can_cycle() {
-                    cmd.set_error(AsError::RequestReachMaxCycle);
+                    cmd.set_error(&AsError::RequestReachMaxCycle);
                     continue;
                 }
 
                 let (slot, to, is_move) = match target {
                     Redirect::Move { slot, to } => (slot, to, true),
                     Redirect::Ask { slot, to } => (slot, to, false),
+                    Redirect::Readonly { slot, from } => {
+                        // routed as moved to the master, which is kept if it's not ready
+                        let to = self.cluster.on_readonly(slot, &from, &cmd);
+                        (slot, to, true)
+                    }
                 };
-                if is_move {
+                if is_move && self.cluster.update_slot(slot, to.clone()) {
                     info!(
                         "cluster {} slot {} was moved to {}",
                         self.cluster.cc.borrow().name,
                         slot,
                         to
                     );
-                    if self.cluster.update_slot(slot, to.clone()) {
-                        self.cluster.trigger_fetch(fetcher::TriggerBy::Moved);
-                    }
+                    self.cluster.trigger_fetch(fetcher::TriggerBy::Moved);
                 }
                 let rc_cmd = cmd.clone();
                 match self.cluster.dispatch_to(&to, cmd) {
@@ -58,10 +61,11 @@ impl Future for RedirectHandler {
                     }
                     Ok(AsyncSink::Ready) => {
                         rc_cmd.borrow_mut().add_cycle();
-                        std::mem::drop(rc_cmd);
                     }
                     Err(err) => {
                         error!("fail to dispath moved cmd to backend {} due to {}", to, err);
+                        // replied by the error, or the client waits for it forever
+                        rc_cmd.set_error(&err);
                     }
                 }
             }
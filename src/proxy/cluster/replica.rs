@@ -0,0 +1,328 @@
+//! selection among the replicas of one slot when read_from_slave is enabled.
+use rand::{thread_rng, Rng};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::com::{ClusterConfig, ReplicaStrategy};
+
+pub trait Strategy {
+    /// the index of the replica to read from, replicas is never empty and round counts the
+    /// selections among them.
+    fn select(&self, replicas: &[String], round: usize) -> usize;
+}
+
+pub fn new_strategy(
+    cc: &ClusterConfig,
+    outstanding: &Outstanding,
+    latencies: &Latencies,
+) -> Box<dyn Strategy> {
+    match cc.replica_strategy.unwrap_or_default() {
+        ReplicaStrategy::RoundRobin => Box::new(RoundRobin),
+        ReplicaStrategy::WeightedRandom => Box::new(WeightedRandom::new(&cc.replica_weights)),
+        ReplicaStrategy::LeastOutstanding => Box::new(LeastOutstanding::new(outstanding.clone())),
+        ReplicaStrategy::LatencyWeighted => {
+            Box::new(LatencyWeighted::new(&cc.replica_weights, latencies.clone()))
+        }
+    }
+}
+
+/// requests awaiting replies of each backend connection, updated by the connection itself.
+#[derive(Clone, Default)]
+pub struct Outstanding {
+    conns: Rc<RefCell<HashMap<String, Rc<Cell<usize>>>>>,
+}
+
+impl Outstanding {
+    /// the counter of the new connection to addr, which replaces the old one.
+    pub fn track(&self, addr: &str) -> Rc<Cell<usize>> {
+        let counter = Rc::new(Cell::new(0));
+        self.conns
+            .borrow_mut()
+            .insert(addr.to_string(), counter.clone());
+        counter
+    }
+
+    pub fn get(&self, addr: &str) -> usize {
+        self.conns.borrow().get(addr).map(|x| x.get()).unwrap_or(0)
+    }
+}
+
+/// the smoothed reply latency in micros of each backend connection, updated by the connection
+/// itself, 0 until the first reply.
+#[derive(Clone, Default)]
+pub struct Latencies {
+    conns: Rc<RefCell<HashMap<String, Rc<Cell<u64>>>>>,
+}
+
+impl Latencies {
+    /// the latency of the new connection to addr, which replaces the old one.
+    pub fn track(&self, addr: &str) -> Rc<Cell<u64>> {
+        let latency = Rc::new(Cell::new(0));
+        self.conns
+            .borrow_mut()
+            .insert(addr.to_string(), latency.clone());
+        latency
+    }
+
+    pub fn get(&self, addr: &str) -> u64 {
+        self.conns.borrow().get(addr).map(|x| x.get()).unwrap_or(0)
+    }
+}
+
+/// move the smoothed latency 1/8 of the way to the elapsed of the reply, like the srtt of TCP.
+pub fn observe(latency: &Cell<u64>, elapsed: Duration) {
+    let sample = (elapsed.as_micros() as u64).max(1);
+    let old = latency.get();
+    if old == 0 {
+        latency.set(sample);
+    } else {
+        latency.set((old * 7 + sample) / 8);
+    }
+}
+
+pub struct RoundRobin;
+
+impl Strategy for RoundRobin {
+    fn select(&self, replicas: &[String], round: usize) -> usize {
+        round % replicas.len()
+    }
+}
+
+/// replicas are chosen at random in proportion to their weights by address, 1 if absent.
+pub struct WeightedRandom {
+    weights: BTreeMap<String, usize>,
+}
+
+impl WeightedRandom {
+    pub fn new(weights: &BTreeMap<String, usize>) -> WeightedRandom {
+        WeightedRandom {
+            weights: weights.clone(),
+        }
+    }
+
+    fn weight(&self, addr: &str) -> usize {
+        self.weights.get(addr).cloned().unwrap_or(1)
+    }
+}
+
+impl Strategy for WeightedRandom {
+    fn select(&self, replicas: &[String], round: usize) -> usize {
+        let total: usize = replicas.iter().map(|x| self.weight(x)).sum();
+        if total == 0 {
+            return round % replicas.len();
+        }
+        let mut point = thread_rng().gen_range(0, total);
+        for (i, addr) in replicas.iter().enumerate() {
+            let weight = self.weight(addr);
+            if point < weight {
+                return i;
+            }
+            point -= weight;
+        }
+        unreachable!("point must be less than total weight")
+    }
+}
+
+/// replicas are chosen at random in proportion to their weights by address divided by their
+/// latencies, so the faster ones take more reads. The replica not replied yet is taken as fast as
+/// the fastest one, and the weights are used alone until any of them is replied.
+pub struct LatencyWeighted {
+    weights: WeightedRandom,
+    latencies: Latencies,
+}
+
+impl LatencyWeighted {
+    pub fn new(weights: &BTreeMap<String, usize>, latencies: Latencies) -> LatencyWeighted {
+        LatencyWeighted {
+            weights: WeightedRandom::new(weights),
+            latencies,
+        }
+    }
+}
+
+impl Strategy for LatencyWeighted {
+    fn select(&self, replicas: &[String], round: usize) -> usize {
+        let observed: Vec<_> = replicas.iter().map(|x| self.latencies.get(x)).collect();
+        let fastest = match observed.iter().filter(|x| **x > 0).min() {
+            Some(fastest) => *fastest,
+            None => return self.weights.select(replicas, round),
+        };
+        let shares: Vec<f64> = replicas
+            .iter()
+            .zip(observed.iter())
+            .map(|(addr, latency)| {
+                let latency = if *latency == 0 { fastest } else { *latency };
+                self.weights.weight(addr) as f64 / latency as f64
+            })
+            .collect();
+        let total: f64 = shares.iter().sum();
+        if total <= 0.0 {
+            return round % replicas.len();
+        }
+        let mut point = thread_rng().gen_range(0.0, total);
+        for (i, share) in shares.iter().enumerate() {
+            if point < *share {
+                return i;
+            }
+            point -= share;
+        }
+        // the rounding error of floats
+        replicas.len() - 1
+    }
+}
+
+/// the replica with the fewest requests awaiting replies, ties are broken by round.
+pub struct LeastOutstanding {
+    outstanding: Outstanding,
+}
+
+impl LeastOutstanding {
+    pub fn new(outstanding: Outstanding) -> LeastOutstanding {
+        LeastOutstanding { outstanding }
+    }
+}
+
+impl Strategy for LeastOutstanding {
+    fn select(&self, replicas: &[String], round: usize) -> usize {
+        let len = replicas.len();
+        (0..len)
+            .map(|i| round.wrapping_add(i) % len)
+            .min_by_key(|i| self.outstanding.get(&replicas[*i]))
+            .expect("replicas never be empty")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn replicas() -> Vec<String> {
+        (1..=3).map(|i| format!("127.0.0.1:700{}", i)).collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let replicas = replicas();
+        let selected: Vec<_> = (0..6).map(|x| RoundRobin.select(&replicas, x)).collect();
+        assert_eq!(selected, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_weighted_random_distribution() {
+        let replicas = replicas();
+        let weights: BTreeMap<_, _> = vec![(replicas[0].clone(), 6), (replicas[1].clone(), 3)]
+            .into_iter()
+            .collect();
+        // the absent one is weighted 1
+        let strategy = WeightedRandom::new(&weights);
+        let rounds = 100_000;
+        let mut counts = [0usize; 3];
+        for round in 0..rounds {
+            counts[strategy.select(&replicas, round)] += 1;
+        }
+        for (count, weight) in counts.iter().zip([6, 3, 1].iter()) {
+            let expected = rounds * weight / 10;
+            let diff = (*count as i64 - expected as i64).abs() as usize;
+            assert!(diff < rounds / 100, "{:?} deviates", counts);
+        }
+
+        // all zero weighted is the same as round robin
+        let zeros: BTreeMap<_, _> = replicas.iter().map(|x| (x.clone(), 0)).collect();
+        assert_eq!(WeightedRandom::new(&zeros).select(&replicas, 4), 1);
+    }
+
+    #[test]
+    fn test_latency_weighted_distribution() {
+        let replicas: Vec<_> = replicas().into_iter().take(2).collect();
+        let weights: BTreeMap<_, _> = vec![(replicas[0].clone(), 3)].into_iter().collect();
+        let latencies = Latencies::default();
+        let strategy = LatencyWeighted::new(&weights, latencies.clone());
+        let rounds = 100_000;
+        let distribution = |strategy: &LatencyWeighted| {
+            let mut counts = [0usize; 2];
+            for round in 0..rounds {
+                counts[strategy.select(&replicas, round)] += 1;
+            }
+            counts
+        };
+        let check = |counts: [usize; 2], shares: [usize; 2]| {
+            let total: usize = shares.iter().sum();
+            for (count, share) in counts.iter().zip(shares.iter()) {
+                let expected = rounds * share / total;
+                let diff = (*count as i64 - expected as i64).abs() as usize;
+                assert!(diff < rounds / 100, "{:?} deviates", counts);
+            }
+        };
+
+        // by weights alone before any reply, 3:1
+        check(distribution(&strategy), [3, 1]);
+        // the second one replied is taken as the fastest one, 3:1 still
+        observe(&latencies.track(&replicas[1]), Duration::from_micros(200));
+        check(distribution(&strategy), [3, 1]);
+        // the first one is 3 times slower, which evens out its weight
+        observe(&latencies.track(&replicas[0]), Duration::from_micros(600));
+        check(distribution(&strategy), [1, 1]);
+    }
+
+    #[test]
+    fn test_observe_latency() {
+        let latency = Cell::new(0);
+        observe(&latency, Duration::from_micros(800));
+        assert_eq!(latency.get(), 800);
+        observe(&latency, Duration::from_micros(0));
+        assert_eq!(latency.get(), 700);
+        for _ in 0..100 {
+            observe(&latency, Duration::from_micros(100));
+        }
+        assert_eq!(latency.get(), 100);
+    }
+
+    #[test]
+    fn test_least_outstanding_unequal_service() {
+        let replicas = replicas();
+        let outstanding = Outstanding::default();
+        let counters: Vec<_> = replicas.iter().map(|x| outstanding.track(x)).collect();
+        let strategy = LeastOutstanding::new(outstanding);
+        // replies per tick of each replica, the first one is 4 times faster than the last
+        let speeds = [8, 4, 2];
+        let mut counts = [0usize; 3];
+        let mut round = 0;
+        for _ in 0..10_000 {
+            for _ in 0..14 {
+                let i = strategy.select(&replicas, round);
+                round += 1;
+                counts[i] += 1;
+                counters[i].set(counters[i].get() + 1);
+            }
+            for (counter, speed) in counters.iter().zip(speeds.iter()) {
+                counter.set(counter.get().saturating_sub(*speed));
+            }
+        }
+        let total: usize = counts.iter().sum();
+        for (count, speed) in counts.iter().zip(speeds.iter()) {
+            let expected = total * speed / 14;
+            let diff = (*count as i64 - expected as i64).abs() as usize;
+            assert!(diff < total / 50, "{:?} deviates", counts);
+        }
+    }
+
+    #[test]
+    fn test_least_outstanding_tie_rotation() {
+        let replicas = replicas();
+        let outstanding = Outstanding::default();
+        let strategy = LeastOutstanding::new(outstanding.clone());
+        let selected: Vec<_> = (0..3).map(|x| strategy.select(&replicas, x)).collect();
+        assert_eq!(selected, vec![0, 1, 2]);
+
+        outstanding.track(&replicas[0]).set(5);
+        outstanding.track(&replicas[1]).set(1);
+        outstanding.track(&replicas[2]).set(3);
+        assert_eq!(strategy.select(&replicas, 0), 1);
+        // replaced by the new connection
+        outstanding.track(&replicas[2]);
+        assert_eq!(strategy.select(&replicas, 0), 2);
+    }
+}
@@ -0,0 +1,174 @@
+//! session consistency of the reads from replicas: each front connection remembers the keys it
+//! wrote within replica_read_session_window, and reads them from master during the window so
+//! the connection never reads its own writes stale from a lagging replica. The keys are kept
+//! as hashes in a ring bounded by replica_read_session_keys, so the oldest are forgotten early
+//! under heavy writes, and the collided hashes only read from master more.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::com::{ClusterConfig, ReplicaReadConsistency};
+use crate::metrics::session_read_incr;
+use crate::protocol::redis::Cmd;
+use crate::proxy::shard::Role;
+use crate::proxy::standalone::fnv::fnv1a64;
+
+const DEFAULT_SESSION_WINDOW: u64 = 1000; // 1s
+const DEFAULT_SESSION_KEYS: usize = 256;
+
+pub struct Session {
+    cluster: String,
+    window: Duration,
+    capacity: usize,
+    // hashes of the keys written and when, in the order of writes
+    recent: VecDeque<(u64, Instant)>,
+}
+
+impl Session {
+    /// None unless read_from_slave with replica_read_consistency session.
+    pub fn new(cc: &ClusterConfig) -> Option<Session> {
+        let consistency = cc.replica_read_consistency.unwrap_or_default();
+        if !cc.read_from_slave.unwrap_or(false) || consistency != ReplicaReadConsistency::Session {
+            return None;
+        }
+        let window = cc
+            .replica_read_session_window
+            .unwrap_or(DEFAULT_SESSION_WINDOW);
+        let capacity = cc.replica_read_session_keys.unwrap_or(DEFAULT_SESSION_KEYS);
+        Some(Session {
+            cluster: cc.name.clone(),
+            window: Duration::from_millis(window),
+            capacity: capacity.max(1),
+            recent: VecDeque::new(),
+        })
+    }
+
+    /// remember the keys of write command, or mark the read command to read from master if
+    /// any of its keys is written recently. The subs are tracked instead of multi-key command.
+    pub fn track(&mut self, cmd: &Cmd) {
+        let now = Instant::now();
+        self.expire(now);
+        match cmd.borrow().subs() {
+            Some(subs) => subs.iter().for_each(|sub| self.track_one(sub, now)),
+            None => self.track_one(cmd, now),
+        }
+    }
+
+    fn track_one(&mut self, cmd: &Cmd, now: Instant) {
+        let mut cmd = cmd.borrow_mut();
+        if cmd.is_mutation() {
+            for key in cmd.keys() {
+                if self.recent.len() == self.capacity {
+                    self.recent.pop_front();
+                }
+                self.recent.push_back((fnv1a64(key), now));
+            }
+        } else if cmd.is_read() {
+            let written = cmd
+                .keys()
+                .into_iter()
+                .map(fnv1a64)
+                .any(|hash| self.recent.iter().any(|x| x.0 == hash));
+            let role = if written {
+                cmd.set_read_master();
+                Role::Master
+            } else {
+                Role::Replica
+            };
+            session_read_incr(&self.cluster, role.as_str());
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, at)) = self.recent.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::session_read_get;
+    use crate::protocol::redis::{Command, Message};
+    use bytes::BytesMut;
+    use std::thread;
+
+    fn new_cmd(args: &[&str]) -> Cmd {
+        let mut buf = BytesMut::new();
+        Message::from_args(args).save(&mut buf);
+        Command::parse_cmd(&mut buf).unwrap().unwrap()
+    }
+
+    fn new_session(name: &str, window: u64, keys: usize) -> Session {
+        let cc = ClusterConfig {
+            name: name.to_string(),
+            read_from_slave: Some(true),
+            replica_read_consistency: Some(ReplicaReadConsistency::Session),
+            replica_read_session_window: Some(window),
+            replica_read_session_keys: Some(keys),
+            ..Default::default()
+        };
+        Session::new(&cc).unwrap()
+    }
+
+    fn read_master(session: &mut Session, args: &[&str]) -> bool {
+        let cmd = new_cmd(args);
+        session.track(&cmd);
+        let read_master = cmd.borrow().is_read_master();
+        read_master
+    }
+
+    #[test]
+    fn test_session_disabled() {
+        let mut cc = ClusterConfig {
+            replica_read_consistency: Some(ReplicaReadConsistency::Session),
+            ..Default::default()
+        };
+        assert!(Session::new(&cc).is_none());
+        cc.read_from_slave = Some(true);
+        assert!(Session::new(&cc).is_some());
+        cc.replica_read_consistency = None;
+        assert!(Session::new(&cc).is_none());
+    }
+
+    #[test]
+    fn test_session_read_your_writes() {
+        let name = "test-session-read-your-writes";
+        let mut session = new_session(name, 60_000, 256);
+        assert!(!read_master(&mut session, &["GET", "a"]));
+        session.track(&new_cmd(&["SET", "a", "1"]));
+        assert!(read_master(&mut session, &["GET", "a"]));
+        assert!(!read_master(&mut session, &["GET", "b"]));
+
+        // each key of multi-key command by its sub
+        session.track(&new_cmd(&["MSET", "c", "1", "d", "2"]));
+        let mget = new_cmd(&["MGET", "b", "d"]);
+        session.track(&mget);
+        let subs = mget.borrow().subs().unwrap();
+        assert!(!subs[0].borrow().is_read_master());
+        assert!(subs[1].borrow().is_read_master());
+
+        assert_eq!(session_read_get(name, "master"), 2);
+        assert_eq!(session_read_get(name, "replica"), 3);
+    }
+
+    #[test]
+    fn test_session_bounded() {
+        let mut session = new_session("test-session-bounded", 50, 2);
+        for key in &["a", "b", "c"] {
+            session.track(&new_cmd(&["SET", *key, "1"]));
+        }
+        // the oldest is forgotten beyond the capacity
+        assert!(!read_master(&mut session, &["GET", "a"]));
+        assert!(read_master(&mut session, &["GET", "c"]));
+        assert_eq!(session.recent.len(), 2);
+
+        // and all are forgotten after the window
+        thread::sleep(Duration::from_millis(60));
+        assert!(!read_master(&mut session, &["GET", "c"]));
+        assert!(session.recent.is_empty());
+    }
+}
@@ -0,0 +1,427 @@
+//! requests and errors counted by each slot of redis cluster, shared by all the worker threads,
+//! which finds out the hot or erroring slots beyond the nodes, e.g.: the imbalance after
+//! resharding. The requests are counted once dispatched (each sub of multi-key command apart),
+//! and the errors once replied to client, only for the ones ever dispatched.
+//!
+//! The counters are saved into slot_stats_file periodically and on graceful shutdown, and loaded
+//! on startup, so they are not reset to zero by every rollout. The file which is corrupt, of
+//! another version or saved by another cluster is ignored with a warning.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::com::{AsError, ClusterConfig};
+use crate::protocol::redis::{Cmd, SLOTS_COUNT};
+
+const DEFAULT_TOP: usize = 10;
+const DEFAULT_SAVE_INTERVAL: u64 = 60;
+const STATE_VERSION: u32 = 1;
+
+lazy_static! {
+    static ref SLOT_STATS: Mutex<HashMap<String, Arc<SlotStats>>> = Mutex::new(HashMap::new());
+}
+
+/// get the slot counters of the cluster, loaded from the state file once created.
+pub fn handle(cc: &ClusterConfig) -> Arc<SlotStats> {
+    let mut all = SLOT_STATS.lock().unwrap();
+    if let Some(stats) = all.get(&cc.name) {
+        return stats.clone();
+    }
+    let stats = Arc::new(SlotStats::from_config(cc));
+    if stats.file.is_some() {
+        spawn_saver(cc, stats.clone());
+    }
+    all.insert(cc.name.clone(), stats.clone());
+    stats
+}
+
+fn spawn_saver(cc: &ClusterConfig, stats: Arc<SlotStats>) {
+    let interval = cc
+        .slot_stats_save_interval
+        .unwrap_or(DEFAULT_SAVE_INTERVAL)
+        .max(1);
+    let spawned = thread::Builder::new()
+        .name(format!("aster-slots-{}", cc.name))
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+            stats.save_or_warn();
+        });
+    if let Err(err) = spawned {
+        warn!(
+            "cluster {} fail to save slot stats periodically due to {}",
+            cc.name, err
+        );
+    }
+}
+
+/// the slot counters of the cluster, None if it's not running in cluster mode.
+pub fn get(cluster: &str) -> Option<Arc<SlotStats>> {
+    SLOT_STATS.lock().unwrap().get(cluster).cloned()
+}
+
+/// options of the hottest slots given by admin api.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TopOption {
+    // count of slots, 10 by default
+    pub top: Option<usize>,
+}
+
+impl TopOption {
+    pub fn top(&self) -> usize {
+        self.top.unwrap_or(DEFAULT_TOP)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlotStat {
+    pub slot: usize,
+    pub requests: u64,
+    pub errors: u64,
+}
+
+/// content of the state file.
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    version: u32,
+    cluster: String,
+    // [slot, requests, errors] of the slots ever counted, ordered by slot
+    slots: Vec<(usize, u64, u64)>,
+}
+
+struct StateFile {
+    path: PathBuf,
+    cluster: String,
+    // the periodic saver, the workers on shutdown and admin reset save by turns
+    saving: Mutex<()>,
+}
+
+pub struct SlotStats {
+    requests: Vec<AtomicU64>,
+    errors: Vec<AtomicU64>,
+    file: Option<StateFile>,
+}
+
+impl SlotStats {
+    fn new() -> SlotStats {
+        SlotStats {
+            requests: (0..SLOTS_COUNT).map(|_| AtomicU64::new(0)).collect(),
+            errors: (0..SLOTS_COUNT).map(|_| AtomicU64::new(0)).collect(),
+            file: None,
+        }
+    }
+
+    fn from_config(cc: &ClusterConfig) -> SlotStats {
+        let mut stats = SlotStats::new();
+        let path = match cc.slot_stats_file.as_ref() {
+            Some(path) => path,
+            None => return stats,
+        };
+        stats.file = Some(StateFile {
+            path: PathBuf::from(path),
+            cluster: cc.name.clone(),
+            saving: Mutex::new(()),
+        });
+        if cc.slot_stats_reset.unwrap_or(false) {
+            info!(
+                "cluster {} ignore slot stats file {} due to slot_stats_reset",
+                cc.name, path
+            );
+        } else if let Err(err) = stats.load() {
+            warn!(
+                "cluster {} ignore slot stats file {} due to {}",
+                cc.name, path, err
+            );
+        }
+        stats
+    }
+
+    /// load the counters from the state file, the file absent is skipped.
+    fn load(&self) -> Result<(), AsError> {
+        let file = match self.file.as_ref() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let data = match fs::read(&file.path) {
+            Ok(data) => data,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let state: State = serde_json::from_slice(&data)
+            .map_err(|err| AsError::BadState(format!("corrupt file: {}", err)))?;
+        if state.version != STATE_VERSION {
+            return Err(AsError::BadState(format!(
+                "version {} is not {}",
+                state.version, STATE_VERSION
+            )));
+        }
+        if state.cluster != file.cluster {
+            return Err(AsError::BadState(format!(
+                "saved by cluster {}",
+                state.cluster
+            )));
+        }
+        if let Some(bad) = state.slots.iter().find(|x| x.0 >= SLOTS_COUNT) {
+            return Err(AsError::BadState(format!("bad slot {}", bad.0)));
+        }
+        for (slot, requests, errors) in state.slots {
+            self.requests[slot].store(requests, Ordering::Relaxed);
+            self.errors[slot].store(errors, Ordering::Relaxed);
+        }
+        info!(
+            "cluster {} load slot stats from {:?}",
+            file.cluster, file.path
+        );
+        Ok(())
+    }
+
+    /// save the counters into the state file, which is replaced by the temporary file synced,
+    /// so the former one is kept whole if aster is killed while saving.
+    pub fn save(&self) -> Result<(), AsError> {
+        let file = match self.file.as_ref() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let _saving = file.saving.lock().unwrap();
+        let state = State {
+            version: STATE_VERSION,
+            cluster: file.cluster.clone(),
+            slots: self
+                .counted()
+                .into_iter()
+                .map(|x| (x.slot, x.requests, x.errors))
+                .collect(),
+        };
+        let data = serde_json::to_vec(&state).expect("serialize slot stats never fail");
+        let mut tmp = file.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut writer = File::create(&tmp)?;
+        writer.write_all(&data)?;
+        writer.sync_all()?;
+        fs::rename(&tmp, &file.path)?;
+        Ok(())
+    }
+
+    pub fn save_or_warn(&self) {
+        if let Err(err) = self.save() {
+            let cluster = self.file.as_ref().map(|x| x.cluster.as_str());
+            warn!(
+                "cluster {} fail to save slot stats due to {}",
+                cluster.unwrap_or(""),
+                err
+            );
+        }
+    }
+
+    /// zero all the counters, and the state file too.
+    pub fn reset(&self) -> Result<(), AsError> {
+        for count in self.requests.iter().chain(self.errors.iter()) {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.save()
+    }
+
+    pub fn incr_request(&self, slot: usize) {
+        if let Some(count) = self.requests.get(slot) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn incr_error(&self, slot: usize) {
+        if let Some(count) = self.errors.get(slot) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// count the errors of the command replied, by each sub of multi-key command.
+    pub fn record_reply(&self, cmd: &Cmd) {
+        let subs = cmd.borrow().subs();
+        let leaves = subs.unwrap_or_else(|| vec![cmd.clone()]);
+        for leaf in leaves {
+            let leaf = leaf.borrow();
+            match leaf.slot() {
+                Some(slot) if leaf.is_error() => self.incr_error(slot),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn get(&self, slot: usize) -> SlotStat {
+        let load = |counts: &[AtomicU64]| {
+            counts
+                .get(slot)
+                .map(|x| x.load(Ordering::Relaxed))
+                .unwrap_or(0)
+        };
+        SlotStat {
+            slot,
+            requests: load(&self.requests),
+            errors: load(&self.errors),
+        }
+    }
+
+    // the slots ever counted, ordered by slot
+    fn counted(&self) -> Vec<SlotStat> {
+        (0..SLOTS_COUNT)
+            .map(|slot| self.get(slot))
+            .filter(|x| x.requests > 0 || x.errors > 0)
+            .collect()
+    }
+
+    /// the n hottest slots ordered by requests, the slots never requested are skipped.
+    pub fn top(&self, n: usize) -> Vec<SlotStat> {
+        let mut stats = self.counted();
+        stats.sort_by(|a, b| {
+            b.requests
+                .cmp(&a.requests)
+                .then(b.errors.cmp(&a.errors))
+                .then(a.slot.cmp(&b.slot))
+        });
+        stats.truncate(n);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::com::AsError;
+    use crate::protocol::redis::Command;
+    use crate::proxy::standalone::Request;
+    use crate::utils::crc::crc16;
+    use bytes::BytesMut;
+    use std::path::Path;
+
+    // dispatch the command as cluster mode does, by the slot of each sub
+    fn dispatch(stats: &SlotStats, req: &[u8]) -> Cmd {
+        let cmd = Command::parse_cmd(&mut BytesMut::from(req))
+            .unwrap()
+            .unwrap();
+        let leaves = cmd.borrow().subs().unwrap_or_else(|| vec![cmd.clone()]);
+        for leaf in leaves {
+            let key_hash = leaf.borrow().key_hash(b"{}", crc16).unwrap();
+            let slot = key_hash as usize % SLOTS_COUNT;
+            leaf.set_slot(slot);
+            stats.incr_request(slot);
+        }
+        cmd
+    }
+
+    #[test]
+    fn test_slot_stats_by_known_slots() {
+        let stats = SlotStats::new();
+        // "foo" is in slot 12182, "bar" in 5061 and "{foo}bar" shares the slot of "foo"
+        dispatch(&stats, b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n");
+        dispatch(&stats, b"*2\r\n$3\r\nGET\r\n$8\r\n{foo}bar\r\n");
+        let mget = dispatch(&stats, b"*3\r\n$4\r\nMGET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n");
+        assert_eq!(stats.get(12182).requests, 3);
+        assert_eq!(stats.get(5061).requests, 1);
+        assert_eq!(stats.get(0).requests, 0);
+
+        let subs = mget.borrow().subs().unwrap();
+        subs[0].set_reply(1usize);
+        subs[1].set_error(&AsError::ProxyFail);
+        stats.record_reply(&mget);
+        assert_eq!(stats.get(5061).errors, 1);
+        assert_eq!(stats.get(12182).errors, 0);
+
+        // the command never dispatched is not counted to any slot
+        let get = Command::parse_cmd(&mut BytesMut::from(
+            &b"*2\r\n$3\r\nGET\r\n$3\r\nbar\r\n"[..],
+        ))
+        .unwrap()
+        .unwrap();
+        get.set_error(&AsError::ReadOnly);
+        stats.record_reply(&get);
+        assert_eq!(stats.get(5061).errors, 1);
+
+        assert_eq!(
+            stats.top(1),
+            vec![SlotStat {
+                slot: 12182,
+                requests: 3,
+                errors: 0
+            }]
+        );
+        assert_eq!(stats.top(10).len(), 2);
+    }
+
+    #[test]
+    fn test_slot_stats_handle() {
+        let cc = ClusterConfig {
+            name: "test-slot-stats".to_string(),
+            ..Default::default()
+        };
+        assert!(get(&cc.name).is_none());
+        handle(&cc).incr_request(1);
+        assert_eq!(get(&cc.name).unwrap().get(1).requests, 1);
+        assert_eq!(handle(&cc).get(1).requests, 1);
+    }
+
+    fn state_config(name: &str, path: &Path) -> ClusterConfig {
+        ClusterConfig {
+            name: name.to_string(),
+            slot_stats_file: Some(path.to_str().unwrap().to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_slot_stats_save_and_load() {
+        let path = std::env::temp_dir().join(format!("aster-slots-{}.json", std::process::id()));
+        let cc = state_config("test-slot-state", &path);
+        let stats = SlotStats::from_config(&cc);
+        stats.incr_request(12182);
+        stats.incr_request(12182);
+        stats.incr_request(5061);
+        stats.incr_error(5061);
+        stats.save().unwrap();
+        // saved deterministically, ordered by slot
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            r#"{"version":1,"cluster":"test-slot-state","slots":[[5061,1,1],[12182,2,0]]}"#
+        );
+
+        let loaded = SlotStats::from_config(&cc);
+        assert_eq!(loaded.top(10), stats.top(10));
+
+        // ignored by the cluster of other name, and by reset
+        let other = SlotStats::from_config(&state_config("test-slot-other", &path));
+        assert!(other.top(10).is_empty());
+        let reset_cc = ClusterConfig {
+            slot_stats_reset: Some(true),
+            ..cc.clone()
+        };
+        assert!(SlotStats::from_config(&reset_cc).top(10).is_empty());
+
+        loaded.reset().unwrap();
+        assert!(loaded.top(10).is_empty());
+        assert!(SlotStats::from_config(&cc).top(10).is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_slot_stats_bad_file_ignored() {
+        let path =
+            std::env::temp_dir().join(format!("aster-slots-bad-{}.json", std::process::id()));
+        let cc = state_config("test-slot-bad", &path);
+        // absent
+        assert!(SlotStats::from_config(&cc).load().is_ok());
+
+        for data in &[
+            "{\"version\":1,\"clus",
+            r#"{"version":2,"cluster":"test-slot-bad","slots":[[1,1,0]]}"#,
+            r#"{"version":1,"cluster":"test-slot-bad","slots":[[1,1,0],[16384,1,0]]}"#,
+        ] {
+            fs::write(&path, data).unwrap();
+            let stats = SlotStats::from_config(&cc);
+            assert!(stats.load().is_err());
+            assert!(stats.top(10).is_empty());
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
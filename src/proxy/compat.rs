@@ -0,0 +1,288 @@
+//! the behaviors differ among the redis compatible stores, selected by backend_flavor of cluster:
+//!
+//! - topology: the command to fetch the slots of redis cluster, Dragonfly in emulated cluster
+//!   mode has no CLUSTER SLOTS and is fetched by CLUSTER NODES.
+//! - unavailable: the error replies meaning the backend is up but can't serve (e.g.: loading the
+//!   dataset or busy running a script), which eject the backend of proxy mode at once until its
+//!   ping is replied by PONG.
+//!
+//! The redirect errors are the same among them and always parsed by the leading MOVED or ASK (see
+//! Message::check_redirect). Supporting the next store is adding its flavor here.
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::com::{AsError, BackendFlavor};
+use crate::protocol::redis::{
+    new_cluster_nodes_cmd, new_cluster_slots_cmd, slots_reply_to_replicas, Cmd, Message,
+    ReplicaLayout, RespType, SLOTS_COUNT,
+};
+
+const UNAVAILABLE_REDIS: &[&[u8]] = &[b"LOADING", b"MASTERDOWN", b"BUSY"];
+const UNAVAILABLE_DRAGONFLY: &[&[u8]] = &[b"LOADING"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Topology {
+    ClusterSlots,
+    ClusterNodes,
+}
+
+impl BackendFlavor {
+    pub fn topology(self) -> Topology {
+        match self {
+            BackendFlavor::Dragonfly => Topology::ClusterNodes,
+            BackendFlavor::Redis | BackendFlavor::KeyDB | BackendFlavor::Garnet => {
+                Topology::ClusterSlots
+            }
+        }
+    }
+
+    pub fn topology_cmd(self) -> Cmd {
+        match self.topology() {
+            Topology::ClusterSlots => new_cluster_slots_cmd(),
+            Topology::ClusterNodes => new_cluster_nodes_cmd(),
+        }
+    }
+
+    /// the slots layout replied to the command of topology_cmd.
+    pub fn topology_layout(self, cmd: Cmd) -> Result<Option<ReplicaLayout>, AsError> {
+        match self.topology() {
+            Topology::ClusterSlots => slots_reply_to_replicas(cmd),
+            Topology::ClusterNodes => {
+                let reply = cmd.borrow_mut().reply.take();
+                let msg = reply.expect("reply must be non-empty");
+                match msg.rtype {
+                    RespType::Bulk(_, _) => {
+                        parse_cluster_nodes(msg.data().unwrap_or_default()).map(Some)
+                    }
+                    _ => Err(AsError::WrongClusterNodesReply(
+                        String::from_utf8_lossy(&msg.data).to_string(),
+                    )),
+                }
+            }
+        }
+    }
+
+    /// the reply is an error meaning the backend can't serve for now.
+    pub fn is_unavailable(self, reply: &Message) -> bool {
+        let prefixes = match self {
+            BackendFlavor::Dragonfly => UNAVAILABLE_DRAGONFLY,
+            BackendFlavor::Redis | BackendFlavor::KeyDB | BackendFlavor::Garnet => {
+                UNAVAILABLE_REDIS
+            }
+        };
+        match reply.rtype {
+            RespType::Error(_) => {}
+            _ => return false,
+        }
+        let data = reply.data().unwrap_or_default();
+        prefixes
+            .iter()
+            .any(|x| data.starts_with(x) && data.get(x.len()).map(|x| *x == b' ').unwrap_or(true))
+    }
+}
+
+/// the slot range of CLUSTER NODES, e.g.: 0-5460 or the single slot 5461.
+pub(crate) fn parse_slot_range(range: &str) -> Option<(usize, usize)> {
+    let mut bounds = range.splitn(2, '-');
+    let begin = bounds.next().and_then(|x| x.parse::<usize>().ok());
+    let end = bounds
+        .next()
+        .map(|x| x.parse::<usize>().ok())
+        .unwrap_or(begin);
+    match (begin, end) {
+        (Some(begin), Some(end)) if begin <= end && end < SLOTS_COUNT => Some((begin, end)),
+        _ => None,
+    }
+}
+
+// each line is "${id} ${ip:port@cport} ${flags} ${master} ${ping} ${pong} ${epoch} ${link}
+// ${slots}...", the slots are ranges like 0-5460 or single slot like 5461, and the migrating
+// ones in brackets are skipped.
+pub(crate) fn parse_cluster_nodes(data: &[u8]) -> Result<ReplicaLayout, AsError> {
+    let text = String::from_utf8_lossy(data);
+    let mut masters = BTreeMap::<usize, String>::new();
+    let mut replicas = HashMap::<String, HashSet<String>>::new();
+    let mut ids = HashMap::<String, String>::new();
+    let mut slots = Vec::new();
+
+    for line in text.lines().filter(|x| !x.trim().is_empty()) {
+        let bad = || AsError::WrongClusterNodesReply(line.to_string());
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            return Err(bad());
+        }
+        let addr = fields[1]
+            .split(|x| x == '@' || x == ',')
+            .next()
+            .filter(|x| !x.is_empty())
+            .ok_or_else(bad)?
+            .to_string();
+        let flags: Vec<_> = fields[2].split(',').collect();
+        if flags.contains(&"fail") || flags.contains(&"noaddr") {
+            continue;
+        }
+        if flags.contains(&"master") {
+            ids.insert(fields[0].to_string(), addr.clone());
+            for range in fields[8..].iter().filter(|x| !x.starts_with('[')) {
+                let (begin, end) = parse_slot_range(range).ok_or_else(bad)?;
+                slots.push((begin, end, addr.clone()));
+            }
+        } else if flags.contains(&"slave") || flags.contains(&"replica") {
+            replicas
+                .entry(fields[3].to_string())
+                .or_insert_with(HashSet::new)
+                .insert(addr);
+        }
+    }
+
+    let mut replicas_by_addr = HashMap::<String, HashSet<String>>::new();
+    for (id, set) in replicas {
+        if let Some(master) = ids.get(&id) {
+            replicas_by_addr.insert(master.clone(), set);
+        }
+    }
+    let mut replica_list = BTreeMap::<usize, Vec<String>>::new();
+    for (begin, end, master) in slots {
+        let mut set: Vec<_> = replicas_by_addr
+            .get(&master)
+            .map(|x| x.iter().cloned().collect())
+            .unwrap_or_default();
+        set.sort();
+        for i in begin..=end {
+            masters.insert(i, master.clone());
+            replica_list.insert(i, set.clone());
+        }
+    }
+    if masters.len() != SLOTS_COUNT {
+        warn!("slots is not full covered but ignore it");
+    }
+    Ok((
+        masters.into_iter().map(|(_, v)| v).collect(),
+        replica_list.into_iter().map(|(_, v)| v).collect(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proxy::cluster::Redirect;
+    use bytes::BytesMut;
+
+    const FLAVORS: &[BackendFlavor] = &[
+        BackendFlavor::Redis,
+        BackendFlavor::Dragonfly,
+        BackendFlavor::KeyDB,
+        BackendFlavor::Garnet,
+    ];
+
+    fn parse(data: &[u8]) -> Message {
+        Message::parse(&mut BytesMut::from(data)).unwrap().unwrap()
+    }
+
+    fn replied(cmd: Cmd, reply: &[u8]) -> Cmd {
+        cmd.set_reply(parse(reply));
+        cmd
+    }
+
+    #[test]
+    fn test_topology_of_cluster_slots() {
+        // recorded from redis 6, KeyDB and Garnet reply the same shape
+        let reply = b"*2\r\n*4\r\n:0\r\n:8191\r\n*3\r\n$9\r\n127.0.0.1\r\n:7000\r\n$40\r\n1111111111111111111111111111111111111111\r\n*3\r\n$9\r\n127.0.0.1\r\n:7003\r\n$40\r\n4444444444444444444444444444444444444444\r\n*3\r\n:8192\r\n:16383\r\n*3\r\n$9\r\n127.0.0.1\r\n:7001\r\n$40\r\n2222222222222222222222222222222222222222\r\n";
+        for flavor in &[
+            BackendFlavor::Redis,
+            BackendFlavor::KeyDB,
+            BackendFlavor::Garnet,
+        ] {
+            assert_eq!(flavor.topology(), Topology::ClusterSlots);
+            let cmd = flavor.topology_cmd();
+            assert_eq!(cmd.req().nth(1), Some(&b"SLOTS"[..]));
+            let (masters, replicas) = flavor
+                .topology_layout(replied(cmd, reply))
+                .unwrap()
+                .unwrap();
+            assert_eq!(masters.len(), SLOTS_COUNT);
+            assert_eq!(masters[8191], "127.0.0.1:7000");
+            assert_eq!(masters[8192], "127.0.0.1:7001");
+            assert_eq!(replicas[0], vec!["127.0.0.1:7003".to_string()]);
+            assert!(replicas[16383].is_empty());
+        }
+    }
+
+    #[test]
+    fn test_topology_of_cluster_nodes() {
+        // recorded from Dragonfly in emulated cluster mode
+        let emulated = b"$101\r\n2b6a2b5b0a3e1e1e0a9b4b1d5e6f7a8b9c0d1e2f 127.0.0.1:6379@6379 myself,master - 0 0 0 connected 0-16383\n\r\n";
+        let flavor = BackendFlavor::Dragonfly;
+        assert_eq!(flavor.topology(), Topology::ClusterNodes);
+        let cmd = flavor.topology_cmd();
+        assert_eq!(cmd.req().nth(1), Some(&b"NODES"[..]));
+        let (masters, replicas) = flavor
+            .topology_layout(replied(cmd, emulated))
+            .unwrap()
+            .unwrap();
+        assert_eq!(masters.len(), SLOTS_COUNT);
+        assert!(masters.iter().all(|x| x == "127.0.0.1:6379"));
+        assert!(replicas.iter().all(|x| x.is_empty()));
+
+        // replicas, single slot, migrating slot and failed node
+        let nodes = b"m1 10.0.0.1:7000@17000 master - 0 0 1 connected 0-8190 8191 [8191->-m2]\n\
+            m2 10.0.0.2:7000@17000,host2 myself,master - 0 0 2 connected 8192-16383\n\
+            r1 10.0.0.3:7000@17000 slave m1 0 0 1 connected\n\
+            r2 10.0.0.4:7000@17000 slave,fail m1 0 0 1 connected\n";
+        let (masters, replicas) = parse_cluster_nodes(nodes).unwrap();
+        assert_eq!(masters.len(), SLOTS_COUNT);
+        assert_eq!(masters[8191], "10.0.0.1:7000");
+        assert_eq!(masters[8192], "10.0.0.2:7000");
+        assert_eq!(replicas[0], vec!["10.0.0.3:7000".to_string()]);
+        assert!(replicas[8192].is_empty());
+
+        assert!(parse_cluster_nodes(b"m1 10.0.0.1:7000 master -\n").is_err());
+        assert!(
+            parse_cluster_nodes(b"m1 10.0.0.1:7000 master - 0 0 1 connected 0-16384\n").is_err()
+        );
+        let cmd = replied(flavor.topology_cmd(), b"-ERR unknown subcommand\r\n");
+        assert!(flavor.topology_layout(cmd).is_err());
+    }
+
+    #[test]
+    fn test_unavailable_by_flavor() {
+        let loading = parse(b"-LOADING Redis is loading the dataset in memory\r\n");
+        let masterdown = parse(b"-MASTERDOWN Link with MASTER is down\r\n");
+        let other = parse(b"-ERR LOADING is not a command\r\n");
+        for flavor in FLAVORS {
+            assert!(flavor.is_unavailable(&loading), "{:?}", flavor);
+            assert!(!flavor.is_unavailable(&other), "{:?}", flavor);
+            assert!(!flavor.is_unavailable(&parse(b"+PONG\r\n")), "{:?}", flavor);
+        }
+        assert!(BackendFlavor::Redis.is_unavailable(&masterdown));
+        assert!(BackendFlavor::KeyDB.is_unavailable(&masterdown));
+        assert!(!BackendFlavor::Dragonfly.is_unavailable(&masterdown));
+        let busy = parse(b"-BUSY Redis is busy running a script\r\n");
+        assert!(BackendFlavor::Redis.is_unavailable(&busy));
+        assert!(!BackendFlavor::Dragonfly.is_unavailable(&busy));
+    }
+
+    #[test]
+    fn test_redirect_of_all_flavors() {
+        // the same among flavors, only the leading MOVED or ASK is redirect
+        let moved = parse(b"-MOVED 3999 127.0.0.1:6381\r\n");
+        let ask = parse(b"-ASK 3999 127.0.0.1:6381\r\n");
+        assert_eq!(
+            moved.check_redirect(),
+            Some(Redirect::Move {
+                slot: 3999,
+                to: "127.0.0.1:6381".to_string()
+            })
+        );
+        assert!(ask.check_redirect().unwrap().is_ask());
+        assert_eq!(
+            parse(b"-ERR unknown command 'ASKING'\r\n").check_redirect(),
+            None
+        );
+        assert_eq!(parse(b"-ERR TASK 1 2 failed\r\n").check_redirect(), None);
+        assert_eq!(
+            parse(b"-MOVEDX 1 127.0.0.1:6381\r\n").check_redirect(),
+            None
+        );
+        assert_eq!(parse(b"$5\r\nMOVED\r\n").check_redirect(), None);
+    }
+}
@@ -0,0 +1,877 @@
+//! self diagnostics of the common misconfigurations, run by the admin api of a running cluster or
+//! by `--doctor` against the config file without serving:
+//!
+//! ```text
+//! cluster test-redis
+//! pass rlimit: nofile 65536, 52 reserved for backends and listeners
+//! warn listen_addr: 127.0.0.1:9001 is loopback, only the local clients can connect
+//! pass backend 127.0.0.1:7001: connected in 0.21ms, replied in 0.35ms
+//! warn ring_balance: 18012 to 31204 of 100000 keys per backend, redis-2 owns 31.2% of keys, expected 25.0% by weight
+//! warn inert_options: read_from_slave is ignored in proxy mode
+//! ```
+//!
+//! Each check is a function of the config (and of what it probes) giving pass, warn or fail with
+//! a short explanation, the report is failed if any check fails. The backends are probed by one
+//! blocking connection each, so the checks are never run by the worker threads.
+//!
+//! The topology is checked alone by `check_topology` before and after resharding: every node is
+//! pinged, and the slots of redis cluster must be covered by exactly one master each:
+//!
+//! ```text
+//! cluster test-redis-cluster
+//! fail slot_coverage: 100 slots are uncovered: 5461-5560
+//! pass slot_overlap: no slot is claimed by more than one master
+//! pass backend 127.0.0.1:7001: connected in 0.21ms, replied in 0.35ms
+//! fail node 127.0.0.1:7003: flagged master,fail by cluster
+//! ```
+use bytes::BytesMut;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::com::{AsError, CacheType, ClusterConfig, Config, ReplicaStrategy};
+use crate::protocol::redis::{Message, MessageMut, ReplicaLayout, SLOTS_COUNT};
+use crate::proxy::compat::{parse_cluster_nodes, parse_slot_range};
+use crate::proxy::probe::{is_healthy, MC_VERSION, REDIS_PING};
+use crate::proxy::standalone::ring_nodes;
+use crate::routing::Routing;
+
+const CHECK_TIMEOUT: u64 = 1000;
+// the replies slower than it are warned unless ping_slow_threshold is given
+const SLOW_RTT: u64 = 50;
+// fds of the log files, admin api and the connections of probes
+const RESERVED_FDS: u64 = 32;
+// the fds left for the clients below it are warned
+const MIN_CLIENT_FDS: u64 = 1024;
+const CORPUS_SIZE: usize = 100_000;
+// the share of keys of backend beyond the ratio of its share of weights is warned
+const MAX_SKEW: f64 = 0.2;
+const MAX_REPLY_SIZE: usize = 64 * 1024 * 1024;
+const REDIS_CLUSTER_NODES: &[u8] = b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nNODES\r\n";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Pass => write!(f, "pass"),
+            Status::Warn => write!(f, "warn"),
+            Status::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, status: Status, detail: String) -> Check {
+        Check {
+            name: name.to_string(),
+            status,
+            detail,
+        }
+    }
+
+    fn pass(name: &str, detail: String) -> Check {
+        Check::new(name, Status::Pass, detail)
+    }
+
+    fn warn(name: &str, detail: String) -> Check {
+        Check::new(name, Status::Warn, detail)
+    }
+
+    fn fail(name: &str, detail: String) -> Check {
+        Check::new(name, Status::Fail, detail)
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.name, self.detail)
+    }
+}
+
+/// the checks of cluster in order.
+pub struct Report {
+    pub cluster: String,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn is_failed(&self) -> bool {
+        self.checks.iter().any(|x| x.status == Status::Fail)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cluster {}", self.cluster)?;
+        for check in &self.checks {
+            writeln!(f, "{}", check)?;
+        }
+        Ok(())
+    }
+}
+
+/// run all the checks of cluster, including the probes of backends.
+pub fn diagnose(cc: &ClusterConfig) -> Report {
+    let mut checks = Vec::new();
+    checks.push(match nofile_limit() {
+        Some(nofile) => check_rlimit(cc, nofile),
+        None => Check::warn("rlimit", "fail to get RLIMIT_NOFILE".to_string()),
+    });
+    checks.extend(check_listen_addrs(cc));
+
+    let timeout = Duration::from_millis(CHECK_TIMEOUT);
+    let request = match cc.cache_type {
+        CacheType::Memcache | CacheType::MemcacheBinary => MC_VERSION,
+        _ => REDIS_PING,
+    };
+    let addrs = backend_addrs(cc);
+    match addrs.as_ref() {
+        Ok(addrs) => {
+            for addr in addrs {
+                checks.push(check_backend(cc, addr, request, timeout));
+            }
+        }
+        Err(err) => checks.push(Check::fail("backends", err.to_string())),
+    }
+
+    match cc.cache_type {
+        CacheType::RedisCluster if cc.read_from_slave.unwrap_or(false) => {
+            let layout = addrs
+                .unwrap_or_default()
+                .iter()
+                .find_map(|x| fetch_cluster_nodes(x, timeout).ok());
+            checks.push(match layout {
+                Some(layout) => check_replicas(&layout),
+                None => Check::warn(
+                    "replicas",
+                    "fail to fetch CLUSTER NODES from any seed".to_string(),
+                ),
+            });
+        }
+        CacheType::RedisCluster => {}
+        _ => checks.push(check_ring_balance(cc, CORPUS_SIZE)),
+    }
+    checks.push(check_inert_options(cc));
+    Report {
+        cluster: cc.name.clone(),
+        checks,
+    }
+}
+
+/// diagnose all the clusters of config, failed if any check fails.
+pub fn run(cfg: &Config) -> Result<Vec<Report>, AsError> {
+    let reports: Vec<_> = cfg.clusters.iter().map(diagnose).collect();
+    let failed: Vec<_> = reports
+        .iter()
+        .filter(|x| x.is_failed())
+        .map(|x| x.cluster.clone())
+        .collect();
+    for report in &reports {
+        println!("{}", report);
+    }
+    if !failed.is_empty() {
+        return Err(AsError::DoctorFail(failed.join(",")));
+    }
+    Ok(reports)
+}
+
+/// the soft limit of open files of the process.
+pub fn nofile_limit() -> Option<u64> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        return None;
+    }
+    Some(rlim.rlim_cur as u64)
+}
+
+/// the fds held by the cluster without any client: each worker thread has its own listeners
+/// and one connection of each backend (the seeds for redis cluster, whose nodes are more).
+fn reserved_fds(cc: &ClusterConfig) -> u64 {
+    let threads = cc.thread.unwrap_or(4) as u64;
+    let backends = (cc.servers.len() + cc.standby.len()) as u64;
+    let listeners = 1 + cc.listeners.len() as u64;
+    threads * (backends + listeners) + RESERVED_FDS
+}
+
+/// the limit of open files leaves room for the clients beyond the fds reserved by cluster.
+pub fn check_rlimit(cc: &ClusterConfig, nofile: u64) -> Check {
+    let reserved = reserved_fds(cc);
+    let detail = format!(
+        "nofile {}, {} reserved for backends and listeners",
+        nofile, reserved
+    );
+    if nofile <= reserved {
+        Check::fail("rlimit", format!("{}, no fd is left for clients", detail))
+    } else if nofile - reserved < MIN_CLIENT_FDS {
+        Check::warn(
+            "rlimit",
+            format!("{}, only {} left for clients", detail, nofile - reserved),
+        )
+    } else {
+        Check::pass("rlimit", detail)
+    }
+}
+
+/// the listen addresses are valid and reachable by the other hosts.
+pub fn check_listen_addrs(cc: &ClusterConfig) -> Vec<Check> {
+    let mut addrs = vec![("listen_addr".to_string(), cc.listen_addr.clone())];
+    for listener in &cc.listeners {
+        let name = format!("listener {}", listener.name);
+        addrs.push((name, listener.listen_addr.clone()));
+    }
+    addrs
+        .into_iter()
+        .map(|(name, addr)| match addr.parse::<SocketAddr>() {
+            Err(_) => Check::fail(&name, format!("{} is not an ip:port", addr)),
+            Ok(sa) if sa.ip().is_loopback() => Check::warn(
+                &name,
+                format!("{} is loopback, only the local clients can connect", addr),
+            ),
+            Ok(sa) if sa.ip().is_unspecified() => {
+                Check::pass(&name, format!("{} listens on all interfaces", addr))
+            }
+            Ok(_) => Check::pass(&name, format!("{} listens on the interface only", addr)),
+        })
+        .collect()
+}
+
+/// the addresses of backends and standby, the seeds for redis cluster.
+fn backend_addrs(cc: &ClusterConfig) -> Result<Vec<String>, AsError> {
+    if let CacheType::RedisCluster = cc.cache_type {
+        return Ok(cc.servers.clone());
+    }
+    let mut addrs: Vec<_> = ring_nodes(&cc.servers)?.into_iter().map(|x| x.1).collect();
+    addrs.extend(ring_nodes(&cc.standby)?.into_iter().map(|x| x.1));
+    Ok(addrs)
+}
+
+fn connect(addr: &str, timeout: Duration) -> Result<(TcpStream, SocketAddr), io::Error> {
+    let sa = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "resolved to nothing"))?;
+    let sock = TcpStream::connect_timeout(&sa, timeout)?;
+    sock.set_read_timeout(Some(timeout))?;
+    sock.set_write_timeout(Some(timeout))?;
+    sock.set_nodelay(true)?;
+    Ok((sock, sa))
+}
+
+fn millis(elapsed: Duration) -> f64 {
+    elapsed.as_micros() as f64 / 1000.0
+}
+
+/// the backend is resolved, connected and replies the ping (version for memcache) in time.
+pub fn check_backend(cc: &ClusterConfig, addr: &str, request: &[u8], timeout: Duration) -> Check {
+    let name = format!("backend {}", addr);
+    let now = Instant::now();
+    let (mut sock, sa) = match connect(addr, timeout) {
+        Ok(conn) => conn,
+        Err(err) => return Check::fail(&name, format!("fail to connect due to {}", err)),
+    };
+    let connected = now.elapsed();
+    let now = Instant::now();
+    let reply = match round_trip(&mut sock, request) {
+        Ok(reply) => reply,
+        Err(err) => return Check::fail(&name, format!("fail to ping due to {}", err)),
+    };
+    let replied = now.elapsed();
+
+    let mut detail = format!(
+        "connected in {:.2}ms, replied in {:.2}ms",
+        millis(connected),
+        millis(replied)
+    );
+    if sa.to_string() != addr {
+        detail = format!("resolved to {}, {}", sa, detail);
+    }
+    let slow = Duration::from_millis(cc.ping_slow_threshold.unwrap_or(SLOW_RTT));
+    if !is_healthy(&reply) {
+        let reply = String::from_utf8_lossy(&reply).trim_end().to_string();
+        Check::fail(&name, format!("{}, but unhealthy {}", detail, reply))
+    } else if replied > slow {
+        Check::warn(&name, format!("{}, slower than {:?}", detail, slow))
+    } else {
+        Check::pass(&name, detail)
+    }
+}
+
+// the reply of one line
+fn round_trip(sock: &mut TcpStream, request: &[u8]) -> Result<Vec<u8>, io::Error> {
+    sock.write_all(request)?;
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 128];
+    while !reply.ends_with(b"\r\n") {
+        if reply.len() > MAX_REPLY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "reply too large",
+            ));
+        }
+        let size = sock.read(&mut buf)?;
+        if size == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        reply.extend_from_slice(&buf[..size]);
+    }
+    Ok(reply)
+}
+
+fn fetch_cluster_nodes(addr: &str, timeout: Duration) -> Result<ReplicaLayout, AsError> {
+    parse_cluster_nodes(&fetch_cluster_nodes_data(addr, timeout)?)
+}
+
+fn fetch_cluster_nodes_data(addr: &str, timeout: Duration) -> Result<Vec<u8>, AsError> {
+    let (mut sock, _) = connect(addr, timeout)?;
+    sock.write_all(REDIS_CLUSTER_NODES)?;
+    let mut src = BytesMut::new();
+    let mut buf = [0u8; 4096];
+    let msg = loop {
+        if let Some(msg) = MessageMut::parse_reply(&mut src)? {
+            break Message::from(msg);
+        }
+        if src.len() > MAX_REPLY_SIZE {
+            return Err(AsError::ReplyTooLarge(MAX_REPLY_SIZE));
+        }
+        let size = sock.read(&mut buf)?;
+        if size == 0 {
+            return Err(AsError::BackendClosedError(addr.to_string()));
+        }
+        src.extend_from_slice(&buf[..size]);
+    };
+    msg.data()
+        .map(|x| x.to_vec())
+        .ok_or_else(|| AsError::WrongClusterNodesReply(format!("{:?}", msg.rtype)))
+}
+
+/// the nodes of CLUSTER NODES as (address, flags) and the slot ranges of the masters not failed.
+pub type Topology = (Vec<(String, String)>, Vec<(usize, usize, String)>);
+
+pub fn parse_topology(data: &[u8]) -> Result<Topology, AsError> {
+    let text = String::from_utf8_lossy(data);
+    let mut nodes = Vec::new();
+    let mut ranges = Vec::new();
+    for line in text.lines().filter(|x| !x.trim().is_empty()) {
+        let bad = || AsError::WrongClusterNodesReply(line.to_string());
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            return Err(bad());
+        }
+        let flags: Vec<_> = fields[2].split(',').collect();
+        if flags.contains(&"noaddr") {
+            continue;
+        }
+        let addr = fields[1]
+            .split(|x| x == '@' || x == ',')
+            .next()
+            .filter(|x| !x.is_empty())
+            .ok_or_else(bad)?
+            .to_string();
+        nodes.push((addr.clone(), fields[2].to_string()));
+        if !flags.contains(&"master") || flags.contains(&"fail") {
+            continue;
+        }
+        for range in fields[8..].iter().filter(|x| !x.starts_with('[')) {
+            let (begin, end) = parse_slot_range(range).ok_or_else(bad)?;
+            ranges.push((begin, end, addr.clone()));
+        }
+    }
+    Ok((nodes, ranges))
+}
+
+fn format_runs(runs: &[(usize, usize)]) -> String {
+    runs.iter()
+        .map(|&(begin, end)| {
+            if begin == end {
+                begin.to_string()
+            } else {
+                format!("{}-{}", begin, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// every slot is covered by exactly one master of the ranges (begin, end, master).
+pub fn check_slots(ranges: &[(usize, usize, String)]) -> Vec<Check> {
+    let mut owners = vec![Vec::new(); SLOTS_COUNT];
+    for (begin, end, master) in ranges {
+        for slot in owners.iter_mut().take(*end + 1).skip(*begin) {
+            if !slot.contains(master) {
+                slot.push(master.clone());
+            }
+        }
+    }
+    // the consecutive slots of the same owners
+    let mut runs: Vec<(usize, usize, &Vec<String>)> = Vec::new();
+    for (slot, masters) in owners.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run) if run.2 == masters => run.1 = slot,
+            _ => runs.push((slot, slot, masters)),
+        }
+    }
+
+    let gaps: Vec<_> = runs
+        .iter()
+        .filter(|x| x.2.is_empty())
+        .map(|x| (x.0, x.1))
+        .collect();
+    let uncovered: usize = gaps.iter().map(|x| x.1 - x.0 + 1).sum();
+    let coverage = if gaps.is_empty() {
+        let masters = ranges.iter().map(|x| &x.2).collect::<HashSet<_>>().len();
+        Check::pass(
+            "slot_coverage",
+            format!(
+                "all {} slots are covered by {} masters",
+                SLOTS_COUNT, masters
+            ),
+        )
+    } else {
+        Check::fail(
+            "slot_coverage",
+            format!("{} slots are uncovered: {}", uncovered, format_runs(&gaps)),
+        )
+    };
+
+    let overlaps: Vec<_> = runs
+        .iter()
+        .filter(|x| x.2.len() > 1)
+        .map(|x| format!("{} by {}", format_runs(&[(x.0, x.1)]), x.2.join(",")))
+        .collect();
+    let overlap = if overlaps.is_empty() {
+        Check::pass(
+            "slot_overlap",
+            "no slot is claimed by more than one master".to_string(),
+        )
+    } else {
+        Check::fail(
+            "slot_overlap",
+            format!("slots are claimed twice: {}", overlaps.join("; ")),
+        )
+    };
+    vec![coverage, overlap]
+}
+
+/// ping every node of the cluster, and check the slots covered by the masters of redis cluster.
+pub fn check_topology(cc: &ClusterConfig) -> Report {
+    let mut checks = Vec::new();
+    let timeout = Duration::from_millis(CHECK_TIMEOUT);
+    let request = match cc.cache_type {
+        CacheType::Memcache | CacheType::MemcacheBinary => MC_VERSION,
+        _ => REDIS_PING,
+    };
+    let addrs = match backend_addrs(cc) {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            checks.push(Check::fail("backends", err.to_string()));
+            Vec::new()
+        }
+    };
+
+    if let CacheType::RedisCluster = cc.cache_type {
+        let topology = addrs.iter().find_map(|x| {
+            fetch_cluster_nodes_data(x, timeout)
+                .and_then(|data| parse_topology(&data))
+                .ok()
+        });
+        match topology {
+            Some((nodes, ranges)) => {
+                checks.extend(check_slots(&ranges));
+                for (addr, flags) in nodes {
+                    if flags.split(',').any(|x| x == "fail" || x == "fail?") {
+                        let detail = format!("flagged {} by cluster", flags);
+                        checks.push(Check::fail(&format!("node {}", addr), detail));
+                    } else {
+                        checks.push(check_backend(cc, &addr, request, timeout));
+                    }
+                }
+            }
+            None => checks.push(Check::fail(
+                "topology",
+                "fail to fetch CLUSTER NODES from any seed".to_string(),
+            )),
+        }
+    } else {
+        for addr in &addrs {
+            checks.push(check_backend(cc, addr, request, timeout));
+        }
+    }
+    Report {
+        cluster: cc.name.clone(),
+        checks,
+    }
+}
+
+/// read_from_slave is effective only for the slots with replicas.
+pub fn check_replicas(layout: &ReplicaLayout) -> Check {
+    let (masters, replicas) = layout;
+    let alone = replicas.iter().filter(|x| x.is_empty()).count();
+    if alone == masters.len() {
+        Check::warn(
+            "replicas",
+            "read_from_slave is inert, there is no replica".to_string(),
+        )
+    } else if alone > 0 {
+        Check::warn(
+            "replicas",
+            format!("{} slots have no replica and are read from master", alone),
+        )
+    } else {
+        let count = replicas.iter().flatten().collect::<HashSet<_>>().len();
+        Check::pass(
+            "replicas",
+            format!("all slots are read from {} replicas", count),
+        )
+    }
+}
+
+/// the keys of a synthetic corpus are spread among backends by their weights.
+pub fn check_ring_balance(cc: &ClusterConfig, corpus: usize) -> Check {
+    let nodes = match ring_nodes(&cc.servers) {
+        Ok(nodes) => nodes,
+        Err(err) => return Check::fail("ring_balance", err.to_string()),
+    };
+    let ring = match Routing::from_config(cc) {
+        Ok(routing) => routing,
+        Err(err) => return Check::fail("ring_balance", err.to_string()),
+    };
+    let ring = ring.ring().expect("ring of proxy mode");
+    let mut counts: Vec<_> = nodes.iter().map(|x| (x.0.clone(), x.2, 0)).collect();
+    for i in 0..corpus {
+        let key = format!("aster-doctor:{}", i);
+        if let Some(node) = ring.lookup(key.as_bytes()) {
+            if let Some(count) = counts.iter_mut().find(|x| x.0 == node) {
+                count.2 += 1;
+            }
+        }
+    }
+    ring_balance(&counts, corpus)
+}
+
+/// the balance of keys counted by (node, weight, keys).
+pub fn ring_balance(counts: &[(String, usize, usize)], corpus: usize) -> Check {
+    let total_weight: usize = counts.iter().map(|x| x.1).sum();
+    if counts.is_empty() || total_weight == 0 || corpus == 0 {
+        return Check::fail("ring_balance", "there is no backend in ring".to_string());
+    }
+    let min = counts.iter().map(|x| x.2).min().unwrap_or(0);
+    let max = counts.iter().map(|x| x.2).max().unwrap_or(0);
+    let detail = format!("{} to {} of {} keys per backend", min, max, corpus);
+    if let Some(empty) = counts.iter().find(|x| x.2 == 0) {
+        return Check::fail(
+            "ring_balance",
+            format!("{}, {} owns no key", detail, empty.0),
+        );
+    }
+    let skew = |x: &(String, usize, usize)| {
+        let expected = x.1 as f64 / total_weight as f64;
+        let share = x.2 as f64 / corpus as f64;
+        (share, expected, (share - expected).abs() / expected)
+    };
+    let worst = counts
+        .iter()
+        .max_by(|x, y| skew(x).2.partial_cmp(&skew(y).2).unwrap())
+        .expect("counts is not empty");
+    let (share, expected, ratio) = skew(worst);
+    if ratio > MAX_SKEW {
+        Check::warn(
+            "ring_balance",
+            format!(
+                "{}, {} owns {:.1}% of keys, expected {:.1}% by weight",
+                detail,
+                worst.0,
+                share * 100.0,
+                expected * 100.0
+            ),
+        )
+    } else {
+        Check::pass("ring_balance", detail)
+    }
+}
+
+/// the options set but never taking effect with the others.
+pub fn check_inert_options(cc: &ClusterConfig) -> Check {
+    let is_cluster = matches!(cc.cache_type, CacheType::RedisCluster);
+    let read_from_slave = cc.read_from_slave.unwrap_or(false);
+    let mut inert = Vec::new();
+    if !is_cluster && cc.read_from_slave.is_some() {
+        inert.push("read_from_slave is ignored in proxy mode");
+    }
+    if is_cluster && !read_from_slave && cc.replica_strategy.is_some() {
+        inert.push("replica_strategy is ignored without read_from_slave");
+    }
+    let weighted = matches!(
+        cc.replica_strategy,
+        Some(ReplicaStrategy::WeightedRandom) | Some(ReplicaStrategy::LatencyWeighted)
+    );
+    if !cc.replica_weights.is_empty() && !weighted {
+        inert.push(
+            "replica_weights is ignored unless replica_strategy is weighted_random or latency_weighted",
+        );
+    }
+    if !is_cluster && cc.fetch_interval.is_some() {
+        inert.push("fetch_interval is ignored in proxy mode");
+    }
+    let standby_options = cc.standby_fail_ratio.is_some()
+        || cc.standby_fail_grace.is_some()
+        || cc.standby_recover_after.is_some();
+    if cc.standby.is_empty() && standby_options {
+        inert.push("standby_* options are ignored without standby");
+    }
+    if cc.max_args.is_none() && cc.max_args_log.unwrap_or(false) {
+        inert.push("max_args_log is ignored without max_args");
+    }
+    if inert.is_empty() {
+        Check::pass("inert_options", "every option set takes effect".to_string())
+    } else {
+        Check::warn("inert_options", inert.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::com::ListenerConfig;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn config(cache_type: CacheType, servers: &[&str]) -> ClusterConfig {
+        ClusterConfig {
+            name: "test-doctor".to_string(),
+            listen_addr: "0.0.0.0:9001".to_string(),
+            cache_type,
+            thread: Some(4),
+            servers: servers.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_rlimit() {
+        let cc = config(
+            CacheType::Redis,
+            &["127.0.0.1:7001:10", "127.0.0.1:7002:10"],
+        );
+        // 4 threads of 2 backends and 1 listener
+        assert_eq!(reserved_fds(&cc), 4 * 3 + RESERVED_FDS);
+        assert_eq!(check_rlimit(&cc, 65536).status, Status::Pass);
+        assert_eq!(check_rlimit(&cc, 1024).status, Status::Warn);
+        assert_eq!(check_rlimit(&cc, 16).status, Status::Fail);
+    }
+
+    #[test]
+    fn test_check_listen_addrs() {
+        let mut cc = config(CacheType::Memcache, &[]);
+        cc.listeners.push(ListenerConfig {
+            name: "local".to_string(),
+            listen_addr: "127.0.0.1:9011".to_string(),
+            ..Default::default()
+        });
+        cc.listeners.push(ListenerConfig {
+            name: "bad".to_string(),
+            listen_addr: "localhost".to_string(),
+            ..Default::default()
+        });
+        let checks = check_listen_addrs(&cc);
+        let status: Vec<_> = checks.iter().map(|x| x.status).collect();
+        assert_eq!(status, vec![Status::Pass, Status::Warn, Status::Fail]);
+        assert_eq!(checks[1].name, "listener local");
+    }
+
+    #[test]
+    fn test_check_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for reply in &[&b"+PONG\r\n"[..], &b"-LOADING dataset in memory\r\n"[..]] {
+                let (mut sock, _) = listener.accept().unwrap();
+                let mut buf = [0u8; 64];
+                let _ = sock.read(&mut buf).unwrap();
+                sock.write_all(reply).unwrap();
+            }
+        });
+        let cc = config(CacheType::Redis, &[]);
+        let timeout = Duration::from_millis(CHECK_TIMEOUT);
+        let check = check_backend(&cc, &addr, REDIS_PING, timeout);
+        assert_eq!(check.status, Status::Pass, "{}", check);
+        let check = check_backend(&cc, &addr, REDIS_PING, timeout);
+        assert_eq!(check.status, Status::Fail, "{}", check);
+        assert!(check
+            .detail
+            .ends_with("unhealthy -LOADING dataset in memory"));
+
+        // nothing is listening any more
+        let check = check_backend(&cc, "127.0.0.1:1", REDIS_PING, timeout);
+        assert_eq!(check.status, Status::Fail, "{}", check);
+    }
+
+    #[test]
+    fn test_check_replicas() {
+        let masters = vec!["10.0.0.1:7000".to_string(); 2];
+        let layout = (masters.clone(), vec![vec![], vec![]]);
+        assert_eq!(check_replicas(&layout).status, Status::Warn);
+        let layout = (
+            masters.clone(),
+            vec![vec!["10.0.0.2:7000".to_string()], vec![]],
+        );
+        assert_eq!(check_replicas(&layout).status, Status::Warn);
+        let replica = vec!["10.0.0.2:7000".to_string()];
+        let layout = (masters, vec![replica.clone(), replica]);
+        assert_eq!(check_replicas(&layout).status, Status::Pass);
+    }
+
+    #[test]
+    fn test_check_ring_balance() {
+        let cc = config(
+            CacheType::Memcache,
+            &[
+                "127.0.0.1:7001:10",
+                "127.0.0.1:7002:10",
+                "127.0.0.1:7003:20",
+            ],
+        );
+        let check = check_ring_balance(&cc, 10_000);
+        assert_eq!(check.status, Status::Pass, "{}", check);
+
+        let counts = |keys: &[usize]| -> Vec<(String, usize, usize)> {
+            keys.iter()
+                .enumerate()
+                .map(|(i, x)| (format!("redis-{}", i), 10, *x))
+                .collect()
+        };
+        assert_eq!(ring_balance(&counts(&[55, 45]), 100).status, Status::Pass);
+        let check = ring_balance(&counts(&[50, 25, 25]), 100);
+        assert_eq!(check.status, Status::Warn);
+        assert!(check
+            .detail
+            .contains("redis-0 owns 50.0% of keys, expected 33.3%"));
+        assert_eq!(ring_balance(&counts(&[100, 0]), 100).status, Status::Fail);
+        assert_eq!(ring_balance(&[], 100).status, Status::Fail);
+    }
+
+    #[test]
+    fn test_check_inert_options() {
+        let mut cc = config(CacheType::Redis, &["127.0.0.1:7001:10"]);
+        assert_eq!(check_inert_options(&cc).status, Status::Pass);
+        cc.read_from_slave = Some(true);
+        cc.standby_fail_ratio = Some(0.5);
+        let check = check_inert_options(&cc);
+        assert_eq!(check.status, Status::Warn);
+        assert_eq!(
+            check.detail,
+            "read_from_slave is ignored in proxy mode; standby_* options are ignored without standby"
+        );
+
+        let mut cc = config(CacheType::RedisCluster, &["127.0.0.1:7001"]);
+        cc.read_from_slave = Some(true);
+        assert_eq!(check_inert_options(&cc).status, Status::Pass);
+        cc.replica_weights.insert("127.0.0.1:7002".to_string(), 2);
+        assert_eq!(check_inert_options(&cc).status, Status::Warn);
+    }
+
+    #[test]
+    fn test_check_slots() {
+        let range = |begin, end, master: &str| (begin, end, master.to_string());
+        let full = vec![
+            range(0, 5460, "10.0.0.1:7000"),
+            range(5461, 10922, "10.0.0.2:7000"),
+            range(10923, 16383, "10.0.0.3:7000"),
+        ];
+        let checks = check_slots(&full);
+        assert!(checks.iter().all(|x| x.status == Status::Pass));
+        assert_eq!(checks[0].detail, "all 16384 slots are covered by 3 masters");
+
+        // the slots 5461-5560 and 16383 are lost during resharding
+        let incomplete = vec![
+            range(0, 5460, "10.0.0.1:7000"),
+            range(5561, 10922, "10.0.0.2:7000"),
+            range(10923, 16382, "10.0.0.3:7000"),
+        ];
+        let checks = check_slots(&incomplete);
+        assert_eq!(checks[0].status, Status::Fail);
+        assert_eq!(checks[0].detail, "101 slots are uncovered: 5461-5560,16383");
+        assert_eq!(checks[1].status, Status::Pass);
+
+        let overlapped = vec![
+            range(0, 5460, "10.0.0.1:7000"),
+            range(5000, 16383, "10.0.0.2:7000"),
+        ];
+        let checks = check_slots(&overlapped);
+        assert_eq!(checks[0].status, Status::Pass);
+        assert_eq!(checks[1].status, Status::Fail);
+        assert_eq!(
+            checks[1].detail,
+            "slots are claimed twice: 5000-5460 by 10.0.0.1:7000,10.0.0.2:7000"
+        );
+    }
+
+    #[test]
+    fn test_parse_topology() {
+        let data = b"\
+a1 10.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-5460
+a2 10.0.0.2:7000@17000 master,fail - 0 0 2 connected 5461-10922
+a3 10.0.0.3:7000@17000 master - 0 0 3 connected 10923-16383 [16383->-a1]
+b1 10.0.0.4:7000@17000 slave a1 0 0 1 connected
+c1 :0@0 master,noaddr - 0 0 4 disconnected
+";
+        let (nodes, ranges) = parse_topology(data).unwrap();
+        let addrs: Vec<_> = nodes.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                "10.0.0.1:7000",
+                "10.0.0.2:7000",
+                "10.0.0.3:7000",
+                "10.0.0.4:7000"
+            ]
+        );
+        assert_eq!(nodes[1].1, "master,fail");
+        // the slots of the failed master are uncovered
+        assert_eq!(
+            ranges,
+            vec![
+                (0, 5460, "10.0.0.1:7000".to_string()),
+                (10923, 16383, "10.0.0.3:7000".to_string()),
+            ]
+        );
+        let checks = check_slots(&ranges);
+        assert_eq!(checks[0].detail, "5462 slots are uncovered: 5461-10922");
+        assert!(parse_topology(b"a1 10.0.0.1:7000 master").is_err());
+        assert!(parse_topology(b"a1 10.0.0.1:7000 master - 0 0 1 connected 16384").is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let report = Report {
+            cluster: "test-doctor".to_string(),
+            checks: vec![
+                Check::pass("rlimit", "nofile 65536".to_string()),
+                Check::warn("inert_options", "fetch_interval".to_string()),
+            ],
+        };
+        assert!(!report.is_failed());
+        assert_eq!(
+            report.to_string(),
+            "cluster test-doctor\npass rlimit: nofile 65536\nwarn inert_options: fetch_interval\n"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! fault injection of each cluster for resilience testing, shared by all the worker threads.
+//! It's off by default and only enabled by admin api, every injected fault is counted by
+//! aster_fault_injected.
+use rand::{thread_rng, Rng};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::com::{AsError, ClusterConfig};
+use crate::metrics::fault_injected_incr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    Latency(Duration),
+    Error,
+}
+
+/// the requests which fault is injected into, all requests if absent.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Scope {
+    // read or write
+    pub class: Option<String>,
+    pub prefix: Option<String>,
+}
+
+impl Scope {
+    fn check(&self) -> Result<(), AsError> {
+        match self.class.as_ref().map(|x| x.as_str()) {
+            None | Some("read") | Some("write") => Ok(()),
+            Some(class) => Err(AsError::BadFault(format!(
+                "class {} must be read or write",
+                class
+            ))),
+        }
+    }
+
+    fn contains<F: Fn(&[u8]) -> bool>(&self, is_read: bool, has_prefix: &F) -> bool {
+        let class_ok = match self.class.as_ref().map(|x| x.as_str()) {
+            Some("read") => is_read,
+            Some("write") => !is_read,
+            _ => true,
+        };
+        class_ok
+            && self
+                .prefix
+                .as_ref()
+                .map(|x| has_prefix(x.as_bytes()))
+                .unwrap_or(true)
+    }
+}
+
+/// latency of millis plus a uniform random jitter on percent of requests.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LatencyFault {
+    pub percent: f64,
+    pub millis: u64,
+    #[serde(default)]
+    pub jitter: u64,
+    #[serde(flatten)]
+    pub scope: Scope,
+}
+
+/// error replied on percent of requests without touching backend.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ErrorFault {
+    pub percent: f64,
+    #[serde(flatten)]
+    pub scope: Scope,
+}
+
+/// all the dispatches to the node (named by alias or address) are failed for duration seconds.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DownFault {
+    pub node: String,
+    pub duration: u64,
+}
+
+#[derive(Default)]
+struct Faults {
+    latency: Option<LatencyFault>,
+    error: Option<ErrorFault>,
+    down: HashMap<String, Instant>,
+}
+
+impl Faults {
+    fn is_empty(&self) -> bool {
+        self.latency.is_none() && self.error.is_none() && self.down.is_empty()
+    }
+}
+
+struct Shared {
+    enabled: AtomicBool,
+    faults: Mutex<Faults>,
+}
+
+lazy_static! {
+    static ref FAULTS: Mutex<HashMap<String, Arc<Shared>>> = Mutex::new(HashMap::new());
+}
+
+/// the fault injection handle of the cluster held by each worker thread.
+pub struct Injector {
+    cluster: String,
+    shared: Arc<Shared>,
+}
+
+pub fn handle(cc: &ClusterConfig) -> Injector {
+    let mut faults = FAULTS.lock().unwrap();
+    let shared = faults
+        .entry(cc.name.clone())
+        .or_insert_with(|| {
+            Arc::new(Shared {
+                enabled: AtomicBool::new(false),
+                faults: Mutex::new(Faults::default()),
+            })
+        })
+        .clone();
+    Injector {
+        cluster: cc.name.clone(),
+        shared,
+    }
+}
+
+fn percent_hit(percent: f64) -> bool {
+    percent > 0.0 && thread_rng().gen::<f64>() * 100.0 < percent
+}
+
+impl Injector {
+    /// decide the fault of request, which only costs an atomic load if nothing is injected.
+    pub fn inject<F: Fn(&[u8]) -> bool>(&self, is_read: bool, has_prefix: F) -> Option<Fault> {
+        if !self.shared.enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let faults = self.shared.faults.lock().unwrap();
+        let hit = |scope: &Scope, percent: f64| {
+            scope.contains(is_read, &has_prefix) && percent_hit(percent)
+        };
+        let fault = if faults.error.as_ref().map(|x| hit(&x.scope, x.percent)) == Some(true) {
+            Fault::Error
+        } else if let Some(latency) = faults.latency.as_ref().filter(|x| hit(&x.scope, x.percent)) {
+            let jitter = if latency.jitter > 0 {
+                thread_rng().gen_range(0, latency.jitter + 1)
+            } else {
+                0
+            };
+            Fault::Latency(Duration::from_millis(latency.millis + jitter))
+        } else {
+            return None;
+        };
+        let name = match fault {
+            Fault::Error => "error",
+            Fault::Latency(_) => "latency",
+        };
+        fault_injected_incr(&self.cluster, name);
+        Some(fault)
+    }
+
+    /// check the node is simulated as down, matched checks the given node name is the node.
+    pub fn is_down<F: Fn(&str) -> bool>(&self, matched: F) -> bool {
+        if !self.shared.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+        let faults = self.shared.faults.lock().unwrap();
+        let now = Instant::now();
+        let down = faults
+            .down
+            .iter()
+            .any(|(name, until)| *until > now && matched(name));
+        if down {
+            fault_injected_incr(&self.cluster, "down");
+        }
+        down
+    }
+}
+
+fn update<F: FnOnce(&mut Faults)>(cluster: &str, f: F) -> bool {
+    let faults = FAULTS.lock().unwrap();
+    let shared = match faults.get(cluster) {
+        Some(shared) => shared,
+        None => return false,
+    };
+    let mut faults = shared.faults.lock().unwrap();
+    f(&mut faults);
+    let now = Instant::now();
+    faults.down.retain(|_, until| *until > now);
+    shared.enabled.store(!faults.is_empty(), Ordering::SeqCst);
+    true
+}
+
+fn check_percent(percent: f64) -> Result<(), AsError> {
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(AsError::BadFault("percent must be in 0..=100".to_string()));
+    }
+    Ok(())
+}
+
+/// inject latency into the cluster, return false if the cluster is not running.
+pub fn set_latency(cluster: &str, fault: LatencyFault) -> Result<bool, AsError> {
+    check_percent(fault.percent)?;
+    fault.scope.check()?;
+    Ok(update(cluster, |faults| faults.latency = Some(fault)))
+}
+
+/// inject errors into the cluster, return false if the cluster is not running.
+pub fn set_error(cluster: &str, fault: ErrorFault) -> Result<bool, AsError> {
+    check_percent(fault.percent)?;
+    fault.scope.check()?;
+    Ok(update(cluster, |faults| faults.error = Some(fault)))
+}
+
+/// simulate the node of cluster is down, return false if the cluster is not running.
+pub fn set_down(cluster: &str, fault: DownFault) -> Result<bool, AsError> {
+    if fault.node.is_empty() || fault.duration == 0 {
+        return Err(AsError::BadFault(
+            "node and duration must be given".to_string(),
+        ));
+    }
+    let until = Instant::now() + Duration::from_secs(fault.duration);
+    Ok(update(cluster, |faults| {
+        faults.down.insert(fault.node, until);
+    }))
+}
+
+/// clear all the faults of cluster, return false if the cluster is not running.
+pub fn clear(cluster: &str) -> bool {
+    update(cluster, |faults| *faults = Faults::default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::fault_injected_get;
+
+    #[test]
+    fn test_fault_inject_scope() {
+        let cc = ClusterConfig {
+            name: "test-fault".to_string(),
+            ..Default::default()
+        };
+        let error = ErrorFault {
+            percent: 100.0,
+            scope: Scope {
+                class: Some("read".to_string()),
+                prefix: Some("user:".to_string()),
+            },
+        };
+        assert!(!set_error(&cc.name, error.clone()).unwrap());
+
+        let injector = handle(&cc);
+        assert_eq!(injector.inject(true, |_| true), None);
+        assert!(set_error(&cc.name, error).unwrap());
+        assert_eq!(injector.inject(true, |x| x == b"user:"), Some(Fault::Error));
+        assert_eq!(injector.inject(false, |_| true), None);
+        assert_eq!(injector.inject(true, |_| false), None);
+        assert_eq!(fault_injected_get(&cc.name, "error"), 1);
+
+        let latency = LatencyFault {
+            percent: 100.0,
+            millis: 10,
+            jitter: 5,
+            scope: Scope::default(),
+        };
+        assert!(set_latency(&cc.name, latency).unwrap());
+        // error is prior to latency, and latency is injected out of the scope of error
+        assert_eq!(injector.inject(true, |_| true), Some(Fault::Error));
+        match injector.inject(false, |_| true) {
+            Some(Fault::Latency(delay)) => {
+                assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(15))
+            }
+            other => panic!("unexpected fault {:?}", other),
+        }
+
+        let down = DownFault {
+            node: "redis-1".to_string(),
+            duration: 60,
+        };
+        assert!(set_down(&cc.name, down).unwrap());
+        assert!(injector.is_down(|x| x == "redis-1"));
+        assert!(!injector.is_down(|x| x == "redis-2"));
+        assert_eq!(fault_injected_get(&cc.name, "down"), 1);
+
+        assert!(clear(&cc.name));
+        assert_eq!(injector.inject(true, |_| true), None);
+        assert!(!injector.is_down(|x| x == "redis-1"));
+        assert!(set_error(
+            &cc.name,
+            ErrorFault {
+                percent: 101.0,
+                scope: Scope::default(),
+            }
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! request and response hooks of cluster registered by embedders, which rewrite or filter
+//! the commands in flight without patching the proxy.
+//!
+//! hooks are invoked after the command is parsed and before it's routed. The multi-key
+//! command (e.g.: MGET a b) is fanned out to subs first, so hooks see each sub exactly once
+//! instead of the whole command. The first hook which doesn't continue wins.
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::com::{AsError, ClusterConfig};
+use crate::protocol::redis::resp::Message;
+use crate::protocol::redis::Cmd;
+use crate::proxy::standalone::Request;
+
+pub enum Action<R> {
+    // go on with the next hook, and dispatch to backend at last
+    Continue,
+    // reply to client by proxy and never dispatch to backend
+    ReplyNow(R),
+    // reply the error to client, e.g.: AsError::Rejected
+    Reject(AsError),
+}
+
+/// hooks are shared by all the worker threads of the cluster. T is bounded by methods to keep
+/// the holders of hooks (e.g.: Cluster<T>) free of bounds.
+pub trait Hook<T>: Send + Sync {
+    fn on_request(&self, _cmd: &mut T) -> Action<T::Reply>
+    where
+        T: Request,
+    {
+        Action::Continue
+    }
+
+    /// called once with the reply before it's sent to client.
+    fn on_response(&self, _cmd: &T, _reply: &mut T::Reply)
+    where
+        T: Request,
+    {
+    }
+}
+
+lazy_static! {
+    // only redis hooks can be registered now
+    static ref HOOKS: Mutex<HashMap<String, Vec<Arc<dyn Hook<Cmd>>>>> =
+        Mutex::new(HashMap::new());
+}
+
+pub(crate) fn register(cluster: &str, hooks: Vec<Arc<dyn Hook<Cmd>>>) {
+    let mut all = HOOKS.lock().unwrap();
+    if hooks.is_empty() {
+        all.remove(cluster);
+    } else {
+        all.insert(cluster.to_string(), hooks);
+    }
+}
+
+/// the hooks of the cluster held by each worker thread.
+pub struct Hooks<T> {
+    hooks: Vec<Arc<dyn Hook<T>>>,
+}
+
+pub fn handle<T: Request + 'static>(cc: &ClusterConfig) -> Hooks<T> {
+    let hooks = HOOKS
+        .lock()
+        .unwrap()
+        .get(&cc.name)
+        .cloned()
+        .unwrap_or_default();
+    // empty for the cluster of other protocol (e.g.: memcache)
+    let hooks: Box<dyn Any> = Box::new(hooks);
+    match hooks.downcast::<Vec<Arc<dyn Hook<T>>>>() {
+        Ok(hooks) => Hooks { hooks: *hooks },
+        Err(_) => Hooks { hooks: Vec::new() },
+    }
+}
+
+impl<T: Request> Hooks<T> {
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn on_request(&self, cmd: &mut T) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        match cmd.subs() {
+            Some(subs) => {
+                for mut sub in subs {
+                    self.request(&mut sub);
+                }
+            }
+            None => self.request(cmd),
+        }
+    }
+
+    fn request(&self, cmd: &mut T) {
+        for hook in &self.hooks {
+            match hook.on_request(cmd) {
+                Action::Continue => {}
+                Action::ReplyNow(reply) => return cmd.set_reply(reply),
+                Action::Reject(err) => return cmd.set_error(&err),
+            }
+        }
+    }
+
+    pub fn on_response(&self, cmd: &T) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        match cmd.subs() {
+            Some(subs) => {
+                for sub in subs {
+                    self.response(&sub);
+                }
+            }
+            None => self.response(cmd),
+        }
+    }
+
+    fn response(&self, cmd: &T) {
+        if let Some(mut reply) = cmd.reply() {
+            for hook in &self.hooks {
+                hook.on_response(cmd, &mut reply);
+            }
+            cmd.set_reply(reply);
+        }
+    }
+}
+
+/// example hook which enforces the max TTL of SET: the larger TTL of SET EX/PX/EXAT/PXAT,
+/// SETEX and PSETEX is cut down to the max, and SET without TTL expires in max seconds.
+pub struct MaxTtl {
+    seconds: u64,
+}
+
+impl MaxTtl {
+    pub fn new(seconds: u64) -> MaxTtl {
+        MaxTtl { seconds }
+    }
+
+    // the position and the max of the TTL argument, None if it's absent
+    fn ttl_arg(&self, req: &Message) -> Option<(usize, u64)> {
+        let name = req.nth(0)?;
+        if name == b"SETEX" {
+            return Some((2, self.seconds));
+        }
+        if name == b"PSETEX" {
+            return Some((2, self.seconds * 1000));
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        for (i, arg) in req.iter().enumerate().skip(3) {
+            let arg = arg.to_ascii_uppercase();
+            let max = match &arg[..] {
+                b"EX" => self.seconds,
+                b"PX" => self.seconds * 1000,
+                b"EXAT" => now.as_secs() + self.seconds,
+                b"PXAT" => now.as_millis() as u64 + self.seconds * 1000,
+                _ => continue,
+            };
+            return Some((i + 1, max));
+        }
+        None
+    }
+}
+
+impl Hook<Cmd> for MaxTtl {
+    fn on_request(&self, cmd: &mut Cmd) -> Action<Message> {
+        let (is_set, ttl) = {
+            let req = cmd.req();
+            let is_set = req.nth(0) == Some(&b"SET"[..]);
+            let ttl = self.ttl_arg(&req).map(|(pos, max)| {
+                let value = req.nth(pos).and_then(|x| btoi::btoi::<u64>(x).ok());
+                (pos, max, value)
+            });
+            let keep_ttl = req
+                .iter()
+                .skip(3)
+                .any(|x| x.eq_ignore_ascii_case(b"KEEPTTL"));
+            (is_set && !keep_ttl, ttl)
+        };
+        match ttl {
+            // the bad TTL is replied by backend
+            Some((pos, max, Some(value))) if value > max => {
+                cmd.rewrite(|req| {
+                    req.set_nth(pos, max.to_string().as_bytes());
+                });
+            }
+            Some(_) => {}
+            None if is_set => {
+                let seconds = self.seconds.to_string();
+                cmd.rewrite(|req| {
+                    req.push_arg(b"EX");
+                    req.push_arg(seconds.as_bytes());
+                });
+            }
+            None => {}
+        }
+        Action::Continue
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::redis::resp::RESP_STRING;
+    use crate::protocol::redis::Command;
+    use bytes::BytesMut;
+
+    fn parse(args: &[&str]) -> Cmd {
+        let mut src = BytesMut::new();
+        Message::from_args(args).save(&mut src);
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    }
+
+    fn args(cmd: &Cmd) -> Vec<String> {
+        cmd.req()
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).to_string())
+            .collect()
+    }
+
+    fn hooks(hooks: Vec<Arc<dyn Hook<Cmd>>>) -> Hooks<Cmd> {
+        Hooks { hooks }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        requests: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl Hook<Cmd> for Recorder {
+        fn on_request(&self, cmd: &mut Cmd) -> Action<Message> {
+            self.requests.lock().unwrap().push(args(cmd));
+            match cmd.req().nth(1) {
+                Some(b"cached") => Action::ReplyNow(Message::from_args(&["hit"])),
+                Some(b"denied") => Action::Reject(AsError::Rejected("denied".to_string())),
+                _ => Action::Continue,
+            }
+        }
+
+        fn on_response(&self, _cmd: &Cmd, reply: &mut Message) {
+            if reply.data() == Some(&b"secret"[..]) {
+                *reply = Message::plain("******", RESP_STRING);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hook_fanned_out_subs() {
+        let recorder = Arc::new(Recorder::default());
+        let hook: Arc<dyn Hook<Cmd>> = recorder.clone();
+        let hooks = hooks(vec![hook]);
+
+        let mut cmd = parse(&["MGET", "a", "cached", "denied", "b"]);
+        hooks.on_request(&mut cmd);
+        assert_eq!(
+            recorder.requests.lock().unwrap().split_off(0),
+            vec![
+                vec!["MGET", "a"],
+                vec!["MGET", "cached"],
+                vec!["MGET", "denied"],
+                vec!["MGET", "b"],
+            ]
+        );
+
+        // the subs done by hooks are never dispatched, even in waves
+        let mut released = Vec::new();
+        while let Some(wave) = cmd.borrow_mut().next_wave(1) {
+            for sub in wave {
+                released.push(args(&sub));
+                sub.set_reply(Message::from_args(&["value"]));
+            }
+        }
+        assert_eq!(released, vec![vec!["MGET", "a"], vec!["MGET", "b"]]);
+        assert!(cmd.is_done());
+        let subs = cmd.subs().unwrap();
+        assert_eq!(subs[1].reply(), Some(Message::from_args(&["hit"])));
+        assert!(subs[2].is_error());
+        assert_eq!(subs[2].reply().unwrap().data(), Some(&b"ERR denied"[..]));
+
+        let mut cmd = parse(&["MSET", "a", "1", "b", "2"]);
+        hooks.on_request(&mut cmd);
+        assert_eq!(
+            recorder.requests.lock().unwrap().split_off(0),
+            vec![vec!["MSET", "a", "1"], vec!["MSET", "b", "2"]]
+        );
+
+        // the single key command is seen as it is
+        let mut cmd = parse(&["GET", "a"]);
+        hooks.on_request(&mut cmd);
+        assert_eq!(
+            recorder.requests.lock().unwrap().split_off(0),
+            vec![vec!["GET", "a"]]
+        );
+        assert!(!cmd.is_done());
+    }
+
+    #[test]
+    fn test_hook_on_response() {
+        let hook: Arc<dyn Hook<Cmd>> = Arc::new(Recorder::default());
+        let hooks = hooks(vec![hook]);
+        let cmd = parse(&["MGET", "a", "b"]);
+        let subs = cmd.subs().unwrap();
+        subs[0].set_reply(Message::plain("secret", RESP_STRING));
+        subs[1].set_reply(Message::plain("public", RESP_STRING));
+        hooks.on_response(&cmd);
+        assert_eq!(subs[0].reply().unwrap().data(), Some(&b"******"[..]));
+        assert_eq!(subs[1].reply().unwrap().data(), Some(&b"public"[..]));
+    }
+
+    #[test]
+    fn test_hook_max_ttl() {
+        let max_ttl: Arc<dyn Hook<Cmd>> = Arc::new(MaxTtl::new(60));
+        let hooks = hooks(vec![max_ttl]);
+        let rewrite = |req: &[&str]| {
+            let mut cmd = parse(req);
+            hooks.on_request(&mut cmd);
+            assert!(!cmd.is_done());
+            args(&cmd)
+        };
+
+        assert_eq!(
+            rewrite(&["SET", "a", "1"]),
+            vec!["SET", "a", "1", "EX", "60"]
+        );
+        assert_eq!(
+            rewrite(&["SET", "a", "1", "ex", "3600"]),
+            vec!["SET", "a", "1", "ex", "60"]
+        );
+        assert_eq!(
+            rewrite(&["SET", "a", "1", "NX", "PX", "120000"]),
+            vec!["SET", "a", "1", "NX", "PX", "60000"]
+        );
+        assert_eq!(
+            rewrite(&["SET", "a", "1", "EX", "10"]),
+            vec!["SET", "a", "1", "EX", "10"]
+        );
+        assert_eq!(
+            rewrite(&["SET", "a", "1", "KEEPTTL"]),
+            vec!["SET", "a", "1", "KEEPTTL"]
+        );
+        assert_eq!(
+            rewrite(&["SET", "a", "1", "EX", "bad"]),
+            vec!["SET", "a", "1", "EX", "bad"]
+        );
+        assert_eq!(
+            rewrite(&["SETEX", "a", "3600", "1"]),
+            vec!["SETEX", "a", "60", "1"]
+        );
+        assert_eq!(
+            rewrite(&["PSETEX", "a", "1000", "1"]),
+            vec!["PSETEX", "a", "1000", "1"]
+        );
+        assert_eq!(rewrite(&["GET", "a"]), vec!["GET", "a"]);
+
+        // the key named as option is never taken as option
+        assert_eq!(
+            rewrite(&["SET", "EX", "1"]),
+            vec!["SET", "EX", "1", "EX", "60"]
+        );
+        let exat = rewrite(&["SET", "a", "1", "EXAT", "99999999999"]);
+        let max = btoi::btoi::<u64>(exat[4].as_bytes()).unwrap();
+        assert!(max < 99999999999);
+    }
+}
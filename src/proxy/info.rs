@@ -0,0 +1,230 @@
+//! INFO is answered by proxy rather than forwarded to one of the backends, whose stats are only
+//! of that single node. The reply is made of the sections given by info_sections (proxy, clients
+//! and stats by default) in the same format as redis, e.g.:
+//!
+//! ```text
+//! # Proxy
+//! aster_version:1.3.1
+//! cluster:test-redis
+//! cache_type:redis
+//! uptime_in_seconds:86400
+//! uptime_in_days:1
+//!
+//! # Clients
+//! connected_clients:3
+//! total_connections_received:42
+//! ```
+//!
+//! The backends section lists the backends seen by the worker: the nodes with their states in
+//! proxy mode and the masters in cluster mode, which is never a fan-out of INFO to them. INFO
+//! with sections (e.g.: INFO clients stats) replies the ones known regardless of info_sections.
+use std::time::{Duration, Instant};
+
+use crate::com::{AsError, CacheType, ClusterConfig};
+use crate::metrics::{cluster_stats, ClusterStats};
+
+const SECTION_PROXY: &str = "proxy";
+const SECTION_CLIENTS: &str = "clients";
+const SECTION_STATS: &str = "stats";
+const SECTION_BACKENDS: &str = "backends";
+const SECTIONS: &[&str] = &[
+    SECTION_PROXY,
+    SECTION_CLIENTS,
+    SECTION_STATS,
+    SECTION_BACKENDS,
+];
+const DEFAULT_SECTIONS: &[&str] = &[SECTION_PROXY, SECTION_CLIENTS, SECTION_STATS];
+// the arguments of INFO taken as the sections of info_sections
+const ARGS_DEFAULT: &[&str] = &["default", "all", "everything"];
+
+lazy_static! {
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// the uptime of INFO is counted from the first cluster spawned.
+pub fn mark_started() {
+    lazy_static::initialize(&STARTED);
+}
+
+/// the sections of info_sections are checked by Config::valid already.
+pub fn check_sections(sections: &[String]) -> Result<(), AsError> {
+    match sections.iter().find(|x| !SECTIONS.contains(&x.as_str())) {
+        Some(section) => Err(AsError::BadConfig(format!(
+            "info_sections: {} must be one of {}",
+            section,
+            SECTIONS.join(", ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+// the sections replied in order, the configured ones unless given by INFO.
+fn sections(cc: &ClusterConfig, args: &[String]) -> Vec<String> {
+    let given: Vec<_> = args.iter().map(|x| x.to_lowercase()).collect();
+    if !given.is_empty() && given.iter().all(|x| !ARGS_DEFAULT.contains(&x.as_str())) {
+        return SECTIONS
+            .iter()
+            .filter(|x| given.iter().any(|y| y == *x))
+            .map(|x| x.to_string())
+            .collect();
+    }
+    if cc.info_sections.is_empty() {
+        return DEFAULT_SECTIONS.iter().map(|x| x.to_string()).collect();
+    }
+    cc.info_sections.clone()
+}
+
+// the same as the config
+fn cache_type_name(cache_type: CacheType) -> &'static str {
+    match cache_type {
+        CacheType::Redis => "redis",
+        CacheType::Memcache => "memcache",
+        CacheType::MemcacheBinary => "memcache_binary",
+        CacheType::RedisCluster => "redis_cluster",
+    }
+}
+
+fn render(
+    cc: &ClusterConfig,
+    sections: &[String],
+    stats: ClusterStats,
+    uptime: Duration,
+    backends: &[String],
+) -> String {
+    let mut blocks = Vec::with_capacity(sections.len());
+    for section in sections {
+        let (title, fields) = match section.as_str() {
+            SECTION_PROXY => (
+                "Proxy",
+                vec![
+                    format!("aster_version:{}", crate::ASTER_VERSION),
+                    format!("cluster:{}", cc.name),
+                    format!("cache_type:{}", cache_type_name(cc.cache_type)),
+                    format!("uptime_in_seconds:{}", uptime.as_secs()),
+                    format!("uptime_in_days:{}", uptime.as_secs() / 86400),
+                ],
+            ),
+            SECTION_CLIENTS => (
+                "Clients",
+                vec![
+                    format!("connected_clients:{}", stats.connections),
+                    format!("total_connections_received:{}", stats.accepted),
+                ],
+            ),
+            SECTION_STATS => (
+                "Stats",
+                vec![
+                    format!("total_commands_processed:{}", stats.requests),
+                    format!("total_commands_forwarded:{}", stats.remote_requests),
+                ],
+            ),
+            SECTION_BACKENDS => {
+                let mut fields = vec![format!("backends:{}", backends.len())];
+                fields.extend(
+                    backends
+                        .iter()
+                        .enumerate()
+                        .map(|(i, x)| format!("backend{}:{}", i, x)),
+                );
+                ("Backends", fields)
+            }
+            _ => continue,
+        };
+        blocks.push(format!("# {}\r\n{}\r\n", title, fields.join("\r\n")));
+    }
+    blocks.join("\r\n")
+}
+
+/// the reply of INFO with its arguments, backends are the fields of each backend, e.g.:
+/// "addr=127.0.0.1:7000,state=active".
+pub fn reply(cc: &ClusterConfig, args: &[String], backends: &[String]) -> String {
+    render(
+        cc,
+        &sections(cc, args),
+        cluster_stats(&cc.name),
+        STARTED.elapsed(),
+        backends,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_info_sections() {
+        assert!(check_sections(&args("proxy backends")).is_ok());
+        assert!(check_sections(&args("proxy memory")).is_err());
+
+        let mut cc = ClusterConfig::default();
+        assert_eq!(sections(&cc, &[]), args("proxy clients stats"));
+        cc.info_sections = args("proxy backends");
+        assert_eq!(sections(&cc, &[]), args("proxy backends"));
+        assert_eq!(sections(&cc, &args("ALL")), args("proxy backends"));
+        // given by INFO in the order of redis, and the unknown ones are ignored
+        assert_eq!(
+            sections(&cc, &args("stats memory Clients")),
+            args("clients stats")
+        );
+        assert!(sections(&cc, &args("memory")).is_empty());
+    }
+
+    #[test]
+    fn test_info_reply() {
+        let cc = ClusterConfig {
+            name: "test-info".to_string(),
+            info_sections: args("proxy clients stats backends"),
+            ..Default::default()
+        };
+        let stats = ClusterStats {
+            connections: 3,
+            accepted: 42,
+            requests: 100,
+            remote_requests: 90,
+        };
+        let backends = vec!["name=redis-1,addr=127.0.0.1:7000,state=active".to_string()];
+        let info = render(
+            &cc,
+            &sections(&cc, &[]),
+            stats,
+            Duration::from_secs(90000),
+            &backends,
+        );
+        assert!(info.starts_with("# Proxy\r\n"));
+        assert!(info.ends_with("\r\n"));
+
+        let lines: Vec<_> = info.split("\r\n").collect();
+        for line in &lines {
+            // titles, blank lines between the sections, and key:value
+            let field = line.is_empty()
+                || line.starts_with("# ")
+                || line.splitn(2, ':').filter(|x| !x.is_empty()).count() == 2;
+            assert!(field, "malformed line {}", line);
+        }
+        for field in &[
+            "cluster:test-info",
+            "cache_type:redis_cluster",
+            "uptime_in_seconds:90000",
+            "uptime_in_days:1",
+            "connected_clients:3",
+            "total_connections_received:42",
+            "total_commands_processed:100",
+            "total_commands_forwarded:90",
+            "backends:1",
+            "backend0:name=redis-1,addr=127.0.0.1:7000,state=active",
+        ] {
+            assert!(lines.contains(field), "{} is absent", field);
+        }
+        assert!(lines.contains(&"# Backends"));
+
+        let clients = render(&cc, &args("clients"), stats, Duration::default(), &[]);
+        assert_eq!(
+            clients,
+            "# Clients\r\nconnected_clients:3\r\ntotal_connections_received:42\r\n"
+        );
+    }
+}
@@ -0,0 +1,617 @@
+//! compressed links between two aster, e.g.: the edge proxy and the central one across
+//! datacenters whose traffic is dominated by the bytes of values.
+//!
+//! The proxy of upstream_link offers `ASTER-LINK <compression>\r\n` on each backend connection
+//! and holds the requests until the answer. The aster accepting it answers
+//! `+ASTER-LINK <compression>\r\n`, then both sides wrap the bytes of protocol into frames of
+//! `[kind: u8][length: u32 be][payload]`, the kind is raw or deflate. A real redis or memcached
+//! answers the offer by one line of error, which falls the link back to plain protocol, so the
+//! same config works against them. The clients and the final backends never know the links.
+use crate::com::{ClusterConfig, LinkCompression};
+use crate::metrics::{link_handshake_incr, link_saved_add};
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use futures::{try_ready, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::io::{self, ErrorKind, Read, Write};
+
+const MAGIC: &[u8] = b"ASTER-LINK ";
+const ACK_PREFIX: &[u8] = b"+";
+const BYTES_CRLF: &[u8] = b"\r\n";
+const BYTES_UNSUPPORTED: &[u8] = b"-ERR unsupported link compression\r\n";
+// the offer or answer longer than it is never a handshake
+const MAX_HANDSHAKE_LINE: usize = 256;
+
+const FRAME_HEADER_LEN: usize = 5;
+const FRAME_RAW: u8 = 0;
+const FRAME_DEFLATE: u8 = 1;
+// max plain bytes of one frame, the larger writes are split. The payload is never larger than
+// the plain bytes since the frame is sent raw if deflate doesn't save any.
+const MAX_FRAME_SIZE: usize = 256 * 1024;
+
+const DEFAULT_LEVEL: u32 = 6;
+const DEFAULT_MIN_FRAME: usize = 512;
+
+const READ_CHUNK: usize = 16 * 1024;
+
+const DIRECTION_SENT: &str = "sent";
+const DIRECTION_RECEIVED: &str = "received";
+
+impl LinkCompression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LinkCompression::Deflate => "deflate",
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<LinkCompression> {
+        match name {
+            b"deflate" => Some(LinkCompression::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// the compression of the links offered or accepted by the cluster.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkOptions {
+    pub compression: LinkCompression,
+    pub level: u32,
+    pub min_frame: usize,
+}
+
+impl LinkOptions {
+    pub fn from_config(cc: &ClusterConfig) -> LinkOptions {
+        LinkOptions {
+            compression: cc.link_compression.unwrap_or_default(),
+            level: cc.link_compression_level.unwrap_or(DEFAULT_LEVEL),
+            min_frame: cc.link_min_frame.unwrap_or(DEFAULT_MIN_FRAME),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    // accepted, the first bytes tell a handshake or the first request of plain protocol
+    Detect,
+    // offered, the requests are held until the answer
+    Offer,
+    Plain,
+    Compressed,
+}
+
+/// the socket of a link, which is plain unless the handshake is answered.
+pub struct Link<S> {
+    sock: S,
+    mode: Mode,
+    opts: LinkOptions,
+    cluster: String,
+    // the backend of the offered links, or the address of the aster accepted from
+    peer: String,
+
+    // bytes read but not decoded, the handshake line or the partial frame
+    rbuf: BytesMut,
+    // plain bytes decoded but not read by the codec
+    plain: BytesMut,
+    // plain bytes written by the codec, framed once flushed
+    wbuf: BytesMut,
+    // bytes pending to the socket
+    out: BytesMut,
+}
+
+impl<S> Link<S>
+where
+    S: Read + Write,
+{
+    fn new(sock: S, mode: Mode, opts: LinkOptions, cluster: &str, peer: &str) -> Link<S> {
+        Link {
+            sock,
+            mode,
+            opts,
+            cluster: cluster.to_string(),
+            peer: peer.to_string(),
+            rbuf: BytesMut::new(),
+            plain: BytesMut::new(),
+            wbuf: BytesMut::new(),
+            out: BytesMut::new(),
+        }
+    }
+
+    /// the plain socket without any handshake.
+    pub fn plain(sock: S) -> Link<S> {
+        let opts = LinkOptions {
+            compression: LinkCompression::default(),
+            level: DEFAULT_LEVEL,
+            min_frame: DEFAULT_MIN_FRAME,
+        };
+        Link::new(sock, Mode::Plain, opts, "", "")
+    }
+
+    /// the socket accepted by the cluster, which answers the handshake if the peer offers.
+    pub fn accept(sock: S, opts: LinkOptions, cluster: &str, peer: &str) -> Link<S> {
+        Link::new(sock, Mode::Detect, opts, cluster, peer)
+    }
+
+    /// the socket connected to the backend, which offers the handshake before the requests.
+    pub fn offer(sock: S, opts: LinkOptions, cluster: &str, peer: &str) -> Link<S> {
+        let mut link = Link::new(sock, Mode::Offer, opts, cluster, peer);
+        link.out
+            .extend_from_slice(&handshake_line(b"", opts.compression));
+        link
+    }
+
+    /// the link is compressed by the handshake answered.
+    pub fn is_compressed(&self) -> bool {
+        self.mode == Mode::Compressed
+    }
+
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; READ_CHUNK];
+        let size = self.sock.read(&mut chunk)?;
+        self.rbuf.extend_from_slice(&chunk[..size]);
+        Ok(size)
+    }
+
+    fn flush_out(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            let size = self.sock.write(&self.out)?;
+            if size == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            self.out.advance(size);
+        }
+        Ok(())
+    }
+
+    fn switch(&mut self, mode: Mode) {
+        self.mode = mode;
+        let result = if mode == Mode::Compressed {
+            "compressed"
+        } else {
+            "plain"
+        };
+        if !self.cluster.is_empty() {
+            link_handshake_incr(&self.cluster, &self.peer, result);
+        }
+        if mode == Mode::Plain {
+            let rest = self.rbuf.take();
+            self.plain.extend_from_slice(&rest);
+        }
+    }
+
+    // consume the bytes read by the mode, the partial ones are kept until more are read.
+    fn decode(&mut self) -> io::Result<()> {
+        match self.mode {
+            Mode::Detect => self.detect()?,
+            Mode::Offer => self.answered()?,
+            _ => {}
+        }
+        match self.mode {
+            Mode::Compressed => self.unframe(),
+            Mode::Plain => {
+                let rest = self.rbuf.take();
+                self.plain.extend_from_slice(&rest);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn detect(&mut self) -> io::Result<()> {
+        let size = self.rbuf.len().min(MAGIC.len());
+        if self.rbuf[..size] != MAGIC[..size] {
+            self.switch(Mode::Plain);
+            return Ok(());
+        }
+        let line = match find_line(&self.rbuf) {
+            Some(line) => line,
+            None if self.rbuf.len() > MAX_HANDSHAKE_LINE => {
+                self.switch(Mode::Plain);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        let line = self.rbuf.split_to(line);
+        let name = &line[MAGIC.len()..line.len() - BYTES_CRLF.len()];
+        match LinkCompression::from_name(name) {
+            Some(compression) if compression == self.opts.compression => {
+                self.out
+                    .extend_from_slice(&handshake_line(ACK_PREFIX, compression));
+                self.switch(Mode::Compressed);
+            }
+            _ => {
+                warn!(
+                    "cluster {} refuse link of {} due to unsupported compression {}",
+                    self.cluster,
+                    self.peer,
+                    String::from_utf8_lossy(name)
+                );
+                self.out.extend_from_slice(BYTES_UNSUPPORTED);
+                self.switch(Mode::Plain);
+            }
+        }
+        // the answer is written at once, since the peer sends nothing before it
+        match self.flush_out() {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            rslt => rslt,
+        }
+    }
+
+    fn answered(&mut self) -> io::Result<()> {
+        let line = match find_line(&self.rbuf) {
+            Some(line) => line,
+            None if self.rbuf.len() > MAX_HANDSHAKE_LINE => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "answer of link handshake is too long",
+                ));
+            }
+            None => return Ok(()),
+        };
+        let line = self.rbuf.split_to(line);
+        if line[..] == handshake_line(ACK_PREFIX, self.opts.compression)[..] {
+            self.switch(Mode::Compressed);
+        } else {
+            info!(
+                "cluster {} link to {} falls back to plain protocol, answered by {:?}",
+                self.cluster,
+                self.peer,
+                String::from_utf8_lossy(&line).trim_end()
+            );
+            self.switch(Mode::Plain);
+        }
+        // the requests held are sent at once, the rest are sent by the next flush
+        self.seal();
+        match self.flush_out() {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            rslt => rslt,
+        }
+    }
+
+    // the offer is answered, Err(WouldBlock) until then.
+    fn poll_answer(&mut self) -> io::Result<()> {
+        self.flush_out()?;
+        while self.mode == Mode::Offer {
+            if self.fill()? == 0 {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "link closed before handshake answered",
+                ));
+            }
+            self.decode()?;
+        }
+        Ok(())
+    }
+
+    fn unframe(&mut self) -> io::Result<()> {
+        let mut saved = 0;
+        while self.rbuf.len() >= FRAME_HEADER_LEN {
+            let kind = self.rbuf[0];
+            let size = BigEndian::read_u32(&self.rbuf[1..FRAME_HEADER_LEN]) as usize;
+            if size > MAX_FRAME_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "link frame too large",
+                ));
+            }
+            if self.rbuf.len() < FRAME_HEADER_LEN + size {
+                break;
+            }
+            self.rbuf.advance(FRAME_HEADER_LEN);
+            let payload = self.rbuf.split_to(size);
+            match kind {
+                FRAME_RAW => self.plain.extend_from_slice(&payload),
+                FRAME_DEFLATE => {
+                    let data = inflate(&payload)?;
+                    saved += data.len().saturating_sub(payload.len());
+                    self.plain.extend_from_slice(&data);
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "unknown kind of link frame",
+                    ));
+                }
+            }
+        }
+        if saved > 0 {
+            link_saved_add(&self.cluster, &self.peer, DIRECTION_RECEIVED, saved);
+        }
+        Ok(())
+    }
+
+    // move the plain bytes written into the frames pending to the socket.
+    fn seal(&mut self) {
+        let mut saved = 0;
+        while !self.wbuf.is_empty() {
+            let size = self.wbuf.len().min(MAX_FRAME_SIZE);
+            let chunk = self.wbuf.split_to(size);
+            if self.mode != Mode::Compressed {
+                self.out.extend_from_slice(&chunk);
+                continue;
+            }
+            let packed = if chunk.len() >= self.opts.min_frame {
+                deflate(&chunk, self.opts.level).filter(|x| x.len() < chunk.len())
+            } else {
+                None
+            };
+            let (kind, payload) = match packed.as_ref() {
+                Some(packed) => (FRAME_DEFLATE, &packed[..]),
+                None => (FRAME_RAW, &chunk[..]),
+            };
+            saved += chunk.len() - payload.len();
+            self.out.reserve(FRAME_HEADER_LEN + payload.len());
+            self.out.put_u8(kind);
+            self.out.put_u32_be(payload.len() as u32);
+            self.out.extend_from_slice(payload);
+        }
+        if saved > 0 {
+            link_saved_add(&self.cluster, &self.peer, DIRECTION_SENT, saved);
+        }
+    }
+}
+
+impl<S> Read for Link<S>
+where
+    S: Read + Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.plain.is_empty() {
+                let size = buf.len().min(self.plain.len());
+                buf[..size].copy_from_slice(&self.plain[..size]);
+                self.plain.advance(size);
+                return Ok(size);
+            }
+            if self.mode == Mode::Plain {
+                return self.sock.read(buf);
+            }
+            if self.fill()? == 0 {
+                return Ok(0);
+            }
+            self.decode()?;
+        }
+    }
+}
+
+impl<S> Write for Link<S>
+where
+    S: Read + Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.mode == Mode::Plain && self.wbuf.is_empty() && self.out.is_empty() {
+            return self.sock.write(buf);
+        }
+        if self.wbuf.len() >= MAX_FRAME_SIZE {
+            self.flush()?;
+        }
+        let size = buf
+            .len()
+            .min(MAX_FRAME_SIZE.saturating_sub(self.wbuf.len()));
+        self.wbuf.extend_from_slice(&buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.mode == Mode::Offer {
+            self.poll_answer()?;
+        }
+        self.seal();
+        self.flush_out()?;
+        self.sock.flush()
+    }
+}
+
+impl<S> AsyncRead for Link<S> where S: AsyncRead + AsyncWrite {}
+
+impl<S> AsyncWrite for Link<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_flush());
+        self.sock.shutdown()
+    }
+}
+
+// e.g.: "ASTER-LINK deflate\r\n" of the offer, "+ASTER-LINK deflate\r\n" of the answer.
+fn handshake_line(prefix: &[u8], compression: LinkCompression) -> Vec<u8> {
+    let mut line = prefix.to_vec();
+    line.extend_from_slice(MAGIC);
+    line.extend_from_slice(compression.as_str().as_bytes());
+    line.extend_from_slice(BYTES_CRLF);
+    line
+}
+
+// the length of the first line ended by "\r\n".
+fn find_line(data: &[u8]) -> Option<usize> {
+    data.windows(BYTES_CRLF.len())
+        .position(|x| x == BYTES_CRLF)
+        .map(|pos| pos + BYTES_CRLF.len())
+}
+
+fn deflate(data: &[u8], level: u32) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len()), Compression::new(level));
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut plain = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_FRAME_SIZE as u64 + 1)
+        .read_to_end(&mut plain)?;
+    if plain.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "link frame too large",
+        ));
+    }
+    Ok(plain)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{link_handshake_get, link_saved_get};
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    // one end of an in-memory socket pair, reading nothing is WouldBlock
+    struct Pipe {
+        rx: Rc<RefCell<VecDeque<u8>>>,
+        tx: Rc<RefCell<VecDeque<u8>>>,
+    }
+
+    fn pair() -> (Pipe, Pipe) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (
+            Pipe {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            Pipe { rx: b, tx: a },
+        )
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut rx = self.rx.borrow_mut();
+            if rx.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let size = buf.len().min(rx.len());
+            for (i, byte) in rx.drain(..size).enumerate() {
+                buf[i] = byte;
+            }
+            Ok(size)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.borrow_mut().extend(buf.iter());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn opts() -> LinkOptions {
+        LinkOptions {
+            compression: LinkCompression::Deflate,
+            level: 6,
+            min_frame: 64,
+        }
+    }
+
+    fn read_all<S: Read + Write>(link: &mut Link<S>) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            match link.read(&mut buf) {
+                Ok(0) => return data,
+                Ok(size) => data.extend_from_slice(&buf[..size]),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return data,
+                Err(err) => panic!("read link error {}", err),
+            }
+        }
+    }
+
+    fn is_blocked(rslt: io::Result<()>) -> bool {
+        match rslt {
+            Err(err) => err.kind() == ErrorKind::WouldBlock,
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_link_compressed() {
+        let (edge, central) = pair();
+        let wire = edge.tx.clone();
+        let mut edge = Link::offer(edge, opts(), "test-link", "central");
+        let mut central = Link::accept(central, opts(), "test-link", "edge");
+
+        // the requests are held until the offer is answered
+        let value = "v".repeat(4096);
+        let req = format!("*3\r\n$3\r\nSET\r\n$1\r\na\r\n$4096\r\n{}\r\n", value);
+        edge.write_all(req.as_bytes()).unwrap();
+        assert!(is_blocked(edge.flush()));
+        assert_eq!(
+            wire.borrow().iter().cloned().collect::<Vec<_>>(),
+            b"ASTER-LINK deflate\r\n".to_vec()
+        );
+        assert!(read_all(&mut central).is_empty());
+        assert!(central.is_compressed());
+        edge.flush().unwrap();
+        assert!(edge.is_compressed());
+        // and compressed on the wire
+        assert!(wire.borrow().len() < req.len() / 4);
+        assert_eq!(read_all(&mut central), req.as_bytes());
+
+        // the small one is framed raw
+        central.write_all(b"+OK\r\n").unwrap();
+        central.flush().unwrap();
+        assert_eq!(read_all(&mut edge), b"+OK\r\n");
+
+        assert_eq!(link_handshake_get("test-link", "central", "compressed"), 1);
+        assert_eq!(link_handshake_get("test-link", "edge", "compressed"), 1);
+        let saved = link_saved_get("test-link", "central", "sent");
+        assert!(saved > req.len() / 2, "{}", saved);
+        assert_eq!(link_saved_get("test-link", "edge", "received"), saved);
+    }
+
+    #[test]
+    fn test_link_fallback_plain() {
+        // a real redis answers the offer by an error
+        let (edge, mut redis) = pair();
+        let mut edge = Link::offer(edge, opts(), "test-link-plain", "redis");
+        edge.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        assert!(is_blocked(edge.flush()));
+        let mut offer = [0u8; 64];
+        let size = redis.read(&mut offer).unwrap();
+        assert_eq!(&offer[..size], b"ASTER-LINK deflate\r\n");
+        redis
+            .write_all(b"-ERR unknown command 'ASTER-LINK'\r\n")
+            .unwrap();
+        edge.flush().unwrap();
+        assert!(!edge.is_compressed());
+        let size = redis.read(&mut offer).unwrap();
+        assert_eq!(&offer[..size], b"*1\r\n$4\r\nPING\r\n");
+        redis.write_all(b"+PONG\r\n").unwrap();
+        assert_eq!(read_all(&mut edge), b"+PONG\r\n");
+
+        // and the plain clients are never taken as offers
+        let (mut client, central) = pair();
+        let mut central = Link::accept(central, opts(), "test-link-plain", "client");
+        client.write_all(b"ASTER").unwrap();
+        assert!(read_all(&mut central).is_empty());
+        client.write_all(b"ISK\r\n").unwrap();
+        assert_eq!(read_all(&mut central), b"ASTERISK\r\n");
+        assert!(!central.is_compressed());
+    }
+
+    #[test]
+    fn test_link_bad_frame() {
+        let (mut edge, central) = pair();
+        let mut central = Link::accept(central, opts(), "test-link-bad", "edge");
+        edge.write_all(b"ASTER-LINK deflate\r\n").unwrap();
+        assert!(read_all(&mut central).is_empty());
+        let mut frame = vec![FRAME_DEFLATE];
+        frame.extend_from_slice(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        edge.write_all(&frame).unwrap();
+        let mut buf = [0u8; 16];
+        let err = central.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,52 @@
+//! maintenance mode of each cluster, shared by all the worker threads. New commands are rejected
+//! in maintenance while the ones in flight are finished and the connections are kept, which is
+//! unlike draining for shutdown.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    static ref MAINTENANCE: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+}
+
+/// get the maintenance mode handle of the cluster, which is off at first.
+pub fn handle(cluster: &str) -> Arc<AtomicBool> {
+    let mut modes = MAINTENANCE.lock().unwrap();
+    modes
+        .entry(cluster.to_string())
+        .or_insert_with(|| Arc::new(AtomicBool::new(false)))
+        .clone()
+}
+
+/// flip the maintenance mode of the cluster, return false if the cluster is not running.
+pub fn set_maintenance(cluster: &str, enable: bool) -> bool {
+    let modes = MAINTENANCE.lock().unwrap();
+    if let Some(mode) = modes.get(cluster) {
+        mode.store(enable, Ordering::SeqCst);
+        return true;
+    }
+    false
+}
+
+pub fn is_maintenance(mode: &AtomicBool) -> bool {
+    mode.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_maintenance_flip() {
+        let name = "test-maintenance-flip";
+        assert!(!set_maintenance(name, true));
+
+        let mode = handle(name);
+        assert!(!is_maintenance(&mode));
+        assert!(set_maintenance(name, true));
+        assert!(is_maintenance(&mode));
+        assert!(is_maintenance(&handle(name)));
+        assert!(set_maintenance(name, false));
+        assert!(!is_maintenance(&mode));
+    }
+}
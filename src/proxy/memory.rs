@@ -0,0 +1,457 @@
+//! approximate memory of the connections of each cluster, which is the capacity of their
+//! buffers and the bytes of requests in flight rather than the truth of allocator. It's
+//! maintained incrementally as the buffers grow and shrink, and summed up by all the worker
+//! threads of the cluster.
+//!
+//! Beyond max_memory, new connections are refused and the largest front connections stop
+//! reading requests until the memory is back. Beyond the hard ceiling, the single most
+//! expensive front connection is closed at a time.
+use bytes::BytesMut;
+use futures::task::Task;
+use futures::{Async, Future, Stream};
+use tokio::codec::{Decoder, Encoder};
+use tokio::timer::Interval;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::com::ClusterConfig;
+use crate::metrics::{connection_memory_set, memory_closed_incr};
+
+const CHECK_INTERVAL: u64 = 100;
+
+/// the part of memory of one connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Part {
+    // read buffer of the codec
+    Input = 0,
+    // write buffer of the codec
+    Output = 1,
+    // requests received but not replied yet, front connection only
+    Inflight = 2,
+}
+
+const PARTS: usize = 3;
+
+#[derive(Default)]
+struct Shared {
+    fronts: AtomicUsize,
+    backs: AtomicUsize,
+    // the last time a front connection is closed beyond the hard ceiling, which closes one at
+    // a time among all the worker threads
+    closed_at: Mutex<Option<Instant>>,
+}
+
+lazy_static! {
+    static ref MEMORY: Mutex<HashMap<String, Arc<Shared>>> = Mutex::new(HashMap::new());
+}
+
+/// get the memory handle of the cluster for one worker thread.
+pub fn handle(cc: &ClusterConfig) -> Memory {
+    let shared = MEMORY
+        .lock()
+        .unwrap()
+        .entry(cc.name.clone())
+        .or_insert_with(Default::default)
+        .clone();
+    let soft = cc.max_memory.unwrap_or(0);
+    Memory {
+        cluster: cc.name.clone(),
+        soft,
+        hard: cc.max_memory_hard.unwrap_or(soft + soft / 4).max(soft),
+        shared,
+        fronts: RefCell::new(HashMap::new()),
+    }
+}
+
+struct Inner {
+    parts: [Cell<usize>; PARTS],
+    shared: Arc<Shared>,
+    is_front: bool,
+    // stop reading requests due to memory pressure
+    paused: Cell<bool>,
+    // closed due to memory beyond the hard ceiling
+    evicted: Cell<bool>,
+}
+
+impl Inner {
+    fn total(&self) -> &AtomicUsize {
+        if self.is_front {
+            &self.shared.fronts
+        } else {
+            &self.shared.backs
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let bytes = self.parts.iter().map(Cell::get).sum();
+        self.total().fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// the memory of one connection, shared by its codec and itself.
+#[derive(Clone)]
+pub struct Meter {
+    inner: Rc<Inner>,
+}
+
+impl Meter {
+    fn new(shared: Arc<Shared>, is_front: bool) -> Meter {
+        Meter {
+            inner: Rc::new(Inner {
+                parts: Default::default(),
+                shared,
+                is_front,
+                paused: Cell::new(false),
+                evicted: Cell::new(false),
+            }),
+        }
+    }
+
+    pub fn set(&self, part: Part, bytes: usize) {
+        let old = self.inner.parts[part as usize].replace(bytes);
+        if bytes > old {
+            self.inner.total().fetch_add(bytes - old, Ordering::Relaxed);
+        } else if bytes < old {
+            self.inner.total().fetch_sub(old - bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add(&self, part: Part, bytes: usize) {
+        self.set(part, self.get(part) + bytes);
+    }
+
+    pub fn sub(&self, part: Part, bytes: usize) {
+        self.set(part, self.get(part).saturating_sub(bytes));
+    }
+
+    pub fn get(&self, part: Part) -> usize {
+        self.inner.parts[part as usize].get()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.inner.parts.iter().map(Cell::get).sum()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.inner.paused.get()
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        self.inner.evicted.get()
+    }
+}
+
+/// the codec which records the capacity of the buffers of connection into the meter.
+pub struct Metered<C> {
+    codec: C,
+    meter: Meter,
+}
+
+impl<C> Metered<C> {
+    pub fn new(codec: C, meter: Meter) -> Metered<C> {
+        Metered { codec, meter }
+    }
+}
+
+impl<C: Decoder> Decoder for Metered<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let rslt = self.codec.decode(src);
+        self.meter.set(Part::Input, src.capacity());
+        rslt
+    }
+}
+
+impl<C: Encoder> Encoder for Metered<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let rslt = self.codec.encode(item, dst);
+        self.meter.set(Part::Output, dst.capacity());
+        rslt
+    }
+}
+
+struct Conn {
+    client: String,
+    meter: Meter,
+    task: Task,
+}
+
+/// the memory of the cluster seen by one worker thread, which only pauses or closes the front
+/// connections of its own.
+pub struct Memory {
+    cluster: String,
+    soft: usize,
+    hard: usize,
+    shared: Arc<Shared>,
+    fronts: RefCell<HashMap<u64, Conn>>,
+}
+
+impl Memory {
+    pub fn is_enabled(&self) -> bool {
+        self.soft > 0
+    }
+
+    pub fn front_meter(&self) -> Meter {
+        Meter::new(self.shared.clone(), true)
+    }
+
+    pub fn back_meter(&self) -> Meter {
+        Meter::new(self.shared.clone(), false)
+    }
+
+    /// bytes of all the connections of the cluster.
+    pub fn used(&self) -> usize {
+        self.shared.fronts.load(Ordering::Relaxed) + self.shared.backs.load(Ordering::Relaxed)
+    }
+
+    /// beyond max_memory, new connections are refused.
+    pub fn is_over(&self) -> bool {
+        self.is_enabled() && self.used() > self.soft
+    }
+
+    /// the front connection is woken up by the task once paused, resumed or evicted.
+    pub fn register(&self, id: u64, client: &str, meter: &Meter, task: Task) {
+        let conn = Conn {
+            client: client.to_string(),
+            meter: meter.clone(),
+            task,
+        };
+        self.fronts.borrow_mut().insert(id, conn);
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.fronts.borrow_mut().remove(&id);
+    }
+
+    /// update the gauges, and apply backpressure to the largest front connections until the
+    /// excess beyond max_memory is covered, the others are resumed.
+    pub fn check(&self) {
+        let fronts = self.shared.fronts.load(Ordering::Relaxed);
+        let backs = self.shared.backs.load(Ordering::Relaxed);
+        connection_memory_set(&self.cluster, "front", fronts);
+        connection_memory_set(&self.cluster, "back", backs);
+        let used = fronts + backs;
+
+        let mut conns = self.fronts.borrow_mut();
+        let mut sorted: Vec<_> = conns.values().collect();
+        sorted.sort_by_key(|x| std::cmp::Reverse(x.meter.bytes()));
+        let mut excess = if self.is_enabled() {
+            used.saturating_sub(self.soft)
+        } else {
+            0
+        };
+        for conn in sorted.iter() {
+            let paused = excess > 0;
+            excess = excess.saturating_sub(conn.meter.bytes());
+            if conn.meter.inner.paused.replace(paused) && !paused {
+                conn.task.notify();
+            }
+        }
+
+        if !self.is_enabled() || used <= self.hard || !self.try_close() {
+            return;
+        }
+        let id = match conns.iter().max_by_key(|(_, x)| x.meter.bytes()) {
+            Some((id, _)) => *id,
+            None => return,
+        };
+        let conn = conns.remove(&id).expect("conn must exist");
+        warn!(
+            "cluster {} memory {} bytes exceeds the hard ceiling {}, close the most expensive \
+             front connection {} of {} bytes (input {}, output {}, in flight {})",
+            self.cluster,
+            used,
+            self.hard,
+            conn.client,
+            conn.meter.bytes(),
+            conn.meter.get(Part::Input),
+            conn.meter.get(Part::Output),
+            conn.meter.get(Part::Inflight),
+        );
+        memory_closed_incr(&self.cluster);
+        conn.meter.inner.evicted.set(true);
+        conn.task.notify();
+    }
+
+    // one connection is closed per check interval among all the worker threads, which gives
+    // the memory a chance to be released before the next one.
+    fn try_close(&self) -> bool {
+        let now = Instant::now();
+        let mut closed_at = self.shared.closed_at.lock().unwrap();
+        match *closed_at {
+            Some(at) if now.duration_since(at) < Duration::from_millis(CHECK_INTERVAL) => false,
+            _ => {
+                *closed_at = Some(now);
+                true
+            }
+        }
+    }
+
+    /// check the memory periodically until the memory is dropped.
+    pub fn guard(self: &Rc<Self>) -> Guard {
+        Guard {
+            memory: Rc::downgrade(self),
+            interval: Interval::new(
+                Instant::now() + Duration::from_millis(CHECK_INTERVAL),
+                Duration::from_millis(CHECK_INTERVAL),
+            ),
+        }
+    }
+}
+
+pub struct Guard {
+    memory: Weak<Memory>,
+    interval: Interval,
+}
+
+impl Future for Guard {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    error!("fail to poll memory interval due {:?}", err);
+                    return Err(());
+                }
+            }
+            match self.memory.upgrade() {
+                Some(memory) => memory.check(),
+                None => return Ok(Async::Ready(())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::lazy;
+    use futures::task;
+
+    fn new_memory(name: &str, soft: usize, hard: usize) -> Memory {
+        let cc = ClusterConfig {
+            name: name.to_string(),
+            max_memory: Some(soft),
+            max_memory_hard: Some(hard),
+            ..Default::default()
+        };
+        handle(&cc)
+    }
+
+    #[test]
+    fn test_meter_incremental() {
+        let memory = new_memory("test-memory-meter", 0, 0);
+        assert!(!memory.is_enabled());
+        let front = memory.front_meter();
+        let back = memory.back_meter();
+        front.set(Part::Input, 8192);
+        front.add(Part::Inflight, 100);
+        front.add(Part::Inflight, 50);
+        front.sub(Part::Inflight, 100);
+        back.set(Part::Output, 4096);
+        assert_eq!(front.bytes(), 8192 + 50);
+        assert_eq!(memory.used(), 8192 + 50 + 4096);
+
+        // shrunk and released
+        front.set(Part::Input, 1024);
+        front.sub(Part::Inflight, 1000);
+        assert_eq!(memory.used(), 1024 + 4096);
+        drop(front);
+        assert_eq!(memory.used(), 4096);
+        drop(back);
+        assert_eq!(memory.used(), 0);
+    }
+
+    #[test]
+    fn test_metered_codec() {
+        use bytes::Bytes;
+        use tokio::codec::BytesCodec;
+
+        let memory = new_memory("test-memory-codec", 0, 0);
+        let meter = memory.front_meter();
+        let mut codec = Metered::new(BytesCodec::new(), meter.clone());
+        let mut src = BytesMut::with_capacity(1024);
+        src.extend_from_slice(b"ping");
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], b"ping");
+        assert!(meter.get(Part::Input) > 0);
+        assert_eq!(meter.get(Part::Input), src.capacity());
+
+        let mut dst = BytesMut::with_capacity(2048);
+        codec.encode(Bytes::from(&b"pong"[..]), &mut dst).unwrap();
+        assert_eq!(meter.get(Part::Output), dst.capacity());
+        assert_eq!(memory.used(), src.capacity() + dst.capacity());
+    }
+
+    #[test]
+    fn test_memory_pause_and_close() {
+        let memory = new_memory("test-memory-limit", 1000, 3000);
+        lazy(|| {
+            let meters: Vec<_> = (0..3).map(|_| memory.front_meter()).collect();
+            for (i, meter) in meters.iter().enumerate() {
+                let client = format!("127.0.0.1:{}", 50000 + i);
+                memory.register(i as u64, &client, meter, task::current());
+            }
+            let paused =
+                |meters: &[Meter]| -> Vec<_> { meters.iter().map(|x| x.is_paused()).collect() };
+            meters[0].set(Part::Input, 100);
+            meters[1].set(Part::Inflight, 600);
+            meters[2].set(Part::Output, 200);
+            memory.check();
+            assert!(!memory.is_over());
+            assert_eq!(paused(&meters), vec![false, false, false]);
+
+            // only the largest ones covering the excess are paused
+            meters[2].add(Part::Output, 200);
+            memory.check();
+            assert!(memory.is_over());
+            assert_eq!(paused(&meters), vec![false, true, false]);
+            meters[0].add(Part::Input, 700);
+            memory.check();
+            assert_eq!(paused(&meters), vec![true, false, false]);
+            meters[2].add(Part::Output, 500);
+            memory.check();
+            assert_eq!(paused(&meters), vec![true, false, true]);
+
+            // the most expensive one is closed beyond the hard ceiling, one at a time
+            meters[1].add(Part::Inflight, 1000);
+            memory.check();
+            assert_eq!(meters.iter().filter(|x| x.is_evicted()).count(), 1);
+            assert!(meters[1].is_evicted());
+            memory.check();
+            assert_eq!(meters.iter().filter(|x| x.is_evicted()).count(), 1);
+
+            // resumed once the memory is back
+            meters[0].set(Part::Input, 0);
+            meters[1].set(Part::Inflight, 0);
+            meters[2].set(Part::Output, 0);
+            memory.check();
+            assert_eq!(paused(&meters), vec![false, false, false]);
+            assert!(!memory.is_over());
+            for i in 0..3 {
+                memory.unregister(i);
+            }
+            drop(meters);
+            assert_eq!(memory.used(), 0);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
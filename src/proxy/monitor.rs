@@ -0,0 +1,204 @@
+//! live feed of the commands received by each cluster, streamed to the connections attached by
+//! `PROXY MONITOR` until they are closed, e.g.:
+//!
+//! ```text
+//! +1700000000.123456 [127.0.0.1:50001] SET user:1
+//! ```
+//!
+//! Only the name and keys of command are sent, values are never. The events are shared by all
+//! the worker threads and limited by rate, and each monitor buffers a bounded count of events,
+//! so the events beyond are dropped instead of slowing down the workers.
+use futures::task::Task;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::com::ClusterConfig;
+
+const SUB_CMD_MONITOR: &str = "MONITOR";
+const MAX_EVENTS_PER_SEC: usize = 1000;
+const MAX_BUFFERED: usize = 256;
+
+lazy_static! {
+    static ref MONITORS: Mutex<HashMap<String, Arc<Monitor>>> = Mutex::new(HashMap::new());
+}
+
+/// get the monitors of the cluster.
+pub fn handle(cc: &ClusterConfig) -> Arc<Monitor> {
+    MONITORS
+        .lock()
+        .unwrap()
+        .entry(cc.name.clone())
+        .or_insert_with(Default::default)
+        .clone()
+}
+
+/// the arguments after PROXY is MONITOR.
+pub fn is_monitor(args: &[String]) -> bool {
+    args.len() == 1 && args[0].eq_ignore_ascii_case(SUB_CMD_MONITOR)
+}
+
+struct Watcher {
+    events: VecDeque<String>,
+    dropped: usize,
+    task: Task,
+}
+
+#[derive(Default)]
+struct Inner {
+    watchers: HashMap<u64, Watcher>,
+    // the second since epoch and count of events published in it
+    second: u64,
+    published: usize,
+}
+
+#[derive(Default)]
+pub struct Monitor {
+    // count of watchers, checked without lock for every command
+    active: AtomicUsize,
+    inner: Mutex<Inner>,
+}
+
+impl Monitor {
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed) > 0
+    }
+
+    /// attach the connection of id, which is woken up by the task once events come.
+    pub fn watch(&self, id: u64, task: Task) {
+        let watcher = Watcher {
+            events: VecDeque::new(),
+            dropped: 0,
+            task,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        if inner.watchers.insert(id, watcher).is_none() {
+            self.active.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn unwatch(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.watchers.remove(&id).is_some() {
+            self.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// send the command received from client to all the monitors.
+    pub fn publish(&self, client: &str, cmd: &str, keys: &[Vec<u8>]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.watchers.is_empty() {
+            return;
+        }
+        if inner.second != now.as_secs() {
+            inner.second = now.as_secs();
+            inner.published = 0;
+        }
+        if inner.published >= MAX_EVENTS_PER_SEC {
+            for watcher in inner.watchers.values_mut() {
+                watcher.dropped += 1;
+            }
+            return;
+        }
+        inner.published += 1;
+
+        let mut event = format!(
+            "{}.{:06} [{}] {}",
+            now.as_secs(),
+            now.subsec_micros(),
+            client,
+            cmd.to_uppercase()
+        );
+        for key in keys {
+            event.push(' ');
+            event.push_str(&String::from_utf8_lossy(key));
+        }
+        for watcher in inner.watchers.values_mut() {
+            if watcher.events.len() >= MAX_BUFFERED {
+                watcher.dropped += 1;
+                continue;
+            }
+            watcher.events.push_back(event.clone());
+            if watcher.events.len() == 1 {
+                watcher.task.notify();
+            }
+        }
+    }
+
+    /// take the events buffered for the connection of id, the count of events dropped is
+    /// given by the last one.
+    pub fn take(&self, id: u64) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let watcher = match inner.watchers.get_mut(&id) {
+            Some(watcher) => watcher,
+            None => return Vec::new(),
+        };
+        let mut events: Vec<_> = watcher.events.drain(..).collect();
+        if watcher.dropped > 0 {
+            events.push(format!("{} events dropped", watcher.dropped));
+            watcher.dropped = 0;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::lazy;
+    use futures::task;
+    use futures::Future;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_is_monitor() {
+        assert!(is_monitor(&args(&["monitor"])));
+        assert!(!is_monitor(&args(&["MONITOR", "x"])));
+        assert!(!is_monitor(&args(&["ADDNODE"])));
+    }
+
+    #[test]
+    fn test_monitor_events() {
+        let monitor = Monitor::default();
+        // nothing is buffered without monitors
+        monitor.publish("127.0.0.1:50001", "get", &[b"a".to_vec()]);
+        assert!(!monitor.is_active());
+
+        lazy(|| {
+            monitor.watch(1, task::current());
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+        assert!(monitor.is_active());
+        monitor.publish("127.0.0.1:50001", "mget", &[b"a".to_vec(), b"b".to_vec()]);
+        let events = monitor.take(1);
+        assert_eq!(events.len(), 1);
+        assert!(
+            events[0].ends_with(" [127.0.0.1:50001] MGET a b"),
+            "{}",
+            events[0]
+        );
+        assert!(monitor.take(1).is_empty());
+
+        // the events beyond the buffer are dropped until taken
+        for _ in 0..MAX_BUFFERED + 2 {
+            monitor.publish("127.0.0.1:50001", "get", &[b"a".to_vec()]);
+        }
+        let events = monitor.take(1);
+        assert_eq!(events.len(), MAX_BUFFERED + 1);
+        assert_eq!(events[MAX_BUFFERED], "2 events dropped");
+
+        monitor.unwatch(1);
+        assert!(!monitor.is_active());
+        assert!(monitor.take(1).is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+//! output buffer limit of front connections, which protects proxy memory from the slow
+//! clients: the replies pending to them are piled up in proxy once the socket is full.
+//!
+//! The reply pacing is the gentler complement of it: the requests of the slow client are no
+//! more read (so never sent to backends) once the replies pending to it exceed reply_pacing
+//! bytes, and read again once it catches up. So the replies are pulled from backends only as
+//! fast as the client reads them, rather than piled up in proxy until the limits close it.
+use futures::{Async, Future};
+use tokio::timer::Delay;
+
+use std::time::{Duration, Instant};
+
+use crate::com::{AsError, ClusterConfig};
+use crate::metrics::{output_limit_closed_incr, reply_paced_incr};
+
+pub struct OutputLimit {
+    cluster: String,
+    hard: usize,
+    soft: usize,
+    soft_duration: Duration,
+    // set since the soft limit is exceeded
+    soft_timer: Option<Delay>,
+}
+
+impl OutputLimit {
+    pub fn new(cc: &ClusterConfig) -> OutputLimit {
+        OutputLimit {
+            cluster: cc.name.clone(),
+            hard: cc.output_buffer_hard_limit.unwrap_or(0),
+            soft: cc.output_buffer_soft_limit.unwrap_or(0),
+            soft_duration: Duration::from_secs(cc.output_buffer_soft_seconds.unwrap_or(0)),
+            soft_timer: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.hard > 0 || self.soft > 0
+    }
+
+    /// check the bytes of replies pending to client, which is called only when the client
+    /// is not writable. The soft timer wakes up the current task even if the client is stuck.
+    pub fn check(&mut self, pending: usize) -> Result<(), AsError> {
+        if self.hard > 0 && pending > self.hard {
+            return Err(self.exceeded(pending));
+        }
+        if self.soft == 0 || pending <= self.soft {
+            self.soft_timer = None;
+            return Ok(());
+        }
+        let soft_duration = self.soft_duration;
+        let timer = self
+            .soft_timer
+            .get_or_insert_with(|| Delay::new(Instant::now() + soft_duration));
+        match timer.poll() {
+            Ok(Async::Ready(_)) => Err(self.exceeded(pending)),
+            Ok(Async::NotReady) => Ok(()),
+            Err(err) => {
+                warn!("fail to poll output buffer soft timer due {}", err);
+                Ok(())
+            }
+        }
+    }
+
+    /// all the pending replies are sent.
+    pub fn reset(&mut self) {
+        self.soft_timer = None;
+    }
+
+    fn exceeded(&mut self, pending: usize) -> AsError {
+        self.soft_timer = None;
+        output_limit_closed_incr(&self.cluster);
+        AsError::OutputBufferLimit(pending)
+    }
+}
+
+pub struct ReplyPacing {
+    cluster: String,
+    max: usize,
+    // set since the replies pending exceed max, until the client is writable again
+    paced: bool,
+}
+
+impl ReplyPacing {
+    pub fn new(cc: &ClusterConfig) -> ReplyPacing {
+        ReplyPacing {
+            cluster: cc.name.clone(),
+            max: cc.reply_pacing.unwrap_or(0),
+            paced: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max > 0
+    }
+
+    /// no more requests of the client are read.
+    pub fn is_paced(&self) -> bool {
+        self.paced
+    }
+
+    /// check the bytes of replies pending to client, which is called only when the client
+    /// is not writable.
+    pub fn check(&mut self, pending: usize) {
+        let paced = self.is_enabled() && pending > self.max;
+        if paced && !self.paced {
+            reply_paced_incr(&self.cluster);
+        }
+        self.paced = paced;
+    }
+
+    /// the client is writable again.
+    pub fn reset(&mut self) {
+        self.paced = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::output_limit_closed_get;
+    use futures::future::poll_fn;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn new_limit(name: &str, hard: usize, soft: usize) -> OutputLimit {
+        let cc = ClusterConfig {
+            name: name.to_string(),
+            output_buffer_hard_limit: Some(hard),
+            output_buffer_soft_limit: Some(soft),
+            ..Default::default()
+        };
+        OutputLimit::new(&cc)
+    }
+
+    #[test]
+    fn test_output_limit_hard() {
+        assert!(!OutputLimit::new(&ClusterConfig::default()).is_enabled());
+
+        let mut limit = new_limit("test-outbuf-hard", 1024, 0);
+        assert!(limit.is_enabled());
+        assert_eq!(limit.check(1024), Ok(()));
+        assert_eq!(limit.check(1025), Err(AsError::OutputBufferLimit(1025)));
+        assert_eq!(output_limit_closed_get("test-outbuf-hard"), 1);
+    }
+
+    #[test]
+    fn test_output_limit_soft() {
+        let mut limit = new_limit("test-outbuf-soft", 0, 512);
+        limit.soft_duration = Duration::from_millis(50);
+        let mut rt = Runtime::new().unwrap();
+        let start = Instant::now();
+        let err = rt
+            .block_on(poll_fn(|| {
+                // the slow consumer keeps beyond the soft limit
+                match limit.check(513) {
+                    Ok(()) => Ok::<_, ()>(Async::NotReady),
+                    Err(err) => Ok(Async::Ready(err)),
+                }
+            }))
+            .unwrap();
+        assert_eq!(err, AsError::OutputBufferLimit(513));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // recovered below the soft limit in time
+        let mut limit = new_limit("test-outbuf-soft-ok", 0, 512);
+        limit.soft_duration = Duration::from_millis(50);
+        rt.block_on(poll_fn(|| {
+            assert_eq!(limit.check(513), Ok(()));
+            assert_eq!(limit.check(512), Ok(()));
+            Ok::<_, ()>(Async::Ready(()))
+        }))
+        .unwrap();
+        assert!(limit.soft_timer.is_none());
+        assert_eq!(output_limit_closed_get("test-outbuf-soft-ok"), 0);
+    }
+}
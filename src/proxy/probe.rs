@@ -0,0 +1,249 @@
+//! self probe of the latency added by proxy: a thread of each cluster sends a synthetic PING
+//! (version for memcache) every self_probe_interval millis through listen_addr over loopback,
+//! so the probe takes the same path as clients through accept, codec and the worker loop, and
+//! its round trip is observed by aster_self_probe_timer, which the bound of proxy added latency
+//! can be alerted on. PING is replied by proxy itself, so the backends are out of the timer.
+//!
+//! The connections of probe are known by their local address, registered before connecting,
+//! so they are labelled as `self-probe` in access log and excluded from the counters of clients
+//! (e.g.: aster_front_connection, aster_total_timer and the requests of listeners).
+use net2::TcpBuilder;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::com::{CacheType, ClusterConfig};
+use crate::metrics::{self_probe_error_incr, self_probe_observe};
+
+/// the client of probe requests in access log.
+pub const CLIENT_LABEL: &str = "self-probe";
+
+pub(crate) const REDIS_PING: &[u8] = b"*1\r\n$4\r\nPING\r\n";
+pub(crate) const MC_VERSION: &[u8] = b"version\r\n";
+const MAX_REPLY_SIZE: usize = 1024;
+const PROBE_TIMEOUT: u64 = 5000;
+
+lazy_static! {
+    // held by the workers, the thread of probe exits once all the workers exit
+    static ref PROBES: Mutex<HashMap<String, Weak<Probe>>> = Mutex::new(HashMap::new());
+}
+
+/// get the probe of the cluster, whose thread is spawned by the first worker.
+pub fn handle(cc: &ClusterConfig) -> Arc<Probe> {
+    let mut probes = PROBES.lock().unwrap();
+    if let Some(probe) = probes.get(&cc.name).and_then(Weak::upgrade) {
+        return probe;
+    }
+    let interval = cc.self_probe_interval.unwrap_or(0);
+    let probe = Arc::new(Probe {
+        enabled: AtomicBool::new(interval > 0),
+        locals: Mutex::new(HashSet::new()),
+    });
+    if interval > 0 {
+        if let Err(err) = spawn_prober(cc, interval, Arc::downgrade(&probe)) {
+            error!(
+                "cluster {} fail to spawn self probe due to {}",
+                cc.name, err
+            );
+        }
+    }
+    probes.insert(cc.name.clone(), Arc::downgrade(&probe));
+    probe
+}
+
+#[derive(Default)]
+pub struct Probe {
+    // checked without lock for every connection accepted
+    enabled: AtomicBool,
+    // local address of the probe connections, which is the client address seen by workers
+    locals: Mutex<HashSet<String>>,
+}
+
+impl Probe {
+    /// the connection accepted from the client is the one of probe.
+    pub fn is_probe(&self, client: &str) -> bool {
+        self.enabled.load(Ordering::Relaxed) && self.locals.lock().unwrap().contains(client)
+    }
+
+    fn register(&self, local: &SocketAddr) {
+        self.locals.lock().unwrap().insert(local.to_string());
+    }
+
+    fn unregister(&self, local: &SocketAddr) {
+        self.locals.lock().unwrap().remove(&local.to_string());
+    }
+}
+
+/// the address probe connects to, the unspecified ip of listen_addr is replaced by loopback.
+fn probe_addr(listen_addr: &str) -> Result<SocketAddr, io::Error> {
+    let mut addr = listen_addr
+        .parse::<SocketAddr>()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if addr.ip().is_unspecified() {
+        let ip = if addr.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        } else {
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        };
+        addr.set_ip(ip);
+    }
+    Ok(addr)
+}
+
+/// the reply is +PONG (or any status) of redis or VERSION line of memcache.
+pub(crate) fn is_healthy(reply: &[u8]) -> bool {
+    reply.starts_with(b"+") || reply.starts_with(b"VERSION")
+}
+
+fn spawn_prober(cc: &ClusterConfig, interval: u64, probe: Weak<Probe>) -> Result<(), io::Error> {
+    let addr = probe_addr(&cc.listen_addr)?;
+    let request = match cc.cache_type {
+        CacheType::Memcache | CacheType::MemcacheBinary => MC_VERSION,
+        _ => REDIS_PING,
+    };
+    let name = cc.name.clone();
+    thread::Builder::new()
+        .name(format!("aster-probe-{}", cc.name))
+        .spawn(move || {
+            let mut prober = Prober {
+                addr,
+                request,
+                conn: None,
+            };
+            loop {
+                thread::sleep(Duration::from_millis(interval));
+                let probe = match probe.upgrade() {
+                    Some(probe) => probe,
+                    None => return,
+                };
+                let now = Instant::now();
+                match prober.round_trip(&probe) {
+                    Ok(()) => self_probe_observe(&name, now.elapsed()),
+                    Err(err) => {
+                        debug!("cluster {} self probe fail due to {}", name, err);
+                        self_probe_error_incr(&name);
+                        prober.close(&probe);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+struct Prober {
+    addr: SocketAddr,
+    request: &'static [u8],
+    // the connection and its local address, reused by rounds until any error
+    conn: Option<(TcpStream, SocketAddr)>,
+}
+
+impl Prober {
+    fn connect(&self, probe: &Probe) -> Result<(TcpStream, SocketAddr), io::Error> {
+        let builder = if self.addr.is_ipv4() {
+            TcpBuilder::new_v4()?
+        } else {
+            TcpBuilder::new_v6()?
+        };
+        builder.bind(SocketAddr::new(self.addr.ip(), 0))?;
+        let local = builder.local_addr()?;
+        // registered before connecting, or the worker may accept it before known
+        probe.register(&local);
+        let sock = match builder.connect(self.addr) {
+            Ok(sock) => sock,
+            Err(err) => {
+                probe.unregister(&local);
+                return Err(err);
+            }
+        };
+        let timeout = Some(Duration::from_millis(PROBE_TIMEOUT));
+        sock.set_read_timeout(timeout)?;
+        sock.set_write_timeout(timeout)?;
+        sock.set_nodelay(true)?;
+        Ok((sock, local))
+    }
+
+    fn round_trip(&mut self, probe: &Probe) -> Result<(), io::Error> {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect(probe)?);
+        }
+        let (sock, _) = self.conn.as_mut().expect("conn never be none");
+        sock.write_all(self.request)?;
+        let mut reply = Vec::new();
+        let mut buf = [0u8; 128];
+        while !reply.ends_with(b"\r\n") {
+            if reply.len() > MAX_REPLY_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "reply too large",
+                ));
+            }
+            let size = sock.read(&mut buf)?;
+            if size == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            reply.extend_from_slice(&buf[..size]);
+        }
+        if !is_healthy(&reply) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                String::from_utf8_lossy(&reply).trim_end().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, probe: &Probe) {
+        if let Some((_, local)) = self.conn.take() {
+            probe.unregister(&local);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_probe_addr() {
+        assert_eq!(
+            probe_addr("0.0.0.0:7788").unwrap(),
+            "127.0.0.1:7788".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            probe_addr("[::]:7788").unwrap(),
+            "[::1]:7788".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            probe_addr("10.0.0.1:7788").unwrap(),
+            "10.0.0.1:7788".parse::<SocketAddr>().unwrap()
+        );
+        assert!(probe_addr("localhost").is_err());
+    }
+
+    #[test]
+    fn test_is_healthy() {
+        assert!(is_healthy(b"+PONG\r\n"));
+        assert!(is_healthy(b"VERSION 1.6.9\r\n"));
+        assert!(!is_healthy(b"-ERR proxy fail to connect\r\n"));
+        assert!(!is_healthy(b"SERVER_ERROR backend\r\n"));
+    }
+
+    #[test]
+    fn test_probe_register() {
+        let probe = Probe::default();
+        let local = "127.0.0.1:50001".parse::<SocketAddr>().unwrap();
+        probe.register(&local);
+        // never checked if disabled
+        assert!(!probe.is_probe("127.0.0.1:50001"));
+        probe.enabled.store(true, Ordering::Relaxed);
+        assert!(probe.is_probe("127.0.0.1:50001"));
+        assert!(!probe.is_probe("127.0.0.1:50002"));
+        probe.unregister(&local);
+        assert!(!probe.is_probe("127.0.0.1:50001"));
+    }
+}
@@ -0,0 +1,54 @@
+//! read-only mode of each cluster, shared by all the worker threads.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::com::ClusterConfig;
+
+lazy_static! {
+    static ref READ_ONLY: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+}
+
+/// get the read-only mode handle of the cluster, which is initialized by config at first.
+pub fn handle(cc: &ClusterConfig) -> Arc<AtomicBool> {
+    let mut modes = READ_ONLY.lock().unwrap();
+    modes
+        .entry(cc.name.clone())
+        .or_insert_with(|| Arc::new(AtomicBool::new(cc.read_only.unwrap_or(false))))
+        .clone()
+}
+
+/// flip the read-only mode of the cluster, return false if the cluster is not running.
+pub fn set_read_only(cluster: &str, enable: bool) -> bool {
+    let modes = READ_ONLY.lock().unwrap();
+    if let Some(mode) = modes.get(cluster) {
+        mode.store(enable, Ordering::SeqCst);
+        return true;
+    }
+    false
+}
+
+pub fn is_read_only(mode: &AtomicBool) -> bool {
+    mode.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_only_flip() {
+        let cc = ClusterConfig {
+            name: "test-read-only".to_string(),
+            read_only: Some(true),
+            ..Default::default()
+        };
+        assert!(!set_read_only(&cc.name, false));
+
+        let mode = handle(&cc);
+        assert!(is_read_only(&mode));
+        assert!(set_read_only(&cc.name, false));
+        assert!(!is_read_only(&mode));
+        assert!(!is_read_only(&handle(&cc)));
+    }
+}
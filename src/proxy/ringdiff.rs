@@ -0,0 +1,352 @@
+//! how many keys a topology change moves, computed against the ring of the current servers
+//! before it's applied, by the admin api or the redis command of proxy mode:
+//!
+//! ```text
+//! PROXY RING DIFF [SAMPLES count] server [server ...]
+//! ```
+//!
+//! The servers proposed are in the same format as the servers of config, and a sample of
+//! synthetic keys (or the keys given by a file to the admin api) is placed by both rings the
+//! same as routing does. The report is by lines:
+//!
+//! ```text
+//! sampled 100000 keys, 20136 moved (20.14%)
+//! nodes added redis-5
+//! nodes changed -
+//! nodes removed -
+//! moved 127.0.0.1:7001 -> 127.0.0.1:7005 5071
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+
+use crate::com::{AsError, CacheType, ClusterConfig};
+use crate::proxy::standalone::{check_servers, ring_nodes, NodesDiff};
+use crate::routing::{Placement, Routing};
+
+pub const DEFAULT_SAMPLES: usize = 100_000;
+// the samples beyond it take the worker too long to answer the command
+const MAX_COMMAND_SAMPLES: usize = 1_000_000;
+const SUB_CMD_RING: &str = "RING";
+const SUB_CMD_DIFF: &str = "DIFF";
+const OPT_SAMPLES: &str = "SAMPLES";
+
+/// options of ring diff given by admin api, the servers proposed are posted as json by
+/// `{"servers": [...]}` unless they are read from the cluster of the candidate config.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RingDiffOption {
+    // path of the candidate config file
+    pub config: Option<String>,
+    // count of synthetic keys, DEFAULT_SAMPLES if absent
+    pub samples: Option<usize>,
+    // path of the file of keys by lines, instead of synthetic keys
+    pub keys: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Proposed {
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RingDiff {
+    pub nodes: NodesDiff,
+    pub sampled: usize,
+    pub moved: usize,
+    // the keys moved by the pair of addresses they are moved from and to
+    pub moves: BTreeMap<(String, String), usize>,
+}
+
+impl fmt::Display for RingDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ratio = if self.sampled == 0 {
+            0.0
+        } else {
+            self.moved as f64 * 100.0 / self.sampled as f64
+        };
+        writeln!(
+            f,
+            "sampled {} keys, {} moved ({:.2}%)",
+            self.sampled, self.moved, ratio
+        )?;
+        let names = |x: &[String]| {
+            if x.is_empty() {
+                "-".to_string()
+            } else {
+                x.join(",")
+            }
+        };
+        writeln!(f, "nodes added {}", names(&self.nodes.added))?;
+        writeln!(f, "nodes changed {}", names(&self.nodes.changed))?;
+        writeln!(f, "nodes removed {}", names(&self.nodes.removed))?;
+        let mut moves: Vec<_> = self.moves.iter().collect();
+        // the largest first
+        moves.sort_by(|x, y| y.1.cmp(x.1));
+        for ((from, to), count) in moves {
+            writeln!(f, "moved {} -> {} {}", from, to, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl RingDiff {
+    pub fn lines(&self) -> Vec<String> {
+        self.to_string().lines().map(|x| x.to_string()).collect()
+    }
+}
+
+/// the placement of keys by the ring of current servers compared with the ring of servers
+/// proposed, proxy mode only.
+pub fn diff<I, K>(cc: &ClusterConfig, servers: &[String], keys: I) -> Result<RingDiff, AsError>
+where
+    I: IntoIterator<Item = K>,
+    K: AsRef<[u8]>,
+{
+    if let CacheType::RedisCluster = cc.cache_type {
+        return Err(AsError::BadConfig(
+            "ring diff only support proxy mode".to_string(),
+        ));
+    }
+    if servers.is_empty() {
+        return Err(AsError::BadConfig("servers proposed is empty".to_string()));
+    }
+    check_servers(servers)?;
+    let proposed_cc = ClusterConfig {
+        servers: servers.to_vec(),
+        ..cc.clone()
+    };
+    let current = Routing::from_config(cc)?;
+    let proposed = Routing::from_config(&proposed_cc)?;
+
+    let mut diff = RingDiff {
+        nodes: NodesDiff::new(&node_addrs(&cc.servers)?, &node_addrs(servers)?),
+        ..Default::default()
+    };
+    for key in keys {
+        let key = key.as_ref();
+        diff.sampled += 1;
+        let from = addr_of(current.locate(key));
+        let to = addr_of(proposed.locate(key));
+        if from != to {
+            diff.moved += 1;
+            *diff.moves.entry((from, to)).or_insert(0) += 1;
+        }
+    }
+    Ok(diff)
+}
+
+fn node_addrs(servers: &[String]) -> Result<HashMap<String, String>, AsError> {
+    Ok(ring_nodes(servers)?
+        .into_iter()
+        .map(|x| (x.0, x.1))
+        .collect())
+}
+
+fn addr_of(placement: Option<Placement>) -> String {
+    match placement {
+        Some(Placement::Node { addr, .. }) => addr,
+        _ => "-".to_string(),
+    }
+}
+
+/// the synthetic keys sampled.
+pub fn synthetic_keys(count: usize) -> impl Iterator<Item = String> {
+    (0..count).map(|i| format!("aster-ring-diff:{}", i))
+}
+
+/// the keys of file by lines, the empty lines are skipped.
+pub fn file_keys(path: &str) -> Result<Vec<String>, AsError> {
+    let content = fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect())
+}
+
+/// the diff asked by admin api, the servers are read from the candidate config or posted.
+pub fn diff_by_option(
+    cc: &ClusterConfig,
+    opt: &RingDiffOption,
+    body: &str,
+) -> Result<RingDiff, AsError> {
+    let servers = match opt.config.as_ref() {
+        Some(path) => crate::com::Config::load(path)?
+            .cluster(&cc.name)
+            .map(|x| x.servers)
+            .ok_or_else(|| {
+                AsError::BadConfig(format!("cluster {} not found in {}", cc.name, path))
+            })?,
+        None => {
+            serde_json::from_str::<Proposed>(body)
+                .map_err(|err| AsError::BadConfig(format!("servers proposed: {}", err)))?
+                .servers
+        }
+    };
+    match opt.keys.as_ref() {
+        Some(path) => diff(cc, &servers, file_keys(path)?),
+        None => diff(
+            cc,
+            &servers,
+            synthetic_keys(opt.samples.unwrap_or(DEFAULT_SAMPLES)),
+        ),
+    }
+}
+
+/// the samples and servers of PROXY RING DIFF, None if the arguments after PROXY are not it.
+pub fn ring_diff_args(args: &[String]) -> Option<Result<(usize, &[String]), AsError>> {
+    match (args.get(0), args.get(1)) {
+        (Some(ring), Some(sub)) if ring.eq_ignore_ascii_case(SUB_CMD_RING) => {
+            if !sub.eq_ignore_ascii_case(SUB_CMD_DIFF) {
+                return Some(Err(AsError::BadProxyCommand(format!(
+                    "unknown subcommand '{}'. Try DIFF.",
+                    sub
+                ))));
+            }
+        }
+        (Some(ring), None) if ring.eq_ignore_ascii_case(SUB_CMD_RING) => {}
+        _ => return None,
+    }
+    let wrong_args = || {
+        AsError::BadProxyCommand("wrong number of arguments for 'proxy|ring' command".to_string())
+    };
+    let mut servers = args.get(2..).unwrap_or_default();
+    let mut samples = DEFAULT_SAMPLES;
+    if servers
+        .first()
+        .map(|x| x.eq_ignore_ascii_case(OPT_SAMPLES))
+        .unwrap_or(false)
+    {
+        samples = match servers.get(1).and_then(|x| x.parse::<usize>().ok()) {
+            Some(samples) if samples <= MAX_COMMAND_SAMPLES => samples,
+            _ => {
+                return Some(Err(AsError::BadProxyCommand(format!(
+                    "samples must be an integer no more than {}",
+                    MAX_COMMAND_SAMPLES
+                ))))
+            }
+        };
+        servers = &servers[2..];
+    }
+    if servers.is_empty() {
+        return Some(Err(wrong_args()));
+    }
+    Some(Ok((samples, servers)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn servers(count: usize) -> Vec<String> {
+        (1..=count)
+            .map(|i| format!("127.0.0.1:700{}:10 redis-{}", i, i))
+            .collect()
+    }
+
+    fn new_cc(count: usize) -> ClusterConfig {
+        ClusterConfig {
+            name: "test-ring-diff".to_string(),
+            cache_type: CacheType::Redis,
+            servers: servers(count),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ring_diff_unchanged() {
+        let cc = new_cc(4);
+        let diff = diff(&cc, &cc.servers, synthetic_keys(1000)).unwrap();
+        assert_eq!(diff.sampled, 1000);
+        assert_eq!(diff.moved, 0);
+        assert!(diff.moves.is_empty());
+        assert_eq!(diff.nodes.preserved.len(), 4);
+    }
+
+    #[test]
+    fn test_ring_diff_add_node() {
+        let cc = new_cc(4);
+        let diff = diff(&cc, &servers(5), synthetic_keys(10_000)).unwrap();
+        assert_eq!(diff.nodes.added, vec!["redis-5".to_string()]);
+        // the keys only move to the node added, about 1/5 of all
+        assert!(diff.moves.keys().all(|x| x.1 == "127.0.0.1:7005"));
+        assert_eq!(diff.moves.values().sum::<usize>(), diff.moved);
+        assert!(diff.moved > 1000 && diff.moved < 3000, "{}", diff.moved);
+
+        let lines = diff.lines();
+        assert!(lines[0].starts_with("sampled 10000 keys, "));
+        assert_eq!(lines[1], "nodes added redis-5");
+        assert_eq!(lines[2], "nodes changed -");
+        assert_eq!(lines[3], "nodes removed -");
+        assert!(lines[4].starts_with("moved 127.0.0.1:700"));
+    }
+
+    #[test]
+    fn test_ring_diff_by_keys() {
+        let cc = new_cc(4);
+        // the address of redis-4 is changed, which moves its keys only
+        let mut proposed = servers(4);
+        proposed[3] = "127.0.0.1:7009:10 redis-4".to_string();
+        let routing = Routing::from_config(&cc).unwrap();
+        let keys: Vec<_> = synthetic_keys(1000).collect();
+        let owned = keys
+            .iter()
+            .filter(|x| addr_of(routing.locate(x.as_bytes())) == "127.0.0.1:7004")
+            .count();
+        let diff = diff(&cc, &proposed, &keys).unwrap();
+        assert_eq!(diff.nodes.changed, vec!["redis-4".to_string()]);
+        assert_eq!(diff.moved, owned);
+        assert_eq!(
+            diff.moves.keys().collect::<Vec<_>>(),
+            vec![&("127.0.0.1:7004".to_string(), "127.0.0.1:7009".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_ring_diff_invalid() {
+        let mut cc = new_cc(4);
+        assert!(diff(&cc, &[], synthetic_keys(1)).is_err());
+        assert!(diff(&cc, &["bad".to_string()], synthetic_keys(1)).is_err());
+        cc.cache_type = CacheType::RedisCluster;
+        assert!(diff(&cc, &servers(4), synthetic_keys(1)).is_err());
+    }
+
+    #[test]
+    fn test_ring_diff_args() {
+        let args = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert!(ring_diff_args(&args(&["SHARD", "a"])).is_none());
+        let given = args(&["ring", "diff", "127.0.0.1:7001:10"]);
+        let (samples, servers) = ring_diff_args(&given).unwrap().unwrap();
+        assert_eq!(samples, DEFAULT_SAMPLES);
+        assert_eq!(servers, &given[2..]);
+
+        let given = args(&["RING", "DIFF", "SAMPLES", "100", "127.0.0.1:7001:10"]);
+        let (samples, servers) = ring_diff_args(&given).unwrap().unwrap();
+        assert_eq!(samples, 100);
+        assert_eq!(servers, &given[4..]);
+
+        for bad in &[
+            &["RING"][..],
+            &["RING", "DIFF"],
+            &["RING", "DUMP", "127.0.0.1:7001:10"],
+            &["RING", "DIFF", "SAMPLES", "x", "127.0.0.1:7001:10"],
+            &["RING", "DIFF", "SAMPLES", "100"],
+        ] {
+            assert!(matches!(ring_diff_args(&args(bad)), Some(Err(_))));
+        }
+    }
+
+    #[test]
+    fn test_diff_by_option() {
+        let cc = new_cc(3);
+        let opt = RingDiffOption {
+            samples: Some(100),
+            ..Default::default()
+        };
+        let body = format!(r#"{{"servers": {:?}}}"#, servers(4));
+        let diff = diff_by_option(&cc, &opt, &body).unwrap();
+        assert_eq!(diff.sampled, 100);
+        assert_eq!(diff.nodes.added, vec!["redis-4".to_string()]);
+        assert!(diff_by_option(&cc, &opt, "{}").is_err());
+    }
+}
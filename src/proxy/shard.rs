@@ -0,0 +1,151 @@
+//! routing details of one key answered by the proxy itself, for debugging the placement of keys:
+//!
+//! ```text
+//! PROXY SHARD {user1000}.following
+//! ```
+//!
+//! replied as pairs of field and value like CONFIG GET: hash, hash_tag (the part of key hashed),
+//! slot of redis cluster or position (the point of ring owning the key) of proxy mode, node and
+//! addr routed to, and role of the node which reads go to under the current read routing.
+//! Writes always go to the master (see the master field of redis cluster).
+use crate::com::AsError;
+
+const SUB_CMD_SHARD: &str = "SHARD";
+
+/// the key of PROXY SHARD, None if the arguments after PROXY are not SHARD.
+pub fn shard_key(args: &[String]) -> Option<Result<&str, AsError>> {
+    match args.get(0) {
+        Some(sub_cmd) if sub_cmd.eq_ignore_ascii_case(SUB_CMD_SHARD) => {}
+        _ => return None,
+    }
+    if args.len() != 2 {
+        return Some(Err(AsError::BadProxyCommand(
+            "wrong number of arguments for 'proxy|shard' command".to_string(),
+        )));
+    }
+    Some(Ok(&args[1]))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Master,
+    Replica,
+    // the standby backends of proxy mode, which take over once the primaries fail
+    Standby,
+}
+
+impl Role {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Role::Master => "master",
+            Role::Replica => "replica",
+            Role::Standby => "standby",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Position {
+    /// the slot of redis cluster and its master.
+    Slot { slot: usize, master: String },
+    /// the point of ring, None for the key pinned or routed to standby.
+    Ring(Option<u64>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shard {
+    pub hash: u64,
+    pub hash_tag: Vec<u8>,
+    pub position: Position,
+    // the name of node is alias if present, None if there's no node for the key
+    pub node: Option<String>,
+    pub addr: Option<String>,
+    pub role: Role,
+}
+
+impl Shard {
+    /// the pairs of field and value, the value of absent field is empty.
+    pub fn fields(&self) -> Vec<String> {
+        let mut fields = vec![
+            "hash".to_string(),
+            self.hash.to_string(),
+            "hash_tag".to_string(),
+            String::from_utf8_lossy(&self.hash_tag).to_string(),
+        ];
+        match &self.position {
+            Position::Slot { slot, master } => {
+                fields.push("slot".to_string());
+                fields.push(slot.to_string());
+                fields.push("master".to_string());
+                fields.push(master.clone());
+            }
+            Position::Ring(point) => {
+                fields.push("position".to_string());
+                fields.push(point.map(|x| x.to_string()).unwrap_or_default());
+            }
+        }
+        fields.push("node".to_string());
+        fields.push(self.node.clone().unwrap_or_default());
+        fields.push("addr".to_string());
+        fields.push(self.addr.clone().unwrap_or_default());
+        fields.push("role".to_string());
+        fields.push(self.role.as_str().to_string());
+        fields
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_shard_key() {
+        assert_eq!(shard_key(&args(&["shard", "a"])), Some(Ok("a")));
+        assert!(matches!(shard_key(&args(&["SHARD"])), Some(Err(_))));
+        assert!(matches!(
+            shard_key(&args(&["SHARD", "a", "b"])),
+            Some(Err(_))
+        ));
+        assert_eq!(shard_key(&args(&["MONITOR"])), None);
+        assert_eq!(shard_key(&[]), None);
+    }
+
+    #[test]
+    fn test_shard_fields() {
+        let shard = Shard {
+            hash: 5474,
+            hash_tag: b"user1000".to_vec(),
+            position: Position::Slot {
+                slot: 5474,
+                master: "127.0.0.1:7001".to_string(),
+            },
+            node: Some("127.0.0.1:7004".to_string()),
+            addr: Some("127.0.0.1:7004".to_string()),
+            role: Role::Replica,
+        };
+        let fields = shard.fields();
+        assert_eq!(
+            fields,
+            args(&[
+                "hash",
+                "5474",
+                "hash_tag",
+                "user1000",
+                "slot",
+                "5474",
+                "master",
+                "127.0.0.1:7001",
+                "node",
+                "127.0.0.1:7004",
+                "addr",
+                "127.0.0.1:7004",
+                "role",
+                "replica"
+            ])
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! latency objectives of commands evaluated by the proxy itself, so the on-call is paged by the
+//! burn rates of error budget without the queries of metrics backend, e.g.:
+//!
+//! ```toml
+//! [[clusters.slo]]
+//! command = "GET"
+//! latency = 5
+//! objective = 99.0
+//! ```
+//!
+//! each reply of the command is counted as good, or bad if it's slower than latency millis or an
+//! error, into the per-minute buckets of a fixed ring of 6 hours shared by all the worker threads.
+//! The burn rate of a window (5m, 1h and 6h) is the ratio of bad replies in it divided by the
+//! error budget (1 - objective), so 1 spends the budget exactly by the end of the period of the
+//! objective whatever it is (e.g.: 30 days), and 14.4 over 1h spends 2% of a 30 days budget.
+//!
+//! the burn rates are published as gauges every 15 seconds, and listed by `PROXY SLO STATUS` and
+//! the admin api. The objectives are replaced by reload, and the buckets of the one whose command
+//! and latency are unchanged are kept.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::com::{ClusterConfig, SloConfig};
+use crate::metrics::{slo_burn_rate_remove, slo_burn_rate_set};
+use crate::proxy::standalone::Request;
+
+// minutes kept by the ring, the longest window
+const BUCKETS: u64 = 360;
+const PUBLISH_INTERVAL: u64 = 15;
+const SUB_CMD_SLO: &str = "SLO";
+const SUB_CMD_STATUS: &str = "STATUS";
+
+/// the rolling windows of burn rates by label and minutes.
+pub const WINDOWS: &[(&str, u64)] = &[("5m", 5), ("1h", 60), ("6h", 360)];
+
+lazy_static! {
+    static ref SLOS: Mutex<HashMap<String, Arc<Slo>>> = Mutex::new(HashMap::new());
+}
+
+pub fn validate(slo: &[SloConfig]) -> Result<(), String> {
+    let mut commands = Vec::with_capacity(slo.len());
+    for item in slo {
+        if item.command.is_empty() || item.command.contains(char::is_whitespace) {
+            return Err(format!("command '{}' is bad", item.command));
+        }
+        let command = item.command.to_uppercase();
+        if commands.contains(&command) {
+            return Err(format!("{}: command is duplicated", item.command));
+        }
+        if item.latency == 0 {
+            return Err(format!("{}: latency must be greater than 0", item.command));
+        }
+        if !(item.objective > 0.0 && item.objective < 100.0) {
+            return Err(format!(
+                "{}: objective must be a percent between 0 and 100",
+                item.command
+            ));
+        }
+        commands.push(command);
+    }
+    Ok(())
+}
+
+/// get the objectives of the cluster, which are replaced by the ones of cc.
+pub fn handle(cc: &ClusterConfig) -> Arc<Slo> {
+    let mut all = SLOS.lock().unwrap();
+    let slo = all
+        .entry(cc.name.clone())
+        .or_insert_with(|| Arc::new(Slo::new(&cc.name)))
+        .clone();
+    slo.update(&cc.slo);
+    if slo.is_enabled() && !slo.publishing.swap(true, Ordering::SeqCst) {
+        spawn_publisher(slo.clone());
+    }
+    slo
+}
+
+fn spawn_publisher(slo: Arc<Slo>) {
+    let cluster = slo.cluster.clone();
+    let spawned = thread::Builder::new()
+        .name(format!("aster-slo-{}", cluster))
+        .spawn(move || loop {
+            slo.publish();
+            thread::sleep(Duration::from_secs(PUBLISH_INTERVAL));
+        });
+    if let Err(err) = spawned {
+        warn!(
+            "cluster {} fail to publish slo burn rates due to {}",
+            cluster, err
+        );
+    }
+}
+
+/// the objectives of the cluster, None if it's not running.
+pub fn get(cluster: &str) -> Option<Arc<Slo>> {
+    SLOS.lock().unwrap().get(cluster).cloned()
+}
+
+/// the arguments after PROXY is SLO STATUS.
+pub fn is_status(args: &[String]) -> bool {
+    args.len() == 2
+        && args[0].eq_ignore_ascii_case(SUB_CMD_SLO)
+        && args[1].eq_ignore_ascii_case(SUB_CMD_STATUS)
+}
+
+fn now_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() / 60)
+        .unwrap_or(0)
+}
+
+#[derive(Default)]
+struct Bucket {
+    // the minute since epoch counted by the bucket
+    minute: AtomicU64,
+    good: AtomicU64,
+    bad: AtomicU64,
+}
+
+struct Ring {
+    buckets: Vec<Bucket>,
+}
+
+impl Ring {
+    fn new() -> Ring {
+        Ring {
+            buckets: (0..BUCKETS).map(|_| Bucket::default()).collect(),
+        }
+    }
+
+    // the bucket of the last turn is reset by the first reply of the minute, the few replies
+    // racing with the reset may be lost.
+    fn record(&self, minute: u64, good: bool) {
+        let bucket = &self.buckets[(minute % BUCKETS) as usize];
+        let current = bucket.minute.load(Ordering::Acquire);
+        if current > minute {
+            return;
+        }
+        if current < minute
+            && bucket
+                .minute
+                .compare_exchange(current, minute, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            bucket.good.store(0, Ordering::Release);
+            bucket.bad.store(0, Ordering::Release);
+        }
+        if good {
+            bucket.good.fetch_add(1, Ordering::Relaxed);
+        } else {
+            bucket.bad.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // the good and bad replies in the last minutes till now, the current minute included.
+    fn sum(&self, now: u64, minutes: u64) -> (u64, u64) {
+        self.buckets
+            .iter()
+            .filter(|x| {
+                let minute = x.minute.load(Ordering::Acquire);
+                minute <= now && minute + minutes > now
+            })
+            .fold((0, 0), |(good, bad), x| {
+                (
+                    good + x.good.load(Ordering::Relaxed),
+                    bad + x.bad.load(Ordering::Relaxed),
+                )
+            })
+    }
+}
+
+struct Objective {
+    // upper case
+    command: String,
+    latency: Duration,
+    objective: f64,
+    ring: Arc<Ring>,
+}
+
+impl Objective {
+    fn burn_rate(&self, now: u64, minutes: u64) -> f64 {
+        let (good, bad) = self.ring.sum(now, minutes);
+        if good + bad == 0 {
+            return 0.0;
+        }
+        let budget = 1.0 - self.objective / 100.0;
+        (bad as f64 / (good + bad) as f64) / budget
+    }
+}
+
+/// the burn rates of one objective by window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub command: String,
+    pub latency: Duration,
+    pub objective: f64,
+    pub burn_rates: Vec<(&'static str, f64)>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}ms {}%",
+            self.command,
+            self.latency.as_millis(),
+            self.objective
+        )?;
+        for (window, rate) in self.burn_rates.iter() {
+            write!(f, " {}={:.2}", window, rate)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Slo {
+    cluster: String,
+    objectives: RwLock<Vec<Objective>>,
+    // checked by each reply before the objectives are locked
+    enabled: AtomicBool,
+    publishing: AtomicBool,
+}
+
+impl Slo {
+    fn new(cluster: &str) -> Slo {
+        Slo {
+            cluster: cluster.to_string(),
+            objectives: RwLock::new(Vec::new()),
+            enabled: AtomicBool::new(false),
+            publishing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn update(&self, slo: &[SloConfig]) {
+        let mut objectives = self.objectives.write().unwrap();
+        let mut updated = Vec::with_capacity(slo.len());
+        for item in slo {
+            let command = item.command.to_uppercase();
+            let latency = Duration::from_millis(item.latency);
+            let ring = objectives
+                .iter()
+                .find(|x| x.command == command && x.latency == latency)
+                .map(|x| x.ring.clone())
+                .unwrap_or_else(|| Arc::new(Ring::new()));
+            updated.push(Objective {
+                command,
+                latency,
+                objective: item.objective,
+                ring,
+            });
+        }
+        for removed in objectives
+            .iter()
+            .filter(|x| updated.iter().all(|y| y.command != x.command))
+        {
+            for (window, _) in WINDOWS {
+                slo_burn_rate_remove(&self.cluster, &removed.command, window);
+            }
+        }
+        self.enabled.store(!updated.is_empty(), Ordering::Relaxed);
+        *objectives = updated;
+    }
+
+    /// count the reply of cmd by its objective if any, the ones never marked are ignored.
+    pub fn record<T: Request>(&self, cmd: &T) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Some(elapsed) = cmd.total_elapsed() {
+            self.record_at(now_minute(), &cmd.cmd_name(), elapsed, cmd.is_error());
+        }
+    }
+
+    fn record_at(&self, minute: u64, command: &str, elapsed: Duration, error: bool) {
+        let objectives = self.objectives.read().unwrap();
+        if let Some(objective) = objectives
+            .iter()
+            .find(|x| x.command.eq_ignore_ascii_case(command))
+        {
+            objective
+                .ring
+                .record(minute, !error && elapsed <= objective.latency);
+        }
+    }
+
+    /// the burn rates of all the objectives in the order of config.
+    pub fn status(&self) -> Vec<Status> {
+        self.status_at(now_minute())
+    }
+
+    fn status_at(&self, now: u64) -> Vec<Status> {
+        self.objectives
+            .read()
+            .unwrap()
+            .iter()
+            .map(|x| Status {
+                command: x.command.clone(),
+                latency: x.latency,
+                objective: x.objective,
+                burn_rates: WINDOWS
+                    .iter()
+                    .map(|(window, minutes)| (*window, x.burn_rate(now, *minutes)))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn publish(&self) {
+        for status in self.status() {
+            for (window, rate) in status.burn_rates {
+                slo_burn_rate_set(&self.cluster, &status.command, window, rate);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn objective(command: &str, latency: u64, objective: f64) -> SloConfig {
+        SloConfig {
+            command: command.to_string(),
+            latency,
+            objective,
+        }
+    }
+
+    fn rates(slo: &Slo, now: u64, i: usize) -> Vec<f64> {
+        slo.status_at(now)[i]
+            .burn_rates
+            .iter()
+            .map(|x| (x.1 * 100.0).round() / 100.0)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_slo() {
+        assert!(validate(&[objective("GET", 5, 99.0), objective("set", 10, 99.9)]).is_ok());
+        assert!(validate(&[objective("", 5, 99.0)]).is_err());
+        assert!(validate(&[objective("GET", 5, 99.0), objective("get", 10, 99.9)]).is_err());
+        assert!(validate(&[objective("GET", 0, 99.0)]).is_err());
+        assert!(validate(&[objective("GET", 5, 100.0)]).is_err());
+        assert!(validate(&[objective("GET", 5, 0.0)]).is_err());
+    }
+
+    #[test]
+    fn test_slo_burn_rate_windows() {
+        let slo = Slo::new("test-slo-windows");
+        slo.update(&[objective("GET", 5, 99.0)]);
+        let base = 1_000_000;
+        let fast = Duration::from_millis(1);
+        let slow = Duration::from_millis(6);
+        // 6 hours ago, 10% slow
+        for i in 0..100 {
+            let elapsed = if i < 10 { slow } else { fast };
+            slo.record_at(base, "GET", elapsed, false);
+        }
+        // the last minute, 2% error
+        let now = base + 359;
+        for i in 0..100 {
+            slo.record_at(now, "get", fast, i < 2);
+        }
+        // never counted by other commands
+        slo.record_at(now, "SET", slow, true);
+        assert_eq!(rates(&slo, now, 0), vec![2.0, 2.0, 6.0]);
+        // out of the 6h window once the ring turns
+        assert_eq!(rates(&slo, now + 1, 0), vec![2.0, 2.0, 2.0]);
+        assert_eq!(rates(&slo, now + 5, 0), vec![0.0, 2.0, 2.0]);
+        // the bucket of the last turn is reset by the new minute
+        slo.record_at(base + BUCKETS, "GET", fast, false);
+        assert_eq!(rates(&slo, base + BUCKETS, 0), vec![1.98, 1.98, 1.98]);
+        assert_eq!(
+            slo.status_at(now)[0].to_string(),
+            "GET 5ms 99% 5m=2.00 1h=2.00 6h=6.00"
+        );
+    }
+
+    #[test]
+    fn test_slo_reload() {
+        let slo = Slo::new("test-slo-reload");
+        assert!(!slo.is_enabled());
+        slo.update(&[objective("GET", 5, 99.0), objective("SET", 5, 99.0)]);
+        assert!(slo.is_enabled());
+        let now = 1_000_000;
+        slo.record_at(now, "GET", Duration::from_millis(6), false);
+        slo.record_at(now, "GET", Duration::from_millis(1), false);
+        slo.record_at(now, "SET", Duration::from_millis(6), false);
+
+        // the counts of GET are kept with the new objective, and the ones of SET are reset by
+        // the new latency
+        slo.update(&[objective("GET", 5, 90.0), objective("SET", 10, 99.0)]);
+        assert_eq!(rates(&slo, now, 0), vec![5.0, 5.0, 5.0]);
+        assert_eq!(rates(&slo, now, 1), vec![0.0, 0.0, 0.0]);
+
+        slo.update(&[]);
+        assert!(!slo.is_enabled());
+        assert!(slo.status_at(now).is_empty());
+    }
+}
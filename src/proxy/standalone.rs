@@ -1,19 +1,43 @@
+pub mod adaptive;
 pub mod back;
+pub mod barrier;
+pub mod commands;
+pub mod deadline;
+pub mod dedup;
+pub mod dialect;
+pub mod drain;
+pub mod dump;
+pub mod failover;
 pub mod fnv;
 pub mod front;
+pub mod hash;
+pub mod hint;
+pub mod idle;
+pub mod integrity;
 pub mod ketama;
+pub mod lifetime;
+pub mod nodes;
+pub mod pin;
 pub mod ping;
 pub mod reload;
+pub mod respcache;
+pub mod retire;
+pub mod retry;
+pub mod slowstart;
+pub mod subfail;
+pub mod tick;
+pub mod tombstone;
+pub mod transition;
 
+use bytes::{Bytes, BytesMut};
 use futures::future::ok;
 use futures::lazy;
 use futures::task::Task;
-use futures::unsync::mpsc::{channel, Sender};
+use futures::unsync::mpsc::{channel, unbounded, Sender, UnboundedSender};
 use futures::{AsyncSink, Future, Sink, Stream};
 
 use tokio::codec::{Decoder, Encoder};
 use tokio::net::TcpStream;
-use tokio::prelude::FutureExt;
 use tokio::runtime::current_thread;
 
 use std::cell::{Cell, RefCell};
@@ -21,24 +45,72 @@ use std::collections::{HashMap, HashSet, VecDeque};
 use std::marker::PhantomData;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::thread::{Builder, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::protocol::{mc, redis};
+#[cfg(feature = "memcache")]
+use crate::protocol::mc;
+#[cfg(feature = "redis")]
+use crate::protocol::redis;
 
-use crate::metrics::{front_conn_incr, thread_incr};
+use crate::metrics::{backend_recycled_incr, client_deadline_expired_incr};
+use crate::metrics::{front_conn_incr, idle_eviction_incr};
+use crate::metrics::{keyless_incr, queue_timeout_incr, tombstone_incr};
+use crate::metrics::{listener_conn_incr, thread_incr};
+use crate::metrics::{reload_drain_failed_incr, reload_drain_inflight_add, reload_drain_observe};
 
 use crate::com::meta::meta_init;
 use crate::com::AsError;
-use crate::com::{create_reuse_port_listener, set_read_write_timeout};
+use crate::com::{connect_backend, create_reuse_port_listener, set_keepalive};
+use crate::com::{set_read_write_timeout, BackendFlavor, BackendOverload, BlockingCommands};
 use crate::com::{CacheType, ClusterConfig};
-use crate::protocol::IntoReply;
+use crate::com::{FrontProtocol, KeylessPolicy, ListenerConfig};
+use crate::protocol::{IntoReply, ReplyMerge};
+use crate::proxy::accept::Accept;
+use crate::proxy::accesslog::{self, AccessLog};
+use crate::proxy::acl::{Acl, AclReply, Category};
+use crate::proxy::budget::{self, Budget};
+use crate::proxy::capture::{self, Capture};
+use crate::proxy::clients::{self, Clients};
+use crate::proxy::fault::{self, Injector};
+use crate::proxy::hook::{self, Hooks};
+use crate::proxy::info;
+use crate::proxy::link::{Link, LinkOptions};
+use crate::proxy::maintenance;
+use crate::proxy::memory::{self, Memory, Meter, Metered};
+use crate::proxy::monitor::{self, Monitor};
+use crate::proxy::probe::{self, Probe};
+use crate::proxy::readonly;
+use crate::proxy::ringdiff;
+use crate::proxy::shard::{self, Position, Role, Shard};
+use crate::proxy::slo::{self, Slo};
+use crate::proxy::worker::{Control, Worker};
+use crate::utils::trim_hash_tag;
 
-use fnv::fnv1a64;
+use adaptive::{Bounds, Estimator};
+use deadline::CommandTimeouts;
+use dedup::Dedup;
+use drain::NodeState;
+use failover::{Eject, Standby};
+use hash::HashMethod;
+use hint::RouteHint;
+use integrity::Integrity;
 use ketama::HashRing;
+use pin::Pins;
+use respcache::{Lookup, RespCache, Ticket};
+use retire::Progress;
+use slowstart::SlowStart;
+use subfail::{SubFailure, SubFailures};
+use tick::{Clock, Event};
+use tombstone::{Tombstone, Tombstones};
+use transition::Transition;
+
+const CTRL_CHANNEL_SIZE: usize = 64;
 
 pub trait Request: Clone {
-    type Reply: Clone + IntoReply<Self::Reply> + From<AsError>;
+    type Reply: Clone + IntoReply<Self::Reply> + From<AsError> + ReplyMerge;
 
     type FrontCodec: Decoder<Item = Self, Error = AsError>
         + Encoder<Item = Self, Error = AsError>
@@ -50,64 +122,419 @@ pub trait Request: Clone {
         + 'static;
 
     fn ping_request() -> Self;
+
+    // the done command replied by the status line without request, e.g.: the events streamed
+    // by PROXY MONITOR.
+    fn status_reply(line: &str) -> Self;
+    // codec of the connections of backend, the node is its address.
+    fn back_codec(cc: &ClusterConfig, node: &str) -> Self::BackCodec;
+    // codec of the clients of listener, the protocol is only given to memcache.
+    fn front_codec(
+        cc: &ClusterConfig,
+        protocol: Option<FrontProtocol>,
+        client: &str,
+    ) -> Self::FrontCodec;
     fn reregister(&mut self, task: Task);
 
-    fn key_hash(&self, hash_tag: &[u8], hasher: fn(&[u8]) -> u64) -> u64;
+    // return None for the command without key (e.g.: version), which is routed by
+    // keyless_policy instead.
+    fn key_hash(&self, hash_tag: &[u8], hasher: HashMethod) -> Option<u64>;
+
+    // the command has no key by the command table (e.g.: version of memcache), rather than
+    // an empty key found at hash time.
+    fn is_keyless(&self) -> bool;
+
+    // reply the keyless command by proxy itself, return false if it's not implemented.
+    fn reply_keyless(&self) -> bool;
 
     fn subs(&self) -> Option<Vec<Self>>;
 
     fn mark_total(&self, cluster: &str);
 
     fn mark_remote(&self, cluster: &str);
+    // the time since mark_total, None if it's never marked (e.g.: the probes).
+    fn total_elapsed(&self) -> Option<Duration>;
 
     fn is_done(&self) -> bool;
     fn is_error(&self) -> bool;
 
+    // the approximate bytes of reply sent to client, counted by output buffer limit.
+    fn reply_size(&self) -> usize;
+
     fn add_cycle(&self);
     fn can_cycle(&self) -> bool;
 
+    // mark the command retried on a stale connection, return false if it's retried already
+    // or can't cycle any more.
+    fn mark_retry(&self) -> bool;
+
+    // the reply is compatible with the shape of the command (e.g.: GET never gets +OK), which
+    // finds out the replies shifted by a misbehaving backend.
+    fn accept_reply(&self, reply: &Self::Reply) -> bool;
+
     fn valid(&self) -> bool;
 
+    // reply QUIT by proxy itself, return false if it's not QUIT. The connection is closed after
+    // all the replies before it are flushed.
+    fn reply_quit(&self) -> bool;
+
+    // reply the read by the tombstone as if the key is absent, return false if the command
+    // has no such reply (e.g.: the binary get of memcache), see standalone::tombstone.
+    fn reply_tombstone(&self, tombstone: Tombstone) -> bool;
+
+    // checksum of the reply of the single key read checked by integrity_sample, None for the
+    // others (e.g.: writes, the parent of subs).
+    fn reply_checksum(&self) -> Option<u64>;
+    // the same read sent again by integrity_sample, see integrity.
+    fn integrity_replay(&self) -> Option<Self>;
+
     fn set_reply<R: IntoReply<Self::Reply>>(&self, t: R);
     fn set_error(&self, t: &AsError);
+
+    // CmdType::Ctrl command generated by proxy itself (e.g.: ping) is sent
+    // ahead of client traffic and never counted against the pipeline limit.
+    fn is_ctrl(&self) -> bool;
+
+    // command may change the data of backend, which is rejected in read-only mode.
+    fn is_mutation(&self) -> bool;
+
+    // command only reads the data of backend (e.g.: GET, MGET), which may be served by replica.
+    fn is_read(&self) -> bool;
+
+    // command writes the data of backend (e.g.: SET, DEL), which must be served by master.
+    // scripts and keyless commands are neither read nor write.
+    fn is_write(&self) -> bool;
+
+    // administrative command (e.g.: FAILOVER) which is denied unless admin_node is set.
+    fn is_admin(&self) -> bool;
+
+    // the name of command may break the whole backend (e.g.: SHUTDOWN), which is denied unless
+    // given by allow_dangerous.
+    fn dangerous_name(&self) -> Option<String>;
+
+    // command may block the backend connection until replied (e.g.: BLPOP), see
+    // blocking_commands.
+    fn is_blocking(&self) -> bool;
+
+    // subs of multi-key command are dispatched in waves of batch size, the next wave is
+    // released only after the former one is done. return None if nothing can be released.
+    fn next_wave(&self, batch: usize) -> Option<Vec<Self>>;
+
+    // some of the subs are not released yet.
+    fn has_wave(&self) -> bool;
+
+    // the routing key starts with the prefix, false for the command without key.
+    fn has_key_prefix(&self, prefix: &[u8]) -> bool;
+
+    // apply f to the routing key, None for the command without key.
+    fn with_key<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R;
+
+    // the BY/GET pattern of SORT which may reference the keys on other nodes.
+    fn sort_pattern(&self, hash_tag: &[u8]) -> Option<Vec<u8>>;
+
+    // the source and destination keys of two-key command (e.g.: SMOVE) hash the same, or it's
+    // rejected as cross slot.
+    fn check_two_keys(&self, hash_tag: &[u8], hasher: HashMethod) -> Result<(), AsError>;
+
+    // cap the timeout of blocking command by max seconds, return the timeout given by client
+    // if it's capped. The keys hashed differently are rejected.
+    fn bound_timeout(
+        &self,
+        hash_tag: &[u8],
+        hasher: HashMethod,
+        max: u64,
+    ) -> Result<Option<String>, AsError>;
+
+    // reply the PROXY command (e.g.: PROXY ADDNODE) by the result of f with its arguments, which
+    // is OK or the bulk strings given (e.g.: PROXY SHARD), return false if it's not a PROXY command.
+    fn handle_proxy<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<Option<Vec<String>>, AsError>;
+
+    // the timeout of PROXY BARRIER, None if it's not a barrier, see standalone::barrier.
+    fn barrier_timeout(&self) -> Option<Result<u64, AsError>>;
+
+    // reply PROXY BARRIER by the count of backends synced.
+    fn set_synced(&self, count: usize);
+
+    // the hint of PROXY ROUTE, None if it's not a route hint, see standalone::hint.
+    fn route_hint(&self) -> Option<Result<RouteHint, AsError>>;
+
+    // the backend address the command (and its subs) is pinned to by PROXY ROUTE, which is
+    // routed to regardless of its key.
+    fn set_pinned(&self, addr: &str);
+    fn pinned(&self) -> Option<String>;
+
+    // split the command broadcast to every backend (e.g.: FUNCTION LOAD) into the subs pinned
+    // to each address, return false if it's never broadcast.
+    fn broadcast(&self, addrs: &[String]) -> bool;
+
+    // reply CLIENT KILL by the number of connections killed by f with its filters, return false
+    // if it's not CLIENT KILL.
+    fn handle_client_kill<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<usize, AsError>;
+
+    // reply INFO by the text made by f with its sections, return false if it's not INFO.
+    fn handle_info<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> String;
+
+    // the category of command and every key of it checked against the users, see proxy::acl.
+    fn acl_category(&self) -> Category;
+    fn acl_keys(&self) -> Vec<Vec<u8>>;
+
+    // reply AUTH or ACL by the result of f with its arguments (with the command name), return
+    // false if it's neither.
+    fn handle_acl<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&[String]) -> Result<AclReply, AsError>;
+
+    // raw request received from client, which is recorded by traffic capture.
+    fn req_data(&self) -> Bytes;
+
+    // save the reply the same as sent to client without consuming it.
+    fn reply_data(&self, buf: &mut BytesMut);
+
+    // name of the command as sent by client, e.g.: SET or set.
+    fn cmd_name(&self) -> String;
+
+    // the keys recorded by access log, which are the keys of subs for multi-key command.
+    fn keys(&self) -> Vec<Vec<u8>>;
+
+    // the backend node dispatched to, which is recorded by access log.
+    fn set_node(&self, node: &str);
+    fn node(&self) -> Option<String>;
+
+    // the timeout of the backend connection in effect when dispatched, which is recorded by
+    // access log as well, see adaptive.
+    fn set_timeout(&self, timeout: Duration);
+    fn timeout(&self) -> Option<Duration>;
+
+    // the time limit by command_timeouts chosen at dispatch, the command not replied in time
+    // since sent is failed by CommandTimeout, see deadline.
+    fn set_time_limit(&self, limit: Duration);
+    fn time_limit(&self) -> Option<Duration>;
+
+    // the time the command is queued in front for dispatch, which is cleared once it's sent to
+    // backend. It's failed by QueueTimeout if it waits longer than queue_timeout.
+    fn set_queued(&self, since: Option<Instant>);
+    fn queued(&self) -> Option<Instant>;
+
+    // reply ASTER DEADLINE after f with its budget, return false if it's not an ASTER command.
+    fn handle_deadline<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(Duration);
+
+    // the deadline of ASTER DEADLINE given before the command (and its subs), which is taken
+    // instead of command_timeouts, see deadline.
+    fn set_deadline(&self, deadline: Instant);
+    fn deadline(&self) -> Option<Instant>;
+
+    // the tick of cluster when decoded, which identifies the request (and its subs) in trace
+    // log, 0 if it's not traced, see tick.
+    fn set_trace_id(&self, id: u64);
+    fn trace_id(&self) -> u64;
+
+    // the policy of the subs failed by proxy (e.g.: unreachable backend) given by
+    // sub_failures, which is ignored by the commands without subs, see subfail.
+    fn set_sub_failure(&self, policy: SubFailure);
+    // the failed subs reset to be dispatched again under the retry policy, each sub is retried
+    // at most once within the retry budget. Empty if there's none to retry, then the command is
+    // merged as fail_fast.
+    fn retry_failed_subs(&self, budget: &Budget) -> Vec<Self>;
+
+    // the reply set by backend or proxy, None if it's not done.
+    fn reply(&self) -> Option<Self::Reply>;
+
+    // the backend replied that it can't serve for now (e.g.: LOADING), see proxy::compat.
+    fn is_unavailable(&self, flavor: BackendFlavor) -> bool;
+
+    // the reply of ping_request is exactly the expected one (e.g.: +PONG of redis or VERSION
+    // line of memcache), rather than any bytes echoed by a wedged backend.
+    fn is_pong(&self) -> bool;
 }
 
 pub struct Cluster<T> {
     pub cc: RefCell<ClusterConfig>,
     hash_tag: Vec<u8>,
+    hash: HashMethod,
     spots: RefCell<HashMap<String, usize>>,
     alias: RefCell<HashMap<String, String>>,
 
     _marker: PhantomData<T>,
     ring: RefCell<HashRing>,
     conns: RefCell<Conns<T>>,
+    // connections of the backends removed by reload, kept until the commands in flight are done
+    retired: RefCell<Vec<Retired<T>>>,
     pings: RefCell<HashMap<String, Rc<Cell<bool>>>>,
+    // count of connections closed by the violations of reply framing by address, shared with
+    // the backs of each connection and reset once the node is ejected
+    violations: RefCell<HashMap<String, Rc<Cell<usize>>>>,
+    keyless: Cell<usize>,
+    standby: RefCell<Standby>,
+    read_only: Arc<AtomicBool>,
+    maintenance: Arc<AtomicBool>,
+    // nodes which is not active, synced from admin state by the drain checker
+    drains: RefCell<HashMap<String, NodeState>>,
+    slow_start: RefCell<SlowStart>,
+    // weights changed by reload in transition and the step applied to ring
+    transition: RefCell<Option<(Transition, u64)>>,
+    // keys pinned to fixed nodes, evaluated before hashing
+    pins: RefCell<Pins>,
+    // the reads to ejected nodes replied by tombstone, reset by reload
+    tombstones: RefCell<Tombstones>,
+    // time limits of commands by name, reset by reload
+    timeouts: RefCell<CommandTimeouts>,
+    // policies of the fan-out commands by name when any sub fails, reset by reload
+    sub_failures: RefCell<SubFailures>,
+    // the reads sampled for integrity checks, reset by reload
+    integrity: RefCell<Integrity>,
+    // connections of the replays of integrity checks, apart from the ones of clients
+    replays: RefCell<HashMap<String, Conn<Sender<T>>>>,
+    // commands of stale connections sent to retry, set once the retry is spawned
+    retry: RefCell<Option<UnboundedSender<T>>>,
+    pub(crate) capture: Capture,
+    // logical clock of the events of requests in trace log
+    pub(crate) clock: Clock,
+    pub(crate) access_log: AccessLog,
+    pub(crate) fault: Injector,
+    pub(crate) hooks: Hooks<T>,
+    pub(crate) dedup: RefCell<Dedup<T>>,
+    // users of the clients, reset by reload
+    pub(crate) acl: RefCell<Acl>,
+    // replies of hot reads cached by response_cache, reset by reload
+    cache: RefCell<RespCache<T::Reply>>,
+    pub(crate) memory: Rc<Memory>,
+    pub(crate) clients: Arc<Clients>,
+    pub(crate) monitor: Arc<Monitor>,
+    pub(crate) probe: Arc<Probe>,
+    // latency objectives shared by the workers, replaced by reload
+    pub(crate) slo: Arc<Slo>,
+    // retries of all kinds shared by the workers, replaced by reload
+    pub(crate) budget: Arc<Budget>,
+    pub(crate) worker: Rc<Worker>,
 }
 
 impl<T: Request + 'static> Cluster<T> {
-    pub(crate) fn run(cc: ClusterConfig) -> Result<(), AsError> {
-        let addr = cc
-            .listen_addr
-            .parse::<SocketAddr>()
-            .expect("parse socket never fail");
+    fn new(cc: &ClusterConfig, worker: Rc<Worker>) -> Cluster<T> {
+        let hash_tag = cc.hash_tag();
+        let standby = Standby::new(cc).expect("fail to setup standby");
+        let read_only = readonly::handle(cc);
+        let maintenance = maintenance::handle(&cc.name);
+        let capture = capture::handle(cc);
+        let access_log = accesslog::handle(cc);
+        let fault = fault::handle(cc);
+        let hooks = hook::handle(cc);
+        let memory = Rc::new(memory::handle(cc));
+        Cluster {
+            cc: RefCell::new(cc.clone()),
+            hash_tag,
+            hash: cc.hash.unwrap_or_default(),
+            spots: RefCell::new(HashMap::new()),
+            alias: RefCell::new(HashMap::new()),
+            _marker: Default::default(),
+            ring: RefCell::new(HashRing::empty()),
+            conns: RefCell::new(Conns::default()),
+            retired: RefCell::new(Vec::new()),
+            pings: RefCell::new(HashMap::new()),
+            violations: RefCell::new(HashMap::new()),
+            keyless: Cell::new(0),
+            standby: RefCell::new(standby),
+            read_only,
+            maintenance,
+            drains: RefCell::new(HashMap::new()),
+            slow_start: RefCell::new(SlowStart::default()),
+            transition: RefCell::new(None),
+            pins: RefCell::new(Pins::default()),
+            tombstones: RefCell::new(Tombstones::default()),
+            timeouts: RefCell::new(CommandTimeouts::default()),
+            sub_failures: RefCell::new(SubFailures::default()),
+            integrity: RefCell::new(Integrity::default()),
+            replays: RefCell::new(HashMap::new()),
+            retry: RefCell::new(None),
+            capture,
+            clock: tick::handle(&cc.name),
+            access_log,
+            fault,
+            hooks,
+            dedup: RefCell::new(Dedup::default()),
+            acl: RefCell::new(Acl::new(&cc.users)),
+            cache: RefCell::new(RespCache::default()),
+            memory,
+            clients: clients::handle(cc),
+            monitor: monitor::handle(cc),
+            slo: slo::handle(cc),
+            budget: budget::handle(cc),
+            probe: probe::handle(cc),
+            worker,
+        }
+    }
+
+    /// the window of deduplication if cmd is one of dedup_writes.
+    pub(crate) fn dedup_window(&self, cmd: &T) -> Option<Duration> {
+        let cc = self.cc.borrow();
+        if cc.dedup_writes.is_empty() || !cmd.is_mutation() || cmd.subs().is_some() {
+            return None;
+        }
+        let name = cmd.cmd_name();
+        if !cc
+            .dedup_writes
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(&name))
+        {
+            return None;
+        }
+        Some(Duration::from_millis(cc.dedup_window()))
+    }
+
+    /// the cached reply of the read matched response_cache, the writes drop the cached replies
+    /// of their keys and are never cached.
+    pub(crate) fn cache_lookup(&self, cmd: &T) -> Lookup<T::Reply> {
+        if cmd.is_mutation() {
+            self.cache_invalidate(cmd);
+            return Lookup::Pass;
+        }
+        let mut cache = self.cache.borrow_mut();
+        if cache.is_empty() || cmd.is_ctrl() || cmd.is_admin() || cmd.subs().is_some() {
+            return Lookup::Pass;
+        }
+        let name = cmd.cmd_name().to_uppercase();
+        cmd.with_key(|key| cache.lookup(&name, key, Instant::now()))
+            .unwrap_or(Lookup::Pass)
+    }
+
+    /// drop the cached replies of all the keys of the write.
+    pub(crate) fn cache_invalidate(&self, cmd: &T) {
+        let mut cache = self.cache.borrow_mut();
+        if cache.is_empty() || !cmd.is_mutation() {
+            return;
+        }
+        for sub in cmd.subs().unwrap_or_else(|| vec![cmd.clone()]) {
+            sub.with_key(|key| cache.invalidate(key));
+        }
+    }
+
+    /// cache the reply of the read looked up as missed, the error replies are never cached.
+    pub(crate) fn cache_store(&self, ticket: Ticket, cmd: &T) {
+        let reply = match cmd.reply() {
+            Some(reply) => reply,
+            None => return,
+        };
+        if cmd.is_error() || reply.is_error_reply() {
+            return;
+        }
+        self.cache
+            .borrow_mut()
+            .store(ticket, reply, cmd.reply_size(), Instant::now());
+    }
+
+    pub(crate) fn run(cc: ClusterConfig, worker: Rc<Worker>) -> Result<(), AsError> {
         let fut = ok::<ClusterConfig, AsError>(cc)
             .and_then(|cc| {
-                let hash_tag = cc
-                    .hash_tag
-                    .as_ref()
-                    .map(|x| x.as_bytes().to_vec())
-                    .unwrap_or_else(|| vec![]);
-                let cluster = Cluster {
-                    cc: RefCell::new(cc.clone()),
-                    hash_tag,
-                    spots: RefCell::new(HashMap::new()),
-                    alias: RefCell::new(HashMap::new()),
-                    _marker: Default::default(),
-                    ring: RefCell::new(HashRing::empty()),
-                    conns: RefCell::new(Conns::default()),
-                    pings: RefCell::new(HashMap::new()),
-                };
-                let rc_cluster = Rc::new(cluster);
+                let rc_cluster = Rc::new(Cluster::new(&cc, worker));
                 rc_cluster.reinit(cc).expect("fail to setup cluster");
                 Ok(rc_cluster)
             })
@@ -137,55 +564,382 @@ impl<T: Request + 'static> Cluster<T> {
                 let rc_cluster = cluster.clone();
                 let reloader = reload::Reloader::new(rc_cluster);
                 current_thread::spawn(reloader);
+                let failover = failover::Failover::new(Rc::downgrade(&cluster));
+                current_thread::spawn(failover);
+                let name = cluster.cc.borrow().name.clone();
+                let drain = drain::Drain::new(Rc::downgrade(&cluster), &name);
+                current_thread::spawn(drain);
+                let ramp = slowstart::Ramp::new(Rc::downgrade(&cluster));
+                current_thread::spawn(ramp);
+                let evictor = idle::Evictor::new(Rc::downgrade(&cluster));
+                current_thread::spawn(evictor);
+                let retirer = retire::Retirer::new(Rc::downgrade(&cluster));
+                current_thread::spawn(retirer);
+                let shift = transition::Shift::new(Rc::downgrade(&cluster));
+                current_thread::spawn(shift);
+                let (tx, rx) = unbounded();
+                cluster.retry.replace(Some(tx));
+                let retry = retry::Retry::new(Rc::downgrade(&cluster), rx);
+                current_thread::spawn(retry);
+                current_thread::spawn(cluster.memory.guard());
                 Ok(cluster)
             })
             .and_then(|cluster| {
-                let rc_cluster = cluster.clone();
-                let listen = create_reuse_port_listener(&addr).expect("bind never fail");
-                let service = listen
-                    .incoming()
-                    .for_each(move |sock| {
-                        let cluster_ref = cluster.clone();
-                        if let Err(err) = sock.set_nodelay(true) {
-                            warn!(
-                                "cluster {} fail to set nodelay but skip, due to {:?}",
-                                cluster_ref.cc.borrow().name,
-                                err
-                            );
-                        }
-                        let client_str = match sock.peer_addr() {
-                            Ok(client) => format!("{}", client),
-                            Err(err) => {
-                                error!(
-                                    "cluster {} fail to get client name due to {:?}",
-                                    cluster_ref.cc.borrow().name,
-                                    err
-                                );
-                                "unknown".to_string()
-                            }
-                        };
-
-                        let codec = T::FrontCodec::default();
-                        let (output, input) = codec.framed(sock).split();
-
-                        front_conn_incr(&cluster.cc.borrow().name);
-                        let fut = front::Front::new(client_str, cluster_ref, input, output);
-                        current_thread::spawn(fut);
-                        Ok(())
-                    })
-                    .map_err(|err| {
-                        error!("fail to accept incoming sock due {}", err);
-                    });
-                current_thread::spawn(service);
-                Ok(rc_cluster)
+                let listeners = cluster.cc.borrow().all_listeners();
+                for listener in listeners {
+                    Cluster::serve(&cluster, listener);
+                }
+                cluster.worker.listening();
+                Ok(cluster)
             })
             .map_err(|err| {
                 error!("fail to start proxy service... due {:?}", err);
             });
-        current_thread::block_on_all(fut).unwrap();
+        let mut rt = current_thread::Runtime::new()?;
+        rt.block_on(fut.and_then(|cluster| cluster.worker.drain()))
+            .unwrap();
+        Ok(())
+    }
+
+    // accept the clients of the listener, which share the backends with the other listeners.
+    fn serve(cluster: &Rc<Cluster<T>>, listener: ListenerConfig) {
+        let addr = listener
+            .listen_addr
+            .parse::<SocketAddr>()
+            .expect("parse socket never fail");
+        let listen = create_reuse_port_listener(&addr).expect("bind never fail");
+        let cluster = cluster.clone();
+        let rc_cluster = cluster.clone();
+        let accept = Accept::new(&cluster.cc.borrow(), listen);
+        let service = accept
+            .for_each(move |sock| {
+                let cluster_ref = cluster.clone();
+                if cluster_ref.memory.is_over() {
+                    warn!(
+                        "cluster {} refuse connection {:?} due to max memory exceeded",
+                        cluster_ref.cc.borrow().name,
+                        sock.peer_addr().ok()
+                    );
+                    return Ok(());
+                }
+                if let Err(err) = sock.set_nodelay(true) {
+                    warn!(
+                        "cluster {} fail to set nodelay but skip, due to {:?}",
+                        cluster_ref.cc.borrow().name,
+                        err
+                    );
+                }
+                let client_str = match sock.peer_addr() {
+                    Ok(client) => format!("{}", client),
+                    Err(err) => {
+                        error!(
+                            "cluster {} fail to get client name due to {:?}",
+                            cluster_ref.cc.borrow().name,
+                            err
+                        );
+                        "unknown".to_string()
+                    }
+                };
+
+                // the links from other aster are labeled by their addresses without port
+                let peer = client_str.rsplitn(2, ':').last().unwrap_or_default();
+                let sock = Link::accept(
+                    sock,
+                    LinkOptions::from_config(&cluster_ref.cc.borrow()),
+                    &cluster_ref.cc.borrow().name,
+                    peer,
+                );
+
+                let meter = cluster_ref.memory.front_meter();
+                let codec =
+                    T::front_codec(&cluster_ref.cc.borrow(), listener.protocol, &client_str);
+                let codec = Metered::new(codec, meter.clone());
+                let (output, input) = codec.framed(sock).split();
+
+                // connections of self probe are excluded from the counters of clients
+                let probe = cluster_ref.probe.is_probe(&client_str);
+                if !probe {
+                    front_conn_incr(&cluster.cc.borrow().name);
+                    listener_conn_incr(&cluster.cc.borrow().name, &listener.name);
+                }
+                let fut = front::Front::new(client_str, cluster_ref, input, output)
+                    .meter(meter)
+                    .listener(&listener)
+                    .probe(probe);
+                current_thread::spawn(fut);
+                Ok(())
+            })
+            .map_err(|err| {
+                error!("fail to accept incoming sock due {}", err);
+            });
+        // stop accepting once closing or the clients are draining
+        let closed = rc_cluster
+            .worker
+            .closed()
+            .select(rc_cluster.clients.draining())
+            .map(|_| ())
+            .map_err(|_| ());
+        current_thread::spawn(service.select(closed).map(|_| ()).map_err(|_| ()));
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        readonly::is_read_only(&self.read_only)
+    }
+
+    pub(crate) fn is_maintenance(&self) -> bool {
+        maintenance::is_maintenance(&self.maintenance)
+    }
+
+    pub(crate) fn allow_admin(&self) -> bool {
+        self.cc.borrow().admin_node.is_some()
+    }
+
+    /// broadcast FUNCTION LOAD and the like to every backend of servers if function_broadcast
+    /// is enabled, whose replies are merged as the first error or the first reply.
+    pub(crate) fn broadcast(&self, cmd: &T) -> bool {
+        if !self.cc.borrow().function_broadcast.unwrap_or(false) {
+            return false;
+        }
+        let mut addrs: Vec<_> = self.nodes().into_iter().map(|x| x.1).collect();
+        addrs.sort();
+        cmd.broadcast(&addrs)
+    }
+
+    pub(crate) fn proxy_command(&self, args: &[String]) -> Result<Option<Vec<String>>, AsError> {
+        // read only, so it's answered even if proxy_admin is disabled
+        if let Some(key) = shard::shard_key(args) {
+            return Ok(Some(self.shard(key?.as_bytes()).fields()));
+        }
+        if nodes::is_nodes(args) {
+            return Ok(Some(self.node_states()));
+        }
+        if commands::is_commands(args) {
+            return Ok(Some(commands::table(
+                &self.cc.borrow(),
+                self.is_read_only(),
+            )));
+        }
+        if slo::is_status(args) {
+            return Ok(Some(
+                self.slo.status().iter().map(|x| x.to_string()).collect(),
+            ));
+        }
+        if let Some(rslt) = ringdiff::ring_diff_args(args) {
+            let (samples, servers) = rslt?;
+            let keys = ringdiff::synthetic_keys(samples);
+            let diff = ringdiff::diff(&self.cc.borrow(), servers, keys)?;
+            return Ok(Some(diff.lines()));
+        }
+        if dump::is_dump(args) {
+            return dump::dump(&self.cc.borrow()).map(Some);
+        }
+        nodes::handle(&self.cc.borrow(), args).map(|_| None)
+    }
+
+    /// the reply of INFO, whose backends are the nodes with their states.
+    pub(crate) fn info(&self, args: &[String]) -> String {
+        let backends: Vec<_> = self
+            .nodes_with_state()
+            .into_iter()
+            .map(|(name, addr, state)| {
+                format!(
+                    "name={},addr={},state={}",
+                    name,
+                    addr,
+                    state.replace(' ', "_")
+                )
+            })
+            .collect();
+        info::reply(&self.cc.borrow(), args, &backends)
+    }
+
+    /// the state of each backend in the order of servers, e.g.: `redis-1 127.0.0.1:6379 active`
+    /// or `redis-2 127.0.0.1:6380 ejected protocol violation`.
+    pub(crate) fn node_states(&self) -> Vec<String> {
+        self.nodes_with_state()
+            .into_iter()
+            .map(|(name, addr, state)| format!("{} {} {}", name, addr, state))
+            .collect()
+    }
+
+    // the name, address and state of each backend in the order of servers.
+    fn nodes_with_state(&self) -> Vec<(String, String, String)> {
+        let sls = match ServerLine::parse_servers(&self.cc.borrow().servers) {
+            Ok(sls) => sls,
+            Err(_) => return Vec::new(),
+        };
+        let standby = self.standby.borrow();
+        let drains = self.drains.borrow();
+        sls.iter()
+            .map(|sl| {
+                let name = sl.name();
+                let state = match standby.ejected_for(&name) {
+                    Some(reason) => format!("ejected {}", reason.as_str()),
+                    None => drains
+                        .get(&name)
+                        .cloned()
+                        .unwrap_or(NodeState::Active)
+                        .as_str()
+                        .to_string(),
+                };
+                (name, sl.addr.clone(), state)
+            })
+            .collect()
+    }
+
+    /// the address of backend named (by alias or address) by PROXY ROUTE, error if it's not a
+    /// healthy node of the live topology.
+    pub(crate) fn check_route(&self, node: &str) -> Result<String, AsError> {
+        let sls = ServerLine::parse_servers(&self.cc.borrow().servers)?;
+        let sl = sls
+            .iter()
+            .find(|sl| sl.name() == node || sl.addr == node)
+            .ok_or_else(|| AsError::BadProxyCommand(format!("unknown node {}", node)))?;
+        let name = sl.name();
+        if let Some(reason) = self.standby.borrow().ejected_for(&name) {
+            return Err(AsError::BadProxyCommand(format!(
+                "node {} is ejected {}",
+                node,
+                reason.as_str()
+            )));
+        }
+        if !self.is_routable(&name) {
+            let state = self.drains.borrow().get(&name).cloned();
+            return Err(AsError::BadProxyCommand(format!(
+                "node {} is {}",
+                node,
+                state.unwrap_or(NodeState::Active).as_str()
+            )));
+        }
+        Ok(sl.addr.clone())
+    }
+
+    /// the routing details of key, the same as the command of key is routed by route.
+    pub(crate) fn shard(&self, key: &[u8]) -> Shard {
+        let hash_tag = trim_hash_tag(key, &self.hash_tag);
+        let hash = self.hash.hash(hash_tag);
+        let mut shard = Shard {
+            hash,
+            hash_tag: hash_tag.to_vec(),
+            position: Position::Ring(None),
+            node: None,
+            addr: None,
+            role: Role::Master,
+        };
+        if let Some(addr) = self.pins.borrow().get(key) {
+            shard.node = Some(self.node_name(addr));
+            shard.addr = Some(addr.to_string());
+            return shard;
+        }
+
+        let standby = self.standby.borrow();
+        if standby.is_active() {
+            let addr = standby.get_node(hash).map(|x| x.to_string());
+            shard.node = addr.clone();
+            shard.addr = addr;
+            shard.role = Role::Standby;
+            return shard;
+        }
+
+        let ring = self.ring.borrow();
+        let slow = self.slow_start.borrow();
+        // the keyless commands are never routed to the ejected nodes kept for the tombstones
+        let accept = |name: &str| {
+            self.is_routable(name) && (key_hash.is_some() || !standby.is_ejected(name))
+        };
+        if let Some((point, name)) = ring
+            .get_point_with(hash, |x| accept(x) && slow.accept(x, hash))
+            .or_else(|| ring.get_point_with(hash, accept))
+        {
+            shard.position = Position::Ring(Some(point));
+            shard.node = Some(name.to_string());
+            shard.addr = Some(self.get_node(name.to_string()));
+        }
+        shard
+    }
+
+    // the alias of backend address, or the address itself without alias.
+    fn node_name(&self, addr: &str) -> String {
+        self.alias
+            .borrow()
+            .iter()
+            .find(|x| x.1 == addr)
+            .map(|x| x.0.clone())
+            .unwrap_or_else(|| addr.to_string())
+    }
+
+    pub(crate) fn check_dangerous(&self, cmd: &T) -> Result<(), AsError> {
+        let name = match cmd.dangerous_name() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let cc = self.cc.borrow();
+        if cc.allows_dangerous(&name) {
+            return Ok(());
+        }
+        warn!("deny dangerous command {} in cluster {}", name, cc.name);
+        Err(AsError::Dangerous(name))
+    }
+
+    pub(crate) fn check_blocking(&self, cmd: &T) -> Result<(), AsError> {
+        if !cmd.is_blocking() {
+            return Ok(());
+        }
+        let cc = self.cc.borrow();
+        let policy = cc.blocking_commands.unwrap_or_default();
+        policy.check(&cc.name, &cmd.cmd_name())?;
+        if policy == BlockingCommands::Bounded {
+            let max = cc.blocking_timeout_max();
+            if let Some(timeout) = cmd.bound_timeout(&self.hash_tag, self.hash, max)? {
+                info!(
+                    "timeout {} of {} in cluster {} is capped to {} seconds",
+                    timeout,
+                    cmd.cmd_name(),
+                    cc.name,
+                    max
+                );
+            }
+        }
         Ok(())
     }
 
+    pub(crate) fn check_two_keys(&self, cmd: &T) -> Result<(), AsError> {
+        cmd.check_two_keys(&self.hash_tag, self.hash)
+    }
+
+    pub(crate) fn check_sort(&self, cmd: &T) -> Result<(), AsError> {
+        match cmd.sort_pattern(&self.hash_tag) {
+            Some(pattern) => {
+                let cc = self.cc.borrow();
+                cc.sort_patterns
+                    .unwrap_or_default()
+                    .check(&cc.name, &pattern)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// reply the keyless command by proxy itself under the local keyless_policy, the others
+    /// are spread the same as random.
+    pub(crate) fn reply_keyless(&self, cmd: &T) -> bool {
+        let cc = self.cc.borrow();
+        if cc.keyless_policy.unwrap_or_default() != KeylessPolicy::Local || !cmd.is_keyless() {
+            return false;
+        }
+        if !cmd.reply_keyless() {
+            return false;
+        }
+        keyless_incr(&cc.name, "proxy");
+        true
+    }
+
+    fn start_slow(&self, name: &str) {
+        if self.cc.borrow().slow_start.unwrap_or(0) > 0 {
+            info!("node {} start warming up", name);
+            self.slow_start.borrow_mut().start(name, Instant::now());
+        }
+    }
+
     fn ping_fail_limit(&self) -> u8 {
         self.cc
             .borrow()
@@ -225,7 +979,10 @@ impl<T: Request + 'static> Cluster<T> {
         let handle = Rc::new(Cell::new(CANCEL));
         {
             let mut pings = self.pings.borrow_mut();
-            pings.insert(node.to_string(), handle.clone());
+            // the former ping of the same node is replaced
+            if let Some(former) = pings.insert(node.to_string(), handle.clone()) {
+                former.set(true);
+            }
         }
 
         let ping = ping::Ping::new(
@@ -242,16 +999,17 @@ impl<T: Request + 'static> Cluster<T> {
 
     pub(crate) fn reinit(self: &Rc<Self>, cc: ClusterConfig) -> Result<(), AsError> {
         let sls = ServerLine::parse_servers(&cc.servers)?;
+        let pins = Pins::new(&cc.pin_keys, &cc.servers)?;
+        let tombstones = Tombstones::new(&cc.tombstone_commands)?;
+        let timeouts = CommandTimeouts::new(&cc.command_timeouts)?;
+        let sub_failures = SubFailures::new(&cc.sub_failures)?;
+        let cache = RespCache::from_config(&cc)?;
         let (nodes, alias, weights) = ServerLine::unwrap_spot(&sls);
         let alias_map: HashMap<_, _> = alias
             .clone()
             .into_iter()
             .zip(nodes.clone().into_iter())
             .collect();
-        let alias_rev: HashMap<_, _> = alias_map
-            .iter()
-            .map(|(x, y)| (y.clone(), x.clone()))
-            .collect();
         let spots_map: HashMap<_, _> = if alias.is_empty() {
             nodes
                 .clone()
@@ -265,53 +1023,198 @@ impl<T: Request + 'static> Cluster<T> {
                 .zip(weights.clone().into_iter())
                 .collect()
         };
-        let hash_ring = if alias.is_empty() {
-            HashRing::new(nodes, weights)?
+        let names = if alias.is_empty() {
+            nodes.clone()
         } else {
-            HashRing::new(alias, weights)?
+            alias.clone()
+        };
+        let mut hash_ring = HashRing::new(names.clone(), weights.clone())?;
+        let target: Vec<_> = names.into_iter().zip(weights.into_iter()).collect();
+        let now = SystemTime::now();
+        let transition = self
+            .next_transition(&cc, &target, now)
+            .map(|x| (x.step(now), x))
+            .filter(|(step, x)| !x.is_done(*step));
+        let effective = match transition.as_ref() {
+            Some((step, transition)) => {
+                let effective = transition.weights(*step);
+                hash_ring = HashRing::new(
+                    effective.iter().map(|x| x.0.clone()).collect(),
+                    effective.iter().map(|x| x.1).collect(),
+                )?;
+                effective
+            }
+            None => target.clone(),
         };
         let addrs: HashSet<_> = if !alias_map.is_empty() {
             alias_map.values().map(|x| x.to_string()).collect()
         } else {
             spots_map.keys().map(|x| x.to_string()).collect()
         };
+        self.standby.borrow_mut().reset(&cc.standby)?;
+        let standby_addrs: HashSet<_> = self.standby.borrow().addrs().iter().cloned().collect();
         let old_addrs = self.conns.borrow().addrs();
+        let unused_addrs = old_addrs
+            .difference(&addrs)
+            .filter(|x| !standby_addrs.contains(*x));
+
+        // the backend is identified by its name (alias or address) and address, the runtime
+        // state of the unchanged one (connection, ping, ejection and warming up) is kept, so
+        // the backends ejected are never reconnected by reloading other settings.
+        let old_nodes = self.nodes();
+        let new_nodes: HashMap<_, _> = sls.iter().map(|x| (x.name(), x.addr.clone())).collect();
+        let diff = NodesDiff::new(&old_nodes, &new_nodes);
+
+        for addr in unused_addrs {
+            if let Some(conn) = self.conns.borrow_mut().remove(&addr) {
+                self.retire(&cc.name, conn, RETIRED_BY_RELOAD);
+            }
+            self.violations.borrow_mut().remove(addr);
+            self.replays.borrow_mut().remove(addr);
+            let mut pings = self.pings.borrow_mut();
+            if let Some(handle) = pings.remove(addr) {
+                handle.set(true);
+            }
+        }
+        for name in diff.removed.iter().chain(diff.changed.iter()) {
+            self.standby.borrow_mut().recover(name);
+            self.slow_start.borrow_mut().stop(name);
+        }
+        // the ejected ones are kept out of the ring until recovered by their pings
+        for name in diff.preserved.iter() {
+            if self.standby.borrow().is_ejected(name) && !tombstones.is_enabled() {
+                hash_ring.del_node(name);
+            }
+        }
+
+        let is_first = old_nodes.is_empty();
+        if !is_first {
+            info!(
+                "cluster {} reload backends, preserved {:?}, recreated {:?}, added {:?}, removed {:?}",
+                cc.name, diff.preserved, diff.changed, diff.added, diff.removed
+            );
+        }
+        transition::publish(&cc.name, &effective, &target);
+        *self.cc.borrow_mut() = cc;
+        *self.ring.borrow_mut() = hash_ring;
+        *self.transition.borrow_mut() = transition.map(|(step, x)| (x, step));
+        *self.alias.borrow_mut() = alias_map;
+        *self.spots.borrow_mut() = spots_map;
+        *self.pins.borrow_mut() = pins;
+        *self.tombstones.borrow_mut() = tombstones;
+        *self.timeouts.borrow_mut() = timeouts;
+        *self.sub_failures.borrow_mut() = sub_failures;
+        *self.integrity.borrow_mut() = Integrity::new(&self.cc.borrow());
+        slo::handle(&self.cc.borrow());
+        budget::handle(&self.cc.borrow());
+        *self.cache.borrow_mut() = cache;
+        *self.acl.borrow_mut() = Acl::new(&self.cc.borrow().users);
 
-        let new_addrs = addrs.difference(&old_addrs);
-        let unused_addrs = old_addrs.difference(&addrs);
-        for addr in new_addrs {
-            self.reconnect(&*addr);
-            let ping_fail_limit = self.ping_fail_limit();
+        let ping_fail_limit = self.ping_fail_limit();
+        for name in diff.changed.iter().chain(diff.added.iter()) {
+            let addr = &new_nodes[name];
+            self.reconnect(addr);
             if ping_fail_limit > 0 {
                 let ping_interval = self.ping_interval();
                 let ping_succ_interval = self.ping_succ_interval();
-                let alias = alias_rev
-                    .get(addr)
-                    .expect("alias must be exists")
-                    .to_string();
                 self.setup_ping(
-                    &alias,
+                    name,
                     addr,
                     ping_interval,
                     ping_succ_interval,
                     ping_fail_limit,
                 );
             }
+            // nodes added by reload are warmed up, except for the first time
+            if !is_first {
+                self.start_slow(name);
+            }
         }
+        Ok(())
+    }
 
-        for addr in unused_addrs {
-            self.conns.borrow_mut().remove(&addr);
-            let mut pings = self.pings.borrow_mut();
-            if let Some(handle) = pings.remove(addr) {
-                handle.set(true);
+    // the transition of the weights changed by reload, the one in progress is kept if the new
+    // weights are the same, or superseded starting from the weights in effect.
+    fn next_transition(
+        &self,
+        cc: &ClusterConfig,
+        target: &[(String, usize)],
+        now: SystemTime,
+    ) -> Option<Transition> {
+        let duration = cc.weight_transition.unwrap_or(0);
+        if duration == 0 {
+            return None;
+        }
+        let current = self.transition.borrow();
+        let from: HashMap<_, _> = match current.as_ref() {
+            Some((_, transition)) if transition.target() == target => {
+                return Some(transition.clone())
             }
+            Some((_, transition)) => transition
+                .weights(transition.step(now))
+                .into_iter()
+                .collect(),
+            None => self.spots.borrow().clone(),
+        };
+        let transition = Transition::new(&from, target, now, Duration::from_millis(duration));
+        if transition.is_some() {
+            info!(
+                "cluster {} start weight transition from {:?} to {:?} in {} millis",
+                cc.name, from, target, duration
+            );
         }
+        transition
+    }
 
-        *self.cc.borrow_mut() = cc;
-        *self.ring.borrow_mut() = hash_ring;
-        *self.alias.borrow_mut() = alias_map;
-        *self.spots.borrow_mut() = spots_map;
-        Ok(())
+    /// rebuild the ring by the step of weight transition in effect at the time.
+    pub(crate) fn shift_weights(&self, now: SystemTime) {
+        let (step, transition) = match self.transition.borrow().as_ref() {
+            Some((applied, transition)) if transition.step(now) != *applied => {
+                (transition.step(now), transition.clone())
+            }
+            _ => return,
+        };
+        let weights = transition.weights(step);
+        let ring = HashRing::new(
+            weights.iter().map(|x| x.0.clone()).collect(),
+            weights.iter().map(|x| x.1).collect(),
+        );
+        let mut ring = match ring {
+            Ok(ring) => ring,
+            Err(err) => {
+                error!("fail to shift weights due to {}", err);
+                return;
+            }
+        };
+        // the ejected ones are kept out of the ring until recovered by their pings
+        let keeps_ejected = self.tombstones.borrow().is_enabled();
+        for (name, _) in weights.iter() {
+            if self.standby.borrow().is_ejected(name) && !keeps_ejected {
+                ring.del_node(name);
+            }
+        }
+        let name = self.cc.borrow().name.clone();
+        transition::publish(&name, &weights, transition.target());
+        *self.ring.borrow_mut() = ring;
+        if transition.is_done(step) {
+            info!("cluster {} finish weight transition", name);
+            self.transition.borrow_mut().take();
+        } else {
+            debug!("cluster {} shift weights to {:?}", name, weights);
+            *self.transition.borrow_mut() = Some((step, transition));
+        }
+    }
+
+    // the address of each backend by name.
+    fn nodes(&self) -> HashMap<String, String> {
+        if self.has_alias() {
+            return self.alias.borrow().clone();
+        }
+        self.spots
+            .borrow()
+            .keys()
+            .map(|x| (x.clone(), x.clone()))
+            .collect()
     }
 
     fn has_alias(&self) -> bool {
@@ -330,56 +1233,353 @@ impl<T: Request + 'static> Cluster<T> {
             .to_string()
     }
 
-    pub(crate) fn add_node(&self, name: String) -> Result<(), AsError> {
-        if let Some(weight) = self.spots.borrow().get(&name).cloned() {
-            let addr = self.get_node(name.clone());
-            let conn = connect(
-                &self.cc.borrow().name,
-                &addr,
-                self.cc.borrow().read_timeout,
-                self.cc.borrow().write_timeout,
-            )?;
-            self.conns.borrow_mut().insert(&addr, conn);
-            self.ring.borrow_mut().add_node(name, weight);
+    fn node_addr(&self, name: &str) -> Option<String> {
+        if !self.has_alias() {
+            return Some(name.to_string());
         }
-        Ok(())
+        self.alias.borrow().get(name).cloned()
     }
 
-    pub(crate) fn remove_node(&self, name: String) {
-        self.ring.borrow_mut().del_node(&name);
-        let node = self.get_node(name);
-        if self.conns.borrow_mut().remove(&node).is_some() {
-            info!("dropping backend connection of {} due active delete", node);
-        }
+    fn is_routable(&self, name: &str) -> bool {
+        self.drains
+            .borrow()
+            .get(name)
+            .map(|x| x.is_active())
+            .unwrap_or(true)
     }
 
-    pub(crate) fn reconnect(&self, addr: &str) {
-        let mut conns = self.conns.borrow_mut();
-        debug!("trying to reconnect to {}", addr);
-        conns.remove(addr);
-        match connect(
-            &self.cc.borrow().name,
-            &addr,
-            self.cc.borrow().read_timeout,
-            self.cc.borrow().write_timeout,
-        ) {
-            Ok(sender) => conns.insert(&addr, sender),
-            Err(err) => {
-                error!("fail to reconnect to {} due {:?}", addr, err);
+    pub(crate) fn is_closed(&self, name: &str) -> bool {
+        self.drains
+            .borrow()
+            .get(name)
+            .map(|x| x.is_closed())
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn close_node(&self, name: &str) {
+        if let Some(addr) = self.node_addr(name) {
+            if self.conns.borrow_mut().remove(&addr).is_some() {
+                info!("dropping backend connection of {} due to maintenance", addr);
             }
         }
     }
 
-    pub fn dispatch_to(&self, addr: &str, cmd: T) -> Result<AsyncSink<T>, AsError> {
-        if !cmd.can_cycle() {
-            // debug!("unable recycle due can't cycle");
-            cmd.set_error(&AsError::ProxyFail);
-            return Ok(AsyncSink::NotReady(cmd));
-        }
+    pub(crate) fn node_inflight(&self, name: &str) -> usize {
+        self.node_addr(name)
+            .and_then(|addr| self.conns.borrow().get(&addr).map(|x| x.inflight.get()))
+            .unwrap_or(0)
+    }
+
+    /// the connection to addr is closed by backend_idle_timeout, and its ping is paused until
+    /// it's reconnected on demand.
+    pub(crate) fn is_evicted(&self, addr: &str) -> bool {
+        self.conns.borrow().is_evicted(addr)
+    }
 
+    /// close the connections idle for backend_idle_timeout but the backend_min_idle ones used
+    /// most recently, the connections with requests in flight or of ejected backends are kept.
+    pub(crate) fn evict_idle(&self, now: Instant) -> Vec<String> {
+        let (timeout, min_idle) = {
+            let cc = self.cc.borrow();
+            (
+                cc.backend_idle_timeout.unwrap_or(0),
+                cc.backend_min_idle.unwrap_or(0),
+            )
+        };
+        if timeout == 0 {
+            return Vec::new();
+        }
+        let ejected: HashSet<_> = {
+            let standby = self.standby.borrow();
+            self.spots
+                .borrow()
+                .keys()
+                .filter(|name| standby.is_ejected(name))
+                .filter_map(|name| self.node_addr(name))
+                .collect()
+        };
+        let mut conns = self.conns.borrow_mut();
+        let used: Vec<_> = conns
+            .inner
+            .values()
+            .filter(|x| !ejected.contains(&x.addr))
+            .map(|x| {
+                let last_used = if x.inflight.get() > 0 {
+                    now
+                } else {
+                    x.last_used
+                };
+                (x.addr.clone(), last_used)
+            })
+            .collect();
+        let evicted = idle::pick_idle(used, Duration::from_millis(timeout), min_idle, now);
+        let name = self.cc.borrow().name.clone();
+        for addr in evicted.iter() {
+            info!("cluster {} close idle backend connection of {}", name, addr);
+            conns.evict(addr);
+            idle_eviction_incr(&name, addr);
+        }
+        evicted
+    }
+
+    // the connection of backend removed by reload is drained before closed, even if nothing
+    // is in flight, since the commands just routed may be still queued in its channel.
+    fn retire(&self, name: &str, conn: Conn<Sender<T>>, reason: &'static str) {
+        let inflight = conn.inflight.get();
+        info!(
+            "cluster {} draining backend {} {} with {} commands in flight",
+            name, conn.addr, reason, inflight
+        );
+        reload_drain_inflight_add(name, &conn.addr, inflight as f64);
+        self.retired.borrow_mut().push(Retired {
+            conn,
+            since: Instant::now(),
+            reported: inflight,
+            reason,
+        });
+    }
+
+    /// retire the connections beyond backend_max_lifetime and replace them by fresh ones, the
+    /// old ones are drained as the connections of backends removed by reload.
+    pub(crate) fn recycle_expired(&self, now: Instant) -> Vec<String> {
+        let expired: Vec<_> = self
+            .conns
+            .borrow()
+            .inner
+            .values()
+            .filter(|x| x.expire_at.map(|at| at <= now).unwrap_or(false))
+            .map(|x| x.addr.clone())
+            .collect();
+        let name = self.cc.borrow().name.clone();
+        for addr in expired.iter() {
+            let conn = self.conns.borrow_mut().remove(addr);
+            if let Some(conn) = conn {
+                self.retire(&name, conn, RETIRED_BY_LIFETIME);
+                backend_recycled_incr(&name, addr);
+                self.reconnect(addr);
+            }
+        }
+        expired
+    }
+
+    /// close the connections of backends removed by reload once drained, or once beyond
+    /// reload_drain_timeout, which fails the rest of commands in flight with BackendClosed.
+    pub(crate) fn check_retired(&self, now: Instant) {
+        let (name, timeout) = {
+            let cc = self.cc.borrow();
+            (cc.name.clone(), cc.reload_drain_timeout())
+        };
+        let timeout = Duration::from_millis(timeout);
+        let mut retired = self.retired.borrow_mut();
+        for mut x in std::mem::replace(&mut *retired, Vec::new()) {
+            let addr = x.conn.addr.clone();
+            let inflight = x.conn.inflight.get();
+            let elapsed = now.saturating_duration_since(x.since);
+            let progress = retire::progress(inflight, elapsed, timeout);
+            let current = if progress == Progress::Draining {
+                inflight
+            } else {
+                0
+            };
+            reload_drain_inflight_add(&name, &addr, current as f64 - x.reported as f64);
+            x.reported = current;
+            match progress {
+                Progress::Draining => {
+                    info!(
+                        "cluster {} backend {} {} is draining {} commands in flight for {:?}",
+                        name, addr, x.reason, inflight, elapsed
+                    );
+                    retired.push(x);
+                    continue;
+                }
+                Progress::Drained => {
+                    info!(
+                        "cluster {} backend {} {} is drained in {:?}",
+                        name, addr, x.reason, elapsed
+                    );
+                }
+                Progress::Expired => {
+                    warn!(
+                        "cluster {} backend {} {} is not drained in {:?}, fail {} commands in flight",
+                        name, addr, x.reason, elapsed, inflight
+                    );
+                    reload_drain_failed_incr(&name, &addr, inflight);
+                }
+            }
+            reload_drain_observe(&name, progress.as_str(), elapsed);
+            // the rest in flight are failed by the backend once its input is closed
+            drop(x);
+        }
+    }
+
+    // the timeout in effect of the connection, the adaptive one or read_timeout.
+    fn timeout_of<S>(&self, conn: &Conn<S>) -> Option<Duration> {
+        let cc = self.cc.borrow();
+        match Bounds::from_config(&cc) {
+            Some(bounds) => Some(conn.estimator.borrow().timeout(&bounds)),
+            None => cc.read_timeout.map(Duration::from_millis),
+        }
+    }
+
+    // the policy of cmd by sub_failures, which is only taken by the fan-out commands.
+    pub(crate) fn set_sub_failure(&self, cmd: &T) {
+        let sub_failures = self.sub_failures.borrow();
+        if !sub_failures.is_enabled() {
+            return;
+        }
+        if let Some(policy) = sub_failures.get(&cmd.cmd_name()) {
+            cmd.set_sub_failure(policy);
+        }
+    }
+
+    // the time limit of cmd by command_timeouts, which is recorded by access log as well as the
+    // timeout in effect of the connection.
+    fn limit_time<S>(&self, cmd: &T, conn: &Conn<S>) {
+        if let Some(deadline) = cmd.deadline() {
+            let limit = deadline.saturating_duration_since(Instant::now());
+            cmd.set_time_limit(limit);
+            cmd.set_timeout(limit);
+            return;
+        }
+        let timeouts = self.timeouts.borrow();
+        if timeouts.is_enabled() {
+            if let Some(limit) = timeouts.get(&cmd.cmd_name()) {
+                cmd.set_time_limit(limit);
+                cmd.set_timeout(limit);
+                return;
+            }
+        }
+        if self.access_log.is_enabled() {
+            if let Some(timeout) = self.timeout_of(conn) {
+                cmd.set_timeout(timeout);
+            }
+        }
+    }
+
+    // the command of the client deadline expired is dropped without dispatched, see deadline.
+    fn drop_expired(&self, cmd: &T) -> bool {
+        match cmd.deadline() {
+            Some(deadline) if deadline <= Instant::now() => {}
+            _ => return false,
+        }
+        client_deadline_expired_incr(&self.cc.borrow().name, "dispatch");
+        cmd.set_error(&AsError::DeadlineExpired);
+        true
+    }
+
+    /// the limit of the time waited in front before dispatched, None if it's disabled.
+    pub(crate) fn queue_timeout(&self) -> Option<Duration> {
+        self.cc
+            .borrow()
+            .queue_timeout
+            .filter(|x| *x > 0)
+            .map(Duration::from_millis)
+    }
+
+    /// fail the command queued longer than queue_timeout, return true if it's failed.
+    pub(crate) fn drop_queued(&self, cmd: &T) -> bool {
+        let limit = match self.queue_timeout() {
+            Some(limit) => limit,
+            None => return false,
+        };
+        match cmd.queued() {
+            Some(since) if since.elapsed() >= limit => {}
+            _ => return false,
+        }
+        queue_timeout_incr(&self.cc.borrow().name);
+        cmd.set_error(&AsError::QueueTimeout(limit.as_millis() as u64));
+        true
+    }
+
+    /// the timeout of the stall checks of the connection to addr, see adaptive.
+    pub(crate) fn stall_timeout(&self, addr: &str) -> Option<Duration> {
+        self.conns
+            .borrow()
+            .get(addr)
+            .and_then(|conn| self.timeout_of(conn))
+    }
+
+    /// the requests to addr have been waiting for the next reply longer than timeout.
+    pub(crate) fn is_stalled(&self, addr: &str, timeout: Duration) -> bool {
+        self.conns
+            .borrow()
+            .get(addr)
+            .and_then(|x| x.waiting.get())
+            .map(|since| since.elapsed() > timeout)
+            .unwrap_or(false)
+    }
+
+    pub(crate) fn add_node(&self, name: String) -> Result<(), AsError> {
+        if let Some(weight) = self.spots.borrow().get(&name).cloned() {
+            let addr = self.get_node(name.clone());
+            let conn = self.connect(&addr)?;
+            self.conns.borrow_mut().insert(conn);
+            self.standby.borrow_mut().recover(&name);
+            self.start_slow(&name);
+            self.ring.borrow_mut().add_node(name, weight);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove_node(&self, name: String, reason: Eject) {
+        self.standby.borrow_mut().eject(&name, reason);
+        self.slow_start.borrow_mut().stop(&name);
+        // the range of ejected node is kept for the tombstones, see standalone::tombstone
+        if !self.tombstones.borrow().is_enabled() {
+            self.ring.borrow_mut().del_node(&name);
+        }
+        let node = self.get_node(name);
+        if self.conns.borrow_mut().remove(&node).is_some() {
+            info!("dropping backend connection of {} due active delete", node);
+        }
+    }
+
+    /// eject the node once its connections are closed by the violations of reply framing
+    /// protocol_error_limit times, which is checked by its ping.
+    pub(crate) fn check_violations(&self, name: &str, addr: &str) -> bool {
+        let limit = self.cc.borrow().protocol_error_limit() as usize;
+        let violations = match self.violations.borrow().get(addr) {
+            Some(violations) if limit > 0 && violations.get() >= limit => violations.clone(),
+            _ => return false,
+        };
+        error!(
+            "remove node={} addr={} by {} protocol violations",
+            name,
+            addr,
+            violations.get()
+        );
+        violations.set(0);
+        self.remove_node(name.to_string(), Eject::ProtocolViolation);
+        true
+    }
+
+    pub(crate) fn reconnect(&self, addr: &str) {
+        let mut conns = self.conns.borrow_mut();
+        debug!("trying to reconnect to {}", addr);
+        conns.remove(addr);
+        match self.connect(&addr) {
+            Ok(conn) => conns.insert(conn),
+            Err(err) => {
+                error!("fail to reconnect to {} due {:?}", addr, err);
+            }
+        }
+    }
+
+    pub fn dispatch_to(&self, addr: &str, cmd: T) -> Result<AsyncSink<T>, AsError> {
+        if !cmd.can_cycle() {
+            // debug!("unable recycle due can't cycle");
+            cmd.set_error(&AsError::ProxyFail);
+            return Ok(AsyncSink::NotReady(cmd));
+        }
+
+        // control commands generated by proxy itself never wait behind client traffic
+        let is_ctrl = cmd.is_ctrl();
         let mut conns = self.conns.borrow_mut();
         loop {
-            if let Some(sender) = conns.get_mut(addr).map(|x| x.sender()) {
+            let sender = conns
+                .get_mut(addr)
+                .map(|x| if is_ctrl { x.ctrl() } else { x.sender() });
+            if let Some(sender) = sender {
                 match sender.start_send(cmd) {
                     Ok(ret) => {
                         return Ok(ret);
@@ -394,18 +1594,260 @@ impl<T: Request + 'static> Cluster<T> {
                 }
             } else {
                 debug!("dispatch_to trying to reconnect to {}", addr);
-                let sender = connect(
-                    &self.cc.borrow().name,
-                    &addr,
-                    self.cc.borrow().read_timeout,
-                    self.cc.borrow().write_timeout,
-                )?;
-                conns.insert(&addr, sender);
+                let conn = self.connect(addr)?;
+                conns.insert(conn);
             }
         }
     }
 
+    fn connect(&self, addr: &str) -> Result<Conn<Sender<T>>, AsError> {
+        let cc = self.cc.borrow();
+        let retry = if cc.retry_on_stale.unwrap_or(false) {
+            self.retry.borrow().clone()
+        } else {
+            None
+        };
+        let violations = self
+            .violations
+            .borrow_mut()
+            .entry(addr.to_string())
+            .or_insert_with(Rc::default)
+            .clone();
+        let mut conn = connect(&cc, addr, self.memory.back_meter(), retry, violations)?;
+        conn.expire_at = lifetime::expire_at(
+            conn.last_used,
+            cc.backend_max_lifetime.unwrap_or(0),
+            cc.backend_lifetime_jitter(),
+            &mut rand::thread_rng(),
+        );
+        Ok(conn)
+    }
+
+    /// send the marker of PROXY BARRIER to the backend behind the commands queued on its
+    /// connection, the marker is failed if it can't be queued (e.g.: the queue is full).
+    pub(crate) fn dispatch_marker(&self, addr: &str, cmd: T) {
+        let mut conns = self.conns.borrow_mut();
+        if conns.get(addr).is_none() {
+            match self.connect(addr) {
+                Ok(conn) => conns.insert(conn),
+                Err(err) => {
+                    cmd.set_error(&err);
+                    return;
+                }
+            }
+        }
+        let sender = conns
+            .get_mut(addr)
+            .map(|x| x.sender())
+            .expect("conn never be absent");
+        match sender.start_send(cmd) {
+            Ok(AsyncSink::Ready) => {}
+            Ok(AsyncSink::NotReady(cmd)) => {
+                cmd.set_error(&AsError::BackendOverloaded(addr.to_string()));
+            }
+            Err(se) => se.into_inner().set_error(&AsError::ProxyFail),
+        }
+    }
+
+    /// send the replay of integrity check to the backend on the connection of replays, so it's
+    /// never replied on the same connection as the read sampled. False if it can't be sent.
+    pub(crate) fn dispatch_replay(&self, addr: &str, cmd: T) -> bool {
+        let mut replays = self.replays.borrow_mut();
+        if !replays.contains_key(addr) {
+            match self.connect(addr) {
+                Ok(conn) => {
+                    replays.insert(addr.to_string(), conn);
+                }
+                Err(err) => {
+                    warn!(
+                        "fail to connect to {} for integrity check due to {}",
+                        addr, err
+                    );
+                    return false;
+                }
+            }
+        }
+        let conn = replays.get_mut(addr).expect("conn never be absent");
+        match conn.sender().start_send(cmd) {
+            Ok(AsyncSink::Ready) => true,
+            Ok(AsyncSink::NotReady(_)) => false,
+            Err(_) => {
+                replays.remove(addr);
+                false
+            }
+        }
+    }
+
+    /// sample the single key reads of cmd replied for the integrity check, see integrity.
+    pub(crate) fn check_integrity(self: &Rc<Self>, cmd: &T) {
+        if !self.integrity.borrow().is_enabled() {
+            return;
+        }
+        for read in cmd.subs().unwrap_or_else(|| vec![cmd.clone()]) {
+            if let Some(compare) = integrity::sample(self, &read) {
+                current_thread::spawn(compare);
+            }
+        }
+    }
+
+    // the command routed to the ejected node kept in the ring for the tombstones is replied by
+    // the tombstone if it's a read given by tombstone_commands, or failed otherwise.
+    fn reply_ejected(&self, cmd: &T, addr: &str) -> bool {
+        let tombstones = self.tombstones.borrow();
+        if !tombstones.is_enabled() {
+            return false;
+        }
+        let is_ejected = self
+            .standby
+            .borrow()
+            .ejected()
+            .any(|node| node == addr || self.node_addr(node).as_deref() == Some(addr));
+        if !is_ejected {
+            return false;
+        }
+        let replied = !cmd.is_mutation()
+            && tombstones
+                .get(&cmd.cmd_name())
+                .map(|x| cmd.reply_tombstone(x))
+                .unwrap_or(false);
+        if replied {
+            tombstone_incr(&self.cc.borrow().name, addr);
+        } else {
+            cmd.set_error(&AsError::BackendEjected(addr.to_string()));
+        }
+        true
+    }
+
+    fn next_keyless_round(&self) -> usize {
+        let round = self.keyless.get();
+        self.keyless.set(round.wrapping_add(1));
+        round
+    }
+
+    // route the command to backend address, commands already sent are never re-routed
+    // when switching between primary and standby backends.
+    fn route(&self, cmd: &T) -> Option<String> {
+        if let Some(addr) = cmd.pinned() {
+            return Some(addr);
+        }
+        if cmd.is_admin() {
+            return self.cc.borrow().admin_node.clone();
+        }
+
+        let pins = self.pins.borrow();
+        if !pins.is_empty() {
+            let pinned = cmd.with_key(|key| pins.get(key).map(|x| x.to_string()));
+            if let Some(addr) = pinned.and_then(|x| x) {
+                return Some(addr);
+            }
+        }
+
+        let key_hash = if !cmd.is_keyless() {
+            cmd.key_hash(&self.hash_tag, self.hash)
+        } else if self.cc.borrow().keyless_policy == Some(KeylessPolicy::Pinned) {
+            Some(self.hash.hash(b""))
+        } else {
+            None
+        };
+        let standby = self.standby.borrow();
+        if standby.is_active() {
+            let addr = match key_hash {
+                Some(key_hash) => standby.get_node(key_hash),
+                None => standby.get_node_by_round(self.next_keyless_round()),
+            };
+            return addr.map(|x| x.to_string());
+        }
+
+        let ring = self.ring.borrow();
+        // the hash range of node under maintenance is taken over by it's successor, and so
+        // is the part of warming node's range which is not ramped yet.
+        let slow = self.slow_start.borrow();
+        let accept = |name: &str| self.is_routable(name);
+        let name = match key_hash {
+            Some(key_hash) => ring
+                .get_node_with(key_hash, |x| accept(x) && slow.accept(x, key_hash))
+                .or_else(|| ring.get_node_with(key_hash, accept)),
+            None => {
+                let round = self.next_keyless_round();
+                ring.get_node_by_round_with(round, |x| accept(x) && slow.accept(x, round as u64))
+                    .or_else(|| ring.get_node_by_round_with(round, accept))
+            }
+        };
+        name.map(|x| self.get_node(x.to_string()))
+    }
+
+    /// dispatch the commands retried from stale connections, the closed connection is
+    /// replaced without consuming the cycle of commands.
+    pub(crate) fn redispatch(&self, cmds: &mut VecDeque<T>) -> Result<(), AsError> {
+        while let Some(cmd) = cmds.pop_front() {
+            if self.drop_expired(&cmd) {
+                continue;
+            }
+            let addr = match self.route(&cmd) {
+                Some(addr) => addr,
+                None => {
+                    cmd.set_error(&AsError::ProxyFail);
+                    continue;
+                }
+            };
+            self.clock.stamp(Event::Routed, cmd.trace_id(), &addr);
+            if self
+                .fault
+                .is_down(|node| node == addr || self.node_addr(node).as_ref() == Some(&addr))
+            {
+                cmd.set_error(&AsError::InjectedDown(addr));
+                continue;
+            }
+            if self.reply_ejected(&cmd, &addr) {
+                continue;
+            }
+            // the backends written are synced by PROXY BARRIER, and the reads are replayed to the
+            // same backends by integrity_sample
+            if self.access_log.is_enabled()
+                || cmd.is_mutation()
+                || self.integrity.borrow().is_enabled()
+            {
+                cmd.set_node(&addr);
+            }
+            let mut conns = self.conns.borrow_mut();
+            if let Some(conn) = conns.get_mut(&addr) {
+                conn.last_used = Instant::now();
+                self.limit_time(&cmd, conn);
+                match conn.sender().start_send(cmd) {
+                    Ok(AsyncSink::Ready) => continue,
+                    Ok(AsyncSink::NotReady(cmd)) => {
+                        cmds.push_front(cmd);
+                        return Ok(());
+                    }
+                    Err(se) => cmds.push_front(se.into_inner()),
+                }
+            } else {
+                cmds.push_front(cmd);
+            }
+            let conn = self.connect(&addr)?;
+            conns.insert(conn);
+        }
+        Ok(())
+    }
+
+    /// dispatch commands in order, the ones to the backends with queue full are kept in order
+    /// and put back without blocking the commands to other backends.
     pub fn dispatch_all(&self, cmds: &mut VecDeque<T>) -> Result<usize, AsError> {
+        let mut held = VecDeque::new();
+        let rslt = self.dispatch_unblocked(cmds, &mut held);
+        while let Some(cmd) = held.pop_back() {
+            cmds.push_front(cmd);
+        }
+        rslt
+    }
+
+    fn dispatch_unblocked(
+        &self,
+        cmds: &mut VecDeque<T>,
+        held: &mut VecDeque<T>,
+    ) -> Result<usize, AsError> {
+        let overload = self.cc.borrow().backend_overload.unwrap_or_default();
+        let mut blocked = HashSet::new();
         let mut count = 0usize;
         loop {
             if cmds.is_empty() {
@@ -417,47 +1859,81 @@ impl<T: Request + 'static> Cluster<T> {
                 count += 1;
                 continue;
             }
-            let key_hash = cmd.key_hash(&self.hash_tag, fnv1a64);
-
-            let addr = if let Some(name) = self.ring.borrow().get_node(key_hash) {
-                self.get_node(name.to_string())
+            if self.drop_expired(&cmd) || self.drop_queued(&cmd) {
+                count += 1;
+                continue;
+            }
+            let addr = if let Some(addr) = self.route(&cmd) {
+                addr
             } else {
                 return Ok(count);
             };
+            self.clock.stamp(Event::Routed, cmd.trace_id(), &addr);
+            if self
+                .fault
+                .is_down(|node| node == addr || self.node_addr(node).as_ref() == Some(&addr))
+            {
+                cmd.set_error(&AsError::InjectedDown(addr));
+                count += 1;
+                continue;
+            }
+            if self.reply_ejected(&cmd, &addr) {
+                count += 1;
+                continue;
+            }
+            if blocked.contains(&addr) {
+                held.push_back(cmd);
+                continue;
+            }
+            // the backends written are synced by PROXY BARRIER, and the reads are replayed to the
+            // same backends by integrity_sample
+            if self.access_log.is_enabled()
+                || cmd.is_mutation()
+                || self.integrity.borrow().is_enabled()
+            {
+                cmd.set_node(&addr);
+            }
+            let keyless = cmd.is_keyless();
             let mut conns = self.conns.borrow_mut();
 
-            if let Some(sender) = conns.get_mut(&addr).map(|x| x.sender()) {
-                match sender.start_send(cmd) {
+            if let Some(conn) = conns.get_mut(&addr) {
+                conn.last_used = Instant::now();
+                self.limit_time(&cmd, conn);
+                let queued = cmd.queued();
+                cmd.set_queued(None);
+                match conn.sender().start_send(cmd) {
                     Ok(AsyncSink::Ready) => {
+                        if keyless {
+                            keyless_incr(&self.cc.borrow().name, &addr);
+                        }
                         count += 1;
                     }
-                    Ok(AsyncSink::NotReady(cmd)) => {
-                        cmds.push_front(cmd);
-                        return Ok(count);
-                    }
+                    Ok(AsyncSink::NotReady(cmd)) => match overload {
+                        BackendOverload::Queue => {
+                            cmd.set_queued(queued);
+                            // woken up by the channel once the backend catches up
+                            held.push_back(cmd);
+                            blocked.insert(addr);
+                        }
+                        BackendOverload::Fail => {
+                            cmd.set_error(&AsError::BackendOverloaded(addr));
+                            count += 1;
+                        }
+                    },
                     Err(se) => {
                         let cmd = se.into_inner();
+                        cmd.set_queued(queued);
                         cmd.add_cycle();
                         cmds.push_front(cmd);
-                        let sender = connect(
-                            &self.cc.borrow().name,
-                            &addr,
-                            self.cc.borrow().read_timeout,
-                            self.cc.borrow().write_timeout,
-                        )?;
-                        conns.insert(&addr, sender);
+                        let conn = self.connect(&addr)?;
+                        conns.insert(conn);
                         return Ok(count);
                     }
                 }
             } else {
                 cmds.push_front(cmd);
-                let sender = connect(
-                    &self.cc.borrow().name,
-                    &addr,
-                    self.cc.borrow().read_timeout,
-                    self.cc.borrow().write_timeout,
-                )?;
-                conns.insert(&addr, sender);
+                let conn = self.connect(&addr)?;
+                conns.insert(conn);
                 return Ok(count);
             }
         }
@@ -467,6 +1943,8 @@ impl<T: Request + 'static> Cluster<T> {
 struct Conns<T> {
     _marker: PhantomData<T>,
     inner: HashMap<String, Conn<Sender<T>>>,
+    // the addresses whose connections are closed by backend_idle_timeout
+    evicted: HashSet<String>,
 }
 
 impl<T> Conns<T> {
@@ -474,20 +1952,32 @@ impl<T> Conns<T> {
         self.inner.keys().cloned().collect()
     }
 
+    fn get(&self, s: &str) -> Option<&Conn<Sender<T>>> {
+        self.inner.get(s)
+    }
+
     fn get_mut(&mut self, s: &str) -> Option<&mut Conn<Sender<T>>> {
         self.inner.get_mut(s)
     }
 
     fn remove(&mut self, addr: &str) -> Option<Conn<Sender<T>>> {
+        self.evicted.remove(addr);
         self.inner.remove(addr)
     }
 
-    fn insert(&mut self, s: &str, sender: Sender<T>) {
-        let conn = Conn {
-            addr: s.to_string(),
-            sender,
-        };
-        self.inner.insert(s.to_string(), conn);
+    fn insert(&mut self, conn: Conn<Sender<T>>) {
+        self.evicted.remove(&conn.addr);
+        self.inner.insert(conn.addr.clone(), conn);
+    }
+
+    fn evict(&mut self, addr: &str) {
+        if self.inner.remove(addr).is_some() {
+            self.evicted.insert(addr.to_string());
+        }
+    }
+
+    fn is_evicted(&self, addr: &str) -> bool {
+        self.evicted.contains(addr)
     }
 }
 
@@ -495,36 +1985,79 @@ impl<T> Default for Conns<T> {
     fn default() -> Conns<T> {
         Conns {
             inner: HashMap::new(),
+            evicted: HashSet::new(),
             _marker: Default::default(),
         }
     }
 }
 
-#[allow(unused)]
+const RETIRED_BY_RELOAD: &str = "removed by reload";
+const RETIRED_BY_LIFETIME: &str = "beyond backend_max_lifetime";
+
+struct Retired<T> {
+    conn: Conn<Sender<T>>,
+    since: Instant,
+    // the commands in flight added to the gauge of drain
+    reported: usize,
+    // why it's retired, e.g.: removed by reload
+    reason: &'static str,
+}
+
 struct Conn<S> {
     addr: String,
     sender: S,
+    ctrl: S,
+    inflight: Rc<Cell<usize>>,
+    // since when the requests in flight have been waiting for the next reply
+    waiting: Rc<Cell<Option<Instant>>>,
+    // latency of the replies for adaptive_timeout, started over by reconnecting
+    estimator: Rc<RefCell<Estimator>>,
+    // when the requests of clients are sent last, for backend_idle_timeout
+    last_used: Instant,
+    // when it's recycled by backend_max_lifetime
+    expire_at: Option<Instant>,
 }
 
 impl<S> Conn<S> {
     fn sender(&mut self) -> &mut S {
         &mut self.sender
     }
+
+    fn ctrl(&mut self) -> &mut S {
+        &mut self.ctrl
+    }
 }
 
 fn connect<T>(
-    cluster: &str,
+    cc: &ClusterConfig,
     node: &str,
-    rt: Option<u64>,
-    wt: Option<u64>,
-) -> Result<Sender<T>, AsError>
+    meter: Meter,
+    retry: Option<UnboundedSender<T>>,
+    violations: Rc<Cell<usize>>,
+) -> Result<Conn<Sender<T>>, AsError>
 where
     T: Request + 'static,
 {
     let node_addr = node.to_string();
     let node_new = node_addr.clone();
-    let cluster = cluster.to_string();
-    let (tx, rx) = channel(1024 * 8);
+    let node_conn = node_addr.clone();
+    let cluster = cc.name.clone();
+    let cluster_conn = cluster.clone();
+    let rt = cc.read_timeout;
+    let wt = cc.write_timeout;
+    let keepalive = cc.tcp_keepalive();
+    let upstream = cc.upstream_link.unwrap_or(false);
+    let link = LinkOptions::from_config(cc);
+    let codec = T::back_codec(cc, node);
+    let (tx, rx) = channel(cc.backend_queue_limit());
+    let (ctrl_tx, ctrl_rx) = channel(CTRL_CHANNEL_SIZE);
+    let inflight = Rc::new(Cell::new(0));
+    let back_inflight = inflight.clone();
+    let waiting = Rc::new(Cell::new(None));
+    let back_waiting = waiting.clone();
+    let estimator = Rc::new(RefCell::new(Estimator::default()));
+    let back_estimator = estimator.clone();
+    let budget = budget::handle(cc);
     let amt = lazy(|| -> Result<(), ()> { Ok(()) })
         .and_then(move |_| {
             let node_clone = node_addr.clone();
@@ -533,28 +2066,53 @@ where
                 .parse()
                 .map_err(|err| error!("fail to parse addr {} due to {:?}", node_clone, err))
         })
-        .and_then(|addr: SocketAddr| {
-            TcpStream::connect(&addr)
-                .timeout(Duration::from_secs(1))
+        .and_then(move |addr: SocketAddr| {
+            connect_backend(&cluster_conn, &node_conn, &addr, Duration::from_secs(1))
                 .map_err(move |err| error!("fail to connect to {} due to {:?}", &addr, err))
         })
         .then(move |srslt: Result<TcpStream, ()>| {
             if let Ok(sock) = srslt {
                 let sock = set_read_write_timeout(sock, rt, wt).expect("set timeout must be ok");
                 sock.set_nodelay(true).expect("set nodelay must ok");
-                let codec = T::BackCodec::default();
-                let (sink, stream) = codec.framed(sock).split();
-                let backend = back::Back::new(cluster, node_new, rx, sink, stream);
+                if let Err(err) = set_keepalive(&sock, keepalive) {
+                    warn!(
+                        "fail to set keepalive of backend {} due to {}",
+                        node_new, err
+                    );
+                }
+                let sock = if upstream {
+                    Link::offer(sock, link, &cluster, &node_new)
+                } else {
+                    Link::plain(sock)
+                };
+                let (sink, stream) = Metered::new(codec, meter).framed(sock).split();
+                let mut backend =
+                    back::Back::new(cluster, node_new, rx, ctrl_rx, sink, stream, back_inflight)
+                        .waiting(back_waiting)
+                        .estimator(back_estimator)
+                        .violations(violations);
+                if let Some(retry) = retry {
+                    backend = backend.retry(retry).budget(budget);
+                }
                 current_thread::spawn(backend);
             } else {
-                let blackhole = back::Blackhole::new(node_new, rx);
+                let blackhole = back::Blackhole::new(node_new, rx.select(ctrl_rx));
                 current_thread::spawn(blackhole);
             }
             Ok(())
         })
         .and_then(|_| Ok(()));
     current_thread::spawn(amt);
-    Ok(tx)
+    Ok(Conn {
+        addr: node.to_string(),
+        sender: tx,
+        ctrl: ctrl_tx,
+        inflight,
+        waiting,
+        estimator,
+        last_used: Instant::now(),
+        expire_at: None,
+    })
 }
 
 struct ServerLine {
@@ -563,7 +2121,105 @@ struct ServerLine {
     alias: Option<String>,
 }
 
+/// the backends of reload compared by name, in the order of names.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct NodesDiff {
+    // the same address as before
+    pub preserved: Vec<String>,
+    // the address is changed
+    pub changed: Vec<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl NodesDiff {
+    pub fn new(old: &HashMap<String, String>, new: &HashMap<String, String>) -> NodesDiff {
+        let mut diff = NodesDiff::default();
+        for (name, addr) in new.iter() {
+            match old.get(name) {
+                Some(old_addr) if old_addr == addr => diff.preserved.push(name.clone()),
+                Some(_) => diff.changed.push(name.clone()),
+                None => diff.added.push(name.clone()),
+            }
+        }
+        diff.removed = old
+            .keys()
+            .filter(|x| !new.contains_key(*x))
+            .cloned()
+            .collect();
+        diff.preserved.sort();
+        diff.changed.sort();
+        diff.added.sort();
+        diff.removed.sort();
+        diff
+    }
+}
+
+/// check the format of servers before they are updated.
+pub(crate) fn check_servers(servers: &[String]) -> Result<(), AsError> {
+    ServerLine::parse_servers(servers).map(|_| ())
+}
+
+/// the (name, address, weight) of nodes in the ring of servers, the same as the ring of proxy.
+/// The node is named by alias if present.
+pub(crate) fn ring_nodes(servers: &[String]) -> Result<Vec<(String, String, usize)>, AsError> {
+    let sls = ServerLine::parse_servers(servers)?;
+    let aliased = sls.iter().filter(|x| x.alias.is_some()).count();
+    if aliased != 0 && aliased != sls.len() {
+        return Err(AsError::BadConfig(
+            "servers: all server must have(or not) alias together".to_string(),
+        ));
+    }
+    Ok(sls
+        .into_iter()
+        .map(|sl| {
+            (
+                sl.alias.unwrap_or_else(|| sl.addr.clone()),
+                sl.addr,
+                sl.weight,
+            )
+        })
+        .collect())
+}
+
+/// the distinct nodes of subs of multi-key command joined by comma.
+pub(crate) fn join_nodes<I: Iterator<Item = String>>(nodes: I) -> Option<String> {
+    let mut joined: Vec<String> = Vec::new();
+    for node in nodes {
+        if !joined.contains(&node) {
+            joined.push(node);
+        }
+    }
+    if joined.is_empty() {
+        None
+    } else {
+        Some(joined.join(","))
+    }
+}
+
+/// the server line is of the node named by alias or address.
+pub(crate) fn is_server_of(line: &str, node: &str) -> bool {
+    ServerLine::parse_servers(&[line.to_string()])
+        .ok()
+        .and_then(|x| x.into_iter().next())
+        .map(|sl| sl.addr == node || sl.alias.as_ref().map(|x| x.as_str()) == Some(node))
+        .unwrap_or(false)
+}
+
+/// the name of node in the ring of the server line, which is the alias if present.
+pub(crate) fn node_name(line: &str) -> Option<String> {
+    ServerLine::parse_servers(&[line.to_string()])
+        .ok()
+        .and_then(|x| x.into_iter().next())
+        .map(|sl| sl.alias.unwrap_or(sl.addr))
+}
+
 impl ServerLine {
+    // the name of node, which is alias if present or address.
+    fn name(&self) -> String {
+        self.alias.clone().unwrap_or_else(|| self.addr.clone())
+    }
+
     fn parse_servers(servers: &[String]) -> Result<Vec<ServerLine>, AsError> {
         // e.g.: 192.168.1.2:1074:10 redis-20
         let mut sl = Vec::with_capacity(servers.len());
@@ -577,6 +2233,7 @@ impl ServerLine {
                     weight: 1,
                     alias,
                 });
+                continue;
             }
 
             let mut fp_sp = first_part.rsplitn(2, ':').filter(|x| !x.is_empty());
@@ -616,23 +2273,28 @@ impl ServerLine {
     }
 }
 
-pub fn run(cc: ClusterConfig, ip: Option<String>) -> Vec<JoinHandle<()>> {
+pub fn run(cc: ClusterConfig, ip: Option<String>, control: Arc<Control>) -> Vec<JoinHandle<()>> {
     let worker = cc.thread.unwrap_or(4);
     (0..worker)
         .map(|_index| {
             let builder = Builder::new();
             let cc = cc.clone();
             let ip = ip.clone();
+            let control = control.clone();
             builder
                 .name(cc.name.clone())
                 .spawn(move || {
                     meta_init(cc.clone(), ip);
 
                     thread_incr();
+                    let worker = Rc::new(Worker::new(control));
+                    // the cache types compiled out are rejected by Config::valid
                     match cc.cache_type {
-                        CacheType::Redis => Cluster::<redis::Cmd>::run(cc).unwrap(),
+                        #[cfg(feature = "redis")]
+                        CacheType::Redis => Cluster::<redis::Cmd>::run(cc, worker).unwrap(),
+                        #[cfg(feature = "memcache")]
                         CacheType::Memcache | CacheType::MemcacheBinary => {
-                            Cluster::<mc::Cmd>::run(cc).unwrap()
+                            Cluster::<mc::Cmd>::run(cc, worker).unwrap()
                         }
                         _ => unreachable!(),
                     }
@@ -641,3 +2303,1143 @@ pub fn run(cc: ClusterConfig, ip: Option<String>) -> Vec<JoinHandle<()>> {
         })
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{keyless_get, queue_timeout_get, tombstone_get};
+    use crate::protocol::redis;
+    use bytes::BytesMut;
+    use futures::Async;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn parse(data: &[u8]) -> redis::Cmd {
+        let mut src = BytesMut::from(data);
+        redis::Command::parse_cmd(&mut src).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_admin_cmd_route() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-admin-route".to_string();
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        let failover = parse(b"*1\r\n$8\r\nFAILOVER\r\n");
+        assert!(failover.is_admin());
+        // denied by default
+        assert!(!cluster.allow_admin());
+
+        cc.admin_node = Some("127.0.0.1:7100".to_string());
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        assert!(cluster.allow_admin());
+        assert_eq!(cluster.route(&failover), Some("127.0.0.1:7100".to_string()));
+
+        // normal command never be routed to admin node
+        let get = parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+        assert!(!get.is_admin());
+        assert_eq!(cluster.route(&get), None);
+    }
+
+    #[test]
+    fn test_function_broadcast() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-function-broadcast".to_string();
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        let load = || parse(b"*3\r\n$8\r\nFUNCTION\r\n$4\r\nLOAD\r\n$5\r\nlua..\r\n");
+        // denied by default
+        let cmd = load();
+        assert!(cmd.is_admin());
+        assert!(!cluster.broadcast(&cmd) && !cluster.allow_admin());
+        assert!(cmd.subs().is_none());
+
+        cc.function_broadcast = Some(true);
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        *cluster.spots.borrow_mut() = vec![
+            ("127.0.0.1:7002".to_string(), 10),
+            ("127.0.0.1:7001".to_string(), 10),
+        ]
+        .into_iter()
+        .collect();
+        let cmd = load();
+        assert!(cluster.broadcast(&cmd));
+        let routed: Vec<_> = cmd
+            .subs()
+            .unwrap()
+            .iter()
+            .map(|x| cluster.route(x))
+            .collect();
+        assert_eq!(
+            routed,
+            vec![
+                Some("127.0.0.1:7001".to_string()),
+                Some("127.0.0.1:7002".to_string())
+            ]
+        );
+
+        // the other admin commands are still gated
+        let failover = parse(b"*1\r\n$8\r\nFAILOVER\r\n");
+        assert!(!cluster.broadcast(&failover));
+    }
+
+    #[test]
+    fn test_route_skip_draining() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-route-drain".to_string();
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        let nodes = vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7002".to_string()];
+        *cluster.ring.borrow_mut() = HashRing::new(nodes, vec![10, 10]).unwrap();
+
+        let get = parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+        let origin = cluster.route(&get).unwrap();
+        cluster
+            .drains
+            .borrow_mut()
+            .insert(origin.clone(), NodeState::Draining);
+        let routed = cluster.route(&get).unwrap();
+        assert_ne!(routed, origin);
+        assert!(!cluster.is_closed(&origin));
+
+        // undrain restores the routing without rebuilding the ring
+        cluster.drains.borrow_mut().clear();
+        assert_eq!(cluster.route(&get), Some(origin));
+    }
+
+    #[test]
+    fn test_route_pinned_keys() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-route-pin".to_string();
+        cc.servers = vec![
+            "127.0.0.1:7001:10".to_string(),
+            "127.0.0.1:7002:10".to_string(),
+        ];
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        let nodes = vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7002".to_string()];
+        *cluster.ring.borrow_mut() = HashRing::new(nodes, vec![10, 10]).unwrap();
+        let get = |key: &str| {
+            parse(format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key).as_bytes())
+        };
+        let keys: Vec<_> = (0..64).map(|i| format!("counter:{}", i)).collect();
+        let hashed: Vec<_> = keys.iter().map(|x| cluster.route(&get(x))).collect();
+        assert!(hashed.contains(&Some("127.0.0.1:7001".to_string())));
+        assert!(hashed.contains(&Some("127.0.0.1:7002".to_string())));
+
+        let pin_keys = vec!["counter:* 127.0.0.1:7002".to_string()];
+        *cluster.pins.borrow_mut() = Pins::new(&pin_keys, &cc.servers).unwrap();
+        let pinned = Some("127.0.0.1:7002".to_string());
+        for key in keys.iter() {
+            assert_eq!(cluster.route(&get(key)), pinned);
+        }
+        // the pinned node is kept even if it's draining
+        cluster
+            .drains
+            .borrow_mut()
+            .insert("127.0.0.1:7002".to_string(), NodeState::Draining);
+        assert_eq!(cluster.route(&get("counter:0")), pinned);
+        cluster.drains.borrow_mut().clear();
+
+        // the others are hashed as usual
+        for i in 0..64 {
+            let key = format!("user:{}", i);
+            let hash = get(&key).key_hash(&cluster.hash_tag, cluster.hash).unwrap();
+            let owner = cluster.ring.borrow().get_node(hash).map(|x| x.to_string());
+            assert_eq!(cluster.route(&get(&key)), owner);
+        }
+    }
+
+    #[test]
+    fn test_route_two_keys() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-route-two-keys".to_string();
+        cc.hash_tag = Some("{}".to_string());
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        let nodes = vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7002".to_string()];
+        *cluster.ring.borrow_mut() = HashRing::new(nodes, vec![10, 10]).unwrap();
+
+        let smove = parse(b"*4\r\n$5\r\nSMOVE\r\n$4\r\n{a}1\r\n$4\r\n{a}2\r\n$1\r\nm\r\n");
+        assert_eq!(cluster.check_two_keys(&smove), Ok(()));
+        let get = parse(b"*2\r\n$3\r\nGET\r\n$4\r\n{a}2\r\n");
+        assert_eq!(cluster.route(&smove), cluster.route(&get));
+
+        let lmove = b"*5\r\n$5\r\nLMOVE\r\n$1\r\na\r\n$1\r\nb\r\n$4\r\nLEFT\r\n$4\r\nLEFT\r\n";
+        assert_eq!(
+            cluster.check_two_keys(&parse(lmove)),
+            Err(AsError::CrossSlot)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "memcache")]
+    fn test_route_keyless_policy() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-route-keyless".to_string();
+        cc.cache_type = CacheType::Memcache;
+        let nodes = vec!["127.0.0.1:7001".to_string(), "127.0.0.1:7002".to_string()];
+        let version = || {
+            let mut data = BytesMut::from(&b"version\r\n"[..]);
+            let mut codec = mc::FrontCodec::default();
+            codec.decode(&mut data).unwrap().unwrap()
+        };
+
+        // spread across the backends by default
+        let cluster = Cluster::<mc::Cmd>::new(&cc, Rc::default());
+        *cluster.ring.borrow_mut() = HashRing::new(nodes.clone(), vec![10, 10]).unwrap();
+        let routed: HashSet<_> = (0..8).map(|_| cluster.route(&version())).collect();
+        assert_eq!(routed.len(), 2);
+        assert!(!cluster.reply_keyless(&version()));
+
+        cc.keyless_policy = Some(KeylessPolicy::Pinned);
+        let cluster = Cluster::<mc::Cmd>::new(&cc, Rc::default());
+        *cluster.ring.borrow_mut() = HashRing::new(nodes.clone(), vec![10, 10]).unwrap();
+        let hash = cluster.hash.hash(b"");
+        let owner = cluster.ring.borrow().get_node(hash).map(|x| x.to_string());
+        for _ in 0..8 {
+            assert_eq!(cluster.route(&version()), owner);
+        }
+
+        cc.keyless_policy = Some(KeylessPolicy::Local);
+        let cluster = Cluster::<mc::Cmd>::new(&cc, Rc::default());
+        let cmd = version();
+        assert!(cluster.reply_keyless(&cmd));
+        assert!(cmd.is_done());
+        assert_eq!(keyless_get("test-route-keyless", "proxy"), 1);
+    }
+
+    #[test]
+    fn test_proxy_shard() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-proxy-shard".to_string();
+        cc.hash_tag = Some("{}".to_string());
+        cc.servers = vec![
+            "127.0.0.1:7001:10 redis-1".to_string(),
+            "127.0.0.1:7002:10 redis-2".to_string(),
+        ];
+        cc.pin_keys = vec!["counter:* redis-2".to_string()];
+        let get = |key: &str| {
+            parse(format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key).as_bytes())
+        };
+        let field = |fields: &[String], name: &str| {
+            let pos = fields.iter().position(|x| x == name).unwrap();
+            fields[pos + 1].clone()
+        };
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+            cluster.reinit(cc.clone()).unwrap();
+            // answered without proxy_admin
+            let fields = cluster
+                .proxy_command(&["shard".to_string(), "{user1000}.following".to_string()])
+                .unwrap()
+                .unwrap();
+            assert_eq!(field(&fields, "hash_tag"), "user1000");
+            assert_eq!(field(&fields, "role"), "master");
+            assert_eq!(
+                Some(field(&fields, "addr")),
+                cluster.route(&get("{user1000}.following"))
+            );
+            assert!(cluster.proxy_command(&["SHARD".to_string()]).is_err());
+
+            for i in 0..64 {
+                let key = format!("{{user:{}}}.name", i);
+                let shard = cluster.shard(key.as_bytes());
+                assert_eq!(shard.hash_tag, format!("user:{}", i).into_bytes());
+                // the keys of the same tag are on the same point
+                assert_eq!(shard, cluster.shard(format!("user:{}", i).as_bytes()));
+                let point = match shard.position {
+                    Position::Ring(point) => point.unwrap(),
+                    position => panic!("unexpected position {:?}", position),
+                };
+                // the first point not less than hash, or the first point of ring
+                let first = cluster.ring.borrow().points().next().unwrap().0;
+                assert!(point >= shard.hash || point == first);
+                assert_eq!(shard.addr, cluster.route(&get(&key)));
+                assert_eq!(
+                    shard.node.map(|x| cluster.get_node(x)),
+                    cluster.route(&get(&key))
+                );
+            }
+
+            // pinned keys are placed without the point of ring
+            let shard = cluster.shard(b"counter:1");
+            assert_eq!(shard.node, Some("redis-2".to_string()));
+            assert_eq!(shard.addr, Some("127.0.0.1:7002".to_string()));
+            assert_eq!(shard.position, Position::Ring(None));
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_nodes_diff() {
+        let nodes = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|x| (x.0.to_string(), x.1.to_string()))
+                .collect()
+        };
+        let old = nodes(&[
+            ("redis-1", "127.0.0.1:7001"),
+            ("redis-2", "127.0.0.1:7002"),
+            ("redis-3", "127.0.0.1:7003"),
+        ]);
+        let new = nodes(&[
+            ("redis-1", "127.0.0.1:7001"),
+            ("redis-2", "127.0.0.1:7012"),
+            ("redis-4", "127.0.0.1:7004"),
+        ]);
+        let diff = NodesDiff::new(&old, &new);
+        assert_eq!(diff.preserved, vec!["redis-1".to_string()]);
+        assert_eq!(diff.changed, vec!["redis-2".to_string()]);
+        assert_eq!(diff.added, vec!["redis-4".to_string()]);
+        assert_eq!(diff.removed, vec!["redis-3".to_string()]);
+    }
+
+    #[test]
+    fn test_reload_preserve_ejected_nodes() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-reload-preserve".to_string();
+        cc.servers = vec![
+            "127.0.0.1:7001:10 redis-1".to_string(),
+            "127.0.0.1:7002:10 redis-2".to_string(),
+            "127.0.0.1:7003:10 redis-3".to_string(),
+        ];
+        let gets: Vec<_> = (0..64)
+            .map(|i| {
+                let key = format!("key-{}", i);
+                parse(format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key).as_bytes())
+            })
+            .collect();
+        let routed = |cluster: &Cluster<redis::Cmd>, addr: &str| {
+            gets.iter()
+                .any(|x| cluster.route(x) == Some(addr.to_string()))
+        };
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            assert!(routed(&cluster, "127.0.0.1:7001"));
+            // ejected by ping
+            cluster.remove_node("redis-1".to_string(), Eject::PingFailure);
+
+            // the ejected node is neither reconnected nor routed by reloading other settings
+            cc.read_timeout = Some(1000);
+            cluster.reinit(cc.clone()).unwrap();
+            assert!(!routed(&cluster, "127.0.0.1:7001"));
+            assert!(!cluster.conns.borrow().addrs().contains("127.0.0.1:7001"));
+            assert!(cluster.standby.borrow().is_ejected("redis-1"));
+            assert!(cluster.conns.borrow().addrs().contains("127.0.0.1:7002"));
+
+            // and recovered as usual
+            cluster.add_node("redis-1".to_string()).unwrap();
+            assert!(routed(&cluster, "127.0.0.1:7001"));
+            cluster.remove_node("redis-1".to_string(), Eject::PingFailure);
+
+            // the node of new address is fresh
+            cc.servers[0] = "127.0.0.1:7004:10 redis-1".to_string();
+            cluster.reinit(cc.clone()).unwrap();
+            assert!(!cluster.standby.borrow().is_ejected("redis-1"));
+            assert!(cluster.conns.borrow().addrs().contains("127.0.0.1:7004"));
+            assert!(routed(&cluster, "127.0.0.1:7004"));
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_tombstone_of_ejected_node() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-tombstone".to_string();
+        cc.servers = vec![
+            "127.0.0.1:7001:10 redis-1".to_string(),
+            "127.0.0.1:7002:10 redis-2".to_string(),
+        ];
+        cc.tombstone_commands = vec!["MGET".to_string(), "HGETALL empty".to_string()];
+        let ejected = "127.0.0.1:7001";
+        let replied = |cmd: &redis::Cmd| {
+            let mut buf = BytesMut::new();
+            cmd.reply_data(&mut buf);
+            buf
+        };
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            let keys: Vec<_> = (0..64)
+                .map(|i| format!("key-{}", i))
+                .filter(|key| {
+                    let data = format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key);
+                    cluster.route(&parse(data.as_bytes())) == Some(ejected.to_string())
+                })
+                .take(2)
+                .collect();
+            assert_eq!(keys.len(), 2);
+            cluster.remove_node("redis-1".to_string(), Eject::PingFailure);
+
+            // the ejected node keeps its range instead of moving to redis-2
+            let cmd = |args: &[&str]| {
+                let mut data = format!("*{}\r\n", args.len());
+                for arg in args {
+                    data.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+                }
+                parse(data.as_bytes())
+            };
+            let mget = cmd(&["MGET", &keys[0], &keys[1]]);
+            let hgetall = cmd(&["HGETALL", &keys[0]]);
+            let get = cmd(&["GET", &keys[0]]);
+            let set = cmd(&["SET", &keys[0], "v"]);
+            let mut cmds: VecDeque<_> = mget.subs().unwrap().into_iter().collect();
+            cmds.extend(vec![hgetall.clone(), get.clone(), set.clone()]);
+            assert_eq!(cluster.dispatch_all(&mut cmds).unwrap(), 5);
+            assert!(cmds.is_empty());
+
+            // the reads given are replied by the tombstones
+            assert!(mget.is_done());
+            assert_eq!(&replied(&mget)[..], &b"*2\r\n$-1\r\n$-1\r\n"[..]);
+            assert_eq!(&replied(&hgetall)[..], &b"*0\r\n"[..]);
+            assert_eq!(tombstone_get(&cc.name, ejected), 3);
+            // and the others are failed, writes included
+            let err = b"-ERR backend 127.0.0.1:7001 is ejected\r\n";
+            assert!(get.is_error() && set.is_error());
+            assert_eq!(&replied(&get)[..], &err[..]);
+            assert_eq!(&replied(&set)[..], &err[..]);
+
+            // the keyless commands are never routed to the ejected node
+            let ping = cmd(&["PING"]);
+            for _ in 0..4 {
+                assert_eq!(cluster.route(&ping), Some("127.0.0.1:7002".to_string()));
+            }
+
+            // and the range is served by the node again once recovered
+            cluster.add_node("redis-1".to_string()).unwrap();
+            let get = cmd(&["GET", &keys[0]]);
+            let mut cmds: VecDeque<_> = vec![get.clone()].into_iter().collect();
+            assert_eq!(cluster.dispatch_all(&mut cmds).unwrap(), 1);
+            assert!(!get.is_done());
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_eject_by_protocol_violations() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-protocol-violations".to_string();
+        cc.servers = vec![
+            "127.0.0.1:7001:10 redis-1".to_string(),
+            "127.0.0.1:7002:10".to_string(),
+        ];
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            let violations = cluster.violations.borrow()["127.0.0.1:7001"].clone();
+            violations.set(2);
+            assert!(!cluster.check_violations("redis-1", "127.0.0.1:7001"));
+            assert_eq!(
+                cluster.node_states(),
+                vec![
+                    "redis-1 127.0.0.1:7001 active",
+                    "127.0.0.1:7002 127.0.0.1:7002 active"
+                ]
+            );
+
+            violations.set(3);
+            assert!(cluster.check_violations("redis-1", "127.0.0.1:7001"));
+            assert_eq!(violations.get(), 0);
+            assert_eq!(
+                cluster.node_states()[0],
+                "redis-1 127.0.0.1:7001 ejected protocol violation"
+            );
+            assert!(!cluster.conns.borrow().addrs().contains("127.0.0.1:7001"));
+
+            // reconnected by the ping succeeded with the counter shared
+            cluster.add_node("redis-1".to_string()).unwrap();
+            assert_eq!(cluster.node_states()[0], "redis-1 127.0.0.1:7001 active");
+            assert!(Rc::ptr_eq(
+                &cluster.violations.borrow()["127.0.0.1:7001"],
+                &violations
+            ));
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_eject_unavailable_until_pong() {
+        use crate::testsupport::{Action, MockBackend, Script};
+        use tokio::timer::Delay;
+
+        // the mock backend replies LOADING to the first 2 pings and PONG to the others
+        let backend = MockBackend::redis(Script::new().times(
+            "PING",
+            2,
+            vec![Action::Error(
+                "LOADING Redis is loading the dataset in memory".to_string(),
+            )],
+        ));
+        let addr = backend.addr().to_string();
+        let pings = || backend.requests_of("PING").len();
+
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-eject-unavailable".to_string();
+        cc.servers = vec![format!("{}:10 redis-1", addr)];
+        cc.ping_fail_limit = Some(3);
+        cc.ping_succ_interval = Some(50);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let cluster = rt
+            .block_on(lazy(|| {
+                let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+                cluster.reinit(cc.clone()).map(|_| cluster)
+            }))
+            .unwrap();
+        let mut wait = |cond: &dyn Fn() -> bool| {
+            for _ in 0..300 {
+                if cond() {
+                    return true;
+                }
+                let delay = Delay::new(Instant::now() + Duration::from_millis(10));
+                rt.block_on(delay).unwrap();
+            }
+            false
+        };
+
+        // ejected at once by the first LOADING, never after ping_fail_limit failures
+        assert!(wait(&|| cluster.standby.borrow().is_ejected("redis-1")));
+        assert!(pings() <= 2);
+        assert_eq!(
+            cluster.node_states()[0],
+            format!("redis-1 {} ejected unavailable", addr)
+        );
+
+        // and ready only after PONG
+        assert!(wait(&|| !cluster.standby.borrow().is_ejected("redis-1")));
+        assert!(pings() >= 3);
+        assert_eq!(cluster.node_states()[0], format!("redis-1 {} active", addr));
+    }
+
+    // the mock backend replies nil to the GETs received only once they're not held, and counts
+    // the connections accepted
+    fn held_backend() -> (String, Arc<AtomicBool>, Arc<AtomicUsize>) {
+        use std::io::{ErrorKind, Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let hold = Arc::new(AtomicBool::new(true));
+        let accepted = Arc::new(AtomicUsize::new(0));
+        {
+            let hold = hold.clone();
+            let accepted = accepted.clone();
+            thread::spawn(move || {
+                for sock in listener.incoming() {
+                    let mut sock = match sock {
+                        Ok(sock) => sock,
+                        Err(_) => return,
+                    };
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    let hold = hold.clone();
+                    thread::spawn(move || {
+                        sock.set_read_timeout(Some(Duration::from_millis(10)))
+                            .unwrap();
+                        let mut buf = [0u8; 1024];
+                        let mut pending = Vec::new();
+                        let mut held = 0;
+                        loop {
+                            match sock.read(&mut buf) {
+                                Ok(0) => return,
+                                Ok(size) => pending.extend_from_slice(&buf[..size]),
+                                Err(ref err)
+                                    if err.kind() == ErrorKind::WouldBlock
+                                        || err.kind() == ErrorKind::TimedOut => {}
+                                Err(_) => return,
+                            }
+                            while let Some(pos) = pending.windows(5).position(|x| x == b"GET\r\n") {
+                                pending.drain(..pos + 5);
+                                held += 1;
+                            }
+                            if hold.load(Ordering::SeqCst) {
+                                continue;
+                            }
+                            for _ in 0..held {
+                                if sock.write_all(b"$-1\r\n").is_err() {
+                                    return;
+                                }
+                            }
+                            held = 0;
+                        }
+                    });
+                }
+            });
+        }
+        (addr, hold, accepted)
+    }
+
+    #[test]
+    fn test_reload_drain_removed_node() {
+        use crate::metrics::{
+            reload_drain_failed_get, reload_drain_get, reload_drain_inflight_get,
+        };
+        use tokio::timer::Delay;
+
+        let (addr, hold, _) = held_backend();
+
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-reload-drain".to_string();
+        cc.servers = vec![format!("{}:10 redis-1", addr)];
+        cc.ping_fail_limit = Some(0);
+        cc.reload_drain_timeout = Some(60_000);
+        let mut removed = cc.clone();
+        removed.servers = vec!["127.0.0.1:7002:10 redis-2".to_string()];
+        let get = || parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+
+        let wait = |rt: &mut current_thread::Runtime, cond: &dyn Fn() -> bool| {
+            for _ in 0..300 {
+                if cond() {
+                    return true;
+                }
+                let delay = Delay::new(Instant::now() + Duration::from_millis(10));
+                rt.block_on(delay).unwrap();
+            }
+            false
+        };
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let cluster = rt
+            .block_on(lazy(|| {
+                let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+                cluster.reinit(cc.clone()).map(|_| cluster)
+            }))
+            .unwrap();
+
+        // the commands in flight to the removed node are still replied after reload
+        let gets = vec![get(), get()];
+        rt.block_on(lazy(|| cluster.dispatch_all(&mut gets.clone().into())))
+            .unwrap();
+        assert!(wait(&mut rt, &|| cluster.node_inflight("redis-1") == 2));
+        rt.block_on(lazy(|| cluster.reinit(removed.clone())))
+            .unwrap();
+        assert_eq!(cluster.retired.borrow().len(), 1);
+        cluster.check_retired(Instant::now());
+        assert_eq!(reload_drain_inflight_get(&cc.name, &addr), 2.0);
+        assert!(gets.iter().all(|x| !x.is_done()));
+
+        hold.store(false, Ordering::SeqCst);
+        assert!(wait(&mut rt, &|| gets.iter().all(|x| x.is_done())));
+        assert!(gets.iter().all(|x| !x.borrow().is_error()));
+        assert!(wait(&mut rt, &|| {
+            cluster.check_retired(Instant::now());
+            cluster.retired.borrow().is_empty()
+        }));
+        assert_eq!(reload_drain_inflight_get(&cc.name, &addr), 0.0);
+        assert_eq!(reload_drain_get(&cc.name, "drained"), 1);
+
+        // and failed with BackendClosed beyond reload_drain_timeout
+        hold.store(true, Ordering::SeqCst);
+        rt.block_on(lazy(|| cluster.reinit(cc.clone()))).unwrap();
+        let held = get();
+        rt.block_on(lazy(|| {
+            cluster.dispatch_all(&mut vec![held.clone()].into())
+        }))
+        .unwrap();
+        assert!(wait(&mut rt, &|| cluster.node_inflight("redis-1") == 1));
+        rt.block_on(lazy(|| cluster.reinit(removed.clone())))
+            .unwrap();
+        cluster.check_retired(Instant::now() + Duration::from_secs(60));
+        assert!(cluster.retired.borrow().is_empty());
+        assert!(wait(&mut rt, &|| held.is_done()));
+        assert!(held.borrow().is_error());
+        assert_eq!(reload_drain_failed_get(&cc.name, &addr), 1);
+        assert_eq!(reload_drain_get(&cc.name, "expired"), 1);
+        assert_eq!(reload_drain_inflight_get(&cc.name, &addr), 0.0);
+    }
+
+    #[test]
+    fn test_recycle_expired_conns() {
+        use crate::metrics::backend_recycled_get;
+        use std::thread;
+        use tokio::timer::Delay;
+
+        let (addr, hold, accepted) = held_backend();
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-recycle-expired".to_string();
+        cc.servers = vec![format!("{}:10 redis-1", addr)];
+        cc.ping_fail_limit = Some(0);
+        cc.reload_drain_timeout = Some(60_000);
+        cc.backend_max_lifetime = Some(50);
+        cc.backend_lifetime_jitter = Some(0);
+        let get = || parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+
+        let wait = |rt: &mut current_thread::Runtime, cond: &dyn Fn() -> bool| {
+            for _ in 0..300 {
+                if cond() {
+                    return true;
+                }
+                let delay = Delay::new(Instant::now() + Duration::from_millis(10));
+                rt.block_on(delay).unwrap();
+            }
+            false
+        };
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let cluster = rt
+            .block_on(lazy(|| {
+                let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+                cluster.reinit(cc.clone()).map(|_| cluster)
+            }))
+            .unwrap();
+        assert!(cluster.recycle_expired(Instant::now()).is_empty());
+
+        let gets = vec![get(), get()];
+        rt.block_on(lazy(|| cluster.dispatch_all(&mut gets.clone().into())))
+            .unwrap();
+        assert!(wait(&mut rt, &|| cluster.node_inflight("redis-1") == 2));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        // replaced by a fresh connection while the old one is drained
+        thread::sleep(Duration::from_millis(60));
+        let recycled = rt
+            .block_on(lazy(|| {
+                Ok::<_, ()>(cluster.recycle_expired(Instant::now()))
+            }))
+            .unwrap();
+        assert_eq!(recycled, vec![addr.clone()]);
+        assert!(cluster.recycle_expired(Instant::now()).is_empty());
+        assert_eq!(cluster.retired.borrow().len(), 1);
+        assert!(cluster.conns.borrow().addrs().contains(&addr));
+        assert!(wait(&mut rt, &|| accepted.load(Ordering::SeqCst) == 2));
+        assert_eq!(backend_recycled_get(&cc.name, &addr), 1);
+
+        // the commands in flight are never dropped
+        let fresh = get();
+        rt.block_on(lazy(|| {
+            cluster.dispatch_all(&mut vec![fresh.clone()].into())
+        }))
+        .unwrap();
+        hold.store(false, Ordering::SeqCst);
+        assert!(wait(&mut rt, &|| {
+            fresh.is_done() && gets.iter().all(|x| x.is_done())
+        }));
+        assert!(gets.iter().all(|x| !x.borrow().is_error()));
+        assert!(!fresh.borrow().is_error());
+        assert!(wait(&mut rt, &|| {
+            cluster.check_retired(Instant::now());
+            cluster.retired.borrow().is_empty()
+        }));
+    }
+
+    #[test]
+    fn test_evict_idle_conns() {
+        use crate::metrics::idle_eviction_get;
+        use std::thread;
+
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-evict-idle".to_string();
+        cc.servers = vec![
+            "127.0.0.1:7001:10 redis-1".to_string(),
+            "127.0.0.1:7002:10 redis-2".to_string(),
+            "127.0.0.1:7003:10 redis-3".to_string(),
+        ];
+        cc.backend_idle_timeout = Some(50);
+        cc.backend_min_idle = Some(1);
+        let get = parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            assert!(cluster.evict_idle(Instant::now()).is_empty());
+            let used = cluster.route(&get).unwrap();
+
+            // all go idle but the one used most recently, which is kept by backend_min_idle
+            thread::sleep(Duration::from_millis(60));
+            cluster.dispatch_all(&mut vec![get.clone()].into()).unwrap();
+            let mut evicted = cluster.evict_idle(Instant::now());
+            evicted.sort();
+            assert_eq!(evicted.len(), 2);
+            assert!(!evicted.contains(&used));
+            assert_eq!(
+                cluster.conns.borrow().addrs(),
+                vec![used.clone()].into_iter().collect()
+            );
+            for addr in evicted.iter() {
+                assert!(cluster.is_evicted(addr));
+            }
+            assert!(cluster.evict_idle(Instant::now()).is_empty());
+            assert_eq!(idle_eviction_get(&cc.name, &evicted[0]), 1);
+            assert_eq!(idle_eviction_get(&cc.name, &used), 0);
+
+            // and reconnected on demand
+            let cmd = (0..64)
+                .map(|i| {
+                    let key = format!("key-{}", i);
+                    parse(format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key).as_bytes())
+                })
+                .find(|x| cluster.route(x).as_ref() == Some(&evicted[0]))
+                .unwrap();
+            cluster.dispatch_all(&mut vec![cmd].into()).unwrap();
+            assert!(cluster.conns.borrow().addrs().contains(&evicted[0]));
+            assert!(!cluster.is_evicted(&evicted[0]));
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reload_weight_transition() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-reload-transition".to_string();
+        cc.weight_transition = Some(20_000);
+        cc.servers = vec![
+            "127.0.0.1:7001:10 redis-1".to_string(),
+            "127.0.0.1:7002:10 redis-2".to_string(),
+        ];
+        let gets: Vec<_> = (0..256)
+            .map(|i| {
+                let key = format!("key-{}", i);
+                parse(format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key).as_bytes())
+            })
+            .collect();
+        let routed = |cluster: &Cluster<redis::Cmd>| {
+            gets.iter()
+                .filter(|x| cluster.route(x) == Some("127.0.0.1:7002".to_string()))
+                .count()
+        };
+        let weights = |pairs: &[(&str, usize, usize)]| {
+            let weights: Vec<_> = pairs.iter().map(|x| (x.0.to_string(), x.1, x.2)).collect();
+            Some(weights)
+        };
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            assert!(cluster.transition.borrow().is_none());
+            let before = routed(&cluster);
+
+            // the former weights are in effect until the next step
+            cc.servers[1] = "127.0.0.1:7002:30 redis-2".to_string();
+            cluster.reinit(cc.clone()).unwrap();
+            assert_eq!(routed(&cluster), before);
+            assert_eq!(
+                transition::weights(&cc.name),
+                weights(&[("redis-1", 10, 10), ("redis-2", 10, 30)])
+            );
+            // and kept by reloading other settings
+            let current = cluster.transition.borrow().clone().unwrap();
+            cc.read_timeout = Some(1000);
+            cluster.reinit(cc.clone()).unwrap();
+            assert_eq!(cluster.transition.borrow().clone(), Some(current));
+
+            let now = SystemTime::now();
+            cluster.shift_weights(now + Duration::from_secs(10));
+            let (_, effective, _) = transition::weights(&cc.name).unwrap()[1].clone();
+            assert!(effective > 10 && effective < 30, "{}", effective);
+            assert!(routed(&cluster) > before);
+
+            // superseded by the reload changing weights again
+            cc.servers[1] = "127.0.0.1:7002:20 redis-2".to_string();
+            cluster.reinit(cc.clone()).unwrap();
+            let (_, current) = cluster.transition.borrow().clone().unwrap();
+            assert_eq!(current.target()[1], ("redis-2".to_string(), 20));
+
+            cluster.shift_weights(now + Duration::from_secs(60));
+            assert!(cluster.transition.borrow().is_none());
+            assert_eq!(
+                transition::weights(&cc.name),
+                weights(&[("redis-1", 10, 10), ("redis-2", 20, 20)])
+            );
+            let expect = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+            *expect.ring.borrow_mut() = HashRing::new(
+                vec!["redis-1".to_string(), "redis-2".to_string()],
+                vec![10, 20],
+            )
+            .unwrap();
+            *expect.alias.borrow_mut() = cluster.alias.borrow().clone();
+            for get in gets.iter() {
+                assert_eq!(cluster.route(get), expect.route(get));
+            }
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_response_cache_of_cmds() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-response-cache".to_string();
+        cc.response_cache = vec!["GET user:*".to_string()];
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        *cluster.cache.borrow_mut() = RespCache::from_config(&cc).unwrap();
+        let get = || parse(b"*2\r\n$3\r\nget\r\n$6\r\nuser:1\r\n");
+        let reply = || {
+            let mut buf = BytesMut::from(&b"$5\r\nalice\r\n"[..]);
+            let reply: redis::Message = redis::MessageMut::parse(&mut buf).unwrap().unwrap().into();
+            reply
+        };
+
+        // the error reply is never cached
+        let first = get();
+        let ticket = match cluster.cache_lookup(&first) {
+            Lookup::Miss(ticket) => ticket,
+            _ => panic!("the first read must miss"),
+        };
+        first.set_error(&AsError::ProxyFail);
+        cluster.cache_store(ticket, &first);
+        assert!(matches!(cluster.cache_lookup(&get()), Lookup::Pass));
+
+        *cluster.cache.borrow_mut() = RespCache::from_config(&cc).unwrap();
+        let first = get();
+        let ticket = match cluster.cache_lookup(&first) {
+            Lookup::Miss(ticket) => ticket,
+            _ => panic!("the first read must miss"),
+        };
+        first.set_reply(reply());
+        cluster.cache_store(ticket, &first);
+        match cluster.cache_lookup(&get()) {
+            Lookup::Hit(cached) => assert_eq!(cached, reply()),
+            _ => panic!("the read must hit"),
+        }
+
+        // any key of the multi-key write drops the cached reply
+        let mset =
+            parse(b"*5\r\n$4\r\nMSET\r\n$6\r\nuser:2\r\n$1\r\nb\r\n$6\r\nuser:1\r\n$1\r\na\r\n");
+        assert!(matches!(cluster.cache_lookup(&mset), Lookup::Pass));
+        assert!(matches!(cluster.cache_lookup(&get()), Lookup::Miss(_)));
+    }
+
+    #[test]
+    fn test_dispatch_around_saturated_backend() {
+        for overload in [BackendOverload::Queue, BackendOverload::Fail].iter() {
+            let mut cc = ClusterConfig::default();
+            cc.name = "test-saturated-backend".to_string();
+            cc.backend_overload = Some(*overload);
+            let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+            let busy = "127.0.0.1:7001".to_string();
+            let idle = "127.0.0.1:7002".to_string();
+            let nodes = vec![busy.clone(), idle.clone()];
+            *cluster.ring.borrow_mut() = HashRing::new(nodes, vec![10, 10]).unwrap();
+
+            let keys: Vec<_> = (0..16).map(|i| format!("key-{}", i)).collect();
+            let mut data = format!("*{}\r\n$4\r\nMGET\r\n", keys.len() + 1);
+            for key in keys.iter() {
+                data.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
+            }
+            let mget = parse(data.as_bytes());
+            let subs = mget.subs().unwrap();
+            let to_busy: Vec<_> = subs
+                .iter()
+                .map(|x| cluster.route(x) == Some(busy.clone()))
+                .collect();
+            assert!(to_busy.iter().any(|x| *x) && !to_busy.iter().all(|x| *x));
+
+            lazy(|| {
+                // the queue of busy backend is full and never taken
+                let (mut busy_tx, _busy_rx) = channel(1);
+                let filler = parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+                assert!(busy_tx.start_send(filler).unwrap().is_ready());
+                let (idle_tx, mut idle_rx) = channel(keys.len());
+                for (addr, sender) in vec![(busy.clone(), busy_tx), (idle.clone(), idle_tx)] {
+                    let (ctrl, _) = channel(1);
+                    cluster.conns.borrow_mut().insert(Conn {
+                        addr,
+                        sender,
+                        ctrl,
+                        inflight: Rc::default(),
+                        waiting: Rc::default(),
+                        estimator: Rc::default(),
+                        last_used: Instant::now(),
+                    });
+                }
+
+                let mut cmds: VecDeque<_> = subs.iter().cloned().collect();
+                let count = cluster.dispatch_all(&mut cmds).unwrap();
+                // the subs to idle backend are never blocked behind the busy one
+                let mut sent = 0;
+                while let Ok(Async::Ready(Some(sub))) = idle_rx.poll() {
+                    sub.set_reply(redis::Message::plain("OK", redis::RESP_STRING));
+                    sent += 1;
+                }
+                assert_eq!(sent, to_busy.iter().filter(|x| !**x).count());
+
+                match overload {
+                    BackendOverload::Queue => {
+                        // kept in order for the busy backend to catch up
+                        assert_eq!(count, sent);
+                        let held: Vec<_> = subs
+                            .iter()
+                            .zip(to_busy.iter())
+                            .filter(|(_, x)| **x)
+                            .map(|(sub, _)| sub.keys())
+                            .collect();
+                        let cmds: Vec<_> = cmds.iter().map(|x| x.keys()).collect();
+                        assert_eq!(cmds, held);
+                        assert!(!mget.is_done());
+                    }
+                    BackendOverload::Fail => {
+                        assert_eq!(count, subs.len());
+                        assert!(cmds.is_empty());
+                        for (sub, is_busy) in subs.iter().zip(to_busy.iter()) {
+                            assert_eq!(sub.is_error(), *is_busy);
+                        }
+                        // the parent completes at once instead of hanging
+                        assert!(mget.is_done());
+                    }
+                }
+                Ok::<(), ()>(())
+            })
+            .wait()
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_dispatch_queue_timeout() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-queue-timeout".to_string();
+        cc.queue_timeout = Some(20);
+        let cluster = Cluster::<redis::Cmd>::new(&cc, Rc::default());
+        let busy = "127.0.0.1:7001".to_string();
+        *cluster.ring.borrow_mut() = HashRing::new(vec![busy.clone()], vec![10]).unwrap();
+        let get = || parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+
+        lazy(|| {
+            // the queue of busy backend is full and never taken
+            let (mut sender, _rx) = channel(1);
+            assert!(sender.start_send(get()).unwrap().is_ready());
+            let (ctrl, _) = channel(1);
+            cluster.conns.borrow_mut().insert(Conn {
+                addr: busy.clone(),
+                sender,
+                ctrl,
+                inflight: Rc::default(),
+                waiting: Rc::default(),
+                estimator: Rc::default(),
+                last_used: Instant::now(),
+            });
+
+            let before = queue_timeout_get(&cc.name);
+            let stale = get();
+            stale.set_queued(Some(Instant::now() - Duration::from_millis(30)));
+            let fresh = get();
+            fresh.set_queued(Some(Instant::now()));
+            let mut cmds: VecDeque<_> = vec![stale.clone(), fresh.clone()].into_iter().collect();
+            assert_eq!(cluster.dispatch_all(&mut cmds).unwrap(), 1);
+            assert!(stale.is_done() && stale.is_error());
+            assert_eq!(queue_timeout_get(&cc.name), before + 1);
+            // held for the backend, and failed once it waits beyond the limit
+            assert_eq!(cmds.len(), 1);
+            assert!(!fresh.is_done() && fresh.queued().is_some());
+            std::thread::sleep(Duration::from_millis(25));
+            assert_eq!(cluster.dispatch_all(&mut cmds).unwrap(), 1);
+            assert!(cmds.is_empty());
+            assert!(fresh.is_error());
+            assert_eq!(queue_timeout_get(&cc.name), before + 2);
+
+            // never expired once it's sent
+            cluster.conns.borrow_mut().remove(&busy);
+            let (sender, _rx) = channel(1);
+            let (ctrl, _) = channel(1);
+            cluster.conns.borrow_mut().insert(Conn {
+                addr: busy.clone(),
+                sender,
+                ctrl,
+                inflight: Rc::default(),
+                waiting: Rc::default(),
+                estimator: Rc::default(),
+                last_used: Instant::now(),
+            });
+            let sent = get();
+            sent.set_queued(Some(Instant::now()));
+            let mut cmds: VecDeque<_> = vec![sent.clone()].into_iter().collect();
+            assert_eq!(cluster.dispatch_all(&mut cmds).unwrap(), 1);
+            assert!(!sent.is_done() && sent.queued().is_none());
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_proxy_add_and_del_node() {
+        let mut cc = ClusterConfig::default();
+        cc.name = "test-proxy-node".to_string();
+        cc.servers = vec!["127.0.0.1:7001:10".to_string()];
+        cc.ping_fail_limit = Some(0);
+        reload::register(&cc).unwrap();
+        drain::register(&cc.name);
+
+        let proxy = |cluster: &Cluster<redis::Cmd>, args: &[&str]| {
+            let mut data = format!("*{}\r\n$5\r\nPROXY\r\n", args.len() + 1);
+            for arg in args {
+                data.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+            }
+            let cmd = parse(data.as_bytes());
+            assert!(cmd.handle_proxy(|args| cluster.proxy_command(args)));
+            cmd.reply().unwrap()
+        };
+        let ok = redis::Message::plain("OK", redis::RESP_STRING);
+        let gets: Vec<_> = (0..64)
+            .map(|i| {
+                let key = format!("key-{}", i);
+                parse(format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key).as_bytes())
+            })
+            .collect();
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            // denied by default
+            let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+            assert_ne!(proxy(&cluster, &["ADDNODE", "127.0.0.1:7002", "10"]), ok);
+
+            cc.proxy_admin = Some(true);
+            let cluster = Rc::new(Cluster::<redis::Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            let node1 = "127.0.0.1:7001".to_string();
+            let node2 = "127.0.0.1:7002".to_string();
+            assert!(gets.iter().all(|x| cluster.route(x) == Some(node1.clone())));
+
+            assert_ne!(proxy(&cluster, &["ADDNODE", "127.0.0.1:7002"]), ok);
+            assert_ne!(proxy(&cluster, &["ADDNODE", "127.0.0.1:7002", "x"]), ok);
+            assert_ne!(proxy(&cluster, &["ADDNODE", "127.0.0.1:7001", "10"]), ok);
+            assert_ne!(proxy(&cluster, &["RESIZE"]), ok);
+            assert_eq!(proxy(&cluster, &["addnode", "127.0.0.1:7002", "10"]), ok);
+
+            // keys begin routing to the added node once applied by the reloader
+            cluster.reinit(reload::cluster(&cc.name).unwrap()).unwrap();
+            let moved: Vec<_> = gets
+                .iter()
+                .filter(|x| cluster.route(x) == Some(node2.clone()))
+                .collect();
+            assert!(!moved.is_empty());
+
+            assert_ne!(proxy(&cluster, &["DELNODE", "127.0.0.1:7003"]), ok);
+            assert_eq!(proxy(&cluster, &["DELNODE", "127.0.0.1:7002"]), ok);
+            assert_eq!(
+                drain::get_state(&cc.name, &node2),
+                Some(NodeState::Draining)
+            );
+            // the removed node is never routed since draining, and kept in servers until drained
+            *cluster.drains.borrow_mut() = drain::states(&cc.name);
+            assert!(moved
+                .iter()
+                .all(|x| cluster.route(x) == Some(node1.clone())));
+            nodes::remove_drained(&cluster.cc.borrow());
+            assert_eq!(reload::cluster(&cc.name).unwrap().servers.len(), 2);
+
+            drain::report_idle(&cc.name, &node2);
+            assert_eq!(drain::get_state(&cc.name, &node2), Some(NodeState::Drained));
+            nodes::remove_drained(&cluster.cc.borrow());
+            let current = reload::cluster(&cc.name).unwrap();
+            assert_eq!(current.servers, vec!["127.0.0.1:7001:10".to_string()]);
+            cluster.reinit(current).unwrap();
+            assert!(!cluster.spots.borrow().contains_key(&node2));
+
+            // the last node is never removed
+            assert_ne!(proxy(&cluster, &["DELNODE", "127.0.0.1:7001"]), ok);
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! adaptive timeout of backends: the latency of replies of each backend connection is tracked
+//! by the moving average and variance, and the timeout taking the connection as stalled is
+//! mean + k * stddev clamped into [adaptive_timeout_floor, adaptive_timeout_ceiling] instead
+//! of read_timeout. The connection reconnected starts over at the ceiling until it's replied
+//! enough times, so the warming backend is never cut off by the latency of a former one.
+use std::time::Duration;
+
+use crate::com::ClusterConfig;
+
+pub const DEFAULT_K: f64 = 4.0;
+pub const DEFAULT_FLOOR: u64 = 100;
+
+const ALPHA: f64 = 0.125;
+// the timeout is the ceiling until the connection is replied the times
+const MIN_SAMPLES: u64 = 8;
+
+/// the bounds of adaptive timeout in millis, None if it's disabled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub k: f64,
+    pub floor: u64,
+    pub ceiling: u64,
+}
+
+impl Bounds {
+    pub fn from_config(cc: &ClusterConfig) -> Option<Bounds> {
+        if !cc.adaptive_timeout.unwrap_or(false) {
+            return None;
+        }
+        let ceiling = cc.adaptive_timeout_ceiling.or(cc.read_timeout)?;
+        Some(Bounds {
+            k: cc.adaptive_timeout_k.unwrap_or(DEFAULT_K),
+            floor: cc.adaptive_timeout_floor.unwrap_or(DEFAULT_FLOOR),
+            ceiling,
+        })
+    }
+}
+
+/// check the adaptive_timeout settings before they are applied.
+pub fn validate(cc: &ClusterConfig) -> Result<(), String> {
+    if !cc.adaptive_timeout.unwrap_or(false) {
+        return Ok(());
+    }
+    let ceiling = match cc.adaptive_timeout_ceiling.or(cc.read_timeout) {
+        Some(ceiling) => ceiling,
+        None => return Err("adaptive_timeout_ceiling or read_timeout must be given".to_string()),
+    };
+    let k = cc.adaptive_timeout_k.unwrap_or(DEFAULT_K);
+    if !k.is_finite() || k <= 0.0 {
+        return Err("adaptive_timeout_k must be greater than 0".to_string());
+    }
+    let floor = cc.adaptive_timeout_floor.unwrap_or(DEFAULT_FLOOR);
+    if floor == 0 || floor > ceiling {
+        return Err(format!(
+            "adaptive_timeout_floor {} must be in (0, {}]",
+            floor, ceiling
+        ));
+    }
+    Ok(())
+}
+
+/// moving average and variance of the latency in millis of the replies of one connection.
+#[derive(Debug, Default)]
+pub struct Estimator {
+    mean: f64,
+    var: f64,
+    samples: u64,
+}
+
+impl Estimator {
+    pub fn observe(&mut self, elapsed: Duration) {
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        if self.samples == 0 {
+            // the same as the first RTT of TCP
+            self.mean = sample;
+            self.var = (sample / 2.0).powi(2);
+        } else {
+            let diff = sample - self.mean;
+            let incr = ALPHA * diff;
+            self.mean += incr;
+            self.var = (1.0 - ALPHA) * (self.var + diff * incr);
+        }
+        self.samples += 1;
+    }
+
+    pub fn timeout(&self, bounds: &Bounds) -> Duration {
+        if self.samples < MIN_SAMPLES {
+            return Duration::from_millis(bounds.ceiling);
+        }
+        let millis = self.mean + bounds.k * self.var.sqrt();
+        let millis = millis.max(bounds.floor as f64).min(bounds.ceiling as f64);
+        Duration::from_micros((millis * 1000.0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BOUNDS: Bounds = Bounds {
+        k: 4.0,
+        floor: 5,
+        ceiling: 1000,
+    };
+
+    #[test]
+    fn test_adaptive_timeout_bounds() {
+        let mut est = Estimator::default();
+        // the ceiling before enough replies
+        est.observe(Duration::from_millis(1));
+        assert_eq!(est.timeout(&BOUNDS), Duration::from_millis(1000));
+
+        // never below the floor for the fast and steady backend
+        for _ in 0..100 {
+            est.observe(Duration::from_micros(200));
+        }
+        assert_eq!(est.timeout(&BOUNDS), Duration::from_millis(5));
+
+        // never beyond the ceiling for the degraded one
+        for _ in 0..100 {
+            est.observe(Duration::from_secs(3));
+        }
+        assert_eq!(est.timeout(&BOUNDS), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_adaptive_timeout_by_variance() {
+        let mut steady = Estimator::default();
+        let mut jittery = Estimator::default();
+        for i in 0..200 {
+            steady.observe(Duration::from_millis(20));
+            jittery.observe(Duration::from_millis(if i % 2 == 0 { 10 } else { 30 }));
+        }
+        let steady = steady.timeout(&BOUNDS);
+        let jittery = jittery.timeout(&BOUNDS);
+        // converged to the mean of 20 millis, and the jitter of 10 millis widens it by k
+        assert!(steady >= Duration::from_millis(20) && steady < Duration::from_millis(22));
+        assert!(jittery > Duration::from_millis(55) && jittery < Duration::from_millis(65));
+    }
+
+    #[test]
+    fn test_validate_adaptive_timeout() {
+        let mut cc = ClusterConfig::default();
+        assert!(validate(&cc).is_ok());
+        assert_eq!(Bounds::from_config(&cc), None);
+
+        cc.adaptive_timeout = Some(true);
+        assert!(validate(&cc).is_err());
+        cc.read_timeout = Some(1000);
+        assert!(validate(&cc).is_ok());
+        assert_eq!(
+            Bounds::from_config(&cc),
+            Some(Bounds {
+                k: DEFAULT_K,
+                floor: DEFAULT_FLOOR,
+                ceiling: 1000
+            })
+        );
+
+        cc.adaptive_timeout_floor = Some(2000);
+        assert!(validate(&cc).is_err());
+        cc.adaptive_timeout_ceiling = Some(3000);
+        assert!(validate(&cc).is_ok());
+        cc.adaptive_timeout_k = Some(0.0);
+        assert!(validate(&cc).is_err());
+    }
+}
@@ -1,8 +1,21 @@
 use crate::com::AsError;
+use crate::metrics::{client_deadline_expired_incr, protocol_error_incr};
+use crate::metrics::{reply_mismatch_incr, reply_too_large_incr};
 
+use futures::unsync::mpsc::UnboundedSender;
 use futures::{Async, AsyncSink, Future, Sink, Stream};
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
 
+use crate::proxy::budget::Budget;
+
+use crate::proxy::standalone::adaptive::Estimator;
+use crate::proxy::standalone::tick::{self, Clock, Event};
 use crate::proxy::standalone::Request;
 
 const MAX_PIPELINE: usize = 512;
@@ -14,6 +27,35 @@ enum State {
     Closed,
 }
 
+// when the command in flight is sent, and its time limit by command_timeouts or ASTER DEADLINE.
+struct Sent {
+    at: Instant,
+    limit: Option<Duration>,
+    // the limit is the rest of the deadline given by client
+    by_client: bool,
+    // failed by the time limit, and its reply is discarded
+    expired: bool,
+}
+
+impl Sent {
+    fn new<T: Request>(cmd: &T) -> Sent {
+        Sent {
+            at: Instant::now(),
+            limit: cmd.time_limit(),
+            by_client: cmd.deadline().is_some(),
+            expired: false,
+        }
+    }
+
+    // the deadline of the command not failed yet.
+    fn deadline(&self) -> Option<Instant> {
+        match self.limit {
+            Some(limit) if !self.expired => Some(self.at + limit),
+            _ => None,
+        }
+    }
+}
+
 impl State {
     fn is_closing(&self) -> bool {
         self == &State::Closing
@@ -31,17 +73,43 @@ where
     O: Sink<SinkItem = T, SinkError = AsError>,
     R: Stream<Item = T::Reply, Error = AsError>,
 {
-    #[allow(unused)]
     cluster: String,
     addr: String,
     state: State,
+    // stamps the commands sent and replied in trace log
+    clock: Clock,
 
     store: Option<T>,
     cmdq: VecDeque<T>,
+    // when each command of cmdq is sent, in the same order
+    sent: VecDeque<Sent>,
+    // wakes up at the nearest deadline of the commands in flight
+    timer: Option<Delay>,
 
     input: I,
+    // ctrl commands from proxy itself, always be forwarded ahead of input.
+    ctrl: I,
     output: O,
     recv: R,
+    // count of commands sent but not replied, used to report drained backend
+    inflight: Rc<Cell<usize>>,
+    // since when the commands in flight have been waiting for the next reply, None if idle
+    waiting: Rc<Cell<Option<Instant>>>,
+    // latency of the replies for adaptive_timeout
+    estimator: Rc<RefCell<Estimator>>,
+
+    // commands in flight are retried by it if the connection is found stale
+    retry: Option<UnboundedSender<T>>,
+    // the retries of stale are withdrawn from it
+    budget: Arc<Budget>,
+    // any reply received since the connection was idle
+    replied: bool,
+    // the replies are shifted or surplus, none of the pending commands can trust its reply
+    mismatch: bool,
+    // the reason of reply framing violated, the connection is quarantined like mismatch
+    violation: Option<String>,
+    // count of connections to the node closed by violations, checked by ping to eject it
+    violations: Rc<Cell<usize>>,
 }
 
 impl<T, I, O, R> Back<T, I, O, R>
@@ -51,24 +119,192 @@ where
     O: Sink<SinkItem = T, SinkError = AsError>,
     R: Stream<Item = T::Reply, Error = AsError>,
 {
-    pub fn new(cluster: String, addr: String, input: I, output: O, recv: R) -> Back<T, I, O, R> {
+    pub fn new(
+        cluster: String,
+        addr: String,
+        input: I,
+        ctrl: I,
+        output: O,
+        recv: R,
+        inflight: Rc<Cell<usize>>,
+    ) -> Back<T, I, O, R> {
         Back {
+            clock: tick::handle(&cluster),
             cluster,
             addr,
             input,
+            ctrl,
             output,
             recv,
+            inflight,
+            waiting: Rc::default(),
+            estimator: Rc::default(),
             state: State::Running,
             store: None,
             cmdq: VecDeque::with_capacity(MAX_PIPELINE),
+            sent: VecDeque::with_capacity(MAX_PIPELINE),
+            timer: None,
+            retry: None,
+            budget: Arc::default(),
+            replied: false,
+            mismatch: false,
+            violation: None,
+            violations: Rc::default(),
+        }
+    }
+
+    pub fn retry(mut self, retry: UnboundedSender<T>) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn budget(mut self, budget: Arc<Budget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn waiting(mut self, waiting: Rc<Cell<Option<Instant>>>) -> Self {
+        self.waiting = waiting;
+        self
+    }
+
+    pub fn estimator(mut self, estimator: Rc<RefCell<Estimator>>) -> Self {
+        self.estimator = estimator;
+        self
+    }
+
+    pub fn violations(mut self, violations: Rc<Cell<usize>>) -> Self {
+        self.violations = violations;
+        self
+    }
+
+    // the wait is restarted by every reply, so it only grows while the backend makes no
+    // progress at all.
+    fn update_inflight(&self, replied: bool) {
+        self.inflight
+            .set(self.cmdq.len() + self.store.iter().count());
+        let waiting = if self.cmdq.is_empty() {
+            None
+        } else if self.sent.front().and_then(Sent::deadline).is_some() {
+            // the one in front is failed by its own time limit
+            None
+        } else if replied {
+            Some(Instant::now())
+        } else {
+            self.waiting.get().or_else(|| Some(Instant::now()))
+        };
+        self.waiting.set(waiting);
+    }
+
+    // fail the commands in flight beyond their time limits, and wake up at the nearest
+    // deadline of the rest.
+    fn check_deadlines(&mut self) {
+        loop {
+            let now = Instant::now();
+            let mut expired = 0;
+            let mut next: Option<Instant> = None;
+            for (cmd, sent) in self.cmdq.iter().zip(self.sent.iter_mut()) {
+                let deadline = match sent.deadline() {
+                    Some(deadline) => deadline,
+                    None => continue,
+                };
+                if deadline <= now {
+                    if sent.by_client {
+                        client_deadline_expired_incr(&self.cluster, "backend");
+                        cmd.set_error(&AsError::DeadlineExpired);
+                    } else {
+                        let limit = sent.limit.unwrap_or_default();
+                        cmd.set_error(&AsError::CommandTimeout(limit.as_millis() as u64));
+                    }
+                    sent.expired = true;
+                    expired += 1;
+                } else {
+                    next = Some(next.map(|x| x.min(deadline)).unwrap_or(deadline));
+                }
+            }
+            if expired > 0 {
+                warn!(
+                    "backend {} of cluster {} failed {} commands by their time limits",
+                    self.addr, self.cluster, expired
+                );
+                self.update_inflight(false);
+            }
+            let next = match next {
+                Some(next) => next,
+                None => {
+                    self.timer = None;
+                    return;
+                }
+            };
+            match self.timer.as_mut() {
+                Some(timer) => timer.reset(next),
+                None => self.timer = Some(Delay::new(next)),
+            }
+            let timer = self.timer.as_mut().expect("timer must be set");
+            match timer.poll() {
+                Ok(Async::NotReady) => return,
+                Ok(Async::Ready(_)) => continue,
+                Err(err) => {
+                    error!(
+                        "fail to poll deadline of backend {} due {:?}",
+                        self.addr, err
+                    );
+                    return;
+                }
+            }
+        }
+    }
+
+    // the connection reused after idle is reset or closed by backend before any reply,
+    // which means none of the commands in flight is processed.
+    fn is_stale(&self, err: &AsError) -> bool {
+        if self.retry.is_none() || self.replied {
+            return false;
+        }
+        match err {
+            AsError::BackendClosedError(_) => true,
+            AsError::IoError(err) => match err.kind() {
+                ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe => true,
+                _ => false,
+            },
+            _ => false,
         }
     }
 
+    fn retry_stale(&mut self, err: &AsError) {
+        if !self.is_stale(err) {
+            return;
+        }
+        let retry = self.retry.as_ref().expect("retry must be set");
+        let total = self.cmdq.len() + self.store.iter().count();
+        let mut kept = VecDeque::new();
+        for cmd in self.cmdq.drain(0..).chain(self.store.take()) {
+            if cmd.is_ctrl() || !cmd.mark_retry() || !self.budget.try_retry() {
+                kept.push_back(cmd);
+                continue;
+            }
+            if let Err(err) = retry.unbounded_send(cmd) {
+                kept.push_back(err.into_inner());
+            }
+        }
+        info!(
+            "backend {} is stale, retry {} commands on a new connection",
+            self.addr,
+            total - kept.len()
+        );
+        self.cmdq = kept;
+        // the rest are failed by closing
+        self.sent.clear();
+    }
+
     fn try_forward(&mut self) -> Result<Async<State>, AsError> {
         let mut count = 0;
+        let mut pipelined = 0;
         let mut ret_state = State::Running;
 
-        for _ in 0..MAX_PIPELINE {
+        while pipelined < MAX_PIPELINE {
             if let Some(cmd) = self.store.take() {
                 let rcmd = cmd.clone();
                 match self.output.start_send(cmd) {
@@ -78,8 +314,13 @@ where
                     }
                     Ok(AsyncSink::Ready) => {
                         count += 1;
+                        if !rcmd.is_ctrl() {
+                            pipelined += 1;
+                        }
 
                         rcmd.mark_remote(&self.cluster);
+                        self.clock.stamp(Event::Sent, rcmd.trace_id(), &self.addr);
+                        self.sent.push_back(Sent::new(&rcmd));
                         self.cmdq.push_back(rcmd);
                     }
                     Err(err) => {
@@ -87,12 +328,27 @@ where
                             "fail to send cmd to backend to {} due to {}",
                             self.addr, err
                         );
-                        rcmd.set_error(&err);
+                        if self.is_stale(&err) {
+                            // never sent, but retried together with the ones in flight
+                            self.sent.push_back(Sent::new(&rcmd));
+                            self.cmdq.push_back(rcmd);
+                        } else {
+                            rcmd.set_error(&err);
+                        }
                         return Err(err);
                     }
                 }
             }
 
+            match self.ctrl.poll() {
+                Ok(Async::Ready(Some(cmd))) => {
+                    self.store = Some(cmd);
+                    continue;
+                }
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => {}
+                Err(_) => unreachable!(),
+            }
+
             match self.input.poll() {
                 Ok(Async::Ready(Some(cmd))) => {
                     self.store = Some(cmd);
@@ -108,21 +364,62 @@ where
             }
         }
 
+        self.update_inflight(false);
+        // flushed even if nothing new is sent, since the rest of the partial write is only
+        // resumed by it once the socket is writable again
+        self.output.poll_complete()?;
         if count > 0 {
-            self.output.poll_complete()?;
             Ok(Async::Ready(ret_state))
         } else {
             Ok(Async::NotReady)
         }
     }
 
+    // the reply doesn't match the command in front, or there's no command waiting for it.
+    fn on_mismatch(&mut self) -> AsError {
+        error!(
+            "backend {} of cluster {} replied out of order with {} commands in flight, close it",
+            self.addr,
+            self.cluster,
+            self.cmdq.len()
+        );
+        reply_mismatch_incr(&self.cluster, &self.addr);
+        self.mismatch = true;
+        AsError::ReplyMismatch(self.addr.clone())
+    }
+
+    // the reply beyond max_reply_size is never read up, the command is failed and the connection
+    // is closed to be reopened on demand.
+    fn on_too_large(&mut self, err: &AsError) {
+        warn!(
+            "backend {} of cluster {} replied beyond max_reply_size, close it",
+            self.addr, self.cluster
+        );
+        reply_too_large_incr(&self.cluster, &self.addr);
+        let _ = self.sent.pop_front();
+        if let Some(cmd) = self.cmdq.pop_front() {
+            cmd.set_error(err);
+        }
+    }
+
+    // the reply framing is violated, the connection is quarantined: all the commands in
+    // flight are failed and it's closed to be reopened on demand.
+    fn on_protocol_error(&mut self, reason: &str) {
+        error!(
+            "backend {} of cluster {} violated reply framing with {} commands in flight, close it",
+            self.addr,
+            self.cluster,
+            self.cmdq.len()
+        );
+        protocol_error_incr(&self.cluster, &self.addr);
+        self.violations.set(self.violations.get() + 1);
+        self.violation = Some(reason.to_string());
+    }
+
     fn try_recv(&mut self) -> Result<Async<()>, AsError> {
         let mut count = 0usize;
         for _ in 0..MAX_PIPELINE {
-            if self.cmdq.is_empty() {
-                break;
-            }
-
+            // polled even if idle, to find out the surplus reply
             let msg = match self.recv.poll() {
                 Ok(Async::Ready(Some(msg))) => {
                     count += 1;
@@ -136,13 +433,36 @@ where
                 }
                 Err(err) => {
                     error!("fail to recv from {} due {:?}", self.addr, err);
+                    match &err {
+                        AsError::ReplyTooLarge(_) => self.on_too_large(&err),
+                        AsError::ReplyProtocolError(reason) => self.on_protocol_error(reason),
+                        _ => {}
+                    }
                     return Err(err);
                 }
             };
 
+            match self.cmdq.front() {
+                Some(cmd) if cmd.accept_reply(&msg) => {}
+                _ => return Err(self.on_mismatch()),
+            }
             let cmd = self.cmdq.pop_front().expect("cmdq never be empty");
-            cmd.set_reply(msg);
+            let sent = self.sent.pop_front();
+            // the blocking commands wait for the data rather than the backend
+            if let Some(sent) = sent.as_ref() {
+                if !cmd.is_ctrl() && !cmd.is_blocking() {
+                    self.estimator.borrow_mut().observe(sent.at.elapsed());
+                }
+            }
+            // the one failed by CommandTimeout is never replied again
+            if !sent.map(|x| x.expired).unwrap_or(false) {
+                self.clock
+                    .stamp(Event::Received, cmd.trace_id(), &self.addr);
+                cmd.set_reply(msg);
+            }
+            self.replied = !self.cmdq.is_empty();
         }
+        self.update_inflight(count > 0);
         if count > 0 {
             Ok(Async::Ready(()))
         } else {
@@ -151,23 +471,35 @@ where
     }
 
     fn on_closed(&mut self) {
+        let err = if self.mismatch {
+            AsError::ReplyMismatch(self.addr.clone())
+        } else if let Some(reason) = self.violation.as_ref() {
+            AsError::ReplyProtocolError(reason.clone())
+        } else {
+            AsError::BackendClosedError(self.addr.clone())
+        };
         for cmd in self.cmdq.drain(0..) {
-            cmd.set_error(&AsError::BackendClosedError(self.addr.clone()));
+            cmd.set_error(&err);
         }
+        self.sent.clear();
         if let Some(cmd) = self.store.take() {
-            cmd.set_error(&AsError::BackendClosedError(self.addr.clone()));
+            cmd.set_error(&err);
         }
-        loop {
-            match self.input.poll() {
-                Ok(Async::Ready(Some(cmd))) => {
-                    cmd.set_error(&AsError::BackendClosedError(self.addr.clone()));
-                }
-                Ok(Async::Ready(None)) | Ok(Async::NotReady) => {
-                    break;
+        for input in &mut [&mut self.ctrl, &mut self.input] {
+            loop {
+                match input.poll() {
+                    Ok(Async::Ready(Some(cmd))) => {
+                        cmd.set_error(&err);
+                    }
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => {
+                        break;
+                    }
+                    Err(_) => unreachable!(),
                 }
-                Err(_) => unreachable!(),
             }
         }
+        self.inflight.set(0);
+        self.waiting.set(None);
     }
 }
 
@@ -197,6 +529,7 @@ where
             }
 
             if !can_recv && !can_forward {
+                self.check_deadlines();
                 return Ok(Async::NotReady);
             }
 
@@ -212,6 +545,7 @@ where
                     }
                     Err(err) => {
                         warn!("fail to recv from {} error {}", self.addr, err);
+                        self.retry_stale(&err);
                         self.state = State::Closing;
                         continue;
                     }
@@ -234,6 +568,7 @@ where
                     }
                     Err(err) => {
                         warn!("fail to forward to {} error {}", self.addr, err);
+                        self.retry_stale(&err);
                         self.state = State::Closing;
                         continue;
                     }
@@ -285,3 +620,563 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bytes::BytesMut;
+    use futures::unsync::mpsc::{channel, unbounded};
+    use futures::{lazy, Poll};
+    use tokio::codec::Decoder;
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::time::Duration;
+
+    use crate::protocol::redis::{Cmd, Command, Message, MessageMut, RedisNodeCodec};
+
+    fn parse_cmd(data: &[u8]) -> Cmd {
+        let mut src = BytesMut::from(data);
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    }
+
+    fn parse_reply(data: &[u8]) -> Message {
+        let mut src = BytesMut::from(data);
+        MessageMut::parse(&mut src).unwrap().unwrap().into()
+    }
+
+    #[test]
+    fn test_ctrl_bypass_flooded_backend() {
+        let flood = MAX_PIPELINE * 4;
+        let (mut tx, rx) = channel(flood);
+        let (mut ctrl_tx, ctrl_rx) = channel(1);
+        let (out_tx, _out_rx) = channel(flood);
+        let (mut reply_tx, reply_rx) = channel(MAX_PIPELINE);
+        let inflight = Rc::new(Cell::new(0));
+
+        lazy(|| {
+            let cmds: Vec<_> = (0..flood)
+                .map(|_| parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"))
+                .collect();
+            for cmd in cmds.iter() {
+                assert!(tx.start_send(cmd.clone()).unwrap().is_ready());
+            }
+            // the ping is enqueued after the flood of client commands
+            let ping = Cmd::ping_request();
+            assert!(ping.is_ctrl());
+            assert!(ctrl_tx.start_send(ping.clone()).unwrap().is_ready());
+
+            let mut back = Back::new(
+                "test-ctrl".to_string(),
+                "127.0.0.1:7000".to_string(),
+                rx,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::None),
+                reply_rx.map_err(|_| AsError::None),
+                inflight.clone(),
+            );
+            assert!(back.poll().unwrap().is_not_ready());
+
+            // the slow backend only replies to one pipeline of commands
+            for _ in 0..MAX_PIPELINE {
+                let reply = parse_reply(b"$1\r\na\r\n");
+                assert!(reply_tx.start_send(reply).unwrap().is_ready());
+            }
+            assert!(back.poll().unwrap().is_not_ready());
+
+            assert!(ping.is_done());
+            assert!(!ping.is_error());
+            assert!(!cmds[flood - 1].is_done());
+            // all forwarded with the ping, but only one pipeline is replied
+            assert_eq!(inflight.get(), flood + 1 - MAX_PIPELINE);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_waiting_restarted_by_reply() {
+        let (mut tx, rx) = channel(2);
+        let (_ctrl_tx, ctrl_rx) = channel(1);
+        let (out_tx, _out_rx) = channel(2);
+        let (mut reply_tx, reply_rx) = channel(2);
+        let waiting = Rc::new(Cell::new(None));
+
+        lazy(|| {
+            let mut back = Back::new(
+                "test-waiting".to_string(),
+                "127.0.0.1:7000".to_string(),
+                rx,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::None),
+                reply_rx.map_err(|_| AsError::None),
+                Rc::new(Cell::new(0)),
+            )
+            .waiting(waiting.clone());
+            assert!(back.poll().unwrap().is_not_ready());
+            assert_eq!(waiting.get(), None);
+
+            for _ in 0..2 {
+                let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+                assert!(tx.start_send(cmd).unwrap().is_ready());
+            }
+            assert!(back.poll().unwrap().is_not_ready());
+            let since = waiting.get().expect("waiting for replies");
+
+            // no progress without reply
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            assert!(back.poll().unwrap().is_not_ready());
+            assert_eq!(waiting.get(), Some(since));
+
+            let reply = parse_reply(b"$1\r\na\r\n");
+            assert!(reply_tx.start_send(reply.clone()).unwrap().is_ready());
+            assert!(back.poll().unwrap().is_not_ready());
+            assert!(waiting.get().expect("still waiting") > since);
+
+            assert!(reply_tx.start_send(reply).unwrap().is_ready());
+            assert!(back.poll().unwrap().is_not_ready());
+            assert_eq!(waiting.get(), None);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_estimator_by_replies() {
+        use crate::proxy::standalone::adaptive::Bounds;
+
+        let (mut tx, rx) = channel(8);
+        let (_ctrl_tx, ctrl_rx) = channel(1);
+        let (out_tx, _out_rx) = channel(8);
+        let (mut reply_tx, reply_rx) = channel(8);
+        let estimator = Rc::new(RefCell::new(Estimator::default()));
+        let bounds = Bounds {
+            k: 4.0,
+            floor: 1,
+            ceiling: 1000,
+        };
+
+        lazy(|| {
+            let mut back = Back::new(
+                "test-estimator".to_string(),
+                "127.0.0.1:7000".to_string(),
+                rx,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::None),
+                reply_rx.map_err(|_| AsError::None),
+                Rc::new(Cell::new(0)),
+            )
+            .estimator(estimator.clone());
+            for _ in 0..8 {
+                let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+                assert!(tx.start_send(cmd).unwrap().is_ready());
+            }
+            assert!(back.poll().unwrap().is_not_ready());
+            let ceiling = Duration::from_millis(1000);
+            assert_eq!(estimator.borrow().timeout(&bounds), ceiling);
+
+            std::thread::sleep(Duration::from_millis(5));
+            for _ in 0..8 {
+                let reply = parse_reply(b"$1\r\na\r\n");
+                assert!(reply_tx.start_send(reply).unwrap().is_ready());
+            }
+            assert!(back.poll().unwrap().is_not_ready());
+            // adapted to the latency of the replies
+            let timeout = estimator.borrow().timeout(&bounds);
+            assert!(timeout >= Duration::from_millis(5) && timeout < ceiling);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_command_timeouts_by_name() {
+        use crate::proxy::standalone::deadline::CommandTimeouts;
+        use tokio::runtime::current_thread::Runtime;
+
+        let lines = vec!["GET 30".to_string(), "SORT 120".to_string()];
+        let timeouts = CommandTimeouts::new(&lines).unwrap();
+        let (mut tx, rx) = channel(2);
+        let (_ctrl_tx, ctrl_rx) = channel(1);
+        let (out_tx, _out_rx) = channel(2);
+        let (mut reply_tx, reply_rx) = channel(2);
+
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let get = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+            let sort = parse_cmd(b"*2\r\n$4\r\nSORT\r\n$1\r\nl\r\n");
+            for cmd in &[&get, &sort] {
+                // chosen by the name at dispatch
+                cmd.set_time_limit(timeouts.get(&cmd.cmd_name()).unwrap());
+                assert!(tx.start_send((*cmd).clone()).unwrap().is_ready());
+            }
+            let mut back = Back::new(
+                "test-deadline".to_string(),
+                "127.0.0.1:7000".to_string(),
+                rx,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::None),
+                reply_rx.map_err(|_| AsError::None),
+                Rc::new(Cell::new(0)),
+            );
+            assert!(back.poll().unwrap().is_not_ready());
+            assert!(!get.is_done());
+
+            // each is failed at its own deadline
+            std::thread::sleep(Duration::from_millis(60));
+            assert!(back.poll().unwrap().is_not_ready());
+            assert!(get.is_done() && get.is_error());
+            let reply = get.reply().unwrap();
+            assert!(String::from_utf8_lossy(reply.raw_data()).contains("30 millis"));
+            assert!(!sort.is_done());
+
+            std::thread::sleep(Duration::from_millis(90));
+            assert!(back.poll().unwrap().is_not_ready());
+            assert!(sort.is_done() && sort.is_error());
+
+            // the late replies are discarded and the connection is kept
+            for data in &[&b"$1\r\na\r\n"[..], &b"*0\r\n"[..]] {
+                assert!(reply_tx.start_send(parse_reply(data)).unwrap().is_ready());
+            }
+            assert!(back.poll().unwrap().is_not_ready());
+            assert!(get.is_error() && sort.is_error());
+            assert_eq!(back.inflight.get(), 0);
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_retry_on_stale_connection() {
+        let (retry_tx, mut retry_rx) = unbounded();
+
+        // the idle connection is reset by backend at the first write
+        let stale = |input| {
+            let (_ctrl_tx, ctrl_rx) = channel(1);
+            let (out_tx, out_rx) = channel(1);
+            drop(out_rx);
+            let (reply_tx, reply_rx) = channel::<Message>(1);
+            let back = Back::new(
+                "test-retry".to_string(),
+                "127.0.0.1:7000".to_string(),
+                input,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::IoError(ErrorKind::ConnectionReset.into())),
+                reply_rx.map_err(|_| AsError::None),
+                Rc::new(Cell::new(0)),
+            )
+            .retry(retry_tx.clone());
+            // never closed before the write
+            (back, reply_tx)
+        };
+
+        lazy(|| {
+            let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+            let (mut tx, rx) = channel(1);
+            assert!(tx.start_send(cmd.clone()).unwrap().is_ready());
+            let (mut back, _reply_tx) = stale(rx);
+            assert!(back.poll().unwrap().is_ready());
+            assert!(!cmd.is_done());
+
+            // retried on a new connection transparently
+            let retried = match retry_rx.poll() {
+                Ok(Async::Ready(Some(retried))) => retried,
+                _ => panic!("command must be retried"),
+            };
+            let (mut tx, rx) = channel(1);
+            let (_ctrl_tx, ctrl_rx) = channel(1);
+            let (out_tx, _out_rx) = channel(1);
+            let (mut reply_tx, reply_rx) = channel(1);
+            assert!(tx.start_send(retried).unwrap().is_ready());
+            let mut back = Back::new(
+                "test-retry".to_string(),
+                "127.0.0.1:7000".to_string(),
+                rx,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::None),
+                reply_rx.map_err(|_| AsError::None),
+                Rc::new(Cell::new(0)),
+            )
+            .retry(retry_tx.clone());
+            assert!(back.poll().unwrap().is_not_ready());
+            assert!(reply_tx
+                .start_send(parse_reply(b"$1\r\na\r\n"))
+                .unwrap()
+                .is_ready());
+            assert!(back.poll().unwrap().is_not_ready());
+            assert!(cmd.is_done());
+            assert!(!cmd.is_error());
+
+            // never retried twice
+            let cmd = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+            assert!(cmd.mark_retry());
+            let (mut tx, rx) = channel(1);
+            assert!(tx.start_send(cmd.clone()).unwrap().is_ready());
+            let (mut back, _reply_tx) = stale(rx);
+            assert!(back.poll().unwrap().is_ready());
+            assert!(cmd.is_error());
+            assert!(retry_rx.poll().unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reply_too_large_close_backend() {
+        use crate::metrics::reply_too_large_get;
+
+        let addr = "127.0.0.1:7000";
+        lazy(|| {
+            let (mut tx, rx) = channel(4);
+            let (_ctrl_tx, ctrl_rx) = channel(1);
+            let (out_tx, _out_rx) = channel(4);
+            let (mut reply_tx, reply_rx) = channel::<Result<Message, AsError>>(4);
+            let replies = reply_rx.map_err(|_| AsError::None).and_then(|x| x);
+            let mut back = Back::new(
+                "test-reply-too-large".to_string(),
+                addr.to_string(),
+                rx,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::None),
+                replies,
+                Rc::new(Cell::new(0)),
+            );
+            let lrange = parse_cmd(b"*4\r\n$6\r\nLRANGE\r\n$1\r\nl\r\n$1\r\n0\r\n$2\r\n-1\r\n");
+            let get = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+            assert!(tx.start_send(lrange.clone()).unwrap().is_ready());
+            assert!(tx.start_send(get.clone()).unwrap().is_ready());
+            assert!(back.poll().unwrap().is_not_ready());
+            // decoded by the codec as the reply of LRANGE grows beyond the limit
+            assert!(reply_tx
+                .start_send(Err(AsError::ReplyTooLarge(16)))
+                .unwrap()
+                .is_ready());
+            assert!(back.poll().unwrap().is_ready());
+
+            let reply_of = |cmd: &Cmd| {
+                let mut buf = BytesMut::new();
+                cmd.reply_data(&mut buf);
+                buf
+            };
+            let expect = format!("-{}\r\n", AsError::ReplyTooLarge(16));
+            assert_eq!(&reply_of(&lrange)[..], expect.as_bytes());
+            // the others in flight are failed by the closed connection
+            let expect = format!("-{}\r\n", AsError::BackendClosedError(addr.to_string()));
+            assert_eq!(&reply_of(&get)[..], expect.as_bytes());
+            assert_eq!(reply_too_large_get("test-reply-too-large", addr), 1);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    // the socket which takes at most 7 bytes by each write and blocks every other write, and
+    // never has anything to read.
+    struct Throttled {
+        written: Rc<RefCell<Vec<u8>>>,
+        blocked: bool,
+    }
+
+    impl Read for Throttled {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Throttled {}
+
+    impl Write for Throttled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let size = buf.len().min(7);
+            self.written.borrow_mut().extend_from_slice(&buf[..size]);
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Throttled {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn test_partial_write_to_backend() {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let value = "v".repeat(20 * 1024);
+        let set = format!(
+            "*3\r\n$3\r\nSET\r\n$1\r\nb\r\n${}\r\n{}\r\n",
+            value.len(),
+            value
+        );
+        // the large one is beyond the write buffer of framed, which is flushed by sending
+        let reqs = vec![
+            b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n".to_vec(),
+            set.into_bytes(),
+            b"*4\r\n$6\r\nLRANGE\r\n$1\r\nl\r\n$1\r\n0\r\n$2\r\n-1\r\n".to_vec(),
+            b"*2\r\n$3\r\nGET\r\n$1\r\nc\r\n".to_vec(),
+        ];
+        let expect = reqs.concat();
+
+        lazy(|| {
+            let (mut tx, rx) = channel(8);
+            let (_ctrl_tx, ctrl_rx) = channel(1);
+            let sock = Throttled {
+                written: written.clone(),
+                blocked: false,
+            };
+            let (sink, stream) = RedisNodeCodec::default().framed(sock).split();
+            let mut back = Back::new(
+                "test-partial-write".to_string(),
+                "127.0.0.1:7000".to_string(),
+                rx,
+                ctrl_rx,
+                sink,
+                stream,
+                Rc::new(Cell::new(0)),
+            );
+            for req in reqs.iter() {
+                assert!(tx.start_send(parse_cmd(req)).unwrap().is_ready());
+            }
+            // polled as the socket is writable again, without any more command sent
+            for _ in 0..expect.len() * 2 {
+                assert!(back.poll().unwrap().is_not_ready());
+            }
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+        // resumed from where it's blocked, neither lost nor duplicated
+        assert_eq!(written.borrow().len(), expect.len());
+        assert!(*written.borrow() == expect);
+    }
+
+    #[test]
+    fn test_protocol_error_quarantine_backend() {
+        use crate::metrics::protocol_error_get;
+
+        let addr = "127.0.0.1:7000";
+        lazy(|| {
+            let (mut tx, rx) = channel(4);
+            let (_ctrl_tx, ctrl_rx) = channel(1);
+            let (out_tx, _out_rx) = channel(4);
+            let (mut reply_tx, reply_rx) = channel::<Result<Message, AsError>>(4);
+            let replies = reply_rx.map_err(|_| AsError::None).and_then(|x| x);
+            let violations = Rc::new(Cell::new(0));
+            let mut back = Back::new(
+                "test-protocol-error".to_string(),
+                addr.to_string(),
+                rx,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::None),
+                replies,
+                Rc::new(Cell::new(0)),
+            )
+            .violations(violations.clone());
+            let get = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+            let set = parse_cmd(b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\nc\r\n");
+            assert!(tx.start_send(get.clone()).unwrap().is_ready());
+            assert!(tx.start_send(set.clone()).unwrap().is_ready());
+            assert!(back.poll().unwrap().is_not_ready());
+            // decoded by the codec as the bulk length mismatches
+            let violation = || AsError::ReplyProtocolError("invalid framing".to_string());
+            assert!(reply_tx.start_send(Err(violation())).unwrap().is_ready());
+            assert!(back.poll().unwrap().is_ready());
+
+            // all in flight are failed by the violation instead of the replies drifted
+            let expect = format!("-{}\r\n", violation());
+            for cmd in &[&get, &set] {
+                let mut buf = BytesMut::new();
+                cmd.reply_data(&mut buf);
+                assert!(cmd.is_error());
+                assert_eq!(&buf[..], expect.as_bytes());
+            }
+            assert_eq!(violations.get(), 1);
+            assert_eq!(protocol_error_get("test-protocol-error", addr), 1);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reply_mismatch_close_backend() {
+        use crate::metrics::reply_mismatch_get;
+
+        let addr = "127.0.0.1:7000";
+        let expect = format!("-{}\r\n", AsError::ReplyMismatch(addr.to_string()));
+        let is_mismatch = |cmd: &Cmd| {
+            let mut buf = BytesMut::new();
+            cmd.reply_data(&mut buf);
+            cmd.is_error() && &buf[..] == expect.as_bytes()
+        };
+        let connect = |cluster: &str, input, reply_rx| {
+            let (_ctrl_tx, ctrl_rx) = channel(1);
+            let (out_tx, out_rx) = channel(4);
+            let back = Back::new(
+                cluster.to_string(),
+                addr.to_string(),
+                input,
+                ctrl_rx,
+                out_tx.sink_map_err(|_| AsError::None),
+                reply_rx.map_err(|_| AsError::None),
+                Rc::new(Cell::new(0)),
+            );
+            (back, out_rx)
+        };
+
+        lazy(|| {
+            // GET never gets +OK, all in flight are failed instead of shifted
+            let (mut tx, rx) = channel(4);
+            let (mut reply_tx, reply_rx) = channel(4);
+            let (mut back, _out_rx) = connect("test-mismatch", rx, reply_rx);
+            let get = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+            let set = parse_cmd(b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\nc\r\n");
+            assert!(tx.start_send(get.clone()).unwrap().is_ready());
+            assert!(tx.start_send(set.clone()).unwrap().is_ready());
+            assert!(back.poll().unwrap().is_not_ready());
+            assert!(reply_tx
+                .start_send(parse_reply(b"+OK\r\n"))
+                .unwrap()
+                .is_ready());
+            assert!(back.poll().unwrap().is_ready());
+            assert!(is_mismatch(&get));
+            assert!(is_mismatch(&set));
+            assert_eq!(reply_mismatch_get("test-mismatch", addr), 1);
+
+            // the surplus reply after all replied
+            let (mut tx, rx) = channel(4);
+            let (mut reply_tx, reply_rx) = channel(4);
+            let (mut back, _out_rx) = connect("test-surplus", rx, reply_rx);
+            let get = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n");
+            assert!(tx.start_send(get.clone()).unwrap().is_ready());
+            assert!(back.poll().unwrap().is_not_ready());
+            for reply in &[&b"$1\r\na\r\n"[..], &b"$1\r\nb\r\n"[..]] {
+                assert!(reply_tx.start_send(parse_reply(reply)).unwrap().is_ready());
+            }
+            let pending = parse_cmd(b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n");
+            assert!(tx.start_send(pending.clone()).unwrap().is_ready());
+            assert!(back.poll().unwrap().is_ready());
+            assert!(get.is_done());
+            assert!(!get.is_error());
+            assert!(is_mismatch(&pending));
+            assert_eq!(reply_mismatch_get("test-surplus", addr), 1);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
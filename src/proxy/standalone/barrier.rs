@@ -0,0 +1,168 @@
+//! durability barrier of the writes fanned out by a client connection (e.g.: MSET):
+//!
+//! ```text
+//! PROXY BARRIER [timeout]
+//! ```
+//!
+//! replied by the count of backends synced, once every backend written by the connection since
+//! its last barrier replied a marker (the ping request, e.g.: PING of redis) sent behind the
+//! commands queued on the backend connection, which is shared by all the clients of worker.
+//! The barrier is replied in order after all the commands before it, so the markers are only
+//! sent once the writes ahead are replied, and they never overtake the writes retried on a
+//! fresh connection (see retry_on_stale), which are replied before the barrier as well.
+//!
+//! The backends whose marker is failed (e.g.: connection closed) or not replied in timeout
+//! millis are not counted. 0 (default) means no limit, the marker is still failed by the
+//! read_timeout of backend.
+use futures::{Async, Future};
+use tokio::timer::Delay;
+
+use std::time::{Duration, Instant};
+
+use crate::com::AsError;
+use crate::proxy::standalone::Request;
+
+const SUB_CMD_BARRIER: &str = "BARRIER";
+
+/// the timeout millis of PROXY BARRIER, None if the arguments after PROXY are not BARRIER.
+pub fn barrier_timeout(args: &[String]) -> Option<Result<u64, AsError>> {
+    match args.get(0) {
+        Some(sub_cmd) if sub_cmd.eq_ignore_ascii_case(SUB_CMD_BARRIER) => {}
+        _ => return None,
+    }
+    let rslt = match args.len() {
+        1 => Ok(0),
+        2 => args[1].parse::<u64>().map_err(|_| {
+            AsError::BadProxyCommand("timeout is not an integer or out of range".to_string())
+        }),
+        _ => Err(AsError::BadProxyCommand(
+            "wrong number of arguments for 'proxy|barrier' command".to_string(),
+        )),
+    };
+    Some(rslt)
+}
+
+pub struct Barrier<T> {
+    timeout: u64,
+    // sent once all the commands before the barrier are replied
+    markers: Option<Vec<T>>,
+    deadline: Option<Delay>,
+}
+
+impl<T: Request> Barrier<T> {
+    pub fn new(timeout: u64) -> Barrier<T> {
+        Barrier {
+            timeout,
+            markers: None,
+            deadline: None,
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.markers.is_some()
+    }
+
+    /// start waiting for the markers sent to the backends written.
+    pub fn start(&mut self, markers: Vec<T>) {
+        if self.timeout > 0 {
+            let deadline = Instant::now() + Duration::from_millis(self.timeout);
+            self.deadline = Some(Delay::new(deadline));
+        }
+        self.markers = Some(markers);
+    }
+
+    /// the count of backends synced, ready once all the markers are done or timed out.
+    pub fn poll_synced(&mut self) -> Async<usize> {
+        let markers = match self.markers.as_ref() {
+            Some(markers) => markers,
+            None => return Async::NotReady,
+        };
+        let synced = markers
+            .iter()
+            .filter(|x| x.is_done() && x.is_pong())
+            .count();
+        if markers.iter().all(|x| x.is_done()) {
+            return Async::Ready(synced);
+        }
+        match self.deadline.as_mut().map(|x| x.poll()) {
+            Some(Ok(Async::NotReady)) | None => Async::NotReady,
+            Some(Ok(Async::Ready(_))) => Async::Ready(synced),
+            Some(Err(err)) => {
+                error!("fail to poll the timeout of barrier due to {:?}", err);
+                Async::Ready(synced)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::redis::Cmd;
+    use futures::future::poll_fn;
+    use tokio::runtime::current_thread;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_barrier_timeout() {
+        assert_eq!(barrier_timeout(&args(&["barrier"])), Some(Ok(0)));
+        assert_eq!(barrier_timeout(&args(&["BARRIER", "100"])), Some(Ok(100)));
+        assert!(matches!(
+            barrier_timeout(&args(&["BARRIER", "-1"])),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            barrier_timeout(&args(&["BARRIER", "1", "2"])),
+            Some(Err(_))
+        ));
+        assert_eq!(barrier_timeout(&args(&["NODES"])), None);
+        assert_eq!(barrier_timeout(&[]), None);
+    }
+
+    #[test]
+    fn test_barrier_synced() {
+        let mut barrier = Barrier::<Cmd>::new(0);
+        assert!(!barrier.is_started());
+        assert_eq!(barrier.poll_synced(), Async::NotReady);
+
+        let markers = vec![
+            Cmd::ping_request(),
+            Cmd::ping_request(),
+            Cmd::ping_request(),
+        ];
+        barrier.start(markers.clone());
+        assert!(barrier.is_started());
+        markers[0].set_reply("PONG");
+        assert_eq!(barrier.poll_synced(), Async::NotReady);
+        markers[1].set_error(&AsError::BackendClosedError("127.0.0.1:7001".to_string()));
+        markers[2].set_reply("PONG");
+        assert_eq!(barrier.poll_synced(), Async::Ready(2));
+
+        // nothing is written since the last barrier
+        let mut barrier = Barrier::<Cmd>::new(0);
+        barrier.start(Vec::new());
+        assert_eq!(barrier.poll_synced(), Async::Ready(0));
+    }
+
+    #[test]
+    fn test_barrier_timed_out() {
+        let mut barrier = Barrier::<Cmd>::new(10);
+        let markers = vec![Cmd::ping_request(), Cmd::ping_request()];
+        let mut rt = current_thread::Runtime::new().unwrap();
+        let synced = rt
+            .block_on(poll_fn(|| {
+                if !barrier.is_started() {
+                    barrier.start(markers.clone());
+                    markers[0].set_reply("PONG");
+                }
+                Ok::<_, ()>(barrier.poll_synced())
+            }))
+            .unwrap();
+        // the marker never replied is not counted
+        assert_eq!(synced, 1);
+        assert!(!markers[1].is_done());
+    }
+}
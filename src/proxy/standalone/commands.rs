@@ -0,0 +1,160 @@
+//! `PROXY COMMANDS` replies the table of the commands known by proxy, one line for each, e.g.:
+//! to verify what is supported and how each command is routed. Each line is
+//! "${name} ${type} ${first} ${last} ${step} ${flags} ${status}", where the key positions are
+//! the same as COMMAND INFO and the flags are joined by comma ("-" if none):
+//!
+//! ```text
+//! get read 1 1 1 readonly allowed
+//! mset mset 1 -1 2 write allowed
+//! shutdown admin 0 0 0 admin,dangerous denied:dangerous
+//! ```
+//!
+//! The status is evaluated by the live config and state of the worker: allowed, unsupported,
+//! or denied by allow_dangerous, admin_node, blocking_commands or read only mode. FUNCTION is
+//! broadcast if function_broadcast is enabled without admin_node. It's read only and answered
+//! even if proxy_admin is disabled.
+use crate::com::{BlockingCommands, ClusterConfig};
+use crate::protocol::redis::cmd::{CommandFlags, CMD_TYPE};
+use crate::protocol::CmdType;
+
+const SUB_CMD_COMMANDS: &str = "COMMANDS";
+const CMD_FUNCTION: &[u8] = b"FUNCTION";
+
+/// the arguments after PROXY is COMMANDS.
+pub fn is_commands(args: &[String]) -> bool {
+    args.len() == 1 && args[0].eq_ignore_ascii_case(SUB_CMD_COMMANDS)
+}
+
+fn type_name(ctype: CmdType) -> &'static str {
+    match ctype {
+        CmdType::Read => "read",
+        CmdType::Write => "write",
+        CmdType::Ctrl => "ctrl",
+        CmdType::NotSupport => "notsupport",
+        CmdType::MSet => "mset",
+        CmdType::MGet => "mget",
+        CmdType::Exists => "exists",
+        CmdType::Eval => "eval",
+        CmdType::Del => "del",
+        CmdType::Admin => "admin",
+    }
+}
+
+fn flag_names(flags: CommandFlags) -> String {
+    let mut names = flags.names();
+    if flags.contains(CommandFlags::DANGEROUS) {
+        names.push("dangerous");
+    }
+    if names.is_empty() {
+        return "-".to_string();
+    }
+    names.join(",")
+}
+
+// the status of the command by the config, the first denial wins.
+fn status(cc: &ClusterConfig, read_only: bool, name: &str, ctype: CmdType) -> &'static str {
+    let flags = CommandFlags::of(name.as_bytes());
+    if ctype.is_not_support() {
+        return "unsupported";
+    }
+    if flags.contains(CommandFlags::DANGEROUS) && !cc.allows_dangerous(name) {
+        return "denied:dangerous";
+    }
+    if ctype == CmdType::Admin && cc.admin_node.is_none() {
+        if name.as_bytes() == CMD_FUNCTION && cc.function_broadcast.unwrap_or(false) {
+            return "broadcast";
+        }
+        return "denied:admin";
+    }
+    if flags.contains(CommandFlags::BLOCKING)
+        && cc.blocking_commands.unwrap_or_default() == BlockingCommands::Deny
+    {
+        return "denied:blocking";
+    }
+    if read_only && ctype.is_mutation() {
+        return "denied:read_only";
+    }
+    "allowed"
+}
+
+/// the lines of the command table ordered by name, read_only is the live mode of the worker.
+pub fn table(cc: &ClusterConfig, read_only: bool) -> Vec<String> {
+    let mut commands: Vec<_> = CMD_TYPE
+        .iter()
+        .map(|(name, ctype)| (String::from_utf8_lossy(name).to_string(), *ctype))
+        .collect();
+    commands.sort_by(|a, b| a.0.cmp(&b.0));
+    commands
+        .into_iter()
+        .map(|(name, ctype)| {
+            let (first, last, step) = ctype.key_spec();
+            format!(
+                "{} {} {} {} {} {} {}",
+                name.to_ascii_lowercase(),
+                type_name(ctype),
+                first,
+                last,
+                step,
+                flag_names(CommandFlags::of(name.as_bytes())),
+                status(cc, read_only, &name, ctype)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line<'a>(lines: &'a [String], name: &str) -> &'a str {
+        let prefix = format!("{} ", name);
+        lines
+            .iter()
+            .find(|x| x.starts_with(&prefix))
+            .map(|x| x.as_str())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_commands_table() {
+        assert!(is_commands(&["commands".to_string()]));
+        assert!(!is_commands(&["COMMANDS".to_string(), "GET".to_string()]));
+
+        let cc = ClusterConfig::default();
+        let lines = table(&cc, false);
+        assert_eq!(lines.len(), CMD_TYPE.len());
+        assert_eq!(line(&lines, "get"), "get read 1 1 1 readonly allowed");
+        assert_eq!(line(&lines, "mset"), "mset mset 1 -1 2 write allowed");
+        assert_eq!(
+            line(&lines, "shutdown"),
+            "shutdown admin 0 0 0 admin,dangerous denied:dangerous"
+        );
+        assert!(line(&lines, "waitaof").ends_with(" denied:admin"));
+        assert!(line(&lines, "blpop").ends_with(" denied:blocking"));
+        assert!(line(&lines, "keys").ends_with(" unsupported"));
+
+        // by the config and the live mode
+        let cc = ClusterConfig {
+            allow_dangerous: vec!["shutdown".to_string()],
+            admin_node: Some("127.0.0.1:7100".to_string()),
+            blocking_commands: Some(BlockingCommands::Warn),
+            ..Default::default()
+        };
+        let lines = table(&cc, true);
+        assert!(line(&lines, "shutdown").ends_with(" allowed"));
+        assert!(line(&lines, "waitaof").ends_with(" allowed"));
+        // denied unless given by allow_dangerous even if admin_node is set
+        assert!(line(&lines, "failover").ends_with(" denied:dangerous"));
+        assert!(line(&lines, "blpop").ends_with(" denied:read_only"));
+        assert!(line(&lines, "set").ends_with(" denied:read_only"));
+        assert!(line(&lines, "get").ends_with(" allowed"));
+
+        let cc = ClusterConfig {
+            function_broadcast: Some(true),
+            ..Default::default()
+        };
+        let lines = table(&cc, false);
+        assert!(line(&lines, "function").ends_with(" broadcast"));
+        assert!(line(&lines, "waitaof").ends_with(" denied:admin"));
+    }
+}
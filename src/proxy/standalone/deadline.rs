@@ -0,0 +1,130 @@
+//! time limits of commands by name: each of command_timeouts is "${command} ${millis}" in
+//! config, e.g.: "MGET 3000" for the large reads or "GET 50" for the ones which should fail
+//! fast. The limit is chosen at dispatch by the name of the command (each sub of the multi-key
+//! command by itself), and the command is failed once it's not replied in the millis since it's
+//! sent to backend. Its reply arriving later is discarded, so the connection is kept.
+//!
+//! The client may give the rest of its own budget to the next command instead, by the prefix
+//! pipelined right before it on the same connection:
+//!
+//! ```text
+//! ASTER DEADLINE millis
+//! ```
+//!
+//! It's replied OK by proxy and never sent to backend. The next command is dropped without
+//! dispatched if the deadline is expired by then, or it's failed the same as command_timeouts
+//! once the deadline is expired in flight. The deadline not followed by any command expires
+//! with nothing to fail.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::com::AsError;
+
+const SUB_CMD_DEADLINE: &[u8] = b"DEADLINE";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandTimeouts {
+    // upper case command and its limit
+    commands: HashMap<String, Duration>,
+}
+
+impl CommandTimeouts {
+    pub fn new(lines: &[String]) -> Result<CommandTimeouts, AsError> {
+        let mut commands = HashMap::new();
+        for line in lines {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let millis = match fields.as_slice() {
+                [_, millis] => millis.parse::<u64>().ok().filter(|x| *x > 0),
+                _ => None,
+            };
+            let millis = millis.ok_or_else(|| {
+                AsError::BadConfig(format!(
+                    "command_timeouts: {} must be \"${{command}} ${{millis}}\"",
+                    line
+                ))
+            })?;
+            commands.insert(fields[0].to_uppercase(), Duration::from_millis(millis));
+        }
+        Ok(CommandTimeouts { commands })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.commands.is_empty()
+    }
+
+    /// the time limit of the command, None if it's not given.
+    pub fn get(&self, name: &str) -> Option<Duration> {
+        self.commands.get(&name.to_uppercase()).cloned()
+    }
+}
+
+/// the budget of ASTER DEADLINE by the arguments after ASTER.
+pub fn client_deadline(args: &[&[u8]]) -> Result<Duration, AsError> {
+    match args.get(0) {
+        Some(sub_cmd) if sub_cmd.eq_ignore_ascii_case(SUB_CMD_DEADLINE) => {}
+        Some(sub_cmd) => {
+            return Err(AsError::BadClientCommand(format!(
+                "unknown subcommand '{}'. Try DEADLINE.",
+                String::from_utf8_lossy(sub_cmd)
+            )))
+        }
+        None => {
+            return Err(AsError::BadClientCommand(
+                "wrong number of arguments for 'aster' command".to_string(),
+            ))
+        }
+    }
+    match args {
+        [_, millis] => btoi::btoi::<u64>(millis)
+            .map(Duration::from_millis)
+            .map_err(|_| {
+                AsError::BadClientCommand("deadline is not an integer or out of range".to_string())
+            }),
+        _ => Err(AsError::BadClientCommand(
+            "wrong number of arguments for 'aster|deadline' command".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_command_timeouts_parse() {
+        let lines: Vec<_> = ["mget 3000", "GET  50"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let timeouts = CommandTimeouts::new(&lines).unwrap();
+        assert!(timeouts.is_enabled());
+        assert_eq!(timeouts.get("MGET"), Some(Duration::from_millis(3000)));
+        assert_eq!(timeouts.get("get"), Some(Duration::from_millis(50)));
+        assert_eq!(timeouts.get("SET"), None);
+
+        for bad in &["GET", "GET 0", "GET fast", "GET 50 100"] {
+            assert!(CommandTimeouts::new(&[bad.to_string()]).is_err());
+        }
+        assert!(!CommandTimeouts::new(&[]).unwrap().is_enabled());
+    }
+
+    #[test]
+    fn test_client_deadline_parse() {
+        let parse = |args: &[&str]| {
+            let args: Vec<_> = args.iter().map(|x| x.as_bytes()).collect();
+            client_deadline(&args)
+        };
+        assert_eq!(parse(&["deadline", "250"]), Ok(Duration::from_millis(250)));
+        assert_eq!(parse(&["DEADLINE", "0"]), Ok(Duration::from_millis(0)));
+        for bad in &[
+            &["DEADLINE"][..],
+            &["DEADLINE", "-1"],
+            &["DEADLINE", "soon"],
+            &["DEADLINE", "10", "20"],
+            &["BUDGET", "10"],
+            &[],
+        ] {
+            assert!(parse(bad).is_err());
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! deduplication of identical writes (same command, key and value) within a short window,
+//! the duplicates are never sent to backend and replied by the reply of the first one.
+use bytes::Bytes;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::com::AsError;
+use crate::proxy::standalone::Request;
+
+#[derive(Debug, PartialEq)]
+pub enum Join {
+    // the first write in flight, which must be completed by its front
+    Leader(Bytes),
+    // the duplicate waiting for the reply of leader
+    Follower,
+    // the identical leader is out of window, sent to backend as usual
+    Pass,
+}
+
+struct Entry<T> {
+    since: Instant,
+    followers: Vec<T>,
+}
+
+pub struct Dedup<T> {
+    entries: HashMap<Bytes, Entry<T>>,
+}
+
+impl<T> Default for Dedup<T> {
+    fn default() -> Self {
+        Dedup {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Request> Dedup<T> {
+    pub fn join(&mut self, cmd: &T, now: Instant, window: Duration) -> Join {
+        let req = cmd.req_data();
+        match self.entries.get_mut(&req) {
+            Some(entry) if now.duration_since(entry.since) <= window => {
+                entry.followers.push(cmd.clone());
+                Join::Follower
+            }
+            Some(_) => Join::Pass,
+            None => {
+                let entry = Entry {
+                    since: now,
+                    followers: Vec::new(),
+                };
+                self.entries.insert(req.clone(), entry);
+                Join::Leader(req)
+            }
+        }
+    }
+
+    /// reply all the followers of the leader, which are notified once dropped here.
+    pub fn complete(&mut self, req: &Bytes, reply: Option<T::Reply>) {
+        let entry = match self.entries.remove(req) {
+            Some(entry) => entry,
+            None => return,
+        };
+        for follower in entry.followers {
+            match reply.as_ref() {
+                Some(reply) => follower.set_reply(reply.clone()),
+                None => follower.set_error(&AsError::ProxyFail),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::redis::{Cmd, Command, Message, MessageMut};
+    use bytes::BytesMut;
+
+    fn set_cmd() -> Cmd {
+        let mut src = BytesMut::from(&b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n"[..]);
+        Command::parse_cmd(&mut src).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_dedup_identical_writes() {
+        let window = Duration::from_millis(5);
+        let now = Instant::now();
+        let mut dedup = Dedup::default();
+
+        let leader = set_cmd();
+        let key = match dedup.join(&leader, now, window) {
+            Join::Leader(key) => key,
+            other => panic!("unexpected join {:?}", other),
+        };
+        let follower = set_cmd();
+        assert_eq!(dedup.join(&follower, now, window), Join::Follower);
+        // too late to join, which is sent to backend as usual
+        let late = set_cmd();
+        assert_eq!(dedup.join(&late, now + window * 2, window), Join::Pass);
+        assert!(!follower.is_done());
+
+        // only the leader reaches backend
+        let mut buf = BytesMut::from(&b"+OK\r\n"[..]);
+        let reply: Message = MessageMut::parse(&mut buf).unwrap().unwrap().into();
+        leader.set_reply(reply.clone());
+        dedup.complete(&key, leader.reply());
+        assert!(follower.is_done());
+        assert!(!follower.is_error());
+        assert_eq!(follower.reply(), Some(reply));
+
+        // the next write leads again after completed
+        match dedup.join(&set_cmd(), now + window * 2, window) {
+            Join::Leader(_) => {}
+            other => panic!("unexpected join {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! the protocols of redis backends given by backend_protocols, so one ring can route to the
+//! backends speaking different dialects during a migration. Each is "${node} ${protocol}", e.g.:
+//! "redis-2 resp3", the node is named by alias or address as in servers, and the one not given
+//! speaks RESP2 as before:
+//!
+//! - resp2: the commands and replies are framed by RESP2.
+//! - resp3: the connection is switched by HELLO 3 ahead of its first command, and the replies of
+//!   RESP3 are rendered by the protocol of each client like the ones of RESP2.
+use std::collections::HashMap;
+
+use crate::com::{AsError, ClusterConfig};
+use crate::protocol::redis::RespVersion;
+use crate::proxy::standalone::{is_server_of, node_name};
+
+fn parse_protocol(name: &str) -> Option<RespVersion> {
+    match name {
+        "resp2" => Some(RespVersion::Resp2),
+        "resp3" => Some(RespVersion::Resp3),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackendProtocols {
+    // the node named by alias or address and its protocol
+    nodes: HashMap<String, RespVersion>,
+}
+
+impl BackendProtocols {
+    pub fn new(lines: &[String]) -> Result<BackendProtocols, AsError> {
+        let mut nodes = HashMap::new();
+        for line in lines {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let protocol = match fields.as_slice() {
+                [_, protocol] => parse_protocol(protocol),
+                _ => None,
+            };
+            let protocol = protocol.ok_or_else(|| {
+                AsError::BadConfig(format!(
+                    "backend_protocols: {} must be \"${{node}} resp2|resp3\"",
+                    line
+                ))
+            })?;
+            nodes.insert(fields[0].to_string(), protocol);
+        }
+        Ok(BackendProtocols { nodes })
+    }
+
+    /// the protocol of the backend connected by the address, RESP2 if it's not given.
+    pub fn get(&self, cc: &ClusterConfig, addr: &str) -> RespVersion {
+        let name = cc
+            .servers
+            .iter()
+            .find(|line| is_server_of(line, addr))
+            .and_then(|line| node_name(line));
+        name.and_then(|x| self.nodes.get(&x))
+            .or_else(|| self.nodes.get(addr))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backend_protocols() {
+        let cc = ClusterConfig {
+            servers: vec![
+                "127.0.0.1:7001:10 redis-1".to_string(),
+                "127.0.0.1:7002:10 redis-2".to_string(),
+                "127.0.0.1:7003:10".to_string(),
+            ],
+            ..Default::default()
+        };
+        let lines: Vec<_> = ["redis-2 resp3", "127.0.0.1:7003 resp3", "redis-1 resp2"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let protocols = BackendProtocols::new(&lines).unwrap();
+        assert_eq!(protocols.get(&cc, "127.0.0.1:7001"), RespVersion::Resp2);
+        assert_eq!(protocols.get(&cc, "127.0.0.1:7002"), RespVersion::Resp3);
+        assert_eq!(protocols.get(&cc, "127.0.0.1:7003"), RespVersion::Resp3);
+        assert_eq!(protocols.get(&cc, "127.0.0.1:7009"), RespVersion::Resp2);
+
+        for bad in &["redis-2", "redis-2 resp1", "redis-2 resp3 resp2"] {
+            assert!(BackendProtocols::new(&[bad.to_string()]).is_err());
+        }
+    }
+}
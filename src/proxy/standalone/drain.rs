@@ -0,0 +1,306 @@
+//! administrative state of backends for planned maintenance, shared by all the worker threads.
+use futures::{Async, Future, Stream};
+use tokio::timer::Interval;
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Weak;
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::proxy::standalone::{nodes, Cluster, Request};
+
+const CHECK_INTERVAL: u64 = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeState {
+    Active,
+    // never routed any new request, but in-flight requests are finished
+    Draining,
+    // draining and all in-flight requests are finished, reported by workers
+    Drained,
+    // never routed and connection is closed immediately
+    Disabled,
+}
+
+impl NodeState {
+    pub fn parse(state: &str) -> Option<NodeState> {
+        match state {
+            "active" => Some(NodeState::Active),
+            "draining" => Some(NodeState::Draining),
+            "disabled" => Some(NodeState::Disabled),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeState::Active => "active",
+            NodeState::Draining => "draining",
+            NodeState::Drained => "drained",
+            NodeState::Disabled => "disabled",
+        }
+    }
+
+    pub fn is_active(self) -> bool {
+        self == NodeState::Active
+    }
+
+    /// the connection of node is closed and ping is paused.
+    pub fn is_closed(self) -> bool {
+        self == NodeState::Drained || self == NodeState::Disabled
+    }
+}
+
+struct NodeDrain {
+    state: NodeState,
+    // workers which have no in-flight requests to the node since draining
+    idle: HashSet<ThreadId>,
+    // removed from servers once drained, by PROXY DELNODE
+    remove: bool,
+}
+
+#[derive(Default)]
+struct Drains {
+    workers: HashSet<ThreadId>,
+    nodes: HashMap<String, NodeDrain>,
+}
+
+lazy_static! {
+    static ref DRAINS: Mutex<HashMap<String, Drains>> = Mutex::new(HashMap::new());
+}
+
+/// register the current worker thread of the cluster.
+pub fn register(cluster: &str) {
+    let mut drains = DRAINS.lock().unwrap();
+    drains
+        .entry(cluster.to_string())
+        .or_insert_with(Drains::default)
+        .workers
+        .insert(thread::current().id());
+}
+
+/// set the state of the node which is named by alias or address as in servers,
+/// return false if the cluster is not running.
+pub fn set_state(cluster: &str, node: &str, state: NodeState) -> bool {
+    let mut drains = DRAINS.lock().unwrap();
+    let drains = match drains.get_mut(cluster) {
+        Some(drains) => drains,
+        None => return false,
+    };
+    if state.is_active() {
+        drains.nodes.remove(node);
+    } else {
+        drains.nodes.insert(
+            node.to_string(),
+            NodeDrain {
+                state,
+                idle: HashSet::new(),
+                remove: false,
+            },
+        );
+    }
+    true
+}
+
+/// drain the node and remove it from servers once drained, return false if the cluster
+/// is not running. It's canceled by setting any state of the node before drained.
+pub fn remove(cluster: &str, node: &str) -> bool {
+    let mut drains = DRAINS.lock().unwrap();
+    let drains = match drains.get_mut(cluster) {
+        Some(drains) => drains,
+        None => return false,
+    };
+    drains.nodes.insert(
+        node.to_string(),
+        NodeDrain {
+            state: NodeState::Draining,
+            idle: HashSet::new(),
+            remove: true,
+        },
+    );
+    true
+}
+
+/// take the drained nodes which are waiting to be removed, each node is taken only once.
+/// the nodes are kept drained, which is never routed before removed by every worker.
+pub fn take_removed(cluster: &str) -> Vec<String> {
+    let mut drains = DRAINS.lock().unwrap();
+    let drains = match drains.get_mut(cluster) {
+        Some(drains) => drains,
+        None => return Vec::new(),
+    };
+    drains
+        .nodes
+        .iter_mut()
+        .filter(|(_, drain)| drain.remove && drain.state == NodeState::Drained)
+        .map(|(node, drain)| {
+            drain.remove = false;
+            node.clone()
+        })
+        .collect()
+}
+
+/// get the state of the node, return None if the cluster is not running.
+pub fn get_state(cluster: &str, node: &str) -> Option<NodeState> {
+    let drains = DRAINS.lock().unwrap();
+    drains.get(cluster).map(|drains| {
+        drains
+            .nodes
+            .get(node)
+            .map(|x| x.state)
+            .unwrap_or(NodeState::Active)
+    })
+}
+
+/// all the nodes of the cluster which is not active.
+pub fn states(cluster: &str) -> HashMap<String, NodeState> {
+    let drains = DRAINS.lock().unwrap();
+    drains
+        .get(cluster)
+        .map(|drains| {
+            drains
+                .nodes
+                .iter()
+                .map(|(node, drain)| (node.clone(), drain.state))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// report the draining node has no in-flight requests in the current worker thread,
+/// the node is drained once all the workers reported.
+pub fn report_idle(cluster: &str, node: &str) {
+    let mut drains = DRAINS.lock().unwrap();
+    if let Some(drains) = drains.get_mut(cluster) {
+        if let Some(drain) = drains.nodes.get_mut(node) {
+            if drain.state != NodeState::Draining {
+                return;
+            }
+            drain.idle.insert(thread::current().id());
+            if drains.workers.is_subset(&drain.idle) {
+                info!("node {} of cluster {} is drained", node, cluster);
+                drain.state = NodeState::Drained;
+            }
+        }
+    }
+}
+
+pub struct Drain<T> {
+    cluster: Weak<Cluster<T>>,
+    interval: Interval,
+}
+
+impl<T: Request + 'static> Drain<T> {
+    pub fn new(cluster: Weak<Cluster<T>>, name: &str) -> Self {
+        register(name);
+        Drain {
+            cluster,
+            interval: Interval::new(
+                Instant::now() + Duration::from_millis(CHECK_INTERVAL),
+                Duration::from_millis(CHECK_INTERVAL),
+            ),
+        }
+    }
+}
+
+impl<T: Request + 'static> Future for Drain<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    error!("fail to poll drain interval due {:?}", err);
+                    return Err(());
+                }
+            }
+
+            let cluster = match self.cluster.upgrade() {
+                Some(cluster) => cluster,
+                None => return Ok(Async::Ready(())),
+            };
+            let name = cluster.cc.borrow().name.clone();
+            let states = states(&name);
+            let last = cluster.drains.replace(states.clone());
+            for (node, state) in states.iter() {
+                if state.is_closed() {
+                    cluster.close_node(node);
+                    continue;
+                }
+                // requests routed before the last check may be still queued in the channel
+                let was_draining = last.get(node) == Some(&NodeState::Draining);
+                if *state == NodeState::Draining && was_draining && cluster.node_inflight(node) == 0
+                {
+                    report_idle(&name, node);
+                }
+            }
+            nodes::remove_drained(&cluster.cc.borrow());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_drain_state() {
+        let cluster = "test-drain-state";
+        assert!(!set_state(cluster, "redis-1", NodeState::Draining));
+        assert_eq!(get_state(cluster, "redis-1"), None);
+
+        register(cluster);
+        assert_eq!(get_state(cluster, "redis-1"), Some(NodeState::Active));
+        assert!(set_state(cluster, "redis-1", NodeState::Draining));
+        assert_eq!(get_state(cluster, "redis-1"), Some(NodeState::Draining));
+
+        // drained after all the workers reported
+        let (registered_tx, registered_rx) = mpsc::channel();
+        let (report_tx, report_rx) = mpsc::channel::<()>();
+        let other = thread::spawn(move || {
+            register(cluster);
+            registered_tx.send(()).unwrap();
+            report_rx.recv().unwrap();
+            report_idle(cluster, "redis-1");
+        });
+        registered_rx.recv().unwrap();
+        report_idle(cluster, "redis-1");
+        assert_eq!(get_state(cluster, "redis-1"), Some(NodeState::Draining));
+        report_tx.send(()).unwrap();
+        other.join().unwrap();
+        assert_eq!(get_state(cluster, "redis-1"), Some(NodeState::Drained));
+        assert_eq!(states(cluster).len(), 1);
+
+        assert!(set_state(cluster, "redis-1", NodeState::Active));
+        assert_eq!(get_state(cluster, "redis-1"), Some(NodeState::Active));
+        assert!(states(cluster).is_empty());
+    }
+
+    #[test]
+    fn test_drain_remove() {
+        let cluster = "test-drain-remove";
+        assert!(!remove(cluster, "redis-1"));
+        assert!(take_removed(cluster).is_empty());
+
+        register(cluster);
+        assert!(remove(cluster, "redis-1"));
+        assert!(remove(cluster, "redis-2"));
+        assert_eq!(get_state(cluster, "redis-1"), Some(NodeState::Draining));
+        // canceled before drained
+        assert!(set_state(cluster, "redis-2", NodeState::Draining));
+
+        // taken only after drained
+        assert!(take_removed(cluster).is_empty());
+        report_idle(cluster, "redis-1");
+        report_idle(cluster, "redis-2");
+        assert_eq!(take_removed(cluster), vec!["redis-1".to_string()]);
+        assert!(take_removed(cluster).is_empty());
+        assert_eq!(get_state(cluster, "redis-1"), Some(NodeState::Drained));
+    }
+}
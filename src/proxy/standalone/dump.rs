@@ -0,0 +1,88 @@
+//! `PROXY CONFIG DUMP` replies the config the worker is really using as the lines of toml, e.g.:
+//! to audit it against the file on disk. It's the cluster after hot reload and the runtime
+//! changes of PROXY ADDNODE/DELNODE, with the thread resolved from the environment and the
+//! defaults of the fields absent filled in, while the passwords of users are redacted. It's
+//! denied unless proxy_admin is enabled.
+use crate::com::{AsError, ClusterConfig};
+
+const SUB_CMD_CONFIG: &str = "CONFIG";
+const SUB_CMD_DUMP: &str = "DUMP";
+const REDACTED: &str = "<redacted>";
+
+/// the arguments after PROXY is CONFIG DUMP.
+pub fn is_dump(args: &[String]) -> bool {
+    args.len() == 2
+        && args[0].eq_ignore_ascii_case(SUB_CMD_CONFIG)
+        && args[1].eq_ignore_ascii_case(SUB_CMD_DUMP)
+}
+
+fn redact(mut cc: ClusterConfig) -> ClusterConfig {
+    for user in cc.users.iter_mut() {
+        user.password = REDACTED.to_string();
+    }
+    cc
+}
+
+/// the lines of the effective config in toml, which is denied unless proxy_admin is enabled.
+pub fn dump(cc: &ClusterConfig) -> Result<Vec<String>, AsError> {
+    if !cc.proxy_admin.unwrap_or(false) {
+        return Err(AsError::RequestNotSupport);
+    }
+    // the values are ordered ahead of the tables by toml::Value
+    let data = toml::Value::try_from(redact(cc.effective()))
+        .and_then(|value| toml::to_string(&value))
+        .map_err(|err| AsError::BadProxyCommand(format!("fail to dump config due {}", err)))?;
+    Ok(data.lines().map(|x| x.to_string()).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::com::UserConfig;
+
+    #[test]
+    fn test_config_dump() {
+        let args = |line: &str| -> Vec<String> {
+            line.split_whitespace().map(|x| x.to_string()).collect()
+        };
+        assert!(is_dump(&args("config dump")));
+        assert!(!is_dump(&args("CONFIG")));
+        assert!(!is_dump(&args("CONFIG DUMP all")));
+
+        let digest = "7c4a8d09ca3762af61e59520943dc26494f8941b";
+        let cc = ClusterConfig {
+            name: "test-dump".to_string(),
+            listen_addr: "127.0.0.1:9001".to_string(),
+            thread: Some(2),
+            servers: vec!["127.0.0.1:7001:10 redis-1".to_string()],
+            reload_drain_timeout: Some(5000),
+            proxy_admin: Some(true),
+            users: vec![UserConfig {
+                name: "app".to_string(),
+                password: digest.to_string(),
+                commands: vec!["read".to_string()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let lines = dump(&cc).unwrap();
+        let has = |line: &str| lines.iter().any(|x| x == line);
+        // overridden, defaults filled in and the rest as is
+        assert!(has("reload_drain_timeout = 5000"));
+        assert!(has(&format!("dedup_window = {}", cc.dedup_window())));
+        assert!(has("thread = 2"));
+        assert!(has("servers = [\"127.0.0.1:7001:10 redis-1\"]"));
+        assert!(has("cache_type = \"redis_cluster\""));
+        // never a credential
+        assert!(has("[[users]]"));
+        assert!(has("password = \"<redacted>\""));
+        assert!(lines.iter().all(|x| !x.contains(digest)));
+        assert_eq!(cc.users[0].password, digest);
+
+        let denied = ClusterConfig {
+            proxy_admin: None,
+            ..cc
+        };
+        assert!(dump(&denied).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use futures::{Async, Future, Stream};
+use tokio::timer::Interval;
+
+use std::collections::HashMap;
+use std::rc::Weak;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::com::{AsError, ClusterConfig};
+use crate::metrics::standby_active_set;
+use crate::proxy::standalone::ketama::HashRing;
+use crate::proxy::standalone::{Cluster, Request, ServerLine};
+
+const DEFAULT_FAIL_RATIO: f64 = 0.5;
+const DEFAULT_FAIL_GRACE: u64 = 10_000;
+const CHECK_INTERVAL: u64 = 1_000;
+
+lazy_static! {
+    static ref FAILBACK: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+/// request all worker threads of the cluster to failback from standby backends.
+pub fn request_failback(cluster: &str) {
+    let mut handle = FAILBACK.lock().unwrap();
+    *handle.entry(cluster.to_string()).or_insert(0) += 1;
+}
+
+fn failback_version(cluster: &str) -> usize {
+    let handle = FAILBACK.lock().unwrap();
+    handle.get(cluster).cloned().unwrap_or(0)
+}
+
+/// the reason the primary node was ejected for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eject {
+    PingFailure,
+    // the connections were closed by the violations of reply framing protocol_error_limit times
+    ProtocolViolation,
+    // the backend replied it can't serve for now (e.g.: LOADING), see proxy::compat
+    Unavailable,
+}
+
+impl Eject {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Eject::PingFailure => "ping failure",
+            Eject::ProtocolViolation => "protocol violation",
+            Eject::Unavailable => "unavailable",
+        }
+    }
+}
+
+pub struct Standby {
+    ring: HashRing,
+    addrs: Vec<String>,
+    active: bool,
+
+    // primary node name, the time and reason it was ejected
+    ejected: HashMap<String, (Instant, Eject)>,
+    healthy_since: Option<Instant>,
+    failback: usize,
+}
+
+impl Standby {
+    pub fn new(cc: &ClusterConfig) -> Result<Standby, AsError> {
+        let mut standby = Standby {
+            ring: HashRing::empty(),
+            addrs: Vec::new(),
+            active: false,
+            ejected: HashMap::new(),
+            healthy_since: None,
+            failback: failback_version(&cc.name),
+        };
+        standby.reset(&cc.standby)?;
+        Ok(standby)
+    }
+
+    /// rebuild standby ring, standby node is always named by it's address.
+    pub fn reset(&mut self, servers: &[String]) -> Result<(), AsError> {
+        let sls = ServerLine::parse_servers(servers)?;
+        let (addrs, _, weights) = ServerLine::unwrap_spot(&sls);
+        self.ring = HashRing::new(addrs.clone(), weights)?;
+        self.addrs = addrs;
+        Ok(())
+    }
+
+    pub fn addrs(&self) -> &[String] {
+        &self.addrs
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn get_node(&self, hash: u64) -> Option<&str> {
+        self.ring.get_node(hash)
+    }
+
+    pub fn get_node_by_round(&self, round: usize) -> Option<&str> {
+        self.ring.get_node_by_round(round)
+    }
+
+    pub fn eject(&mut self, name: &str, reason: Eject) {
+        self.ejected
+            .entry(name.to_string())
+            .or_insert_with(|| (Instant::now(), reason));
+    }
+
+    pub fn recover(&mut self, name: &str) {
+        self.ejected.remove(name);
+    }
+
+    pub fn is_ejected(&self, name: &str) -> bool {
+        self.ejected.contains_key(name)
+    }
+
+    pub fn ejected_for(&self, name: &str) -> Option<Eject> {
+        self.ejected.get(name).map(|x| x.1)
+    }
+
+    pub fn ejected(&self) -> impl Iterator<Item = &str> {
+        self.ejected.keys().map(|x| x.as_str())
+    }
+
+    /// check the policy and return the new state if routing should be flipped.
+    pub fn check(&mut self, cc: &ClusterConfig, total: usize, now: Instant) -> Option<bool> {
+        if self.addrs.is_empty() {
+            return None;
+        }
+
+        if !self.active {
+            if total == 0 {
+                return None;
+            }
+            let grace = Duration::from_millis(cc.standby_fail_grace.unwrap_or(DEFAULT_FAIL_GRACE));
+            let ratio = cc.standby_fail_ratio.unwrap_or(DEFAULT_FAIL_RATIO);
+            let count = self
+                .ejected
+                .values()
+                .filter(|(since, _)| now.duration_since(*since) >= grace)
+                .count();
+            if count as f64 / total as f64 > ratio {
+                self.active = true;
+                self.healthy_since = None;
+                self.failback = failback_version(&cc.name);
+                return Some(true);
+            }
+            return None;
+        }
+
+        let version = failback_version(&cc.name);
+        if version != self.failback {
+            self.failback = version;
+            self.active = false;
+            return Some(false);
+        }
+
+        if !self.ejected.is_empty() {
+            self.healthy_since = None;
+            return None;
+        }
+        let since = *self.healthy_since.get_or_insert(now);
+        match cc.standby_recover_after {
+            Some(after)
+                if after > 0 && now.duration_since(since) >= Duration::from_millis(after) =>
+            {
+                self.active = false;
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct Failover<T> {
+    cluster: Weak<Cluster<T>>,
+    interval: Interval,
+}
+
+impl<T: Request + 'static> Failover<T> {
+    pub fn new(cluster: Weak<Cluster<T>>) -> Self {
+        Failover {
+            cluster,
+            interval: Interval::new(
+                Instant::now() + Duration::from_millis(CHECK_INTERVAL),
+                Duration::from_millis(CHECK_INTERVAL),
+            ),
+        }
+    }
+}
+
+impl<T: Request + 'static> Future for Failover<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    error!("fail to poll failover interval due {:?}", err);
+                    return Err(());
+                }
+            }
+
+            let cluster = match self.cluster.upgrade() {
+                Some(cluster) => cluster,
+                None => return Ok(Async::Ready(())),
+            };
+            let name = cluster.cc.borrow().name.clone();
+            let total = cluster.spots.borrow().len();
+            let flipped =
+                cluster
+                    .standby
+                    .borrow_mut()
+                    .check(&cluster.cc.borrow(), total, Instant::now());
+            match flipped {
+                Some(true) => {
+                    error!(
+                        "cluster {} primary backends are majority ejected, switch to standby {:?}",
+                        name,
+                        cluster.standby.borrow().addrs()
+                    );
+                    let addrs = cluster.standby.borrow().addrs().to_vec();
+                    for addr in addrs {
+                        cluster.reconnect(&addr);
+                    }
+                    standby_active_set(&name, true);
+                }
+                Some(false) => {
+                    warn!("cluster {} failback from standby to primary backends", name);
+                    standby_active_set(&name, false);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(name: &str, recover_after: Option<u64>) -> ClusterConfig {
+        ClusterConfig {
+            name: name.to_string(),
+            standby: vec!["127.0.0.1:7001:10".to_string()],
+            standby_fail_ratio: Some(0.5),
+            standby_fail_grace: Some(1_000),
+            standby_recover_after: recover_after,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_failover_after_grace() {
+        let cc = config("test-failover-grace", Some(1_000));
+        let mut standby = Standby::new(&cc).unwrap();
+        standby.eject("redis-1", Eject::PingFailure);
+        standby.eject("redis-2", Eject::ProtocolViolation);
+        assert_eq!(
+            standby.ejected_for("redis-2"),
+            Some(Eject::ProtocolViolation)
+        );
+        let now = Instant::now();
+        assert_eq!(standby.check(&cc, 3, now), None);
+        assert_eq!(
+            standby.check(&cc, 3, now + Duration::from_secs(2)),
+            Some(true)
+        );
+        assert!(standby.is_active());
+
+        standby.recover("redis-1");
+        standby.recover("redis-2");
+        let later = now + Duration::from_secs(3);
+        assert_eq!(standby.check(&cc, 3, later), None);
+        assert_eq!(
+            standby.check(&cc, 3, later + Duration::from_secs(2)),
+            Some(false)
+        );
+        assert!(!standby.is_active());
+    }
+
+    #[test]
+    fn test_failback_by_admin_only() {
+        let cc = config("test-failover-admin", None);
+        let mut standby = Standby::new(&cc).unwrap();
+        let now = Instant::now() + Duration::from_secs(2);
+        standby.eject("redis-1", Eject::PingFailure);
+        assert_eq!(standby.check(&cc, 1, now), Some(true));
+
+        standby.recover("redis-1");
+        assert_eq!(standby.check(&cc, 1, now + Duration::from_secs(3600)), None);
+        request_failback(&cc.name);
+        assert_eq!(
+            standby.check(&cc, 1, now + Duration::from_secs(3601)),
+            Some(false)
+        );
+    }
+}
@@ -1,19 +1,40 @@
-use crate::com::AsError;
+use crate::com::{AsError, ListenerConfig, DEFAULT_LISTENER};
+use bytes::{Bytes, BytesMut};
+use futures::future::poll_fn;
 use futures::task;
 use futures::{Async, AsyncSink, Future, Sink, Stream};
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tokio::runtime::current_thread;
+use tokio::timer::Delay;
 
+use crate::proxy::accesslog::Entry;
+use crate::proxy::capture;
+use crate::proxy::clients::{ClientFlags, Clients};
+use crate::proxy::fault::Fault;
+use crate::proxy::memory::{Meter, Part};
+use crate::proxy::monitor;
+use crate::proxy::outbuf::{OutputLimit, ReplyPacing};
+use crate::proxy::probe;
+use crate::proxy::standalone::barrier::Barrier;
+use crate::proxy::standalone::dedup::Join;
+use crate::proxy::standalone::hint::RouteHint;
+use crate::proxy::standalone::respcache::{Lookup, Ticket};
+use crate::proxy::standalone::tick::Event;
 use crate::proxy::standalone::Cluster;
 use crate::proxy::standalone::Request;
 
-use crate::metrics::front_conn_decr;
+use crate::metrics::{dedup_writes_incr, front_conn_decr, response_cache_incr};
+use crate::metrics::{listener_conn_decr, listener_requests, IntCounter};
 
 const MAX_BATCH_SIZE: usize = 2048;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum State {
     Running,
+    Closing,
     Closed,
 }
 
@@ -26,12 +47,57 @@ where
     cluster: Rc<Cluster<T>>,
 
     client: String,
+    // the listener accepted the connection, whose clients may be read-only
+    listener: String,
+    read_only: bool,
+    requests: IntCounter,
+    // id and sequence of requests and replies in traffic capture
+    client_id: u64,
+    recv_seq: u64,
+    reply_seq: u64,
+    // replies before the sequence are passed to hooks
+    hooked_seq: u64,
 
     input: I,
     output: O,
 
     sendq: VecDeque<T>,
+    // fired at the earliest queue_timeout of the commands in sendq
+    queue_timer: Option<Delay>,
     waitq: VecDeque<T>,
+    // some commands in waitq have subs waiting for the next wave
+    waving: bool,
+    // recv sequence and command held until the waves ahead are released, or it may overtake
+    // the subs of the same key
+    held: VecDeque<(u64, T)>,
+    // task is registered to be woken up when worker is closing
+    registered: bool,
+    // set by CLIENT KILL or the draining of clients from any worker thread
+    flags: Arc<ClientFlags>,
+    output_limit: OutputLimit,
+    pacing: ReplyPacing,
+    // recv sequence and request of the dedup leaders in waitq
+    dedups: VecDeque<(u64, Bytes)>,
+    // recv sequence and ticket of the reads in waitq missed in response cache
+    caches: VecDeque<(u64, Ticket)>,
+    // backends written since the last PROXY BARRIER, and recv sequence of the barriers in waitq
+    written: HashSet<String>,
+    barriers: VecDeque<(u64, Barrier<T>)>,
+    // node named by PROXY ROUTE, and whether it pins the connection or the next command only
+    route: Option<(String, bool)>,
+    // deadline given by ASTER DEADLINE, taken by the next command only
+    deadline: Option<Instant>,
+    // authenticated by AUTH if the cluster has users
+    user: Option<String>,
+    // recv time of each command in waitq, only if access log is enabled
+    recv_times: VecDeque<(SystemTime, Instant)>,
+    // approximate memory of buffers and requests in flight
+    meter: Meter,
+    // attached by PROXY MONITOR, the events are sent once all the replies are sent
+    monitoring: bool,
+    events: VecDeque<String>,
+    // connection of self probe, which is excluded from the counters of clients
+    probe: bool,
     state: State,
 }
 
@@ -42,33 +108,141 @@ where
     O: Sink<SinkItem = T, SinkError = AsError>,
 {
     pub fn new(client: String, cluster: Rc<Cluster<T>>, input: I, output: O) -> Front<T, I, O> {
+        let output_limit = OutputLimit::new(&cluster.cc.borrow());
+        let pacing = ReplyPacing::new(&cluster.cc.borrow());
+        let meter = cluster.memory.front_meter();
+        let requests = listener_requests(&cluster.cc.borrow().name, DEFAULT_LISTENER);
         Front {
             cluster,
             client,
+            listener: DEFAULT_LISTENER.to_string(),
+            read_only: false,
+            requests,
+            client_id: capture::next_client_id(),
+            recv_seq: 0,
+            reply_seq: 0,
+            hooked_seq: 0,
             input,
             output,
             sendq: VecDeque::with_capacity(MAX_BATCH_SIZE),
+            queue_timer: None,
             waitq: VecDeque::with_capacity(MAX_BATCH_SIZE),
+            waving: false,
+            held: VecDeque::new(),
+            registered: false,
+            flags: Arc::default(),
+            output_limit,
+            pacing,
+            dedups: VecDeque::new(),
+            caches: VecDeque::new(),
+            written: HashSet::new(),
+            barriers: VecDeque::new(),
+            route: None,
+            deadline: None,
+            user: None,
+            recv_times: VecDeque::new(),
+            meter,
+            monitoring: false,
+            events: VecDeque::new(),
+            probe: false,
             state: State::Running,
         }
     }
+
+    /// the meter shared with the codec of the connection.
+    pub fn meter(mut self, meter: Meter) -> Self {
+        self.meter = meter;
+        self
+    }
+
+    /// the listener accepted the connection, which is the default one of listen_addr if not set.
+    pub fn listener(mut self, listener: &ListenerConfig) -> Self {
+        self.listener = listener.name.clone();
+        self.read_only = listener.read_only.unwrap_or(false);
+        self.requests = listener_requests(&self.cluster.cc.borrow().name, &listener.name);
+        self
+    }
+
+    /// the connection is the one of self probe, whose client is labelled in access log.
+    pub fn probe(mut self, probe: bool) -> Self {
+        self.probe = probe;
+        self
+    }
+
     fn try_reply(&mut self) -> Result<Async<usize>, AsError> {
         let mut count = 0usize;
+        // the client is not writable
+        let mut blocked = false;
         loop {
             if self.waitq.is_empty() {
                 break;
             }
+            if self.barriers.front().map(|x| x.0) == Some(self.reply_seq) {
+                self.try_barrier();
+            }
             let cmd = self.waitq.pop_front().expect("command never be error");
             if !cmd.is_done() {
                 self.waitq.push_front(cmd);
                 break;
             }
+            let retried = cmd.retry_failed_subs(&self.cluster.budget);
+            if !retried.is_empty() {
+                // dispatched again by the retry of sub_failures, counted to wake up the sending
+                count += retried.len();
+                self.sendq.extend(retried);
+                self.waitq.push_front(cmd);
+                break;
+            }
+            if self.dedups.front().map(|x| x.0) == Some(self.reply_seq) {
+                let (_, req) = self.dedups.pop_front().expect("dedups never be empty");
+                self.cluster.dedup.borrow_mut().complete(&req, cmd.reply());
+            }
+            if self.caches.front().map(|x| x.0) == Some(self.reply_seq) {
+                let (_, ticket) = self.caches.pop_front().expect("caches never be empty");
+                self.cluster.cache_store(ticket, &cmd);
+            }
+            // dropped again once replied, the reads may be cached before the write reached
+            // backend if it was held (e.g.: behind the waves or a saturated backend)
+            self.cluster.cache_invalidate(&cmd);
+            if cmd.is_mutation() {
+                // the node of subs are joined, e.g.: MSET
+                let node = cmd.node().unwrap_or_default();
+                self.written
+                    .extend(node.split(',').filter(|x| !x.is_empty()).map(String::from));
+            }
+            if self.hooked_seq == self.reply_seq {
+                // sampled once before the hooks, even if the reply is pushed back
+                self.cluster.clock.stamp(Event::Merged, cmd.trace_id(), "");
+                self.cluster.check_integrity(&cmd);
+                self.cluster.slo.record(&cmd);
+                self.cluster.budget.record(&cmd);
+                self.cluster.hooks.on_response(&cmd);
+                self.hooked_seq += 1;
+            }
+            let reply = self.capture_reply(&cmd);
+            let access = self.access_entry(&cmd);
+            let trace = cmd.trace_id();
+            let size = cmd.req_data().len();
             match self.output.start_send(cmd) {
                 Ok(AsyncSink::Ready) => {
+                    self.meter.sub(Part::Inflight, size);
+                    let tick = self.cluster.clock.stamp(Event::Flushed, trace, "");
+                    if let Some(reply) = reply {
+                        self.cluster
+                            .capture
+                            .reply(self.client_id, self.reply_seq, tick, reply);
+                    }
+                    if let Some(entry) = access {
+                        self.recv_times.pop_front();
+                        self.cluster.access_log.log(entry);
+                    }
+                    self.reply_seq += 1;
                     count += 1;
                 }
                 Ok(AsyncSink::NotReady(cmd)) => {
                     self.waitq.push_front(cmd);
+                    blocked = true;
+                    self.check_output_limit()?;
                     break;
                 }
                 Err(err) => {
@@ -79,20 +253,345 @@ where
             }
         }
 
+        if self.monitoring && self.waitq.is_empty() {
+            count += self.try_send_events()?;
+        }
+
         if count > 0 {
             self.output.poll_complete()?;
         }
+        if self.waitq.is_empty() {
+            self.output_limit.reset();
+        }
+        if !blocked {
+            self.pacing.reset();
+        }
         Ok(Async::Ready(count))
     }
 
+    // the barrier is at the front of waitq, so all the commands before it are replied and the
+    // markers are sent behind them.
+    fn try_barrier(&mut self) {
+        let (_, barrier) = self.barriers.front_mut().expect("barriers never be empty");
+        if !barrier.is_started() {
+            let mut markers = Vec::with_capacity(self.written.len());
+            for addr in self.written.drain() {
+                let mut marker = T::ping_request();
+                marker.reregister(task::current());
+                self.cluster.dispatch_marker(&addr, marker.clone());
+                markers.push(marker);
+            }
+            barrier.start(markers);
+        }
+        if let Async::Ready(synced) = barrier.poll_synced() {
+            let cmd = self.waitq.front().expect("waitq never be empty");
+            cmd.set_synced(synced);
+            self.barriers.pop_front();
+        }
+    }
+
+    fn try_send_events(&mut self) -> Result<usize, AsError> {
+        if self.events.is_empty() {
+            self.events = self.cluster.monitor.take(self.client_id).into();
+        }
+        let mut count = 0usize;
+        while let Some(event) = self.events.pop_front() {
+            match self.output.start_send(T::status_reply(&event)) {
+                Ok(AsyncSink::Ready) => count += 1,
+                Ok(AsyncSink::NotReady(_)) => {
+                    self.events.push_front(event);
+                    break;
+                }
+                Err(err) => {
+                    error!(
+                        "fail to send monitor events to client {} {}",
+                        self.client, err
+                    );
+                    self.output.close()?;
+                    return Err(err);
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn check_output_limit(&mut self) -> Result<(), AsError> {
+        if !self.output_limit.is_enabled() && !self.pacing.is_enabled() {
+            return Ok(());
+        }
+        let pending = self
+            .waitq
+            .iter()
+            .take_while(|x| x.is_done())
+            .map(|x| x.reply_size())
+            .sum();
+        self.pacing.check(pending);
+        self.output_limit.check(pending)
+    }
+
+    fn capture_reply(&self, cmd: &T) -> Option<Bytes> {
+        if !self.cluster.capture.is_replying() {
+            return None;
+        }
+        let mut buf = BytesMut::new();
+        cmd.reply_data(&mut buf);
+        Some(buf.freeze())
+    }
+
+    fn access_entry(&self, cmd: &T) -> Option<Entry> {
+        let (time, since) = self.recv_times.front()?;
+        Some(Entry {
+            time: *time,
+            client: if self.probe {
+                probe::CLIENT_LABEL.to_string()
+            } else {
+                self.client.clone()
+            },
+            cmd: cmd.cmd_name(),
+            keys: cmd.keys(),
+            node: cmd.node(),
+            latency: since.elapsed(),
+            error: cmd.is_error(),
+            timeout: cmd.timeout(),
+            tick: cmd.trace_id(),
+        })
+    }
+
     fn try_send(&mut self) -> Result<usize, AsError> {
-        self.cluster.dispatch_all(&mut self.sendq)
+        if self.waving {
+            self.release_waves();
+        }
+        let count = self.cluster.dispatch_all(&mut self.sendq)?;
+        Ok(count + self.expire_queued())
+    }
+
+    // the commands left in sendq (e.g.: behind a saturated backend) are failed once they wait
+    // longer than queue_timeout, and the timer wakes up the front at the earliest of the rest.
+    fn expire_queued(&mut self) -> usize {
+        let limit = match self.cluster.queue_timeout() {
+            Some(limit) if !self.sendq.is_empty() => limit,
+            _ => {
+                self.queue_timer = None;
+                return 0;
+            }
+        };
+        let len = self.sendq.len();
+        let cluster = &self.cluster;
+        self.sendq.retain(|cmd| !cluster.drop_queued(cmd));
+        let expired = len - self.sendq.len();
+        self.queue_timer = self
+            .sendq
+            .iter()
+            .filter_map(|cmd| cmd.queued())
+            .min()
+            .map(|since| Delay::new(since + limit));
+        if let Some(timer) = self.queue_timer.as_mut() {
+            // polled to be woken up, the one due already is fired at the next poll
+            if let Err(err) = timer.poll() {
+                error!("fail to wait for queue_timeout due to {:?}", err);
+            }
+        }
+        expired
+    }
+
+    fn release_waves(&mut self) {
+        let batch = self.cluster.cc.borrow().multi_key_batch();
+        let mut waving = false;
+        // the held commands are never released here
+        let end = match self.held.front() {
+            Some((seq, _)) => (seq - self.reply_seq) as usize,
+            None => self.waitq.len(),
+        };
+        let queued = self.cluster.queue_timeout().map(|_| Instant::now());
+        for cmd in self.waitq.iter().take(end) {
+            if let Some(wave) = cmd.next_wave(batch) {
+                for sub in wave {
+                    sub.set_queued(queued);
+                    self.sendq.push_back(sub);
+                }
+            }
+            waving = waving || cmd.has_wave();
+        }
+        self.waving = waving;
+        while !self.waving {
+            match self.held.pop_front() {
+                Some((_, cmd)) => self.dispatch(&cmd, batch),
+                None => break,
+            }
+        }
+    }
+
+    fn dispatch(&mut self, cmd: &T, batch: usize) {
+        let queued = self.cluster.queue_timeout().map(|_| Instant::now());
+        if let Some(wave) = cmd.next_wave(batch) {
+            for sub in wave {
+                sub.set_queued(queued);
+                self.sendq.push_back(sub);
+            }
+            self.waving = self.waving || cmd.has_wave();
+        } else {
+            cmd.set_queued(queued);
+            self.sendq.push_back(cmd.clone());
+        }
+    }
+
+    fn inject(&mut self, cmd: &T, fault: Fault, batch: usize) {
+        let delay = match fault {
+            Fault::Error => {
+                for sub in cmd.subs().unwrap_or_default() {
+                    sub.set_error(&AsError::Injected);
+                }
+                cmd.set_error(&AsError::Injected);
+                return;
+            }
+            Fault::Latency(delay) => delay,
+        };
+        let mut cmds: VecDeque<T> = match cmd.next_wave(batch) {
+            Some(wave) => wave.into_iter().collect(),
+            None => vec![cmd.clone()].into_iter().collect(),
+        };
+        self.waving = self.waving || cmd.has_wave();
+
+        let cluster = self.cluster.clone();
+        let dispatch = Delay::new(Instant::now() + delay)
+            .map_err(|err| error!("fail to inject latency due to {:?}", err))
+            .and_then(move |_| {
+                poll_fn(move || match cluster.dispatch_all(&mut cmds) {
+                    Ok(_) if !cmds.is_empty() => Ok(Async::NotReady),
+                    Ok(_) => Ok(Async::Ready(())),
+                    Err(err) => {
+                        error!("fail to dispatch delayed commands due to {:?}", err);
+                        for cmd in cmds.drain(..) {
+                            cmd.set_error(&err);
+                        }
+                        Ok(Async::Ready(()))
+                    }
+                })
+            });
+        current_thread::spawn(dispatch);
+    }
+
+    // AUTH and ACL are answered by the users of cluster, and the others are checked against the
+    // user authenticated. The self probe is never checked.
+    fn check_acl(&mut self, cmd: &T) -> Result<(), AsError> {
+        let acl = self.cluster.acl.borrow();
+        let user = &mut self.user;
+        if cmd.handle_acl(|args| acl.handle(user, args)) || self.probe || !acl.is_enabled() {
+            return Ok(());
+        }
+        acl.check(
+            self.user.as_deref(),
+            cmd.acl_category(),
+            &cmd.cmd_name(),
+            &cmd.acl_keys(),
+        )
+    }
+
+    // PROXY ROUTE, the node is checked here and again by each command pinned to it.
+    fn route_to(&mut self, hint: RouteHint) -> Result<(), AsError> {
+        match hint {
+            RouteHint::Pin { node, sticky } => {
+                self.cluster.check_route(&node)?;
+                self.route = Some((node, sticky));
+            }
+            RouteHint::Reset => self.route = None,
+        }
+        Ok(())
+    }
+
+    // pin cmd to the node of PROXY ROUTE, which may be ejected or removed since the hint.
+    fn pin_route(&mut self, cmd: &T) -> Result<(), AsError> {
+        let (node, sticky) = match self.route.clone() {
+            Some(route) => route,
+            None => return Ok(()),
+        };
+        if !sticky {
+            self.route = None;
+        }
+        let addr = self.cluster.check_route(&node)?;
+        cmd.set_pinned(&addr);
+        Ok(())
+    }
+
+    // join the identical write in flight, return true if cmd waits for its reply.
+    fn try_dedup(&mut self, cmd: &T) -> bool {
+        if cmd.pinned().is_some() {
+            return false;
+        }
+        let window = match self.cluster.dedup_window(cmd) {
+            Some(window) => window,
+            None => return false,
+        };
+        let join = self
+            .cluster
+            .dedup
+            .borrow_mut()
+            .join(cmd, Instant::now(), window);
+        match join {
+            Join::Leader(req) => {
+                self.dedups.push_back((self.recv_seq - 1, req));
+                false
+            }
+            Join::Follower => {
+                dedup_writes_incr(&self.cluster.cc.borrow().name);
+                true
+            }
+            Join::Pass => false,
+        }
+    }
+
+    // reply the read by response cache, return true if it's hit.
+    fn try_cache(&mut self, cmd: &T) -> bool {
+        // the pinned read is always answered by its backend
+        if cmd.pinned().is_some() {
+            return false;
+        }
+        let result = match self.cluster.cache_lookup(cmd) {
+            Lookup::Hit(reply) => {
+                cmd.set_reply(reply);
+                "hit"
+            }
+            Lookup::Miss(ticket) => {
+                self.caches.push_back((self.recv_seq - 1, ticket));
+                "miss"
+            }
+            Lookup::Pass => return false,
+        };
+        response_cache_incr(&self.cluster.cc.borrow().name, result);
+        result == "hit"
+    }
+
+    // the leaders in flight are watched until done after front closed, or their
+    // followers would never be replied.
+    fn release_dedups(&mut self) {
+        for (seq, req) in self.dedups.drain(..) {
+            let mut leader = match self.waitq.get((seq - self.reply_seq) as usize) {
+                Some(leader) => leader.clone(),
+                None => continue,
+            };
+            let cluster = self.cluster.clone();
+            current_thread::spawn(poll_fn(move || {
+                leader.reregister(task::current());
+                if !leader.is_done() {
+                    return Ok(Async::NotReady);
+                }
+                cluster.dedup.borrow_mut().complete(&req, leader.reply());
+                Ok(Async::Ready(()))
+            }));
+        }
     }
 
     fn try_recv(&mut self) -> Result<usize, AsError> {
         let mut count = 0usize;
+        // mode is loaded once to keep the whole pipelined batch consistent
+        let read_only = self.read_only || self.cluster.is_read_only();
+        let maintenance = self.cluster.is_maintenance();
+        let batch = self.cluster.cc.borrow().multi_key_batch();
         loop {
-            if self.waitq.len() == MAX_BATCH_SIZE {
+            if self.waitq.len() == MAX_BATCH_SIZE
+                || self.meter.is_paused()
+                || self.pacing.is_paced()
+            {
                 return Ok(count);
             }
 
@@ -104,18 +603,151 @@ where
 
             if let Some(mut cmd) = cmd {
                 count += 1;
+                if !self.probe {
+                    self.requests.inc();
+                }
                 cmd.reregister(task::current());
+                let trace = self.cluster.clock.decoded(self.client_id);
+                if trace > 0 {
+                    cmd.set_trace_id(trace);
+                }
+                if self.cluster.capture.is_active() {
+                    self.cluster.capture.request(
+                        self.client_id,
+                        self.recv_seq,
+                        trace,
+                        cmd.req_data(),
+                    );
+                }
+                self.recv_seq += 1;
+                if self.cluster.access_log.is_enabled() {
+                    self.recv_times
+                        .push_back((SystemTime::now(), Instant::now()));
+                }
 
-                cmd.mark_total(&self.cluster.cc.borrow().name);
-                if cmd.valid() && !cmd.is_done() {
+                if !self.probe {
+                    cmd.mark_total(&self.cluster.cc.borrow().name);
+                }
+                if self.cluster.monitor.is_active() && !self.monitoring && !self.probe {
+                    self.cluster
+                        .monitor
+                        .publish(&self.client, &cmd.cmd_name(), &cmd.keys());
+                }
+                // taken by whatever comes next, so the one not followed by a command is dropped
+                let deadline = self.deadline.take();
+                if cmd.reply_quit() {
+                    // replied after the ones before it, and closed once all are flushed
+                    self.state = State::Closing;
+                } else if self.flags.is_drained() {
+                    // the next request after drained is rejected, and closed once all are flushed
+                    cmd.set_error(&AsError::ShuttingDown);
+                    self.state = State::Closing;
+                } else if let Err(err) = self.check_acl(&cmd) {
+                    cmd.set_error(&err);
+                } else if let Err(err) = self.cluster.check_dangerous(&cmd) {
+                    // denied before anything else, even if it's not supported by proxy
+                    cmd.set_error(&err);
+                } else if cmd.valid() && !cmd.is_done() {
                     // for done command, never send to backend
-                    if let Some(subs) = cmd.subs() {
-                        self.sendq.extend(subs.into_iter());
+                    let given = cmd.handle_deadline(|budget| {
+                        self.deadline = Some(Instant::now() + budget);
+                    });
+                    if let (false, Some(deadline)) = (given, deadline) {
+                        cmd.set_deadline(deadline);
+                    }
+                    let route = match cmd.route_hint() {
+                        Some(hint) => {
+                            let rslt = hint.and_then(|hint| self.route_to(hint));
+                            cmd.handle_proxy(|_| rslt.map(|_| None));
+                            true
+                        }
+                        None => false,
+                    };
+                    let client_id = self.client_id;
+                    let cluster = &self.cluster;
+                    let clients: &Clients = &self.cluster.clients;
+                    let mut monitor = false;
+                    let barrier = match cmd.barrier_timeout() {
+                        Some(Ok(timeout)) => {
+                            self.barriers
+                                .push_back((self.recv_seq - 1, Barrier::new(timeout)));
+                            true
+                        }
+                        Some(Err(err)) => {
+                            cmd.set_error(&err);
+                            true
+                        }
+                        None => false,
+                    };
+                    if !barrier
+                        && !route
+                        && !given
+                        && !cmd.handle_proxy(|args| {
+                            let rslt = cluster.proxy_command(args);
+                            monitor = rslt.is_ok() && monitor::is_monitor(args);
+                            rslt
+                        })
+                        && !cmd.handle_client_kill(|args| clients.kill(client_id, args))
+                        && !cmd.handle_info(|args| cluster.info(args))
+                    {
+                        self.cluster.hooks.on_request(&mut cmd);
+                    }
+                    self.cluster.set_sub_failure(&cmd);
+                    if monitor && !self.monitoring {
+                        self.monitoring = true;
+                        self.cluster.monitor.watch(client_id, task::current());
+                    }
+                    if cmd.is_done() {
+                        // replied by PROXY commands or hooks
+                    } else if barrier {
+                        // replied once the backends written are synced
+                    } else if self.cluster.reply_keyless(&cmd) {
+                        // replied by proxy under the local keyless_policy
+                    } else if maintenance {
+                        for sub in cmd.subs().unwrap_or_default() {
+                            sub.set_error(&AsError::Maintenance);
+                        }
+                        cmd.set_error(&AsError::Maintenance);
+                    } else if read_only && cmd.is_mutation() {
+                        for sub in cmd.subs().unwrap_or_default() {
+                            sub.set_error(&AsError::ReadOnly);
+                        }
+                        cmd.set_error(&AsError::ReadOnly);
+                    } else if cmd.is_admin()
+                        && !self.cluster.broadcast(&cmd)
+                        && !self.cluster.allow_admin()
+                    {
+                        cmd.set_error(&AsError::RequestNotSupport);
+                    } else if let Err(err) = self.cluster.check_blocking(&cmd) {
+                        cmd.set_error(&err);
+                    } else if let Err(err) = self.cluster.check_two_keys(&cmd) {
+                        cmd.set_error(&err);
+                    } else if let Err(err) = self.cluster.check_sort(&cmd) {
+                        cmd.set_error(&err);
+                    } else if let Err(err) = self.pin_route(&cmd) {
+                        cmd.set_error(&err);
+                    } else if let Some(fault) = self
+                        .cluster
+                        .fault
+                        .inject(!cmd.is_mutation(), |x| cmd.has_key_prefix(x))
+                    {
+                        self.inject(&cmd, fault, batch);
+                    } else if self.try_cache(&cmd) {
+                        // replied by the response cache
+                    } else if self.try_dedup(&cmd) {
+                        // replied by the identical write in flight
+                    } else if self.waving {
+                        self.held.push_back((self.recv_seq - 1, cmd.clone()));
                     } else {
-                        self.sendq.push_back(cmd.clone());
+                        self.dispatch(&cmd, batch);
                     }
                 }
+                self.meter.add(Part::Inflight, cmd.req_data().len());
                 self.waitq.push_back(cmd);
+                if self.state == State::Closing {
+                    // nothing is received after QUIT
+                    return Ok(count);
+                }
             } else {
                 self.state = State::Closed;
                 return Ok(0);
@@ -137,9 +769,57 @@ where
         let mut can_reply = true;
         let mut can_send = true;
         let mut can_recv = self.state == State::Running;
+        if !self.registered {
+            self.cluster
+                .worker
+                .register(self.client_id, task::current());
+            self.flags =
+                self.cluster
+                    .clients
+                    .register(self.client_id, &self.client, task::current());
+            if self.cluster.memory.is_enabled() {
+                self.cluster.memory.register(
+                    self.client_id,
+                    &self.client,
+                    &self.meter,
+                    task::current(),
+                );
+            }
+            self.registered = true;
+        }
         loop {
+            if self.state != State::Closed && (self.meter.is_evicted() || self.flags.is_killed()) {
+                // closed by the memory beyond the hard ceiling or CLIENT KILL
+                self.state = State::Closed;
+            }
+            if self.state == State::Running
+                && self.flags.is_drained()
+                && self.waitq.is_empty()
+                && self.sendq.is_empty()
+            {
+                // drained for the rollouts once all the requests in flight are replied
+                self.state = State::Closing;
+                can_recv = false;
+            }
+            if self.state != State::Closed && self.cluster.worker.is_closing() {
+                // no more requests are received, and closed after all are replied
+                self.state = if self.waitq.is_empty() && self.sendq.is_empty() {
+                    State::Closed
+                } else {
+                    State::Closing
+                };
+                can_recv = false;
+            }
+            if self.state == State::Closing && self.waitq.is_empty() && self.sendq.is_empty() {
+                // the pending replies are flushed before closed
+                if let Ok(Async::NotReady) = self.output.close() {
+                    return Ok(Async::NotReady);
+                }
+                self.state = State::Closed;
+            }
             if self.state == State::Closed {
                 debug!("front drop of {}", self.client);
+                self.release_dedups();
                 return Ok(Async::Ready(()));
             }
 
@@ -168,6 +848,7 @@ where
                             self.client, err
                         );
                         self.state = State::Closed;
+                        self.release_dedups();
                         return Err(());
                     }
                 }
@@ -199,6 +880,14 @@ where
                         can_recv = 0 != size && self.state == State::Running;
                         // trace!("front recv is ready and recv {}", size);
                     }
+                    Err(AsError::ProtocolError(reason)) => {
+                        // replied by the protocol error, and closed after all are replied
+                        warn!("client {} meet protocol error: {}", self.client, reason);
+                        self.state = State::Closing;
+                        can_send = true;
+                        can_reply = true;
+                        can_recv = false;
+                    }
                     Err(err) => {
                         error!("fail to read from client {} due to {}", self.client, err);
                         self.state = State::Closed;
@@ -217,6 +906,544 @@ where
     O: Sink<SinkItem = T, SinkError = AsError>,
 {
     fn drop(&mut self) {
-        front_conn_decr(&self.cluster.cc.borrow().name);
+        self.cluster.worker.unregister(self.client_id);
+        self.cluster.memory.unregister(self.client_id);
+        self.cluster.clients.unregister(self.client_id);
+        self.cluster.monitor.unwatch(self.client_id);
+        if !self.probe {
+            front_conn_decr(&self.cluster.cc.borrow().name);
+            listener_conn_decr(&self.cluster.cc.borrow().name, &self.listener);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::lazy;
+    use futures::unsync::mpsc::channel;
+    use tokio::codec::{Encoder, FramedRead};
+
+    use crate::com::ClusterConfig;
+    use crate::protocol::redis::{Cmd, RedisHandleCodec};
+
+    #[test]
+    fn test_reply_before_protocol_error() {
+        let cc = ClusterConfig {
+            name: "test-protocol-error".to_string(),
+            ..Default::default()
+        };
+        let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+        // the pipeline of valid, invalid and valid requests
+        let data: &[u8] = b"*1\r\n$4\r\nPING\r\n*x\r\n$4\r\nPING\r\n*1\r\n$4\r\nPING\r\n";
+        let input = FramedRead::new(data, RedisHandleCodec::default());
+        let (tx, rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+        let front = Front::new("127.0.0.1:50001".to_string(), cluster, input, output);
+        // closed after the replies are flushed
+        front.wait().unwrap();
+
+        let mut codec = RedisHandleCodec::default();
+        let mut buf = BytesMut::new();
+        for cmd in rx.wait() {
+            codec.encode(cmd.unwrap(), &mut buf).unwrap();
+        }
+        assert_eq!(
+            &buf[..],
+            &b"+PONG\r\n-ERR Protocol error: invalid multibulk length\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_info_by_proxy() {
+        let cc = ClusterConfig {
+            name: "test-info-by-proxy".to_string(),
+            servers: vec!["127.0.0.1:7001:10 redis-1".to_string()],
+            info_sections: vec!["proxy".to_string(), "backends".to_string()],
+            ..Default::default()
+        };
+        let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+        let data: &[u8] = b"*1\r\n$4\r\nINFO\r\n*2\r\n$4\r\nINFO\r\n$7\r\nclients\r\n";
+        let input = FramedRead::new(data, RedisHandleCodec::default());
+        let (tx, rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+        let front = Front::new("127.0.0.1:50007".to_string(), cluster, input, output);
+        front.wait().unwrap();
+
+        let mut codec = RedisHandleCodec::default();
+        let replies: Vec<_> = rx
+            .wait()
+            .map(|cmd| {
+                let mut buf = BytesMut::new();
+                codec.encode(cmd.unwrap(), &mut buf).unwrap();
+                String::from_utf8_lossy(&buf).to_string()
+            })
+            .collect();
+        assert_eq!(replies.len(), 2);
+        // the bulk strings answered by proxy without backend
+        assert!(replies[0].starts_with("$"));
+        assert!(replies[0].contains("\r\n# Proxy\r\naster_version:"));
+        assert!(replies[0].contains("cluster:test-info-by-proxy\r\n"));
+        assert!(
+            replies[0].contains("\r\nbackend0:name=redis-1,addr=127.0.0.1:7001,state=active\r\n")
+        );
+        assert!(!replies[0].contains("# Clients"));
+        assert!(replies[1].contains("\r\n# Clients\r\nconnected_clients:"));
+        assert!(!replies[1].contains("# Proxy"));
+    }
+
+    #[test]
+    #[cfg(feature = "memcache")]
+    fn test_mc_close_after_bad_binary_header() {
+        use crate::com::FrontProtocol;
+        use crate::protocol::mc;
+
+        let cc = ClusterConfig {
+            name: "test-mc-bad-binary-header".to_string(),
+            ..Default::default()
+        };
+        let cluster = Rc::new(Cluster::<mc::Cmd>::new(&cc, Rc::default()));
+        let client = "127.0.0.1:50001";
+        let mut data = vec![0u8; 24];
+        data[0] = 0x80;
+        data[1] = 0xff;
+        data.extend_from_slice(b"get a\r\n");
+        let codec = mc::Cmd::front_codec(&cc, Some(FrontProtocol::Binary), client);
+        let input = FramedRead::new(&data[..], codec);
+        let (tx, rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+        let front = Front::new(client.to_string(), cluster, input, output);
+        // closed after the reply of binary error is flushed
+        front.wait().unwrap();
+
+        let mut codec = mc::Cmd::front_codec(&cc, Some(FrontProtocol::Binary), client);
+        let mut buf = BytesMut::new();
+        for cmd in rx.wait() {
+            codec.encode(cmd.unwrap(), &mut buf).unwrap();
+        }
+        let mut reply = vec![0u8; 24];
+        reply[0] = 0x81;
+        reply[1] = 0xff;
+        reply[7] = 0x04;
+        assert_eq!(&buf[..], &reply[..]);
+    }
+
+    #[test]
+    fn test_client_kill_close_front() {
+        let cc = ClusterConfig {
+            name: "test-client-kill".to_string(),
+            ..Default::default()
+        };
+        let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+        let (_input_tx, input_rx) = channel::<Cmd>(16);
+        let input = input_rx.map_err(|_| AsError::None);
+        let (tx, rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+        let client = "127.0.0.1:50002".to_string();
+        let mut front = Front::new(client, cluster.clone(), input, output);
+        let kill_args = vec!["ID".to_string(), front.client_id.to_string()];
+        let other = front.client_id + 1;
+
+        lazy(|| {
+            // idle and waiting for requests
+            assert_eq!(front.poll(), Ok(Async::NotReady));
+            assert!(cluster.clients.kill(other, &["ID".to_string()]).is_err());
+            assert_eq!(cluster.clients.kill(other, &kill_args), Ok(1));
+            assert_eq!(front.poll(), Ok(Async::Ready(())));
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+        drop(front);
+        assert_eq!(cluster.clients.kill(other, &kill_args), Ok(0));
+        // the connection is closed
+        assert!(rx.wait().next().is_none());
+    }
+    #[test]
+    fn test_hold_behind_waves() {
+        use crate::protocol::redis::Message;
+
+        let cc = ClusterConfig {
+            name: "test-hold-behind-waves".to_string(),
+            multi_key_batch: Some(1),
+            ..Default::default()
+        };
+        let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+        let mut data = BytesMut::new();
+        Message::from_args(vec!["MSET", "a", "1", "b", "2", "c", "3"]).save(&mut data);
+        Message::from_args(vec!["EXPIRE", "c", "10"]).save(&mut data);
+        Message::from_args(vec!["GET", "d"]).save(&mut data);
+        let input = FramedRead::new(&data[..], RedisHandleCodec::default());
+        let (tx, _rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+        let mut front = Front::new("127.0.0.1:50003".to_string(), cluster, input, output);
+
+        let sent = lazy(|| {
+            assert_eq!(front.try_recv(), Ok(3));
+            // EXPIRE and GET are held until the last sub of MSET is released
+            assert_eq!(front.sendq.len(), 1);
+            assert_eq!(front.held.len(), 2);
+            let mut sent = Vec::new();
+            while let Some(cmd) = front.sendq.pop_front() {
+                let args: Vec<_> = (0..2)
+                    .filter_map(|i| cmd.borrow().req.nth(i).map(|x| x.to_vec()))
+                    .collect();
+                sent.push(String::from_utf8_lossy(&args.join(&b' ')).to_string());
+                let reply = Message::parse(&mut BytesMut::from(&b"+OK\r\n"[..]));
+                cmd.set_reply(reply.unwrap().unwrap());
+                front.release_waves();
+            }
+            Ok::<_, ()>(sent)
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(
+            sent,
+            vec!["MSET a", "MSET b", "MSET c", "EXPIRE c", "GET d"]
+        );
+        assert!(front.held.is_empty());
+    }
+
+    #[test]
+    fn test_barrier_track_written() {
+        use crate::protocol::redis::Message;
+
+        let cc = ClusterConfig {
+            name: "test-barrier-track-written".to_string(),
+            ..Default::default()
+        };
+        let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+        let mut data = BytesMut::new();
+        Message::from_args(vec!["PROXY", "BARRIER"]).save(&mut data);
+        Message::from_args(vec!["MSET", "a", "1", "b", "2"]).save(&mut data);
+        Message::from_args(vec!["PROXY", "BARRIER", "x"]).save(&mut data);
+        let input = FramedRead::new(&data[..], RedisHandleCodec::default());
+        let (tx, rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+        let mut front = Front::new("127.0.0.1:50004".to_string(), cluster, input, output);
+
+        lazy(|| {
+            assert_eq!(front.try_recv(), Ok(3));
+            assert_eq!(front.barriers.len(), 1);
+            // the subs of MSET are sent to different backends
+            for (i, cmd) in front.sendq.drain(..).enumerate() {
+                cmd.set_node(&format!("127.0.0.1:700{}", i + 1));
+                let reply = Message::parse(&mut BytesMut::from(&b"+OK\r\n"[..]));
+                cmd.set_reply(reply.unwrap().unwrap());
+            }
+            // nothing is written before the first barrier
+            assert_eq!(front.try_reply(), Ok(Async::Ready(3)));
+            assert!(front.barriers.is_empty());
+            let mut written: Vec<_> = front.written.iter().cloned().collect();
+            written.sort();
+            assert_eq!(written, vec!["127.0.0.1:7001", "127.0.0.1:7002"]);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        drop(front);
+        let mut codec = RedisHandleCodec::default();
+        let mut buf = BytesMut::new();
+        for cmd in rx.wait() {
+            codec.encode(cmd.unwrap(), &mut buf).unwrap();
+        }
+        assert!(buf.starts_with(b":0\r\n+OK\r\n-ERR"), "{:?}", buf);
+    }
+
+    #[test]
+    fn test_route_pin_node() {
+        use crate::protocol::redis::Message;
+        use crate::proxy::standalone::failover::Eject;
+
+        let cc = ClusterConfig {
+            name: "test-route-pin-node".to_string(),
+            servers: vec![
+                "127.0.0.1:7001:10 redis-1".to_string(),
+                "127.0.0.1:7002:10 redis-2".to_string(),
+            ],
+            ..Default::default()
+        };
+        let mut data = BytesMut::new();
+        let get = vec!["GET", "a"];
+        Message::from_args(vec!["PROXY", "ROUTE", "redis-2"]).save(&mut data);
+        Message::from_args(get.clone()).save(&mut data);
+        Message::from_args(get.clone()).save(&mut data);
+        Message::from_args(vec!["PROXY", "ROUTE", "redis-9"]).save(&mut data);
+        Message::from_args(vec!["PROXY", "ROUTE", "127.0.0.1:7001", "CONNECTION"]).save(&mut data);
+        Message::from_args(get.clone()).save(&mut data);
+        Message::from_args(get.clone()).save(&mut data);
+        Message::from_args(vec!["PROXY", "ROUTE", "RESET"]).save(&mut data);
+        Message::from_args(get).save(&mut data);
+        let input = FramedRead::new(&data[..], RedisHandleCodec::default());
+        let (tx, _rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            let mut front = Front::new(
+                "127.0.0.1:50005".to_string(),
+                cluster.clone(),
+                input,
+                output,
+            );
+            assert_eq!(front.try_recv(), Ok(9));
+
+            // the pinned ones land on the node regardless of the key hash
+            let routed: Vec<_> = front
+                .sendq
+                .iter()
+                .map(|cmd| cluster.route(cmd).unwrap())
+                .collect();
+            let natural = routed[1].clone();
+            assert_eq!(
+                routed,
+                vec![
+                    "127.0.0.1:7002".to_string(),
+                    natural.clone(),
+                    "127.0.0.1:7001".to_string(),
+                    "127.0.0.1:7001".to_string(),
+                    natural,
+                ]
+            );
+
+            let mut codec = RedisHandleCodec::default();
+            let mut buf = BytesMut::new();
+            for i in &[0, 3, 4, 7] {
+                codec.encode(front.waitq[*i].clone(), &mut buf).unwrap();
+            }
+            assert_eq!(
+                &buf[..],
+                &b"+OK\r\n-ERR unknown node redis-9\r\n+OK\r\n+OK\r\n"[..]
+            );
+
+            // and the unhealthy node is refused
+            cluster
+                .standby
+                .borrow_mut()
+                .eject("redis-2", Eject::Unavailable);
+            assert_eq!(
+                cluster.check_route("redis-2"),
+                Err(AsError::BadProxyCommand(
+                    "node redis-2 is ejected unavailable".to_string()
+                ))
+            );
+            assert_eq!(
+                cluster.check_route("redis-1"),
+                Ok("127.0.0.1:7001".to_string())
+            );
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_client_deadline_next_command() {
+        use crate::metrics::client_deadline_expired_get;
+        use crate::protocol::redis::Message;
+        use std::time::Duration;
+
+        let cc = ClusterConfig {
+            name: "test-client-deadline".to_string(),
+            servers: vec!["127.0.0.1:7001:10 redis-1".to_string()],
+            ..Default::default()
+        };
+        let mut data = BytesMut::new();
+        Message::from_args(vec!["ASTER", "DEADLINE", "0"]).save(&mut data);
+        Message::from_args(vec!["GET", "a"]).save(&mut data);
+        Message::from_args(vec!["GET", "b"]).save(&mut data);
+        Message::from_args(vec!["aster", "deadline", "60000"]).save(&mut data);
+        Message::from_args(vec!["GET", "c"]).save(&mut data);
+        Message::from_args(vec!["ASTER", "DEADLINE", "soon"]).save(&mut data);
+        // never followed
+        Message::from_args(vec!["ASTER", "DEADLINE", "10"]).save(&mut data);
+        let input = FramedRead::new(&data[..], RedisHandleCodec::default());
+        let (tx, _rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            let mut front = Front::new(
+                "127.0.0.1:50006".to_string(),
+                cluster.clone(),
+                input,
+                output,
+            );
+            assert_eq!(front.try_recv(), Ok(7));
+            // the prefixes never leak to backends
+            assert_eq!(front.sendq.len(), 3);
+            assert!(front.sendq[1].deadline().is_none());
+            assert!(front.deadline.is_some());
+
+            let before = client_deadline_expired_get(&cc.name, "dispatch");
+            let mut sendq = front.sendq.clone();
+            cluster.dispatch_all(&mut sendq).unwrap();
+            assert_eq!(
+                client_deadline_expired_get(&cc.name, "dispatch"),
+                before + 1
+            );
+            // dropped without dispatched
+            assert!(front.waitq[1].is_done() && front.waitq[1].is_error());
+            assert!(!front.waitq[2].is_done());
+            // the rest of the budget instead of the default
+            assert_eq!(front.waitq[2].time_limit(), None);
+            let limit = front.waitq[4].time_limit().unwrap();
+            assert!(limit > Duration::from_secs(59) && limit <= Duration::from_secs(60));
+
+            let mut codec = RedisHandleCodec::default();
+            let mut buf = BytesMut::new();
+            for i in &[0, 1, 3, 5, 6] {
+                codec.encode(front.waitq[*i].clone(), &mut buf).unwrap();
+            }
+            assert_eq!(
+                &buf[..],
+                &b"+OK\r\n-ERR deadline given by ASTER DEADLINE expired\r\n+OK\r\n\
+                   -ERR deadline is not an integer or out of range\r\n+OK\r\n"[..]
+            );
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_acl_users() {
+        use crate::com::UserConfig;
+        use crate::protocol::redis::Message;
+        use crate::proxy::acl::hash_password;
+
+        let cc = ClusterConfig {
+            name: "test-acl-users".to_string(),
+            servers: vec!["127.0.0.1:7001:10 redis-1".to_string()],
+            users: vec![UserConfig {
+                name: "app".to_string(),
+                password: hash_password("secret"),
+                commands: vec!["read".to_string(), "write".to_string()],
+                keys: vec!["app:*".to_string()],
+            }],
+            ..Default::default()
+        };
+        let mut data = BytesMut::new();
+        for args in &[
+            vec!["GET", "app:1"],
+            vec!["AUTH", "app", "wrong"],
+            vec!["AUTH", "app", "secret"],
+            vec!["GET", "app:1"],
+            vec!["MGET", "app:1", "other"],
+            vec!["SMOVE", "app:1", "other", "x"],
+            vec!["SET", "app:2", "1"],
+            vec!["PROXY", "NODES"],
+            vec!["ACL", "WHOAMI"],
+            vec!["PING"],
+        ] {
+            Message::from_args(args).save(&mut data);
+        }
+        let input = FramedRead::new(&data[..], RedisHandleCodec::default());
+        let (tx, _rx) = channel(16);
+        let output = tx.sink_map_err(|_| AsError::None);
+
+        let mut rt = current_thread::Runtime::new().unwrap();
+        rt.block_on(lazy(|| {
+            let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+            cluster.reinit(cc.clone()).unwrap();
+            let mut front = Front::new(
+                "127.0.0.1:50006".to_string(),
+                cluster.clone(),
+                input,
+                output,
+            );
+            assert_eq!(front.try_recv(), Ok(10));
+            // only GET and SET allowed are sent
+            let sent: Vec<_> = front.sendq.iter().map(|x| x.cmd_name()).collect();
+            assert_eq!(sent, vec!["GET", "SET"]);
+            assert_eq!(front.user, Some("app".to_string()));
+
+            let mut codec = RedisHandleCodec::default();
+            let mut buf = BytesMut::new();
+            for i in &[0, 1, 2, 4, 5, 7, 8, 9] {
+                codec.encode(front.waitq[*i].clone(), &mut buf).unwrap();
+            }
+            let expect = [
+                "-NOAUTH Authentication required.\r\n",
+                "-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+                "+OK\r\n",
+                "-NOPERM No permissions to access a key\r\n",
+                "-NOPERM No permissions to access a key\r\n",
+                "-NOPERM User app has no permissions to run the 'proxy' command\r\n",
+                "$3\r\napp\r\n",
+                "+PONG\r\n",
+            ];
+            assert_eq!(String::from_utf8_lossy(&buf), expect.concat());
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reply_pacing_slow_client() {
+        use crate::metrics::reply_paced_get;
+        use crate::protocol::redis::{Command, Message};
+
+        let cc = ClusterConfig {
+            name: "test-reply-pacing".to_string(),
+            reply_pacing: Some(64),
+            ..Default::default()
+        };
+        let cluster = Rc::new(Cluster::<Cmd>::new(&cc, Rc::default()));
+        let (mut input_tx, input_rx) = channel::<Cmd>(64);
+        let input = input_rx.map_err(|_| AsError::None);
+        // the throttled client reads the replies one by one
+        let (tx, mut rx) = channel(1);
+        let output = tx.sink_map_err(|_| AsError::None);
+        let mut front = Front::new("127.0.0.1:50005".to_string(), cluster, input, output);
+        let get = || {
+            let mut src = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n"[..]);
+            Command::parse_cmd(&mut src).unwrap().unwrap()
+        };
+        let value = "v".repeat(100);
+        let reply = || {
+            let mut src = BytesMut::from(format!("${}\r\n{}\r\n", value.len(), value).as_bytes());
+            Message::parse(&mut src).unwrap().unwrap()
+        };
+
+        lazy(|| {
+            for _ in 0..4 {
+                input_tx.start_send(get()).unwrap();
+            }
+            assert_eq!(front.try_recv(), Ok(4));
+            // replied by backends faster than the client reads
+            while let Some(cmd) = front.sendq.pop_front() {
+                cmd.set_reply(reply());
+            }
+            front.try_reply().unwrap();
+            assert!(front.pacing.is_paced());
+            let pending = front.waitq.len();
+            assert!(pending > 0);
+            assert_eq!(reply_paced_get(&cc.name), 1);
+
+            // no more requests are read, so nothing more is pulled from backends
+            for _ in 0..4 {
+                input_tx.start_send(get()).unwrap();
+            }
+            assert_eq!(front.try_recv(), Ok(0));
+            assert!(front.sendq.is_empty());
+            assert_eq!(front.waitq.len(), pending);
+
+            // and read again once the client catches up
+            while !front.waitq.is_empty() {
+                let _ = rx.poll();
+                front.try_reply().unwrap();
+            }
+            assert!(!front.pacing.is_paced());
+            assert_eq!(front.try_recv(), Ok(4));
+            assert_eq!(front.sendq.len(), 4);
+            assert_eq!(reply_paced_get(&cc.name), 1);
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
     }
 }
@@ -0,0 +1,255 @@
+//! hash functions of keys in proxy mode. The hash is truncated to 32 bits as the points of
+//! ketama ring, so the placement is the same as twemproxy with the same hash.
+use std::convert::TryInto;
+
+use crate::proxy::standalone::fnv::fnv1a64;
+use crate::utils::crc::{crc16_twemproxy, crc32a};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum HashMethod {
+    #[serde(rename = "fnv1a_64")]
+    Fnv1a64,
+    #[serde(rename = "murmur3_32")]
+    Murmur3x32,
+    #[serde(rename = "murmur3_128")]
+    Murmur3x128,
+    #[serde(rename = "xxhash64")]
+    XxHash64,
+    #[serde(rename = "crc32a")]
+    Crc32a,
+    #[serde(rename = "crc16")]
+    Crc16,
+}
+
+impl Default for HashMethod {
+    fn default() -> HashMethod {
+        HashMethod::Fnv1a64
+    }
+}
+
+impl HashMethod {
+    #[inline]
+    pub fn hash(self, key: &[u8]) -> u64 {
+        let hash = match self {
+            HashMethod::Fnv1a64 => fnv1a64(key),
+            HashMethod::Murmur3x32 => u64::from(murmur3_32(key, 0)),
+            HashMethod::Murmur3x128 => murmur3_128(key, 0).0,
+            HashMethod::XxHash64 => xxhash64(key, 0),
+            HashMethod::Crc32a => crc32a(key),
+            HashMethod::Crc16 => crc16_twemproxy(key),
+        };
+        hash & 0xffff_ffff
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data[..4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    u64::from_le_bytes(data[..8].try_into().unwrap())
+}
+
+// the tail of less than 8 bytes read as little endian
+fn read_tail(data: &[u8]) -> u64 {
+    data.iter()
+        .rev()
+        .fold(0u64, |acc, x| (acc << 8) | u64::from(*x))
+}
+
+/// MurmurHash3_x86_32 of the reference implementation.
+pub fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        h ^= mix(read_u32(chunk));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        h ^= mix(read_tail(tail) as u32);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+fn fmix64(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    k ^ (k >> 33)
+}
+
+/// MurmurHash3_x64_128 of the reference implementation, as the (low, high) 64 bits.
+pub fn murmur3_128(data: &[u8], seed: u64) -> (u64, u64) {
+    const C1: u64 = 0x87c3_7b91_1142_53d5;
+    const C2: u64 = 0x4cf5_ad43_2745_937f;
+    let mix1 = |k: u64| k.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    let mix2 = |k: u64| k.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+
+    let (mut h1, mut h2) = (seed, seed);
+    let mut chunks = data.chunks_exact(16);
+    for chunk in &mut chunks {
+        h1 ^= mix1(read_u64(chunk));
+        h1 = h1
+            .rotate_left(27)
+            .wrapping_add(h2)
+            .wrapping_mul(5)
+            .wrapping_add(0x52dc_e729);
+        h2 ^= mix2(read_u64(&chunk[8..]));
+        h2 = h2
+            .rotate_left(31)
+            .wrapping_add(h1)
+            .wrapping_mul(5)
+            .wrapping_add(0x3849_5ab5);
+    }
+    let tail = chunks.remainder();
+    if tail.len() > 8 {
+        h2 ^= mix2(read_tail(&tail[8..]));
+    }
+    if !tail.is_empty() {
+        h1 ^= mix1(read_tail(&tail[..tail.len().min(8)]));
+    }
+
+    h1 ^= data.len() as u64;
+    h2 ^= data.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix64(h1);
+    h2 = fmix64(h2);
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    (h1, h2)
+}
+
+const PRIME64_1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME64_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME64_3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME64_4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME64_5: u64 = 0x27d4_eb2f_1656_67c5;
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge(acc: u64, val: u64) -> u64 {
+    (acc ^ xxh64_round(0, val))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+/// XXH64 of the reference implementation.
+pub fn xxhash64(data: &[u8], seed: u64) -> u64 {
+    let mut rest = data;
+    let mut h = if data.len() >= 32 {
+        let mut v = [
+            seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+            seed.wrapping_add(PRIME64_2),
+            seed,
+            seed.wrapping_sub(PRIME64_1),
+        ];
+        while rest.len() >= 32 {
+            for (i, acc) in v.iter_mut().enumerate() {
+                *acc = xxh64_round(*acc, read_u64(&rest[i * 8..]));
+            }
+            rest = &rest[32..];
+        }
+        let h = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        v.iter().fold(h, |acc, x| xxh64_merge(acc, *x))
+    } else {
+        seed.wrapping_add(PRIME64_5)
+    };
+    h = h.wrapping_add(data.len() as u64);
+
+    while rest.len() >= 8 {
+        h ^= xxh64_round(0, read_u64(rest));
+        h = h
+            .rotate_left(27)
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        h ^= u64::from(read_u32(rest)).wrapping_mul(PRIME64_1);
+        h = h
+            .rotate_left(23)
+            .wrapping_mul(PRIME64_2)
+            .wrapping_add(PRIME64_3);
+        rest = &rest[4..];
+    }
+    for b in rest {
+        h ^= u64::from(*b).wrapping_mul(PRIME64_5);
+        h = h.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FOX: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+    #[test]
+    fn test_murmur3_golden() {
+        assert_eq!(murmur3_32(b"", 0), 0);
+        assert_eq!(murmur3_32(b"hello", 0), 0x248b_fa47);
+        assert_eq!(murmur3_32(FOX, 0), 0x2e4f_f723);
+
+        assert_eq!(murmur3_128(b"", 0), (0, 0));
+        assert_eq!(
+            murmur3_128(b"hello", 0),
+            (0xcbd8_a7b3_41bd_9b02, 0x5b1e_906a_48ae_1d19)
+        );
+        assert_eq!(
+            murmur3_128(FOX, 0),
+            (0xe34b_bc7b_bc07_1b6c, 0x7a43_3ca9_c49a_9347)
+        );
+    }
+
+    #[test]
+    fn test_xxhash64_golden() {
+        assert_eq!(xxhash64(b"", 0), 0xef46_db37_51d8_e999);
+        assert_eq!(xxhash64(b"a", 0), 0xd24e_c4f1_a98c_6e5b);
+        assert_eq!(xxhash64(b"abc", 0), 0x44bc_2cf5_ad77_0999);
+        assert_eq!(xxhash64(FOX, 0), 0x0b24_2d36_1fda_71bc);
+    }
+
+    #[test]
+    fn test_hash_method_golden() {
+        let cases = [
+            (HashMethod::Fnv1a64, 0x23c6_cdfc, 0xe7e4_7110),
+            (HashMethod::Murmur3x32, 0xb4fe_f382, 0x2e4f_f723),
+            (HashMethod::Murmur3x128, 0xdb66_cca4, 0xbc07_1b6c),
+            (HashMethod::XxHash64, 0x40e6_ae83, 0x1fda_71bc),
+            (HashMethod::Crc32a, 0xcbf4_3926, 0x414f_a339),
+            (HashMethod::Crc16, 0x8690_31c3, 0x22a3_f0c8),
+        ];
+        for (method, digits, fox) in cases.iter() {
+            assert_eq!(method.hash(b"123456789"), *digits, "{:?}", method);
+            assert_eq!(method.hash(FOX), *fox, "{:?}", method);
+        }
+        assert_eq!(HashMethod::default(), HashMethod::Fnv1a64);
+    }
+}
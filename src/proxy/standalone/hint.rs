@@ -0,0 +1,100 @@
+//! routing hint of a client connection, which pins its commands to a backend by name (alias
+//! or address) regardless of the key hash, e.g.: to debug a hot key or read a migrated one
+//! from its old node:
+//!
+//! ```text
+//! PROXY ROUTE node [ONCE|CONNECTION]
+//! PROXY ROUTE RESET
+//! ```
+//!
+//! ONCE (default) pins only the next command, and CONNECTION pins all the commands after it
+//! until RESET. The node is validated against the live topology of the worker both by the
+//! hint and by each command pinned, which is failed if the node is unknown, ejected, draining
+//! or closed.
+use crate::com::AsError;
+
+const SUB_CMD_ROUTE: &str = "ROUTE";
+const ROUTE_ONCE: &str = "ONCE";
+const ROUTE_CONNECTION: &str = "CONNECTION";
+const ROUTE_RESET: &str = "RESET";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouteHint {
+    // pin the next command, or all the commands after it if sticky
+    Pin { node: String, sticky: bool },
+    Reset,
+}
+
+/// the hint of PROXY ROUTE, None if the arguments after PROXY are not ROUTE.
+pub fn route_hint(args: &[String]) -> Option<Result<RouteHint, AsError>> {
+    match args.get(0) {
+        Some(sub_cmd) if sub_cmd.eq_ignore_ascii_case(SUB_CMD_ROUTE) => {}
+        _ => return None,
+    }
+    let rslt = match args.len() {
+        2 if args[1].eq_ignore_ascii_case(ROUTE_RESET) => Ok(RouteHint::Reset),
+        2 => Ok(RouteHint::Pin {
+            node: args[1].clone(),
+            sticky: false,
+        }),
+        3 if args[2].eq_ignore_ascii_case(ROUTE_ONCE) => Ok(RouteHint::Pin {
+            node: args[1].clone(),
+            sticky: false,
+        }),
+        3 if args[2].eq_ignore_ascii_case(ROUTE_CONNECTION) => Ok(RouteHint::Pin {
+            node: args[1].clone(),
+            sticky: true,
+        }),
+        3 => Err(AsError::BadProxyCommand(format!(
+            "unknown route scope '{}'. Try ONCE, CONNECTION.",
+            args[2]
+        ))),
+        _ => Err(AsError::BadProxyCommand(
+            "wrong number of arguments for 'proxy|route' command".to_string(),
+        )),
+    };
+    Some(rslt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn pin(node: &str, sticky: bool) -> Option<Result<RouteHint, AsError>> {
+        Some(Ok(RouteHint::Pin {
+            node: node.to_string(),
+            sticky,
+        }))
+    }
+
+    #[test]
+    fn test_route_hint() {
+        assert_eq!(
+            route_hint(&args(&["route", "redis-1"])),
+            pin("redis-1", false)
+        );
+        assert_eq!(
+            route_hint(&args(&["ROUTE", "redis-1", "once"])),
+            pin("redis-1", false)
+        );
+        assert_eq!(
+            route_hint(&args(&["ROUTE", "127.0.0.1:7001", "CONNECTION"])),
+            pin("127.0.0.1:7001", true)
+        );
+        assert_eq!(
+            route_hint(&args(&["ROUTE", "reset"])),
+            Some(Ok(RouteHint::Reset))
+        );
+        assert!(matches!(
+            route_hint(&args(&["ROUTE", "redis-1", "ALWAYS"])),
+            Some(Err(_))
+        ));
+        assert!(matches!(route_hint(&args(&["ROUTE"])), Some(Err(_))));
+        assert_eq!(route_hint(&args(&["BARRIER"])), None);
+        assert_eq!(route_hint(&[]), None);
+    }
+}
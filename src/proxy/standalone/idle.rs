@@ -0,0 +1,97 @@
+//! idle eviction of backend connections: the connection without requests for
+//! backend_idle_timeout is closed to free the resources of both sides in quiet periods, and
+//! reconnected on demand by the next request routed to it. The backend_min_idle connections
+//! used most recently are always kept, and so are the ones of ejected backends for their pings.
+use futures::{Async, Future, Stream};
+use tokio::timer::Interval;
+
+use std::rc::Weak;
+use std::time::{Duration, Instant};
+
+use crate::proxy::standalone::{Cluster, Request};
+
+const CHECK_INTERVAL: u64 = 1_000;
+
+/// the addresses of the connections idle for timeout, given when they are used last, and
+/// the min_idle ones used most recently are never picked.
+pub fn pick_idle(
+    mut conns: Vec<(String, Instant)>,
+    timeout: Duration,
+    min_idle: usize,
+    now: Instant,
+) -> Vec<String> {
+    conns.sort_by(|x, y| y.1.cmp(&x.1));
+    conns
+        .into_iter()
+        .skip(min_idle)
+        .filter(|x| now.saturating_duration_since(x.1) >= timeout)
+        .map(|x| x.0)
+        .collect()
+}
+
+pub struct Evictor<T> {
+    cluster: Weak<Cluster<T>>,
+    interval: Interval,
+}
+
+impl<T: Request + 'static> Evictor<T> {
+    pub fn new(cluster: Weak<Cluster<T>>) -> Self {
+        Evictor {
+            cluster,
+            interval: Interval::new(
+                Instant::now() + Duration::from_millis(CHECK_INTERVAL),
+                Duration::from_millis(CHECK_INTERVAL),
+            ),
+        }
+    }
+}
+
+impl<T: Request + 'static> Future for Evictor<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    error!("fail to poll idle eviction interval due {:?}", err);
+                    return Err(());
+                }
+            }
+
+            let cluster = match self.cluster.upgrade() {
+                Some(cluster) => cluster,
+                None => return Ok(Async::Ready(())),
+            };
+            cluster.evict_idle(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pick_idle() {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(10);
+        let conns = vec![
+            ("a".to_string(), now - Duration::from_secs(30)),
+            ("b".to_string(), now - Duration::from_secs(1)),
+            ("c".to_string(), now - Duration::from_secs(20)),
+        ];
+        let mut idle = pick_idle(conns.clone(), timeout, 0, now);
+        idle.sort();
+        assert_eq!(idle, vec!["a".to_string(), "c".to_string()]);
+        // the one used recently counts toward min_idle
+        assert_eq!(
+            pick_idle(conns.clone(), timeout, 2, now),
+            vec!["a".to_string()]
+        );
+        assert!(pick_idle(conns, timeout, 3, now).is_empty());
+    }
+}
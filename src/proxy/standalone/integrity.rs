@@ -0,0 +1,141 @@
+//! integrity sampling of the single key reads, a diagnostic of the silent corruption between
+//! proxy and backends (e.g.: a bad NIC), never a steady-state setting.
+//!
+//! 1 in integrity_sample of the reads replied without error (GET and each key of MGET of redis,
+//! each key of memcache text get) is sent again to the same backend on another connection once
+//! it's replied, and the checksums (xxh3) of both replies are compared. The mismatch is counted
+//! by the backend and logged with the hash of key, the write between both reads is counted as
+//! well. It costs nothing but one branch per reply if disabled.
+use futures::task;
+use futures::{Async, Future};
+use twox_hash::xxh3;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::com::ClusterConfig;
+use crate::metrics::{integrity_check_incr, integrity_mismatch_incr};
+use crate::proxy::standalone::fnv::fnv1a64;
+use crate::proxy::standalone::{Cluster, Request};
+
+pub fn checksum(data: &[u8]) -> u64 {
+    xxh3::hash64(data)
+}
+
+#[derive(Debug, Default)]
+pub struct Integrity {
+    // 1 in sample of the reads is checked, 0 means disabled
+    sample: u64,
+    // reads seen since the last one sampled
+    seen: Cell<u64>,
+}
+
+impl Integrity {
+    pub fn new(cc: &ClusterConfig) -> Integrity {
+        Integrity {
+            sample: cc.integrity_sample.unwrap_or(0),
+            seen: Cell::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sample > 0
+    }
+
+    // the first read is sampled, then each one after sample - 1 reads.
+    fn hit(&self) -> bool {
+        let seen = self.seen.get();
+        self.seen.set((seen + 1) % self.sample);
+        seen == 0
+    }
+}
+
+/// the compare of the single key read replied if it's sampled, None if it's not checked.
+pub fn sample<T: Request + 'static>(cluster: &Rc<Cluster<T>>, read: &T) -> Option<Compare<T>> {
+    if read.is_error() {
+        return None;
+    }
+    let expected = read.reply_checksum()?;
+    let addr = read.node()?;
+    if !cluster.integrity.borrow().hit() {
+        return None;
+    }
+    let replay = read.integrity_replay()?;
+    Some(Compare {
+        cluster: cluster.clone(),
+        addr,
+        key_hash: read.with_key(fnv1a64).unwrap_or_default(),
+        expected,
+        replay,
+        sent: false,
+    })
+}
+
+/// the replay of the read sampled, resolved once it's replied and compared.
+pub struct Compare<T> {
+    cluster: Rc<Cluster<T>>,
+    addr: String,
+    key_hash: u64,
+    // checksum of the reply of the read sampled
+    expected: u64,
+    replay: T,
+    sent: bool,
+}
+
+impl<T: Request + 'static> Future for Compare<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        if !self.sent {
+            self.replay.reregister(task::current());
+            if !self
+                .cluster
+                .dispatch_replay(&self.addr, self.replay.clone())
+            {
+                return Ok(Async::Ready(()));
+            }
+            self.sent = true;
+        }
+        if !self.replay.is_done() {
+            return Ok(Async::NotReady);
+        }
+        // e.g.: the connection is broken, which is not a corruption
+        if self.replay.is_error() {
+            return Ok(Async::Ready(()));
+        }
+        let cluster = self.cluster.cc.borrow().name.clone();
+        integrity_check_incr(&cluster, &self.addr);
+        if self.replay.reply_checksum() != Some(self.expected) {
+            integrity_mismatch_incr(&cluster, &self.addr);
+            warn!(
+                "cluster {} backend {} replied different values to the reads of key hash {:016x}",
+                cluster, self.addr, self.key_hash
+            );
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_integrity_sample_rate() {
+        let integrity = Integrity::new(&ClusterConfig::default());
+        assert!(!integrity.is_enabled());
+
+        let cc = ClusterConfig {
+            integrity_sample: Some(3),
+            ..Default::default()
+        };
+        let integrity = Integrity::new(&cc);
+        assert!(integrity.is_enabled());
+        let hits: Vec<_> = (0..7).map(|_| integrity.hit()).collect();
+        assert_eq!(hits, vec![true, false, false, true, false, false, true]);
+
+        assert_eq!(checksum(b"$1\r\na\r\n"), checksum(b"$1\r\na\r\n"));
+        assert_ne!(checksum(b"$1\r\na\r\n"), checksum(b"$1\r\nb\r\n"));
+    }
+}
@@ -126,6 +126,52 @@ impl HashRing {
         let pos = self.get_pos_by_hash(hash);
         self.ticks.get(pos).map(|x| x.node.as_ref())
     }
+
+    /// get node by round robin, used for commands which carry no key.
+    pub fn get_node_by_round(&self, round: usize) -> Option<&str> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        self.nodes.get(round % self.nodes.len()).map(|x| x.as_ref())
+    }
+
+    /// get the first node accepted by the filter clockwise from the hash, so the hash range
+    /// of skipped node is taken over by it's successor without rebuilding the ring.
+    pub fn get_node_with<F>(&self, hash: u64, accept: F) -> Option<&str>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.get_point_with(hash, accept).map(|x| x.1)
+    }
+
+    /// the point of ring owning the hash and its node, see get_node_with.
+    pub fn get_point_with<F>(&self, hash: u64, accept: F) -> Option<(u64, &str)>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let pos = self.get_pos_by_hash(hash);
+        let len = self.ticks.len();
+        (0..len)
+            .map(|i| &self.ticks[(pos + i) % len])
+            .find(|x| accept(&x.node))
+            .map(|x| (x.hash, x.node.as_ref()))
+    }
+
+    /// the points of ring in ascending order of hash, with the node owning each point.
+    pub fn points(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.ticks.iter().map(|x| (x.hash, x.node.as_ref()))
+    }
+
+    /// get node accepted by the filter by round robin.
+    pub fn get_node_by_round_with<F>(&self, round: usize, accept: F) -> Option<&str>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let len = self.nodes.len();
+        (0..len)
+            .map(|i| self.nodes[(round + i) % len].as_ref())
+            .find(|x| accept(x))
+    }
 }
 
 #[cfg(test)]
@@ -158,4 +204,49 @@ mod test_ketama {
             Some("mc-x")
         )
     }
+
+    #[test]
+    fn ketama_keyless_round() {
+        let nodes = vec!["mc-1".to_owned(), "mc-2".to_owned(), "mc-3".to_owned()];
+        let ring =
+            HashRing::new(nodes.clone(), vec![10, 10, 10]).expect("create new hash ring success");
+        let picked: std::collections::HashSet<_> = (0..nodes.len())
+            .map(|round| ring.get_node_by_round(round).unwrap().to_owned())
+            .collect();
+        assert_eq!(picked.len(), nodes.len());
+
+        // empty key always hash to the same node, which is why keyless
+        // commands never go through get_node.
+        let empty = ring.get_node(fnv1a64(b""));
+        assert!((0..16).all(|_| ring.get_node(fnv1a64(b"")) == empty));
+        assert_eq!(HashRing::empty().get_node_by_round(0), None);
+    }
+
+    #[test]
+    fn ketama_skip_node() {
+        let nodes = vec!["mc-1".to_owned(), "mc-2".to_owned(), "mc-3".to_owned()];
+        let ring =
+            HashRing::new(nodes.clone(), vec![10, 10, 10]).expect("create new hash ring success");
+        let hash = fnv1a64(b"a");
+        let node = ring.get_node(hash).unwrap().to_owned();
+        assert_eq!(ring.get_node_with(hash, |_| true), Some(node.as_str()));
+
+        // keys of the skipped node move to others while the rest keep their node
+        let skipped = ring.get_node_with(hash, |x| x != node).unwrap();
+        assert_ne!(skipped, node);
+        for key in &[&b"b"[..], b"c", b"d", b"e", b"f"] {
+            let hash = fnv1a64(key);
+            let origin = ring.get_node(hash).unwrap();
+            if origin != node {
+                assert_eq!(ring.get_node_with(hash, |x| x != node), Some(origin));
+            }
+        }
+        for round in 0..3 {
+            assert_ne!(
+                ring.get_node_by_round_with(round, |x| x != node),
+                Some(node.as_str())
+            );
+        }
+        assert_eq!(ring.get_node_with(hash, |_| false), None);
+    }
 }
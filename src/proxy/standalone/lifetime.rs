@@ -0,0 +1,43 @@
+//! max lifetime of backend connections: the connection older than backend_max_lifetime is
+//! recycled even if it's healthy, e.g.: to follow the rebalancing of the load balancer in front
+//! of backends. It's replaced by a fresh connection for the new commands at once, and the old
+//! one is drained as the connection of backend removed by reload (see retire), so the commands
+//! in flight on it are never failed. Each connection lives for the lifetime plus a random jitter
+//! up to backend_lifetime_jitter, so the ones made together (e.g.: on startup) never reconnect
+//! all at once.
+use rand::Rng;
+
+use std::time::{Duration, Instant};
+
+/// when the connection made at now is recycled, None if the lifetime is 0 (disabled).
+pub fn expire_at<R: Rng>(now: Instant, lifetime: u64, jitter: u64, rng: &mut R) -> Option<Instant> {
+    if lifetime == 0 {
+        return None;
+    }
+    let jitter = rng.gen_range(0, jitter + 1);
+    Some(now + Duration::from_millis(lifetime + jitter))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expire_at() {
+        let mut rng = rand::thread_rng();
+        let now = Instant::now();
+        assert_eq!(expire_at(now, 0, 1000, &mut rng), None);
+        assert_eq!(
+            expire_at(now, 1000, 0, &mut rng),
+            Some(now + Duration::from_millis(1000))
+        );
+        let expires: Vec<_> = (0..100)
+            .map(|_| expire_at(now, 1000, 500, &mut rng).unwrap())
+            .collect();
+        assert!(expires.iter().all(|x| {
+            *x >= now + Duration::from_millis(1000) && *x <= now + Duration::from_millis(1500)
+        }));
+        // spread by the jitter
+        assert!(expires.iter().any(|x| *x != expires[0]));
+    }
+}
@@ -0,0 +1,147 @@
+//! backends added or removed one by one at runtime by the redis commands of proxy mode:
+//!
+//! ```text
+//! PROXY ADDNODE addr weight [alias]
+//! PROXY DELNODE node
+//! ```
+//!
+//! the servers are updated as a new config version which is applied by the reloader of each
+//! worker in seconds, the same as hot reload. The added node is warmed up by slow start, and
+//! the removed node (named by alias or address) is drained before dropped from the ring.
+//!
+//! `PROXY MONITOR` is accepted here as well, and the connection is attached to proxy::monitor
+//! by the front. `PROXY NODES` lists the state of each backend seen by the worker, with the
+//! reason of the ejected one (e.g.: `ejected protocol violation`), which is read only and
+//! answered even if proxy_admin is disabled.
+use crate::com::{AsError, ClusterConfig};
+use crate::proxy::monitor;
+use crate::proxy::standalone::drain::{self, NodeState};
+use crate::proxy::standalone::{check_servers, is_server_of, node_name, reload};
+
+const SUB_CMD_ADDNODE: &str = "ADDNODE";
+const SUB_CMD_DELNODE: &str = "DELNODE";
+const SUB_CMD_NODES: &str = "NODES";
+
+/// the arguments after PROXY is NODES.
+pub fn is_nodes(args: &[String]) -> bool {
+    args.len() == 1 && args[0].eq_ignore_ascii_case(SUB_CMD_NODES)
+}
+
+/// handle the arguments after PROXY, which is denied unless proxy_admin is enabled.
+pub fn handle(cc: &ClusterConfig, args: &[String]) -> Result<(), AsError> {
+    if !cc.proxy_admin.unwrap_or(false) {
+        return Err(AsError::RequestNotSupport);
+    }
+    if monitor::is_monitor(args) {
+        return Ok(());
+    }
+    let sub_cmd = args.get(0).map(|x| x.to_uppercase()).unwrap_or_default();
+    match (sub_cmd.as_str(), args.len()) {
+        (SUB_CMD_ADDNODE, 3) | (SUB_CMD_ADDNODE, 4) => {
+            add(cc, &args[1], &args[2], args.get(3).map(|x| x.as_str()))
+        }
+        (SUB_CMD_DELNODE, 2) => remove(cc, &args[1]),
+        (SUB_CMD_ADDNODE, _) | (SUB_CMD_DELNODE, _) => Err(AsError::BadProxyCommand(format!(
+            "wrong number of arguments for 'proxy|{}' command",
+            sub_cmd.to_lowercase()
+        ))),
+        _ => Err(AsError::BadProxyCommand(format!(
+            "unknown subcommand '{}'. Try ADDNODE, BARRIER, COMMANDS, CONFIG, DELNODE, MONITOR, NODES, RING, ROUTE, SHARD, SLO.",
+            args.get(0).map(|x| x.as_str()).unwrap_or_default()
+        ))),
+    }
+}
+
+/// add the backend into servers.
+pub fn add(
+    cc: &ClusterConfig,
+    addr: &str,
+    weight: &str,
+    alias: Option<&str>,
+) -> Result<(), AsError> {
+    if !addr.contains(':') || weight.parse::<usize>().is_err() {
+        return Err(AsError::BadProxyCommand(format!(
+            "invalid node {} of weight {}",
+            addr, weight
+        )));
+    }
+    let server = match alias {
+        Some(alias) => format!("{}:{} {}", addr, weight, alias),
+        None => format!("{}:{}", addr, weight),
+    };
+    check_servers(&[server.clone()])?;
+    let name = node_name(&server).expect("server must be valid");
+
+    let exists = |line: &String| is_server_of(line, addr) || is_server_of(line, &name);
+    reload::update(&cc.name, |cc| {
+        if cc.servers.iter().any(exists) {
+            return Err(AsError::BadProxyCommand(format!(
+                "node {} already exists",
+                name
+            )));
+        }
+        cc.servers.push(server);
+        Ok(())
+    })?;
+    // the node may be drained by the former removal
+    drain::set_state(&cc.name, &name, NodeState::Active);
+    info!("node {} is added into cluster {}", name, cc.name);
+    persist(cc)
+}
+
+/// drain the backend, which is removed from servers once drained.
+pub fn remove(cc: &ClusterConfig, node: &str) -> Result<(), AsError> {
+    let current = reload::cluster(&cc.name)
+        .ok_or_else(|| AsError::BadConfig(format!("cluster {} not found", cc.name)))?;
+    let name = current
+        .servers
+        .iter()
+        .find(|x| is_server_of(x, node))
+        .and_then(|x| node_name(x))
+        .ok_or_else(|| AsError::BadProxyCommand(format!("node {} not found", node)))?;
+    if current.servers.len() == 1 {
+        return Err(AsError::BadProxyCommand(format!(
+            "node {} is the last one of cluster {}",
+            node, cc.name
+        )));
+    }
+    if !drain::remove(&cc.name, &name) {
+        return Err(AsError::BadConfig(format!("cluster {} not found", cc.name)));
+    }
+    info!(
+        "node {} of cluster {} is draining to be removed",
+        name, cc.name
+    );
+    Ok(())
+}
+
+/// remove the drained backends from servers, which is called by the drain checker.
+pub fn remove_drained(cc: &ClusterConfig) {
+    for node in drain::take_removed(&cc.name) {
+        let rslt = reload::update(&cc.name, |cc| {
+            cc.servers.retain(|x| !is_server_of(x, &node));
+            if cc.servers.is_empty() {
+                return Err(AsError::BadConfig(format!(
+                    "{}.servers can't be empty",
+                    cc.name
+                )));
+            }
+            Ok(())
+        })
+        .and_then(|_| persist(cc));
+        match rslt {
+            Ok(()) => info!("node {} is removed from cluster {}", node, cc.name),
+            Err(err) => error!(
+                "fail to remove node {} from cluster {} due to {}",
+                node, cc.name, err
+            ),
+        }
+    }
+}
+
+fn persist(cc: &ClusterConfig) -> Result<(), AsError> {
+    if cc.proxy_admin_persist.unwrap_or(false) {
+        reload::persist(&cc.name)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! keys pinned to a fixed node regardless of the hash, for the workloads which need strict
+//! ordering (e.g.: a global counter). Each pin is "${pattern} ${node}" in config, the pattern is
+//! glob-style matched against the whole key and the node is named by alias or address as in
+//! servers.
+//!
+//! Pins are evaluated in order and the first matched wins. They precede hashing and standby,
+//! only admin commands routed by admin_node come before them. The pinned keys never move even
+//! when the node is drained, ejected or warming up, which would break the ordering.
+use crate::com::AsError;
+use crate::proxy::standalone::ring_nodes;
+
+#[derive(Clone, Debug, Default)]
+pub struct Pins {
+    // pattern and address of the node
+    pins: Vec<(Vec<u8>, String)>,
+}
+
+impl Pins {
+    pub fn new(pin_keys: &[String], servers: &[String]) -> Result<Pins, AsError> {
+        if pin_keys.is_empty() {
+            return Ok(Pins::default());
+        }
+        let nodes = ring_nodes(servers)?;
+        let mut pins = Vec::with_capacity(pin_keys.len());
+        for pin in pin_keys {
+            let fields: Vec<_> = pin.split_whitespace().collect();
+            if fields.len() != 2 {
+                return Err(AsError::BadConfig(format!(
+                    "pin_keys: {} must be \"${{pattern}} ${{node}}\"",
+                    pin
+                )));
+            }
+            let addr = nodes
+                .iter()
+                .find(|x| x.0 == fields[1])
+                .map(|x| x.1.clone())
+                .ok_or_else(|| {
+                    AsError::BadConfig(format!("pin_keys: node {} not in servers", fields[1]))
+                })?;
+            pins.push((fields[0].as_bytes().to_vec(), addr));
+        }
+        Ok(Pins { pins })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+
+    /// the address of the node which the key is pinned to.
+    pub fn get(&self, key: &[u8]) -> Option<&str> {
+        self.pins
+            .iter()
+            .find(|x| glob_match(&x.0, key))
+            .map(|x| x.1.as_str())
+    }
+}
+
+/// match the glob-style pattern of '*' (any bytes) and '?' (one byte).
+pub fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // the position of the last '*' in pattern and the key it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match star {
+                // backtrack and let the '*' match one more byte
+                Some((sp, sk)) => {
+                    star = Some((sp, sk + 1));
+                    p = sp + 1;
+                    k = sk + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"counter:*", b"counter:"));
+        assert!(glob_match(b"counter:*", b"counter:global"));
+        assert!(!glob_match(b"counter:*", b"counters"));
+        assert!(glob_match(b"*:seq", b"order:seq"));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+        assert!(glob_match(b"user:?", b"user:1"));
+        assert!(!glob_match(b"user:?", b"user:10"));
+        assert!(glob_match(b"exact", b"exact"));
+        assert!(!glob_match(b"exact", b"exactly"));
+        assert!(glob_match(b"*", b""));
+    }
+
+    #[test]
+    fn test_pins_first_match_wins() {
+        let servers = vec![
+            "127.0.0.1:7001:10 redis-1".to_string(),
+            "127.0.0.1:7002:10 redis-2".to_string(),
+        ];
+        let pin_keys = vec![
+            "counter:global redis-2".to_string(),
+            "counter:* redis-1".to_string(),
+        ];
+        let pins = Pins::new(&pin_keys, &servers).unwrap();
+        assert_eq!(pins.get(b"counter:global"), Some("127.0.0.1:7002"));
+        assert_eq!(pins.get(b"counter:other"), Some("127.0.0.1:7001"));
+        assert_eq!(pins.get(b"user:1"), None);
+
+        assert!(Pins::new(&["counter:*".to_string()], &servers).is_err());
+        assert!(Pins::new(&["counter:* redis-3".to_string()], &servers).is_err());
+        assert!(Pins::new(&[], &servers).unwrap().is_empty());
+    }
+}
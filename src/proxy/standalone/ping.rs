@@ -1,20 +1,70 @@
 use futures::task;
 use futures::{Async, AsyncSink, Future, Stream};
-use tokio::timer::Interval;
+use tokio::timer::{Delay, Interval};
 
 use std::cell::Cell;
 use std::rc::{Rc, Weak};
 use std::time::{Duration, Instant};
 
+use crate::com::BackendFlavor;
+use crate::metrics::{backend_timeout_set, ping_latency_set};
+use crate::proxy::standalone::failover::Eject;
 use crate::proxy::standalone::{Cluster, Request};
 
+const EWMA_ALPHA: f64 = 0.2;
+// the backoff of pinging the unavailable backend is doubled up to it
+const MAX_BACKOFF: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Health {
+    Alive,
+    // replied as expected but slower than ping_slow_threshold, counted toward ejection
+    // without reconnecting
+    Slow,
+    // replied it can't serve for now (e.g.: LOADING), ejected at once and pinged by backoff
+    // until it's replied by PONG
+    Unavailable,
+    Dead,
+}
+
 #[derive(Debug)]
 enum State<T> {
-    Justice(bool),
+    Justice(Health),
     OnFail,
     OnSuccess,
+    Backoff(Delay),
     Sending(T),
-    Waitting(T),
+    Waitting(T, Instant),
+}
+
+/// exponentially weighted moving average of ping latency in millis.
+#[derive(Default)]
+struct Ewma {
+    value: Option<f64>,
+}
+
+impl Ewma {
+    fn observe(&mut self, sample: f64) -> f64 {
+        let value = match self.value {
+            Some(value) => value + EWMA_ALPHA * (sample - value),
+            None => sample,
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+fn judge<T: Request>(cmd: &T, flavor: BackendFlavor, elapsed: Duration, slow: u64) -> Health {
+    // e.g.: LOADING while the backend is loading the dataset
+    if cmd.is_unavailable(flavor) {
+        Health::Unavailable
+    } else if cmd.is_error() || !cmd.is_pong() {
+        Health::Dead
+    } else if slow > 0 && elapsed > Duration::from_millis(slow) {
+        Health::Slow
+    } else {
+        Health::Alive
+    }
 }
 
 pub struct Ping<T: Request> {
@@ -25,9 +75,16 @@ pub struct Ping<T: Request> {
 
     fail_interval: Interval,
     succ_interval: Interval,
+    stall_interval: Interval,
 
     count: u8,
     limit: u8,
+    latency: Ewma,
+    // checks in a row which found the requests of connection stalled
+    stalls: u8,
+    succ_interval_millis: u64,
+    // the last backoff of the unavailable backend, 0 if it's available
+    backoff: u64,
 
     state: State<T>,
     cancel: Rc<Cell<bool>>,
@@ -47,7 +104,11 @@ impl<T: Request> Ping<T> {
             Instant::now() + Duration::from_secs(1),
             Duration::from_millis(interval_millis),
         );
-        let succ_interval = Interval::new(
+        // the first ping is the handshake of backend, sent at once so the unavailable one is
+        // found before most of the requests routed to it
+        let succ_interval =
+            Interval::new(Instant::now(), Duration::from_millis(succ_interval_millis));
+        let stall_interval = Interval::new(
             Instant::now() + Duration::from_secs(1),
             Duration::from_millis(succ_interval_millis),
         );
@@ -58,19 +119,126 @@ impl<T: Request> Ping<T> {
             addr,
             fail_interval,
             succ_interval,
+            stall_interval,
             limit,
             count: 0,
+            latency: Ewma::default(),
+            stalls: 0,
+            succ_interval_millis,
+            backoff: 0,
             state: State::OnSuccess,
             cancel,
         }
     }
 }
 
+impl<T: Request + 'static> Ping<T> {
+    // ping is paused when the node is drained or disabled by admin
+    fn is_closed(&self) -> bool {
+        self.cluster
+            .upgrade()
+            .map(|cluster| cluster.is_closed(&self.name))
+            .unwrap_or(false)
+    }
+
+    // ping is paused as well when the idle connection is closed, until it's reconnected by
+    // the requests routed to the node
+    fn is_evicted(&self) -> bool {
+        self.cluster
+            .upgrade()
+            .map(|cluster| cluster.is_evicted(&self.addr))
+            .unwrap_or(false)
+    }
+
+    // the connection is cycled once its requests are found stalled by several checks in a
+    // row even if pings succeed, since one connection may be wedged alone.
+    fn check_stalled(&mut self) {
+        while let Ok(Async::Ready(Some(_))) = self.stall_interval.poll() {
+            let cluster = match self.cluster.upgrade() {
+                Some(cluster) => cluster,
+                None => return,
+            };
+            let (name, adaptive, limit) = {
+                let cc = cluster.cc.borrow();
+                let adaptive = cc.adaptive_timeout.unwrap_or(false);
+                (cc.name.clone(), adaptive, cc.stale_conn_limit())
+            };
+            // read_timeout, or the one adapted to the replies of the connection
+            let timeout = cluster.stall_timeout(&self.addr);
+            match timeout {
+                Some(timeout) if adaptive => {
+                    backend_timeout_set(&name, &self.name, timeout.as_secs_f64() * 1000.0);
+                }
+                _ => {}
+            }
+            let timeout = match timeout {
+                Some(timeout) if limit > 0 => timeout,
+                _ => continue,
+            };
+            if !cluster.is_stalled(&self.addr, timeout) {
+                self.stalls = 0;
+                continue;
+            }
+            self.stalls += 1;
+            if self.stalls >= limit {
+                warn!(
+                    "cycle the connection to {}({}) stalled beyond {:?} for {} checks in a row",
+                    self.name, self.addr, timeout, self.stalls
+                );
+                self.stalls = 0;
+                cluster.reconnect(&self.addr);
+            }
+        }
+    }
+
+    // the node ejected by the violations of reply framing is recovered by the next ping
+    // succeeded, the same as the one ejected by ping failures.
+    fn check_violations(&mut self) {
+        let cluster = match self.cluster.upgrade() {
+            Some(cluster) => cluster,
+            None => return,
+        };
+        if cluster.check_violations(&self.name, &self.addr) {
+            self.count = self.limit.saturating_add(1);
+        }
+    }
+
+    // eject the unavailable backend at once, it's recovered by the first ping replied by PONG
+    // the same as the one ejected by ping failures.
+    fn unavailable(&mut self) -> Result<(), ()> {
+        if self.count <= self.limit {
+            let cluster = self.cluster.upgrade().ok_or(())?;
+            warn!(
+                "remove node={} addr={} until it's available",
+                self.name, self.addr
+            );
+            cluster.remove_node(self.name.clone(), Eject::Unavailable);
+            // the connection dropped by ejection is kept for the pings only
+            cluster.reconnect(&self.addr);
+            self.count = self.limit.saturating_add(1);
+        }
+        self.backoff = next_backoff(self.backoff, self.succ_interval_millis);
+        self.state = State::Backoff(Delay::new(
+            Instant::now() + Duration::from_millis(self.backoff),
+        ));
+        Ok(())
+    }
+}
+
+fn next_backoff(backoff: u64, initial: u64) -> u64 {
+    if backoff == 0 {
+        return initial.min(MAX_BACKOFF);
+    }
+    backoff.saturating_mul(2).min(MAX_BACKOFF)
+}
+
 impl<T: Request + 'static> Future for Ping<T> {
     type Item = ();
     type Error = ();
 
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        self.check_stalled();
+        self.check_violations();
         loop {
             if self.cancel.get() {
                 info!("ping to {}({}) was canceld by handle", self.name, self.addr);
@@ -78,8 +246,15 @@ impl<T: Request + 'static> Future for Ping<T> {
             }
 
             match self.state {
-                State::Justice(is_last_succ) => {
-                    if is_last_succ {
+                // never ejected if ping_fail_limit is 0
+                State::Justice(Health::Unavailable) if self.limit > 0 => {
+                    if self.unavailable().is_err() {
+                        return Ok(Async::Ready(()));
+                    }
+                }
+                State::Justice(health) => {
+                    if health == Health::Alive {
+                        self.backoff = 0;
                         if self.count > self.limit {
                             // removed but success next time
                             if let Some(cluster) = self.cluster.upgrade() {
@@ -104,7 +279,7 @@ impl<T: Request + 'static> Future for Ping<T> {
                         if self.count == self.limit {
                             if let Some(cluster) = self.cluster.upgrade() {
                                 info!("remove node={} addr={} by ping error", self.name, self.addr);
-                                cluster.remove_node(self.name.clone());
+                                cluster.remove_node(self.name.clone(), Eject::PingFailure);
                             } else {
                                 return Ok(Async::Ready(()));
                             }
@@ -115,6 +290,9 @@ impl<T: Request + 'static> Future for Ping<T> {
                             self.state = State::OnSuccess;
                         }
 
+                        if health == Health::Slow {
+                            continue;
+                        }
                         if let Some(cluster) = self.cluster.upgrade() {
                             cluster.reconnect(&self.addr);
                         } else {
@@ -124,6 +302,9 @@ impl<T: Request + 'static> Future for Ping<T> {
                 }
                 State::OnSuccess => match self.succ_interval.poll() {
                     Ok(Async::Ready(Some(_))) => {
+                        if self.is_closed() || self.is_evicted() {
+                            continue;
+                        }
                         let mut cmd = T::ping_request();
                         cmd.reregister(task::current());
                         self.state = State::Sending(cmd);
@@ -138,8 +319,29 @@ impl<T: Request + 'static> Future for Ping<T> {
                         warn!("fail to poll interval due when succ {:?}", err);
                     }
                 },
+                State::Backoff(ref mut delay) => match delay.poll() {
+                    Ok(Async::Ready(_)) => {
+                        if self.is_closed() {
+                            self.state = State::OnSuccess;
+                            continue;
+                        }
+                        let mut cmd = T::ping_request();
+                        cmd.reregister(task::current());
+                        self.state = State::Sending(cmd);
+                    }
+                    Ok(Async::NotReady) => {
+                        return Ok(Async::NotReady);
+                    }
+                    Err(err) => {
+                        warn!("fail to poll backoff of unavailable backend {:?}", err);
+                        self.state = State::OnFail;
+                    }
+                },
                 State::OnFail => match self.fail_interval.poll() {
                     Ok(Async::Ready(Some(_))) => {
+                        if self.is_closed() {
+                            continue;
+                        }
                         let mut cmd = T::ping_request();
                         cmd.reregister(task::current());
                         self.state = State::Sending(cmd);
@@ -157,7 +359,7 @@ impl<T: Request + 'static> Future for Ping<T> {
                 State::Sending(ref cmd) => {
                     let rc_cmd = cmd.clone();
                     if !rc_cmd.can_cycle() {
-                        self.state = State::Justice(false);
+                        self.state = State::Justice(Health::Dead);
                         continue;
                     }
                     if let Some(cluster) = self.cluster.upgrade() {
@@ -166,11 +368,11 @@ impl<T: Request + 'static> Future for Ping<T> {
                                 return Ok(Async::NotReady);
                             }
                             Ok(AsyncSink::Ready) => {
-                                self.state = State::Waitting(cmd.clone());
+                                self.state = State::Waitting(cmd.clone(), Instant::now());
                             }
                             Err(err) => {
                                 info!("fail to dispatch_to {} due to {:?}", self.addr, err);
-                                self.state = State::Justice(false);
+                                self.state = State::Justice(Health::Dead);
                             }
                         }
                     } else {
@@ -179,13 +381,90 @@ impl<T: Request + 'static> Future for Ping<T> {
                     }
                 }
 
-                State::Waitting(ref cmd) => {
+                State::Waitting(ref cmd, sent) => {
                     if !cmd.is_done() {
                         return Ok(Async::NotReady);
                     }
-                    self.state = State::Justice(!cmd.is_error());
+                    let cluster = match self.cluster.upgrade() {
+                        Some(cluster) => cluster,
+                        None => return Ok(Async::Ready(())),
+                    };
+                    let cc = cluster.cc.borrow();
+                    let elapsed = sent.elapsed();
+                    let health = judge(
+                        cmd,
+                        cc.backend_flavor.unwrap_or_default(),
+                        elapsed,
+                        cc.ping_slow_threshold.unwrap_or(0),
+                    );
+                    if health != Health::Dead && health != Health::Unavailable {
+                        let millis = elapsed.as_secs_f64() * 1000.0;
+                        ping_latency_set(&cc.name, &self.name, self.latency.observe(millis));
+                    }
+                    if health == Health::Slow {
+                        warn!(
+                            "ping to {}({}) is slow in {:?}",
+                            self.name, self.addr, elapsed
+                        );
+                    }
+                    self.state = State::Justice(health);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::redis::{Cmd, Message};
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_ewma_latency() {
+        let mut latency = Ewma::default();
+        assert_eq!(latency.observe(10.0), 10.0);
+        assert_eq!(latency.observe(20.0), 12.0);
+        // a single spike is smoothed, but the sustained slowness is followed
+        for _ in 0..64 {
+            latency.observe(100.0);
+        }
+        assert!((latency.observe(100.0) - 100.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_judge_ping_reply() {
+        let replied = |data: &[u8]| {
+            let ping = Cmd::ping_request();
+            ping.set_reply(Message::parse(&mut BytesMut::from(data)).unwrap().unwrap());
+            ping
+        };
+        let flavor = BackendFlavor::default();
+        let fast = Duration::from_millis(1);
+        let slow = Duration::from_millis(200);
+
+        let pong = replied(b"+PONG\r\n");
+        assert_eq!(judge(&pong, flavor, fast, 100), Health::Alive);
+        assert_eq!(judge(&pong, flavor, slow, 100), Health::Slow);
+        // disabled by default
+        assert_eq!(judge(&pong, flavor, slow, 0), Health::Alive);
+
+        // the stale data echoed by a wedged backend
+        let stale = replied(b"$1\r\na\r\n");
+        assert_eq!(judge(&stale, flavor, fast, 100), Health::Dead);
+        let loading = replied(b"-LOADING Redis is loading the dataset in memory\r\n");
+        assert_eq!(judge(&loading, flavor, fast, 100), Health::Unavailable);
+        let busy = replied(b"-BUSY Redis is busy running a script\r\n");
+        assert_eq!(judge(&busy, flavor, fast, 100), Health::Unavailable);
+        let err = replied(b"-ERR unknown command\r\n");
+        assert_eq!(judge(&err, flavor, fast, 100), Health::Dead);
+    }
+
+    #[test]
+    fn test_next_backoff() {
+        assert_eq!(next_backoff(0, 1000), 1000);
+        assert_eq!(next_backoff(1000, 1000), 2000);
+        assert_eq!(next_backoff(8000, 1000), MAX_BACKOFF);
+        assert_eq!(next_backoff(0, 60_000), MAX_BACKOFF);
+    }
+}
@@ -5,6 +5,7 @@ use tokio::timer::Interval;
 
 use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::path::Path;
 use std::rc::{Rc, Weak};
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -58,6 +59,97 @@ impl FileWatcher {
             .expect("current version must be exists")
     }
 
+    fn register(&self, cc: &ClusterConfig) {
+        let current = self.current.load(Ordering::SeqCst);
+        let mut handle = self.versions.lock().unwrap();
+        let config = handle
+            .get_mut(&current)
+            .expect("current version must be exists");
+        if config.cluster(&cc.name).is_none() {
+            config.clusters.push(cc.clone());
+        }
+    }
+
+    fn update<F>(&self, name: &str, f: F) -> Result<(), AsError>
+    where
+        F: FnOnce(&mut ClusterConfig) -> Result<(), AsError>,
+    {
+        let mut handle = self.versions.lock().unwrap();
+        let current = self.current.load(Ordering::SeqCst);
+        let mut config = handle
+            .get(&current)
+            .cloned()
+            .expect("current version must be exists");
+        let cc = config
+            .clusters
+            .iter_mut()
+            .find(|x| x.name == name)
+            .ok_or_else(|| AsError::BadConfig(format!("cluster {} not found", name)))?;
+        f(cc)?;
+        // only the updated cluster is checked, other clusters failed to start never block it
+        Config {
+            clusters: vec![cc.clone()],
+            ..Default::default()
+        }
+        .valid()?;
+
+        info!(
+            "update config of cluster {} as {:?}",
+            name,
+            config.cluster(name)
+        );
+        handle.insert(current + 1, config);
+        self.current.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // only the servers of the cluster is replaced, other content is kept but the comments
+    // and the layout of the file are lost.
+    fn persist(&self, name: &str) -> Result<(), AsError> {
+        if self.watchfile.is_empty() {
+            return Err(AsError::BadConfig(format!(
+                "{}.servers can't be persisted without config file",
+                name
+            )));
+        }
+        let servers = self
+            .current_config()
+            .cluster(name)
+            .map(|cc| cc.servers)
+            .ok_or_else(|| AsError::BadConfig(format!("cluster {} not found", name)))?;
+
+        let data = fs::read_to_string(&self.watchfile)?;
+        let mut value: toml::Value = toml::from_str(&data)?;
+        let cluster = value
+            .get_mut("clusters")
+            .and_then(|x| x.as_array_mut())
+            .and_then(|clusters| {
+                clusters
+                    .iter_mut()
+                    .find(|x| x.get("name").and_then(|y| y.as_str()) == Some(name))
+            })
+            .and_then(|x| x.as_table_mut())
+            .ok_or_else(|| {
+                AsError::BadConfig(format!("cluster {} not found in {}", name, self.watchfile))
+            })?;
+        cluster.insert(
+            "servers".to_string(),
+            toml::Value::Array(servers.into_iter().map(toml::Value::String).collect()),
+        );
+        let data = toml::to_string(&value)
+            .map_err(|err| AsError::BadConfig(format!("{}.servers {}", name, err)))?;
+
+        // replaced by rename, which never leaves a partial file to be reloaded
+        let tmp = format!("{}.tmp", self.watchfile);
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.watchfile)?;
+        info!(
+            "persist servers of cluster {} into {}",
+            name, self.watchfile
+        );
+        Ok(())
+    }
+
     fn reload(&self) -> Result<(), AsError> {
         thread::sleep(Duration::from_millis(200));
         debug!("reload from file {:p}", &self.watchfile);
@@ -166,9 +258,34 @@ fn get_config(version: usize) -> Option<Config> {
     fw.get_config(version)
 }
 
-fn enable_reload() -> bool {
+/// register the cluster spawned by the embedding api (e.g.: without config file), which
+/// is the base of later updates.
+pub fn register(cc: &ClusterConfig) -> Result<(), AsError> {
+    init("", Config::default(), false)?;
+    let fw = unsafe { G_FW.as_ref().unwrap() };
+    fw.register(cc);
+    Ok(())
+}
+
+/// update the config of cluster as a new version, which is applied by the reloader
+/// of each worker thread in seconds.
+pub fn update<F>(name: &str, f: F) -> Result<(), AsError>
+where
+    F: FnOnce(&mut ClusterConfig) -> Result<(), AsError>,
+{
+    let fw = unsafe { G_FW.as_ref().unwrap() };
+    fw.update(name, f)
+}
+
+/// the config of cluster in the current version, which may be not applied by workers yet.
+pub fn cluster(name: &str) -> Option<ClusterConfig> {
+    current_version().config().and_then(|x| x.cluster(name))
+}
+
+/// write the current servers of the cluster back to the config file.
+pub fn persist(name: &str) -> Result<(), AsError> {
     let fw = unsafe { G_FW.as_ref().unwrap() };
-    fw.enable_reload()
+    fw.persist(name)
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -185,17 +302,14 @@ pub struct Reloader<T> {
     cluster: Weak<Cluster<T>>,
     current: Version,
     interval: Interval,
-    enable: bool,
 }
 
 impl<T: Request + 'static> Reloader<T> {
     pub fn new(cluster: Rc<Cluster<T>>) -> Self {
-        let enable = enable_reload();
         let name = cluster.cc.borrow().name.clone();
         let weak = Rc::downgrade(&cluster);
         Reloader {
             name,
-            enable,
             cluster: weak,
             current: Version(0),
             interval: Interval::new(
@@ -212,12 +326,9 @@ where
 {
     type Item = ();
     type Error = ();
+    // the version is only changed by file watcher if reload is enabled by cli arguments,
+    // or by the embedding api.
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        if !self.enable {
-            debug!("success reload exists due reload not allow by cli arguments");
-            return Ok(Async::Ready(()));
-        }
-
         loop {
             match self.interval.poll() {
                 Ok(Async::Ready(_)) => {}
@@ -270,3 +381,58 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_persist_servers() {
+        let path = env::temp_dir().join(format!("aster-persist-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(
+            &path,
+            r#"
+[[clusters]]
+name = "test-persist"
+listen_addr = "127.0.0.1:9001"
+cache_type = "redis"
+servers = ["127.0.0.1:7001:10"]
+
+[[clusters]]
+name = "other"
+listen_addr = "127.0.0.1:9002"
+cache_type = "redis"
+servers = ["127.0.0.1:7101:10"]
+"#,
+        )
+        .unwrap();
+
+        let config = Config::load(&path).unwrap();
+        let fw = FileWatcher::new(path.clone(), config, false);
+        fw.update("test-persist", |cc| {
+            cc.servers.push("127.0.0.1:7002:10".to_string());
+            Ok(())
+        })
+        .unwrap();
+        fw.persist("test-persist").unwrap();
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(
+            config.cluster("test-persist").unwrap().servers,
+            vec![
+                "127.0.0.1:7001:10".to_string(),
+                "127.0.0.1:7002:10".to_string()
+            ]
+        );
+        assert_eq!(
+            config.cluster("other").unwrap().servers,
+            vec!["127.0.0.1:7101:10".to_string()]
+        );
+        assert!(fw.persist("absent").is_err());
+        fs::remove_file(&path).unwrap();
+
+        let fw = FileWatcher::new(String::new(), Config::default(), false);
+        assert!(fw.persist("test-persist").is_err());
+    }
+}
@@ -0,0 +1,349 @@
+//! opt-in cache of the replies of hot reads, each rule is "${command} ${pattern}" in config, e.g.:
+//! "GET user:*". The reply of a matched read is cached by (command, key) for a short ttl and
+//! served without touching backend, and the cached replies of a key are dropped once any write
+//! of the key is seen by the proxy.
+//!
+//! The cache belongs to each worker thread, so writes through other workers or other clients of
+//! backends are only seen after the ttl. Only one read of an entry is sent to backend at a time,
+//! and its reply is cached only if no write of the key is seen until it's replied.
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::com::{AsError, ClusterConfig};
+use crate::proxy::standalone::pin::glob_match;
+
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    // upper case command and pattern of key
+    rules: Vec<(String, Vec<u8>)>,
+}
+
+impl Rules {
+    pub fn new(rules: &[String]) -> Result<Rules, AsError> {
+        let mut parsed = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let fields: Vec<_> = rule.split_whitespace().collect();
+            if fields.len() != 2 {
+                return Err(AsError::BadConfig(format!(
+                    "response_cache: {} must be \"${{command}} ${{pattern}}\"",
+                    rule
+                )));
+            }
+            parsed.push((fields[0].to_uppercase(), fields[1].as_bytes().to_vec()));
+        }
+        Ok(Rules { rules: parsed })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn matches(&self, name: &str, key: &[u8]) -> bool {
+        self.rules
+            .iter()
+            .any(|x| x.0 == name && glob_match(&x.1, key))
+    }
+
+    // commands which may be cached, each only once.
+    fn commands(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.rules.iter().map(|x| x.0.as_str()).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// the read sent to backend, whose reply is cached by store.
+#[derive(Debug, PartialEq)]
+pub struct Ticket {
+    name: String,
+    key: Vec<u8>,
+    token: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Lookup<R> {
+    Hit(R),
+    // cached after replied
+    Miss(Ticket),
+    // not cached, e.g.: no rule matched or the same read is in flight
+    Pass,
+}
+
+enum Slot<R> {
+    Pending(u64, Instant),
+    Ready(R, Instant),
+}
+
+struct Entry<R> {
+    slot: Slot<R>,
+    size: usize,
+    tick: u64,
+}
+
+pub struct RespCache<R> {
+    rules: Rules,
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<(String, Vec<u8>), Entry<R>>,
+    // the entries ordered by the last access, the least recent first
+    lru: BTreeMap<u64, (String, Vec<u8>)>,
+    used: usize,
+    tick: u64,
+}
+
+impl<R> Default for RespCache<R> {
+    fn default() -> Self {
+        RespCache::new(Rules::default(), Duration::from_millis(0), 0)
+    }
+}
+
+impl<R> RespCache<R> {
+    pub fn new(rules: Rules, ttl: Duration, capacity: usize) -> Self {
+        RespCache {
+            rules,
+            ttl,
+            capacity,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            used: 0,
+            tick: 0,
+        }
+    }
+
+    pub fn from_config(cc: &ClusterConfig) -> Result<Self, AsError> {
+        let rules = Rules::new(&cc.response_cache)?;
+        let ttl = Duration::from_millis(cc.response_cache_ttl());
+        Ok(RespCache::new(rules, ttl, cc.response_cache_size()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// bytes of keys and replies cached.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, entry_key: &(String, Vec<u8>)) {
+        if let Some(entry) = self.entries.remove(entry_key) {
+            self.lru.remove(&entry.tick);
+            self.used -= entry.size;
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.capacity {
+            let oldest = match self.lru.iter().next() {
+                Some((_, entry_key)) => entry_key.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+impl<R: Clone> RespCache<R> {
+    /// the cached reply of the read, or a ticket to cache its reply once replied by backend.
+    pub fn lookup(&mut self, name: &str, key: &[u8], now: Instant) -> Lookup<R> {
+        if !self.rules.matches(name, key) {
+            return Lookup::Pass;
+        }
+        let entry_key = (name.to_string(), key.to_vec());
+        let tick = self.next_tick();
+        let ttl = self.ttl;
+        if let Some(entry) = self.entries.get_mut(&entry_key) {
+            match &entry.slot {
+                Slot::Ready(reply, expire) if *expire > now => {
+                    let reply = reply.clone();
+                    let entry_key = self.lru.remove(&entry.tick).expect("entry must be in lru");
+                    entry.tick = tick;
+                    self.lru.insert(tick, entry_key);
+                    return Lookup::Hit(reply);
+                }
+                // the former read may never be replied (e.g.: the client is closed)
+                Slot::Pending(_, since) if now.duration_since(*since) < ttl => {
+                    return Lookup::Pass;
+                }
+                _ => {}
+            }
+        }
+        self.remove(&entry_key);
+
+        let size = key.len();
+        if size > self.capacity {
+            return Lookup::Pass;
+        }
+        let entry = Entry {
+            slot: Slot::Pending(tick, now),
+            size,
+            tick,
+        };
+        self.entries.insert(entry_key.clone(), entry);
+        self.lru.insert(tick, entry_key);
+        self.used += size;
+        self.evict();
+        Lookup::Miss(Ticket {
+            name: name.to_string(),
+            key: key.to_vec(),
+            token: tick,
+        })
+    }
+
+    /// cache the reply of size bytes for the ticket, unless any write of the key was seen
+    /// since the ticket was issued.
+    pub fn store(&mut self, ticket: Ticket, reply: R, size: usize, now: Instant) {
+        let entry_key = (ticket.name, ticket.key);
+        match self.entries.get(&entry_key) {
+            Some(Entry {
+                slot: Slot::Pending(token, _),
+                ..
+            }) if *token == ticket.token => {}
+            _ => return,
+        }
+        self.remove(&entry_key);
+        let size = entry_key.1.len() + size;
+        if size > self.capacity {
+            return;
+        }
+        let tick = self.next_tick();
+        let entry = Entry {
+            slot: Slot::Ready(reply, now + self.ttl),
+            size,
+            tick,
+        };
+        self.entries.insert(entry_key.clone(), entry);
+        self.lru.insert(tick, entry_key);
+        self.used += size;
+        self.evict();
+    }
+
+    /// drop the cached replies and the reads in flight of the key written.
+    pub fn invalidate(&mut self, key: &[u8]) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let names: Vec<_> = self
+            .rules
+            .commands()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect();
+        for name in names {
+            self.remove(&(name, key.to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cache(capacity: usize) -> RespCache<String> {
+        let rules = Rules::new(&["get user:*".to_string(), "STRLEN user:*".to_string()]).unwrap();
+        RespCache::new(rules, Duration::from_millis(100), capacity)
+    }
+
+    fn miss(cache: &mut RespCache<String>, name: &str, key: &[u8], now: Instant) -> Ticket {
+        match cache.lookup(name, key, now) {
+            Lookup::Miss(ticket) => ticket,
+            other => panic!("unexpected lookup {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_bad_rules() {
+        assert!(Rules::new(&["GET".to_string()]).is_err());
+        assert!(Rules::new(&["GET a b".to_string()]).is_err());
+        assert!(Rules::new(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cache_hit_and_miss() {
+        let now = Instant::now();
+        let mut cache = cache(1024);
+        assert_eq!(cache.lookup("GET", b"order:1", now), Lookup::Pass);
+        assert_eq!(cache.lookup("SET", b"user:1", now), Lookup::Pass);
+
+        let ticket = miss(&mut cache, "GET", b"user:1", now);
+        // the same read in flight is never cached twice
+        assert_eq!(cache.lookup("GET", b"user:1", now), Lookup::Pass);
+        cache.store(ticket, "alice".to_string(), 5, now);
+        assert_eq!(cache.used(), 11);
+        assert_eq!(
+            cache.lookup("GET", b"user:1", now),
+            Lookup::Hit("alice".to_string())
+        );
+        // cached by command and key
+        miss(&mut cache, "STRLEN", b"user:1", now);
+
+        // expired after ttl
+        let ttl = Duration::from_millis(100);
+        let later = now + ttl;
+        miss(&mut cache, "GET", b"user:1", later);
+        // the read never replied is taken over after ttl
+        let ticket = miss(&mut cache, "GET", b"user:1", later + ttl);
+        cache.store(ticket, "bob".to_string(), 3, later);
+        assert_eq!(
+            cache.lookup("GET", b"user:1", later),
+            Lookup::Hit("bob".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cache_write_invalidation() {
+        let now = Instant::now();
+        let mut cache = cache(1024);
+        let ticket = miss(&mut cache, "GET", b"user:1", now);
+        cache.store(ticket, "alice".to_string(), 5, now);
+        let strlen = miss(&mut cache, "STRLEN", b"user:1", now);
+
+        cache.invalidate(b"user:1");
+        assert_eq!(cache.used(), 0);
+        // the read in flight across the write is never cached
+        cache.store(strlen, "5".to_string(), 1, now);
+        let ticket = miss(&mut cache, "GET", b"user:1", now);
+        cache.invalidate(b"user:2");
+        cache.store(ticket, "carol".to_string(), 5, now);
+        assert_eq!(
+            cache.lookup("GET", b"user:1", now),
+            Lookup::Hit("carol".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cache_lru_eviction() {
+        let now = Instant::now();
+        // two entries of 6 bytes key and 4 bytes reply
+        let mut cache = cache(20);
+        for key in &[&b"user:1"[..], &b"user:2"[..]] {
+            let ticket = miss(&mut cache, "GET", key, now);
+            cache.store(ticket, "xxxx".to_string(), 4, now);
+        }
+        // user:1 becomes the most recent
+        assert!(matches!(
+            cache.lookup("GET", b"user:1", now),
+            Lookup::Hit(_)
+        ));
+        let ticket = miss(&mut cache, "GET", b"user:3", now);
+        cache.store(ticket, "xxxx".to_string(), 4, now);
+        assert!(cache.used() <= 20);
+        assert!(matches!(
+            cache.lookup("GET", b"user:1", now),
+            Lookup::Hit(_)
+        ));
+        miss(&mut cache, "GET", b"user:2", now);
+
+        // the reply larger than the whole cache is never cached
+        let ticket = miss(&mut cache, "GET", b"user:4", now);
+        cache.store(ticket, "x".repeat(64), 64, now);
+        let later = now + Duration::from_millis(100);
+        miss(&mut cache, "GET", b"user:4", later);
+    }
+}
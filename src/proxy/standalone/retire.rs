@@ -0,0 +1,109 @@
+//! drain of the backends removed by reload: the connection of the backend removed (or moved to
+//! another address) is never routed any new command, but it's kept until the commands in
+//! flight on it are replied, so reloading never fails them. The commands in flight and how
+//! long it's been draining are reported every second by log and metrics, and the drain is
+//! bounded by reload_drain_timeout, after which the connection is closed and the rest of
+//! commands are failed with BackendClosed. So are the connections recycled by
+//! backend_max_lifetime (see lifetime), which are checked by the same interval.
+use futures::{Async, Future, Stream};
+use tokio::timer::Interval;
+
+use std::rc::Weak;
+use std::time::{Duration, Instant};
+
+use crate::proxy::standalone::{Cluster, Request};
+
+const CHECK_INTERVAL: u64 = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Progress {
+    Draining,
+    Drained,
+    // beyond reload_drain_timeout with commands in flight
+    Expired,
+}
+
+impl Progress {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Progress::Draining => "draining",
+            Progress::Drained => "drained",
+            Progress::Expired => "expired",
+        }
+    }
+}
+
+/// the progress of drain by the commands in flight and how long it's been draining.
+pub fn progress(inflight: usize, elapsed: Duration, timeout: Duration) -> Progress {
+    if inflight == 0 {
+        Progress::Drained
+    } else if elapsed >= timeout {
+        Progress::Expired
+    } else {
+        Progress::Draining
+    }
+}
+
+pub struct Retirer<T> {
+    cluster: Weak<Cluster<T>>,
+    interval: Interval,
+}
+
+impl<T: Request + 'static> Retirer<T> {
+    pub fn new(cluster: Weak<Cluster<T>>) -> Self {
+        Retirer {
+            cluster,
+            interval: Interval::new(
+                Instant::now() + Duration::from_millis(CHECK_INTERVAL),
+                Duration::from_millis(CHECK_INTERVAL),
+            ),
+        }
+    }
+}
+
+impl<T: Request + 'static> Future for Retirer<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    error!("fail to poll reload drain interval due {:?}", err);
+                    return Err(());
+                }
+            }
+
+            let cluster = match self.cluster.upgrade() {
+                Some(cluster) => cluster,
+                None => return Ok(Async::Ready(())),
+            };
+            let now = Instant::now();
+            cluster.recycle_expired(now);
+            cluster.check_retired(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let timeout = Duration::from_secs(30);
+        let second = Duration::from_secs(1);
+        assert_eq!(progress(3, second, timeout), Progress::Draining);
+        assert_eq!(progress(0, second, timeout), Progress::Drained);
+        // drained is never expired
+        assert_eq!(progress(0, timeout, timeout), Progress::Drained);
+        assert_eq!(progress(3, timeout, timeout), Progress::Expired);
+        assert_eq!(
+            progress(1, second, Duration::from_secs(0)),
+            Progress::Expired
+        );
+    }
+}
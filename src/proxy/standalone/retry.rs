@@ -0,0 +1,63 @@
+//! transparent retry of the commands in flight on a stale backend connection, e.g.: the
+//! connection reused after idle is reset by the idle timeout of backend. They are dispatched
+//! again on a new connection, and each command is retried at most once.
+use futures::unsync::mpsc::UnboundedReceiver;
+use futures::{Async, Future, Stream};
+
+use std::collections::VecDeque;
+use std::rc::Weak;
+
+use crate::proxy::standalone::{Cluster, Request};
+
+pub struct Retry<T> {
+    cluster: Weak<Cluster<T>>,
+    input: UnboundedReceiver<T>,
+    cmds: VecDeque<T>,
+}
+
+impl<T: Request + 'static> Retry<T> {
+    pub fn new(cluster: Weak<Cluster<T>>, input: UnboundedReceiver<T>) -> Self {
+        Retry {
+            cluster,
+            input,
+            cmds: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Request + 'static> Future for Retry<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            loop {
+                match self.input.poll() {
+                    Ok(Async::Ready(Some(cmd))) => self.cmds.push_back(cmd),
+                    Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                    Ok(Async::NotReady) => break,
+                    Err(_) => unreachable!(),
+                }
+            }
+            if self.cmds.is_empty() {
+                return Ok(Async::NotReady);
+            }
+
+            let cluster = match self.cluster.upgrade() {
+                Some(cluster) => cluster,
+                None => return Ok(Async::Ready(())),
+            };
+            match cluster.redispatch(&mut self.cmds) {
+                // notified by the sender once it's ready
+                Ok(()) if !self.cmds.is_empty() => return Ok(Async::NotReady),
+                Ok(()) => {}
+                Err(err) => {
+                    error!("fail to retry commands due {}", err);
+                    for cmd in self.cmds.drain(0..) {
+                        cmd.set_error(&err);
+                    }
+                }
+            }
+        }
+    }
+}
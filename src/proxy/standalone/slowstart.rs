@@ -0,0 +1,160 @@
+//! slow-start of newly added or recovered backends, only a ramping fraction of their keys
+//! are routed to them while warming up and the rest go to the successor in ring.
+use futures::{Async, Future, Stream};
+use tokio::timer::Interval;
+
+use std::collections::HashMap;
+use std::rc::Weak;
+use std::time::{Duration, Instant};
+
+use crate::metrics::slow_start_ramp_set;
+use crate::proxy::standalone::{Cluster, Request};
+
+const CHECK_INTERVAL: u64 = 1_000;
+const RAMP_SCALE: u64 = 1_000;
+
+#[derive(Default)]
+pub struct SlowStart {
+    since: HashMap<String, Instant>,
+    // per mille of keys routed to the warming node, only changed by check, which
+    // keeps the routing of the same key stable within one ramp step
+    ramps: HashMap<String, u64>,
+}
+
+impl SlowStart {
+    pub fn start(&mut self, name: &str, now: Instant) {
+        self.since.insert(name.to_string(), now);
+        self.ramps.insert(name.to_string(), 0);
+    }
+
+    pub fn stop(&mut self, name: &str) {
+        self.since.remove(name);
+        self.ramps.remove(name);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ramps.is_empty()
+    }
+
+    /// step the ramp of all the warming nodes and return their fraction,
+    /// node is finished with fraction 1.0 after the period.
+    pub fn check(&mut self, period: Duration, now: Instant) -> Vec<(String, f64)> {
+        let period = period.as_millis().max(1) as u64;
+        let mut ramps = Vec::with_capacity(self.since.len());
+        for (name, since) in self.since.iter() {
+            let elapsed = now.duration_since(*since).as_millis() as u64;
+            let ramp = (elapsed.saturating_mul(RAMP_SCALE) / period).min(RAMP_SCALE);
+            self.ramps.insert(name.clone(), ramp);
+            ramps.push((name.clone(), ramp as f64 / RAMP_SCALE as f64));
+        }
+        for (name, fraction) in ramps.iter() {
+            if *fraction >= 1.0 {
+                self.stop(name);
+            }
+        }
+        ramps
+    }
+
+    /// the key (or round for keyless command) is bucketed by hash, so the same key is
+    /// always accepted once the ramp passes its bucket.
+    pub fn accept(&self, name: &str, hash: u64) -> bool {
+        self.ramps
+            .get(name)
+            .map(|ramp| hash % RAMP_SCALE < *ramp)
+            .unwrap_or(true)
+    }
+}
+
+pub struct Ramp<T> {
+    cluster: Weak<Cluster<T>>,
+    interval: Interval,
+}
+
+impl<T: Request + 'static> Ramp<T> {
+    pub fn new(cluster: Weak<Cluster<T>>) -> Self {
+        Ramp {
+            cluster,
+            interval: Interval::new(
+                Instant::now() + Duration::from_millis(CHECK_INTERVAL),
+                Duration::from_millis(CHECK_INTERVAL),
+            ),
+        }
+    }
+}
+
+impl<T: Request + 'static> Future for Ramp<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    error!("fail to poll slow-start interval due {:?}", err);
+                    return Err(());
+                }
+            }
+
+            let cluster = match self.cluster.upgrade() {
+                Some(cluster) => cluster,
+                None => return Ok(Async::Ready(())),
+            };
+            if cluster.slow_start.borrow().is_empty() {
+                continue;
+            }
+            let name = cluster.cc.borrow().name.clone();
+            let period = Duration::from_millis(cluster.cc.borrow().slow_start.unwrap_or(0));
+            let ramps = cluster
+                .slow_start
+                .borrow_mut()
+                .check(period, Instant::now());
+            for (node, fraction) in ramps {
+                if fraction >= 1.0 {
+                    info!("node {} of cluster {} is warmed up", node, name);
+                }
+                slow_start_ramp_set(&name, &node, fraction);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn accepted(slow: &SlowStart) -> Vec<u64> {
+        (0..10_000u64)
+            .filter(|x| slow.accept("redis-1", *x))
+            .collect()
+    }
+
+    #[test]
+    fn test_slow_start_ramp() {
+        let period = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut slow = SlowStart::default();
+        slow.start("redis-1", now);
+        assert!(accepted(&slow).is_empty());
+        assert!(slow.accept("redis-2", 1));
+
+        let ramps = slow.check(period, now + Duration::from_secs(18));
+        assert_eq!(ramps, vec![("redis-1".to_string(), 0.3)]);
+        let first = accepted(&slow);
+        assert_eq!(first.len(), 3_000);
+        // stable until next step
+        assert_eq!(accepted(&slow), first);
+
+        slow.check(period, now + Duration::from_secs(36));
+        let second = accepted(&slow);
+        assert_eq!(second.len(), 6_000);
+        assert!(first.iter().all(|x| second.contains(x)));
+
+        let ramps = slow.check(period, now + Duration::from_secs(61));
+        assert_eq!(ramps, vec![("redis-1".to_string(), 1.0)]);
+        assert!(slow.is_empty());
+        assert_eq!(accepted(&slow).len(), 10_000);
+    }
+}
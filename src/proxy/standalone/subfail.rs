@@ -0,0 +1,92 @@
+//! the policies of the fan-out commands (e.g.: MGET, DEL, MSET and mc get) when any of their
+//! subs is failed by proxy, such as the backend unreachable or the time limit exceeded. Each of
+//! sub_failures is "${command} ${policy}" in config, e.g.: "MGET best_effort":
+//!
+//! - fail_fast: the whole command is failed by the error of the first failed sub.
+//! - best_effort: the failed subs are replied as absent keys, e.g.: nil of MGET and 0 of DEL,
+//!   while the one can't be partial (e.g.: MSET) is failed as fail_fast.
+//! - retry: the failed subs are dispatched once more, and failed as fail_fast at last.
+//!
+//! The errors replied by backend (e.g.: WRONGTYPE) are never the failures of proxy, which are
+//! merged as before. The commands not given keep the merging of their own.
+use std::collections::HashMap;
+
+use crate::com::AsError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubFailure {
+    FailFast,
+    BestEffort,
+    Retry,
+}
+
+impl SubFailure {
+    fn parse(name: &str) -> Option<SubFailure> {
+        match name {
+            "fail_fast" => Some(SubFailure::FailFast),
+            "best_effort" => Some(SubFailure::BestEffort),
+            "retry" => Some(SubFailure::Retry),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubFailures {
+    // upper case command and its policy
+    commands: HashMap<String, SubFailure>,
+}
+
+impl SubFailures {
+    pub fn new(lines: &[String]) -> Result<SubFailures, AsError> {
+        let mut commands = HashMap::new();
+        for line in lines {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let policy = match fields.as_slice() {
+                [_, policy] => SubFailure::parse(policy),
+                _ => None,
+            };
+            let policy = policy.ok_or_else(|| {
+                AsError::BadConfig(format!(
+                    "sub_failures: {} must be \"${{command}} fail_fast|best_effort|retry\"",
+                    line
+                ))
+            })?;
+            commands.insert(fields[0].to_uppercase(), policy);
+        }
+        Ok(SubFailures { commands })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.commands.is_empty()
+    }
+
+    /// the policy of the command, None if it's not given.
+    pub fn get(&self, name: &str) -> Option<SubFailure> {
+        self.commands.get(&name.to_uppercase()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sub_failures_parse() {
+        let lines: Vec<_> = ["mget best_effort", "DEL  retry", "MSET fail_fast"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let failures = SubFailures::new(&lines).unwrap();
+        assert!(failures.is_enabled());
+        assert_eq!(failures.get("MGET"), Some(SubFailure::BestEffort));
+        assert_eq!(failures.get("del"), Some(SubFailure::Retry));
+        assert_eq!(failures.get("MSET"), Some(SubFailure::FailFast));
+        assert_eq!(failures.get("EXISTS"), None);
+
+        for bad in &["MGET", "MGET partial", "MGET best_effort retry"] {
+            assert!(SubFailures::new(&[bad.to_string()]).is_err());
+        }
+        assert!(!SubFailures::new(&[]).unwrap().is_enabled());
+    }
+}
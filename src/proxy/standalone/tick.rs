@@ -0,0 +1,196 @@
+//! logical clock of each cluster shared by all the worker threads, which orders the events of
+//! requests in trace log to replay the exact interleaving of them (e.g.: a heisenbug between
+//! two clients). Each event takes the next tick of the clock and is logged as:
+//!
+//! ```text
+//! tick=1042 cluster=test-redis event=routed req=1037 node=127.0.0.1:7001
+//! ```
+//!
+//! req is the tick when the request was decoded, which identifies it (and its subs) across the
+//! events: decoded, routed, sent, received, merged and flushed. The same tick is written to the
+//! capture file and the access log. Ticks are only taken once trace log is enabled, and the
+//! stamping is compiled away by the static max level of log (e.g.: release_max_level_debug),
+//! then all the ticks are 0. The lines are sorted back by `aster-proxy trace-sort`.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::Level;
+
+lazy_static! {
+    static ref CLOCKS: Mutex<HashMap<String, Arc<AtomicU64>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Routed,
+    Sent,
+    Received,
+    Merged,
+    Flushed,
+}
+
+impl Event {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Event::Routed => "routed",
+            Event::Sent => "sent",
+            Event::Received => "received",
+            Event::Merged => "merged",
+            Event::Flushed => "flushed",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Clock {
+    cluster: String,
+    ticks: Arc<AtomicU64>,
+}
+
+/// get the clock of the cluster, which starts from 1.
+pub fn handle(cluster: &str) -> Clock {
+    let mut clocks = CLOCKS.lock().unwrap();
+    let ticks = clocks
+        .entry(cluster.to_string())
+        .or_insert_with(|| Arc::new(AtomicU64::new(0)))
+        .clone();
+    Clock {
+        cluster: cluster.to_string(),
+        ticks,
+    }
+}
+
+impl Clock {
+    /// the next tick, 0 if trace log is disabled.
+    pub fn tick(&self) -> u64 {
+        if !log_enabled!(Level::Trace) {
+            return 0;
+        }
+        self.ticks.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// stamp the request decoded from client, return its tick as the id of request.
+    pub fn decoded(&self, client: u64) -> u64 {
+        let tick = self.tick();
+        if tick > 0 {
+            trace!(
+                "tick={} cluster={} event=decoded req={} client={}",
+                tick,
+                self.cluster,
+                tick,
+                client
+            );
+        }
+        tick
+    }
+
+    /// stamp the event of the request given by its trace_id, node is empty if it's not
+    /// related, return the tick or 0 if the request is not traced.
+    pub fn stamp(&self, event: Event, req: u64, node: &str) -> u64 {
+        if req == 0 || !log_enabled!(Level::Trace) {
+            return 0;
+        }
+        let tick = self.tick();
+        if node.is_empty() {
+            trace!(
+                "tick={} cluster={} event={} req={}",
+                tick,
+                self.cluster,
+                event.as_str(),
+                req
+            );
+        } else {
+            trace!(
+                "tick={} cluster={} event={} req={} node={}",
+                tick,
+                self.cluster,
+                event.as_str(),
+                req,
+                node
+            );
+        }
+        tick
+    }
+}
+
+/// the stamped lines of cluster in the order of ticks, only the ones of reqs are kept unless
+/// it's empty. The prefix of log (e.g.: time and level) is dropped.
+pub fn sort<I, S>(lines: I, cluster: &str, reqs: &[u64]) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut stamped: Vec<(u64, String)> = lines
+        .into_iter()
+        .filter_map(|line| {
+            let line = line.as_ref();
+            let line = &line[line.find("tick=")?..];
+            let fields: HashMap<_, _> = line
+                .split_whitespace()
+                .filter_map(|x| {
+                    let mut kv = x.splitn(2, '=');
+                    Some((kv.next()?, kv.next()?))
+                })
+                .collect();
+            if fields.get("cluster") != Some(&cluster) {
+                return None;
+            }
+            let tick = fields.get("tick")?.parse::<u64>().ok()?;
+            let req = fields.get("req")?.parse::<u64>().ok()?;
+            if !reqs.is_empty() && !reqs.contains(&req) {
+                return None;
+            }
+            Some((tick, line.to_string()))
+        })
+        .collect();
+    // stable, the same tick may be given by the logs of restarted proxy
+    stamped.sort_by_key(|x| x.0);
+    stamped.into_iter().map(|x| x.1).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tick_increase() {
+        let _ = env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Trace)
+            .is_test(true)
+            .try_init();
+        let clock = handle("test-tick-increase");
+        let first = clock.tick();
+        assert!(first > 0);
+        assert_eq!(handle("test-tick-increase").tick(), first + 1);
+        assert_eq!(clock.decoded(1), first + 2);
+        assert_eq!(handle("test-tick-other").tick(), 1);
+    }
+
+    #[test]
+    fn test_tick_sort() {
+        let lines = vec![
+            "[2026-10-15T10:00:00Z TRACE aster] tick=5 cluster=a event=sent req=2 node=n1",
+            "tick=2 cluster=a event=decoded req=2 client=1",
+            "tick=3 cluster=a event=decoded req=3 client=2",
+            "tick=4 cluster=b event=decoded req=4 client=3",
+            "some other line",
+            "tick=6 cluster=a event=sent req=3 node=n1",
+            "tick=1 cluster=a event=decoded req=1 client=1",
+        ];
+        assert_eq!(
+            sort(lines.clone(), "a", &[3, 2]),
+            vec![
+                "tick=2 cluster=a event=decoded req=2 client=1",
+                "tick=3 cluster=a event=decoded req=3 client=2",
+                "tick=5 cluster=a event=sent req=2 node=n1",
+                "tick=6 cluster=a event=sent req=3 node=n1",
+            ]
+        );
+        assert_eq!(sort(lines.clone(), "a", &[]).len(), 5);
+        assert_eq!(
+            sort(lines, "b", &[]),
+            vec!["tick=4 cluster=b event=decoded req=4 client=3"]
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! tombstone replies of the reads to the ejected nodes, for the caches where a miss is
+//! acceptable. Each of tombstone_commands is "${command} [nil|empty|zero]" in config, nil by
+//! default.
+//!
+//! Once any is given, the ejected node keeps its hash range instead of being taken over by its
+//! successor: the given reads of its keys are replied by the tombstone as if the keys are
+//! absent, and all the others (writes included) are failed, until the node is recovered by its
+//! pings. Memcache always replies the miss (END) to the text get, gets, gat and gats.
+use std::collections::HashMap;
+
+use crate::com::AsError;
+
+/// the reply as if the key is absent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tombstone {
+    // null bulk, e.g.: GET
+    Nil,
+    // empty array, e.g.: HGETALL, SMEMBERS
+    Empty,
+    // integer 0, e.g.: EXISTS, SCARD
+    Zero,
+}
+
+impl Tombstone {
+    fn parse(name: &str) -> Option<Tombstone> {
+        match name.to_lowercase().as_str() {
+            "nil" => Some(Tombstone::Nil),
+            "empty" => Some(Tombstone::Empty),
+            "zero" => Some(Tombstone::Zero),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tombstones {
+    // lower case command and its tombstone
+    commands: HashMap<String, Tombstone>,
+}
+
+impl Tombstones {
+    pub fn new(lines: &[String]) -> Result<Tombstones, AsError> {
+        let mut commands = HashMap::new();
+        for line in lines {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let tombstone = match fields.as_slice() {
+                [_] => Some(Tombstone::Nil),
+                [_, reply] => Tombstone::parse(reply),
+                _ => None,
+            };
+            let tombstone = tombstone.ok_or_else(|| {
+                AsError::BadConfig(format!(
+                    "tombstone_commands: {} must be \"${{command}} [nil|empty|zero]\"",
+                    line
+                ))
+            })?;
+            commands.insert(fields[0].to_lowercase(), tombstone);
+        }
+        Ok(Tombstones { commands })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.commands.is_empty()
+    }
+
+    /// the tombstone of the read command, None if it's not given.
+    pub fn get(&self, name: &str) -> Option<Tombstone> {
+        self.commands.get(&name.to_lowercase()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tombstones_parse() {
+        let lines: Vec<_> = ["GET", "hgetall empty", "EXISTS Zero", "mget nil"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        let tombstones = Tombstones::new(&lines).unwrap();
+        assert!(tombstones.is_enabled());
+        assert_eq!(tombstones.get("get"), Some(Tombstone::Nil));
+        assert_eq!(tombstones.get("HGETALL"), Some(Tombstone::Empty));
+        assert_eq!(tombstones.get("exists"), Some(Tombstone::Zero));
+        assert_eq!(tombstones.get("MGET"), Some(Tombstone::Nil));
+        assert_eq!(tombstones.get("set"), None);
+
+        assert!(!Tombstones::new(&[]).unwrap().is_enabled());
+        for bad in &["GET none", "GET nil 1", ""] {
+            assert!(Tombstones::new(&[bad.to_string()]).is_err());
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! gradual transition of the weights changed by hot reload: the ring is rebuilt in steps from
+//! the former weights to the new ones over weight_transition millis, so the keys are moved in
+//! small parts instead of a miss spike of the whole delta at once.
+//!
+//! The steps are aligned to the wall clock and the weights of each step are integers
+//! interpolated from the former and new ones, so the workers and the proxies reloaded with the
+//! same config in the same step place the keys the same way at each step. The transition is
+//! superseded by the next reload changing weights, which starts from the weights in effect.
+use futures::{Async, Future, Stream};
+use tokio::timer::Interval;
+
+use std::collections::HashMap;
+use std::rc::Weak;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::proxy::standalone::{Cluster, Request};
+
+const CHECK_INTERVAL: u64 = 1_000;
+const STEP_INTERVAL: u64 = 5_000;
+
+lazy_static! {
+    // the name, effective and target weight of backends of each cluster, for admin api
+    static ref WEIGHTS: Mutex<HashMap<String, Vec<(String, usize, usize)>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// the name, effective and target weight of the backends of cluster in proxy mode.
+pub fn weights(cluster: &str) -> Option<Vec<(String, usize, usize)>> {
+    WEIGHTS.lock().unwrap().get(cluster).cloned()
+}
+
+pub(crate) fn publish(cluster: &str, effective: &[(String, usize)], target: &[(String, usize)]) {
+    let weights = effective
+        .iter()
+        .zip(target.iter())
+        .map(|(x, y)| (x.0.clone(), x.1, y.1))
+        .collect();
+    WEIGHTS.lock().unwrap().insert(cluster.to_string(), weights);
+}
+
+// the index of step since epoch.
+fn wall_step(now: SystemTime) -> u64 {
+    let since = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since.as_millis() as u64 / STEP_INTERVAL
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    from: HashMap<String, usize>,
+    // the new weights in the order of servers
+    to: Vec<(String, usize)>,
+    // the wall step the transition started at and the count of steps
+    start: u64,
+    steps: u64,
+}
+
+impl Transition {
+    /// None if no weight of the backends kept is changed, the backends added are ramped by
+    /// slow_start instead.
+    pub fn new(
+        from: &HashMap<String, usize>,
+        to: &[(String, usize)],
+        now: SystemTime,
+        duration: Duration,
+    ) -> Option<Transition> {
+        let changed = to
+            .iter()
+            .any(|(name, weight)| from.get(name).map(|x| x != weight).unwrap_or(false));
+        if !changed {
+            return None;
+        }
+        let millis = duration.as_millis() as u64;
+        Some(Transition {
+            from: from.clone(),
+            to: to.to_vec(),
+            start: wall_step(now),
+            steps: ((millis + STEP_INTERVAL - 1) / STEP_INTERVAL).max(1),
+        })
+    }
+
+    pub fn target(&self) -> &[(String, usize)] {
+        &self.to
+    }
+
+    /// the step in effect at the time, which is the count of steps once finished.
+    pub fn step(&self, now: SystemTime) -> u64 {
+        wall_step(now).saturating_sub(self.start).min(self.steps)
+    }
+
+    pub fn is_done(&self, step: u64) -> bool {
+        step >= self.steps
+    }
+
+    /// the weights of the step in the order of servers.
+    pub fn weights(&self, step: u64) -> Vec<(String, usize)> {
+        let step = step.min(self.steps);
+        self.to
+            .iter()
+            .map(|(name, to)| {
+                let weight = match self.from.get(name) {
+                    Some(from) => {
+                        let (from, to) = (*from as u64, *to as u64);
+                        (from * (self.steps - step) + to * step) / self.steps
+                    }
+                    None => *to as u64,
+                };
+                (name.clone(), weight as usize)
+            })
+            .collect()
+    }
+}
+
+pub struct Shift<T> {
+    cluster: Weak<Cluster<T>>,
+    interval: Interval,
+}
+
+impl<T: Request + 'static> Shift<T> {
+    pub fn new(cluster: Weak<Cluster<T>>) -> Self {
+        Shift {
+            cluster,
+            interval: Interval::new(
+                Instant::now() + Duration::from_millis(CHECK_INTERVAL),
+                Duration::from_millis(CHECK_INTERVAL),
+            ),
+        }
+    }
+}
+
+impl<T: Request + 'static> Future for Shift<T> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    error!("fail to poll weight transition interval due {:?}", err);
+                    return Err(());
+                }
+            }
+
+            let cluster = match self.cluster.upgrade() {
+                Some(cluster) => cluster,
+                None => return Ok(Async::Ready(())),
+            };
+            cluster.shift_weights(SystemTime::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn weights(pairs: &[(&str, usize)]) -> Vec<(String, usize)> {
+        pairs.iter().map(|(x, y)| (x.to_string(), *y)).collect()
+    }
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_transition_steps() {
+        let from: HashMap<_, _> = weights(&[("redis-1", 10), ("redis-2", 10)])
+            .into_iter()
+            .collect();
+        let to = weights(&[("redis-1", 10), ("redis-2", 30), ("redis-3", 10)]);
+        let duration = Duration::from_secs(20);
+        let transition = Transition::new(&from, &to, at(1_000_000), duration).unwrap();
+        assert_eq!(transition.target(), &to[..]);
+
+        assert_eq!(transition.step(at(1_000_000)), 0);
+        assert_eq!(
+            transition.weights(0),
+            weights(&[("redis-1", 10), ("redis-2", 10), ("redis-3", 10)])
+        );
+        assert_eq!(transition.step(at(1_004_999)), 0);
+        assert_eq!(transition.step(at(1_005_000)), 1);
+        assert_eq!(
+            transition.weights(1),
+            weights(&[("redis-1", 10), ("redis-2", 15), ("redis-3", 10)])
+        );
+        assert_eq!(
+            transition.weights(3),
+            weights(&[("redis-1", 10), ("redis-2", 25), ("redis-3", 10)])
+        );
+        let step = transition.step(at(1_020_000));
+        assert!(transition.is_done(step));
+        assert_eq!(transition.weights(step), to);
+        assert_eq!(transition.step(at(2_000_000)), step);
+
+        // nothing to transit if only backends are added or removed
+        let to = weights(&[("redis-1", 10), ("redis-3", 10)]);
+        assert_eq!(Transition::new(&from, &to, at(1_000_000), duration), None);
+    }
+
+    #[test]
+    fn test_transition_deterministic() {
+        let from: HashMap<_, _> = weights(&[("redis-1", 10), ("redis-2", 40)])
+            .into_iter()
+            .collect();
+        let to = weights(&[("redis-1", 40), ("redis-2", 10)]);
+        let duration = Duration::from_secs(60);
+        // reloaded in the same step by different proxies
+        let first = Transition::new(&from, &to, at(1_000_100), duration).unwrap();
+        let second = Transition::new(&from, &to, at(1_004_900), duration).unwrap();
+        assert_eq!(first, second);
+        for millis in (1_000_000..1_070_000).step_by(1_000) {
+            let (x, y) = (first.step(at(millis)), second.step(at(millis)));
+            assert_eq!(x, y);
+            assert_eq!(first.weights(x), second.weights(y));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! status of each cluster started from the config file. The cluster failed to start (e.g.: bad
+//! config or listen address in use) is reported without stopping the others unless in strict
+//! mode, and can be retried after the config is fixed by hot reload.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::com::{AsError, ClusterConfig};
+use crate::embed::{ClusterBuilder, ClusterHandle};
+use crate::proxy::standalone::reload;
+use crate::proxy::warmup::{self, WarmupOption};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Running,
+    Failed(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Running => write!(f, "running"),
+            Status::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+lazy_static! {
+    static ref STATUS: Mutex<BTreeMap<String, Status>> = Mutex::new(BTreeMap::new());
+    // the ip given by cli, used by the clusters started by retry
+    static ref IP: Mutex<Option<String>> = Mutex::new(None);
+}
+
+pub fn init(ip: Option<String>) {
+    *IP.lock().unwrap() = ip;
+}
+
+/// start the cluster and record its status, the cache is warmed up if warmup_keys is given.
+pub fn start(cc: ClusterConfig) -> Result<ClusterHandle, AsError> {
+    let name = cc.name.clone();
+    let ip = IP.lock().unwrap().clone();
+    let rslt = ClusterBuilder::from_config(cc.clone()).ip(ip).spawn();
+    let status = match &rslt {
+        Ok(_) => Status::Running,
+        Err(err) => Status::Failed(err.to_string()),
+    };
+    STATUS.lock().unwrap().insert(name.clone(), status);
+    // the cluster keeps running even if the warm-up is failed to start
+    if rslt.is_ok() && cc.warmup_keys.is_some() {
+        if let Err(err) = warmup::start(&cc, WarmupOption::default()) {
+            error!("fail to warm up cluster {} due {}", name, err);
+        }
+    }
+    rslt
+}
+
+/// start the failed cluster again by the current config, which may be fixed by hot reload.
+/// The workers keep running after the handle is dropped.
+pub fn retry(name: &str) -> Result<ClusterHandle, AsError> {
+    match STATUS.lock().unwrap().get(name) {
+        Some(Status::Failed(_)) => {}
+        Some(Status::Running) => {
+            return Err(AsError::BadConfig(format!("cluster {} is running", name)));
+        }
+        None => return Err(AsError::BadConfig(format!("cluster {} not found", name))),
+    }
+    let cc = reload::cluster(name)
+        .ok_or_else(|| AsError::BadConfig(format!("cluster {} not found in config", name)))?;
+    info!("retry to start cluster {}", name);
+    start(cc)
+}
+
+/// the status of all the clusters ordered by name.
+pub fn list() -> Vec<(String, Status)> {
+    STATUS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, status)| (name.clone(), status.clone()))
+        .collect()
+}
+
+/// names of the clusters failed to start.
+pub fn failed() -> Vec<String> {
+    STATUS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, status)| **status != Status::Running)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::com::CacheType;
+
+    fn config(name: &str) -> ClusterConfig {
+        ClusterConfig {
+            name: name.to_string(),
+            listen_addr: "127.0.0.1:0".to_string(),
+            cache_type: CacheType::Redis,
+            thread: Some(1),
+            servers: vec!["127.0.0.1:7001:10".to_string()],
+            ping_fail_limit: Some(0),
+            ..Default::default()
+        }
+    }
+
+    fn status(name: &str) -> Option<Status> {
+        list().into_iter().find(|x| x.0 == name).map(|x| x.1)
+    }
+
+    #[test]
+    fn test_start_isolated_and_retry() {
+        let good = start(config("test-startup-good")).unwrap();
+        let mut bad = config("test-startup-bad");
+        bad.hash_tag = Some("{".to_string());
+        assert!(start(bad.clone()).is_err());
+
+        assert_eq!(status("test-startup-good"), Some(Status::Running));
+        match status("test-startup-bad") {
+            Some(Status::Failed(reason)) => assert!(reason.contains("hash_tag"), reason),
+            other => panic!("unexpected status {:?}", other),
+        }
+        assert!(failed().contains(&"test-startup-bad".to_string()));
+        assert!(!failed().contains(&"test-startup-good".to_string()));
+
+        assert!(retry("test-startup-good").is_err());
+        assert!(retry("test-startup-absent").is_err());
+
+        // fixed by reload
+        bad.hash_tag = None;
+        reload::register(&bad).unwrap();
+        let retried = retry("test-startup-bad").unwrap();
+        assert_eq!(status("test-startup-bad"), Some(Status::Running));
+        assert!(!failed().contains(&"test-startup-bad".to_string()));
+
+        retried.shutdown();
+        good.shutdown();
+    }
+}
@@ -0,0 +1,216 @@
+//! the limits of value size advertised by backends, learned at startup by probe_value_limit so
+//! the oversized writes are rejected by proxy before any byte is sent to backends: the
+//! proto-max-bulk-len of redis by `CONFIG GET` and the item_size_max of memcache by
+//! `stats settings`. Each backend (the seeds for redis cluster) is asked once by a blocking
+//! connection, and the minimum of the limits is enforced with max_value_size of config.
+//!
+//! The backends denying the query (e.g.: CONFIG renamed by the managed services) are limited by
+//! max_value_size only, and the limits are never learned again by hot reload.
+use bytes::BytesMut;
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::com::{AsError, CacheType, ClusterConfig};
+use crate::protocol::redis::{Message, MessageMut, RespType};
+use crate::proxy::standalone::ring_nodes;
+
+const PROBE_TIMEOUT: u64 = 1000;
+const MAX_REPLY_SIZE: usize = 64 * 1024;
+const REDIS_CONFIG_GET: &[u8] = b"*3\r\n$6\r\nCONFIG\r\n$3\r\nGET\r\n$18\r\nproto-max-bulk-len\r\n";
+const MC_STATS_SETTINGS: &[u8] = b"stats settings\r\n";
+
+lazy_static! {
+    static ref LIMITS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+/// the limit enforced by the front codec of cluster, 0 means no limit.
+pub fn effective(cc: &ClusterConfig) -> usize {
+    let learned = LIMITS.lock().unwrap().get(&cc.name).cloned().unwrap_or(0);
+    min_limit(cc.max_value_size.unwrap_or(0), learned)
+}
+
+// the minimum of limits but 0 which means no limit
+fn min_limit(x: usize, y: usize) -> usize {
+    match (x, y) {
+        (0, y) => y,
+        (x, 0) => x,
+        (x, y) => x.min(y),
+    }
+}
+
+/// ask the backends of cluster for their limits if probe_value_limit, the minimum is kept as
+/// the learned limit of cluster, and the different limits among backends are warned.
+pub fn learn(cc: &ClusterConfig) {
+    if !cc.probe_value_limit.unwrap_or(false) {
+        return;
+    }
+    let addrs = match backend_addrs(cc) {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            warn!("cluster {} skip value limit probe due to {}", cc.name, err);
+            return;
+        }
+    };
+    let timeout = Duration::from_millis(PROBE_TIMEOUT);
+    let mut limits = Vec::new();
+    for addr in &addrs {
+        match fetch_limit(cc.cache_type, addr, timeout) {
+            Ok(limit) => limits.push((addr.clone(), limit)),
+            Err(err) => warn!(
+                "cluster {} fail to probe value limit of {} due to {}",
+                cc.name, addr, err
+            ),
+        }
+    }
+    let learned = limits.iter().map(|x| x.1).fold(0, min_limit);
+    if limits.iter().any(|x| x.1 != learned) {
+        let detail: Vec<_> = limits.iter().map(|x| format!("{}={}", x.0, x.1)).collect();
+        warn!(
+            "cluster {} backends differ in value limit ({}), the minimum {} is enforced",
+            cc.name,
+            detail.join(","),
+            learned
+        );
+    }
+    info!(
+        "cluster {} learned value limit {} from {} backends",
+        cc.name,
+        learned,
+        limits.len()
+    );
+    LIMITS.lock().unwrap().insert(cc.name.clone(), learned);
+}
+
+fn backend_addrs(cc: &ClusterConfig) -> Result<Vec<String>, AsError> {
+    if let CacheType::RedisCluster = cc.cache_type {
+        return Ok(cc.servers.clone());
+    }
+    Ok(ring_nodes(&cc.servers)?.into_iter().map(|x| x.1).collect())
+}
+
+fn fetch_limit(cache_type: CacheType, addr: &str, timeout: Duration) -> Result<usize, AsError> {
+    let sa = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| AsError::BadConfig(format!("{} resolved to nothing", addr)))?;
+    let mut sock = TcpStream::connect_timeout(&sa, timeout)?;
+    sock.set_read_timeout(Some(timeout))?;
+    sock.set_write_timeout(Some(timeout))?;
+    let request = match cache_type {
+        CacheType::Memcache | CacheType::MemcacheBinary => MC_STATS_SETTINGS,
+        _ => REDIS_CONFIG_GET,
+    };
+    sock.write_all(request)?;
+    let mut src = BytesMut::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let limit = match cache_type {
+            CacheType::Memcache | CacheType::MemcacheBinary => parse_item_size_max(&src)?,
+            _ => parse_proto_max_bulk_len(&mut src)?,
+        };
+        if let Some(limit) = limit {
+            return Ok(limit);
+        }
+        if src.len() > MAX_REPLY_SIZE {
+            return Err(AsError::ReplyTooLarge(MAX_REPLY_SIZE));
+        }
+        let size = sock.read(&mut buf)?;
+        if size == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        src.extend_from_slice(&buf[..size]);
+    }
+}
+
+/// item_size_max of the complete reply of `stats settings`, None if incomplete.
+pub fn parse_item_size_max(src: &[u8]) -> Result<Option<usize>, AsError> {
+    if !src.ends_with(b"END\r\n") {
+        if src.starts_with(b"ERROR") || src.starts_with(b"CLIENT_ERROR") {
+            return Err(AsError::BadReply);
+        }
+        return Ok(None);
+    }
+    src.split(|x| *x == b'\n')
+        .find_map(|line| {
+            let mut words = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|x| !x.is_empty());
+            match (words.next(), words.next(), words.next()) {
+                (Some(b"STAT"), Some(b"item_size_max"), Some(value)) => {
+                    btoi::btoi::<usize>(value).ok()
+                }
+                _ => None,
+            }
+        })
+        .map(Some)
+        .ok_or(AsError::BadReply)
+}
+
+/// proto-max-bulk-len of the reply of `CONFIG GET`, None if incomplete.
+pub fn parse_proto_max_bulk_len(src: &mut BytesMut) -> Result<Option<usize>, AsError> {
+    let msg = match MessageMut::parse_reply(src)? {
+        Some(msg) => Message::from(msg),
+        None => return Ok(None),
+    };
+    if let RespType::Error(_) = msg.rtype {
+        return Err(AsError::BadReply);
+    }
+    msg.iter()
+        .nth(1)
+        .and_then(|x| btoi::btoi::<usize>(x).ok())
+        .map(Some)
+        .ok_or(AsError::BadReply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_min_limit() {
+        assert_eq!(min_limit(0, 0), 0);
+        assert_eq!(min_limit(1024, 0), 1024);
+        assert_eq!(min_limit(0, 2048), 2048);
+        assert_eq!(min_limit(4096, 2048), 2048);
+    }
+
+    #[test]
+    fn test_parse_item_size_max() {
+        let reply = b"STAT maxbytes 67108864\r\nSTAT item_size_max 1048576\r\nEND\r\n";
+        assert_eq!(parse_item_size_max(&reply[..]).unwrap(), Some(1_048_576));
+        assert_eq!(parse_item_size_max(&reply[..30]).unwrap(), None);
+        assert!(parse_item_size_max(b"ERROR\r\n").is_err());
+        assert!(parse_item_size_max(b"STAT maxbytes 67108864\r\nEND\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_proto_max_bulk_len() {
+        let reply = &b"*2\r\n$18\r\nproto-max-bulk-len\r\n$9\r\n536870912\r\n"[..];
+        let mut src = BytesMut::from(&reply[..20]);
+        assert_eq!(parse_proto_max_bulk_len(&mut src).unwrap(), None);
+        let mut src = BytesMut::from(reply);
+        assert_eq!(
+            parse_proto_max_bulk_len(&mut src).unwrap(),
+            Some(536_870_912)
+        );
+        let mut src = BytesMut::from(&b"-ERR unknown command 'CONFIG'\r\n"[..]);
+        assert!(parse_proto_max_bulk_len(&mut src).is_err());
+    }
+
+    #[test]
+    fn test_effective() {
+        let mut cc = ClusterConfig {
+            name: "test-value-limit".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(effective(&cc), 0);
+        cc.max_value_size = Some(4096);
+        assert_eq!(effective(&cc), 4096);
+        LIMITS.lock().unwrap().insert(cc.name.clone(), 1024);
+        assert_eq!(effective(&cc), 1024);
+    }
+}
@@ -0,0 +1,580 @@
+//! cache warm-up of memcache cluster by replaying a manifest of keys, e.g.: after a cold deploy
+//! of the new fleet. The values are read by GET from the donor (e.g.: the proxy or a node of
+//! the old fleet) and stored by ADD through the proxy of the cluster, so the values written by
+//! clients in the meantime are never overwritten.
+//!
+//! Each warm-up runs in a dedicated thread with blocking connections and is paced by the rate of
+//! keys per second, so it never takes the worker threads but for the ADDs it sends. The manifest
+//! has one key per line and only the first field is taken, so the lines of "${key} ${count}"
+//! (e.g.: exported hot keys) are accepted as well. Empty lines and lines of '#' are skipped.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::com::{AsError, CacheType, ClusterConfig};
+
+const MAX_BATCH: usize = 100;
+const MAX_KEY_LEN: usize = 250;
+const IO_TIMEOUT: u64 = 5_000;
+const CHECK_INTERVAL: u64 = 100;
+
+/// options of warm-up given by admin api, which override the config of cluster.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct WarmupOption {
+    // the manifest of keys
+    pub keys: Option<String>,
+    pub donor: Option<String>,
+    // max keys per second
+    pub rate: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    Running,
+    Done,
+    Aborted,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Running => write!(f, "running"),
+            State::Done => write!(f, "done"),
+            State::Aborted => write!(f, "aborted"),
+        }
+    }
+}
+
+/// progress of the warm-up of cluster.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub state: State,
+    pub total: usize,
+    // stored into the cluster
+    pub warmed: usize,
+    // stored by clients ahead of warm-up
+    pub skipped: usize,
+    // not found on donor
+    pub missed: usize,
+    pub failed: usize,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "state: {}, keys: {}, warmed: {}, skipped: {}, missed: {}, failed: {}",
+            self.state, self.total, self.warmed, self.skipped, self.missed, self.failed
+        )
+    }
+}
+
+struct Job {
+    total: usize,
+    warmed: AtomicUsize,
+    skipped: AtomicUsize,
+    missed: AtomicUsize,
+    failed: AtomicUsize,
+    abort: AtomicBool,
+    state: Mutex<State>,
+}
+
+impl Job {
+    fn new(total: usize) -> Job {
+        Job {
+            total,
+            warmed: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            missed: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            abort: AtomicBool::new(false),
+            state: Mutex::new(State::Running),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            state: self.state.lock().unwrap().clone(),
+            total: self.total,
+            warmed: self.warmed.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            missed: self.missed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+lazy_static! {
+    static ref JOBS: Mutex<HashMap<String, Arc<Job>>> = Mutex::new(HashMap::new());
+}
+
+/// start the warm-up of the cluster in background, only one warm-up of each cluster runs at a
+/// time.
+pub fn start(cc: &ClusterConfig, opt: WarmupOption) -> Result<(), AsError> {
+    match cc.cache_type {
+        CacheType::Memcache => {}
+        _ => {
+            return Err(AsError::BadWarmup(
+                "only support cache_type memcache".to_string(),
+            ))
+        }
+    }
+    let path = opt
+        .keys
+        .or_else(|| cc.warmup_keys.clone())
+        .ok_or_else(|| AsError::BadWarmup("manifest of keys is not given".to_string()))?;
+    let donor = opt
+        .donor
+        .or_else(|| cc.warmup_donor.clone())
+        .ok_or_else(|| AsError::BadWarmup("donor is not given".to_string()))?;
+    let rate = opt.rate.unwrap_or_else(|| cc.warmup_rate());
+    if rate == 0 {
+        return Err(AsError::BadWarmup(
+            "rate must be greater than 0".to_string(),
+        ));
+    }
+    let target = local_addr(&cc.listen_addr)?;
+    let keys = load_keys(&path)?;
+    let ttl = cc.warmup_ttl();
+
+    let mut jobs = JOBS.lock().unwrap();
+    if let Some(job) = jobs.get(&cc.name) {
+        if *job.state.lock().unwrap() == State::Running {
+            return Err(AsError::BadWarmup("warm-up is running".to_string()));
+        }
+    }
+    let job = Arc::new(Job::new(keys.len()));
+    let name = cc.name.clone();
+    let worker = job.clone();
+    thread::Builder::new()
+        .name(format!("aster-warmup-{}", cc.name))
+        .spawn(move || {
+            info!(
+                "warm-up of cluster {} from {} with {} keys is started",
+                name,
+                donor,
+                keys.len()
+            );
+            let state = if run(&worker, &keys, &donor, &target, rate, ttl) {
+                State::Done
+            } else {
+                State::Aborted
+            };
+            *worker.state.lock().unwrap() = state;
+            info!(
+                "warm-up of cluster {} is finished with {}",
+                name,
+                worker.progress()
+            );
+        })?;
+    jobs.insert(cc.name.clone(), job);
+    Ok(())
+}
+
+/// abort the running warm-up of the cluster, return false if there's none.
+pub fn stop(cluster: &str) -> bool {
+    match JOBS.lock().unwrap().get(cluster) {
+        Some(job) if *job.state.lock().unwrap() == State::Running => {
+            job.abort.store(true, Ordering::SeqCst);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// progress of the last warm-up of the cluster.
+pub fn progress(cluster: &str) -> Option<Progress> {
+    JOBS.lock().unwrap().get(cluster).map(|x| x.progress())
+}
+
+// the address to reach the proxy of cluster itself.
+fn local_addr(listen_addr: &str) -> Result<String, AsError> {
+    let mut addr: SocketAddr = listen_addr
+        .parse()
+        .map_err(|_| AsError::BadWarmup(format!("bad listen_addr {}", listen_addr)))?;
+    if addr.ip().is_unspecified() {
+        if addr.is_ipv4() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        } else {
+            addr.set_ip(Ipv6Addr::LOCALHOST.into());
+        }
+    }
+    Ok(addr.to_string())
+}
+
+fn load_keys(path: &str) -> Result<Vec<String>, AsError> {
+    let file = File::open(path)
+        .map_err(|err| AsError::BadWarmup(format!("fail to open {} due to {}", path, err)))?;
+    let mut keys = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let key = match line.split_whitespace().next() {
+            Some(key) if !key.starts_with('#') => key,
+            _ => continue,
+        };
+        if key.len() > MAX_KEY_LEN {
+            warn!(
+                "warm-up key {} is skipped for longer than {}",
+                key, MAX_KEY_LEN
+            );
+            continue;
+        }
+        keys.push(key.to_string());
+    }
+    Ok(keys)
+}
+
+// warm up the keys batch by batch, return false if it's aborted.
+fn run(job: &Job, keys: &[String], donor: &str, target: &str, rate: u64, ttl: u32) -> bool {
+    let batch = (rate as usize).min(MAX_BATCH);
+    let mut donor_conn = None;
+    let mut target_conn = None;
+    let begin = Instant::now();
+    for (i, chunk) in keys.chunks(batch).enumerate() {
+        let at = begin + Duration::from_micros((i * batch) as u64 * 1_000_000 / rate);
+        if !pace(job, at) {
+            return false;
+        }
+
+        let values = match connect(&mut donor_conn, donor).and_then(|x| x.get(chunk)) {
+            Ok(values) => values,
+            Err(err) => {
+                warn!(
+                    "fail to get warm-up keys from donor {} due to {}",
+                    donor, err
+                );
+                donor_conn = None;
+                job.failed.fetch_add(chunk.len(), Ordering::Relaxed);
+                continue;
+            }
+        };
+        job.missed
+            .fetch_add(chunk.len() - values.len(), Ordering::Relaxed);
+        if values.is_empty() {
+            continue;
+        }
+        match connect(&mut target_conn, target).and_then(|x| x.add(&values, ttl)) {
+            Ok((stored, skipped)) => {
+                job.warmed.fetch_add(stored, Ordering::Relaxed);
+                job.skipped.fetch_add(skipped, Ordering::Relaxed);
+                job.failed
+                    .fetch_add(values.len() - stored - skipped, Ordering::Relaxed);
+            }
+            Err(err) => {
+                warn!("fail to add warm-up keys into {} due to {}", target, err);
+                target_conn = None;
+                job.failed.fetch_add(values.len(), Ordering::Relaxed);
+            }
+        }
+    }
+    true
+}
+
+// wait until the time of next batch, return false if it's aborted.
+fn pace(job: &Job, at: Instant) -> bool {
+    loop {
+        if job.abort.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= at {
+            return true;
+        }
+        thread::sleep((at - now).min(Duration::from_millis(CHECK_INTERVAL)));
+    }
+}
+
+fn connect<'a>(conn: &'a mut Option<Conn>, addr: &str) -> io::Result<&'a mut Conn> {
+    if conn.is_none() {
+        *conn = Some(Conn::connect(addr)?);
+    }
+    Ok(conn.as_mut().expect("conn must be connected"))
+}
+
+struct Value {
+    key: String,
+    flags: u32,
+    data: Vec<u8>,
+}
+
+// blocking connection of memcache text protocol
+struct Conn {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Conn {
+    fn connect(addr: &str) -> io::Result<Conn> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address not resolved"))?;
+        let timeout = Duration::from_millis(IO_TIMEOUT);
+        let writer = TcpStream::connect_timeout(&addr, timeout)?;
+        writer.set_read_timeout(Some(timeout))?;
+        writer.set_write_timeout(Some(timeout))?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Conn { reader, writer })
+    }
+
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            ));
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    fn get(&mut self, keys: &[String]) -> io::Result<Vec<Value>> {
+        let mut req = b"get".to_vec();
+        for key in keys {
+            req.push(b' ');
+            req.extend_from_slice(key.as_bytes());
+        }
+        req.extend_from_slice(b"\r\n");
+        self.writer.write_all(&req)?;
+
+        let mut values = Vec::new();
+        loop {
+            let line = self.line()?;
+            if line == "END" {
+                return Ok(values);
+            }
+            let bad = || io::Error::new(io::ErrorKind::InvalidData, line.clone());
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 4 || fields[0] != "VALUE" {
+                return Err(bad());
+            }
+            let flags = fields[2].parse().map_err(|_| bad())?;
+            let len: usize = fields[3].parse().map_err(|_| bad())?;
+            let mut data = vec![0u8; len + 2];
+            self.reader.read_exact(&mut data)?;
+            data.truncate(len);
+            values.push(Value {
+                key: fields[1].to_string(),
+                flags,
+                data,
+            });
+        }
+    }
+
+    // store the values absent in the cluster, return the count of stored and skipped.
+    fn add(&mut self, values: &[Value], ttl: u32) -> io::Result<(usize, usize)> {
+        let mut req = Vec::new();
+        for value in values {
+            write!(
+                req,
+                "add {} {} {} {}\r\n",
+                value.key,
+                value.flags,
+                ttl,
+                value.data.len()
+            )?;
+            req.extend_from_slice(&value.data);
+            req.extend_from_slice(b"\r\n");
+        }
+        self.writer.write_all(&req)?;
+
+        let (mut stored, mut skipped) = (0, 0);
+        for value in values {
+            match self.line()?.as_str() {
+                "STORED" => stored += 1,
+                "NOT_STORED" => skipped += 1,
+                reply => warn!("fail to add warm-up key {} due to {}", value.key, reply),
+            }
+        }
+        Ok((stored, skipped))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::net::TcpListener;
+
+    type Store = Arc<Mutex<HashMap<String, (u32, Vec<u8>)>>>;
+
+    // memcache of get and add only
+    fn mock(store: Store) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut writer = stream.unwrap();
+                let mut reader = BufReader::new(writer.try_clone().unwrap());
+                let store = store.clone();
+                thread::spawn(move || loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let fields: Vec<_> = line.split_whitespace().collect();
+                    let mut reply = Vec::new();
+                    if fields[0] == "get" {
+                        for key in &fields[1..] {
+                            if let Some((flags, data)) = store.lock().unwrap().get(*key) {
+                                write!(reply, "VALUE {} {} {}\r\n", key, flags, data.len())
+                                    .unwrap();
+                                reply.extend_from_slice(data);
+                                reply.extend_from_slice(b"\r\n");
+                            }
+                        }
+                        reply.extend_from_slice(b"END\r\n");
+                    } else {
+                        let len: usize = fields[4].parse().unwrap();
+                        let mut data = vec![0u8; len + 2];
+                        reader.read_exact(&mut data).unwrap();
+                        data.truncate(len);
+                        let mut store = store.lock().unwrap();
+                        if store.contains_key(fields[1]) {
+                            reply.extend_from_slice(b"NOT_STORED\r\n");
+                        } else {
+                            store.insert(fields[1].to_string(), (fields[2].parse().unwrap(), data));
+                            reply.extend_from_slice(b"STORED\r\n");
+                        }
+                    }
+                    writer.write_all(&reply).unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    fn manifest(name: &str, data: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn config(name: &str, target: &str, keys: &str, donor: &str) -> ClusterConfig {
+        ClusterConfig {
+            name: name.to_string(),
+            listen_addr: target.to_string(),
+            cache_type: CacheType::Memcache,
+            warmup_keys: Some(keys.to_string()),
+            warmup_donor: Some(donor.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn wait(cluster: &str) -> Progress {
+        for _ in 0..100 {
+            let progress = progress(cluster).unwrap();
+            if progress.state != State::Running {
+                return progress;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("warm-up of {} never finished", cluster);
+    }
+
+    #[test]
+    fn test_load_keys_of_manifest() {
+        let path = manifest(
+            "aster-test-warmup-keys.txt",
+            "# hot keys\nuser:1 1024\n\n  user:2\nuser:3\n",
+        );
+        assert_eq!(
+            load_keys(&path).unwrap(),
+            vec!["user:1", "user:2", "user:3"]
+        );
+        assert!(load_keys("/nonexistent/aster-warmup-keys").is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_local_addr() {
+        assert_eq!(local_addr("0.0.0.0:9001").unwrap(), "127.0.0.1:9001");
+        assert_eq!(local_addr("10.0.0.1:9001").unwrap(), "10.0.0.1:9001");
+        assert!(local_addr("localhost").is_err());
+    }
+
+    #[test]
+    fn test_warmup_from_donor() {
+        let donor_store = Store::default();
+        for i in 0..5 {
+            let value = (i, format!("value-{}", i).into_bytes());
+            donor_store
+                .lock()
+                .unwrap()
+                .insert(format!("user:{}", i), value);
+        }
+        let target_store = Store::default();
+        // written by clients ahead of warm-up
+        let fresh = (9, b"fresh".to_vec());
+        target_store
+            .lock()
+            .unwrap()
+            .insert("user:0".to_string(), fresh.clone());
+        let donor = mock(donor_store);
+        let target = mock(target_store.clone());
+
+        let keys: String = (0..7).map(|i| format!("user:{}\n", i)).collect();
+        let path = manifest("aster-test-warmup-donor.txt", &keys);
+        let cc = config("test-warmup-donor", &target, &path, &donor);
+        start(&cc, WarmupOption::default()).unwrap();
+        let progress = wait("test-warmup-donor");
+        assert_eq!(
+            progress,
+            Progress {
+                state: State::Done,
+                total: 7,
+                warmed: 4,
+                skipped: 1,
+                missed: 2,
+                failed: 0,
+            }
+        );
+        let target_store = target_store.lock().unwrap();
+        assert_eq!(target_store.get("user:0"), Some(&fresh));
+        assert_eq!(target_store.get("user:3"), Some(&(3, b"value-3".to_vec())));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_warmup_abort() {
+        let donor = mock(Store::default());
+        let target = mock(Store::default());
+        let path = manifest("aster-test-warmup-abort.txt", "a\nb\nc\n");
+        let cc = config("test-warmup-abort", &target, &path, &donor);
+        // one key per second
+        let opt = WarmupOption {
+            rate: Some(1),
+            ..Default::default()
+        };
+        start(&cc, opt.clone()).unwrap();
+        assert!(start(&cc, opt).is_err());
+        assert!(stop("test-warmup-abort"));
+
+        let progress = wait("test-warmup-abort");
+        assert_eq!(progress.state, State::Aborted);
+        assert!(progress.missed < 3);
+        assert!(!stop("test-warmup-abort"));
+        assert!(!stop("test-warmup-absent"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_warmup_donor_down() {
+        let target = mock(Store::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let donor = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let path = manifest("aster-test-warmup-down.txt", "a\nb\n");
+        let cc = config("test-warmup-down", &target, &path, &donor);
+        start(&cc, WarmupOption::default()).unwrap();
+        let progress = wait("test-warmup-down");
+        assert_eq!(progress.state, State::Done);
+        assert_eq!(progress.failed, 2);
+    }
+}
@@ -0,0 +1,186 @@
+//! lifecycle of the worker threads of one cluster, the spawner is told once each of them is
+//! listening, and closing drains them gracefully: the listener is stopped at once, and the
+//! front connections are closed after all their in-flight requests are replied.
+use futures::task::Task;
+use futures::{Async, Future, Stream};
+use tokio::timer::Interval;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CHECK_INTERVAL: u64 = 100;
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 5_000;
+
+/// shared by the spawner and all the worker threads of the cluster.
+pub struct Control {
+    closing: AtomicBool,
+    drain_timeout: Duration,
+    ready: Mutex<Option<Sender<()>>>,
+}
+
+impl Default for Control {
+    fn default() -> Control {
+        Control {
+            closing: AtomicBool::new(false),
+            drain_timeout: Duration::from_millis(DEFAULT_DRAIN_TIMEOUT),
+            ready: Mutex::new(None),
+        }
+    }
+}
+
+impl Control {
+    pub fn new(ready: Sender<()>, drain_timeout: Duration) -> Control {
+        Control {
+            closing: AtomicBool::new(false),
+            drain_timeout,
+            ready: Mutex::new(Some(ready)),
+        }
+    }
+
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+}
+
+/// the part of control held by the cluster of each worker thread.
+#[derive(Default)]
+pub struct Worker {
+    control: Arc<Control>,
+    // tasks of front connections, woken up to close themselves when closing
+    fronts: RefCell<HashMap<u64, Task>>,
+}
+
+impl Worker {
+    pub fn new(control: Arc<Control>) -> Worker {
+        Worker {
+            control,
+            fronts: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.control.is_closing()
+    }
+
+    pub fn listening(&self) {
+        if let Some(ready) = self.control.ready.lock().unwrap().as_ref() {
+            let _ = ready.send(());
+        }
+    }
+
+    pub fn register(&self, id: u64, task: Task) {
+        self.fronts.borrow_mut().insert(id, task);
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.fronts.borrow_mut().remove(&id);
+    }
+
+    /// resolved once closing, which stops the listener.
+    pub fn closed(self: &Rc<Self>) -> Closed {
+        Closed {
+            worker: self.clone(),
+            interval: new_interval(),
+        }
+    }
+
+    /// resolved after closing and all the front connections are closed or the drain timeout,
+    /// the worker thread exits then.
+    pub fn drain(self: &Rc<Self>) -> Drain {
+        Drain {
+            worker: self.clone(),
+            interval: new_interval(),
+            deadline: None,
+        }
+    }
+}
+
+fn new_interval() -> Interval {
+    Interval::new(
+        Instant::now() + Duration::from_millis(CHECK_INTERVAL),
+        Duration::from_millis(CHECK_INTERVAL),
+    )
+}
+
+fn poll_tick(interval: &mut Interval) -> Result<Async<()>, ()> {
+    match interval.poll() {
+        Ok(Async::Ready(_)) => Ok(Async::Ready(())),
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(err) => {
+            error!("fail to poll worker interval due {:?}", err);
+            Err(())
+        }
+    }
+}
+
+pub struct Closed {
+    worker: Rc<Worker>,
+    interval: Interval,
+}
+
+impl Future for Closed {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            if self.worker.is_closing() {
+                return Ok(Async::Ready(()));
+            }
+            if let Async::NotReady = poll_tick(&mut self.interval)? {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+pub struct Drain {
+    worker: Rc<Worker>,
+    interval: Interval,
+    deadline: Option<Instant>,
+}
+
+impl Future for Drain {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            if self.worker.is_closing() {
+                let now = Instant::now();
+                let deadline = match self.deadline {
+                    Some(deadline) => deadline,
+                    None => {
+                        // idle fronts are never polled unless woken up
+                        for task in self.worker.fronts.borrow().values() {
+                            task.notify();
+                        }
+                        *self
+                            .deadline
+                            .get_or_insert(now + self.worker.control.drain_timeout)
+                    }
+                };
+                let remains = self.worker.fronts.borrow().len();
+                if remains == 0 {
+                    return Ok(Async::Ready(()));
+                }
+                if now >= deadline {
+                    warn!("drain timeout and close {} front connections", remains);
+                    return Ok(Async::Ready(()));
+                }
+            }
+            if let Async::NotReady = poll_tick(&mut self.interval)? {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
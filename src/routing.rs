@@ -0,0 +1,235 @@
+//! which backend owns the key, answered the same as the proxy routes it, e.g.:
+//!
+//! ```no_run
+//! use libaster::routing::Routing;
+//!
+//! let routing = Routing::load("aster.toml", "foo").unwrap();
+//! if let Some(placement) = routing.locate(b"key1") {
+//!     println!("key1 {}", placement);
+//! }
+//! ```
+//!
+//! The ring is built from the servers in config, regardless of the nodes drained, failed over
+//! or warming up at runtime. Keys pinned by pin_keys are placed on their nodes.
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::com::{AsError, CacheType, ClusterConfig, Config};
+use crate::proxy::standalone::ketama::HashRing;
+use crate::proxy::standalone::pin::Pins;
+use crate::proxy::standalone::ring_nodes;
+use crate::utils::trim_hash_tag;
+
+pub use crate::protocol::redis::SLOTS_COUNT;
+pub use crate::proxy::standalone::hash::HashMethod;
+
+/// crc16 (XMODEM) of redis cluster.
+pub fn crc16(key: &[u8]) -> u16 {
+    crate::utils::crc::crc16(key) as u16
+}
+
+/// the slot of redis cluster, only the part in hash tag (e.g.: "{}") is hashed if present.
+pub fn slot_for_key(key: &[u8], hash_tag: &[u8]) -> usize {
+    crc16(trim_hash_tag(key, hash_tag)) as usize % SLOTS_COUNT
+}
+
+/// the ketama ring of proxy mode, keys are hashed by fnv1a_64 without hash tag by default.
+pub struct KetamaRing {
+    ring: HashRing,
+    hash: HashMethod,
+    hash_tag: Vec<u8>,
+}
+
+impl KetamaRing {
+    /// the ring of (node, weight), the node is named by alias or address as in servers.
+    pub fn new(servers: &[(String, usize)]) -> Result<KetamaRing, AsError> {
+        let (nodes, weights) = servers.iter().cloned().unzip();
+        Ok(KetamaRing {
+            ring: HashRing::new(nodes, weights)?,
+            hash: HashMethod::default(),
+            hash_tag: Vec::new(),
+        })
+    }
+
+    pub fn hash(mut self, hash: HashMethod) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    pub fn hash_tag(mut self, hash_tag: &[u8]) -> Self {
+        self.hash_tag = hash_tag.to_vec();
+        self
+    }
+
+    /// the node owning the key.
+    pub fn lookup(&self, key: &[u8]) -> Option<&str> {
+        let key = trim_hash_tag(key, &self.hash_tag);
+        self.ring.get_node(self.hash.hash(key))
+    }
+
+    /// the points of ring in ascending order of hash, each key is owned by the node of the
+    /// first point not less than its hash, or the first point if there's none.
+    pub fn points(&self) -> Vec<(u64, &str)> {
+        self.ring.points().collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// the slot of redis cluster, whose owner is known only by CLUSTER SLOTS of the cluster.
+    Slot(usize),
+    /// the node in the ring and its address.
+    Node { name: String, addr: String },
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Placement::Slot(slot) => write!(f, "slot {}", slot),
+            Placement::Node { name, addr } if name == addr => write!(f, "{}", addr),
+            Placement::Node { name, addr } => write!(f, "{} ({})", addr, name),
+        }
+    }
+}
+
+/// the placement of keys in the cluster of config.
+pub struct Routing {
+    // None for redis cluster
+    ring: Option<KetamaRing>,
+    pins: Pins,
+    addrs: HashMap<String, String>,
+    hash_tag: Vec<u8>,
+}
+
+impl Routing {
+    /// load the cluster of given name from the config file.
+    pub fn load<P: AsRef<Path>>(path: P, cluster: &str) -> Result<Routing, AsError> {
+        let cfg = Config::load(path)?;
+        let cc = cfg
+            .cluster(cluster)
+            .ok_or_else(|| AsError::BadConfig(format!("cluster {} not found", cluster)))?;
+        Routing::from_config(&cc)
+    }
+
+    pub fn from_config(cc: &ClusterConfig) -> Result<Routing, AsError> {
+        let hash_tag = cc.hash_tag();
+        if let CacheType::RedisCluster = cc.cache_type {
+            return Ok(Routing {
+                ring: None,
+                pins: Pins::default(),
+                addrs: HashMap::new(),
+                hash_tag,
+            });
+        }
+        let nodes = ring_nodes(&cc.servers)?;
+        let servers: Vec<_> = nodes.iter().map(|x| (x.0.clone(), x.2)).collect();
+        let ring = KetamaRing::new(&servers)?
+            .hash(cc.hash.unwrap_or_default())
+            .hash_tag(&hash_tag);
+        Ok(Routing {
+            ring: Some(ring),
+            pins: Pins::new(&cc.pin_keys, &cc.servers)?,
+            addrs: nodes.into_iter().map(|x| (x.0, x.1)).collect(),
+            hash_tag,
+        })
+    }
+
+    /// the ring of proxy mode, None for redis cluster.
+    pub fn ring(&self) -> Option<&KetamaRing> {
+        self.ring.as_ref()
+    }
+
+    /// the placement of key, None if the ring is empty.
+    pub fn locate(&self, key: &[u8]) -> Option<Placement> {
+        let ring = match self.ring.as_ref() {
+            Some(ring) => ring,
+            None => return Some(Placement::Slot(slot_for_key(key, &self.hash_tag))),
+        };
+        if let Some(addr) = self.pins.get(key) {
+            let name = self.addrs.iter().find(|x| x.1 == addr).map(|x| x.0.clone());
+            return Some(Placement::Node {
+                name: name.unwrap_or_else(|| addr.to_string()),
+                addr: addr.to_string(),
+            });
+        }
+        ring.lookup(key).map(|name| Placement::Node {
+            name: name.to_string(),
+            addr: self.addrs[name].clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_slot_for_key() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(slot_for_key(b"foo", b"{}"), 12182);
+        assert_eq!(
+            slot_for_key(b"{user1000}.following", b"{}"),
+            slot_for_key(b"user1000", b"{}")
+        );
+        assert_eq!(slot_for_key(b"{}foo", b"{}"), slot_for_key(b"{}foo", b""));
+    }
+
+    #[test]
+    fn test_ketama_ring_lookup() {
+        let servers: Vec<_> = (1..=3).map(|i| (format!("mc-{}", i), 10)).collect();
+        let ring = KetamaRing::new(&servers).unwrap().hash_tag(b"{}");
+        let points = ring.points();
+        assert_eq!(points.len(), 3 * 160);
+        assert!(points.windows(2).all(|x| x[0].0 <= x[1].0));
+
+        for i in 0..100 {
+            let key = format!("key-{}", i);
+            let hash = HashMethod::Fnv1a64.hash(key.as_bytes());
+            let owner = points.iter().find(|x| x.0 >= hash).unwrap_or(&points[0]).1;
+            assert_eq!(ring.lookup(key.as_bytes()), Some(owner));
+            // hashed by the tag only
+            let tagged = format!("{{{}}}.suffix", key);
+            assert_eq!(ring.lookup(tagged.as_bytes()), Some(owner));
+        }
+    }
+
+    #[test]
+    fn test_routing_from_config() {
+        let mut cc = ClusterConfig {
+            name: "test-routing".to_string(),
+            servers: vec![
+                "127.0.0.1:7001:10 redis-1".to_string(),
+                "127.0.0.1:7002:10 redis-2".to_string(),
+            ],
+            hash: Some(HashMethod::Crc32a),
+            ..Default::default()
+        };
+        let routing = Routing::from_config(&cc).unwrap();
+        let ring = routing.ring().unwrap();
+        match routing.locate(b"key1").unwrap() {
+            Placement::Node { name, addr } => {
+                assert_eq!(Some(name.as_str()), ring.lookup(b"key1"));
+                assert_eq!(addr, format!("127.0.0.1:700{}", &name[6..]));
+            }
+            placement => panic!("unexpected placement {}", placement),
+        }
+
+        cc.pin_keys = vec!["counter:* redis-2".to_string()];
+        let routing = Routing::from_config(&cc).unwrap();
+        let pinned = Placement::Node {
+            name: "redis-2".to_string(),
+            addr: "127.0.0.1:7002".to_string(),
+        };
+        assert_eq!(routing.locate(b"counter:1"), Some(pinned));
+
+        cc.servers.push("127.0.0.1:7003:10".to_string());
+        assert!(Routing::from_config(&cc).is_err());
+
+        cc.cache_type = CacheType::RedisCluster;
+        let routing = Routing::from_config(&cc).unwrap();
+        assert!(routing.ring().is_none());
+        assert_eq!(routing.locate(b"foo"), Some(Placement::Slot(12182)));
+        assert_eq!(routing.locate(b"foo").unwrap().to_string(), "slot 12182");
+    }
+}
@@ -0,0 +1,579 @@
+//! scriptable mock backends of redis and memcache for tests, built by feature testsupport (and
+//! always in the unit tests of the crate). Each mock listens on a random port of localhost,
+//! answers the requests by a script, e.g.: delay, error reply, closing the connection in the
+//! middle of a reply or a malformed frame, and records every request received for assertions.
+//! The requests matched by no rule are answered like a real server by an in-memory store of
+//! strings, so the replies merged by proxy (e.g.: MGET fanned out) can be checked by value:
+//!
+//! ```ignore
+//! let script = Script::new()
+//!     .times("PING", 2, vec![Action::Error("LOADING dataset in memory".to_string())])
+//!     .on_key("GET", "slow", vec![Action::Delay(500), Action::Respond]);
+//! let backend = MockBackend::redis(script);
+//! let handle = ClusterBuilder::new("test").servers(vec![backend.server("redis-1")]).spawn()?;
+//! ```
+//!
+//! The mock is closed once dropped, and so are the connections accepted by it.
+use bytes::BytesMut;
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::protocol::redis::MessageMut;
+
+// the accepting and reading are polled by it, to find the mock closed
+const POLL_INTERVAL: u64 = 5;
+
+const MC_STORAGE: &[&str] = &["set", "add", "replace", "append", "prepend", "cas"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Redis,
+    Memcache,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    // answer like a real server
+    Respond,
+    // the raw bytes as reply
+    Reply(Vec<u8>),
+    // the error reply, `-msg` of redis or `SERVER_ERROR msg` of memcache
+    Error(String),
+    // sleep millis before the next action, the requests pipelined behind are delayed as well
+    Delay(u64),
+    // write the first bytes of the normal reply only and close the connection
+    CloseAfter(usize),
+    // close the connection without reply
+    Close,
+    // the reply violating the framing of protocol
+    Malformed,
+}
+
+struct Rule {
+    // upper case, or * for any command
+    cmd: String,
+    key: Option<Vec<u8>>,
+    // the times left to be matched, None means always
+    times: Option<usize>,
+    actions: Vec<Action>,
+}
+
+impl Rule {
+    fn matches(&self, req: &[Vec<u8>]) -> bool {
+        if self.times == Some(0) {
+            return false;
+        }
+        let name = req
+            .get(0)
+            .map(|x| String::from_utf8_lossy(x).to_uppercase())
+            .unwrap_or_default();
+        let key_matched = match self.key.as_ref() {
+            Some(key) => req.get(1) == Some(key),
+            None => true,
+        };
+        (self.cmd == "*" || self.cmd == name) && key_matched
+    }
+}
+
+/// the actions taken in order for each request, by the first rule matched. The requests
+/// matched by no rule are answered by Respond.
+#[derive(Default)]
+pub struct Script {
+    rules: Vec<Rule>,
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script::default()
+    }
+
+    /// take the actions for every request of the command, `*` matches any command.
+    pub fn on(self, cmd: &str, actions: Vec<Action>) -> Script {
+        self.rule(cmd, None, None, actions)
+    }
+
+    /// take the actions for the requests of the command whose first key is given.
+    pub fn on_key(self, cmd: &str, key: &str, actions: Vec<Action>) -> Script {
+        self.rule(cmd, Some(key), None, actions)
+    }
+
+    /// take the actions for the first times requests of the command only.
+    pub fn times(self, cmd: &str, times: usize, actions: Vec<Action>) -> Script {
+        self.rule(cmd, None, Some(times), actions)
+    }
+
+    fn rule(
+        mut self,
+        cmd: &str,
+        key: Option<&str>,
+        times: Option<usize>,
+        actions: Vec<Action>,
+    ) -> Script {
+        self.rules.push(Rule {
+            cmd: cmd.to_uppercase(),
+            key: key.map(|x| x.as_bytes().to_vec()),
+            times,
+            actions,
+        });
+        self
+    }
+
+    fn actions(&mut self, req: &[Vec<u8>]) -> Vec<Action> {
+        match self.rules.iter_mut().find(|x| x.matches(req)) {
+            Some(rule) => {
+                if let Some(times) = rule.times.as_mut() {
+                    *times -= 1;
+                }
+                rule.actions.clone()
+            }
+            None => vec![Action::Respond],
+        }
+    }
+}
+
+struct Shared {
+    protocol: Protocol,
+    script: Mutex<Script>,
+    // value and flags (memcache only) of keys
+    store: Mutex<HashMap<Vec<u8>, (Vec<u8>, u32)>>,
+    requests: Mutex<Vec<Vec<Vec<u8>>>>,
+    connections: AtomicUsize,
+    closed: AtomicBool,
+}
+
+pub struct MockBackend {
+    addr: String,
+    shared: Arc<Shared>,
+}
+
+impl MockBackend {
+    pub fn redis(script: Script) -> MockBackend {
+        MockBackend::spawn(Protocol::Redis, script)
+    }
+
+    pub fn memcache(script: Script) -> MockBackend {
+        MockBackend::spawn(Protocol::Memcache, script)
+    }
+
+    pub fn spawn(protocol: Protocol, script: Script) -> MockBackend {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("fail to bind mock backend");
+        listener
+            .set_nonblocking(true)
+            .expect("fail to set mock backend nonblocking");
+        let addr = listener.local_addr().unwrap().to_string();
+        let shared = Arc::new(Shared {
+            protocol,
+            script: Mutex::new(script),
+            store: Mutex::new(HashMap::new()),
+            requests: Mutex::new(Vec::new()),
+            connections: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+        });
+        let accepting = shared.clone();
+        thread::spawn(move || accept(listener, accepting));
+        MockBackend { addr, shared }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// the line of servers, e.g.: `127.0.0.1:7001:10 redis-1`.
+    pub fn server(&self, alias: &str) -> String {
+        format!("{}:10 {}", self.addr, alias)
+    }
+
+    /// every request received in order, by its arguments. The data block of memcache storage
+    /// command is the last argument.
+    pub fn requests(&self) -> Vec<Vec<String>> {
+        self.shared
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|req| {
+                req.iter()
+                    .map(|x| String::from_utf8_lossy(x).to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// the requests of the command only, case insensitive.
+    pub fn requests_of(&self, cmd: &str) -> Vec<Vec<String>> {
+        self.requests()
+            .into_iter()
+            .filter(|req| req[0].eq_ignore_ascii_case(cmd))
+            .collect()
+    }
+
+    /// the count of connections accepted.
+    pub fn connections(&self) -> usize {
+        self.shared.connections.load(Ordering::SeqCst)
+    }
+
+    /// set the value of key, which is answered to the reads by Respond.
+    pub fn set(&self, key: &str, value: &str) {
+        self.shared
+            .store
+            .lock()
+            .unwrap()
+            .insert(key.as_bytes().to_vec(), (value.as_bytes().to_vec(), 0));
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.shared
+            .store
+            .lock()
+            .unwrap()
+            .get(key.as_bytes())
+            .map(|x| String::from_utf8_lossy(&x.0).to_string())
+    }
+}
+
+impl Drop for MockBackend {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+fn accept(listener: TcpListener, shared: Arc<Shared>) {
+    while !shared.closed.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((sock, _)) => {
+                shared.connections.fetch_add(1, Ordering::SeqCst);
+                let shared = shared.clone();
+                thread::spawn(move || serve(sock, shared));
+            }
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL));
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+fn serve(mut sock: TcpStream, shared: Arc<Shared>) {
+    let timeout = Some(Duration::from_millis(POLL_INTERVAL));
+    if sock.set_nonblocking(false).is_err() || sock.set_read_timeout(timeout).is_err() {
+        return;
+    }
+    let mut buf = BytesMut::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        loop {
+            let req = match parse(shared.protocol, &mut buf) {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(()) => {
+                    let _ = sock.shutdown(Shutdown::Both);
+                    return;
+                }
+            };
+            shared.requests.lock().unwrap().push(req.clone());
+            let actions = shared.script.lock().unwrap().actions(&req);
+            if !answer(&mut sock, &shared, &req, actions) {
+                let _ = sock.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+        if shared.closed.load(Ordering::SeqCst) {
+            let _ = sock.shutdown(Shutdown::Both);
+            return;
+        }
+        match sock.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(size) => buf.extend_from_slice(&chunk[..size]),
+            Err(ref err)
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {}
+            Err(_) => return,
+        }
+    }
+}
+
+// take the actions of request, return false if the connection is closed by them.
+fn answer(sock: &mut TcpStream, shared: &Shared, req: &[Vec<u8>], actions: Vec<Action>) -> bool {
+    for action in actions {
+        let reply = match action {
+            Action::Respond => respond(shared, req),
+            Action::Reply(reply) => reply,
+            Action::Error(msg) => match shared.protocol {
+                Protocol::Redis => format!("-{}\r\n", msg).into_bytes(),
+                Protocol::Memcache => format!("SERVER_ERROR {}\r\n", msg).into_bytes(),
+            },
+            Action::Delay(millis) => {
+                thread::sleep(Duration::from_millis(millis));
+                continue;
+            }
+            Action::CloseAfter(size) => {
+                let reply = respond(shared, req);
+                let _ = sock.write_all(&reply[..size.min(reply.len())]);
+                return false;
+            }
+            Action::Close => return false,
+            Action::Malformed => match shared.protocol {
+                Protocol::Redis => b"$x\r\n".to_vec(),
+                Protocol::Memcache => b"VALUE key x 1\r\n".to_vec(),
+            },
+        };
+        if sock.write_all(&reply).is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+// the arguments of the request at the front of buf, Err if it's malformed.
+fn parse(protocol: Protocol, buf: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>, ()> {
+    match protocol {
+        Protocol::Redis => {
+            let msg = match MessageMut::parse_request(buf) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => return Ok(None),
+                Err(_) => return Err(()),
+            };
+            let args = (0..msg.args_count())
+                .filter_map(|x| msg.nth(x).map(|x| x.to_vec()))
+                .collect();
+            Ok(Some(args))
+        }
+        Protocol::Memcache => parse_mc(buf),
+    }
+}
+
+fn parse_mc(buf: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>, ()> {
+    let pos = match buf.windows(2).position(|x| x == b"\r\n") {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let mut args: Vec<Vec<u8>> = buf[..pos]
+        .split(|x| *x == b' ')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_vec())
+        .collect();
+    let name = args
+        .get(0)
+        .map(|x| String::from_utf8_lossy(x).to_lowercase())
+        .ok_or(())?;
+    let mut size = pos + 2;
+    if MC_STORAGE.contains(&name.as_str()) {
+        let len: usize = args
+            .get(4)
+            .and_then(|x| String::from_utf8_lossy(x).parse().ok())
+            .ok_or(())?;
+        if buf.len() < size + len + 2 {
+            return Ok(None);
+        }
+        args.push(buf[size..size + len].to_vec());
+        size += len + 2;
+    }
+    buf.split_to(size);
+    Ok(Some(args))
+}
+
+// the reply of a real server by the store.
+fn respond(shared: &Shared, req: &[Vec<u8>]) -> Vec<u8> {
+    let mut store = shared.store.lock().unwrap();
+    match shared.protocol {
+        Protocol::Redis => respond_redis(&mut store, req),
+        Protocol::Memcache => respond_mc(&mut store, req),
+    }
+}
+
+fn bulk(value: Option<&(Vec<u8>, u32)>) -> Vec<u8> {
+    match value {
+        Some((value, _)) => {
+            let mut reply = format!("${}\r\n", value.len()).into_bytes();
+            reply.extend_from_slice(value);
+            reply.extend_from_slice(b"\r\n");
+            reply
+        }
+        None => b"$-1\r\n".to_vec(),
+    }
+}
+
+fn respond_redis(store: &mut HashMap<Vec<u8>, (Vec<u8>, u32)>, req: &[Vec<u8>]) -> Vec<u8> {
+    let name = String::from_utf8_lossy(&req[0]).to_uppercase();
+    let keys = &req[1..];
+    match name.as_str() {
+        "PING" => b"+PONG\r\n".to_vec(),
+        "GET" if keys.len() == 1 => bulk(store.get(&keys[0])),
+        "SET" if keys.len() >= 2 => {
+            store.insert(keys[0].clone(), (keys[1].clone(), 0));
+            b"+OK\r\n".to_vec()
+        }
+        "MGET" => {
+            let mut reply = format!("*{}\r\n", keys.len()).into_bytes();
+            for key in keys {
+                reply.extend_from_slice(&bulk(store.get(key)));
+            }
+            reply
+        }
+        "MSET" => {
+            for pair in keys.chunks(2).filter(|x| x.len() == 2) {
+                store.insert(pair[0].clone(), (pair[1].clone(), 0));
+            }
+            b"+OK\r\n".to_vec()
+        }
+        "DEL" | "UNLINK" => {
+            let count = keys.iter().filter(|x| store.remove(*x).is_some()).count();
+            format!(":{}\r\n", count).into_bytes()
+        }
+        "EXISTS" => {
+            let count = keys.iter().filter(|x| store.contains_key(*x)).count();
+            format!(":{}\r\n", count).into_bytes()
+        }
+        _ => b"+OK\r\n".to_vec(),
+    }
+}
+
+fn respond_mc(store: &mut HashMap<Vec<u8>, (Vec<u8>, u32)>, req: &[Vec<u8>]) -> Vec<u8> {
+    let name = String::from_utf8_lossy(&req[0]).to_lowercase();
+    let arg = |i: usize| req.get(i).map(|x| String::from_utf8_lossy(x).to_string());
+    let noreply = arg(req.len() - 1).as_deref() == Some("noreply");
+    let reply: &[u8] = match name.as_str() {
+        "version" => b"VERSION mock\r\n",
+        "get" | "gets" => {
+            let mut reply = Vec::new();
+            for key in &req[1..] {
+                if let Some((value, flags)) = store.get(key) {
+                    reply.extend_from_slice(b"VALUE ");
+                    reply.extend_from_slice(key);
+                    if name == "gets" {
+                        reply.extend_from_slice(
+                            format!(" {} {} 1\r\n", flags, value.len()).as_bytes(),
+                        );
+                    } else {
+                        reply.extend_from_slice(
+                            format!(" {} {}\r\n", flags, value.len()).as_bytes(),
+                        );
+                    }
+                    reply.extend_from_slice(value);
+                    reply.extend_from_slice(b"\r\n");
+                }
+            }
+            reply.extend_from_slice(b"END\r\n");
+            return reply;
+        }
+        _ if MC_STORAGE.contains(&name.as_str()) => {
+            let key = req[1].clone();
+            let flags = arg(2).and_then(|x| x.parse().ok()).unwrap_or(0);
+            let data = req[req.len() - 1].clone();
+            let stored = match (name.as_str(), store.get_mut(&key)) {
+                ("add", Some(_)) | ("replace", None) => false,
+                ("append", None) | ("prepend", None) => false,
+                ("append", Some(value)) => {
+                    value.0.extend_from_slice(&data);
+                    true
+                }
+                ("prepend", Some(value)) => {
+                    let mut data = data;
+                    data.extend_from_slice(&value.0);
+                    value.0 = data;
+                    true
+                }
+                _ => {
+                    store.insert(key, (data, flags));
+                    true
+                }
+            };
+            if stored {
+                b"STORED\r\n"
+            } else {
+                b"NOT_STORED\r\n"
+            }
+        }
+        "delete" => match store.remove(&req[1]) {
+            Some(_) => b"DELETED\r\n",
+            None => b"NOT_FOUND\r\n",
+        },
+        "touch" => match store.get(&req[1]) {
+            Some(_) => b"TOUCHED\r\n",
+            None => b"NOT_FOUND\r\n",
+        },
+        _ => b"ERROR\r\n",
+    };
+    if noreply {
+        Vec::new()
+    } else {
+        reply.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(sock: &mut TcpStream, req: &[u8], size: usize) -> Vec<u8> {
+        sock.write_all(req).unwrap();
+        let mut reply = vec![0u8; size];
+        sock.read_exact(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn test_mock_redis_script() {
+        let script = Script::new()
+            .times("PING", 1, vec![Action::Error("LOADING".to_string())])
+            .on_key("GET", "bad", vec![Action::Malformed])
+            .on_key("GET", "cut", vec![Action::CloseAfter(3)]);
+        let backend = MockBackend::redis(script);
+        backend.set("a", "1");
+        let mut sock = TcpStream::connect(backend.addr()).unwrap();
+        assert_eq!(
+            roundtrip(&mut sock, b"*1\r\n$4\r\nPING\r\n", 10),
+            b"-LOADING\r\n"
+        );
+        assert_eq!(
+            roundtrip(&mut sock, b"*1\r\n$4\r\nPING\r\n", 7),
+            b"+PONG\r\n"
+        );
+        let mget = b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n";
+        assert_eq!(roundtrip(&mut sock, mget, 16), b"*2\r\n$1\r\n1\r\n$-1\r\n");
+        assert_eq!(
+            roundtrip(&mut sock, b"*2\r\n$3\r\nGET\r\n$3\r\nbad\r\n", 4),
+            b"$x\r\n"
+        );
+        // closed after the first bytes of reply
+        let cut = b"*2\r\n$3\r\nGET\r\n$3\r\ncut\r\n";
+        assert_eq!(roundtrip(&mut sock, cut, 3), b"$-1");
+        let mut rest = Vec::new();
+        sock.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        assert_eq!(backend.connections(), 1);
+        assert_eq!(backend.requests().len(), 5);
+        assert_eq!(
+            backend.requests_of("mget"),
+            vec![vec!["MGET".to_string(), "a".to_string(), "b".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_mock_memcache_store() {
+        let backend = MockBackend::memcache(Script::new());
+        let mut sock = TcpStream::connect(backend.addr()).unwrap();
+        assert_eq!(
+            roundtrip(&mut sock, b"set a 3 0 2\r\nhi\r\n", 8),
+            b"STORED\r\n"
+        );
+        assert_eq!(
+            roundtrip(&mut sock, b"add a 0 0 1\r\nx\r\n", 12),
+            b"NOT_STORED\r\n"
+        );
+        let expect = b"VALUE a 3 2\r\nhi\r\nEND\r\n";
+        assert_eq!(
+            roundtrip(&mut sock, b"get a b\r\n", expect.len()),
+            &expect[..]
+        );
+        assert_eq!(roundtrip(&mut sock, b"delete a\r\n", 9), b"DELETED\r\n");
+        assert_eq!(backend.get("a"), None);
+        assert_eq!(backend.requests()[0], vec!["set", "a", "3", "0", "2", "hi"]);
+    }
+}
@@ -38,22 +38,65 @@ pub(crate) fn myitoa(input: usize, buf: &mut BytesMut) {
     buf.extend_from_slice(value.as_bytes());
 }
 
+/// the part of key between the first open byte and the first close byte after it, which is
+/// the same as twemproxy and redis cluster (with "{}"). The whole key is hashed if the tag is
+/// unclosed or empty (e.g.: abc{}de{x} is hashed as it is).
 #[inline]
 pub fn trim_hash_tag<'a, 'b>(key: &'a [u8], hash_tag: &'b [u8]) -> &'a [u8] {
     if hash_tag.len() != 2 {
         return key;
     }
     if let Some(begin) = key.iter().position(|x| *x == hash_tag[0]) {
-        if let Some(end_offset) = key[begin..].iter().position(|x| *x == hash_tag[1]) {
-            // to avoid abc{}de
-            if end_offset > 1 {
-                return &key[begin + 1..begin + end_offset];
+        // the close byte is searched after the open one, which may be the same (e.g.: "::")
+        if let Some(len) = key[begin + 1..].iter().position(|x| *x == hash_tag[1]) {
+            if len > 0 {
+                return &key[begin + 1..begin + 1 + len];
             }
         }
     }
     key
 }
 
+#[test]
+fn test_trim_hash_tag() {
+    let tag = b"{}";
+    // tag at the start, middle and end
+    assert_eq!(trim_hash_tag(b"{user1000}.following", tag), b"user1000");
+    assert_eq!(trim_hash_tag(b"foo{bar}zap", tag), b"bar");
+    assert_eq!(trim_hash_tag(b"foo{bar}", tag), b"bar");
+    // only the first tag is used
+    assert_eq!(trim_hash_tag(b"foo{bar}{zap}", tag), b"bar");
+    assert_eq!(trim_hash_tag(b"{{bar}}", tag), b"{bar");
+    assert_eq!(trim_hash_tag(b"}foo{bar}", tag), b"bar");
+    // empty and unclosed tag means the whole key
+    assert_eq!(trim_hash_tag(b"foo{}{bar}", tag), b"foo{}{bar}");
+    assert_eq!(trim_hash_tag(b"{}", tag), b"{}");
+    assert_eq!(trim_hash_tag(b"foo{bar", tag), b"foo{bar");
+    assert_eq!(trim_hash_tag(b"foo{", tag), b"foo{");
+    assert_eq!(trim_hash_tag(b"", tag), b"");
+    // no tag
+    assert_eq!(trim_hash_tag(b"foo{bar}", b""), b"foo{bar}");
+    assert_eq!(trim_hash_tag(b"foo{bar}", b"{"), b"foo{bar}");
+
+    // distinct open and close bytes, or the same
+    assert_eq!(trim_hash_tag(b"user[1000]:name", b"[]"), b"1000");
+    assert_eq!(trim_hash_tag(b"user:1000:name", b"::"), b"1000");
+    assert_eq!(trim_hash_tag(b"user::name", b"::"), b"user::name");
+    assert_eq!(trim_hash_tag(b"user:1000", b"::"), b"user:1000");
+}
+
+#[test]
+fn test_trim_hash_tag_redis_slot() {
+    use crate::utils::crc::crc16;
+
+    let slot = |key: &[u8]| crc16(trim_hash_tag(key, b"{}")) % 16384;
+    assert_eq!(slot(b"foo"), 12182);
+    assert_eq!(slot(b"{foo}.bar"), 12182);
+    assert_eq!(slot(b"bar{foo}{zap}"), 12182);
+    assert_eq!(slot(b"{user1000}.following"), slot(b"{user1000}.followers"));
+    assert_eq!(slot(b"{}foo"), crc16(b"{}foo") % 16384);
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Range {
     pub begin: u32,
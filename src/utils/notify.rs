@@ -1,73 +1,141 @@
 use futures::task::Task;
 use std::cell::{Cell, RefCell};
+use std::fmt;
 use std::rc::Rc;
 
+use crate::metrics::{notify_reregister_incr, notify_wakeup_incr, IntGauge};
+
+/// shared by a command and all its subs, which wakes the task of front once every expected
+/// completion is seen.
+///
+/// The completions are counted by the done/undone transitions of the commands instead of the
+/// references to them, so the clones kept by backends, retries or waves never skew the count.
+///
+/// The wakeups and registrations are counted by aster_notify_wakeups and
+/// aster_notify_reregisters, whose ratio to the commands finds out the wakeup storms.
 #[derive(Debug, Clone)]
 pub struct Notify {
-    shared: NotifyShared,
+    task: Rc<RefCell<Option<Task>>>,
+    pending: Rc<Cell<usize>>,
+    wakeups: Rc<Cell<usize>>,
+    // the subs of fan-out command reserved, released once the command and all its subs (and
+    // the clones of them) are dropped
+    reserved: Option<Rc<Reserved>>,
+}
+
+/// the subs counted by the gauge of aster_outstanding_subs while they are alive, see
+/// protocol::SubsLimit.
+pub struct Reserved {
+    gauge: IntGauge,
+    count: i64,
+}
+
+impl Reserved {
+    pub fn new(gauge: IntGauge, count: usize) -> Reserved {
+        let count = count as i64;
+        gauge.add(count);
+        Reserved { gauge, count }
+    }
+}
+
+impl Drop for Reserved {
+    fn drop(&mut self) {
+        self.gauge.sub(self.count);
+    }
+}
+
+impl fmt::Debug for Reserved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reserved({})", self.count)
+    }
 }
 
 impl Notify {
     pub fn empty() -> Self {
         Notify {
-            shared: NotifyShared {
-                task: Rc::new(RefCell::new(None)),
-                count: Rc::new(Cell::new(1u16)),
-                expect: std::u16::MAX,
-            },
+            task: Rc::new(RefCell::new(None)),
+            pending: Rc::new(Cell::new(0)),
+            wakeups: Rc::new(Cell::new(0)),
+            reserved: None,
         }
     }
 
+    /// hold the subs reserved until the command and all its subs are dropped, it must be held
+    /// before the subs are made.
+    pub fn hold(&mut self, reserved: Reserved) {
+        self.reserved = Some(Rc::new(reserved));
+    }
+
     pub fn set_task(&mut self, task: Task) {
-        self.shared.task.borrow_mut().replace(task);
+        notify_reregister_incr();
+        self.task.borrow_mut().replace(task);
     }
 
     pub fn notify(&self) {
-        if let Some(task) = self.shared.task.borrow().as_ref() {
-            // trace!("trace notify Some");
+        notify_wakeup_incr();
+        self.wakeups.set(self.wakeups.get() + 1);
+        if let Some(task) = self.task.borrow().as_ref() {
             task.notify();
-        } else {
-            // trace!("trace notify None");
         }
     }
 
-    pub fn set_expect(&mut self, expect: u16) {
-        self.shared.expect = expect;
+    /// the times notified, shared by the command and all its subs.
+    pub fn wakeups(&self) -> usize {
+        self.wakeups.get()
     }
 
-    pub fn expect(&self) -> u16 {
-        self.shared.expect
+    /// the count of completions to wait for, 1 for a single command or the count of its subs.
+    pub fn set_expect(&mut self, expect: usize) {
+        self.pending.set(expect);
     }
 
-    pub fn fetch_sub(&self, val: u16) -> u16 {
-        let origin_val = self.shared.count.get();
-        self.shared.count.set(origin_val - val);
-        origin_val
+    /// the count of completions not seen yet.
+    pub fn pending(&self) -> usize {
+        self.pending.get()
     }
 
-    pub fn fetch_add(&self, val: u16) -> u16 {
-        let origin_val = self.shared.count.get();
-        self.shared.count.set(origin_val - val);
-        origin_val
+    /// one command is done, the task is notified on the last one.
+    pub fn done(&self) {
+        let pending = self.pending.get();
+        if pending == 0 {
+            return;
+        }
+        self.pending.set(pending - 1);
+        if pending == 1 {
+            self.notify();
+        }
     }
-}
 
-#[derive(Debug)]
-struct NotifyShared {
-    task: Rc<RefCell<Option<Task>>>,
-    count: Rc<Cell<u16>>,
-    expect: u16,
+    /// one command done is reset to be retried.
+    pub fn undone(&self) {
+        self.pending.set(self.pending.get() + 1);
+    }
 }
 
-impl Clone for NotifyShared {
-    fn clone(&self) -> NotifyShared {
-        let count = self.count.clone();
-        let now_val = count.get();
-        count.set(now_val + 1);
-        NotifyShared {
-            count,
-            task: self.task.clone(),
-            expect: self.expect,
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_notify_pending_by_transitions() {
+        let mut notify = Notify::empty();
+        notify.set_expect(2);
+        let sub = notify.clone();
+        let cloned = sub.clone();
+        drop(cloned);
+        assert_eq!(notify.pending(), 2);
+
+        sub.done();
+        sub.undone();
+        assert_eq!(notify.pending(), 2);
+        sub.done();
+        assert_eq!(notify.wakeups(), 0);
+        notify.done();
+        assert_eq!(notify.pending(), 0);
+        assert_eq!(sub.wakeups(), 1);
+        // never underflow by the duplicated completion
+        notify.done();
+        assert_eq!(notify.pending(), 0);
+        assert_eq!(notify.wakeups(), 1);
     }
 }
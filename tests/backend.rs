@@ -0,0 +1,421 @@
+//! the behaviours of proxy against the scriptable mock backends of libaster::testsupport: the
+//! replies merged by fan-out, the get and delete of memcache getdel, the replies flushed before
+//! QUIT, the links between two aster, the commands retried on stale connections, the stalled
+//! requests timed out, and the backends ejected by their replies. Run it by:
+//!
+//!     cargo test --features testsupport --test backend
+use bytes::BytesMut;
+use libaster::com::CacheType;
+use libaster::protocol::redis::Message;
+use libaster::testsupport::{Action, MockBackend, Script};
+use libaster::ClusterBuilder;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Client {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        Client {
+            stream,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// the raw bytes of the reply.
+    fn call(&mut self, args: &[&str]) -> Vec<u8> {
+        let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+        let mut req = BytesMut::new();
+        Message::from_args(&args).save(&mut req);
+        self.stream.write_all(&req).unwrap();
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(reply) = Message::parse(&mut self.buf).unwrap() {
+                return reply.data.to_vec();
+            }
+            let size = self
+                .stream
+                .read(&mut chunk)
+                .unwrap_or_else(|err| panic!("no reply of {:?} due to {}", args, err));
+            assert_ne!(size, 0, "connection closed by {:?}", args);
+            self.buf.extend_from_slice(&chunk[..size]);
+        }
+    }
+}
+
+fn wait_until<F: FnMut() -> bool>(mut cond: F) -> bool {
+    let begin = Instant::now();
+    while begin.elapsed() < WAIT_TIMEOUT {
+        if cond() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+fn keys_of(backend: &MockBackend, cmd: &str, step: usize) -> Vec<String> {
+    backend
+        .requests_of(cmd)
+        .into_iter()
+        .flat_map(|req| req.into_iter().skip(1).step_by(step))
+        .collect()
+}
+
+#[test]
+fn test_fanout_merge_redis() {
+    let backends = vec![
+        MockBackend::redis(Script::new()),
+        MockBackend::redis(Script::new()),
+    ];
+    let handle = ClusterBuilder::new("test-backend-fanout")
+        .servers(vec![
+            backends[0].server("redis-1"),
+            backends[1].server("redis-2"),
+        ])
+        .config(|cc| cc.ping_fail_limit = Some(0))
+        .spawn()
+        .unwrap();
+    let keys: Vec<String> = (0..16).map(|x| format!("key:{}", x)).collect();
+    let mut mset = vec!["MSET"];
+    for key in &keys {
+        mset.push(key);
+        mset.push(key);
+    }
+
+    let mut client = Client::connect(handle.local_addr());
+    assert_eq!(client.call(&mset), b"+OK\r\n");
+    // each key is sent to one backend only, and the keys are spread over both
+    let mut sent: Vec<String> = Vec::new();
+    for backend in &backends {
+        let part = keys_of(backend, "MSET", 2);
+        assert!(!part.is_empty());
+        for key in &part {
+            assert_eq!(backend.get(key).as_ref(), Some(key));
+        }
+        sent.extend(part);
+    }
+    sent.sort();
+    let mut expect = keys.clone();
+    expect.sort();
+    assert_eq!(sent, expect);
+
+    // the values are merged in the order of keys
+    let mut mget = vec!["MGET"];
+    mget.extend(keys.iter().map(|x| x.as_str()));
+    mget.push("nokey");
+    let mut expect = format!("*{}\r\n", keys.len() + 1);
+    for key in &keys {
+        expect.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
+    }
+    expect.push_str("$-1\r\n");
+    assert_eq!(client.call(&mget), expect.as_bytes());
+
+    // and the counts are summed
+    let mut del = vec!["DEL"];
+    del.extend(keys.iter().map(|x| x.as_str()));
+    del.push("nokey");
+    assert_eq!(client.call(&del), format!(":{}\r\n", keys.len()).as_bytes());
+    for backend in &backends {
+        assert!(keys.iter().all(|x| backend.get(x).is_none()));
+    }
+    handle.shutdown();
+}
+
+#[test]
+fn test_fanout_merge_memcache() {
+    let backends = vec![
+        MockBackend::memcache(Script::new()),
+        MockBackend::memcache(Script::new()),
+    ];
+    let keys: Vec<String> = (0..16).map(|x| format!("key:{}", x)).collect();
+    for key in &keys {
+        // seeded to both, the value tells which one replied
+        backends[0].set(key, "mc-1");
+        backends[1].set(key, "mc-2");
+    }
+    let handle = ClusterBuilder::new("test-backend-fanout-mc")
+        .cache_type(CacheType::Memcache)
+        .servers(vec![backends[0].server("mc-1"), backends[1].server("mc-2")])
+        .config(|cc| cc.ping_fail_limit = Some(0))
+        .spawn()
+        .unwrap();
+
+    let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+    client.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    client
+        .write_all(format!("get {} nokey\r\n", keys.join(" ")).as_bytes())
+        .unwrap();
+    let mut reply = Vec::new();
+    let mut chunk = [0u8; 4096];
+    while !reply.ends_with(b"END\r\n") {
+        let size = client.read(&mut chunk).unwrap();
+        assert_ne!(size, 0);
+        reply.extend_from_slice(&chunk[..size]);
+    }
+    let reply = String::from_utf8(reply).unwrap();
+    let lines: Vec<&str> = reply.split("\r\n").collect();
+    assert_eq!(lines.len(), keys.len() * 2 + 2, "{}", reply);
+
+    // the values are merged in the order of keys, each of the backend asked
+    for (i, key) in keys.iter().enumerate() {
+        let value = lines[i * 2 + 1];
+        assert_eq!(lines[i * 2], format!("VALUE {} 0 4", key));
+        let owner = if value == "mc-1" {
+            &backends[0]
+        } else {
+            &backends[1]
+        };
+        assert!(keys_of(owner, "get", 1).contains(key), "{}", key);
+    }
+    assert!(backends.iter().all(|x| !x.requests_of("get").is_empty()));
+    handle.shutdown();
+}
+
+#[test]
+fn test_getdel_memcache() {
+    let backend = MockBackend::memcache(Script::new());
+    backend.set("a", "1");
+    let handle = ClusterBuilder::new("test-backend-getdel")
+        .cache_type(CacheType::Memcache)
+        .servers(vec![backend.server("mc-1")])
+        .config(|cc| cc.ping_fail_limit = Some(0))
+        .spawn()
+        .unwrap();
+
+    let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+    client.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let mut getdel = |key: &str| {
+        client
+            .write_all(format!("getdel {}\r\n", key).as_bytes())
+            .unwrap();
+        let mut reply = Vec::new();
+        let mut chunk = [0u8; 4096];
+        while !reply.ends_with(b"END\r\n") {
+            let size = client.read(&mut chunk).unwrap();
+            assert_ne!(size, 0);
+            reply.extend_from_slice(&chunk[..size]);
+        }
+        String::from_utf8(reply).unwrap()
+    };
+    // the value is replied and the key deleted, by the get and delete in order
+    assert_eq!(getdel("a"), "VALUE a 0 1\r\n1\r\nEND\r\n");
+    // the delete is never replied, so it may be handled after the get replied
+    assert!(wait_until(|| backend.get("a").is_none()));
+    assert_eq!(backend.requests_of("get"), vec![vec!["get", "a"]]);
+    assert_eq!(
+        backend.requests_of("delete"),
+        vec![vec!["delete", "a", "noreply"]]
+    );
+    // and nothing for the key deleted
+    assert_eq!(getdel("a"), "END\r\n");
+    handle.shutdown();
+}
+
+#[test]
+fn test_quit_after_replies() {
+    // the GET before QUIT is replied late
+    let backend = MockBackend::redis(Script::new().on_key(
+        "GET",
+        "a",
+        vec![Action::Delay(200), Action::Respond],
+    ));
+    backend.set("a", "1");
+    let handle = ClusterBuilder::new("test-backend-quit")
+        .servers(vec![backend.server("redis-1")])
+        .config(|cc| cc.ping_fail_limit = Some(0))
+        .spawn()
+        .unwrap();
+
+    let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+    client.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    client
+        .write_all(
+            b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n*1\r\n$4\r\nQUIT\r\n*2\r\n$3\r\nGET\r\n$1\r\nb\r\n",
+        )
+        .unwrap();
+    // the GET is replied before +OK of QUIT, then the connection is closed
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), "$1\r\n1\r\n+OK\r\n");
+    // and nothing after QUIT is received
+    assert_eq!(backend.requests_of("GET"), vec![vec!["GET", "a"]]);
+    handle.shutdown();
+}
+
+#[test]
+fn test_upstream_link() {
+    // edge -> central -> redis, the link between the two aster is compressed
+    let backend = MockBackend::redis(Script::new());
+    let central = ClusterBuilder::new("test-backend-link-central")
+        .servers(vec![backend.server("redis-1")])
+        .config(|cc| cc.ping_fail_limit = Some(0))
+        .spawn()
+        .unwrap();
+    let edge = ClusterBuilder::new("test-backend-link-edge")
+        .servers(vec![format!("{}:10 central", central.local_addr())])
+        .config(|cc| {
+            cc.ping_fail_limit = Some(0);
+            cc.upstream_link = Some(true);
+        })
+        .spawn()
+        .unwrap();
+
+    let mut client = Client::connect(edge.local_addr());
+    let value = "v".repeat(64 * 1024);
+    assert_eq!(client.call(&["SET", "a", &value]), b"+OK\r\n");
+    assert_eq!(backend.get("a").as_ref(), Some(&value));
+    let mut expect = format!("${}\r\n", value.len()).into_bytes();
+    expect.extend_from_slice(value.as_bytes());
+    expect.extend_from_slice(b"\r\n");
+    assert_eq!(client.call(&["GET", "a"]), expect);
+    // the handshake is never seen by the final backend
+    assert!(backend.requests_of("ASTER-LINK").is_empty());
+    edge.shutdown();
+    central.shutdown();
+
+    // and falls back to plain protocol against a real redis
+    let backend = MockBackend::redis(Script::new().on(
+        "ASTER-LINK",
+        vec![Action::Error(
+            "ERR unknown command 'ASTER-LINK'".to_string(),
+        )],
+    ));
+    backend.set("a", "1");
+    let edge = ClusterBuilder::new("test-backend-link-plain")
+        .servers(vec![backend.server("redis-1")])
+        .config(|cc| {
+            cc.ping_fail_limit = Some(0);
+            cc.upstream_link = Some(true);
+        })
+        .spawn()
+        .unwrap();
+    let mut client = Client::connect(edge.local_addr());
+    assert_eq!(client.call(&["GET", "a"]), b"$1\r\n1\r\n");
+    assert_eq!(
+        backend.requests_of("ASTER-LINK"),
+        vec![vec!["ASTER-LINK", "deflate"]]
+    );
+    edge.shutdown();
+}
+
+#[test]
+fn test_retry_on_stale_connection() {
+    // the first GET finds the connection closed by backend before any reply
+    let backend = MockBackend::redis(Script::new().times("GET", 1, vec![Action::Close]));
+    backend.set("a", "1");
+    let handle = ClusterBuilder::new("test-backend-retry")
+        .servers(vec![backend.server("redis-1")])
+        .config(|cc| {
+            cc.ping_fail_limit = Some(0);
+            cc.retry_on_stale = Some(true);
+        })
+        .spawn()
+        .unwrap();
+
+    let mut client = Client::connect(handle.local_addr());
+    assert_eq!(client.call(&["GET", "a"]), b"$1\r\n1\r\n");
+    assert_eq!(backend.requests_of("GET").len(), 2);
+    assert!(backend.connections() >= 2);
+    handle.shutdown();
+
+    // failed without retry
+    let backend = MockBackend::redis(Script::new().times("GET", 1, vec![Action::Close]));
+    let handle = ClusterBuilder::new("test-backend-no-retry")
+        .servers(vec![backend.server("redis-1")])
+        .config(|cc| cc.ping_fail_limit = Some(0))
+        .spawn()
+        .unwrap();
+    let mut client = Client::connect(handle.local_addr());
+    let reply = client.call(&["GET", "a"]);
+    assert!(
+        reply.starts_with(b"-"),
+        "{}",
+        String::from_utf8_lossy(&reply)
+    );
+    assert_eq!(backend.requests_of("GET").len(), 1);
+    handle.shutdown();
+}
+
+#[test]
+fn test_timeout_stalled_request() {
+    // the reply of "slow" is far beyond read_timeout
+    let backend = MockBackend::redis(Script::new().on_key(
+        "GET",
+        "slow",
+        vec![Action::Delay(10_000), Action::Respond],
+    ));
+    let handle = ClusterBuilder::new("test-backend-timeout")
+        .servers(vec![backend.server("redis-1")])
+        .read_timeout(200)
+        .config(|cc| {
+            cc.ping_succ_interval = Some(50);
+            cc.stale_conn_limit = Some(1);
+        })
+        .spawn()
+        .unwrap();
+
+    let mut client = Client::connect(handle.local_addr());
+    let begin = Instant::now();
+    let reply = client.call(&["GET", "slow"]);
+    // failed by the connection cycled, long before the backend replies
+    assert!(
+        reply.starts_with(b"-"),
+        "{}",
+        String::from_utf8_lossy(&reply)
+    );
+    assert!(
+        begin.elapsed() < Duration::from_secs(5),
+        "{:?}",
+        begin.elapsed()
+    );
+
+    // and the connection reconnected serves the others
+    assert_eq!(client.call(&["SET", "a", "1"]), b"+OK\r\n");
+    assert!(backend.connections() >= 2);
+    handle.shutdown();
+}
+
+#[test]
+fn test_eject_unavailable_backend() {
+    // the backend is loading for the first pings
+    let backend = MockBackend::redis(Script::new().times(
+        "PING",
+        3,
+        vec![Action::Error(
+            "LOADING Redis is loading the dataset in memory".to_string(),
+        )],
+    ));
+    let handle = ClusterBuilder::new("test-backend-eject")
+        .servers(vec![backend.server("redis-1")])
+        .config(|cc| {
+            cc.ping_fail_limit = Some(3);
+            cc.ping_succ_interval = Some(50);
+        })
+        .spawn()
+        .unwrap();
+
+    let mut client = Client::connect(handle.local_addr());
+    let ejected = format!("redis-1 {} ejected unavailable", backend.addr());
+    let active = format!("redis-1 {} active", backend.addr());
+    let node = |client: &mut Client| {
+        let reply = client.call(&["PROXY", "NODES"]);
+        String::from_utf8_lossy(&reply).to_string()
+    };
+    assert!(wait_until(|| node(&mut client).contains(&ejected)));
+    // and recovered by the first PONG
+    assert!(wait_until(|| node(&mut client).contains(&active)));
+    assert!(backend.requests_of("PING").len() >= 4);
+    handle.shutdown();
+}
@@ -0,0 +1,304 @@
+//! conformance of the replies proxied by aster with the replies of a real redis.
+//!
+//! The corpus is sent to a redis-server directly and then through aster after FLUSHALL, and the
+//! replies are compared byte by byte, so a command misclassified or mishandled by the proxy
+//! (e.g.: routed as keyless, fanned out wrongly or rejected by the reply check) is caught. The
+//! redis-server binary is taken from env ASTER_REDIS_SERVER or PATH, run it by:
+//!
+//!     cargo test --test conformance -- --ignored
+use bytes::BytesMut;
+use libaster::protocol::redis::Message;
+use libaster::ClusterBuilder;
+
+use std::env;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ENV_REDIS_SERVER: &str = "ASTER_REDIS_SERVER";
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct RedisServer {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl RedisServer {
+    fn spawn() -> RedisServer {
+        let bin = env::var(ENV_REDIS_SERVER).unwrap_or_else(|_| "redis-server".to_string());
+        // take a free port from the OS, it's released to redis-server at once
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|x| x.local_addr())
+            .expect("no free port")
+            .port();
+        let child = Command::new(&bin)
+            .args(&["--bind", "127.0.0.1", "--port", &port.to_string()])
+            .args(&["--save", "", "--appendonly", "no"])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|err| panic!("fail to run {} due to {}", bin, err));
+        let server = RedisServer {
+            child,
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        };
+        let begin = Instant::now();
+        while TcpStream::connect(server.addr).is_err() {
+            assert!(begin.elapsed() < READY_TIMEOUT, "redis-server not ready");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+}
+
+impl Drop for RedisServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        Client {
+            stream,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// the raw bytes of the reply.
+    fn call(&mut self, args: &[String]) -> Vec<u8> {
+        let mut req = BytesMut::new();
+        Message::from_args(args).save(&mut req);
+        self.stream.write_all(&req).unwrap();
+        self.read_reply(args)
+    }
+
+    fn read_reply(&mut self, args: &[String]) -> Vec<u8> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(reply) = Message::parse(&mut self.buf).unwrap() {
+                return reply.data.to_vec();
+            }
+            let size = self
+                .stream
+                .read(&mut chunk)
+                .unwrap_or_else(|err| panic!("no reply of {:?} due to {}", args, err));
+            assert_ne!(size, 0, "connection closed by {:?}", args);
+            self.buf.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    fn run(&mut self, corpus: &[Vec<String>]) -> Vec<Vec<u8>> {
+        corpus.iter().map(|args| self.call(args)).collect()
+    }
+
+    /// send the whole corpus before reading any reply.
+    fn pipeline(&mut self, corpus: &[Vec<String>]) -> Vec<Vec<u8>> {
+        let mut req = BytesMut::new();
+        for args in corpus {
+            Message::from_args(args).save(&mut req);
+        }
+        self.stream.write_all(&req).unwrap();
+        corpus.iter().map(|args| self.read_reply(args)).collect()
+    }
+}
+
+fn cmd(args: &[&str]) -> Vec<String> {
+    args.iter().map(|x| x.to_string()).collect()
+}
+
+// the string and bitmap commands, some of them are generated over the offsets and the ranges
+// crossing the byte boundaries.
+fn string_corpus() -> Vec<Vec<String>> {
+    let mut corpus = vec![
+        cmd(&["SET", "str", "hello world"]),
+        cmd(&["GETRANGE", "str", "0", "4"]),
+        cmd(&["GETRANGE", "str", "-5", "-1"]),
+        cmd(&["GETRANGE", "str", "100", "200"]),
+        cmd(&["GETRANGE", "nokey", "0", "-1"]),
+        cmd(&["SETRANGE", "str", "6", "redis"]),
+        cmd(&["SETRANGE", "padded", "5", "x"]),
+        cmd(&["GET", "padded"]),
+        cmd(&["STRLEN", "str"]),
+        cmd(&["STRLEN", "nokey"]),
+        cmd(&["APPEND", "str", "!"]),
+        cmd(&["SUBSTR", "str", "0", "-1"]),
+        cmd(&["SETRANGE", "str", "-1", "x"]),
+    ];
+    for offset in &[0, 1, 7, 8, 9, 63, 100, 1000] {
+        let offset = offset.to_string();
+        corpus.push(cmd(&["SETBIT", "flags", &offset, "1"]));
+        corpus.push(cmd(&["GETBIT", "flags", &offset]));
+        corpus.push(cmd(&["SETBIT", "flags", &offset, "1"]));
+        corpus.push(cmd(&["GETBIT", "nokey", &offset]));
+    }
+    corpus.push(cmd(&["SETBIT", "flags", "8", "0"]));
+    corpus.push(cmd(&["SETBIT", "flags", "-1", "1"]));
+    corpus.push(cmd(&["SETBIT", "flags", "1", "2"]));
+    corpus.push(cmd(&["BITCOUNT", "flags"]));
+    corpus.push(cmd(&["BITCOUNT", "nokey"]));
+    for (start, end) in &[(0, 0), (0, -1), (1, 2), (-2, -1), (10, 5)] {
+        let (start, end) = (start.to_string(), end.to_string());
+        corpus.push(cmd(&["BITCOUNT", "flags", &start, &end]));
+        corpus.push(cmd(&["BITPOS", "flags", "1", &start, &end]));
+        corpus.push(cmd(&["BITPOS", "flags", "0", &start, &end]));
+    }
+    corpus.push(cmd(&["BITPOS", "flags", "1"]));
+    corpus.push(cmd(&["BITPOS", "nokey", "0"]));
+    corpus.push(cmd(&["BITPOS", "nokey", "1"]));
+    corpus.extend(vec![
+        cmd(&[
+            "BITFIELD", "field", "SET", "u8", "0", "255", "GET", "u4", "0",
+        ]),
+        cmd(&["BITFIELD", "field", "INCRBY", "u8", "0", "10"]),
+        cmd(&[
+            "BITFIELD", "field", "OVERFLOW", "SAT", "INCRBY", "u8", "0", "300",
+        ]),
+        cmd(&[
+            "BITFIELD", "field", "OVERFLOW", "FAIL", "INCRBY", "i8", "#1", "200",
+        ]),
+        cmd(&["BITFIELD", "field", "GET", "i16", "0", "GET", "u8", "#1"]),
+        cmd(&["BITFIELD", "field"]),
+        cmd(&["BITFIELD", "field", "GET", "u99", "0"]),
+        cmd(&["BITFIELD", "nokey", "GET", "u8", "0"]),
+        // the wrong type error is replied by redis and passed through
+        cmd(&["LPUSH", "list", "a"]),
+        cmd(&["SETBIT", "list", "0", "1"]),
+        cmd(&["GETRANGE", "list", "0", "1"]),
+        cmd(&["BITFIELD", "list", "GET", "u8", "0"]),
+    ]);
+    corpus
+}
+
+// a few commands of the other types to catch the regressions of the common ones.
+fn common_corpus() -> Vec<Vec<String>> {
+    vec![
+        cmd(&["SET", "a", "1"]),
+        cmd(&["INCRBY", "a", "10"]),
+        cmd(&["INCRBYFLOAT", "a", "0.5"]),
+        cmd(&["MSET", "b", "2", "c", "3"]),
+        cmd(&["MGET", "a", "b", "c", "nokey"]),
+        cmd(&["EXISTS", "a", "b", "nokey"]),
+        cmd(&["DEL", "b", "c", "nokey"]),
+        cmd(&["HSET", "hash", "f1", "v1"]),
+        cmd(&["HMGET", "hash", "f1", "f2"]),
+        cmd(&["HINCRBY", "hash", "n", "3"]),
+        cmd(&["RPUSH", "queue", "x", "y", "z"]),
+        cmd(&["LRANGE", "queue", "0", "-1"]),
+        cmd(&["LINDEX", "queue", "5"]),
+        cmd(&["SADD", "set", "m"]),
+        cmd(&["SISMEMBER", "set", "m"]),
+        cmd(&["ZADD", "zset", "1", "one", "2", "two"]),
+        cmd(&["ZRANGE", "zset", "0", "-1", "WITHSCORES"]),
+        cmd(&["ZSCORE", "zset", "two"]),
+        cmd(&["TYPE", "zset"]),
+        cmd(&["PING"]),
+    ]
+}
+
+// the writes and TTL mutations of one key interleaved with the multi-key commands, whose subs
+// are dispatched in waves. The TTL are large enough to be stable in seconds during the test.
+fn ttl_corpus() -> Vec<Vec<String>> {
+    let mut corpus = Vec::new();
+    for round in 0..200 {
+        // the key is moved across the waves of MSET
+        let mut pairs: Vec<_> = (0..16).map(|i| format!("other:{}", i)).collect();
+        pairs.insert(round % 17, "ttl".to_string());
+        let mut mset = cmd(&["MSET"]);
+        for key in pairs {
+            mset.push(key);
+            mset.push(round.to_string());
+        }
+        corpus.push(mset);
+        match round % 5 {
+            0 => corpus.push(cmd(&["EXPIRE", "ttl", "100000"])),
+            1 => corpus.push(cmd(&["PEXPIRE", "ttl", "200000000"])),
+            2 => corpus.push(cmd(&["PERSIST", "ttl"])),
+            3 => corpus.push(cmd(&["SET", "ttl", "set", "EX", "300000"])),
+            _ => corpus.push(cmd(&["DEL", "other:0", "ttl", "other:1"])),
+        }
+        corpus.push(cmd(&["TTL", "ttl"]));
+        if round % 3 == 0 {
+            corpus.push(cmd(&["EXPIRE", "ttl", "400000"]));
+        }
+    }
+    corpus.push(cmd(&["MGET", "ttl", "other:0", "other:1"]));
+    corpus
+}
+
+#[test]
+#[ignore]
+fn test_ttl_ordering_with_redis() {
+    let redis = RedisServer::spawn();
+    let proxy = ClusterBuilder::new("test-ttl-ordering")
+        .servers(vec![format!("{}:10 redis-1", redis.addr)])
+        .config(|cc| cc.multi_key_batch = Some(2))
+        .spawn()
+        .unwrap();
+
+    let corpus = ttl_corpus();
+    let ttl = cmd(&["TTL", "ttl"]);
+    let mut direct = Client::connect(redis.addr);
+    direct.call(&cmd(&["FLUSHALL"]));
+    let expected = direct.pipeline(&corpus);
+    let expected_ttl = direct.call(&ttl);
+    direct.call(&cmd(&["FLUSHALL"]));
+    let mut client = Client::connect(proxy.local_addr());
+    let actual = client.pipeline(&corpus);
+    let actual_ttl = client.call(&ttl);
+    proxy.shutdown();
+
+    for (i, (expected, actual)) in expected.iter().zip(actual.iter()).enumerate() {
+        assert_eq!(
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(actual),
+            "reply of {:?}",
+            corpus[i]
+        );
+    }
+    assert_eq!(expected_ttl, actual_ttl);
+}
+
+#[test]
+#[ignore]
+fn test_conformance_with_redis() {
+    let redis = RedisServer::spawn();
+    let proxy = ClusterBuilder::new("test-conformance")
+        .servers(vec![format!("{}:10 redis-1", redis.addr)])
+        .spawn()
+        .unwrap();
+
+    let mut corpus = string_corpus();
+    corpus.extend(common_corpus());
+    let mut direct = Client::connect(redis.addr);
+    direct.call(&cmd(&["FLUSHALL"]));
+    let expected = direct.run(&corpus);
+    direct.call(&cmd(&["FLUSHALL"]));
+    let actual = Client::connect(proxy.local_addr()).run(&corpus);
+
+    let mismatched: Vec<_> = corpus
+        .iter()
+        .zip(expected.iter().zip(actual.iter()))
+        .filter(|(_, (expected, actual))| expected != actual)
+        .map(|(args, (expected, actual))| {
+            format!(
+                "{:?}: expected {:?} but proxied {:?}",
+                args,
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(actual)
+            )
+        })
+        .collect();
+    proxy.shutdown();
+    assert!(mismatched.is_empty(), "\n{}", mismatched.join("\n"));
+}